@@ -1,9 +1,17 @@
+use std::borrow::Cow;
+
 use luminal::{
     op::*,
-    prelude::{petgraph::visit::EdgeRef, *},
+    prelude::{
+        petgraph::{visit::EdgeRef, Direction},
+        *,
+    },
 };
 
-use super::other::ARange;
+use super::{
+    matmul::{offset, usizes},
+    other::ARange,
+};
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Sub;
@@ -33,6 +41,9 @@ impl Operator for Sub {
         }
         vec![Tensor::new(data)]
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -40,7 +51,7 @@ pub struct SubtractionCompiler;
 
 impl Compiler for SubtractionCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let (lhs, rhs) = (node(), node());
         let mul = binary::<Mul>(rhs.clone(), super::constant(-1.));
         let add = binary::<Add>(lhs.clone(), mul.clone());
@@ -82,6 +93,7 @@ impl Compiler for SubtractionCompiler {
                 .input(b, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(add, sub, &mut graph.graph);
+            remap(add, sub, &mut ids, graph);
 
             graph.graph.remove_node(add);
             s.try_delete();
@@ -113,10 +125,13 @@ impl Operator for Equal {
             } else {
                 0.0
             };
-            data[i] = if a < b { 1. } else { 0. };
+            data[i] = if a == b { 1. } else { 0. };
         }
         vec![Tensor::new(data)]
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -124,12 +139,12 @@ pub struct EqualCompiler;
 
 impl Compiler for EqualCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let one = super::constant(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<LessThan>(lhs.clone(), rhs.clone());
         let ne = binary::<Add>(lt1.clone(), binary::<LessThan>(rhs.clone(), lhs.clone()));
-        let eq = binary::<Sub>(one, ne);
+        let eq = ordered_binary::<Sub>(one, ne);
 
         let mut s = eq.clone().search(graph);
         while s.next_match() {
@@ -160,6 +175,7 @@ impl Compiler for EqualCompiler {
                 .input(rhs, b_edge.1, b_edge.2)
                 .finish();
             move_outgoing_edge(eq, equals, &mut graph.graph);
+            remap(eq, equals, &mut ids, graph);
 
             graph.graph.remove_node(eq);
             s.try_delete();
@@ -167,35 +183,92 @@ impl Compiler for EqualCompiler {
     }
 }
 
+/// Rows to prefetch ahead of the one being copied. Lookups jump around the whole table, so each row is a fresh trip to
+/// memory unless it's been asked for early.
+const PREFETCH_ROWS: usize = 4;
+#[cfg(target_arch = "x86_64")]
+const CACHE_LINE: usize = 64 / std::mem::size_of::<f32>();
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Gather {
     pub embed_dim: usize,
+    /// Multiplier for every gathered value, fused in from a scale following the gather
+    pub scale: f32,
+    /// Format gathered values get rounded to, fused in from a cast following the gather
+    pub cast: Option<DType>,
 }
 
 impl Operator for Gather {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        // Inp 1 should be Vec<f32> and inp 2 should be a CudaSlice<T>
-        let indexes = tensors[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let weights = tensors[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+        let indexes = get_vec(&tensors[0].0);
+        // Weights come in expanded along the batch, which every row shares
+        let mut weight_shape = tensors[1].1;
+        weight_shape.remove_dim(0);
+        let (row_stride, col_stride) = match usizes(weight_shape.strides())[..] {
+            [row, col] => (row, col),
+            _ => unreachable!(),
+        };
+        let weights = get_vec(&tensors[1].0);
+        let weights = &weights[offset(&weight_shape)..];
 
         let mut out = vec![0.; indexes.len() * self.embed_dim];
-        for token in 0..indexes.len() {
-            let e = indexes[token] as usize;
-            for dim in 0..self.embed_dim {
-                out[token * self.embed_dim + dim] = weights[e * self.embed_dim + dim];
+        let row_start = |token: usize| indexes[token] as usize * row_stride;
+        for (token, out) in out.chunks_exact_mut(self.embed_dim).enumerate() {
+            let start = row_start(token);
+            if col_stride != 1 {
+                for (dim, out) in out.iter_mut().enumerate() {
+                    *out = weights[start + dim * col_stride];
+                }
+            } else {
+                if let Some(&next) = indexes.get(token + PREFETCH_ROWS) {
+                    let next = next as usize * row_stride;
+                    prefetch(&weights[next..next + self.embed_dim]);
+                }
+                // Copies whole cache lines at a time
+                out.copy_from_slice(&weights[start..start + self.embed_dim]);
+            }
+            if self.scale != 1. || self.cast.is_some() {
+                for x in out.iter_mut() {
+                    *x *= self.scale;
+                    if let Some(dtype) = self.cast {
+                        *x = dtype.round(*x);
+                    }
+                }
             }
         }
 
-        vec![Tensor::new(out)]
+        // Stored in half precision, like the cast would have
+        vec![match self.cast {
+            Some(DType::F16) => Tensor::new(out.into_iter().map(f16::from_f32).collect::<Vec<_>>()),
+            Some(DType::BF16) => {
+                Tensor::new(out.into_iter().map(bf16::from_f32).collect::<Vec<_>>())
+            }
+            _ => Tensor::new(out),
+        }]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Ask for a slice to be brought into cache, without waiting for it
+fn prefetch(data: &[f32]) {
+    #[cfg(target_arch = "x86_64")]
+    for line in data.chunks(CACHE_LINE) {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        unsafe { _mm_prefetch::<_MM_HINT_T0>(line.as_ptr() as *const i8) };
     }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = data;
 }
 
+/// Replace one hot embedding lookups with `Gather`, along with a scale or cast directly after them
 #[derive(Debug, Default)]
 pub struct GatherCompiler;
 
 impl Compiler for GatherCompiler {
     type Output = ();
-    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let indexes = node();
         let eq = binary::<Equal>(indexes.clone(), op::<ARange>());
         let embedding = node();
@@ -203,50 +276,102 @@ impl Compiler for GatherCompiler {
         let sum_reduce = unary::<SumReduce>(mul.clone());
         let mut s = sum_reduce.clone().search(graph);
         while s.next_match() {
-            if s.check_no_delete(&[embedding.id]) {
+            if s.check_no_delete(&[embedding.id, indexes.id, sum_reduce.id]) {
                 continue;
             }
-            let emb_shape = graph
-                .edges_connecting(s.get(&embedding), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let index_shape = graph
-                .edges_connecting(s.get(&indexes), s.get(&eq))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2;
-            let embed_dim = graph
-                .graph
-                .edges_connecting(s.get(&embedding), s.get(&mul))
-                .next()
-                .unwrap()
-                .weight()
-                .as_data()
-                .unwrap()
-                .2
-                .shape()[2]
-                .to_usize()
-                .unwrap();
+            let edge_shape = |a: &SelectGraph, b: &SelectGraph| {
+                graph
+                    .edges_connecting(s.get(a), s.get(b))
+                    .next()
+                    .unwrap()
+                    .weight()
+                    .as_data()
+                    .unwrap()
+                    .2
+            };
+            let (emb_shape, index_shape) =
+                (edge_shape(&embedding, &mul), edge_shape(&indexes, &eq));
+            if emb_shape.is_padded() {
+                continue;
+            }
+            let embed_dim = emb_shape.shape()[2].to_usize().unwrap();
 
             let gather = graph
-                .add_op(Gather { embed_dim })
+                .add_op(Gather {
+                    embed_dim,
+                    scale: 1.,
+                    cast: None,
+                })
                 .input(s.get(&indexes), 0, index_shape)
                 .input(s.get(&embedding), 0, emb_shape)
                 .finish();
             move_outgoing_edge(s.get(&sum_reduce), gather, &mut graph.graph);
+            remap(s.get(&sum_reduce), gather, &mut ids, graph);
             graph.remove_node(s.get(&sum_reduce));
             s.try_delete();
+            fuse_into_gather(graph, gather, &mut ids);
+        }
+    }
+}
+
+/// Pull scales and casts that are the only consumer of a gather into it, so the rows only get written once
+fn fuse_into_gather<To: ToIdsMut>(graph: &mut Graph, gather: NodeIndex, mut ids: To) {
+    loop {
+        let consumers = graph
+            .edges_directed(gather, Direction::Outgoing)
+            .filter_map(|e| e.weight().as_data().map(|(_, _, s)| (e.target(), s)))
+            .collect::<Vec<_>>();
+        let [(consumer, shape)] = consumers[..] else {
+            return;
+        };
+        let op = graph.get_op::<Gather>(gather).clone();
+        // Anything after a cast has to see the rounded values, so that's as far as fusing goes
+        if graph.no_delete.contains(&gather) || shape.is_reshaped() || op.cast.is_some() {
+            return;
+        }
+        let mut constant = None;
+        let fused = if let Some(Cast(dtype)) = graph.try_get_op::<Cast>(consumer) {
+            Gather {
+                cast: Some(*dtype),
+                ..op
+            }
+        } else if graph.is_op::<Mul>(consumer) {
+            let Some((c, value)) =
+                graph
+                    .get_sources(consumer)
+                    .into_iter()
+                    .find_map(|(src, _, _)| match graph.try_get_op::<Constant>(src) {
+                        Some(Constant(ConstantValue::Float(v))) => Some((src, *v)),
+                        _ => None,
+                    })
+            else {
+                return;
+            };
+            constant = Some(c);
+            Gather {
+                scale: op.scale * value,
+                ..op
+            }
+        } else {
+            return;
+        };
+        *graph.get_op_mut::<Gather>(gather) = fused;
+        move_outgoing_edge(consumer, gather, &mut graph.graph);
+        remap(consumer, gather, &mut ids, graph);
+        graph.remove_node(consumer);
+        if let Some(c) = constant {
+            if !graph.no_delete.contains(&c)
+                && graph.edges_directed(c, Direction::Outgoing).count() == 0
+            {
+                graph.remove_node(c);
+            }
         }
     }
 }
 
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
-    tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
+fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> Cow<'a, [f32]> {
+    tensor
+        .borrowed()
+        .widened_f32s()
+        .unwrap_or_else(|e| panic!("{e}"))
 }
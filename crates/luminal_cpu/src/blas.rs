@@ -0,0 +1,79 @@
+use std::ffi::c_int;
+
+const ROW_MAJOR: c_int = 101;
+const NO_TRANS: c_int = 111;
+const TRANS: c_int = 112;
+
+#[cfg_attr(feature = "accelerate", link(name = "Accelerate", kind = "framework"))]
+#[cfg_attr(feature = "openblas", link(name = "openblas"))]
+#[cfg_attr(feature = "mkl", link(name = "mkl_rt"))]
+extern "C" {
+    #[allow(clippy::too_many_arguments)]
+    fn cblas_sgemm(
+        layout: c_int,
+        trans_a: c_int,
+        trans_b: c_int,
+        m: c_int,
+        n: c_int,
+        k: c_int,
+        alpha: f32,
+        a: *const f32,
+        lda: c_int,
+        b: *const f32,
+        ldb: c_int,
+        beta: f32,
+        c: *mut f32,
+        ldc: c_int,
+    );
+}
+
+/// How BLAS can read a [rows, cols] matrix with these (row, column) strides: transposed or not, and the distance
+/// between the starts of its rows. BLAS needs one of the strides to be 1.
+fn layout(rows: usize, cols: usize, (row, col): (usize, usize)) -> Option<(c_int, c_int)> {
+    // The stride of a dimension of size 1 never gets used
+    if col == 1 || cols == 1 {
+        let ld = if rows == 1 { cols } else { row };
+        (ld >= cols).then_some((NO_TRANS, ld as c_int))
+    } else if row == 1 || rows == 1 {
+        let ld = if cols == 1 { rows } else { col };
+        (ld >= rows).then_some((TRANS, ld as c_int))
+    } else {
+        None
+    }
+}
+
+/// Multiply an [m, k] matrix by a [k, n] matrix with the linked BLAS library, writing a contiguous [m, n] matrix into
+/// `c`. Returns false without doing anything if either input has a layout BLAS can't read.
+pub(crate) fn sgemm(
+    (m, k, n): (usize, usize, usize),
+    a: &[f32],
+    a_strides: (usize, usize),
+    b: &[f32],
+    b_strides: (usize, usize),
+    c: &mut [f32],
+) -> bool {
+    let (Some((trans_a, lda)), Some((trans_b, ldb))) =
+        (layout(m, k, a_strides), layout(k, n, b_strides))
+    else {
+        return false;
+    };
+    unsafe {
+        cblas_sgemm(
+            ROW_MAJOR,
+            trans_a,
+            trans_b,
+            m as c_int,
+            n as c_int,
+            k as c_int,
+            1.0,
+            a.as_ptr(),
+            lda,
+            b.as_ptr(),
+            ldb,
+            0.0,
+            c.as_mut_ptr(),
+            n.max(1) as c_int,
+        );
+    }
+    true
+}
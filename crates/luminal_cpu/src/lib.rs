@@ -1,6 +1,12 @@
 mod binary;
+#[cfg(feature = "blas")]
+mod blas;
 mod matmul;
 mod other;
+mod reduce;
+mod softmax;
+
+pub use matmul::{MatMulAutotuner, MatMulKernel, PackWeights, SetMatMulKernel};
 
 use std::any::Any;
 
@@ -14,14 +20,62 @@ use luminal::{
 
 // Ops and compilers specific to CPU execution
 
-pub type CPUCompiler = (
-    matmul::MatMulCompiler,
-    binary::SubtractionCompiler,
-    binary::EqualCompiler,
-    other::ARangeCompiler,
-    binary::GatherCompiler,
-    UnaryFusionCompiler,
-);
+/// Runs the CPU passes in their default order. Use `CPUCompiler::passes` to reorder, toggle or inspect them.
+#[derive(Debug, Default)]
+pub struct CPUCompiler;
+
+impl CPUCompiler {
+    /// Every CPU pass, by name
+    pub fn passes() -> PassManager {
+        PassManager::new()
+            .add("MatMul", matmul::MatMulCompiler::default())
+            .add("Subtraction", binary::SubtractionCompiler)
+            .add("Equal", binary::EqualCompiler)
+            .add("ARange", other::ARangeCompiler)
+            .add("Gather", binary::GatherCompiler)
+            .add("Softmax", softmax::SoftmaxCompiler)
+            .add("RecomputeIntermediates", RecomputeIntermediates::default())
+            .add("ReduceFusion", reduce::ReduceFusionCompiler)
+            .add("UnaryFusion", UnaryFusionCompiler)
+    }
+}
+
+impl Compiler for CPUCompiler {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, ids: T) {
+        Self::passes().compile(graph, ids);
+    }
+}
+
+/// The host as a backend. Tensors stay in host memory, so compiling for it just runs the CPU passes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CPU;
+
+impl luminal::backend::Device for CPU {
+    type Buffer = Vec<f32>;
+    type Staging = ();
+    const HOST: bool = true;
+    fn alloc(&self, len: usize) -> Vec<f32> {
+        vec![0.; len]
+    }
+    fn copy_to_device(&self, _: &mut (), data: &[f32]) -> Vec<f32> {
+        data.to_vec()
+    }
+    fn copy_from_device(&self, _: &mut (), buffer: &Vec<f32>) -> Vec<f32> {
+        buffer.clone()
+    }
+}
+
+impl Backend for CPU {
+    type Device = CPU;
+    type Compiler = CPUCompiler;
+    fn device(&self) -> CPU {
+        CPU
+    }
+    fn compiler(&self) -> CPUCompiler {
+        CPUCompiler
+    }
+}
 
 pub(crate) fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
@@ -123,6 +177,13 @@ pub struct FusedUnary(Vec<fn(f32) -> f32>);
 impl Operator for FusedUnary {
     fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         let mut t = inp.pop().unwrap().0.cloned();
+        if !t.is::<Vec<f32>>() {
+            t = Tensor::new(
+                t.widened_f32s()
+                    .unwrap_or_else(|e| panic!("{e}"))
+                    .into_owned(),
+            );
+        }
         for a in t.downcast_mut::<Vec<f32>>().unwrap().iter_mut() {
             for f in &self.0 {
                 *a = (f)(*a);
@@ -131,6 +192,9 @@ impl Operator for FusedUnary {
 
         vec![t]
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
 }
 
 #[cfg(test)]
@@ -149,7 +213,7 @@ mod tests {
         let b = cx.tensor::<(Dyn<'K'>, Dyn<'N'>)>();
         let mut c = a.matmul(b).retrieve();
 
-        cx.compile(CPUCompiler::default(), &mut c);
+        cx.compile(CPUCompiler, &mut c);
 
         let d_dev = dfdx::prelude::Cpu::default();
         for m in (1..23).step_by(4) {
@@ -186,8 +250,586 @@ mod tests {
         cx.execute();
 
         let unoptimized_c = c.data();
-        cx.compile(CPUCompiler::default(), &mut c);
+        cx.compile(CPUCompiler, &mut c);
         cx.execute();
         assert_close(&c.data(), &unoptimized_c);
     }
+
+    #[test]
+    fn test_cpu_matmul_views() {
+        use crate::matmul::{BatchedMatMul2D, MatMul2D};
+
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 3>>().set(random_vec(12));
+        let w = cx.tensor::<R2<7, 3>>().set(random_vec(21));
+        let x = cx.tensor::<R3<2, 5, 3>>().set(random_vec(30));
+        let mut outs = (
+            // Linear layer weights, read transposed
+            a.matmul(w.permute()).retrieve(),
+            x.matmul(w.permute()).retrieve(),
+            // Column major A, and slices that don't start at the beginning of the buffer
+            a.permute::<R2<3, 4>, _>().matmul(a).retrieve(),
+            a.slice((1.., 1..))
+                .realize::<R2<3, 2>>()
+                .matmul(w.slice((2..4, 1..)).realize::<R2<2, 2>>().permute())
+                .retrieve(),
+            x.slice((1.., 2.., ..))
+                .realize::<R3<1, 3, 3>>()
+                .matmul(w.slice((3.., ..)).realize::<R2<4, 3>>().permute())
+                .retrieve(),
+            // Padding isn't in memory, so this one gets copied out first
+            a.matmul(w.permute().pad::<R2<3, 8>>(((0, 0), (1, 0))))
+                .retrieve(),
+        );
+        cx.execute();
+        let expected = (
+            outs.0.data(),
+            outs.1.data(),
+            outs.2.data(),
+            outs.3.data(),
+            outs.4.data(),
+            outs.5.data(),
+        );
+
+        cx.compile((GenericCompiler::default(), CPUCompiler), &mut outs);
+        let count =
+            |f: fn(&Graph, NodeIndex) -> bool| cx.node_indices().filter(|n| f(&cx, *n)).count();
+        assert_eq!(count(|cx, n| cx.is_op::<MatMul2D>(n)), 4);
+        assert_eq!(count(|cx, n| cx.is_op::<BatchedMatMul2D>(n)), 2);
+        assert_eq!(count(|cx, n| cx.is_op::<Contiguous>(n)), 1);
+        cx.execute();
+        assert_close(&outs.0.data(), &expected.0);
+        assert_close(&outs.1.data(), &expected.1);
+        assert_close(&outs.2.data(), &expected.2);
+        assert_close(&outs.3.data(), &expected.3);
+        assert_close(&outs.4.data(), &expected.4);
+        assert_close(&outs.5.data(), &expected.5);
+    }
+
+    #[test]
+    fn test_cpu_gemv() {
+        let mut cx = Graph::new();
+        let (w_data, v_data) = (random_vec(19 * 37), random_vec(37));
+        let x = cx.tensor::<(Dyn<'S'>, LConst<37>)>();
+        let w = cx.tensor::<R2<19, 37>>().set(w_data.clone());
+        let v = cx.tensor::<R2<37, 1>>().set(v_data.clone());
+        // Weights read transposed and as is, and a matrix times a column
+        let mut outs = (
+            x.matmul(w.permute()).retrieve(),
+            x.matmul(w.permute::<R2<37, 19>, _>().contiguous())
+                .retrieve(),
+            w.matmul(v).retrieve(),
+        );
+        cx.compile(CPUCompiler, &mut outs);
+
+        // Decoding one token at a time, then a prompt, which goes back to sgemm
+        for s in [1, 4] {
+            let x_data = random_vec(s * 37);
+            x.set_dyn(x_data.clone(), &[s, 37]);
+            cx.execute();
+            let mut expected = vec![0.; s * 19];
+            for i in 0..s {
+                for j in 0..19 {
+                    for p in 0..37 {
+                        expected[i * 19 + j] += x_data[i * 37 + p] * w_data[j * 37 + p];
+                    }
+                }
+            }
+            assert_close(&outs.0.data(), &expected);
+            assert_close(&outs.1.data(), &expected);
+            let expected = (0..19)
+                .map(|j| (0..37).map(|p| w_data[j * 37 + p] * v_data[p]).sum())
+                .collect::<Vec<f32>>();
+            assert_close(&outs.2.data(), &expected);
+            outs.0.drop();
+            outs.1.drop();
+            outs.2.drop();
+        }
+    }
+
+    #[test]
+    fn test_cpu_mixed_precision_matmul() {
+        let mut cx = Graph::new();
+        let (a_data, b_data) = (random_vec(15), random_vec(20));
+        let a = cx.tensor::<R2<3, 5>>().set(a_data.clone());
+        let b = cx.tensor::<R2<5, 4>>().set(b_data.clone());
+        let mut c = a.matmul(b).retrieve();
+        let mut reference = a.cast::<bf16>().matmul(b.cast::<bf16>()).retrieve();
+
+        cx.compile(
+            (AutoCast::new(DType::BF16).skip(reference), CPUCompiler),
+            (&mut c, &mut reference),
+        );
+        // The casts go in front of the matmuls, which read their bf16 buffers and accumulate in f32
+        let count =
+            |f: fn(&Graph, NodeIndex) -> bool| cx.node_indices().filter(|n| f(&cx, *n)).count();
+        assert_eq!(count(|cx, n| cx.is_op::<crate::matmul::MatMul2D>(n)), 2);
+        assert_eq!(count(|cx, n| cx.is_op::<Cast>(n)), 4);
+        cx.execute();
+        assert_close(&c.data(), &reference.data());
+        let round = |x: &f32| bf16::from_f32(*x).to_f32();
+        let expected = (0..3)
+            .flat_map(|i| (0..4).map(move |j| (i, j)))
+            .map(|(i, j)| {
+                (0..5)
+                    .map(|p| round(&a_data[i * 5 + p]) * round(&b_data[p * 4 + j]))
+                    .sum()
+            })
+            .collect::<Vec<f32>>();
+        assert_close(&c.data(), &expected);
+    }
+
+    #[test]
+    fn test_cpu_fuse_shared_unary() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<8>>().set(random_vec(8));
+        let exp = a.exp2();
+        let mut outs = (exp.sin().retrieve(), exp.recip().retrieve());
+        cx.execute();
+        let expected = (outs.0.data(), outs.1.data());
+
+        // The shared exp2 gets copied into both branches, so each fuses into a single op
+        cx.compile(CPUCompiler, &mut outs);
+        assert!(!cx.node_indices().any(|n| cx.is_op::<Exp2>(n)));
+        assert_eq!(
+            cx.node_indices()
+                .filter(|n| cx.is_op::<crate::FusedUnary>(*n))
+                .count(),
+            2
+        );
+        cx.execute();
+        assert_exact(&outs.0.data(), &expected.0);
+        assert_exact(&outs.1.data(), &expected.1);
+    }
+
+    #[test]
+    fn test_cpu_reduce_fusion() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<4, 32>>().set(random_vec(4 * 32));
+        let b = cx.tensor::<R2<4, 32>>().set(random_vec(4 * 32));
+        let mut outs = (
+            (a + b).softmax::<LAxis<1>>().retrieve(),
+            a.layer_norm::<LAxis<1>, _>(1e-5).retrieve(),
+            (a * b).max_reduce::<_, LAxis<0>>().retrieve(),
+            (a - b)
+                .exp2()
+                .permute::<_, LAxes2<1, 0>>()
+                .sum_reduce::<_, LAxis<1>>()
+                .retrieve(),
+        );
+        cx.execute();
+        let expected = (outs.0.data(), outs.1.data(), outs.2.data(), outs.3.data());
+
+        // Softmaxes get their own kernel, so leave them as reductions to fuse into
+        cx.compile(CPUCompiler::passes().disable("Softmax"), &mut outs);
+        // Only the layer norm's mean reads straight from a tensor, so it's the one reduction left unfused
+        let count =
+            |f: fn(&Graph, NodeIndex) -> bool| cx.node_indices().filter(|n| f(&cx, *n)).count();
+        assert_eq!(
+            count(|cx, n| cx.is_op::<SumReduce>(n) || cx.is_op::<MaxReduce>(n)),
+            1
+        );
+        assert_eq!(count(|cx, n| cx.is_op::<crate::reduce::FusedReduce>(n)), 5);
+        cx.execute();
+        assert_close(&outs.0.data(), &expected.0);
+        assert_close(&outs.1.data(), &expected.1);
+        assert_exact(&outs.2.data(), &expected.2);
+        assert_exact(&outs.3.data(), &expected.3);
+    }
+
+    #[test]
+    fn test_cpu_freeze_dynamic() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<(Dyn<'s'>,)>();
+        let y = (x + cx.arange::<Dyn<'s'>>()).retrieve();
+        let model = cx.freeze(CPUCompiler, &[x.no_shape()], &[y.no_shape()]);
+        // The graph the ops were compiled in is gone, so sizes have to come from each run
+        for n in [3, 1, 5] {
+            let out = model.run(&[(&vec![1.; n], &[n])]);
+            assert_exact(&out[0], &(1..=n).map(|i| i as f32).collect::<Vec<_>>());
+        }
+
+        let mut cx = Graph::new();
+        let x = cx.tensor::<(Dyn<'s'>, LConst<3>)>();
+        let y = (x * x).sum_reduce::<_, LAxis<1>>().retrieve();
+        let model = cx.freeze(CPUCompiler, &[x.no_shape()], &[y.no_shape()]);
+        for n in [2, 4] {
+            let data = (0..n * 3).map(|i| i as f32).collect::<Vec<_>>();
+            let out = model.run(&[(&data, &[n, 3])]);
+            let expected = data
+                .chunks(3)
+                .map(|r| r.iter().map(|v| v * v).sum::<f32>())
+                .collect::<Vec<_>>();
+            assert_exact(&out[0], &expected);
+        }
+    }
+
+    #[test]
+    fn test_cpu_softmax() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 40>>().set(random_vec(2 * 3 * 40));
+        let mut outs = (
+            a.softmax::<LAxis<2>>().retrieve(),
+            a.permute::<_, LAxes3<0, 2, 1>>()
+                .softmax::<LAxis<1>>()
+                .retrieve(),
+            // The exp is read by something besides the softmax, so it has to stay around
+            (a.softmax::<LAxis<1>>() + a.exp()).retrieve(),
+        );
+        cx.execute();
+        let expected = (outs.0.data(), outs.1.data(), outs.2.data());
+
+        cx.compile((GenericCompiler::default(), CPUCompiler), &mut outs);
+        assert_eq!(
+            cx.node_indices()
+                .filter(|n| cx.is_op::<crate::softmax::Softmax>(*n))
+                .count(),
+            3
+        );
+        cx.execute();
+        assert_exact(&outs.0.data(), &expected.0);
+        assert_exact(&outs.1.data(), &expected.1);
+        assert_close(&outs.2.data(), &expected.2);
+    }
+
+    #[test]
+    fn test_cpu_gather() {
+        use crate::binary::Gather;
+
+        let (table, indexes) = (random_vec(11 * 37), [3., 0., 10., 3., 7., 1., 1., 9., 4.]);
+        let mut cx = Graph::new();
+        let ids = cx.tensor::<(Dyn<'S'>,)>();
+        let w = cx.tensor::<R2<11, 37>>().set(table.clone());
+        let w_t = cx.tensor::<R2<37, 11>>().set(
+            (0..37 * 11)
+                .map(|i| table[i % 11 * 37 + i / 11])
+                .collect::<Vec<_>>(),
+        );
+        let w_2 = cx.tensor::<R2<11, 37>>().set(table.clone());
+        let looked_up = w_2.gather(ids).retrieve();
+        let mut outs = (
+            (w.gather(ids) * 8.).cast::<bf16>().retrieve(),
+            // Tables stored transposed are read with strides
+            w_t.permute::<R2<11, 37>, _>().gather(ids).retrieve(),
+            // The plain lookup is needed too, so the scale can't go into it
+            (looked_up * 2.).retrieve(),
+            looked_up,
+        );
+        cx.compile((GenericCompiler::default(), CPUCompiler), &mut outs);
+        let count =
+            |f: fn(&Graph, NodeIndex) -> bool| cx.node_indices().filter(|n| f(&cx, *n)).count();
+        assert_eq!(count(|cx, n| cx.is_op::<Gather>(n)), 3);
+        assert_eq!(count(|cx, n| cx.is_op::<Cast>(n)), 0);
+        assert_eq!(count(|cx, n| cx.is_op::<Mul>(n)), 1);
+
+        ids.set_dyn(indexes.to_vec(), &[indexes.len()]);
+        cx.execute();
+        let rows = indexes
+            .iter()
+            .flat_map(|i| table[*i as usize * 37..(*i as usize + 1) * 37].to_vec())
+            .collect::<Vec<_>>();
+        let map = |f: fn(f32) -> f32| rows.iter().map(|x| f(*x)).collect::<Vec<_>>();
+        assert_exact(&outs.0.data(), &map(|x| DType::BF16.round(x * 8.)));
+        assert_exact(&outs.1.data(), &rows);
+        assert_exact(&outs.2.data(), &map(|x| x * 2.));
+        assert_exact(&outs.3.data(), &rows);
+    }
+
+    #[test]
+    fn test_cpu_scatter_gather() {
+        use crate::binary::Gather;
+
+        // Like a paged cache: write a few rows into a pool, then read some slots back
+        let mut cx = Graph::new();
+        let pool = cx
+            .tensor::<(Dyn<'P'>, LConst<3>)>()
+            .set_dyn((0..15).map(|i| i as f32).collect::<Vec<_>>(), &[5, 3]);
+        let write = cx.tensor::<(Dyn<'W'>,)>().set_dyn(vec![4., 1.], &[2]);
+        let rows = cx
+            .tensor::<(Dyn<'W'>, LConst<3>)>()
+            .set_dyn(vec![-1., -2., -3., -4., -5., -6.], &[2, 3]);
+        let read = cx.tensor::<(Dyn<'R'>,)>().set_dyn(vec![1., 2., 4.], &[3]);
+        let mut out = pool.scatter(write, rows).gather(read).retrieve();
+        cx.compile((GenericCompiler::default(), CPUCompiler), &mut out);
+        // Neither direction goes through a one-hot matmul
+        let count =
+            |f: fn(&Graph, NodeIndex) -> bool| cx.node_indices().filter(|n| f(&cx, *n)).count();
+        assert_eq!(count(|cx, n| cx.is_op::<Gather>(n)), 1);
+        assert_eq!(count(|cx, n| cx.is_op::<crate::matmul::MatMul2D>(n)), 0);
+
+        cx.execute();
+        assert_exact(&out.data(), &[-4., -5., -6., 6., 7., 8., -1., -2., -3.]);
+    }
+
+    #[test]
+    fn test_cpu_equal() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<4>>().set(vec![1., 2., 3., 4.]);
+        let b = cx.tensor::<R1<4>>().set(vec![2., 2., 1., 4.]);
+        let mut out = a.equals(b).retrieve();
+        cx.compile((GenericCompiler::default(), CPUCompiler), &mut out);
+        assert!(cx
+            .node_indices()
+            .any(|n| cx.is_op::<crate::binary::Equal>(n)));
+
+        cx.execute();
+        assert_exact(&out.data(), &[0., 1., 0., 1.]);
+    }
+
+    #[test]
+    fn test_cpu_backend() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let mut c = a.matmul(b).exp2().retrieve();
+        cx.execute();
+        let expected = c.data();
+        c.drop();
+
+        cx.compile_for(&crate::CPU, &mut c);
+        assert!(cx
+            .node_indices()
+            .any(|n| cx.is_op::<crate::matmul::MatMul2D>(n)));
+        // Nothing needs copying to run on the host
+        assert!(!cx
+            .node_indices()
+            .any(|n| cx.is_op::<CopyToDevice<crate::CPU>>(n)
+                || cx.is_op::<CopyFromDevice<crate::CPU>>(n)));
+        cx.execute();
+        assert_close(&c.data(), &expected);
+    }
+
+    #[test]
+    fn test_cpu_disable_pass() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let mut c = a.matmul(b).exp2().sin().retrieve();
+        cx.execute();
+        let expected = c.data();
+
+        let steps = cx.compile(CPUCompiler::passes().disable("MatMul").report(true), &mut c);
+        assert!(!steps.iter().any(|s| s.pass == "MatMul"));
+        assert!(!cx
+            .node_indices()
+            .any(|n| cx.is_op::<crate::matmul::MatMul2D>(n)));
+        // The unary ops still get fused
+        let fusion = steps.iter().find(|s| s.pass == "UnaryFusion").unwrap();
+        assert!(!fusion.diff.is_empty());
+        assert!(!cx.node_indices().any(|n| cx.is_op::<Exp2>(n)));
+        cx.execute();
+        assert_close(&c.data(), &expected);
+    }
+
+    #[test]
+    fn test_cpu_deterministic_matmul() {
+        use crate::matmul::MatMul2D;
+        use crate::{MatMulAutotuner, MatMulKernel};
+
+        let (a_data, b_data) = (random_vec(3 * 37), random_vec(37 * 5));
+        let mut cx = Graph::new();
+        cx.set_deterministic(true);
+        let a = cx.tensor::<R2<3, 37>>().set(a_data.clone());
+        let b = cx.tensor::<R2<37, 5>>().set(b_data.clone());
+        let mut c = a.matmul(b).retrieve();
+        cx.compile((CPUCompiler, MatMulAutotuner(Autotuner::new())), &mut c);
+        let matmul = cx
+            .node_indices()
+            .find(|n| cx.is_op::<MatMul2D>(*n))
+            .unwrap();
+        assert_eq!(cx.get_op::<MatMul2D>(matmul).0, MatMulKernel::DETERMINISTIC);
+        cx.execute();
+
+        // Every output is summed in order, exactly like a naive loop
+        let mut expected = vec![0.; 3 * 5];
+        for i in 0..3 {
+            for j in 0..5 {
+                for p in 0..37 {
+                    expected[i * 5 + j] += a_data[i * 37 + p] * b_data[p * 5 + j];
+                }
+            }
+        }
+        assert_exact(&c.data(), &expected);
+    }
+
+    #[test]
+    fn test_cpu_pack_weights() {
+        use crate::matmul::{MatMul2D, PackPanels, PackedMatMul};
+        use crate::PackWeights;
+
+        let mut cx = Graph::new();
+        let (w_data, w_t_data, v_data) = (
+            random_vec(24 * 20),
+            random_vec(20 * 24),
+            random_vec(24 * 20),
+        );
+        let x = cx.tensor::<(Dyn<'S'>, LConst<24>)>();
+        let xb = cx.tensor::<(LConst<2>, Dyn<'S'>, LConst<24>)>();
+        let w = cx.tensor::<R2<24, 20>>().set(w_data.clone());
+        let w_t = cx.tensor::<R2<20, 24>>().set(w_t_data.clone());
+        // Not kept, so it could change between runs and doesn't get packed
+        let v = cx.tensor::<R2<24, 20>>().set(v_data.clone());
+        cx.keep_tensors((w, w_t));
+        let mut outs = (
+            x.matmul(w).retrieve(),
+            x.matmul(w_t.permute()).retrieve(),
+            xb.matmul(w).retrieve(),
+            x.matmul(v).retrieve(),
+        );
+        cx.compile(CPUCompiler, &mut outs);
+        let packed = cx.compile(PackWeights, &mut outs);
+        assert_eq!(packed.len(), 2);
+        let count =
+            |f: fn(&Graph, NodeIndex) -> bool| cx.node_indices().filter(|n| f(&cx, *n)).count();
+        assert_eq!(count(|cx, n| cx.is_op::<PackPanels>(n)), 2);
+        assert_eq!(count(|cx, n| cx.is_op::<PackedMatMul>(n)), 3);
+        assert_eq!(count(|cx, n| cx.is_op::<MatMul2D>(n)), 1);
+
+        // In order sums, matching the packed panels exactly
+        let matmul = |a: &[f32], b: &[f32], m: usize| {
+            let mut c = vec![0.; m * 20];
+            for i in 0..m {
+                for p in 0..24 {
+                    for j in 0..20 {
+                        c[i * 20 + j] += a[i * 24 + p] * b[p * 20 + j];
+                    }
+                }
+            }
+            c
+        };
+        let w_t_data = (0..24 * 20)
+            .map(|i| w_t_data[(i % 20) * 24 + i / 20])
+            .collect::<Vec<_>>();
+        // Decoding, a few rows, then a prompt long enough to go back to sgemm
+        for s in [1, 6, 40] {
+            let (x_data, xb_data) = (random_vec(s * 24), random_vec(2 * s * 24));
+            x.set_dyn(x_data.clone(), &[s, 24]);
+            xb.set_dyn(xb_data.clone(), &[2, s, 24]);
+            cx.execute();
+            assert!(packed.iter().all(|p| cx.tensors.contains_key(&(*p, 0))));
+            let expected = matmul(&x_data, &w_data, s);
+            let batched = [
+                matmul(&xb_data[..s * 24], &w_data, s),
+                matmul(&xb_data[s * 24..], &w_data, s),
+            ]
+            .concat();
+            let transposed = matmul(&x_data, &w_t_data, s);
+            if s <= 32 {
+                assert_exact(&outs.0.data(), &expected);
+                assert_exact(&outs.1.data(), &transposed);
+                assert_exact(&outs.2.data(), &batched);
+            } else {
+                assert_close(&outs.0.data(), &expected);
+                assert_close(&outs.1.data(), &transposed);
+                assert_close(&outs.2.data(), &batched);
+            }
+            assert_close(&outs.3.data(), &matmul(&x_data, &v_data, s));
+            outs.0.drop();
+            outs.1.drop();
+            outs.2.drop();
+            outs.3.drop();
+        }
+
+        // New weights get packed again once the old copies are dropped
+        let w_data = random_vec(24 * 20);
+        cx.drop_tensors(w);
+        w.set(w_data.clone());
+        cx.drop_tensors(&packed);
+        let x_data = random_vec(24);
+        x.set_dyn(x_data.clone(), &[1, 24]);
+        xb.set_dyn(random_vec(48), &[2, 1, 24]);
+        cx.execute();
+        assert_exact(&outs.0.data(), &matmul(&x_data, &w_data, 1));
+    }
+
+    #[test]
+    fn test_cpu_set_matmul_kernel() {
+        use crate::matmul::{BatchedMatMul2D, MatMul2D};
+        use crate::{MatMulKernel, SetMatMulKernel};
+
+        #[cfg(feature = "blas")]
+        let kernel = MatMulKernel::Blas;
+        #[cfg(not(feature = "blas"))]
+        let kernel = MatMulKernel::LoopIjk;
+        for deterministic in [false, true] {
+            let mut cx = Graph::new();
+            cx.set_deterministic(deterministic);
+            let a = cx.tensor::<R3<2, 3, 5>>().set(random_vec(30));
+            let b = cx.tensor::<R2<4, 5>>().set(random_vec(20));
+            let mut outs = (
+                a.matmul(b.permute()).retrieve(),
+                b.matmul(a.slice((..1, .., ..)).reshape::<R2<3, 5>>().permute())
+                    .retrieve(),
+            );
+            cx.execute();
+            let expected = (outs.0.data(), outs.1.data());
+            cx.compile((CPUCompiler, SetMatMulKernel(kernel)), &mut outs);
+            cx.execute();
+            assert_close(&outs.0.data(), &expected.0);
+            assert_close(&outs.1.data(), &expected.1);
+
+            // Deterministic graphs don't get switched off their in-order kernel
+            let expected_kernel = if deterministic {
+                MatMulKernel::DETERMINISTIC
+            } else {
+                kernel
+            };
+            let kernels = cx
+                .node_indices()
+                .filter_map(|n| {
+                    if cx.is_op::<MatMul2D>(n) {
+                        Some(cx.get_op::<MatMul2D>(n).0)
+                    } else if cx.is_op::<BatchedMatMul2D>(n) {
+                        Some(cx.get_op::<BatchedMatMul2D>(n).0)
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(kernels, vec![expected_kernel; 2]);
+        }
+    }
+
+    #[test]
+    fn test_cpu_matmul_kernels() {
+        use crate::matmul::{BatchedMatMul2D, MatMul2D};
+        use crate::{MatMulAutotuner, MatMulKernel};
+
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 5>>().set(random_vec(30));
+        let b = cx.tensor::<R2<4, 5>>().set(random_vec(20));
+        let c = cx.tensor::<R2<5, 4>>().set(random_vec(20));
+        // Transposed and contiguous right hand sides, batched and not
+        let mut outs = (
+            a.matmul(b.permute()).retrieve(),
+            a.slice((..1, .., ..))
+                .reshape::<R2<3, 5>>()
+                .matmul(c)
+                .retrieve(),
+        );
+        cx.execute();
+        let expected = (outs.0.data(), outs.1.data());
+
+        cx.compile((CPUCompiler, MatMulAutotuner(Autotuner::new())), &mut outs);
+        cx.execute();
+        assert_close(&outs.0.data(), &expected.0);
+        assert_close(&outs.1.data(), &expected.1);
+
+        // Every kernel gives the same answer
+        let nodes = cx.node_indices().collect::<Vec<_>>();
+        assert!(nodes.iter().any(|n| cx.is_op::<MatMul2D>(*n)));
+        assert!(nodes.iter().any(|n| cx.is_op::<BatchedMatMul2D>(*n)));
+        for &kernel in MatMulKernel::ALL {
+            for n in &nodes {
+                if cx.is_op::<MatMul2D>(*n) {
+                    cx.get_op_mut::<MatMul2D>(*n).0 = kernel;
+                } else if cx.is_op::<BatchedMatMul2D>(*n) {
+                    cx.get_op_mut::<BatchedMatMul2D>(*n).0 = kernel;
+                }
+            }
+            cx.execute();
+            assert_close(&outs.0.data(), &expected.0);
+            assert_close(&outs.1.data(), &expected.1);
+        }
+    }
 }
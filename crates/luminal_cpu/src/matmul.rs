@@ -1,7 +1,10 @@
+use std::fmt::Display;
+
 use luminal::{
-    op::{InputTensor, Mul, Operator, SumReduce},
-    prelude::*,
+    op::{Contiguous, InputTensor, Mul, Operator, SumReduce},
+    prelude::{petgraph::Direction, *},
 };
+use rustc_hash::FxHashMap;
 
 pub type MatMulCompiler = (MatMul2DCompiler, BatchMatMul2DCompiler);
 
@@ -40,10 +43,11 @@ impl Compiler for MatMul2DCompiler {
             srcs[0].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
+            let (a, b) = (strided(graph, srcs[0]), strided(graph, srcs[1]));
             let new_op = graph
-                .add_op(MatMul2D)
-                .input(srcs[0].0, 0, srcs[0].2)
-                .input(srcs[1].0, 0, srcs[1].2)
+                .add_op(MatMul2D(MatMulKernel::for_graph(graph)))
+                .input(a.0, a.1, a.2)
+                .input(b.0, b.1, b.2)
                 .finish();
 
             // Create edges to dests
@@ -58,37 +62,298 @@ impl Compiler for MatMul2DCompiler {
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct MatMul2D;
+/// Ways of multiplying two matrices, which `MatMulAutotuner` picks between
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatMulKernel {
+    /// Packed and blocked sgemm, best for all but the smallest matrices. Switches to a matrix-vector product when A has
+    /// one row or B has one column.
+    #[default]
+    Sgemm,
+    /// Scale rows of B into each row of C, which streams through B when its rows are contiguous
+    LoopIkj,
+    /// Dot each row of A with each column of B, which streams through B when it's transposed
+    LoopIjk,
+    /// The vendor BLAS library picked with the `accelerate`, `openblas` or `mkl` feature. Inputs BLAS can't read in place
+    /// go through sgemm instead.
+    #[cfg(feature = "blas")]
+    Blas,
+}
+
+impl MatMulKernel {
+    pub const ALL: &'static [MatMulKernel] = &[
+        Self::Sgemm,
+        Self::LoopIkj,
+        Self::LoopIjk,
+        #[cfg(feature = "blas")]
+        Self::Blas,
+    ];
+
+    /// Kernel for deterministic graphs. Sgemm's blocking and inner kernel depend on the CPU's features, so its results
+    /// can differ in the last bits between machines, while this one always sums each output in order.
+    pub const DETERMINISTIC: MatMulKernel = Self::LoopIkj;
+
+    /// Kernel new matmuls in a graph start out with
+    fn for_graph(graph: &Graph) -> Self {
+        if graph.deterministic {
+            Self::DETERMINISTIC
+        } else {
+            Self::default()
+        }
+    }
+
+    /// Multiply an [m, k] matrix by a [k, n] matrix, writing a contiguous [m, n] matrix into `c`. Strides are given as
+    /// (row, column).
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        &self,
+        (m, k, n): (usize, usize, usize),
+        a: &[f32],
+        (a_row, a_col): (usize, usize),
+        b: &[f32],
+        (b_row, b_col): (usize, usize),
+        c: &mut [f32],
+    ) {
+        match self {
+            #[cfg(feature = "blas")]
+            Self::Blas
+                if crate::blas::sgemm((m, k, n), a, (a_row, a_col), b, (b_row, b_col), c) => {}
+            #[cfg(feature = "blas")]
+            Self::Blas => Self::Sgemm.run((m, k, n), a, (a_row, a_col), b, (b_row, b_col), c),
+            // Sgemm's packing costs more than it saves when there's only one row or column, like while decoding
+            Self::Sgemm if m == 1 => gemv((n, k), b, (b_col, b_row), a, a_col, c),
+            Self::Sgemm if n == 1 => gemv((m, k), a, (a_row, a_col), b, b_row, c),
+            Self::Sgemm => unsafe {
+                matrixmultiply::sgemm(
+                    m,
+                    k,
+                    n,
+                    1.0,
+                    a.as_ptr(),
+                    a_row as isize,
+                    a_col as isize,
+                    b.as_ptr(),
+                    b_row as isize,
+                    b_col as isize,
+                    0.0,
+                    c.as_mut_ptr(),
+                    n as isize,
+                    1,
+                );
+            },
+            Self::LoopIkj => {
+                c[..m * n].fill(0.);
+                for i in 0..m {
+                    let c_row = &mut c[i * n..(i + 1) * n];
+                    for p in 0..k {
+                        let a = a[i * a_row + p * a_col];
+                        for (j, c) in c_row.iter_mut().enumerate() {
+                            *c += a * b[p * b_row + j * b_col];
+                        }
+                    }
+                }
+            }
+            Self::LoopIjk => {
+                for i in 0..m {
+                    for j in 0..n {
+                        c[i * n + j] = (0..k)
+                            .map(|p| a[i * a_row + p * a_col] * b[p * b_row + j * b_col])
+                            .sum();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Multiply a [rows, k] matrix by a vector, writing `rows` contiguous outputs
+fn gemv(
+    (rows, k): (usize, usize),
+    mat: &[f32],
+    (mat_row, mat_col): (usize, usize),
+    vec: &[f32],
+    vec_stride: usize,
+    out: &mut [f32],
+) {
+    if mat_col == 1 && vec_stride == 1 {
+        // Each output is a dot product of two contiguous runs
+        let vec = &vec[..k];
+        for (r, out) in out[..rows].iter_mut().enumerate() {
+            *out = dot(&mat[r * mat_row..r * mat_row + k], vec);
+        }
+    } else if mat_row == 1 {
+        // Columns are contiguous, so add each one in scaled by its element of the vector
+        out[..rows].fill(0.);
+        for p in 0..k {
+            let v = vec[p * vec_stride];
+            let col = &mat[p * mat_col..p * mat_col + rows];
+            for (out, m) in out.iter_mut().zip(col) {
+                *out += v * m;
+            }
+        }
+    } else {
+        for (r, out) in out[..rows].iter_mut().enumerate() {
+            *out = (0..k)
+                .map(|p| mat[r * mat_row + p * mat_col] * vec[p * vec_stride])
+                .sum();
+        }
+    }
+}
+
+/// Dot product over independent lanes, so it vectorizes
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    const LANES: usize = 8;
+    let mut acc = [0.; LANES];
+    let (a_chunks, b_chunks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+    let tail = a_chunks
+        .remainder()
+        .iter()
+        .zip(b_chunks.remainder())
+        .map(|(a, b)| a * b)
+        .sum::<f32>();
+    for (a, b) in a_chunks.zip(b_chunks) {
+        for i in 0..LANES {
+            acc[i] += a[i] * b[i];
+        }
+    }
+    acc.iter().sum::<f32>() + tail
+}
+
+impl Display for MatMulKernel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Sgemm => "sgemm",
+                Self::LoopIkj => "loop_ikj",
+                Self::LoopIjk => "loop_ijk",
+                #[cfg(feature = "blas")]
+                Self::Blas => "blas",
+            }
+        )
+    }
+}
+
+pub(crate) fn usizes(exprs: Vec<BigExpression>) -> Vec<usize> {
+    exprs.into_iter().map(|e| e.to_usize().unwrap()).collect()
+}
+
+/// A matmul input the kernels can read in place. Permuted and sliced views are just strides and an offset, so weights
+/// used transposed (`x @ w.T`) go straight in, but padding has to be written out first.
+fn strided(
+    graph: &mut Graph,
+    (node, output, shape): (NodeIndex, u8, ShapeTracker),
+) -> (NodeIndex, u8, ShapeTracker) {
+    if !shape.is_padded() {
+        return (node, output, shape);
+    }
+    let copy = graph.add_op(Contiguous).input(node, output, shape).finish();
+    (copy, 0, shape.contiguous())
+}
+
+/// Where a strided view starts in its buffer
+pub(crate) fn offset(shape: &ShapeTracker) -> usize {
+    usizes(shape.strides())
+        .into_iter()
+        .zip(&shape.indexes)
+        .map(|(stride, i)| stride * shape.mask[*i].0.to_usize().unwrap())
+        .sum()
+}
+
+/// A matmul input in the format it's stored in, starting at its view's offset
+#[derive(Clone, Copy)]
+enum Elements<'a> {
+    F32(&'a [f32]),
+    F16(&'a [f16]),
+    BF16(&'a [bf16]),
+}
+
+impl<'a> Elements<'a> {
+    fn of(tensor: &'a Tensor, offset: usize) -> Self {
+        if let Some(data) = tensor.downcast_ref::<Vec<f16>>() {
+            Self::F16(&data[offset..])
+        } else if let Some(data) = tensor.downcast_ref::<Vec<bf16>>() {
+            Self::BF16(&data[offset..])
+        } else {
+            Self::F32(&tensor.f32s().unwrap_or_else(|e| panic!("{e}"))[offset..])
+        }
+    }
+
+    fn skip(self, n: usize) -> Self {
+        match self {
+            Self::F32(d) => Self::F32(&d[n..]),
+            Self::F16(d) => Self::F16(&d[n..]),
+            Self::BF16(d) => Self::BF16(&d[n..]),
+        }
+    }
+
+    #[inline]
+    fn get(&self, i: usize) -> f32 {
+        match self {
+            Self::F32(d) => d[i],
+            Self::F16(d) => d[i].to_f32(),
+            Self::BF16(d) => d[i].to_f32(),
+        }
+    }
+}
+
+/// Mixed precision matmul of inputs stored in any format, widening each element as it's read and accumulating in f32
+fn mixed_matmul(
+    (m, k, n): (usize, usize, usize),
+    a: Elements,
+    (a_row, a_col): (usize, usize),
+    b: Elements,
+    (b_row, b_col): (usize, usize),
+    c: &mut [f32],
+) {
+    for i in 0..m {
+        let c = &mut c[i * n..(i + 1) * n];
+        for p in 0..k {
+            let a = a.get(i * a_row + p * a_col);
+            for (j, c) in c.iter_mut().enumerate() {
+                *c += a * b.get(p * b_row + j * b_col);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct MatMul2D(pub MatMulKernel);
 
 impl Operator for MatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
-        let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
-        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let b_data = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let mut c = vec![0.; a_shape[0].to_usize().unwrap() * b_shape[1].to_usize().unwrap()];
-        unsafe {
-            matrixmultiply::sgemm(
-                a_shape[0].to_usize().unwrap(),
-                a_shape[1].to_usize().unwrap(),
-                b_shape[1].to_usize().unwrap(),
-                1.0,
-                a_data.as_ptr(),
-                a_strides[0].to_usize().unwrap() as isize,
-                a_strides[1].to_usize().unwrap() as isize,
-                b_data.as_ptr(),
-                b_strides[0].to_usize().unwrap() as isize,
-                b_strides[1].to_usize().unwrap() as isize,
-                0.0,
-                c.as_mut_ptr(),
-                b_shape[1].to_usize().unwrap() as isize,
-                1,
+        let (a_shape, b_shape) = (usizes(inp[0].1.shape()), usizes(inp[1].1.shape()));
+        let (a_strides, b_strides) = (usizes(inp[0].1.strides()), usizes(inp[1].1.strides()));
+        let (a, b) = (
+            Elements::of(inp[0].0.borrowed(), offset(&inp[0].1)),
+            Elements::of(inp[1].0.borrowed(), offset(&inp[1].1)),
+        );
+        let mut c = vec![0.; a_shape[0] * b_shape[1]];
+        let (Elements::F32(a_data), Elements::F32(b_data)) = (a, b) else {
+            mixed_matmul(
+                (a_shape[0], a_shape[1], b_shape[1]),
+                a,
+                (a_strides[0], a_strides[1]),
+                b,
+                (b_strides[0], b_strides[1]),
+                &mut c,
             );
-        }
+            return vec![Tensor::new(c)];
+        };
+        self.0.run(
+            (a_shape[0], a_shape[1], b_shape[1]),
+            a_data,
+            (a_strides[0], a_strides[1]),
+            b_data,
+            (b_strides[0], b_strides[1]),
+            &mut c,
+        );
 
         vec![Tensor::new(c)]
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
 }
 
 #[derive(Debug, Default)]
@@ -127,10 +392,11 @@ impl Compiler for BatchMatMul2DCompiler {
             srcs[1].2.remove_dim(1);
             srcs[1].2.remove_dim(0);
             srcs[1].2.permute(&[1, 0]);
+            let (a, b) = (strided(graph, srcs[0]), strided(graph, srcs[1]));
             let new_op = graph
-                .add_op(BatchedMatMul2D)
-                .input(srcs[0].0, 0, srcs[0].2)
-                .input(srcs[1].0, 0, srcs[1].2)
+                .add_op(BatchedMatMul2D(MatMulKernel::for_graph(graph)))
+                .input(a.0, a.1, a.2)
+                .input(b.0, b.1, b.2)
                 .finish();
 
             // Create edges to dests
@@ -145,45 +411,372 @@ impl Compiler for BatchMatMul2DCompiler {
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct BatchedMatMul2D;
+#[derive(Debug, Default, PartialEq)]
+pub struct BatchedMatMul2D(pub MatMulKernel);
 
 // ABCxCD -> ABD
 impl Operator for BatchedMatMul2D {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (a_shape, b_shape) = (inp[0].1.shape(), inp[1].1.shape());
-        let (a_strides, b_strides) = (inp[0].1.strides(), inp[1].1.strides());
-        let a_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let b_data = inp[1].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let mut c = vec![
-            0.;
-            a_shape[0].to_usize().unwrap()
-                * a_shape[1].to_usize().unwrap()
-                * b_shape[1].to_usize().unwrap()
-        ];
-
-        let mat_size = a_shape[1].to_usize().unwrap() * b_shape[1].to_usize().unwrap();
-        for i in 0..a_shape[0].to_usize().unwrap() {
-            unsafe {
-                matrixmultiply::sgemm(
-                    a_shape[1].to_usize().unwrap(),
-                    a_shape[2].to_usize().unwrap(),
-                    b_shape[1].to_usize().unwrap(),
-                    1.0,
-                    a_data.as_ptr().add(i * a_strides[0].to_usize().unwrap()),
-                    a_strides[1].to_usize().unwrap() as isize,
-                    a_strides[2].to_usize().unwrap() as isize,
-                    b_data.as_ptr(),
-                    b_strides[0].to_usize().unwrap() as isize,
-                    b_strides[1].to_usize().unwrap() as isize,
-                    0.0,
-                    c.as_mut_ptr().add(i * mat_size),
-                    b_shape[1].to_usize().unwrap() as isize,
-                    1,
+        let (a_shape, b_shape) = (usizes(inp[0].1.shape()), usizes(inp[1].1.shape()));
+        let (a_strides, b_strides) = (usizes(inp[0].1.strides()), usizes(inp[1].1.strides()));
+        let (a, b) = (
+            Elements::of(inp[0].0.borrowed(), offset(&inp[0].1)),
+            Elements::of(inp[1].0.borrowed(), offset(&inp[1].1)),
+        );
+        let mat_size = a_shape[1] * b_shape[1];
+        let mut c = vec![0.; a_shape[0] * mat_size];
+        let (Elements::F32(a_data), Elements::F32(b_data)) = (a, b) else {
+            for (i, c) in c.chunks_exact_mut(mat_size).enumerate() {
+                mixed_matmul(
+                    (a_shape[1], a_shape[2], b_shape[1]),
+                    a.skip(i * a_strides[0]),
+                    (a_strides[1], a_strides[2]),
+                    b,
+                    (b_strides[0], b_strides[1]),
+                    c,
                 );
             }
+            return vec![Tensor::new(c)];
+        };
+
+        for (i, c) in c.chunks_exact_mut(mat_size).enumerate() {
+            self.0.run(
+                (a_shape[1], a_shape[2], b_shape[1]),
+                &a_data[i * a_strides[0]..],
+                (a_strides[1], a_strides[2]),
+                b_data,
+                (b_strides[0], b_strides[1]),
+                c,
+            );
         }
 
         vec![Tensor::new(c)]
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Times every `MatMulKernel` on the shapes and layouts each matmul in the graph runs with, and switches it to the
+/// fastest. Matmuls with dynamic dimensions that aren't set yet keep the default kernel, and deterministic graphs aren't
+/// tuned at all. Run this after `CPUCompiler`.
+#[derive(Debug, Default)]
+pub struct MatMulAutotuner(pub Autotuner);
+
+impl Compiler for MatMulAutotuner {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        if graph.deterministic {
+            return;
+        }
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let batched = graph.is_op::<BatchedMatMul2D>(node);
+            if !batched && !graph.is_op::<MatMul2D>(node) {
+                continue;
+            }
+            let mut inputs = vec![];
+            for (_, _, mut shape) in graph.get_sources(node) {
+                shape.resolve_global_dyn_dims(&graph.dyn_map);
+                if shape.shape().iter().any(|d| d.to_usize().is_none()) {
+                    break;
+                }
+                let data = vec![1.; shape.n_physical_elements().to_usize().unwrap()];
+                inputs.push((Tensor::new(data), shape));
+            }
+            if inputs.len() != 2 {
+                continue;
+            }
+
+            // Kernels do best on different layouts, so tell apart inputs that are transposed
+            let layout = |s: &ShapeTracker| {
+                let strides = usizes(s.strides());
+                if strides.last() == Some(&1) {
+                    "row"
+                } else {
+                    "col"
+                }
+            };
+            let key = TuneKey::new(
+                "cpu",
+                &format!(
+                    "{}({},{})",
+                    if batched {
+                        "BatchedMatMul2D"
+                    } else {
+                        "MatMul2D"
+                    },
+                    layout(&inputs[0].1),
+                    layout(&inputs[1].1)
+                ),
+                inputs.iter().map(|(_, s)| s.shape_usize()).collect(),
+            );
+            let kernel = self.0.tune(&key, MatMulKernel::ALL, |kernel| {
+                let inputs = inputs
+                    .iter()
+                    .map(|(t, s)| (InputTensor::Borrowed(t), *s))
+                    .collect();
+                if batched {
+                    BatchedMatMul2D(*kernel).process(inputs);
+                } else {
+                    MatMul2D(*kernel).process(inputs);
+                }
+            });
+            if batched {
+                graph.get_op_mut::<BatchedMatMul2D>(node).0 = kernel;
+            } else {
+                graph.get_op_mut::<MatMul2D>(node).0 = kernel;
+            }
+        }
+    }
+}
+
+/// Runs every matmul in the graph with one kernel, like `MatMulKernel::Blas` to hand them all to the BLAS library, or
+/// `MatMulKernel::Sgemm` to keep a graph on the pure Rust one. Deterministic graphs keep their kernel. Run this after
+/// `CPUCompiler`.
+#[derive(Debug, Default)]
+pub struct SetMatMulKernel(pub MatMulKernel);
+
+impl Compiler for SetMatMulKernel {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        if graph.deterministic {
+            return;
+        }
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            if graph.is_op::<MatMul2D>(node) {
+                graph.get_op_mut::<MatMul2D>(node).0 = self.0;
+            } else if graph.is_op::<BatchedMatMul2D>(node) {
+                graph.get_op_mut::<BatchedMatMul2D>(node).0 = self.0;
+            }
+        }
+    }
+}
+
+/// Columns in each panel of a packed matrix
+const PANEL: usize = 16;
+/// Rows of A multiplied against a panel at once, sharing each load of it
+const PANEL_ROWS: usize = 4;
+
+/// Copies a [k, n] matrix into panels of `PANEL` columns, each stored row by row and the last one zero padded, so
+/// `PackedMatMul` can stream through it whatever layout it started in
+#[derive(Debug, Default, PartialEq)]
+pub struct PackPanels;
+
+impl Operator for PackPanels {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (k, n) = match usizes(inp[0].1.shape())[..] {
+            [k, n] => (k, n),
+            _ => unreachable!(),
+        };
+        let strides = usizes(inp[0].1.strides());
+        // Half precision weights get widened as they're packed
+        let b = Elements::of(inp[0].0.borrowed(), offset(&inp[0].1));
+        let mut packed = vec![0.; n.div_ceil(PANEL) * k * PANEL];
+        for (panel, out) in packed.chunks_exact_mut(k * PANEL).enumerate() {
+            let cols = panel * PANEL..n.min((panel + 1) * PANEL);
+            for (p, out) in out.chunks_exact_mut(PANEL).enumerate() {
+                for (out, j) in out.iter_mut().zip(cols.clone()) {
+                    *out = b.get(p * strides[0] + j * strides[1]);
+                }
+            }
+        }
+        vec![Tensor::new(packed)]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// A matmul with B both as it is and packed by `PackPanels`, and A optionally batched. Up to `PACKED_MAX_ROWS` rows of
+/// A are multiplied by the packed panels, which skips packing B on every run. Sgemm's inner kernel is faster than the
+/// panels' on more rows, so those go to the kernel on the unpacked B unless it's `MatMulKernel::DETERMINISTIC`. Panels
+/// sum each output in order, so their results match that kernel's exactly.
+#[derive(Debug, Default, PartialEq)]
+pub struct PackedMatMul(pub MatMulKernel);
+
+/// Most rows of A the packed panels are used for
+const PACKED_MAX_ROWS: usize = 32;
+
+impl Operator for PackedMatMul {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (a_shape, a_strides) = (usizes(inp[0].1.shape()), usizes(inp[0].1.strides()));
+        let (b_shape, b_strides) = (usizes(inp[1].1.shape()), usizes(inp[1].1.strides()));
+        // Unbatched inputs are a batch of one
+        let (batch, a_batch) = if a_shape.len() == 3 {
+            (a_shape[0], a_strides[0])
+        } else {
+            (1, 0)
+        };
+        let (m, k, n) = (a_shape[a_shape.len() - 2], b_shape[0], b_shape[1]);
+        let a_stride = (a_strides[a_shape.len() - 2], a_strides[a_shape.len() - 1]);
+        let a_elements = Elements::of(inp[0].0.borrowed(), offset(&inp[0].1));
+        let b_elements = Elements::of(inp[1].0.borrowed(), offset(&inp[1].1));
+        let mut c = vec![0.; batch * m * n];
+        if c.is_empty() || k == 0 {
+            return vec![Tensor::new(c)];
+        }
+        let use_panels = m <= PACKED_MAX_ROWS || self.0 == MatMulKernel::DETERMINISTIC;
+        let a = match (a_elements, b_elements) {
+            // Panels are packed in f32, whatever format B is stored in
+            (Elements::F32(a), _) if use_panels => a,
+            (Elements::F32(a), Elements::F32(b)) => {
+                for (i, c) in c.chunks_exact_mut(m * n).enumerate() {
+                    self.0.run(
+                        (m, k, n),
+                        &a[i * a_batch..],
+                        a_stride,
+                        b,
+                        (b_strides[0], b_strides[1]),
+                        c,
+                    );
+                }
+                return vec![Tensor::new(c)];
+            }
+            _ => {
+                for (i, c) in c.chunks_exact_mut(m * n).enumerate() {
+                    mixed_matmul(
+                        (m, k, n),
+                        a_elements.skip(i * a_batch),
+                        a_stride,
+                        b_elements,
+                        (b_strides[0], b_strides[1]),
+                        c,
+                    );
+                }
+                return vec![Tensor::new(c)];
+            }
+        };
+        let panels = inp[2].0.borrowed().as_f32_slice().unwrap();
+        for (i, c) in c.chunks_exact_mut(m * n).enumerate() {
+            let a = &a[i * a_batch..];
+            for (panel, b) in panels.chunks_exact(k * PANEL).enumerate() {
+                let mut row = 0;
+                while row + PANEL_ROWS <= m {
+                    panel_rows::<PANEL_ROWS>((row, panel), n, a, a_stride, b, c);
+                    row += PANEL_ROWS;
+                }
+                for row in row..m {
+                    panel_rows::<1>((row, panel), n, a, a_stride, b, c);
+                }
+            }
+        }
+        vec![Tensor::new(c)]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Multiply `R` rows of A, starting at `row`, by one panel, keeping the outputs in registers until they're done
+fn panel_rows<const R: usize>(
+    (row, panel): (usize, usize),
+    n: usize,
+    a: &[f32],
+    (a_row, a_col): (usize, usize),
+    b: &[f32],
+    c: &mut [f32],
+) {
+    #[cfg(target_arch = "x86_64")]
+    if std::arch::is_x86_feature_detected!("avx") {
+        // Wider vectors, but still a separate multiply and add, so results stay the same as without
+        return unsafe { panel_rows_avx::<R>((row, panel), n, a, (a_row, a_col), b, c) };
+    }
+    panel_rows_inner::<R>((row, panel), n, a, (a_row, a_col), b, c)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx")]
+unsafe fn panel_rows_avx<const R: usize>(
+    (row, panel): (usize, usize),
+    n: usize,
+    a: &[f32],
+    (a_row, a_col): (usize, usize),
+    b: &[f32],
+    c: &mut [f32],
+) {
+    panel_rows_inner::<R>((row, panel), n, a, (a_row, a_col), b, c)
+}
+
+#[inline(always)]
+fn panel_rows_inner<const R: usize>(
+    (row, panel): (usize, usize),
+    n: usize,
+    a: &[f32],
+    (a_row, a_col): (usize, usize),
+    b: &[f32],
+    c: &mut [f32],
+) {
+    let mut acc = [[0.; PANEL]; R];
+    let a_rows: [&[f32]; R] = std::array::from_fn(|r| &a[(row + r) * a_row..]);
+    for (p, b) in b.chunks_exact(PANEL).enumerate() {
+        let b: &[f32; PANEL] = b.try_into().unwrap();
+        for (acc, a) in acc.iter_mut().zip(&a_rows) {
+            let a = a[p * a_col];
+            for j in 0..PANEL {
+                acc[j] += a * b[j];
+            }
+        }
+    }
+    let cols = panel * PANEL..n.min((panel + 1) * PANEL);
+    for (r, acc) in acc.iter().enumerate() {
+        let start = (row + r) * n;
+        c[start + cols.start..start + cols.end].copy_from_slice(&acc[..cols.len()]);
+    }
+}
+
+/// Packs the weights of every matmul once, so executions don't each pay for sgemm packing them again, which is most of
+/// the work when decoding a token at a time. Weights are the B inputs that were kept with `Graph::keep_tensors` and
+/// aren't computed from anything, which is how loaders mark them. The packed copies are pinned, so they're made on the
+/// next execution and kept after that, taking as much memory again as the weights they come from.
+///
+/// Returns the packed copies. Changing a weight after they're made means dropping them with `Graph::drop_tensors`, so
+/// they get packed again. Run this after `CPUCompiler`, and after any pass that picks matmul kernels.
+#[derive(Debug, Default)]
+pub struct PackWeights;
+
+impl Compiler for PackWeights {
+    type Output = Vec<NodeIndex>;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) -> Vec<NodeIndex> {
+        let mut packed = FxHashMap::default();
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            if !graph.is_op::<MatMul2D>(node) && !graph.is_op::<BatchedMatMul2D>(node) {
+                continue;
+            }
+            let kernel = if let Some(MatMul2D(kernel)) = graph.try_get_op(node) {
+                *kernel
+            } else {
+                graph.get_op::<BatchedMatMul2D>(node).0
+            };
+            let srcs = graph.get_sources(node);
+            let (weight, output, shape) = srcs[1];
+            if !graph.no_delete.contains(&weight)
+                || graph
+                    .edges_directed(weight, Direction::Incoming)
+                    .next()
+                    .is_some()
+                || shape.shape().iter().any(|d| d.to_usize().is_none())
+            {
+                continue;
+            }
+            // Weights shared between matmuls get packed once
+            let pack = *packed.entry((weight, output, shape)).or_insert_with(|| {
+                graph
+                    .add_op(PackPanels)
+                    .input(weight, output, shape)
+                    .finish()
+            });
+            let new_op = graph
+                .add_op(PackedMatMul(kernel))
+                .input(srcs[0].0, srcs[0].1, srcs[0].2)
+                .input(weight, output, shape)
+                .input(pack, 0, shape.contiguous())
+                .finish();
+            move_outgoing_edge(node, new_op, graph);
+            remap(node, new_op, &mut ids, graph);
+            graph.remove_node(node);
+        }
+        let packed = packed.into_values().collect::<Vec<_>>();
+        graph.pin_tensors(&packed);
+        packed
+    }
 }
@@ -0,0 +1,373 @@
+use std::fmt::Debug;
+
+use luminal::{
+    op::*,
+    prelude::{petgraph::Direction, *},
+};
+use rustc_hash::FxHashMap;
+
+use super::binary::Sub;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReduceKind {
+    Sum,
+    Max,
+}
+
+/// Elementwise ops that can be computed inside a reduction loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Elementwise {
+    Exp2,
+    Log2,
+    Sin,
+    Recip,
+    Sqrt,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    LessThan,
+    Select,
+}
+
+impl Elementwise {
+    fn from_op(op: &dyn Operator) -> Option<Self> {
+        let any = op.as_any();
+        Some(if any.is::<Exp2>() {
+            Self::Exp2
+        } else if any.is::<Log2>() {
+            Self::Log2
+        } else if any.is::<Sin>() {
+            Self::Sin
+        } else if any.is::<Recip>() {
+            Self::Recip
+        } else if any.is::<Sqrt>() {
+            Self::Sqrt
+        } else if any.is::<Add>() {
+            Self::Add
+        } else if any.is::<Sub>() {
+            Self::Sub
+        } else if any.is::<Mul>() {
+            Self::Mul
+        } else if any.is::<Mod>() {
+            Self::Mod
+        } else if any.is::<LessThan>() {
+            Self::LessThan
+        } else if any.is::<Select>() {
+            Self::Select
+        } else {
+            return None;
+        })
+    }
+
+    fn apply(&self, a: f32, b: f32) -> f32 {
+        match self {
+            Self::Exp2 => a.exp2(),
+            Self::Log2 => a.log2(),
+            Self::Sin => a.sin(),
+            Self::Recip => a.recip(),
+            Self::Sqrt => a.sqrt(),
+            Self::Add => a + b,
+            Self::Sub => a - b,
+            Self::Mul => a * b,
+            Self::Mod => a % b,
+            Self::LessThan => (a < b) as i32 as f32,
+            Self::Select => {
+                if b != 0. {
+                    a
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum FusedNode {
+    /// An input of the fused op
+    Input(usize),
+    Constant(f32),
+    /// An elementwise op, reading each of its sources through the view on the edge it replaced
+    Op(Elementwise, Vec<(usize, ShapeTracker)>),
+}
+
+/// A reduction computing its input on the fly from a tree of elementwise ops, instead of reading it from memory
+#[derive(Clone, PartialEq)]
+pub struct FusedReduce {
+    pub kind: ReduceKind,
+    pub dim: usize,
+    /// View the reduction reads the root of the tree through
+    pub shape: ShapeTracker,
+    /// The tree, with the root last
+    pub nodes: Vec<FusedNode>,
+}
+
+impl FusedReduce {
+    fn fmt_node(&self, node: usize, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.nodes[node] {
+            FusedNode::Input(i) => write!(f, "in{i}"),
+            FusedNode::Constant(c) => write!(f, "{c:?}"),
+            FusedNode::Op(op, srcs) => {
+                write!(f, "{op:?}(")?;
+                for (i, (src, _)) in srcs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    self.fmt_node(*src, f)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+
+    /// Value of `node` at `index` in its output
+    fn eval(
+        &self,
+        node: usize,
+        index: usize,
+        inputs: &[&[f32]],
+        exprs: &[Vec<(BigExpression, BigExpression)>],
+        stack: &mut Vec<i64>,
+    ) -> f32 {
+        match &self.nodes[node] {
+            FusedNode::Input(i) => inputs[*i][index],
+            FusedNode::Constant(c) => *c,
+            FusedNode::Op(op, srcs) => {
+                let mut args = [0.; 2];
+                for (arg, ((src, _), (ind, val))) in
+                    args.iter_mut().zip(srcs.iter().zip(&exprs[node]))
+                {
+                    if val.exec_single_var_stack(index, stack) != 0 {
+                        let index = ind.exec_single_var_stack(index, stack);
+                        *arg = self.eval(*src, index, inputs, exprs, stack);
+                    }
+                }
+                op.apply(args[0], args[1])
+            }
+        }
+    }
+}
+
+impl Debug for FusedReduce {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fused{:?}Reduce({}, ", self.kind, self.dim)?;
+        self.fmt_node(self.nodes.len() - 1, f)?;
+        write!(f, ")")
+    }
+}
+
+impl Operator for FusedReduce {
+    fn infer_shape(&self, _: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut shape = self.shape.contiguous();
+        shape.remove_dim(self.dim);
+        Some(shape)
+    }
+
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let resolve = |shape: &ShapeTracker| {
+            let mut shape = *shape;
+            shape.resolve_global_dyn_dims(dyn_map);
+            shape
+        };
+        let exprs = self
+            .nodes
+            .iter()
+            .map(|n| match n {
+                FusedNode::Op(_, srcs) => srcs
+                    .iter()
+                    .map(|(_, s)| {
+                        let s = resolve(s);
+                        (s.index_expression(), s.valid_expression())
+                    })
+                    .collect(),
+                _ => vec![],
+            })
+            .collect::<Vec<_>>();
+        let inputs = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().widened_f32s())
+            .collect::<Result<Vec<_>, _>>()?;
+        let inputs = inputs.iter().map(|i| i.as_ref()).collect::<Vec<_>>();
+        let shape = resolve(&self.shape);
+        let (ind, val) = (shape.index_expression(), shape.valid_expression());
+        let sh = shape.shape_usize();
+        let front_size = sh.iter().take(self.dim).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.dim + 1).product::<usize>().max(1);
+        let dim_size = sh[self.dim];
+        let root = self.nodes.len() - 1;
+        let mut stack = vec![];
+        let mut result = vec![
+            match self.kind {
+                ReduceKind::Sum => 0.,
+                ReduceKind::Max => -f32::INFINITY,
+            };
+            front_size * back_size
+        ];
+        // Same loop order as the unfused reductions, so sums come out the same
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let out = &mut result[i * back_size + j];
+                for k in 0..dim_size {
+                    let orig_index = i * dim_size * back_size + k * back_size + j;
+                    let x = if val.exec_single_var_stack(orig_index, &mut stack) != 0 {
+                        let index = ind.exec_single_var_stack(orig_index, &mut stack);
+                        self.eval(root, index, &inputs, &exprs, &mut stack)
+                    } else {
+                        0.
+                    };
+                    match self.kind {
+                        ReduceKind::Sum => *out += x,
+                        ReduceKind::Max => *out = out.max(x),
+                    }
+                }
+            }
+        }
+        Ok(vec![Tensor::new(result)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Compute elementwise ops feeding a SumReduce or MaxReduce inside the reduction, so their full size output never gets
+/// written out. Ops are only pulled in when the reduction is their one consumer, so run this after
+/// `RecomputeIntermediates`.
+#[derive(Debug, Default)]
+pub struct ReduceFusionCompiler;
+
+impl Compiler for ReduceFusionCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        for reduce in graph.node_indices().collect::<Vec<_>>() {
+            let Some(op) = graph.node_weight(reduce) else {
+                continue;
+            };
+            let (kind, dim) = if let Some(SumReduce(dim)) = op.as_any().downcast_ref() {
+                (ReduceKind::Sum, *dim)
+            } else if let Some(MaxReduce(dim)) = op.as_any().downcast_ref() {
+                (ReduceKind::Max, *dim)
+            } else {
+                continue;
+            };
+            let (src, output, shape) = graph.get_sources(reduce)[0];
+            if !fusable(graph, src, output) {
+                continue;
+            }
+            let mut fused = FusedReduce {
+                kind,
+                dim,
+                shape,
+                nodes: vec![],
+            };
+            let (mut inputs, mut absorbed) = (vec![], vec![]);
+            add_node(
+                graph,
+                src,
+                output,
+                &mut fused.nodes,
+                &mut inputs,
+                &mut absorbed,
+            );
+
+            let mut new_op = graph.add_op(fused);
+            for (input, output, shape) in inputs {
+                new_op = new_op.input(input, output, shape);
+            }
+            let new_op = new_op.finish();
+            move_outgoing_edge(reduce, new_op, &mut graph.graph);
+            remap(reduce, new_op, &mut ids, graph);
+            graph.remove_node(reduce);
+            for node in absorbed {
+                let sources = graph
+                    .get_sources(node)
+                    .into_iter()
+                    .map(|(n, _, _)| n)
+                    .collect::<Vec<_>>();
+                graph.remove_node(node);
+                // Constants that were only read by the fused ops aren't needed anymore
+                for src in sources {
+                    if graph.contains_node(src)
+                        && graph.is_op::<Constant>(src)
+                        && !graph.no_delete.contains(&src)
+                        && graph.edges_directed(src, Direction::Outgoing).count() == 0
+                    {
+                        graph.remove_node(src);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether an output can be computed inside its consumer: it's elementwise, and the consumer is the only thing reading it
+fn fusable(graph: &Graph, node: NodeIndex, output: u8) -> bool {
+    output == 0
+        && Elementwise::from_op(graph.node_weight(node).unwrap().as_ref()).is_some()
+        && !graph.no_delete.contains(&node)
+        && graph.edges_directed(node, Direction::Outgoing).count() == 1
+        && graph
+            .edges_directed(node, Direction::Incoming)
+            .all(|e| !e.weight().is_schedule())
+}
+
+/// Add `node` to the tree, pulling in its sources if it can be fused, or making it an input if it can't
+fn add_node(
+    graph: &Graph,
+    node: NodeIndex,
+    output: u8,
+    nodes: &mut Vec<FusedNode>,
+    inputs: &mut Vec<(NodeIndex, u8, ShapeTracker)>,
+    absorbed: &mut Vec<NodeIndex>,
+) -> usize {
+    let op = graph.node_weight(node).unwrap();
+    if let Some(Constant(ConstantValue::Float(c))) = op.as_any().downcast_ref() {
+        nodes.push(FusedNode::Constant(*c));
+    } else if fusable(graph, node, output) {
+        let srcs = graph
+            .get_sources(node)
+            .into_iter()
+            .map(|(src, output, shape)| {
+                (add_node(graph, src, output, nodes, inputs, absorbed), shape)
+            })
+            .collect();
+        nodes.push(FusedNode::Op(
+            Elementwise::from_op(op.as_ref()).unwrap(),
+            srcs,
+        ));
+        absorbed.push(node);
+    } else {
+        // The view is applied on the edge into the fused node, so the input itself is read as is
+        let input = match inputs
+            .iter()
+            .position(|(n, o, _)| *n == node && *o == output)
+        {
+            Some(i) => i,
+            None => {
+                inputs.push((node, output, graph_output_shape(graph, node, output)));
+                inputs.len() - 1
+            }
+        };
+        nodes.push(FusedNode::Input(input));
+    }
+    nodes.len() - 1
+}
+
+/// Shape of an edge leaving `node` from `output`, to give the new edge into the fused op
+fn graph_output_shape(graph: &Graph, node: NodeIndex, output: u8) -> ShapeTracker {
+    graph
+        .edges_directed(node, Direction::Outgoing)
+        .filter_map(|e| e.weight().as_data())
+        .find(|(_, o, _)| *o == output)
+        .map(|(_, _, s)| s)
+        .unwrap()
+}
@@ -0,0 +1,144 @@
+use luminal::{op::*, prelude::*};
+
+use super::binary::Sub;
+
+/// Softmax along one dimension, scaled before exponentiating: `exp2((x - max(x)) * scale) / sum(...)`. Each row is read
+/// three times while it's still in cache, instead of making five full size passes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Softmax {
+    pub dim: usize,
+    pub scale: f32,
+}
+
+impl Operator for Softmax {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.dim).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.dim + 1).product::<usize>().max(1);
+        let dim_size = sh[self.dim];
+        let input = inp[0]
+            .0
+            .borrowed()
+            .widened_f32s()
+            .unwrap_or_else(|e| panic!("{e}"));
+        let (ind, val) = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let mut out = vec![0.; front_size * dim_size * back_size];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                let index = |k: usize| i * dim_size * back_size + k * back_size + j;
+                // Read the row once, keeping it in the output while the max is found
+                let mut max = -f32::INFINITY;
+                for k in 0..dim_size {
+                    let x = if val.exec_single_var_stack(index(k), &mut stack) != 0 {
+                        input[ind.exec_single_var_stack(index(k), &mut stack)]
+                    } else {
+                        0.
+                    };
+                    out[index(k)] = x;
+                    max = max.max(x);
+                }
+                let mut sum = 0.;
+                for k in 0..dim_size {
+                    let e = ((out[index(k)] - max) * self.scale).exp2();
+                    out[index(k)] = e;
+                    sum += e;
+                }
+                let recip = sum.recip();
+                for k in 0..dim_size {
+                    out[index(k)] *= recip;
+                }
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Replace softmax subgraphs (max reduce, subtract, exp, sum reduce, divide) with a single `Softmax` op
+#[derive(Debug, Default)]
+pub struct SoftmaxCompiler;
+
+impl Compiler for SoftmaxCompiler {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let x = node();
+        let max = unary::<MaxReduce>(x.clone());
+        let sub = ordered_binary::<Sub>(x.clone(), max.clone());
+        let scale = op::<Constant>();
+        let mul = binary::<Mul>(sub.clone(), scale.clone());
+        let exp = unary::<Exp2>(mul.clone());
+        let sum = unary::<SumReduce>(exp.clone());
+        let recip = unary::<Recip>(sum.clone());
+        let div = binary::<Mul>(exp.clone(), recip.clone());
+        let mut s = div.clone().search(graph);
+        while s.next_match() {
+            if s.check_no_delete(&[x.id, scale.id, div.id]) {
+                continue;
+            }
+            let dim = graph.get_op::<MaxReduce>(s.get(&max)).0;
+            let Some(Constant(ConstantValue::Float(scale))) = graph
+                .node_weight(s.get(&scale))
+                .unwrap()
+                .as_any()
+                .downcast_ref()
+            else {
+                continue;
+            };
+            let scale = *scale;
+            let edge = |a: &SelectGraph, b: &SelectGraph| {
+                graph
+                    .graph
+                    .edges_connecting(s.get(a), s.get(b))
+                    .next()
+                    .and_then(|e| e.weight().as_data())
+                    .unwrap()
+            };
+            let (_, x_output, x_shape) = edge(&x, &max);
+            // Both reductions are over the same dimension, of the same view of x, and get expanded back along it. The
+            // sum can be expanded before or after it's inverted.
+            let (sum_recip, recip_div) = (edge(&sum, &recip).2, edge(&recip, &div).2);
+            let sum_expanded = expanded_along(sum_recip, dim) && !recip_div.is_reshaped()
+                || !sum_recip.is_reshaped() && expanded_along(recip_div, dim);
+            if graph.get_op::<SumReduce>(s.get(&sum)).0 != dim
+                || edge(&x, &sub).2 != x_shape
+                || !expanded_along(edge(&max, &sub).2, dim)
+                || !sum_expanded
+                || [
+                    edge(&sub, &mul).2,
+                    edge(&mul, &exp).2,
+                    edge(&exp, &sum).2,
+                    edge(&exp, &div).2,
+                ]
+                .iter()
+                .any(|s| s.is_reshaped())
+            {
+                continue;
+            }
+            let softmax = graph
+                .add_op(Softmax { dim, scale })
+                .input(s.get(&x), x_output, x_shape)
+                .finish();
+            let div = s.get(&div);
+            move_outgoing_edge(div, softmax, &mut graph.graph);
+            remap(div, softmax, &mut ids, graph);
+            graph.remove_node(div);
+            s.try_delete();
+        }
+    }
+}
+
+/// Whether a view just broadcasts a contiguous tensor along `dim`
+fn expanded_along(mut shape: ShapeTracker, dim: usize) -> bool {
+    if !shape.fake[shape.indexes[dim]] || shape.is_sliced() || shape.is_padded() {
+        return false;
+    }
+    shape.remove_dim(dim);
+    shape.is_contiguous()
+}
@@ -0,0 +1,42 @@
+//! Random small graphs of primitive ops, run through the CPU backend's compiler stacks and checked against dfdx.
+//!
+//! The programs and their reference come from the core crate's fuzz test, so both backends see the same ops.
+
+#[path = "../../../tests/fuzz/mod.rs"]
+mod fuzz;
+
+use fuzz::{check_program, op};
+use luminal::prelude::*;
+use luminal_cpu::CPUCompiler;
+use proptest::prelude::*;
+
+/// Compiler stacks every program goes through
+const STACKS: &[&str] = &["CPUCompiler", "GenericCompiler + CPUCompiler"];
+
+fn compile(stack: &str, cx: &mut Graph, outputs: &mut Vec<DynGraphTensor>) {
+    match stack {
+        "CPUCompiler" => {
+            cx.compile(CPUCompiler, outputs);
+        }
+        "GenericCompiler + CPUCompiler" => {
+            cx.compile((GenericCompiler::default(), CPUCompiler), outputs);
+        }
+        _ => unreachable!(),
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+    #[test]
+    fn fuzz_cpu_ops(
+        n in 1..4usize,
+        program in prop::collection::vec(op(), 1..10),
+        a in prop::collection::vec(-1f32..1., 9),
+        b in prop::collection::vec(-1f32..1., 9),
+    ) {
+        let (a, b) = (&a[..n * n], &b[..n * n]);
+        for stack in STACKS {
+            check_program(&program, n, a, b, stack, |cx, outputs| compile(stack, cx, outputs))?;
+        }
+    }
+}
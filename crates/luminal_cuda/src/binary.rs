@@ -95,7 +95,7 @@ pub struct SubtractionCompiler<T: CudaFloat>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for SubtractionCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         let (lhs, rhs) = (node(), node());
         let mul = binary::<CudaMul<T>>(rhs.clone(), constant::<T>(-1.));
         let add = binary::<CudaAdd<T>>(lhs.clone(), mul.clone());
@@ -227,7 +227,7 @@ pub struct EqualCompiler<T: CudaFloat>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for EqualCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         let one = constant::<T>(1.);
         let (lhs, rhs) = (node(), node());
         let lt1 = binary::<CudaLessThan<T>>(lhs.clone(), rhs.clone());
@@ -235,7 +235,7 @@ impl<T: CudaFloat> Compiler for EqualCompiler<T> {
             lt1.clone(),
             binary::<CudaLessThan<T>>(rhs.clone(), lhs.clone()),
         );
-        let eq = binary::<CudaSub<T>>(one, ne);
+        let eq = ordered_binary::<CudaSub<T>>(one, ne);
 
         let mut s = eq.clone().search(graph);
         while s.next_match() {
@@ -356,7 +356,7 @@ pub struct GatherCompiler<T: CudaFloat>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for GatherCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         let indexes = node();
         let ind_copy = unary::<CudaCopyToDevice<T>>(indexes.clone());
         let equal = binary::<CudaEqual<T>>(op::<CudaARange<T>>(), ind_copy.clone());
@@ -0,0 +1,482 @@
+use std::{marker::PhantomData, sync::Arc};
+
+use cudarc::driver::{result, sys, CudaDevice, DevicePtr, DeviceSlice};
+use itertools::Itertools;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use luminal::{
+    op::*,
+    prelude::{
+        petgraph::{algo::toposort, visit::EdgeRef, Direction},
+        *,
+    },
+};
+
+use crate::{
+    binary::CudaGather,
+    prim::{CudaConstant, CudaCopyFromDevice, CudaCopyToDevice},
+    CudaData, CudaFloat, QuantizedGather,
+};
+
+/// An op in a captured region, with where each of its inputs comes from
+type RegionOp = (Box<dyn Operator>, Vec<(Source, ShapeTracker)>);
+
+/// Where an op in a captured region reads an input from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    /// An input of the region
+    Input(usize),
+    /// An output of an earlier op in the region
+    Op(usize, u8),
+}
+
+/// Kernel launches that get recorded into a CUDA graph once they run the same way twice in a row, and then replayed with
+/// a single launch. Any change to the dyn dims, the input sizes, or the address of a kept input records them again.
+pub struct CudaGraph<T> {
+    /// The region's ops in the order they run
+    ops: Vec<RegionOp>,
+    /// Op outputs the region returns, in output order
+    outputs: Vec<(usize, u8)>,
+    device: Arc<CudaDevice>,
+    dyn_map: *const FxHashMap<char, usize>,
+    /// Dyn dims of the last run, to tell when they've stopped changing
+    last_dims: Option<Vec<(char, usize)>>,
+    captured: Option<Captured>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> std::fmt::Debug for CudaGraph<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CudaGraph({} ops)", self.ops.len())
+    }
+}
+
+/// An input the captured kernels read from
+enum CapturedInput {
+    /// A buffer owned by the region, which each run's input gets copied into
+    Copied(Tensor),
+    /// A kept tensor read where it is, so it has to stay at this address
+    InPlace(sys::CUdeviceptr, usize),
+}
+
+struct Captured {
+    exec: sys::CUgraphExec,
+    graph: sys::CUgraph,
+    dims: Vec<(char, usize)>,
+    inputs: Vec<CapturedInput>,
+    /// Outputs of every op while capturing, which each replay writes again
+    tensors: Vec<Vec<Tensor>>,
+    stream: sys::CUstream,
+}
+
+impl Captured {
+    /// Whether a replay would read these inputs
+    fn matches<T: CudaFloat>(
+        &self,
+        dims: &[(char, usize)],
+        inp: &[(InputTensor, ShapeTracker)],
+    ) -> bool {
+        self.dims == dims
+            && self.inputs.iter().zip(inp).all(|(captured, (input, _))| {
+                let buffer = device_buffer::<T>(input.borrowed());
+                match captured {
+                    CapturedInput::Copied(slot) => {
+                        buffer.map(|(_, size)| size)
+                            == device_buffer::<T>(slot).map(|(_, size)| size)
+                    }
+                    CapturedInput::InPlace(ptr, size) => {
+                        matches!(input, InputTensor::Borrowed(_)) && buffer == Some((*ptr, *size))
+                    }
+                }
+            })
+    }
+}
+
+impl Drop for Captured {
+    fn drop(&mut self) {
+        unsafe {
+            result::stream::synchronize(self.stream).unwrap();
+            sys::lib().cuGraphExecDestroy(self.exec).result().unwrap();
+            sys::lib().cuGraphDestroy(self.graph).result().unwrap();
+        }
+    }
+}
+
+/// Address and size in bytes of a tensor's buffer, if it's on the device
+fn device_buffer<T: CudaFloat>(tensor: &Tensor) -> Option<(sys::CUdeviceptr, usize)> {
+    if let Some(CudaData(buf)) = tensor.downcast_ref::<CudaData<T>>() {
+        Some((*buf.device_ptr(), buf.num_bytes()))
+    } else {
+        tensor
+            .downcast_ref::<CudaData<u8>>()
+            .map(|CudaData(buf)| (*buf.device_ptr(), buf.num_bytes()))
+    }
+}
+
+impl<T: CudaFloat> CudaGraph<T> {
+    /// Run every op in order, returning the outputs of all of them
+    fn run_ops(&mut self, inputs: &[&Tensor], free_intermediates: bool) -> Vec<Vec<Tensor>> {
+        let dyn_map = unsafe { self.dyn_map.as_ref().unwrap() };
+        // Ops whose outputs are still needed, by later ops or as outputs of the region
+        let mut uses = vec![0; self.ops.len()];
+        for (_, srcs) in &self.ops {
+            for (src, _) in srcs {
+                if let Source::Op(op, _) = src {
+                    uses[*op] += 1;
+                }
+            }
+        }
+        for (op, _) in &self.outputs {
+            uses[*op] += 1;
+        }
+        let mut tensors: Vec<Vec<Tensor>> = Vec::with_capacity(self.ops.len());
+        for (op, srcs) in &mut self.ops {
+            let inp = srcs
+                .iter()
+                .map(|(src, shape)| {
+                    let mut shape = *shape;
+                    shape.resolve_global_dyn_dims(dyn_map);
+                    let tensor = match src {
+                        Source::Input(i) => inputs[*i],
+                        Source::Op(op, output) => &tensors[*op][*output as usize],
+                    };
+                    (InputTensor::Borrowed(tensor), shape)
+                })
+                .collect();
+            let out = op
+                .try_process(inp, dyn_map)
+                .unwrap_or_else(|e| panic!("{e}"));
+            if free_intermediates {
+                for (src, _) in srcs.iter() {
+                    if let Source::Op(op, _) = src {
+                        uses[*op] -= 1;
+                        if uses[*op] == 0 {
+                            tensors[*op].clear();
+                        }
+                    }
+                }
+            }
+            tensors.push(out);
+        }
+        tensors
+    }
+
+    fn take_outputs(&self, tensors: Vec<Vec<Tensor>>) -> Vec<Tensor> {
+        let mut tensors = tensors
+            .into_iter()
+            .map(|t| t.into_iter().map(Some).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        self.outputs
+            .iter()
+            .map(|(op, output)| tensors[*op][*output as usize].take().unwrap())
+            .collect()
+    }
+
+    /// Record the region into a CUDA graph, then launch it
+    fn capture(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dims: Vec<(char, usize)>,
+    ) -> Captured {
+        let inputs = inp
+            .into_iter()
+            .map(|(input, _)| match input {
+                InputTensor::Owned(tensor) => (CapturedInput::Copied(tensor), None),
+                InputTensor::Borrowed(tensor) => {
+                    let (ptr, size) = device_buffer::<T>(tensor).unwrap();
+                    (CapturedInput::InPlace(ptr, size), Some(tensor))
+                }
+            })
+            .collect::<Vec<_>>();
+        let stream = *self.device.cu_stream();
+        let mut graph = std::ptr::null_mut();
+        let mut exec = std::ptr::null_mut();
+        unsafe {
+            sys::lib()
+                .cuStreamBeginCapture_v2(
+                    stream,
+                    sys::CUstreamCaptureMode::CU_STREAM_CAPTURE_MODE_RELAXED,
+                )
+                .result()
+                .unwrap();
+        }
+        let tensors = self.run_ops(
+            &inputs
+                .iter()
+                .map(|(input, tensor)| match input {
+                    CapturedInput::Copied(t) => t,
+                    CapturedInput::InPlace(..) => tensor.unwrap(),
+                })
+                .collect::<Vec<_>>(),
+            false,
+        );
+        unsafe {
+            sys::lib()
+                .cuStreamEndCapture(stream, &mut graph)
+                .result()
+                .unwrap();
+            // Buffers allocated during capture get allocated again by each launch, at the same addresses
+            sys::lib()
+                .cuGraphInstantiateWithFlags(
+                    &mut exec,
+                    graph,
+                    sys::CUgraphInstantiate_flags::CUDA_GRAPH_INSTANTIATE_FLAG_AUTO_FREE_ON_LAUNCH
+                        as u64,
+                )
+                .result()
+                .unwrap();
+            sys::lib().cuGraphLaunch(exec, stream).result().unwrap();
+        }
+        Captured {
+            exec,
+            graph,
+            dims,
+            inputs: inputs.into_iter().map(|(input, _)| input).collect(),
+            tensors,
+            stream,
+        }
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaGraph<T> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let dyn_map = unsafe { self.dyn_map.as_ref().unwrap() };
+        let dims = dyn_map
+            .iter()
+            .map(|(c, v)| (*c, *v))
+            .sorted()
+            .collect::<Vec<_>>();
+        if self
+            .captured
+            .as_ref()
+            .map(|c| !c.matches::<T>(&dims, &inp))
+            .unwrap_or_default()
+        {
+            self.captured = None;
+        }
+        if let Some(captured) = &self.captured {
+            for ((input, _), slot) in inp.iter().zip(&captured.inputs) {
+                if let CapturedInput::Copied(slot) = slot {
+                    let (src, size) = device_buffer::<T>(input.borrowed()).unwrap();
+                    let (dst, _) = device_buffer::<T>(slot).unwrap();
+                    unsafe { result::memcpy_dtod_async(dst, src, size, captured.stream).unwrap() };
+                }
+            }
+            unsafe {
+                sys::lib()
+                    .cuGraphLaunch(captured.exec, captured.stream)
+                    .result()
+                    .unwrap();
+            }
+        } else {
+            let steady = self.last_dims.as_ref() == Some(&dims);
+            self.last_dims = Some(dims.clone());
+            if !steady
+                || inp
+                    .iter()
+                    .any(|(t, _)| device_buffer::<T>(t.borrowed()).is_none())
+            {
+                let inputs = inp.iter().map(|(t, _)| t.borrowed()).collect::<Vec<_>>();
+                let tensors = self.run_ops(&inputs, true);
+                return self.take_outputs(tensors);
+            }
+            self.captured = Some(self.capture(inp, dims));
+        }
+        // The captured buffers get written again by the next replay, so the outputs are copies
+        let captured = self.captured.as_ref().unwrap();
+        self.outputs
+            .iter()
+            .map(|(op, output)| captured.tensors[*op][*output as usize].clone())
+            .collect()
+    }
+}
+
+/// Hands a region output back to the node it was made by, so ids pointing at that node still work
+#[derive(Debug)]
+struct CapturedOutput;
+
+impl Operator for CapturedOutput {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+}
+
+/// Record the graph's kernel launches into a CUDA graph once the dyn dims stop changing between runs, and replay it with
+/// one launch while they stay the same. Launch overhead is most of the time spent decoding a small batch, so this is
+/// meant for the per token step. Run after `CudaCompiler`.
+///
+/// Copies to and from the host, constants and gathers all read host memory, so they run outside of the captured region,
+/// along with pinned tensors and anything after them that feeds back into the region.
+#[derive(Debug, Default)]
+pub struct CudaGraphCompiler<T>(PhantomData<T>);
+
+impl<T: CudaFloat> Compiler for CudaGraphCompiler<T> {
+    type Output = ();
+    fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
+        let capturable = |node: NodeIndex| {
+            let op = graph.node_weight(node).unwrap().as_any();
+            !(op.is::<Function>()
+                || op.is::<CudaCopyToDevice<T>>()
+                || op.is::<CudaCopyFromDevice<T>>()
+                || op.is::<CudaConstant<T>>()
+                || op.is::<CudaGather<T>>()
+                || op.is::<QuantizedGather<T>>()
+                || graph.pinned.contains(&node))
+        };
+        // Nodes after a node outside the region that depends on the region can't be in it, or the region would depend
+        // on itself
+        let (mut region, mut in_region) = (vec![], FxHashSet::default());
+        let (mut after_region, mut after_exit) = (FxHashSet::default(), FxHashSet::default());
+        for node in toposort(&graph.graph, None).unwrap() {
+            let preds = graph
+                .neighbors_directed(node, Direction::Incoming)
+                .collect::<Vec<_>>();
+            if preds.iter().any(|p| after_exit.contains(p)) {
+                after_exit.insert(node);
+            } else if capturable(node) {
+                region.push(node);
+                in_region.insert(node);
+            } else if preds.iter().any(|p| after_region.contains(p)) {
+                after_exit.insert(node);
+            }
+            if in_region.contains(&node) || preds.iter().any(|p| after_region.contains(p)) {
+                after_region.insert(node);
+            }
+        }
+        if region.len() < 2 {
+            return;
+        }
+        let local = region
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (*n, i))
+            .collect::<FxHashMap<_, _>>();
+        // Nodes that can be looked up after compiling keep their index, and hand back their output from the region
+        let visible = ids
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .chain(graph.no_delete.iter().copied())
+            .chain(graph.retained.iter().copied())
+            .chain(graph.to_retrieve.keys().copied())
+            .filter(|n| in_region.contains(n))
+            .collect::<FxHashSet<_>>();
+
+        let (mut inputs, mut outputs) = (vec![], vec![]);
+        let mut ops = vec![];
+        let (mut schedule_in, mut edges_out) = (FxHashSet::default(), vec![]);
+        for &node in &region {
+            let mut srcs = vec![];
+            for edge in graph.edges_directed(node, Direction::Incoming) {
+                let Some((input_order, output, shape)) = edge.weight().as_data() else {
+                    if !in_region.contains(&edge.source()) {
+                        schedule_in.insert(edge.source());
+                    }
+                    continue;
+                };
+                let src = if let Some(op) = local.get(&edge.source()) {
+                    Source::Op(*op, output)
+                } else {
+                    let input = (edge.source(), output);
+                    Source::Input(match inputs.iter().position(|(i, _)| *i == input) {
+                        Some(i) => i,
+                        None => {
+                            inputs.push((input, shape));
+                            inputs.len() - 1
+                        }
+                    })
+                };
+                srcs.push((input_order, src, shape));
+            }
+            srcs.sort_by_key(|(i, _, _)| *i);
+            ops.push(
+                srcs.into_iter()
+                    .map(|(_, s, sh)| (s, sh))
+                    .collect::<Vec<_>>(),
+            );
+            let mut output_index =
+                |output: (usize, u8)| match outputs.iter().position(|o| *o == output) {
+                    Some(i) => i as u8,
+                    None => {
+                        outputs.push(output);
+                        (outputs.len() - 1) as u8
+                    }
+                };
+            if visible.contains(&node) {
+                let output_order = output_index((local[&node], 0));
+                edges_out.push((
+                    node,
+                    Dependency::Data {
+                        input_order: 0,
+                        output_order,
+                        shape: ShapeTracker::new(&[]),
+                    },
+                ));
+                continue;
+            }
+            for edge in graph.edges_directed(node, Direction::Outgoing) {
+                if in_region.contains(&edge.target()) {
+                    continue;
+                }
+                let dependency = match *edge.weight() {
+                    Dependency::Data {
+                        input_order,
+                        output_order,
+                        shape,
+                    } => Dependency::Data {
+                        input_order,
+                        output_order: output_index((local[&node], output_order)),
+                        shape,
+                    },
+                    Dependency::Schedule => Dependency::Schedule,
+                };
+                edges_out.push((edge.target(), dependency));
+            }
+        }
+        if outputs.len() > u8::MAX as usize || inputs.len() > u8::MAX as usize {
+            return;
+        }
+        // Take the ops out of the graph, leaving a passthrough on the visible nodes
+        let ops = region
+            .iter()
+            .zip(ops)
+            .map(|(&node, srcs)| {
+                let op = if visible.contains(&node) {
+                    for edge in graph
+                        .edges_directed(node, Direction::Incoming)
+                        .map(|e| e.id())
+                        .collect::<Vec<_>>()
+                    {
+                        graph.remove_edge(edge);
+                    }
+                    std::mem::replace(
+                        graph.node_weight_mut(node).unwrap(),
+                        Box::new(CapturedOutput),
+                    )
+                } else {
+                    graph.remove_node(node).unwrap()
+                };
+                (op, srcs)
+            })
+            .collect();
+        let mut new_op = graph.add_op(CudaGraph::<T> {
+            ops,
+            outputs,
+            device: crate::cuda_device(),
+            dyn_map: &graph.dyn_map,
+            last_dims: None,
+            captured: None,
+            _phantom: Default::default(),
+        });
+        for ((src, output), shape) in inputs {
+            new_op = new_op.input(src, output, shape);
+        }
+        let region_op = new_op.finish();
+        for src in schedule_in {
+            graph.add_schedule_dependency(src, region_op);
+        }
+        for (dest, dependency) in edges_out {
+            graph.add_edge(region_op, dest, dependency);
+        }
+    }
+}
@@ -65,7 +65,7 @@ fn is_more_than_one_view(
 impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let device = CudaDevice::new(0).unwrap();
+        let device = crate::cuda_device();
         // Track fused ops to compile later
         let mut fused_ops = FxHashSet::default();
 
@@ -86,7 +86,7 @@ impl<T: CudaFloat> Compiler for ElementwiseFusionCompiler<T> {
                 };
                 if graph.no_delete.contains(&a)
                     || graph.no_delete.contains(&b)
-                    || (!graph.check_node_type::<CudaConstant<T>>(a)
+                    || (!graph.is_op::<CudaConstant<T>>(a)
                         && graph
                             .edges_directed(a, Direction::Outgoing)
                             .filter(|e| e.target() != b)
@@ -1,12 +1,16 @@
 mod binary;
+mod cuda_graph;
 mod elementwise_fusion;
 mod matmul;
 mod other;
 mod prim;
 mod quantized;
+mod transfer;
 #[macro_use]
 mod unary;
+pub use cuda_graph::CudaGraphCompiler;
 pub use quantized::*;
+pub use transfer::{Staging, TransferManager};
 
 pub use cudarc::driver::CudaDevice;
 
@@ -15,16 +19,27 @@ pub use cudarc::driver::CudaDevice;
 mod tests;
 
 use cudarc::{
-    driver::{CudaFunction, CudaSlice, DeviceRepr},
-    nvrtc::{compile_ptx_with_opts, CompileOptions},
+    driver::{CudaFunction, CudaSlice, DeviceRepr, DeviceSlice, LaunchAsync, LaunchConfig},
+    nvrtc::{compile_ptx_with_opts, CompileOptions, Ptx},
 };
 use itertools::Itertools;
 use prim::CudaConstant;
 use rustc_hash::FxHashMap;
 
-use std::{collections::hash_map::DefaultHasher, ffi::c_void, fmt::Write, hash::Hasher, sync::Arc};
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::c_void,
+    fmt::Write,
+    hash::Hasher,
+    marker::PhantomData,
+    sync::{Arc, OnceLock},
+};
 
-use luminal::{op::InputTensor, prelude::*};
+use luminal::{
+    backend::{self, Backend, Kernel},
+    op::InputTensor,
+    prelude::*,
+};
 
 /// Compile graphs to run on CUDA GPUs in supported data formats
 pub type CudaCompiler<T> = (
@@ -48,6 +63,132 @@ pub type SpecialOpsCompiler<T> = (
     matmul::MatMulCompiler<T>,
 );
 
+/// A CUDA GPU running ops in `T`, with the streams its host copies go through
+#[derive(Debug, Clone)]
+pub struct Cuda<T> {
+    pub device: Arc<CudaDevice>,
+    transfers: Arc<OnceLock<TransferManager>>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Cuda<T> {
+    pub fn new(device: Arc<CudaDevice>) -> Self {
+        Self {
+            device,
+            transfers: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Created on the first copy, so graphs that never copy don't make streams
+    fn transfers(&self) -> &TransferManager {
+        self.transfers
+            .get_or_init(|| TransferManager::new(self.device.clone()).unwrap())
+    }
+}
+
+impl<T> Default for Cuda<T> {
+    fn default() -> Self {
+        Self::new(cuda_device())
+    }
+}
+
+impl<T: CudaFloat> backend::Device for Cuda<T> {
+    type Buffer = CudaData<T>;
+    type Staging = Staging<T>;
+
+    fn alloc(&self, len: usize) -> Self::Buffer {
+        CudaData(self.device.alloc_zeros::<T>(len).unwrap())
+    }
+
+    fn copy_to_device(&self, staging: &mut Self::Staging, data: &[f32]) -> Self::Buffer {
+        CudaData(self.transfers().upload(staging, data, T::from_f32).unwrap())
+    }
+
+    fn copy_from_device(&self, staging: &mut Self::Staging, buffer: &Self::Buffer) -> Vec<f32> {
+        self.transfers()
+            .download(staging, &buffer.0, |x| x.to_f32())
+            .unwrap()
+    }
+
+    /// Quantized weights are kept on the device as bytes
+    fn holds(&self, tensor: &Tensor) -> bool {
+        tensor.is::<CudaData<T>>() || tensor.is::<CudaData<u8>>()
+    }
+
+    fn synchronize(&self) {
+        self.device.synchronize().unwrap();
+    }
+}
+
+impl<T: CudaFloat> Backend for Cuda<T> {
+    type Device = Self;
+    type Compiler = CudaCompiler<T>;
+
+    fn device(&self) -> Self {
+        self.clone()
+    }
+
+    fn compiler(&self) -> Self::Compiler {
+        Default::default()
+    }
+
+    fn runs_on_host(graph: &Graph, node: NodeIndex) -> bool {
+        prim::has_no_kernel(graph, node)
+    }
+}
+
+/// An elementwise kernel, taking its outputs, then its inputs, then the element count and the dynamic dimensions it
+/// reads
+pub struct CudaKernel<T> {
+    function: CudaFunction,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(CudaKernel);
+
+impl<T> CudaKernel<T> {
+    pub fn with_dyn_symbols(mut self, dyn_symbols: Vec<char>) -> Self {
+        self.dyn_symbols = dyn_symbols;
+        self
+    }
+}
+
+impl<T: CudaFloat> Kernel for CudaKernel<T> {
+    type Device = Cuda<T>;
+
+    fn compile(device: &Cuda<T>, source: &str) -> Result<Self, String> {
+        Ok(Self {
+            function: try_compile_and_load_kernel(source.to_string(), &device.device)?,
+            dyn_symbols: vec![],
+            _phantom: Default::default(),
+        })
+    }
+
+    fn launch(
+        &self,
+        _: &Cuda<T>,
+        inputs: &[&CudaData<T>],
+        outputs: &mut [CudaData<T>],
+        dyn_map: &FxHashMap<char, usize>,
+    ) {
+        let numel = outputs[0].0.len();
+        let mut params = outputs
+            .iter()
+            .map(|o| (&o.0).as_kernel_param())
+            .chain(inputs.iter().map(|i| (&i.0).as_kernel_param()))
+            .collect::<Vec<_>>();
+        params.push(numel.as_kernel_param());
+        input_dyn_dims(&mut params, &self.dyn_symbols, dyn_map);
+        unsafe {
+            self.function
+                .clone()
+                .launch(LaunchConfig::for_num_elems(numel as u32), &mut params)
+                .unwrap();
+        }
+    }
+}
+
 pub trait CudaFloat:
     std::fmt::Debug
     + Copy
@@ -61,6 +202,7 @@ pub trait CudaFloat:
     fn from_f32(a: f32) -> Self;
     fn is_f32() -> bool;
     fn type_name() -> &'static str;
+    fn dtype() -> DType;
 }
 
 impl CudaFloat for f32 {
@@ -76,6 +218,9 @@ impl CudaFloat for f32 {
     fn type_name() -> &'static str {
         "float"
     }
+    fn dtype() -> DType {
+        DType::F32
+    }
 }
 #[derive(Debug)]
 pub struct CudaData<T>(pub CudaSlice<T>);
@@ -94,6 +239,18 @@ impl<T: CudaFloat> Data for CudaData<T> {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    fn size(&self) -> usize {
+        self.0.num_bytes()
+    }
+
+    fn dtype(&self) -> DType {
+        T::dtype()
+    }
+
+    fn device(&self) -> &'static str {
+        "cuda"
+    }
 }
 
 impl CudaFloat for f16 {
@@ -109,6 +266,9 @@ impl CudaFloat for f16 {
     fn type_name() -> &'static str {
         "__half"
     }
+    fn dtype() -> DType {
+        DType::F16
+    }
 }
 
 impl CudaFloat for u8 {
@@ -124,6 +284,9 @@ impl CudaFloat for u8 {
     fn type_name() -> &'static str {
         "uint8_t"
     }
+    fn dtype() -> DType {
+        DType::U8
+    }
 }
 
 fn expr_to_cuda_string(expr: &BigExpression) -> String {
@@ -231,12 +394,34 @@ fn input_dyn_dims(
     }
 }
 
-fn compile_and_load_kernel(mut code: String, device: &Arc<CudaDevice>) -> CudaFunction {
+static DEVICE: OnceLock<Arc<CudaDevice>> = OnceLock::new();
+
+/// The device graphs get compiled for. All ops share it so their work is queued in order on one stream, which isn't the
+/// legacy default stream since that one can't be captured into a CUDA graph.
+pub fn cuda_device() -> Arc<CudaDevice> {
+    DEVICE
+        .get_or_init(|| CudaDevice::new_with_stream(0).unwrap())
+        .clone()
+}
+
+/// PTX of every kernel compiled so far, saved between runs
+static KERNELS: OnceLock<KernelCache> = OnceLock::new();
+
+fn compile_and_load_kernel(code: String, device: &Arc<CudaDevice>) -> CudaFunction {
+    try_compile_and_load_kernel(code, device).unwrap()
+}
+
+fn try_compile_and_load_kernel(
+    mut code: String,
+    device: &Arc<CudaDevice>,
+) -> Result<CudaFunction, String> {
     let name = format!("kernel_{}", hash(&code));
     code = code.replace("kernel", &name);
     if !device.has_func(&name, &name) {
-        device
-            .load_ptx(
+        let key = KernelKey::new("cuda", &name).with_source(&code);
+        let ptx = KERNELS
+            .get_or_init(KernelCache::from_env)
+            .get_or_compile(&key, || {
                 compile_ptx_with_opts(
                     code,
                     CompileOptions {
@@ -245,13 +430,20 @@ fn compile_and_load_kernel(mut code: String, device: &Arc<CudaDevice>) -> CudaFu
                         ..Default::default()
                     },
                 )
-                .unwrap(),
+                .map(|ptx| ptx.to_src().into_bytes())
+            })
+            .map_err(|e| e.to_string())?;
+        device
+            .load_ptx(
+                Ptx::from_src(String::from_utf8_lossy(&ptx)),
                 &name,
                 &[name.clone().leak()],
             )
-            .unwrap();
+            .map_err(|e| e.to_string())?;
     }
-    device.get_func(&name, &name).unwrap()
+    device
+        .get_func(&name, &name)
+        .ok_or_else(|| format!("{name} wasn't loaded"))
 }
 
 #[macro_export]
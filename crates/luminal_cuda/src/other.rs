@@ -76,7 +76,7 @@ pub struct ARangeCompiler<T: CudaFloat>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, _: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         // TODO: Make sure this actually checks the shape transformations to ensure pooling happens
         let contig_one = constant::<T>(1.);
         let contig1 = unary::<CudaContiguous<T>>(contig_one.clone());
@@ -84,7 +84,7 @@ impl<T: CudaFloat> Compiler for ARangeCompiler<T> {
             unary::<CudaSumReduce<T>>(unary::<CudaContiguous<T>>(unary::<CudaContiguous<T>>(
                 unary::<CudaContiguous<T>>(contig1.clone()),
             )));
-        let sub = binary::<CudaSub<T>>(sum_reduce.clone(), constant::<T>(1.));
+        let sub = ordered_binary::<CudaSub<T>>(sum_reduce.clone(), constant::<T>(1.));
         let mut s1 = sub.clone().search(graph);
         let neg_one = constant::<T>(-1.);
         let add = binary::<CudaAdd<T>>(sum_reduce, neg_one.clone());
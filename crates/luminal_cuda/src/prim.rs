@@ -1,4 +1,7 @@
-use crate::{compile_and_load_kernel, get_buffer_from_tensor, input_dyn_dims, CudaData, CudaFloat};
+use crate::{
+    compile_and_load_kernel, get_buffer_from_tensor, input_dyn_dims, Cuda, CudaData, CudaFloat,
+    CudaKernel,
+};
 
 use super::{get_idx_valid_exps, render_dyn_dim_inputs};
 use itertools::Itertools;
@@ -13,63 +16,15 @@ use std::{
 use cudarc::driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig};
 
 use luminal::{
-    op::{Function as LFunction, *},
+    op::*,
     prelude::{petgraph::visit::EdgeRef, *},
 };
 
 /// Copy a tensor to the GPU
-#[derive(Clone)]
-pub struct CudaCopyToDevice<T>(Arc<CudaDevice>, PhantomData<T>);
-crate::debug_type!(CudaCopyToDevice);
-
-impl<T> CudaCopyToDevice<T> {
-    pub fn new(dev: Arc<CudaDevice>) -> Self {
-        CudaCopyToDevice(dev, Default::default())
-    }
-}
-
-impl<T: CudaFloat> Operator for CudaCopyToDevice<T> {
-    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        if inp[0].0.borrowed().is::<CudaData<T>>() || inp[0].0.borrowed().is::<CudaData<u8>>() {
-            // Already on device
-            return vec![inp.pop().unwrap().0.cloned()];
-        }
-        let cpu_data = inp[0].0.borrowed().downcast_ref::<Vec<f32>>().unwrap();
-        let vec = cpu_data
-            .iter()
-            .copied()
-            .map(T::from_f32)
-            .collect::<Vec<_>>();
-        vec![Tensor::new(CudaData(self.0.htod_sync_copy(&vec).unwrap()))]
-    }
-}
+pub type CudaCopyToDevice<T> = CopyToDevice<Cuda<T>>;
 
 /// Copy a tensor from the GPU
-#[derive(Clone)]
-pub struct CudaCopyFromDevice<T>(Arc<CudaDevice>, PhantomData<T>);
-crate::debug_type!(CudaCopyFromDevice);
-
-impl<T> CudaCopyFromDevice<T> {
-    pub fn new(dev: Arc<CudaDevice>) -> Self {
-        CudaCopyFromDevice(dev, Default::default())
-    }
-}
-
-impl<T: CudaFloat> Operator for CudaCopyFromDevice<T> {
-    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        if inp[0].0.borrowed().is::<Vec<f32>>() {
-            // Already off device
-            return vec![inp.pop().unwrap().0.cloned()];
-        }
-        let buf = self
-            .0
-            .dtoh_sync_copy(get_buffer_from_tensor::<T>(&inp[0].0))
-            .unwrap();
-        vec![Tensor::new(
-            buf.into_iter().map(T::to_f32).collect::<Vec<_>>(),
-        )]
-    }
-}
+pub type CudaCopyFromDevice<T> = CopyFromDevice<Cuda<T>>;
 
 /// Constant value on device
 #[derive(Clone)]
@@ -126,21 +81,10 @@ impl<T: CudaFloat> Operator for CudaConstant<T> {
 #[macro_export]
 macro_rules! cuda_unary_op {
     ($op: expr, $op_name: ident) => {
-        #[derive(Clone)]
-        pub struct $op_name<T> {
-            function: CudaFunction,
-            device: Arc<CudaDevice>,
-            dyn_symbols: Vec<char>,
-            dyn_map: *const FxHashMap<char, usize>,
-            _phantom: PhantomData<T>,
-        }
+        pub struct $op_name<T>(KernelOp<CudaKernel<T>>);
 
         impl<T: CudaFloat> $op_name<T> {
-            pub fn new(
-                shape: ShapeTracker,
-                device: Arc<CudaDevice>,
-                dyn_map: *const FxHashMap<char, usize>,
-            ) -> Self {
+            pub fn new(shape: ShapeTracker, device: Cuda<T>) -> Self {
                 let (idx_exp, valid_exp) = get_idx_valid_exps(shape);
                 let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[shape]);
                 let type_name = T::type_name();
@@ -154,35 +98,24 @@ macro_rules! cuda_unary_op {
             }}
         }}", $op
                 );
-                Self {
-                    function: compile_and_load_kernel(code, &device),
-                    device,
-                    dyn_symbols,
-                    dyn_map,
-                    _phantom: Default::default(),
-                }
+                let kernel = CudaKernel::compile(&device, &code)
+                    .unwrap()
+                    .with_dyn_symbols(dyn_symbols);
+                Self(KernelOp::new(kernel, device, vec![shape.n_elements()]))
             }
         }
 
         impl<T: CudaFloat> Operator for $op_name<T> {
             fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-                let inp = get_buffer_from_tensor::<T>(&tensors[0].0);
-                let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-                let out = self.device.alloc_zeros::<T>(inp_size).unwrap();
-                let mut params = vec![
-                    (&out).as_kernel_param(),
-                    inp.as_kernel_param(),
-                    inp_size.as_kernel_param(),
-                ];
-                input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
-                unsafe {
-                    self.function
-                        .clone()
-                        .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                        .unwrap();
-                }
+                self.0.process(tensors)
+            }
 
-                vec![Tensor::new(CudaData(out))]
+            fn try_process(
+                &mut self,
+                tensors: Vec<(InputTensor, ShapeTracker)>,
+                dyn_map: &FxHashMap<char, usize>,
+            ) -> Result<Vec<Tensor>, LuminalError> {
+                self.0.try_process(tensors, dyn_map)
             }
 
             fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
@@ -205,23 +138,11 @@ cuda_unary_op!(if T::is_f32() { "sqrt" } else { "hsqrt" }, CudaSqrt);
 cuda_unary_op!("sin", CudaSin);
 cuda_unary_op!(if T::is_f32() { "__frcp_rn" } else { "hrcp" }, CudaRecip);
 
-#[derive(Clone)]
-pub struct CudaAdd<T> {
-    function: CudaFunction,
-    device: Arc<CudaDevice>,
-    _phantom: PhantomData<T>,
-    dyn_symbols: Vec<char>,
-    dyn_map: *const FxHashMap<char, usize>,
-}
+pub struct CudaAdd<T>(KernelOp<CudaKernel<T>>);
 crate::debug_type!(CudaAdd);
 
 impl<T: CudaFloat> CudaAdd<T> {
-    pub fn new(
-        a_shape: ShapeTracker,
-        b_shape: ShapeTracker,
-        device: Arc<CudaDevice>,
-        dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    pub fn new(a_shape: ShapeTracker, b_shape: ShapeTracker, device: Cuda<T>) -> Self {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
@@ -237,38 +158,24 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
             + (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
-            device,
-            _phantom: Default::default(),
-            dyn_symbols,
-            dyn_map,
-        }
+        let kernel = CudaKernel::compile(&device, &code)
+            .unwrap()
+            .with_dyn_symbols(dyn_symbols);
+        Self(KernelOp::new(kernel, device, vec![a_shape.n_elements()]))
     }
 }
 
 impl<T: CudaFloat> Operator for CudaAdd<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
-        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            a.as_kernel_param(),
-            b.as_kernel_param(),
-            inp_size.as_kernel_param(),
-        ];
-        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
-
-        unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                .unwrap();
-        }
+        self.0.process(tensors)
+    }
 
-        vec![Tensor::new(CudaData(out))]
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        self.0.try_process(tensors, dyn_map)
     }
 
     fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
@@ -279,23 +186,11 @@ impl<T: CudaFloat> Operator for CudaAdd<T> {
     }
 }
 
-#[derive(Clone)]
-pub struct CudaMul<T> {
-    function: CudaFunction,
-    device: Arc<CudaDevice>,
-    _phantom: PhantomData<T>,
-    dyn_symbols: Vec<char>,
-    dyn_map: *const FxHashMap<char, usize>,
-}
+pub struct CudaMul<T>(KernelOp<CudaKernel<T>>);
 crate::debug_type!(CudaMul);
 
 impl<T: CudaFloat> CudaMul<T> {
-    pub fn new(
-        a_shape: ShapeTracker,
-        b_shape: ShapeTracker,
-        device: Arc<CudaDevice>,
-        dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    pub fn new(a_shape: ShapeTracker, b_shape: ShapeTracker, device: Cuda<T>) -> Self {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
@@ -308,38 +203,24 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         out[idx] = (({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}]) * (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]);
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
-            device,
-            _phantom: Default::default(),
-            dyn_symbols,
-            dyn_map,
-        }
+        let kernel = CudaKernel::compile(&device, &code)
+            .unwrap()
+            .with_dyn_symbols(dyn_symbols);
+        Self(KernelOp::new(kernel, device, vec![a_shape.n_elements()]))
     }
 }
 
 impl<T: CudaFloat> Operator for CudaMul<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
-        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            a.as_kernel_param(),
-            b.as_kernel_param(),
-            inp_size.as_kernel_param(),
-        ];
-        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
-
-        unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                .unwrap();
-        }
+        self.0.process(tensors)
+    }
 
-        vec![Tensor::new(CudaData(out))]
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        self.0.try_process(tensors, dyn_map)
     }
 
     fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
@@ -350,23 +231,11 @@ impl<T: CudaFloat> Operator for CudaMul<T> {
     }
 }
 
-#[derive(Clone)]
-pub struct CudaMod<T> {
-    function: CudaFunction,
-    device: Arc<CudaDevice>,
-    _phantom: PhantomData<T>,
-    dyn_symbols: Vec<char>,
-    dyn_map: *const FxHashMap<char, usize>,
-}
+pub struct CudaMod<T>(KernelOp<CudaKernel<T>>);
 crate::debug_type!(CudaMod);
 
 impl<T: CudaFloat> CudaMod<T> {
-    pub fn new(
-        a_shape: ShapeTracker,
-        b_shape: ShapeTracker,
-        device: Arc<CudaDevice>,
-        dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    pub fn new(a_shape: ShapeTracker, b_shape: ShapeTracker, device: Cuda<T>) -> Self {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
@@ -379,38 +248,24 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         out[idx] = fmod((({a_valid}) == 0 ? ({type_name})0.0 : inp_a[{a_idx}]), (({b_valid}) == 0 ? ({type_name})0.0 : inp_b[{b_idx}]));
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
-            device,
-            _phantom: Default::default(),
-            dyn_symbols,
-            dyn_map,
-        }
+        let kernel = CudaKernel::compile(&device, &code)
+            .unwrap()
+            .with_dyn_symbols(dyn_symbols);
+        Self(KernelOp::new(kernel, device, vec![a_shape.n_elements()]))
     }
 }
 
 impl<T: CudaFloat> Operator for CudaMod<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
-        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            a.as_kernel_param(),
-            b.as_kernel_param(),
-            inp_size.as_kernel_param(),
-        ];
-        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
-
-        unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                .unwrap();
-        }
+        self.0.process(tensors)
+    }
 
-        vec![Tensor::new(CudaData(out))]
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        self.0.try_process(tensors, dyn_map)
     }
 
     fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
@@ -421,23 +276,11 @@ impl<T: CudaFloat> Operator for CudaMod<T> {
     }
 }
 
-#[derive(Clone)]
-pub struct CudaLessThan<T> {
-    function: CudaFunction,
-    device: Arc<CudaDevice>,
-    _phantom: PhantomData<T>,
-    dyn_symbols: Vec<char>,
-    dyn_map: *const FxHashMap<char, usize>,
-}
+pub struct CudaLessThan<T>(KernelOp<CudaKernel<T>>);
 crate::debug_type!(CudaLessThan);
 
 impl<T: CudaFloat> CudaLessThan<T> {
-    pub fn new(
-        a_shape: ShapeTracker,
-        b_shape: ShapeTracker,
-        device: Arc<CudaDevice>,
-        dyn_map: *const FxHashMap<char, usize>,
-    ) -> Self {
+    pub fn new(a_shape: ShapeTracker, b_shape: ShapeTracker, device: Cuda<T>) -> Self {
         let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
         let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
         let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
@@ -456,43 +299,79 @@ extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a,
         }}
     }}
 }}");
-        Self {
-            function: compile_and_load_kernel(code, &device),
-            device,
-            _phantom: Default::default(),
-            dyn_symbols,
-            dyn_map,
-        }
+        let kernel = CudaKernel::compile(&device, &code)
+            .unwrap()
+            .with_dyn_symbols(dyn_symbols);
+        Self(KernelOp::new(kernel, device, vec![a_shape.n_elements()]))
     }
 }
 
 impl<T: CudaFloat> Operator for CudaLessThan<T> {
     fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let a = get_buffer_from_tensor::<T>(&tensors[0].0);
-        let b = get_buffer_from_tensor::<T>(&tensors[1].0);
-        let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
-        let out = unsafe { self.device.alloc::<T>(inp_size).unwrap() };
-        let mut params = vec![
-            (&out).as_kernel_param(),
-            a.as_kernel_param(),
-            b.as_kernel_param(),
-            inp_size.as_kernel_param(),
-        ];
-        input_dyn_dims(&mut params, &self.dyn_symbols, self.dyn_map);
+        self.0.process(tensors)
+    }
 
-        unsafe {
-            self.function
-                .clone()
-                .launch(LaunchConfig::for_num_elems(inp_size as u32), &mut params)
-                .unwrap();
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        self.0.try_process(tensors, dyn_map)
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "elementwise" {
+            return Some(Box::new("(float)(input0 < input1 ? 1.0 : 0.0)".to_string()));
         }
+        None
+    }
+}
 
-        vec![Tensor::new(CudaData(out))]
+pub struct CudaSelect<T>(KernelOp<CudaKernel<T>>);
+crate::debug_type!(CudaSelect);
+
+impl<T: CudaFloat> CudaSelect<T> {
+    pub fn new(a_shape: ShapeTracker, b_shape: ShapeTracker, device: Cuda<T>) -> Self {
+        let (a_idx, a_valid) = get_idx_valid_exps(a_shape);
+        let (b_idx, b_valid) = get_idx_valid_exps(b_shape);
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape]);
+        let type_name = T::type_name();
+        let code = format!("
+#include \"cuda_fp16.h\"
+extern \"C\" __global__ void kernel({type_name} *out, const {type_name} *inp_a, const {type_name} *inp_b, int numel{rendered}) {{
+    int idx = blockIdx.x * blockDim.x + threadIdx.x;
+    if (idx < numel) {{
+        {type_name} b_t = (({b_valid}) != 0) ? inp_b[{b_idx}] : ({type_name})0.0;
+        if (b_t != ({type_name})0.0 && ({a_valid}) != 0) {{
+            out[idx] = inp_a[{a_idx}];
+        }} else {{
+            out[idx] = ({type_name})0.0;
+        }}
+    }}
+}}");
+        let kernel = CudaKernel::compile(&device, &code)
+            .unwrap()
+            .with_dyn_symbols(dyn_symbols);
+        Self(KernelOp::new(kernel, device, vec![a_shape.n_elements()]))
+    }
+}
+
+impl<T: CudaFloat> Operator for CudaSelect<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.0.process(tensors)
+    }
+
+    fn try_process(
+        &mut self,
+        tensors: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        self.0.try_process(tensors, dyn_map)
     }
 
     fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
         if key == "elementwise" {
-            return Some(Box::new("(float)(input0 < input1 ? 1.0 : 0.0)".to_string()));
+            return Some(Box::new("(input1 != 0.0 ? input0 : 0.0)".to_string()));
         }
         None
     }
@@ -682,6 +561,16 @@ impl<T: CudaFloat> Operator for CudaMaxReduce<T> {
     }
 }
 
+/// Ops without a CUDA kernel. They run on the host like functions, with their inputs copied from the device and their
+/// output copied back.
+pub(crate) fn has_no_kernel(graph: &Graph, node: NodeIndex) -> bool {
+    graph.is_op::<MinReduce>(node)
+        || graph.is_op::<ProdReduce>(node)
+        || graph.is_op::<CumSum>(node)
+        || graph.is_op::<IndexAdd>(node)
+        || graph.is_op::<Cast>(node)
+}
+
 /// Convert all primitive ops to cuda primitive ops, and insert copy to and from device ops
 #[derive(Debug, Default)]
 pub struct PrimitiveCompiler<T>(PhantomData<T>);
@@ -689,90 +578,10 @@ pub struct PrimitiveCompiler<T>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let dev = CudaDevice::new(0).unwrap();
-        // Go through the graph and insert copy ops
-        // Copy function output to device and input from device
-        for function_node in graph
-            .node_indices()
-            .filter(|n| {
-                graph.node_weight(*n).unwrap().as_any().is::<Function>()
-                    && graph.edges(*n).count() != 0
-            })
-            .collect::<Vec<_>>()
-        {
-            // Create copy node
-            let copy_node = graph
-                .add_op(CudaCopyToDevice::<T>::new(dev.clone()))
-                .input(function_node, 0, ShapeTracker::new(&[]))
-                .finish();
-
-            // Switch outgoing edges from input to copy_node
-            for (edge_id, weight, dest) in graph
-                .edges_directed(function_node, petgraph::Direction::Outgoing)
-                .map(|e| (e.id(), *e.weight(), e.target()))
-                .filter(|(_, _, trg)| *trg != copy_node)
-                .collect::<Vec<_>>()
-            {
-                graph.add_edge(copy_node, dest, weight);
-                graph.remove_edge(edge_id);
-            }
-
-            if graph.no_delete.remove(&function_node) {
-                graph.no_delete.insert(copy_node);
-            }
-            if let Some(v) = graph.to_retrieve.get(&function_node) {
-                graph.to_retrieve.insert(copy_node, *v);
-            }
-
-            // Insert copy from device for function inputs
-            for (source, edge, edge_weight) in graph
-                .edges_directed(function_node, petgraph::Direction::Incoming)
-                .map(|e| (e.source(), e.id(), *e.weight()))
-                .collect::<Vec<_>>()
-            {
-                let copy_from_node = graph
-                    .add_op(CudaCopyFromDevice::<T>::new(dev.clone()))
-                    .input(source, 0, ShapeTracker::new(&[]))
-                    .finish();
-                graph.add_edge(copy_from_node, function_node, edge_weight);
-                graph.remove_edge(edge);
-            }
-        }
-
-        // Copy to_retrieve from device
-        for (output_node, (_, output_shape)) in graph
-            .to_retrieve
-            .iter()
-            .map(|(a, b)| (*a, *b))
-            // Filter to non-functions
-            .filter(|(n, _)| !graph.node_weight(*n).unwrap().as_any().is::<LFunction>())
-            .collect::<Vec<_>>()
-        {
-            if graph
-                .node_weight(output_node)
-                .unwrap()
-                .as_any()
-                .is::<CudaCopyToDevice<T>>()
-            {
-                // This output is already a copy to, instead of adding a copy from, let's remap back to the source
-                let src = graph
-                    .neighbors_directed(output_node, petgraph::Direction::Incoming)
-                    .next()
-                    .unwrap();
-                graph.no_delete.remove(&output_node);
-                graph.no_delete.insert(src);
-                let w = graph.to_retrieve.remove(&output_node).unwrap();
-                graph.to_retrieve.insert(src, w);
-            } else {
-                // Create copy node
-                let copy_node = graph
-                    .add_op(CudaCopyFromDevice::<T>::new(dev.clone()))
-                    .input(output_node, 0, output_shape)
-                    .finish();
-
-                remap(output_node, copy_node, &mut ids, graph);
-            }
-        }
+        let dev = crate::cuda_device();
+        // All copies in the graph share the transfer streams
+        let cuda = Cuda::<T>::new(dev.clone());
+        InsertCopies::new(cuda.clone(), has_no_kernel).compile(graph, &mut ids);
 
         fn is<T: Any>(type_id: TypeId) -> bool {
             type_id == TypeId::of::<T>()
@@ -789,11 +598,11 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
             let op = graph.node_weight(id).unwrap().as_any().type_id();
             let op_ref = graph.graph.node_weight_mut(id).unwrap();
             if is::<Log2>(op) {
-                *op_ref = Box::new(CudaLog2::<T>::new(shapes[0], dev.clone(), &graph.dyn_map));
+                *op_ref = Box::new(CudaLog2::<T>::new(shapes[0], cuda.clone()));
             } else if is::<Exp2>(op) {
-                *op_ref = Box::new(CudaExp2::<T>::new(shapes[0], dev.clone(), &graph.dyn_map));
+                *op_ref = Box::new(CudaExp2::<T>::new(shapes[0], cuda.clone()));
             } else if is::<Sin>(op) {
-                *op_ref = Box::new(CudaSin::<T>::new(shapes[0], dev.clone(), &graph.dyn_map));
+                *op_ref = Box::new(CudaSin::<T>::new(shapes[0], cuda.clone()));
             } else if let Some(c) = op_ref.as_any().downcast_ref::<Constant>() {
                 *op_ref = Box::new(CudaConstant::<T>::new(
                     dev.clone(),
@@ -801,43 +610,21 @@ impl<T: CudaFloat> Compiler for PrimitiveCompiler<T> {
                     &graph.dyn_map,
                 ));
             } else if is::<Recip>(op) {
-                *op_ref = Box::new(CudaRecip::<T>::new(shapes[0], dev.clone(), &graph.dyn_map));
+                *op_ref = Box::new(CudaRecip::<T>::new(shapes[0], cuda.clone()));
             } else if is::<Sqrt>(op) {
-                *op_ref = Box::new(CudaSqrt::<T>::new(shapes[0], dev.clone(), &graph.dyn_map));
+                *op_ref = Box::new(CudaSqrt::<T>::new(shapes[0], cuda.clone()));
             } else if is::<Add>(op) {
-                *op_ref = Box::new(CudaAdd::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(CudaAdd::<T>::new(shapes[0], shapes[1], cuda.clone()));
             } else if is::<Mul>(op) {
-                *op_ref = Box::new(CudaMul::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(CudaMul::<T>::new(shapes[0], shapes[1], cuda.clone()));
             } else if is::<Mod>(op) {
-                *op_ref = Box::new(CudaMod::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(CudaMod::<T>::new(shapes[0], shapes[1], cuda.clone()));
             } else if is::<LessThan>(op) {
-                *op_ref = Box::new(CudaLessThan::<T>::new(
-                    shapes[0],
-                    shapes[1],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(CudaLessThan::<T>::new(shapes[0], shapes[1], cuda.clone()));
+            } else if is::<Select>(op) {
+                *op_ref = Box::new(CudaSelect::<T>::new(shapes[0], shapes[1], cuda.clone()));
             } else if is::<Contiguous>(op) {
-                *op_ref = Box::new(CudaContiguous::<T>::new(
-                    shapes[0],
-                    dev.clone(),
-                    &graph.dyn_map,
-                ));
+                *op_ref = Box::new(CudaContiguous::<T>::new(shapes[0], cuda.clone()));
             } else if let Some(SumReduce(dim)) = op_ref.as_any().downcast_ref() {
                 *op_ref = Box::new(CudaSumReduce::<T>::new(
                     *dim,
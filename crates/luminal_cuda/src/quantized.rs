@@ -257,7 +257,7 @@ impl<T> CudaQuantizedCompiler<T> {
 impl<T: CudaFloat + Default> Compiler for CudaQuantizedCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut remap: To) {
-        let device = CudaDevice::new(0).unwrap();
+        let device = crate::cuda_device();
         let mut weight_ids = self.0.clone();
         let mut local_remap = remap.to_ids_mut();
         for w in &mut weight_ids {
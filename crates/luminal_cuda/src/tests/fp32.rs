@@ -20,7 +20,7 @@ use luminal::{
     },
 };
 
-use crate::{single_unary_test, CudaCompiler};
+use crate::{single_unary_test, CudaCompiler, CudaGraphCompiler};
 single_unary_test!(|a| a.ln(), |a| a.ln(), test_ln, f32, 3); // For some reason ln fails on larger tensors
 
 #[test]
@@ -81,6 +81,62 @@ fn test_constant() {
     assert_exact(&a.data(), &[625.0]);
 }
 
+#[test]
+fn test_staged_transfers() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'a'>,)>();
+    let mut b = (a * 2.).retrieve();
+    cx.compile(CudaCompiler::<f32>::default(), &mut b);
+
+    // Staging buffers grow for larger inputs and are reused for smaller ones
+    for n in [4, 1000, 7] {
+        let data = random_vec(n);
+        a.set_dyn(data.clone(), &[n]);
+        cx.execute();
+        assert_exact(&b.data(), &data.iter().map(|x| x * 2.).collect::<Vec<_>>());
+        b.drop();
+    }
+}
+
+#[test]
+fn test_cuda_graph() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'s'>, LConst<4>)>();
+    let w_data = random_vec(12);
+    let w = cx.tensor::<R2<4, 3>>().set(w_data.clone()).keep();
+    let mut b = ((a.matmul(w) + 1.).relu() * 2.)
+        .sum_reduce::<_, LAxis<1>>()
+        .retrieve();
+    cx.compile(
+        (
+            CudaCompiler::<f32>::default(),
+            CudaGraphCompiler::<f32>::default(),
+        ),
+        &mut b,
+    );
+
+    // Captured on the second run with the same dims, replayed after that, and captured again when they change
+    for s in [2, 2, 2, 3, 3, 2, 2] {
+        let data = random_vec(s * 4);
+        a.set_dyn(data.clone(), &[s, 4]);
+        cx.execute();
+        let expected = (0..s)
+            .map(|i| {
+                (0..3)
+                    .map(|j| {
+                        let x = (0..4)
+                            .map(|k| data[i * 4 + k] * w_data[k * 3 + j])
+                            .sum::<f32>();
+                        (x + 1.).max(0.) * 2.
+                    })
+                    .sum::<f32>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+        b.drop();
+    }
+}
+
 #[test]
 fn test_log2() {
     let mut cx = Graph::new();
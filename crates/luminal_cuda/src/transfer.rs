@@ -0,0 +1,205 @@
+use std::{ffi::c_void, sync::Arc};
+
+use cudarc::driver::{
+    result::{self, stream::StreamKind, DriverError},
+    sys, CudaDevice, CudaSlice, DevicePtr, DeviceRepr, DeviceSlice,
+};
+
+/// Page-locked host memory. The GPU copies to and from it directly, so copies can run asynchronously, while pageable
+/// memory has to be staged through a driver buffer first, which makes every copy synchronous.
+pub struct PinnedBuffer<T> {
+    ptr: *mut T,
+    len: usize,
+}
+
+impl<T: DeviceRepr> PinnedBuffer<T> {
+    /// Needs the device's context bound to the current thread
+    fn new(len: usize) -> Result<Self, DriverError> {
+        let mut ptr = std::ptr::null_mut::<c_void>();
+        unsafe {
+            sys::lib()
+                .cuMemAllocHost_v2(&mut ptr, len.max(1) * std::mem::size_of::<T>())
+                .result()?;
+        }
+        Ok(Self {
+            ptr: ptr as *mut T,
+            len,
+        })
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl<T> Drop for PinnedBuffer<T> {
+    fn drop(&mut self) {
+        unsafe {
+            sys::lib()
+                .cuMemFreeHost(self.ptr as *mut c_void)
+                .result()
+                .unwrap();
+        }
+    }
+}
+
+/// A copy op's pinned buffer, with events for ordering its copies against the default stream
+pub struct Staging<T> {
+    buffer: Option<PinnedBuffer<T>>,
+    /// Recorded after the last copy through the buffer, so it isn't refilled while the GPU is still reading it
+    done: Option<sys::CUevent>,
+    /// Recorded on the default stream before a copy, for the copy's stream to wait on
+    ready: Option<sys::CUevent>,
+}
+
+impl<T> Default for Staging<T> {
+    fn default() -> Self {
+        Self {
+            buffer: None,
+            done: None,
+            ready: None,
+        }
+    }
+}
+
+impl<T: DeviceRepr> Staging<T> {
+    /// The first `len` elements of the buffer once the last copy through it is done, growing it if it's too small
+    fn take(&mut self, len: usize) -> Result<&mut [T], DriverError> {
+        if let Some(done) = self.done {
+            unsafe { sys::lib().cuEventSynchronize(done).result()? };
+        }
+        if self.buffer.as_ref().map(|b| b.len < len).unwrap_or(true) {
+            self.buffer = Some(PinnedBuffer::new(len)?);
+        }
+        Ok(&mut self.buffer.as_mut().unwrap().as_mut_slice()[..len])
+    }
+}
+
+/// Record `event` on `stream`, creating it the first time
+fn record(
+    event: &mut Option<sys::CUevent>,
+    stream: sys::CUstream,
+) -> Result<sys::CUevent, DriverError> {
+    let event = match *event {
+        Some(event) => event,
+        None => *event.insert(result::event::create(
+            sys::CUevent_flags::CU_EVENT_DISABLE_TIMING,
+        )?),
+    };
+    unsafe { result::event::record(event, stream)? };
+    Ok(event)
+}
+
+/// Make `stream` wait for everything before `event`, without blocking the host
+fn wait(stream: sys::CUstream, event: sys::CUevent) -> Result<(), DriverError> {
+    unsafe {
+        result::stream::wait_event(
+            stream,
+            event,
+            sys::CUevent_wait_flags::CU_EVENT_WAIT_DEFAULT,
+        )
+    }
+}
+
+impl<T> Drop for Staging<T> {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(done) = self.done {
+                sys::lib().cuEventSynchronize(done).result().unwrap();
+            }
+            for event in [self.done, self.ready].into_iter().flatten() {
+                result::event::destroy(event).unwrap();
+            }
+        }
+    }
+}
+
+/// Copies between host and device for one compiled graph, each direction on its own stream so they overlap with
+/// kernels on the device's default stream. Kernels only wait on the copies they read, so weights for later layers keep
+/// uploading while earlier layers run.
+pub struct TransferManager {
+    dev: Arc<CudaDevice>,
+    upload: sys::CUstream,
+    download: sys::CUstream,
+}
+
+// Streams can be used from any thread, like the device's own
+unsafe impl Send for TransferManager {}
+unsafe impl Sync for TransferManager {}
+
+impl std::fmt::Debug for TransferManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TransferManager")
+    }
+}
+
+impl TransferManager {
+    pub fn new(dev: Arc<CudaDevice>) -> Result<Self, DriverError> {
+        dev.bind_to_thread()?;
+        Ok(Self {
+            upload: result::stream::create(StreamKind::NonBlocking)?,
+            download: result::stream::create(StreamKind::NonBlocking)?,
+            dev,
+        })
+    }
+
+    /// Copy `data` to the device through `staging`, converting it on the way. Returns once the copy is queued, and
+    /// kernels queued after it wait for it to finish.
+    pub fn upload<T: DeviceRepr>(
+        &self,
+        staging: &mut Staging<T>,
+        data: &[f32],
+        convert: impl Fn(f32) -> T,
+    ) -> Result<CudaSlice<T>, DriverError> {
+        self.dev.bind_to_thread()?;
+        let host = staging.take(data.len())?;
+        for (h, d) in host.iter_mut().zip(data) {
+            *h = convert(*d);
+        }
+        let dst = unsafe { self.dev.alloc::<T>(data.len())? };
+        // The allocation is ordered on the default stream, so the copy has to wait for it
+        let stream = *self.dev.cu_stream();
+        wait(self.upload, record(&mut staging.ready, stream)?)?;
+        let host = &staging.buffer.as_ref().unwrap().as_slice()[..data.len()];
+        unsafe { result::memcpy_htod_async(*dst.device_ptr(), host, self.upload)? };
+        wait(stream, record(&mut staging.done, self.upload)?)?;
+        Ok(dst)
+    }
+
+    /// Copy `src` back to the host through `staging` once the kernels writing it are done
+    pub fn download<T: DeviceRepr, O>(
+        &self,
+        staging: &mut Staging<T>,
+        src: &CudaSlice<T>,
+        convert: impl Fn(&T) -> O,
+    ) -> Result<Vec<O>, DriverError> {
+        self.dev.bind_to_thread()?;
+        wait(
+            self.download,
+            record(&mut staging.ready, *self.dev.cu_stream())?,
+        )?;
+        let host = staging.take(src.len())?;
+        unsafe { result::memcpy_dtoh_async(host, *src.device_ptr(), self.download)? };
+        let done = record(&mut staging.done, self.download)?;
+        unsafe { sys::lib().cuEventSynchronize(done).result()? };
+        Ok(staging.buffer.as_ref().unwrap().as_slice()[..src.len()]
+            .iter()
+            .map(convert)
+            .collect())
+    }
+}
+
+impl Drop for TransferManager {
+    fn drop(&mut self) {
+        unsafe {
+            result::stream::synchronize(self.upload).unwrap();
+            result::stream::synchronize(self.download).unwrap();
+            result::stream::destroy(self.upload).unwrap();
+            result::stream::destroy(self.download).unwrap();
+        }
+    }
+}
@@ -18,7 +18,7 @@ use crate::{
         CudaAdd, CudaConstant, CudaContiguous, CudaExp2, CudaMaxReduce, CudaMul, CudaRecip,
         CudaSin, CudaSqrt, CudaSumReduce,
     },
-    render_dyn_dim_inputs, CudaData, CudaFloat,
+    render_dyn_dim_inputs, Cuda, CudaData, CudaFloat, CudaKernel,
 };
 
 /// Special kernel for efficient mean reduction
@@ -128,7 +128,7 @@ pub struct MeanReduceCompiler<T>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for MeanReduceCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         // Look for the mean-reduce pattern
         // mul(recip(fake_sum_reduce(const_ones)), sum_reduce(x))
         let fake_sum_reduce = op::<CudaConstant<T>>();
@@ -314,7 +314,7 @@ pub struct StdNormCompiler<T>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for StdNormCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         // Look for the RMSNorm pattern
         // mul(recip(sqrt(add(mean_reduce(mul(x, x)), 1e-6))), x)
 
@@ -374,7 +374,7 @@ impl<T: CudaFloat> Compiler for StdNormCompiler<T> {
             // Input must be contiguous
             if sh.is_reshaped() {
                 x = graph
-                    .add_op(CudaContiguous::<T>::new(sh, dev.clone(), &graph.dyn_map))
+                    .add_op(CudaContiguous::<T>::new(sh, Cuda::new(dev.clone())))
                     .input(x, 0, sh)
                     .finish();
                 sh = sh.contiguous();
@@ -406,7 +406,7 @@ pub struct CudaExpCompiler<T: CudaFloat>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for CudaExpCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         // Look for the exp pattern
         // exp2(mul(x, const))
 
@@ -429,7 +429,7 @@ impl<T: CudaFloat> Compiler for CudaExpCompiler<T> {
                 .as_data()
                 .unwrap();
             let exp = graph
-                .add_op(CudaExp::<T>::new(src_shape, dev.clone(), &graph.dyn_map))
+                .add_op(CudaExp::<T>::new(src_shape, Cuda::new(dev.clone())))
                 .input(s.get(&inp), 0, src_shape)
                 .finish();
 
@@ -454,7 +454,7 @@ pub struct CudaCosCompiler<T>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for CudaCosCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         // Look for the cos pattern
         // sin(add(mul(const_neg_one, x), const_pi_over_2))
 
@@ -480,7 +480,7 @@ impl<T: CudaFloat> Compiler for CudaCosCompiler<T> {
                 .unwrap()
                 .2;
             let cos = graph
-                .add_op(CudaCos::<T>::new(shape, dev.clone(), &graph.dyn_map))
+                .add_op(CudaCos::<T>::new(shape, Cuda::new(dev.clone())))
                 .input(s.get(&inp), 0, shape)
                 .finish();
 
@@ -609,7 +609,7 @@ pub struct SoftmaxCompiler<T>(PhantomData<T>);
 impl<T: CudaFloat> Compiler for SoftmaxCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
-        let dev = CudaDevice::new(0).unwrap();
+        let dev = crate::cuda_device();
         // Look for the mean-reduce pattern
         // mul(recip(fake_sum_reduce(const_ones)), sum_reduce(x))
 
@@ -0,0 +1,61 @@
+/* C bindings for running graphs saved with Graph::save. Passing a NULL model or run fails like any other error. */
+#ifndef LUMINAL_H
+#define LUMINAL_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A loaded and compiled graph. Any number of runs can use one model at once, from different threads. */
+typedef struct LuminalModel LuminalModel;
+
+/* One run of a model, with its bound inputs and the outputs of its last execution. */
+typedef struct LuminalRun LuminalRun;
+
+/* Load a saved graph and compile it. Returns NULL if it can't be loaded. */
+LuminalModel *luminal_model_load(const char *path);
+
+/* Free a model. Every run started from it needs to be freed first. */
+void luminal_model_free(LuminalModel *model);
+
+/* Number of inputs each run needs bound, or 0 if model is NULL. */
+size_t luminal_model_num_inputs(const LuminalModel *model);
+
+/* Name of an input, or NULL if there's no input at index. The name lives as long as the model. */
+const char *luminal_model_input_name(const LuminalModel *model, size_t index);
+
+/* Number of outputs each run produces, or 0 if model is NULL. */
+size_t luminal_model_num_outputs(const LuminalModel *model);
+
+/* Start a run of a model. Returns NULL if model is NULL. The run only keeps a pointer to the model, so the model
+ * must not be freed until every run started from it is. */
+LuminalRun *luminal_run_new(const LuminalModel *model);
+
+/* Free a run, along with its outputs. */
+void luminal_run_free(LuminalRun *run);
+
+/* Copy len floats into an input, with a shape of rank dimensions. Binding an input again replaces its data.
+ * Returns 0, or -1 if the arguments are invalid. */
+int luminal_run_bind(LuminalRun *run, const char *name, const float *data, size_t len, const size_t *shape,
+                     size_t rank);
+
+/* Execute the model on the bound inputs. Returns 0, or -1 if an input is missing or doesn't fit. */
+int luminal_run_execute(LuminalRun *run);
+
+/* Number of floats in an output of the last execution, or 0 if there's no output at index. */
+size_t luminal_run_output_len(const LuminalRun *run, size_t index);
+
+/* Data of an output of the last execution, or NULL if there's no output at index. The data stays valid until the run
+ * is executed again or freed. */
+const float *luminal_run_output(const LuminalRun *run, size_t index);
+
+/* The last error on this thread, or NULL if nothing has failed yet. The message lives until the next error. */
+const char *luminal_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
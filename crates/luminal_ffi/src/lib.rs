@@ -0,0 +1,350 @@
+//! C bindings for running saved graphs, declared in `include/luminal.h`.
+//!
+//! A model is a graph written with `Graph::save`, compiled for the CPU when it's loaded. Runs are started from a model,
+//! have their inputs bound by name, and hold their outputs until they're ran again or freed. Any number of runs can
+//! use one model at once, from different threads.
+//!
+//! Functions that can fail return NULL, -1, or 0 for counts, and leave a message for `luminal_last_error` on the
+//! calling thread. Passing a NULL model or run fails the same way. Panics are caught before they reach the caller.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::Path,
+    ptr, slice,
+};
+
+use luminal::prelude::*;
+use luminal_cpu::CPUCompiler;
+
+/// A loaded and compiled graph
+pub struct LuminalModel {
+    model: CompiledGraph,
+    input_names: Vec<CString>,
+}
+
+/// One run of a model, with its bound inputs and the outputs of its last execution
+pub struct LuminalRun {
+    /// Borrowed from the caller, who keeps the model alive until the run is freed
+    model: *const LuminalModel,
+    inputs: Vec<(String, Vec<f32>, Vec<usize>)>,
+    outputs: Vec<Vec<f32>>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: impl ToString) {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Run `f`, turning errors and panics into `failed` with the message saved for `luminal_last_error`
+fn guard<T>(failed: T, f: impl FnOnce() -> Result<T, String>) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(message)) => {
+            set_error(message);
+            failed
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<String>()
+                .cloned()
+                .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "Unknown panic".to_string());
+            set_error(message);
+            failed
+        }
+    }
+}
+
+const NULL_MODEL: &str = "Model is NULL";
+const NULL_RUN: &str = "Run is NULL";
+
+impl LuminalModel {
+    /// Load a saved graph and compile it
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LuminalError> {
+        let mut cx = Graph::new();
+        let outputs = cx.load(path)?;
+        // Inputs go in the order they were saved in
+        let mut inputs = cx
+            .inputs
+            .iter()
+            .map(|(name, (id, shape))| (name.clone(), *id, *shape))
+            .collect::<Vec<_>>();
+        inputs.sort_by_key(|(_, id, _)| *id);
+        let input_tensors = inputs
+            .iter()
+            .map(|(_, id, shape)| GraphTensor::<()>::from_id(*id, *shape, &mut cx))
+            .collect::<Vec<_>>();
+        let output_tensors = outputs
+            .iter()
+            .map(|(id, shape)| GraphTensor::<()>::from_id(*id, *shape, &mut cx))
+            .collect::<Vec<_>>();
+        let model = cx.freeze(
+            (GenericCompiler::default(), CPUCompiler),
+            &input_tensors,
+            &output_tensors,
+        );
+        Ok(Self {
+            input_names: model
+                .input_names()
+                .map(|n| CString::new(n.replace('\0', " ")).unwrap())
+                .collect(),
+            model,
+        })
+    }
+}
+
+/// Load a graph saved with `Graph::save` and compile it. Returns NULL if it can't be loaded.
+///
+/// # Safety
+/// `path` must be a valid, NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_model_load(path: *const c_char) -> *mut LuminalModel {
+    guard(ptr::null_mut(), || {
+        if path.is_null() {
+            return Err("Path is NULL".to_string());
+        }
+        let path = CStr::from_ptr(path).to_str().map_err(|e| e.to_string())?;
+        let model = LuminalModel::load(path).map_err(|e| e.to_string())?;
+        Ok(Box::into_raw(Box::new(model)))
+    })
+}
+
+/// Free a model. Every run started from it needs to be freed first.
+///
+/// # Safety
+/// `model` must come from `luminal_model_load`, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_model_free(model: *mut LuminalModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// Number of inputs each run needs bound, or 0 if `model` is NULL
+///
+/// # Safety
+/// `model` must be a live model, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_model_num_inputs(model: *const LuminalModel) -> usize {
+    guard(0, || {
+        let model = model.as_ref().ok_or(NULL_MODEL)?;
+        Ok(model.model.num_inputs())
+    })
+}
+
+/// Name of an input, or NULL if there's no input at `index`. The name lives as long as the model.
+///
+/// # Safety
+/// `model` must be a live model, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_model_input_name(
+    model: *const LuminalModel,
+    index: usize,
+) -> *const c_char {
+    guard(ptr::null(), || {
+        let model = model.as_ref().ok_or(NULL_MODEL)?;
+        Ok(model
+            .input_names
+            .get(index)
+            .map_or(ptr::null(), |n| n.as_ptr()))
+    })
+}
+
+/// Number of outputs each run produces, or 0 if `model` is NULL
+///
+/// # Safety
+/// `model` must be a live model, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_model_num_outputs(model: *const LuminalModel) -> usize {
+    guard(0, || {
+        let model = model.as_ref().ok_or(NULL_MODEL)?;
+        Ok(model.model.num_outputs())
+    })
+}
+
+/// Start a run of a model. Returns NULL if `model` is NULL.
+///
+/// The run only keeps a pointer to the model, so the model must not be freed until every run started from it is.
+///
+/// # Safety
+/// `model` must be a live model, or NULL, and outlive the run.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_run_new(model: *const LuminalModel) -> *mut LuminalRun {
+    guard(ptr::null_mut(), || {
+        if model.is_null() {
+            return Err(NULL_MODEL.to_string());
+        }
+        Ok(Box::into_raw(Box::new(LuminalRun {
+            model,
+            inputs: vec![],
+            outputs: vec![],
+        })))
+    })
+}
+
+/// Free a run, along with its outputs
+///
+/// # Safety
+/// `run` must come from `luminal_run_new`, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_run_free(run: *mut LuminalRun) {
+    if !run.is_null() {
+        drop(Box::from_raw(run));
+    }
+}
+
+/// Copy `len` floats into an input, with a shape of `rank` dimensions. Binding an input again replaces its data.
+/// Returns 0, or -1 if the arguments are invalid. Whether the data fits the input is checked when the run executes.
+///
+/// # Safety
+/// `run` must be a live run or NULL, `name` a NUL terminated string, and `data` and `shape` must hold `len` and `rank` elements.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_run_bind(
+    run: *mut LuminalRun,
+    name: *const c_char,
+    data: *const f32,
+    len: usize,
+    shape: *const usize,
+    rank: usize,
+) -> c_int {
+    guard(-1, || {
+        let run = run.as_mut().ok_or(NULL_RUN)?;
+        if name.is_null() || (data.is_null() && len > 0) || (shape.is_null() && rank > 0) {
+            return Err("Name, data or shape is NULL".to_string());
+        }
+        let name = CStr::from_ptr(name).to_str().map_err(|e| e.to_string())?;
+        let data = if len == 0 {
+            vec![]
+        } else {
+            slice::from_raw_parts(data, len).to_vec()
+        };
+        let shape = if rank == 0 {
+            vec![]
+        } else {
+            slice::from_raw_parts(shape, rank).to_vec()
+        };
+        run.inputs.retain(|(n, _, _)| n != name);
+        run.inputs.push((name.to_string(), data, shape));
+        Ok(0)
+    })
+}
+
+/// Execute the model on the bound inputs. Returns 0, or -1 if an input is missing or doesn't fit.
+///
+/// # Safety
+/// `run` must be a live run, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_run_execute(run: *mut LuminalRun) -> c_int {
+    guard(-1, || {
+        let run = run.as_mut().ok_or(NULL_RUN)?;
+        let model = &(&*run.model).model;
+        run.outputs = run
+            .inputs
+            .iter()
+            .fold(model.context(), |cx, (name, data, shape)| {
+                cx.with_input(name, data.clone(), shape)
+            })
+            .run()
+            .map_err(|e| e.to_string())?;
+        Ok(0)
+    })
+}
+
+/// Number of floats in an output of the last execution, or 0 if there's no output at `index`
+///
+/// # Safety
+/// `run` must be a live run, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_run_output_len(run: *const LuminalRun, index: usize) -> usize {
+    guard(0, || {
+        let run = run.as_ref().ok_or(NULL_RUN)?;
+        Ok(run.outputs.get(index).map_or(0, |o| o.len()))
+    })
+}
+
+/// Data of an output of the last execution, or NULL if there's no output at `index`. The data stays valid until the
+/// run is executed again or freed.
+///
+/// # Safety
+/// `run` must be a live run, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn luminal_run_output(run: *const LuminalRun, index: usize) -> *const f32 {
+    guard(ptr::null(), || {
+        let run = run.as_ref().ok_or(NULL_RUN)?;
+        Ok(run.outputs.get(index).map_or(ptr::null(), |o| o.as_ptr()))
+    })
+}
+
+/// The last error on this thread, or NULL if nothing has failed yet. The message lives until the next error.
+#[no_mangle]
+pub extern "C" fn luminal_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_c_api() {
+        let mut cx = Graph::new();
+        let x = cx.input::<(Dyn<'b'>, Const<2>)>("x");
+        let w = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+        let y = x.matmul(w).relu();
+        let path = std::env::temp_dir().join(format!("luminal_ffi_{}.bin", std::process::id()));
+        cx.save(&path, &[(y.id, y.shape)]).unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        unsafe {
+            let model = luminal_model_load(c_path.as_ptr());
+            std::fs::remove_file(&path).unwrap();
+            assert!(!model.is_null());
+            assert_eq!(luminal_model_num_inputs(model), 1);
+            assert_eq!(luminal_model_num_outputs(model), 1);
+            let name = CStr::from_ptr(luminal_model_input_name(model, 0));
+            assert_eq!(name.to_str().unwrap(), "x");
+            assert!(luminal_model_input_name(model, 1).is_null());
+
+            let run = luminal_run_new(model);
+            // Nothing bound yet
+            assert_eq!(luminal_run_execute(run), -1);
+            let error = CStr::from_ptr(luminal_last_error());
+            assert_eq!(
+                error.to_str().unwrap(),
+                "Input x must be bound before running"
+            );
+
+            let (data, shape) = ([1., -1., 0., 1.], [2, 2]);
+            assert_eq!(
+                luminal_run_bind(run, name.as_ptr(), data.as_ptr(), 4, shape.as_ptr(), 2),
+                0
+            );
+            assert_eq!(luminal_run_execute(run), 0);
+            let len = luminal_run_output_len(run, 0);
+            let out = slice::from_raw_parts(luminal_run_output(run, 0), len);
+            assert_eq!(out, &[0., 0., 0., 4., 5., 6.]);
+            assert!(luminal_run_output(run, 1).is_null());
+
+            luminal_run_free(run);
+            luminal_model_free(model);
+            assert!(luminal_model_load(c"/no/such/graph".as_ptr()).is_null());
+
+            // NULL models and runs are reported instead of dereferenced
+            assert_eq!(luminal_model_num_inputs(ptr::null()), 0);
+            assert!(luminal_model_input_name(ptr::null(), 0).is_null());
+            assert!(luminal_run_new(ptr::null()).is_null());
+            let error = CStr::from_ptr(luminal_last_error());
+            assert_eq!(error.to_str().unwrap(), "Model is NULL");
+            assert_eq!(luminal_run_execute(ptr::null_mut()), -1);
+            assert_eq!(luminal_run_output_len(ptr::null(), 0), 0);
+            let error = CStr::from_ptr(luminal_last_error());
+            assert_eq!(error.to_str().unwrap(), "Run is NULL");
+        }
+    }
+}
@@ -127,7 +127,7 @@ impl<T: MetalFloat> Operator for MetalSub<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -309,7 +309,7 @@ impl<T: MetalFloat> Operator for MetalEqual<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -341,7 +341,7 @@ impl<T: MetalFloat> Compiler for MetalEqualCompiler<T> {
             lt1.clone(),
             binary::<MetalLessThan<T>>(rhs.clone(), lhs.clone()),
         );
-        let eq = binary::<MetalSub<T>>(one, ne);
+        let eq = ordered_binary::<MetalSub<T>>(one, ne);
 
         let mut s = eq.clone().search(graph);
         while s.next_match() {
@@ -466,7 +466,7 @@ impl<T: MetalFloat> Operator for MetalGather<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 }
@@ -281,7 +281,7 @@ impl Operator for CommandBufferWrapper {
             unsafe { self.dyn_map.as_ref().unwrap() },
         )
         .into_iter()
-        .map(|b| Tensor::new(MetalBuffer(b)))
+        .map(|b| Tensor::new(MetalBuffer::new(b)))
         .collect()
     }
 
@@ -91,7 +91,7 @@ impl<T: MetalFloat> Compiler for ElementwiseFusionCompiler<T> {
                 };
                 if graph.no_delete.contains(&a)
                     || graph.no_delete.contains(&b)
-                    || (!graph.check_node_type::<MetalConstant<T>>(a)
+                    || (!graph.is_op::<MetalConstant<T>>(a)
                         && graph
                             .edges_directed(a, Direction::Outgoing)
                             .filter(|e| e.target() != b)
@@ -570,7 +570,7 @@ impl<T: MetalFloat> Operator for FusedElementwiseOp<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -1,6 +1,8 @@
 use std::{
     any::{Any, TypeId},
     fmt::{Debug, Write},
+    marker::PhantomData,
+    mem::size_of,
     ops::Deref,
     sync::Arc,
 };
@@ -26,7 +28,11 @@ use metal_rs::*;
 use prim::MetalConstant;
 use rustc_hash::FxHashMap;
 
-use luminal::{op::InputTensor, prelude::*};
+use luminal::{
+    backend::{self, Backend},
+    op::InputTensor,
+    prelude::*,
+};
 
 /// Compile graphs to run on Metal-supported macOS devices in supported data formats
 pub type MetalCompiler<T> = (MetalCompilerPreBuffer<T>, BufferCompilers);
@@ -58,13 +64,27 @@ pub type SpecialOpsCompiler<T> = (
     matmul::MetalMatMulCompiler<T>,
 );
 
+/// A GPU buffer holding a tensor. Planned buffers get allocated bigger than the tensors they hold, so the tensor's size
+/// is kept alongside the buffer instead of going by the buffer's length.
 #[derive(Debug, Clone)]
-pub struct MetalBuffer(pub Buffer);
+pub struct MetalBuffer {
+    pub buffer: Buffer,
+    /// Bytes of the buffer the tensor takes up
+    pub len: usize,
+}
+
+impl MetalBuffer {
+    /// Wrap a buffer the tensor fills entirely
+    pub fn new(buffer: Buffer) -> Self {
+        let len = buffer.length() as usize;
+        Self { buffer, len }
+    }
+}
 
 impl Deref for MetalBuffer {
     type Target = Buffer;
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.buffer
     }
 }
 
@@ -76,6 +96,93 @@ impl Data for MetalBuffer {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    // Buffers don't record what they hold, so they're described as f32s
+    fn size(&self) -> usize {
+        self.len
+    }
+
+    fn device(&self) -> &'static str {
+        "metal"
+    }
+}
+
+/// A Metal GPU running ops in `T`. Its buffers are shared with the host, so copies read and write them directly.
+#[derive(Clone)]
+pub struct Metal<T> {
+    pub device: Device,
+    _phantom: PhantomData<T>,
+}
+crate::debug_type!(Metal);
+
+impl<T> Metal<T> {
+    pub fn new(device: Device) -> Self {
+        Self {
+            device,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<T> Default for Metal<T> {
+    fn default() -> Self {
+        Self::new(Device::system_default().unwrap())
+    }
+}
+
+impl<T: MetalFloat> backend::Device for Metal<T> {
+    type Buffer = MetalBuffer;
+    type Staging = ();
+
+    fn alloc(&self, len: usize) -> Self::Buffer {
+        let bytes = len * size_of::<T>();
+        // Metal can't make empty buffers
+        let buffer = self.device.new_buffer(
+            bytes.max(size_of::<T>()) as u64,
+            MTLResourceOptions::StorageModeShared,
+        );
+        unsafe { std::ptr::write_bytes(buffer.contents() as *mut u8, 0, bytes) };
+        MetalBuffer { buffer, len: bytes }
+    }
+
+    fn copy_to_device(&self, _: &mut (), data: &[f32]) -> Self::Buffer {
+        let mut data = data.iter().copied().map(T::from_f32).collect::<Vec<T>>();
+        if data.is_empty() {
+            data.push(T::from_f32(0.0));
+        }
+        let buffer = self.device.new_buffer_with_bytes_no_copy(
+            data.as_ptr() as *mut _,
+            (data.len() * size_of::<T>()) as u64,
+            MTLResourceOptions::StorageModeShared,
+            None,
+        );
+        data.leak();
+        MetalBuffer::new(buffer)
+    }
+
+    fn copy_from_device(&self, _: &mut (), buffer: &Self::Buffer) -> Vec<f32> {
+        let ptr = buffer.contents() as *const T;
+        (0..buffer.len / size_of::<T>())
+            .map(|i| unsafe { *ptr.add(i) }.to_f32())
+            .collect()
+    }
+}
+
+impl<T: MetalFloat> Backend for Metal<T> {
+    type Device = Self;
+    type Compiler = MetalCompiler<T>;
+
+    fn device(&self) -> Self {
+        self.clone()
+    }
+
+    fn compiler(&self) -> Self::Compiler {
+        Default::default()
+    }
+
+    fn runs_on_host(graph: &Graph, node: NodeIndex) -> bool {
+        prim::has_no_kernel(graph, node)
+    }
 }
 
 pub trait MetalFloat: Copy + Debug + PartialEq + 'static + Default {
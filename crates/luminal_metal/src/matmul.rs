@@ -185,7 +185,7 @@ impl<T: MetalFloat> Operator for Matmul<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -158,7 +158,7 @@ impl<T: MetalFloat> Operator for MetalARange<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -190,7 +190,7 @@ impl<T: MetalFloat> Compiler for ARangeCompiler<T> {
             unary::<MetalSumReduce<T>>(unary::<MetalContiguous<T>>(unary::<MetalContiguous<T>>(
                 unary::<MetalContiguous<T>>(contig1.clone()),
             )));
-        let sub = binary::<MetalSub<T>>(sum_reduce.clone(), constant::<T>(1.));
+        let sub = ordered_binary::<MetalSub<T>>(sum_reduce.clone(), constant::<T>(1.));
         let mut s1 = sub.clone().search(graph);
         let neg_one = constant::<T>(-1.);
         let add = binary::<MetalAdd<T>>(sum_reduce, neg_one.clone());
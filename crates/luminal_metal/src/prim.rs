@@ -6,72 +6,13 @@ use objc::rc::autoreleasepool;
 use petgraph::visit::EdgeRef;
 use rustc_hash::FxHashMap;
 
-use luminal::{
-    op::{Function as LFunction, *},
-    prelude::*,
-};
+use luminal::{op::*, prelude::*};
 
 /// Copy a tensor to the GPU
-#[derive(Clone)]
-pub struct MetalCopyToDevice<T>(Device, PhantomData<T>);
-crate::debug_type!(MetalCopyToDevice);
-
-impl<T> MetalCopyToDevice<T> {
-    pub fn new(dev: Device) -> Self {
-        Self(dev, Default::default())
-    }
-}
-
-impl<T: MetalFloat> Operator for MetalCopyToDevice<T> {
-    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        if inp[0].0.borrowed().is::<MetalBuffer>() {
-            // Already on device
-            return vec![inp.pop().unwrap().0.cloned()];
-        }
-        let mut data = inp[0]
-            .0
-            .borrowed()
-            .downcast_ref::<Vec<f32>>()
-            .unwrap()
-            .iter()
-            .copied()
-            .map(MetalFloat::from_f32)
-            .collect::<Vec<T>>();
-        if data.is_empty() {
-            data.push(T::from_f32(0.0));
-        }
-        let buffer = self.0.new_buffer_with_bytes_no_copy(
-            data.as_ptr() as *mut _,
-            (data.len() * std::mem::size_of::<T>()) as u64,
-            MTLResourceOptions::StorageModeShared,
-            None,
-        );
-        data.leak();
-        vec![Tensor::new(MetalBuffer(buffer))]
-    }
-}
+pub type MetalCopyToDevice<T> = CopyToDevice<Metal<T>>;
 
 /// Copy a tensor from the GPU
-#[derive(Clone, Default)]
-pub struct MetalCopyFromDevice<T>(PhantomData<T>);
-crate::debug_type!(MetalCopyFromDevice);
-
-impl<T: MetalFloat> Operator for MetalCopyFromDevice<T> {
-    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        if inp[0].0.borrowed().is::<Vec<f32>>() {
-            // Already off device
-            return vec![inp.pop().unwrap().0.cloned()];
-        }
-        let buffer = get_buffer_from_tensor(&inp[0].0);
-        let mut data = vec![0.0; buffer.length() as usize / std::mem::size_of::<T>()];
-        let ptr = buffer.contents() as *mut T;
-        for (i, d) in data.iter_mut().enumerate() {
-            *d = unsafe { *ptr.add(i) }.to_f32();
-        }
-
-        vec![Tensor::new(data)]
-    }
-}
+pub type MetalCopyFromDevice<T> = CopyFromDevice<Metal<T>>;
 
 #[derive(Clone)]
 pub struct MetalConstant<T>(
@@ -101,7 +42,7 @@ impl<T: MetalFloat> Operator for MetalConstant<T> {
             }
             ConstantValue::Float(f) => *f,
         });
-        vec![Tensor::new(MetalBuffer(self.1.new_buffer_with_data(
+        vec![Tensor::new(MetalBuffer::new(self.1.new_buffer_with_data(
             &val as *const T as *const _,
             std::mem::size_of::<T>() as u64,
             MTLResourceOptions::StorageModeShared,
@@ -218,7 +159,7 @@ macro_rules! metal_unary_op {
                     command_buffer.commit();
                     command_buffer.wait_until_completed();
 
-                    vec![Tensor::new(MetalBuffer(out))]
+                    vec![Tensor::new(MetalBuffer::new(out))]
                 })
             }
 
@@ -345,7 +286,7 @@ impl<T: MetalFloat> Operator for MetalAdd<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -464,7 +405,7 @@ impl<T: MetalFloat> Operator for MetalMul<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -591,7 +532,7 @@ impl<T: MetalFloat> Operator for MetalLessThan<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -608,6 +549,133 @@ impl<T: MetalFloat> Operator for MetalLessThan<T> {
     }
 }
 
+#[derive(Clone)]
+pub struct MetalSelect<T> {
+    pipeline: ComputePipelineState,
+    queue: CommandQueue,
+    device: Device,
+    dyn_symbols: Vec<char>,
+    _phantom: PhantomData<T>,
+    dyn_map: *const FxHashMap<char, usize>,
+}
+crate::debug_type!(MetalSelect);
+
+impl<T: MetalFloat> MetalSelect<T> {
+    pub fn new(
+        a_shape: ShapeTracker,
+        b_shape: ShapeTracker,
+        device: Device,
+        queue: CommandQueue,
+        dyn_map: *const FxHashMap<char, usize>,
+    ) -> Self {
+        let (a_idx_exp, a_valid_exp) = get_idx_valid_exps(a_shape);
+        let (b_idx_exp, b_valid_exp) = get_idx_valid_exps(b_shape);
+        let type_name = T::type_name();
+        let (dyn_symbols, rendered) = render_dyn_dim_inputs(&[a_shape, b_shape], 4);
+        let code = format!("
+#include <metal_stdlib>
+using namespace metal;
+kernel void mkernel(device {type_name} *inp_a [[buffer(0)]], device {type_name} *inp_b [[buffer(1)]], device {type_name} *out [[buffer(2)]], device int& n_elements [[buffer(3)]], uint idx [[thread_position_in_grid]]{rendered}) {{
+    if (idx < n_elements) {{
+        {type_name} b_t = 0.0h;
+        if (({b_valid_exp}) != 0) {{
+            b_t = inp_b[{b_idx_exp}];
+        }}
+        if (b_t != 0.0h && ({a_valid_exp}) != 0) {{
+            out[idx] = inp_a[{a_idx_exp}];
+        }} else {{
+            out[idx] = 0.0h;
+        }}
+    }}
+}}
+"
+        );
+        Self {
+            pipeline: compile_function("mkernel", &code, &device),
+            queue,
+            device,
+            dyn_symbols,
+            _phantom: Default::default(),
+            dyn_map,
+        }
+    }
+}
+
+impl<T> MetalKernel for MetalSelect<T> {
+    fn output_buffer_sizes(&self, input_shapes: &[ShapeTracker]) -> Vec<BigExpression> {
+        vec![input_shapes[0].n_elements() * size_of::<T>()]
+    }
+    fn metal_forward(
+        &self,
+        inputs: &[(&Buffer, ShapeTracker)],
+        command_buffer: &CommandBufferRef,
+        _: &[&Buffer],
+        output_buffers: &[&Buffer],
+    ) {
+        let inp_size = inputs[0].1.n_elements().to_usize().unwrap();
+
+        let encoder =
+            command_buffer.compute_command_encoder_with_descriptor(ComputePassDescriptor::new());
+        encoder.set_compute_pipeline_state(&self.pipeline);
+
+        // Set inputs
+        encoder.set_buffer(0, Some(inputs[0].0), 0);
+        encoder.set_buffer(1, Some(inputs[1].0), 0);
+        encoder.set_buffer(2, Some(output_buffers[0]), 0);
+        encoder.set_u32(3, inp_size as u32);
+        input_dyn_dims(
+            &self.dyn_symbols,
+            unsafe { self.dyn_map.as_ref().unwrap() },
+            encoder,
+            4,
+        );
+
+        // Execute
+        encoder.dispatch_1d(inp_size);
+        encoder.end_encoding();
+    }
+}
+
+impl<T: MetalFloat> Operator for MetalSelect<T> {
+    fn process(&mut self, tensors: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        autoreleasepool(|| {
+            let command_buffer = self.queue.new_command_buffer();
+            let inp_size = tensors[0].1.n_elements().to_usize().unwrap();
+            let out = self.device.new_buffer(
+                (inp_size * std::mem::size_of::<T>()) as u64,
+                MTLResourceOptions::StorageModeShared,
+            );
+
+            self.metal_forward(
+                &[
+                    (get_buffer_from_tensor(&tensors[0].0), tensors[0].1),
+                    (get_buffer_from_tensor(&tensors[1].0), tensors[1].1),
+                ],
+                command_buffer,
+                &[],
+                &[&out],
+            );
+
+            command_buffer.commit();
+            command_buffer.wait_until_completed();
+
+            vec![Tensor::new(MetalBuffer::new(out))]
+        })
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn Any>) -> Option<Box<dyn Any>> {
+        if key == "metal" {
+            return Some(Box::new(MetalKernelWrapper(Arc::new(Box::new(
+                self.clone(),
+            )))));
+        }
+        if key == "elementwise" {
+            return Some(Box::new("((input1) != 0.0 ? (input0) : 0.0)".to_string()));
+        }
+        None
+    }
+}
+
 #[derive(Clone)]
 pub struct MetalMod<T> {
     pipeline: ComputePipelineState,
@@ -708,7 +776,7 @@ impl<T: MetalFloat> Operator for MetalMod<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -865,7 +933,7 @@ impl<T: MetalFloat> Operator for MetalSumReduce<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -1019,7 +1087,7 @@ impl<T: MetalFloat> Operator for MetalMaxReduce<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -1036,109 +1104,23 @@ impl<T: MetalFloat> Operator for MetalMaxReduce<T> {
 #[derive(Default, Debug)]
 pub struct PrimitiveCompiler<T>(PhantomData<T>);
 
+/// Ops without a Metal kernel. They run on the host like functions, with their inputs copied from the device and their
+/// output copied back.
+pub(crate) fn has_no_kernel(graph: &Graph, node: NodeIndex) -> bool {
+    graph.is_op::<MinReduce>(node)
+        || graph.is_op::<ProdReduce>(node)
+        || graph.is_op::<CumSum>(node)
+        || graph.is_op::<IndexAdd>(node)
+        || graph.is_op::<Cast>(node)
+}
+
 impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
     type Output = ();
     fn compile<To: ToIdsMut>(&self, graph: &mut Graph, mut ids: To) {
         let dev = Device::system_default().unwrap();
         let queue = dev.new_command_queue();
-        // Go through the graph and insert copy ops
-        // Copy function output to device and input from device
-        for function_node in graph
-            .node_indices()
-            .filter(|n| graph.node_weight(*n).unwrap().as_any().is::<LFunction>())
-            .collect::<Vec<_>>()
-        {
-            if graph
-                .edges_directed(function_node, petgraph::Direction::Outgoing)
-                .count()
-                > 0
-            {
-                // Copy outputs to device
-                let copy_node = graph
-                    .add_op(MetalCopyToDevice::<T>::new(dev.clone()))
-                    .input(function_node, 0, ShapeTracker::new(&[]))
-                    .finish();
-
-                // Switch outgoing edges from input to copy_node
-                for (edge_id, weight, dest) in graph
-                    .edges_directed(function_node, petgraph::Direction::Outgoing)
-                    .map(|e| (e.id(), *e.weight(), e.target()))
-                    .filter(|(_, _, trg)| *trg != copy_node)
-                    .collect::<Vec<_>>()
-                {
-                    graph.add_edge(copy_node, dest, weight);
-                    graph.remove_edge(edge_id);
-                }
-
-                if graph.no_delete.remove(&function_node) {
-                    graph.no_delete.insert(copy_node);
-                }
-                if let Some(w) = graph.to_retrieve.remove(&function_node) {
-                    graph.to_retrieve.insert(copy_node, w);
-                }
-            }
-
-            // Insert copy from device for function inputs
-            for (source, edge, edge_weight) in graph
-                .edges_directed(function_node, petgraph::Direction::Incoming)
-                .filter(|e| !e.weight().is_schedule())
-                .map(|e| (e.source(), e.id(), *e.weight()))
-                .collect::<Vec<_>>()
-            {
-                let (input_order, output_order, shape) = edge_weight.as_data().unwrap();
-                let copy_from_node = graph
-                    .add_op(MetalCopyFromDevice::<T>::default())
-                    .input(source, output_order, shape)
-                    .finish();
-                graph.add_edge(
-                    copy_from_node,
-                    function_node,
-                    Dependency::Data {
-                        input_order,
-                        output_order: 0,
-                        shape,
-                    },
-                );
-                graph.remove_edge(edge);
-            }
-        }
-
-        // Copy to_retrieve from device
-        for (output_node, (_, output_shape)) in graph
-            .to_retrieve
-            .iter()
-            .map(|(a, b)| (*a, *b))
-            // Filter to non-functions
-            .filter(|(n, _)| !graph.node_weight(*n).unwrap().as_any().is::<LFunction>())
-            .collect::<Vec<_>>()
-        {
-            if graph
-                .node_weight(output_node)
-                .unwrap()
-                .as_any()
-                .is::<MetalCopyToDevice<T>>()
-            {
-                // This output is already a copy to, instead of adding a copy from, let's remap back to the source
-                let src = graph
-                    .neighbors_directed(output_node, petgraph::Direction::Incoming)
-                    .next()
-                    .unwrap();
-                if graph.no_delete.remove(&output_node) {
-                    graph.no_delete.insert(src);
-                }
-                if let Some(w) = graph.to_retrieve.remove(&output_node) {
-                    graph.to_retrieve.insert(src, w);
-                }
-            } else {
-                // Create copy node
-                let copy_node = graph
-                    .add_op(MetalCopyFromDevice::<T>::default())
-                    .input(output_node, 0, output_shape)
-                    .finish();
-
-                remap(output_node, copy_node, &mut ids, graph);
-            }
-        }
+        // Copy host op outputs to the device and their inputs from it
+        InsertCopies::new(Metal::<T>::new(dev.clone()), has_no_kernel).compile(graph, &mut ids);
 
         // Swap primitive ops
         for id in graph.node_indices().collect::<Vec<_>>() {
@@ -1168,7 +1150,7 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                 *op_ref = Box::new(MetalConstant::<T>(
                     c.0.clone(),
                     dev.clone(),
-                    c.1,
+                    &graph.dyn_map,
                     Default::default(),
                 ));
             } else if is::<Sin>(op) {
@@ -1216,6 +1198,14 @@ impl<T: MetalFloat + 'static> Compiler for PrimitiveCompiler<T> {
                     queue.clone(),
                     &graph.dyn_map,
                 ));
+            } else if is::<Select>(op) {
+                *op_ref = Box::new(MetalSelect::<T>::new(
+                    src_shapes[0],
+                    src_shapes[1],
+                    dev.clone(),
+                    queue.clone(),
+                    &graph.dyn_map,
+                ));
             } else if is::<Mod>(op) {
                 *op_ref = Box::new(MetalMod::<T>::new(
                     src_shapes[0],
@@ -33,7 +33,7 @@ use crate::{
     prim::{MetalConstant, MetalCopyFromDevice, MetalCopyToDevice, MetalMaxReduce, MetalSumReduce},
     select_function_from_lib,
     unary::{MetalMeanReduce, MetalStdNorm},
-    MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper,
+    Metal, MetalBuffer, MetalFloat, MetalKernel, MetalKernelWrapper,
 };
 
 use super::{compile_function, SetInt};
@@ -422,7 +422,7 @@ impl<T: MetalFloat> Operator for QuantizedMatmul<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -516,7 +516,7 @@ impl<T: MetalFloat> Operator for QuantizedGather<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 }
@@ -591,7 +591,7 @@ impl<T: MetalFloat + Default> Compiler for SerializeQuantizedGraph<T> {
         // Serialize and save graph away
         let mut ops = vec![];
         for node in graph.node_indices().collect::<Vec<_>>() {
-            if graph.check_node_type::<Function>(node) {
+            if graph.is_op::<Function>(node) {
                 continue;
             }
             let data = if let Some(op) = graph.try_get_op::<MetalMeanReduce<T>>(node) {
@@ -640,9 +640,9 @@ impl<T: MetalFloat + Default> Compiler for SerializeQuantizedGraph<T> {
                     "dyn_chars": op.dyn_chars,
                     "output_buffer_sizes": op.output_buffer_sizes,
                 })
-            } else if graph.check_node_type::<QuantizedMatmul<T>>(node)
-                || graph.check_node_type::<MetalCopyFromDevice<T>>(node)
-                || graph.check_node_type::<MetalCopyToDevice<T>>(node)
+            } else if graph.is_op::<QuantizedMatmul<T>>(node)
+                || graph.is_op::<MetalCopyFromDevice<T>>(node)
+                || graph.is_op::<MetalCopyToDevice<T>>(node)
             {
                 json!({})
             } else {
@@ -814,12 +814,14 @@ impl<T: MetalFloat> Compiler for DeserializeQuantizedGraph<T> {
                     .finish()
             } else if name == "QuantizedMatmul" {
                 graph.add_op(quantized_matmul.clone()).finish()
-            } else if name == "MetalCopyToDevice" {
+            } else if name.starts_with("CopyToDevice") {
                 graph
-                    .add_op(MetalCopyToDevice::<T>::new(dev.clone()))
+                    .add_op(MetalCopyToDevice::<T>::new(Metal::new(dev.clone())))
+                    .finish()
+            } else if name.starts_with("CopyFromDevice") {
+                graph
+                    .add_op(MetalCopyFromDevice::<T>::new(Metal::new(dev.clone())))
                     .finish()
-            } else if name == "MetalCopyFromDevice" {
-                graph.add_op(MetalCopyFromDevice::<T>::default()).finish()
             } else {
                 panic!("Found unexpected serialized op: {name}");
             };
@@ -891,7 +893,7 @@ mod tests {
             std::mem::size_of_val(weights) as u64,
             MTLResourceOptions::StorageModeShared,
         );
-        Tensor::new(MetalBuffer(buffer))
+        Tensor::new(MetalBuffer::new(buffer))
     }
 
     #[test]
@@ -92,7 +92,7 @@ macro_rules! binary_test {
 
 pub fn assert_op_in_graph<T: Operator + 'static>(graph: &Graph) {
     assert!(
-        graph.node_indices().any(|i| graph.check_node_type::<T>(i)),
+        graph.node_indices().any(|i| graph.is_op::<T>(i)),
         "Node not found in the graph!"
     );
 }
@@ -159,7 +159,7 @@ impl<T: MetalFloat> Operator for MetalMeanReduce<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -370,7 +370,7 @@ impl<T: 'static + Clone> Operator for MetalStdNorm<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -561,7 +561,7 @@ impl<T: MetalFloat> Operator for MetalExp<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
@@ -696,7 +696,7 @@ impl<T: MetalFloat> Operator for MetalCos<T> {
             command_buffer.commit();
             command_buffer.wait_until_completed();
 
-            vec![Tensor::new(MetalBuffer(out))]
+            vec![Tensor::new(MetalBuffer::new(out))]
         })
     }
 
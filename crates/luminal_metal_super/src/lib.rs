@@ -31,6 +31,15 @@ impl Data for MetalBuffer {
     fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
         self
     }
+
+    // Buffers don't record what they hold, so they're described as f32s
+    fn size(&self) -> usize {
+        self.length() as usize
+    }
+
+    fn device(&self) -> &'static str {
+        "metal"
+    }
 }
 
 pub trait MetalFloat: Copy + Debug + PartialEq + 'static + Default {
@@ -291,6 +291,108 @@ impl<
             .reshape::<R3<CH_OUT, DIMX_OUT, DIMY_OUT>>()
     }
 }
+
+/// A 2D convolution over batches of images with a bias, and zero padding of half the kernel on each side so stride 1
+/// keeps the images' size. Weights are laid out [CH_OUT, CH_IN, KERNEL, KERNEL] like PyTorch's `nn.Conv2d`.
+pub struct PaddedConv2D<const CH_IN: usize, const CH_OUT: usize, const KERNEL: usize> {
+    pub weight: GraphTensor<R4<CH_OUT, CH_IN, KERNEL, KERNEL>>,
+    pub bias: GraphTensor<R1<CH_OUT>>,
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const KERNEL: usize> InitModule
+    for PaddedConv2D<CH_IN, CH_OUT, KERNEL>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        // Init weight and bias as uniform(-1, 1)
+        let mut rng = thread_rng();
+        Self {
+            weight: cx.named_tensor("Weight").set(
+                (0..(CH_IN * CH_OUT * KERNEL * KERNEL))
+                    .map(|_| rng.gen_range(-1_f32..1_f32))
+                    .collect::<Vec<_>>(),
+            ),
+            bias: cx.named_tensor("Bias").set(
+                (0..CH_OUT)
+                    .map(|_| rng.gen_range(-1_f32..1_f32))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const KERNEL: usize> SerializeModule
+    for PaddedConv2D<CH_IN, CH_OUT, KERNEL>
+{
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const KERNEL: usize>
+    PaddedConv2D<CH_IN, CH_OUT, KERNEL>
+{
+    /// Convolve with a stride, giving images of `(size + 2 * (KERNEL / 2) - KERNEL) / stride + 1` pixels a side
+    pub fn forward_strided<
+        B: Dimension,
+        H: Dimension,
+        W: Dimension,
+        HO: Dimension,
+        WO: Dimension,
+    >(
+        &self,
+        input: GraphTensor<(B, Const<CH_IN>, H, W)>,
+        stride: usize,
+    ) -> GraphTensor<(B, Const<CH_OUT>, HO, WO)> {
+        let pad = KERNEL / 2;
+        let b = input.shape.shape()[0].small();
+        // Unfold each output pixel's patch: [B, C, H, W] -> [B, C, W', Kx, H', Ky] -> [B, H', W', C, Ky, Kx]
+        let pool = |x: DynGraphTensor| {
+            GraphTensor::<()>::from_id(x.id, x.shape, x.graph_ref)
+                .pool_last_dim::<()>(KERNEL, stride, 0)
+                .into_dyn()
+        };
+        let patches = pool(
+            pool(
+                input
+                    .into_dyn()
+                    .pad(&[(0, 0), (0, 0), (pad, pad), (pad, pad)]),
+            )
+            .permute(&[0, 1, 3, 4, 2]),
+        )
+        .permute(&[0, 4, 2, 1, 5, 3]);
+        let [h, w] = [1, 2].map(|i| patches.dims()[i]);
+        let weight = self
+            .weight
+            .into_dyn()
+            .reshape(&[CH_OUT, CH_IN * KERNEL * KERNEL])
+            .permute(&[1, 0]);
+        (patches
+            .reshape(&[b, (h * w).simplify(), (CH_IN * KERNEL * KERNEL).into()])
+            .matmul(weight)
+            + self.bias.into_dyn())
+        .permute(&[0, 2, 1])
+        .reshape(&[b, CH_OUT.into(), h, w])
+        .typed()
+    }
+}
+
+impl<
+        const CH_IN: usize,
+        const CH_OUT: usize,
+        const KERNEL: usize,
+        B: Dimension,
+        H: Dimension,
+        W: Dimension,
+    > Module<GraphTensor<(B, Const<CH_IN>, H, W)>> for PaddedConv2D<CH_IN, CH_OUT, KERNEL>
+{
+    type Output = GraphTensor<(B, Const<CH_OUT>, H, W)>;
+
+    fn forward(&self, input: GraphTensor<(B, Const<CH_IN>, H, W)>) -> Self::Output {
+        assert!(KERNEL % 2 == 1, "Only odd kernels keep the images' size");
+        self.forward_strided(input, 1)
+    }
+}
 pub struct Conv3D<
     const CH_IN: usize,
     const CH_OUT: usize,
@@ -461,7 +563,7 @@ impl<
 
 #[cfg(test)]
 mod tests {
-    use super::{Conv1D, Conv2D, Conv3D};
+    use super::{Conv1D, Conv2D, Conv3D, PaddedConv2D};
     use candle_core::{Device, Tensor};
     use luminal::{
         prelude::*,
@@ -738,4 +840,41 @@ mod tests {
 
         assert_close(&out1.data(), &exp_out1.data());
     }
+
+    #[test]
+    fn test_padded_conv2d() {
+        let mut cx = Graph::new();
+        let mut rng = StdRng::seed_from_u64(0);
+        let model = PaddedConv2D::<3, 4, 3>::initialize(&mut cx);
+        let (weight, bias) = (
+            random_vec_rng(4 * 3 * 3 * 3, &mut rng),
+            random_vec_rng(4, &mut rng),
+        );
+        model.weight.set(weight.clone());
+        model.bias.set(bias.clone());
+        let input_data = random_vec_rng(2 * 3 * 5 * 6, &mut rng);
+        let input = cx
+            .tensor::<(Dyn<'b'>, Const<3>, Const<5>, Const<6>)>()
+            .set_dyn(input_data.clone(), &[2, 3, 5, 6]);
+        let same = model.forward(input).retrieve();
+        let strided = model
+            .forward_strided::<_, _, _, Const<3>, Const<3>>(input, 2)
+            .retrieve();
+        cx.execute();
+
+        let input = Tensor::from_vec(input_data, (2, 3, 5, 6), &Device::Cpu).unwrap();
+        let kernel = Tensor::from_vec(weight, (4, 3, 3, 3), &Device::Cpu).unwrap();
+        let bias = Tensor::from_vec(bias, (1, 4, 1, 1), &Device::Cpu).unwrap();
+        for (out, stride) in [(same.data(), 1), (strided.data(), 2)] {
+            let expected = input
+                .conv2d(&kernel, 1, stride, 1, 1)
+                .unwrap()
+                .broadcast_add(&bias)
+                .unwrap();
+            assert_close(
+                &out,
+                &expected.flatten_all().unwrap().to_vec1::<f32>().unwrap(),
+            );
+        }
+    }
 }
@@ -0,0 +1,564 @@
+use luminal::prelude::*;
+
+use crate::{FullyConnected, GroupNorm, PaddedConv2D, PermutedLinear};
+
+mod scheduler;
+pub use scheduler::*;
+
+/// Sinusoidal embeddings of diffusion timesteps, as [B, DIM]: the cosines then the sines of each timestep times
+/// frequencies spaced geometrically from 1 down to 1 / 10000.
+pub fn timestep_embedding<B: Dimension, const DIM: usize>(
+    timesteps: GraphTensor<(B,)>,
+) -> GraphTensor<(B, Const<DIM>)> {
+    let half = DIM / 2;
+    let b = timesteps.shape.shape()[0].small();
+    let freqs = timesteps
+        .graph()
+        .dyn_tensor("Timestep Frequencies", &[half])
+        .set(
+            (0..half)
+                .map(|i| (-(10000f64.ln()) * i as f64 / half as f64).exp() as f32)
+                .collect(),
+        );
+    let args = timesteps.into_dyn().reshape(&[b, 1.into()]) * freqs;
+    args.cos().concat_along(args.sin(), 1).typed()
+}
+
+/// Embeds diffusion timesteps for the resnet blocks to condition on: sinusoidal embeddings of `IN` dimensions, then two
+/// fully connected layers with a SiLU between them.
+pub struct TimestepEmbedding<const IN: usize, const DIM: usize> {
+    pub linear_1: FullyConnected<IN, DIM>,
+    pub linear_2: FullyConnected<DIM, DIM>,
+}
+
+impl<const IN: usize, const DIM: usize> InitModule for TimestepEmbedding<IN, DIM> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            linear_1: InitModule::initialize(cx),
+            linear_2: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<const IN: usize, const DIM: usize> SerializeModule for TimestepEmbedding<IN, DIM> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("linear_1", &self.linear_1);
+        s.module("linear_2", &self.linear_2);
+    }
+}
+
+impl<const IN: usize, const DIM: usize, B: Dimension> Module<GraphTensor<(B,)>>
+    for TimestepEmbedding<IN, DIM>
+{
+    type Output = GraphTensor<(B, Const<DIM>)>;
+
+    fn forward(&self, timesteps: GraphTensor<(B,)>) -> Self::Output {
+        let embedding = timestep_embedding::<B, IN>(timesteps);
+        self.linear_2
+            .forward(self.linear_1.forward(embedding).swish())
+    }
+}
+
+/// A UNet's residual block: two normalized 3x3 convolutions with the timestep embedding added between them, and a 1x1
+/// convolution on the skip connection when the number of channels changes.
+pub struct ResnetBlock2D<
+    const CH_IN: usize,
+    const CH_OUT: usize,
+    const TEMB: usize,
+    const GROUPS: usize,
+> {
+    pub norm1: GroupNorm<GROUPS, CH_IN>,
+    pub conv1: PaddedConv2D<CH_IN, CH_OUT, 3>,
+    pub time_emb_proj: FullyConnected<TEMB, CH_OUT>,
+    pub norm2: GroupNorm<GROUPS, CH_OUT>,
+    pub conv2: PaddedConv2D<CH_OUT, CH_OUT, 3>,
+    pub conv_shortcut: Option<PaddedConv2D<CH_IN, CH_OUT, 1>>,
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const TEMB: usize, const GROUPS: usize> InitModule
+    for ResnetBlock2D<CH_IN, CH_OUT, TEMB, GROUPS>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            norm1: InitModule::initialize(cx),
+            conv1: InitModule::initialize(cx),
+            time_emb_proj: InitModule::initialize(cx),
+            norm2: InitModule::initialize(cx),
+            conv2: InitModule::initialize(cx),
+            conv_shortcut: (CH_IN != CH_OUT).then(|| InitModule::initialize(cx)),
+        }
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const TEMB: usize, const GROUPS: usize>
+    SerializeModule for ResnetBlock2D<CH_IN, CH_OUT, TEMB, GROUPS>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("norm1", &self.norm1);
+        s.module("conv1", &self.conv1);
+        s.module("time_emb_proj", &self.time_emb_proj);
+        s.module("norm2", &self.norm2);
+        s.module("conv2", &self.conv2);
+        if let Some(shortcut) = &self.conv_shortcut {
+            s.module("conv_shortcut", shortcut);
+        }
+    }
+}
+
+impl<
+        const CH_IN: usize,
+        const CH_OUT: usize,
+        const TEMB: usize,
+        const GROUPS: usize,
+        B: Dimension,
+        H: Dimension,
+        W: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, Const<CH_IN>, H, W)>,
+        GraphTensor<(B, Const<TEMB>)>,
+    )> for ResnetBlock2D<CH_IN, CH_OUT, TEMB, GROUPS>
+{
+    type Output = GraphTensor<(B, Const<CH_OUT>, H, W)>;
+
+    fn forward(
+        &self,
+        (input, temb): (
+            GraphTensor<(B, Const<CH_IN>, H, W)>,
+            GraphTensor<(B, Const<TEMB>)>,
+        ),
+    ) -> Self::Output {
+        let hidden = self.conv1.forward(self.norm1.forward(input).swish())
+            + self
+                .time_emb_proj
+                .forward(temb.swish())
+                .expand::<_, Axes2<2, 3>>();
+        let hidden = self.conv2.forward(self.norm2.forward(hidden).swish());
+        let skip = match &self.conv_shortcut {
+            Some(shortcut) => shortcut.forward(input),
+            // Without a shortcut the channels already match
+            None => GraphTensor::from_id(input.id, input.shape, input.graph_ref),
+        };
+        skip + hidden
+    }
+}
+
+/// Attention between the pixels of images: group norm, then multi-head attention from each pixel to every pixel (or to
+/// a sequence of context tokens of `CONTEXT` dimensions, like a prompt's text embeddings), added back onto the input.
+pub struct AttentionBlock2D<
+    const CH: usize,
+    const HEADS: usize,
+    const GROUPS: usize,
+    const CONTEXT: usize = CH,
+> {
+    pub norm: GroupNorm<GROUPS, CH>,
+    pub to_q: PermutedLinear<CH, CH>,
+    pub to_k: PermutedLinear<CONTEXT, CH>,
+    pub to_v: PermutedLinear<CONTEXT, CH>,
+    pub to_out: FullyConnected<CH, CH>,
+}
+
+impl<const CH: usize, const HEADS: usize, const GROUPS: usize, const CONTEXT: usize> InitModule
+    for AttentionBlock2D<CH, HEADS, GROUPS, CONTEXT>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        assert_eq!(CH % HEADS, 0, "Channels must split evenly into heads");
+        Self {
+            norm: InitModule::initialize(cx),
+            to_q: InitModule::initialize(cx),
+            to_k: InitModule::initialize(cx),
+            to_v: InitModule::initialize(cx),
+            to_out: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<const CH: usize, const HEADS: usize, const GROUPS: usize, const CONTEXT: usize> SerializeModule
+    for AttentionBlock2D<CH, HEADS, GROUPS, CONTEXT>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("norm", &self.norm);
+        s.module("to_q", &self.to_q);
+        s.module("to_k", &self.to_k);
+        s.module("to_v", &self.to_v);
+        s.module("to_out", &self.to_out);
+    }
+}
+
+impl<const CH: usize, const HEADS: usize, const GROUPS: usize, const CONTEXT: usize>
+    AttentionBlock2D<CH, HEADS, GROUPS, CONTEXT>
+{
+    /// Attend from the pixels of `input` to the `context` tokens, shaped [B, tokens, CONTEXT], or to its own pixels
+    fn attend<B: Dimension, H: Dimension, W: Dimension>(
+        &self,
+        input: GraphTensor<(B, Const<CH>, H, W)>,
+        context: Option<DynGraphTensor>,
+    ) -> GraphTensor<(B, Const<CH>, H, W)> {
+        let dims = input.shape.shape();
+        let [b, h, w] = [0, 2, 3].map(|i| dims[i].small());
+        let pixels = (h * w).simplify();
+        let sequence = self
+            .norm
+            .forward(input)
+            .into_dyn()
+            .reshape(&[b, CH.into(), pixels])
+            .permute(&[0, 2, 1]);
+        let context = context.unwrap_or(sequence);
+        let tokens = context.dims()[1];
+        let project = |x: DynGraphTensor, weight: DynGraphTensor, len| {
+            // [B, len, CH] -> [B, HEADS, len, CH / HEADS]
+            x.matmul(weight.permute(&[1, 0]))
+                .reshape(&[b, len, HEADS.into(), (CH / HEADS).into()])
+                .permute(&[0, 2, 1, 3])
+        };
+        let queries = project(sequence, self.to_q.weight.into_dyn(), pixels);
+        let keys = project(context, self.to_k.weight.into_dyn(), tokens);
+        let values = project(context, self.to_v.weight.into_dyn(), tokens);
+        let weights = (queries.matmul(keys.permute(&[0, 1, 3, 2]))
+            * (1. / ((CH / HEADS) as f32).sqrt()))
+        .softmax(3);
+        let attended = weights
+            .matmul(values)
+            .permute(&[0, 2, 1, 3])
+            .reshape(&[b, pixels, CH.into()])
+            .typed::<(B, Dyn<'-'>, Const<CH>)>();
+        let out = self
+            .to_out
+            .forward(attended)
+            .into_dyn()
+            .permute(&[0, 2, 1])
+            .reshape(&[b, CH.into(), h, w])
+            .typed::<(B, Const<CH>, H, W)>();
+        input + out
+    }
+}
+
+// Self attention
+impl<
+        const CH: usize,
+        const HEADS: usize,
+        const GROUPS: usize,
+        const CONTEXT: usize,
+        B: Dimension,
+        H: Dimension,
+        W: Dimension,
+    > Module<GraphTensor<(B, Const<CH>, H, W)>> for AttentionBlock2D<CH, HEADS, GROUPS, CONTEXT>
+{
+    type Output = GraphTensor<(B, Const<CH>, H, W)>;
+
+    fn forward(&self, input: GraphTensor<(B, Const<CH>, H, W)>) -> Self::Output {
+        assert_eq!(
+            CH, CONTEXT,
+            "Self attention needs the context to be the input"
+        );
+        self.attend(input, None)
+    }
+}
+
+// Cross attention to context tokens
+impl<
+        const CH: usize,
+        const HEADS: usize,
+        const GROUPS: usize,
+        const CONTEXT: usize,
+        B: Dimension,
+        H: Dimension,
+        W: Dimension,
+        S: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, Const<CH>, H, W)>,
+        GraphTensor<(B, S, Const<CONTEXT>)>,
+    )> for AttentionBlock2D<CH, HEADS, GROUPS, CONTEXT>
+{
+    type Output = GraphTensor<(B, Const<CH>, H, W)>;
+
+    fn forward(
+        &self,
+        (input, context): (
+            GraphTensor<(B, Const<CH>, H, W)>,
+            GraphTensor<(B, S, Const<CONTEXT>)>,
+        ),
+    ) -> Self::Output {
+        self.attend(input, Some(context.into_dyn()))
+    }
+}
+
+/// Halves the size of images with a 3x3 convolution of stride 2
+pub struct Downsample2D<const CH: usize> {
+    pub conv: PaddedConv2D<CH, CH, 3>,
+}
+
+impl<const CH: usize> InitModule for Downsample2D<CH> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            conv: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<const CH: usize> SerializeModule for Downsample2D<CH> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("conv", &self.conv);
+    }
+}
+
+impl<const CH: usize> Downsample2D<CH> {
+    pub fn forward<B: Dimension, H: Dimension, W: Dimension, HO: Dimension, WO: Dimension>(
+        &self,
+        input: GraphTensor<(B, Const<CH>, H, W)>,
+    ) -> GraphTensor<(B, Const<CH>, HO, WO)> {
+        self.conv.forward_strided(input, 2)
+    }
+}
+
+/// Doubles the size of images by repeating each pixel, then smooths them with a 3x3 convolution
+pub struct Upsample2D<const CH: usize> {
+    pub conv: PaddedConv2D<CH, CH, 3>,
+}
+
+impl<const CH: usize> InitModule for Upsample2D<CH> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            conv: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<const CH: usize> SerializeModule for Upsample2D<CH> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("conv", &self.conv);
+    }
+}
+
+impl<const CH: usize> Upsample2D<CH> {
+    pub fn forward<B: Dimension, H: Dimension, W: Dimension, HO: Dimension, WO: Dimension>(
+        &self,
+        input: GraphTensor<(B, Const<CH>, H, W)>,
+    ) -> GraphTensor<(B, Const<CH>, HO, WO)> {
+        self.conv
+            .forward(input.upsample_nearest::<(B, Const<CH>, HO, WO)>(2))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{timestep_embedding, AttentionBlock2D, ResnetBlock2D};
+
+    /// Set a tensor to random data, and get the data back
+    fn randomize<S: ConstShape>(tensor: GraphTensor<S>) -> Vec<f32> {
+        let data = random_vec(tensor.shape.n_elements().to_usize().unwrap());
+        tensor.set(data.clone());
+        data
+    }
+
+    /// Convolution with zero padding of half the kernel, written out over images shaped [B, C, H, W]
+    fn conv(
+        x: &[f32],
+        [b, c_in, h, w]: [usize; 4],
+        weight: &[f32],
+        bias: &[f32],
+        k: usize,
+    ) -> Vec<f32> {
+        let pad = (k / 2) as isize;
+        let mut out = vec![];
+        for n in 0..b {
+            for (o, bias) in bias.iter().enumerate() {
+                for y in 0..h as isize {
+                    for x_ in 0..w as isize {
+                        let mut sum = *bias;
+                        for i in 0..c_in {
+                            for ky in 0..k {
+                                for kx in 0..k {
+                                    let (sy, sx) = (y + ky as isize - pad, x_ + kx as isize - pad);
+                                    if (0..h as isize).contains(&sy)
+                                        && (0..w as isize).contains(&sx)
+                                    {
+                                        sum += weight[((o * c_in + i) * k + ky) * k + kx]
+                                            * x[((n * c_in + i) * h + sy as usize) * w
+                                                + sx as usize];
+                                    }
+                                }
+                            }
+                        }
+                        out.push(sum);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Group norm with unit weights and zero biases, over groups of `group_size` contiguous elements
+    fn group_norm(x: &[f32], group_size: usize) -> Vec<f32> {
+        x.chunks(group_size)
+            .flat_map(|group| {
+                let mean = group.iter().sum::<f32>() / group.len() as f32;
+                let var =
+                    group.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / group.len() as f32;
+                group.iter().map(move |v| (v - mean) / (var + 1e-5).sqrt())
+            })
+            .collect()
+    }
+
+    fn silu(x: &[f32]) -> Vec<f32> {
+        x.iter().map(|v| v / (1. + (-v).exp())).collect()
+    }
+
+    #[test]
+    fn test_timestep_embedding() {
+        let mut cx = Graph::new();
+        let timesteps = cx.tensor::<R1<2>>().set([0., 981.]);
+        let embedding = timestep_embedding::<_, 6>(timesteps).retrieve();
+        cx.execute();
+
+        let expected = [0., 981.]
+            .into_iter()
+            .flat_map(|t: f32| {
+                let args = (0..3)
+                    .map(|i| t * (-(10000f32.ln()) * i as f32 / 3.).exp())
+                    .collect::<Vec<_>>();
+                let cos = args.iter().map(|a| a.cos()).collect::<Vec<_>>();
+                cos.into_iter()
+                    .chain(args.iter().map(|a| a.sin()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&embedding.data(), &expected);
+    }
+
+    #[test]
+    fn test_resnet_block() {
+        let mut cx = Graph::new();
+        let block: ResnetBlock2D<2, 4, 3, 2> = InitModule::initialize(&mut cx);
+        let shortcut = block.conv_shortcut.as_ref().unwrap();
+        let (conv1_w, conv1_b) = (randomize(block.conv1.weight), randomize(block.conv1.bias));
+        let (conv2_w, conv2_b) = (randomize(block.conv2.weight), randomize(block.conv2.bias));
+        let (skip_w, skip_b) = (randomize(shortcut.weight), randomize(shortcut.bias));
+        let (proj_w, proj_b) = (
+            randomize(block.time_emb_proj.weight),
+            randomize(block.time_emb_proj.bias),
+        );
+        let input = cx.tensor::<R4<2, 2, 3, 3>>();
+        let temb = cx.tensor::<R2<2, 3>>();
+        let (data, temb_data) = (randomize(input), randomize(temb));
+        let out = block.forward((input, temb)).retrieve();
+        cx.execute();
+
+        let hidden = conv(
+            &silu(&group_norm(&data, 9)),
+            [2, 2, 3, 3],
+            &conv1_w,
+            &conv1_b,
+            3,
+        );
+        // Each channel of each image gets its projected timestep embedding added
+        let temb = silu(&temb_data);
+        let hidden = hidden
+            .chunks(9)
+            .enumerate()
+            .flat_map(|(i, pixels)| {
+                let (n, o) = (i / 4, i % 4);
+                let shift = proj_b[o]
+                    + (0..3)
+                        .map(|j| temb[n * 3 + j] * proj_w[o * 3 + j])
+                        .sum::<f32>();
+                pixels.iter().map(move |p| p + shift)
+            })
+            .collect::<Vec<_>>();
+        let hidden = conv(
+            &silu(&group_norm(&hidden, 18)),
+            [2, 4, 3, 3],
+            &conv2_w,
+            &conv2_b,
+            3,
+        );
+        let skip = conv(&data, [2, 2, 3, 3], &skip_w, &skip_b, 1);
+        let expected = skip
+            .iter()
+            .zip(&hidden)
+            .map(|(a, b)| a + b)
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_attention_block() {
+        let mut cx = Graph::new();
+        let block: AttentionBlock2D<4, 2, 2> = InitModule::initialize(&mut cx);
+        let [q_w, k_w, v_w, out_w] = [
+            block.to_q.weight,
+            block.to_k.weight,
+            block.to_v.weight,
+            block.to_out.weight,
+        ]
+        .map(randomize);
+        let out_b = randomize(block.to_out.bias);
+        let input = cx.tensor::<R4<1, 4, 2, 3>>();
+        let context = cx.tensor::<R3<1, 5, 4>>();
+        let (data, context_data) = (randomize(input), randomize(context));
+        let out = block.forward(input).retrieve();
+        let cross = block.forward((input, context)).retrieve();
+        cx.execute();
+
+        // Rows of [tokens, 4] times a [4 out, 4 in] weight
+        let project = |x: &[f32], w: &[f32]| {
+            x.chunks(4)
+                .flat_map(|row| {
+                    w.chunks(4)
+                        .map(|w| row.iter().zip(w).map(|(a, b)| a * b).sum::<f32>())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let attend = |context: &[f32]| {
+            // The normalized pixels as a sequence of 6 tokens
+            let normed = group_norm(&data, 12);
+            let pixels = (0..6)
+                .flat_map(|p| (0..4).map(|c| normed[c * 6 + p]).collect::<Vec<_>>())
+                .collect::<Vec<_>>();
+            let context = if context.is_empty() { &pixels } else { context };
+            let (q, k, v) = (
+                project(&pixels, &q_w),
+                project(context, &k_w),
+                project(context, &v_w),
+            );
+            let tokens = context.len() / 4;
+            let mut attended = vec![0.; 24];
+            for head in 0..2 {
+                for p in 0..6 {
+                    let scores = (0..tokens)
+                        .map(|t| {
+                            (0..2)
+                                .map(|d| q[p * 4 + head * 2 + d] * k[t * 4 + head * 2 + d])
+                                .sum::<f32>()
+                                / 2f32.sqrt()
+                        })
+                        .collect::<Vec<_>>();
+                    let max = scores.iter().cloned().fold(f32::MIN, f32::max);
+                    let total = scores.iter().map(|s| (s - max).exp()).sum::<f32>();
+                    for (t, s) in scores.iter().enumerate() {
+                        for d in 0..2 {
+                            attended[p * 4 + head * 2 + d] +=
+                                (s - max).exp() / total * v[t * 4 + head * 2 + d];
+                        }
+                    }
+                }
+            }
+            let projected = project(&attended, &out_w);
+            (0..24)
+                .map(|i| {
+                    let (c, p) = (i / 6, i % 6);
+                    data[i] + projected[p * 4 + c] + out_b[c]
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_close(&out.data(), &attend(&[]));
+        assert_close(&cross.data(), &attend(&context_data));
+    }
+}
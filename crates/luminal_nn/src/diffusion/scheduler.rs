@@ -0,0 +1,296 @@
+use luminal::prelude::*;
+
+/// How much noise a diffusion model was trained to remove at each of its timesteps, as the fraction of the signal's
+/// variance left at each one
+#[derive(Debug, Clone)]
+pub struct NoiseSchedule {
+    pub alphas_cumprod: Vec<f64>,
+}
+
+impl NoiseSchedule {
+    /// Noise added at each timestep (the betas) spaced evenly from `beta_start` to `beta_end`
+    pub fn linear(timesteps: usize, beta_start: f64, beta_end: f64) -> Self {
+        Self::from_betas((0..timesteps).map(|i| lerp(beta_start, beta_end, i, timesteps)))
+    }
+
+    /// Betas whose square roots are spaced evenly, which is what Stable Diffusion was trained with
+    pub fn scaled_linear(timesteps: usize, beta_start: f64, beta_end: f64) -> Self {
+        Self::from_betas(
+            (0..timesteps).map(|i| lerp(beta_start.sqrt(), beta_end.sqrt(), i, timesteps).powi(2)),
+        )
+    }
+
+    /// Stable Diffusion's schedule: 1000 timesteps of scaled linear betas from 0.00085 to 0.012
+    pub fn stable_diffusion() -> Self {
+        Self::scaled_linear(1000, 0.00085, 0.012)
+    }
+
+    fn from_betas(betas: impl Iterator<Item = f64>) -> Self {
+        let mut alpha = 1.;
+        Self {
+            alphas_cumprod: betas
+                .map(|beta| {
+                    alpha *= 1. - beta;
+                    alpha
+                })
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.alphas_cumprod.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.alphas_cumprod.is_empty()
+    }
+
+    /// Standard deviation of the noise relative to the signal at a timestep, interpolating between timesteps
+    fn sigma(&self, timestep: f64) -> f64 {
+        let sigma = |t: usize| ((1. - self.alphas_cumprod[t]) / self.alphas_cumprod[t]).sqrt();
+        let lower = (timestep.floor() as usize).min(self.len() - 1);
+        let upper = (lower + 1).min(self.len() - 1);
+        let frac = timestep - lower as f64;
+        sigma(lower) * (1. - frac) + sigma(upper) * frac
+    }
+}
+
+fn lerp(start: f64, end: f64, i: usize, n: usize) -> f64 {
+    start + (end - start) * i as f64 / (n - 1).max(1) as f64
+}
+
+/// Picks the timesteps to denoise at and how to update the sample at each one. Every step's update is a weighted sum
+/// of the sample and the model's noise prediction, so a `DenoisingStep` can run it in the graph.
+pub trait DiffusionScheduler {
+    /// Timestep to run the model at for each step, noisiest first
+    fn timesteps(&self) -> Vec<f32>;
+    /// Standard deviation of the noise that denoising starts from
+    fn init_noise_sigma(&self) -> f32 {
+        1.
+    }
+    /// What the sample is multiplied by before it's fed to the model at a step
+    fn input_scale(&self, _step: usize) -> f32 {
+        1.
+    }
+    /// Weights of the sample and the predicted noise in the next sample after a step
+    fn step_coefficients(&self, step: usize) -> (f32, f32);
+}
+
+/// Deterministic DDIM sampling ([*Denoising Diffusion Implicit Models*](https://arxiv.org/abs/2010.02502) with eta = 0),
+/// stepping through evenly spaced timesteps with a model that predicts noise.
+#[derive(Debug, Clone)]
+pub struct Ddim {
+    pub schedule: NoiseSchedule,
+    pub steps: usize,
+    /// Signal left after the last step. 1 removes all the noise, Stable Diffusion uses the first timestep's.
+    pub final_alpha_cumprod: f64,
+}
+
+impl Ddim {
+    pub fn new(schedule: NoiseSchedule, steps: usize) -> Self {
+        Self {
+            schedule,
+            steps,
+            final_alpha_cumprod: 1.,
+        }
+    }
+
+    fn timestep(&self, step: usize) -> Option<usize> {
+        let ratio = self.schedule.len() / self.steps;
+        (step < self.steps).then(|| (self.steps - 1 - step) * ratio)
+    }
+}
+
+impl DiffusionScheduler for Ddim {
+    fn timesteps(&self) -> Vec<f32> {
+        (0..self.steps)
+            .map(|s| self.timestep(s).unwrap() as f32)
+            .collect()
+    }
+
+    fn step_coefficients(&self, step: usize) -> (f32, f32) {
+        let alpha = self.schedule.alphas_cumprod[self.timestep(step).unwrap()];
+        let alpha_prev = self
+            .timestep(step + 1)
+            .map(|t| self.schedule.alphas_cumprod[t])
+            .unwrap_or(self.final_alpha_cumprod);
+        // Predict the clean sample, then add back the noise left at the next timestep
+        let sample = (alpha_prev / alpha).sqrt();
+        let noise = (1. - alpha_prev).sqrt() - sample * (1. - alpha).sqrt();
+        (sample as f32, noise as f32)
+    }
+}
+
+/// Euler sampling over noise levels ([*Elucidating the Design Space of Diffusion-Based Generative
+/// Models*](https://arxiv.org/abs/2206.00364)), with timesteps spaced evenly over the whole schedule and a model that
+/// predicts noise. Samples are the signal plus noise of each step's sigma, so the model's input gets scaled back to
+/// unit variance.
+#[derive(Debug, Clone)]
+pub struct Euler {
+    pub schedule: NoiseSchedule,
+    pub steps: usize,
+}
+
+impl Euler {
+    pub fn new(schedule: NoiseSchedule, steps: usize) -> Self {
+        Self { schedule, steps }
+    }
+
+    fn timestep(&self, step: usize) -> f64 {
+        lerp((self.schedule.len() - 1) as f64, 0., step, self.steps)
+    }
+
+    /// Noise level at a step, which reaches 0 after the last one
+    fn sigma(&self, step: usize) -> f64 {
+        if step < self.steps {
+            self.schedule.sigma(self.timestep(step))
+        } else {
+            0.
+        }
+    }
+}
+
+impl DiffusionScheduler for Euler {
+    fn timesteps(&self) -> Vec<f32> {
+        (0..self.steps).map(|s| self.timestep(s) as f32).collect()
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        (self.sigma(0).powi(2) + 1.).sqrt() as f32
+    }
+
+    fn input_scale(&self, step: usize) -> f32 {
+        (1. / (self.sigma(step).powi(2) + 1.).sqrt()) as f32
+    }
+
+    fn step_coefficients(&self, step: usize) -> (f32, f32) {
+        (1., (self.sigma(step + 1) - self.sigma(step)) as f32)
+    }
+}
+
+/// A scheduler's step in the graph: the scalars of the current step are inputs, which `set` fills in before each run.
+///
+/// The model reads `timestep` and the sample scaled by `scale_input`, and `step` turns its noise prediction into the
+/// next sample. `run` then feeds each step's output back in as the next one's sample.
+pub struct DenoisingStep {
+    pub timestep: GraphTensor<R0>,
+    input_scale: GraphTensor<R0>,
+    sample_weight: GraphTensor<R0>,
+    noise_weight: GraphTensor<R0>,
+}
+
+impl DenoisingStep {
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            timestep: cx.named_tensor("Timestep"),
+            input_scale: cx.named_tensor("Input Scale"),
+            sample_weight: cx.named_tensor("Sample Weight"),
+            noise_weight: cx.named_tensor("Noise Weight"),
+        }
+    }
+
+    /// The sample as the model takes it at this step
+    pub fn scale_input<S: Shape>(&self, sample: GraphTensor<S>) -> GraphTensor<S> {
+        sample * self.input_scale.expand_to(sample.shape)
+    }
+
+    /// The next, less noisy sample, from the current one and the model's prediction of its noise
+    pub fn step<S: Shape>(&self, sample: GraphTensor<S>, noise: GraphTensor<S>) -> GraphTensor<S> {
+        sample * self.sample_weight.expand_to(sample.shape)
+            + noise * self.noise_weight.expand_to(noise.shape)
+    }
+
+    /// Set the inputs for a step of `scheduler`
+    pub fn set(&self, scheduler: &impl DiffusionScheduler, step: usize) {
+        let (sample, noise) = scheduler.step_coefficients(step);
+        self.timestep.set(scheduler.timesteps()[step]);
+        self.input_scale.set(scheduler.input_scale(step));
+        self.sample_weight.set(sample);
+        self.noise_weight.set(noise);
+    }
+
+    /// Denoise from unit variance `noise` through every step of `scheduler`, feeding the retrieved `next` sample back in
+    /// as `sample`. Returns the final sample. Any dynamic dimensions of the sample need to be set already.
+    pub fn run<S: Shape>(
+        &self,
+        cx: &mut Graph,
+        scheduler: &impl DiffusionScheduler,
+        sample: GraphTensor<S>,
+        next: GraphTensor<S>,
+        noise: Vec<f32>,
+    ) -> Vec<f32> {
+        let shape = sample
+            .shape
+            .shape()
+            .iter()
+            .map(|d| d.exec(&cx.dyn_map).expect("The sample's shape isn't known"))
+            .collect::<Vec<_>>();
+        let sigma = scheduler.init_noise_sigma();
+        let mut data = noise.into_iter().map(|n| n * sigma).collect::<Vec<_>>();
+        for step in 0..scheduler.timesteps().len() {
+            self.set(scheduler, step);
+            sample.set_dyn(data, &shape);
+            cx.execute();
+            data = next.data();
+            next.drop();
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{Ddim, DenoisingStep, DiffusionScheduler, Euler, NoiseSchedule};
+
+    /// Denoise from `start`, with a model that always predicts `noise`
+    fn denoise(scheduler: &impl DiffusionScheduler, start: &[f32], noise: &[f32]) -> Vec<f32> {
+        let mut cx = Graph::new();
+        let step = DenoisingStep::new(&mut cx);
+        let sample = cx.tensor::<R1<4>>();
+        let prediction = cx.tensor::<R1<4>>().set(noise.to_vec());
+        let next = step.step(sample, prediction).retrieve();
+        let sigma = scheduler.init_noise_sigma();
+        let start = start.iter().map(|s| s / sigma).collect();
+        step.run(&mut cx, scheduler, sample, next, start)
+    }
+
+    #[test]
+    fn test_ddim() {
+        let (clean, noise) = (random_vec(4), random_vec(4));
+        let scheduler = Ddim::new(NoiseSchedule::stable_diffusion(), 20);
+        assert_eq!(scheduler.timesteps()[..3], [950., 900., 850.]);
+        // The first sample is mostly noise, and predicting that noise exactly steps back to the clean sample
+        let alpha = scheduler.schedule.alphas_cumprod[950] as f32;
+        let start = clean
+            .iter()
+            .zip(&noise)
+            .map(|(c, n)| alpha.sqrt() * c + (1. - alpha).sqrt() * n)
+            .collect::<Vec<_>>();
+        assert_close(&denoise(&scheduler, &start, &noise), &clean);
+    }
+
+    #[test]
+    fn test_euler() {
+        let (clean, noise) = (random_vec(4), random_vec(4));
+        let scheduler = Euler::new(NoiseSchedule::stable_diffusion(), 10);
+        let timesteps = scheduler.timesteps();
+        assert_eq!((timesteps[0], timesteps[9]), (999., 0.));
+        let sigma = scheduler.schedule.sigma(999.) as f32;
+        assert_close(
+            &[scheduler.input_scale(0)],
+            &[1. / (sigma * sigma + 1.).sqrt()],
+        );
+        // Samples are the clean sample plus sigma times the noise, and sigma reaches 0 after the last step
+        let start = clean
+            .iter()
+            .zip(&noise)
+            .map(|(c, n)| c + sigma * n)
+            .collect::<Vec<_>>();
+        assert_close(&denoise(&scheduler, &start, &noise), &clean);
+    }
+}
@@ -0,0 +1,327 @@
+use luminal::{prelude::*, tests::random_vec};
+
+/// A linear layer with its output features split evenly across the ranks of a [`ProcessGroup`].
+///
+/// Each process holds only its own rank's columns of the weight and computes its own slice of the outputs, so nothing
+/// is exchanged until the slices are gathered, or fed straight into a [`RowParallelLinear`] which sums them back up.
+/// Inputs need a batch dimension.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_nn::{ColumnParallelLinear, RowParallelLinear};
+/// // Every process joins the group with its own rank, here a group of one
+/// let group = ProcessGroup::tcp(0, 1, "127.0.0.1:29500").unwrap();
+/// let mut cx = Graph::new();
+/// let up = ColumnParallelLinear::<4, 8>::new(&mut cx, &group);
+/// let down = RowParallelLinear::<8, 4>::new(&mut cx, &group);
+/// let input = cx.tensor::<R2<1, 4>>().set(vec![1.; 4]);
+/// // One all-reduce for the whole feed forward, leaving the output on every rank
+/// let out = down.forward(up.forward(input).relu()).retrieve();
+/// cx.execute();
+/// assert_eq!(out.data().len(), 4);
+/// ```
+pub struct ColumnParallelLinear<const A: usize, const B: usize> {
+    /// This rank's columns of the weight, shaped [A, B / world size]
+    pub weight: DynGraphTensor,
+    pub group: ProcessGroup,
+}
+
+impl<const A: usize, const B: usize> ColumnParallelLinear<A, B> {
+    pub fn new(cx: &mut Graph, group: &ProcessGroup) -> Self {
+        let ranks = group.world_size();
+        assert!(
+            B % ranks == 0,
+            "{B} output features can't be split evenly across {ranks} ranks"
+        );
+        Self {
+            weight: cx
+                .dyn_tensor("Weight", &[A, B / ranks])
+                .set(random_vec(A * B / ranks)),
+            group: group.clone(),
+        }
+    }
+
+    /// Load this rank's columns of a whole [A, B] weight
+    pub fn load(&self, weight: &[f32]) {
+        let cols = B / self.group.world_size();
+        let rank = self.group.rank();
+        self.weight.set(
+            weight
+                .chunks(B)
+                .flat_map(|row| &row[rank * cols..(rank + 1) * cols])
+                .copied()
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    /// Gather every rank's slice of the outputs into the whole output
+    pub fn gather(&self, output: DynGraphTensor) -> DynGraphTensor {
+        output.all_gather(&self.group, output.rank() - 1)
+    }
+}
+
+impl<const A: usize, const B: usize> SerializeModule for ColumnParallelLinear<A, B> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor(
+            &format!("shard{}", self.group.rank()),
+            GraphTensor::<()>::from_id(self.weight.id, self.weight.shape, self.weight.graph_ref),
+        );
+    }
+}
+
+impl<const A: usize, const B: usize, S: Shape> Module<GraphTensor<S>>
+    for ColumnParallelLinear<A, B>
+{
+    type Output = DynGraphTensor;
+
+    fn forward(&self, input: GraphTensor<S>) -> Self::Output {
+        input.into_dyn().matmul(self.weight)
+    }
+}
+
+/// A linear layer with its input features split evenly across the ranks of a [`ProcessGroup`].
+///
+/// Takes this rank's slice of the inputs, like the output of a [`ColumnParallelLinear`], and all-reduces the partial
+/// products so every rank ends up with the whole output.
+pub struct RowParallelLinear<const A: usize, const B: usize> {
+    /// This rank's rows of the weight, shaped [A / world size, B]
+    pub weight: DynGraphTensor,
+    pub group: ProcessGroup,
+}
+
+impl<const A: usize, const B: usize> RowParallelLinear<A, B> {
+    pub fn new(cx: &mut Graph, group: &ProcessGroup) -> Self {
+        let ranks = group.world_size();
+        assert!(
+            A % ranks == 0,
+            "{A} input features can't be split evenly across {ranks} ranks"
+        );
+        Self {
+            weight: cx
+                .dyn_tensor("Weight", &[A / ranks, B])
+                .set(random_vec(A * B / ranks)),
+            group: group.clone(),
+        }
+    }
+
+    /// Load this rank's rows of a whole [A, B] weight
+    pub fn load(&self, weight: &[f32]) {
+        let rows = A / self.group.world_size();
+        let rank = self.group.rank();
+        self.weight
+            .set(weight[rank * rows * B..(rank + 1) * rows * B].to_vec());
+    }
+}
+
+impl<const A: usize, const B: usize> SerializeModule for RowParallelLinear<A, B> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor(
+            &format!("shard{}", self.group.rank()),
+            GraphTensor::<()>::from_id(self.weight.id, self.weight.shape, self.weight.graph_ref),
+        );
+    }
+}
+
+impl<const A: usize, const B: usize> Module<DynGraphTensor> for RowParallelLinear<A, B> {
+    type Output = DynGraphTensor;
+
+    fn forward(&self, input: DynGraphTensor) -> Self::Output {
+        input.matmul(self.weight).all_reduce(&self.group)
+    }
+}
+
+/// Multi-head self attention with its heads split evenly across the ranks of a [`ProcessGroup`], like
+/// [`MultiHeadSelfAttention`](crate::MultiHeadSelfAttention).
+///
+/// The query, key and value projections are column parallel, so each rank projects and attends with only its own
+/// heads. The output projection is row parallel over those heads, and its all-reduce is the only exchange in the layer.
+pub struct ParallelSelfAttention<
+    const DIM: usize,
+    const K_DIM: usize,
+    const V_DIM: usize,
+    const HEADS: usize,
+> {
+    pub w_q: ColumnParallelLinear<DIM, K_DIM>,
+    pub w_k: ColumnParallelLinear<DIM, K_DIM>,
+    pub w_v: ColumnParallelLinear<DIM, V_DIM>,
+    pub w_o: RowParallelLinear<V_DIM, DIM>,
+}
+
+impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize>
+    ParallelSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    pub fn new(cx: &mut Graph, group: &ProcessGroup) -> Self {
+        let ranks = group.world_size();
+        assert!(
+            HEADS % ranks == 0,
+            "{HEADS} heads can't be split evenly across {ranks} ranks"
+        );
+        // Heads are contiguous runs of features, so an even split of the heads is an even split of the features
+        Self {
+            w_q: ColumnParallelLinear::new(cx, group),
+            w_k: ColumnParallelLinear::new(cx, group),
+            w_v: ColumnParallelLinear::new(cx, group),
+            w_o: RowParallelLinear::new(cx, group),
+        }
+    }
+}
+
+impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize> SerializeModule
+    for ParallelSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("w_q", &self.w_q);
+        s.module("w_k", &self.w_k);
+        s.module("w_v", &self.w_v);
+        s.module("w_o", &self.w_o);
+    }
+}
+
+impl<
+        const DIM: usize,
+        const K_DIM: usize,
+        const V_DIM: usize,
+        const HEADS: usize,
+        B: Dimension,
+        S: Dimension,
+    > Module<GraphTensor<(B, S, Const<DIM>)>> for ParallelSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        let heads = HEADS / self.w_o.group.world_size();
+        let (b, s) = (B::size(), S::size());
+        // [B, S, heads * head dim] -> [B, heads, S, head dim]
+        let split_heads = |x: DynGraphTensor, head_dim: usize| {
+            x.reshape(&[b, s, heads.into(), head_dim.into()])
+                .permute(&[0, 2, 1, 3])
+        };
+        let queries = split_heads(self.w_q.forward(input), K_DIM / HEADS);
+        let keys = split_heads(self.w_k.forward(input), K_DIM / HEADS).permute(&[0, 1, 3, 2]);
+        let values = split_heads(self.w_v.forward(input), V_DIM / HEADS);
+
+        let weights =
+            (queries.matmul(keys) * (1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32).softmax(3);
+        let tokens = weights.matmul(values).permute(&[0, 2, 1, 3]).reshape(&[
+            b,
+            s,
+            (heads * V_DIM / HEADS).into(),
+        ]);
+        self.w_o.forward(tokens).typed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{ColumnParallelLinear, ParallelSelfAttention, RowParallelLinear};
+    use crate::{Linear, MultiHeadSelfAttention};
+
+    #[test]
+    fn test_tensor_parallel_mlp() {
+        let (w_up, w_down) = (random_vec(4 * 8), random_vec(8 * 4));
+        let input = random_vec(12);
+
+        let mut cx = Graph::new();
+        let inp = cx.tensor::<R2<3, 4>>().set(input.clone());
+        let up: Linear<4, 8> = InitModule::initialize(&mut cx);
+        let down: Linear<8, 4> = InitModule::initialize(&mut cx);
+        up.weight.set(w_up.clone());
+        down.weight.set(w_down.clone());
+        let hidden_ref = up.forward(inp).retrieve();
+        let reference = down.forward(up.forward(inp).relu()).retrieve();
+        cx.execute();
+
+        let master = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        // One thread per rank, each building only its own shards
+        std::thread::scope(|s| {
+            let handles = (0..2)
+                .map(|rank| {
+                    let (master, input, w_up, w_down) =
+                        (master.clone(), input.clone(), w_up.clone(), w_down.clone());
+                    s.spawn(move || {
+                        let group = ProcessGroup::tcp(rank, 2, master).unwrap();
+                        let mut cx = Graph::new();
+                        let input = cx.tensor::<R2<3, 4>>().set(input);
+                        let tp_up = ColumnParallelLinear::<4, 8>::new(&mut cx, &group);
+                        let tp_down = RowParallelLinear::<8, 4>::new(&mut cx, &group);
+                        tp_up.load(&w_up);
+                        tp_down.load(&w_down);
+                        assert_eq!(tp_up.weight.shape.shape_usize(), vec![4, 4]);
+                        assert_eq!(tp_down.weight.shape.shape_usize(), vec![4, 4]);
+                        let hidden = tp_up.forward(input);
+                        let gathered = tp_up.gather(hidden).retrieve();
+                        let out = tp_down.forward(hidden.relu()).retrieve();
+                        cx.execute();
+                        (gathered.data(), out.data())
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                let (gathered, out) = handle.join().unwrap();
+                assert_close(&gathered, &hidden_ref.data());
+                assert_close(&out, &reference.data());
+            }
+        });
+    }
+
+    #[test]
+    fn test_parallel_self_attention() {
+        let weights = [
+            random_vec(4 * 8),
+            random_vec(4 * 8),
+            random_vec(4 * 8),
+            random_vec(8 * 4),
+        ];
+        let input = random_vec(2 * 3 * 4);
+
+        let mut cx = Graph::new();
+        let inp = cx.tensor::<R3<2, 3, 4>>().set(input.clone());
+        let attn: MultiHeadSelfAttention<4, 8, 8, 4> = InitModule::initialize(&mut cx);
+        attn.w_q.weight.set(weights[0].clone());
+        attn.w_k.weight.set(weights[1].clone());
+        attn.w_v.weight.set(weights[2].clone());
+        attn.w_o.weight.set(weights[3].clone());
+        let reference = attn.forward(inp).retrieve();
+        cx.execute();
+
+        let master = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        // Each rank holds two of the four heads
+        std::thread::scope(|s| {
+            let handles = (0..2)
+                .map(|rank| {
+                    let (master, input, weights) = (master.clone(), input.clone(), weights.clone());
+                    s.spawn(move || {
+                        let group = ProcessGroup::tcp(rank, 2, master).unwrap();
+                        let mut cx = Graph::new();
+                        let input = cx.tensor::<R3<2, 3, 4>>().set(input);
+                        let attn = ParallelSelfAttention::<4, 8, 8, 4>::new(&mut cx, &group);
+                        attn.w_q.load(&weights[0]);
+                        attn.w_k.load(&weights[1]);
+                        attn.w_v.load(&weights[2]);
+                        attn.w_o.load(&weights[3]);
+                        assert_eq!(attn.w_q.weight.shape.shape_usize(), vec![4, 4]);
+                        assert_eq!(attn.w_o.weight.shape.shape_usize(), vec![4, 4]);
+                        let out = attn.forward(input).retrieve();
+                        cx.execute();
+                        out.data()
+                    })
+                })
+                .collect::<Vec<_>>();
+            for handle in handles {
+                assert_close(&handle.join().unwrap(), &reference.data());
+            }
+        });
+    }
+}
@@ -1,5 +1,7 @@
 use luminal::{prelude::*, tests::random_vec};
 
+use crate::LayerNorm;
+
 pub struct Embedding<const N: usize, const DIM: usize> {
     pub weight: GraphTensor<R2<N, DIM>>,
 }
@@ -85,6 +87,70 @@ impl<B: Dimension, S: Dimension, const N: usize, const DIM: usize> Module<GraphT
     }
 }
 
+/// The input embeddings of a BERT-style encoder: the sum of token, position and token type embeddings, layer normed.
+/// Token types tell apart the two sentences of a pair, and are all 0 for a single sentence.
+pub struct EncoderEmbedding<
+    const VOCAB: usize,
+    const MAX_SEQ: usize,
+    const TYPES: usize,
+    const DIM: usize,
+> {
+    pub word: Embedding<VOCAB, DIM>,
+    pub position: Embedding<MAX_SEQ, DIM>,
+    pub token_type: Embedding<TYPES, DIM>,
+    pub norm: LayerNorm<DIM>,
+}
+
+impl<const VOCAB: usize, const MAX_SEQ: usize, const TYPES: usize, const DIM: usize> InitModule
+    for EncoderEmbedding<VOCAB, MAX_SEQ, TYPES, DIM>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            word: InitModule::initialize(cx),
+            position: InitModule::initialize(cx),
+            token_type: InitModule::initialize(cx),
+            norm: LayerNorm::init(true, true, true, 1e-12, cx),
+        }
+    }
+}
+
+impl<const VOCAB: usize, const MAX_SEQ: usize, const TYPES: usize, const DIM: usize> SerializeModule
+    for EncoderEmbedding<VOCAB, MAX_SEQ, TYPES, DIM>
+{
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.module("word_embeddings", &self.word);
+        s.module("position_embeddings", &self.position);
+        s.module("token_type_embeddings", &self.token_type);
+        s.module("LayerNorm", &self.norm);
+    }
+}
+
+// Batch of (token ids, token type ids)
+impl<
+        B: Dimension,
+        S: Dimension,
+        const VOCAB: usize,
+        const MAX_SEQ: usize,
+        const TYPES: usize,
+        const DIM: usize,
+    > Module<(GraphTensor<(B, S)>, GraphTensor<(B, S)>)>
+    for EncoderEmbedding<VOCAB, MAX_SEQ, TYPES, DIM>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (ids, token_types): (GraphTensor<(B, S)>, GraphTensor<(B, S)>),
+    ) -> Self::Output {
+        let positions = self
+            .position
+            .forward(ids.graph().arange::<S>())
+            .expand::<_, Axis<0>>();
+        self.norm
+            .forward(self.word.forward(ids) + positions + self.token_type.forward(token_types))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use dfdx::{
@@ -94,7 +160,7 @@ mod tests {
 
     use luminal::prelude::Module;
 
-    use super::Embedding;
+    use super::{Embedding, EncoderEmbedding};
     use dfdx::nn::BuildOnDevice;
     luminal::test_imports!();
 
@@ -132,4 +198,26 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
         assert_close(&batch_out.data(), &d_batch_out.as_vec());
     }
+
+    #[test]
+    fn test_encoder_embedding() {
+        let mut cx = Graph::new();
+        let ids = cx.tensor::<R2<2, 2>>().set([[2., 0.], [1., 1.]]);
+        let types = cx.tensor::<R2<2, 2>>().set([[0., 0.], [0., 1.]]);
+        let model: EncoderEmbedding<3, 4, 2, 2> = InitModule::initialize(&mut cx);
+        model.word.weight.set([[1., 2.], [3., 5.], [-1., 0.]]);
+        model
+            .position
+            .weight
+            .set([[0., 1.], [1., 0.], [7., 7.], [7., 7.]]);
+        model.token_type.weight.set([[0., 0.], [4., 0.]]);
+        model.norm.weight.unwrap().set([1., 2.]);
+        model.norm.bias.unwrap().set([0., 1.]);
+        let out = model.forward((ids, types)).retrieve();
+        cx.execute();
+
+        // Summed embeddings are [-1, 1], [2, 2], [3, 6] and [8, 5], which normalize to (-1, 1), (0, 0) or (1, -1)
+        let (up, down) = ([-1., 3.], [1., -1.]);
+        assert_close(&out.data(), &[up, [0., 1.], up, down].concat());
+    }
 }
@@ -4,10 +4,16 @@ mod activation;
 pub use activation::*;
 mod convolution;
 pub use convolution::*;
+mod diffusion;
+pub use diffusion::*;
+mod distributed;
+pub use distributed::*;
 mod embedding;
 pub use embedding::*;
 mod linear;
 pub use linear::*;
+mod moe;
+pub use moe::*;
 mod norm;
 pub use norm::*;
 mod transformer;
@@ -74,9 +74,68 @@ where
     }
 }
 
+/// A linear layer with a bias, holding its weight as [out, in] like PyTorch's `nn.Linear`. Image classifiers use one as
+/// their fully connected head.
+pub struct FullyConnected<const A: usize, const B: usize> {
+    pub weight: GraphTensor<R2<B, A>>,
+    pub bias: GraphTensor<R1<B>>,
+}
+
+impl<const A: usize, const B: usize> InitModule for FullyConnected<A, B> {
+    fn initialize(cx: &mut Graph) -> Self {
+        // Init weight and bias as uniform(-1, 1)
+        let mut rng = thread_rng();
+        Self {
+            weight: cx.named_tensor("Weight").set(
+                (0..(A * B))
+                    .map(|_| rng.gen_range(-1_f32..1_f32))
+                    .collect::<Vec<_>>(),
+            ),
+            bias: cx.named_tensor("Bias").set(
+                (0..B)
+                    .map(|_| rng.gen_range(-1_f32..1_f32))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
+
+impl<const A: usize, const B: usize> SerializeModule for FullyConnected<A, B> {
+    fn serialize(&self, s: &mut luminal::module::Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+    }
+}
+
+impl<const A: usize, const B: usize, S: Shape, O: Shape> Module<GraphTensor<S>>
+    for FullyConnected<A, B>
+where
+    GraphTensor<S>: Matmul<R2<A, B>, Output = GraphTensor<O>>,
+{
+    type Output = GraphTensor<O>;
+
+    fn forward(&self, input: GraphTensor<S>) -> Self::Output {
+        let out = input.matmul(self.weight.permute());
+        // Broadcast the bias along every dimension but the last
+        let mut bias = self.bias.shape;
+        for (i, dim) in out
+            .shape
+            .shape()
+            .into_iter()
+            .rev()
+            .skip(1)
+            .rev()
+            .enumerate()
+        {
+            bias.expand(i, dim.small());
+        }
+        out + GraphTensor::from_id(self.bias.id, bias, self.bias.graph_ref)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Linear;
+    use super::{FullyConnected, Linear};
     use luminal::{prelude::*, tests::assert_close};
     #[test]
     fn test_linear() {
@@ -101,4 +160,20 @@ mod tests {
         assert_close(&unoptimized_b, &b.data());
         assert_close(&unoptimized_batch_out, &batch_out.data());
     }
+
+    #[test]
+    fn test_fully_connected() {
+        let mut cx = Graph::new();
+        let model: FullyConnected<3, 2> = InitModule::initialize(&mut cx);
+        model.weight.set([[1., 0., -1.], [2., 1., 0.]]);
+        model.bias.set([0.5, -0.5]);
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let batch = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [0., 1., 0.]]);
+        let b = model.forward(a).retrieve();
+        let batch_out = model.forward(batch).retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &[-1.5, 3.5]);
+        assert_close(&batch_out.data(), &[-1.5, 3.5, 0.5, 0.5]);
+    }
 }
@@ -0,0 +1,299 @@
+use luminal::{prelude::*, tests::random_vec};
+
+use crate::Linear;
+
+/// A Mixtral-style mixture of experts feed forward layer. A router scores each token against every expert, and the token
+/// only runs through its `TOP_K` highest scoring experts, weighted by a softmax over their scores. Each expert is a SwiGLU
+/// feed forward.
+///
+/// Tokens are grouped by expert before running through them. Each expert gets a buffer with a slot per token it can
+/// take, and runs one matmul over its buffer against its own weights. The results are then added back into the tokens
+/// they came from.
+pub struct MoE<const DIM: usize, const HIDDEN: usize, const EXPERTS: usize, const TOP_K: usize> {
+    pub router: Linear<DIM, EXPERTS>,
+    pub w_gate: GraphTensor<R3<EXPERTS, DIM, HIDDEN>>,
+    pub w_up: GraphTensor<R3<EXPERTS, DIM, HIDDEN>>,
+    pub w_down: GraphTensor<R3<EXPERTS, HIDDEN, DIM>>,
+    /// How many tokens each expert has slots for, as a multiple of its even share of `tokens * TOP_K / EXPERTS`. Tokens
+    /// picking an expert after it's full skip it. `None` gives every expert a slot for every token, so none get skipped.
+    pub capacity_factor: Option<f32>,
+}
+
+impl<const DIM: usize, const HIDDEN: usize, const EXPERTS: usize, const TOP_K: usize> InitModule
+    for MoE<DIM, HIDDEN, EXPERTS, TOP_K>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            router: InitModule::initialize(cx),
+            w_gate: cx
+                .named_tensor("Gate Weight")
+                .set(random_vec(EXPERTS * DIM * HIDDEN)),
+            w_up: cx
+                .named_tensor("Up Weight")
+                .set(random_vec(EXPERTS * DIM * HIDDEN)),
+            w_down: cx
+                .named_tensor("Down Weight")
+                .set(random_vec(EXPERTS * HIDDEN * DIM)),
+            capacity_factor: None,
+        }
+    }
+}
+
+impl<const DIM: usize, const HIDDEN: usize, const EXPERTS: usize, const TOP_K: usize>
+    SerializeModule for MoE<DIM, HIDDEN, EXPERTS, TOP_K>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("router", &self.router);
+        s.tensor("w_gate", self.w_gate);
+        s.tensor("w_up", self.w_up);
+        s.tensor("w_down", self.w_down);
+    }
+}
+
+impl<const DIM: usize, const HIDDEN: usize, const EXPERTS: usize, const TOP_K: usize>
+    MoE<DIM, HIDDEN, EXPERTS, TOP_K>
+{
+    /// Slots in each expert's buffer for a batch of `tokens` tokens
+    fn capacity(&self, tokens: Expression) -> Expression {
+        match self.capacity_factor {
+            Some(factor) => {
+                // Hundredths of a token, rounded up
+                let factor = (factor * 100.).ceil() as usize;
+                ((tokens * (TOP_K * factor) + (EXPERTS * 100 - 1)) / (EXPERTS * 100))
+                    .min(tokens)
+                    .simplify()
+            }
+            None => tokens,
+        }
+    }
+}
+
+// Single
+impl<
+        const DIM: usize,
+        const HIDDEN: usize,
+        const EXPERTS: usize,
+        const TOP_K: usize,
+        S: Dimension,
+    > Module<GraphTensor<(S, Const<DIM>)>> for MoE<DIM, HIDDEN, EXPERTS, TOP_K>
+{
+    type Output = GraphTensor<(S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(S, Const<DIM>)>) -> Self::Output {
+        // Pass to batched forward
+        <Self as Module<GraphTensor<(Const<1>, S, Const<DIM>)>>>::forward(self, input.expand())
+            .reshape()
+    }
+}
+
+// Batched
+impl<
+        const DIM: usize,
+        const HIDDEN: usize,
+        const EXPERTS: usize,
+        const TOP_K: usize,
+        B: Dimension,
+        S: Dimension,
+    > Module<GraphTensor<(B, S, Const<DIM>)>> for MoE<DIM, HIDDEN, EXPERTS, TOP_K>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(&self, input: GraphTensor<(B, S, Const<DIM>)>) -> Self::Output {
+        let dims = input
+            .shape
+            .shape()
+            .into_iter()
+            .map(|d| d.small())
+            .collect::<Vec<_>>();
+        let tokens = (dims[0] * dims[1]).simplify();
+        let capacity = self.capacity(tokens);
+        let x = input.dyn_reshape::<(Dyn<'-'>, Const<DIM>), _>(&[tokens, DIM.into()]);
+
+        // Pick the top k experts one at a time, pushing each one to the bottom once it's been picked
+        let mut logits = self.router.forward(x);
+        let (mut experts, mut scores) = (vec![], vec![]);
+        for _ in 0..TOP_K.min(EXPERTS) {
+            let expert = logits.argmax();
+            scores.push(logits.max_reduce::<_, Axis<1>>());
+            logits -= expert.one_hot::<Const<EXPERTS>>() * f32::MAX;
+            experts.push(expert);
+        }
+        // Softmax over the picked scores, the first of which is the largest
+        let exp = scores
+            .iter()
+            .map(|s| (*s - scores[0]).exp())
+            .collect::<Vec<_>>();
+        let sum = exp.iter().copied().reduce(|a, b| a + b).unwrap();
+
+        // Fill each expert's buffer in the order tokens picked it, along with which token and weight each slot holds.
+        // Slots are numbered expert * capacity + position, and tokens past an expert's capacity go to -1, which
+        // index_add skips.
+        let cx = input.graph();
+        let slots = (capacity * EXPERTS).simplify();
+        let mut buffer = cx
+            .constant(0.)
+            .expand_to::<(Dyn<'-'>, Const<DIM>)>(ShapeTracker::new(&[slots, DIM.into()]));
+        let mut slot_tokens = cx
+            .constant(0.)
+            .expand_to::<(Dyn<'-'>,)>(ShapeTracker::new(&[slots]));
+        let mut slot_weights = slot_tokens;
+        let mut taken = cx
+            .constant(0.)
+            .expand_to::<(Const<EXPERTS>,)>(ShapeTracker::new(&[EXPERTS.into()]));
+        let token_ids = cx.arange_expr(tokens);
+        for (expert, exp) in experts.into_iter().zip(exp) {
+            let picked = expert.one_hot::<Const<EXPERTS>>();
+            let mut taken_shape = taken.shape;
+            taken_shape.expand(0, tokens);
+            let positions = (picked.cumsum::<Axis<0>>() - 1.)
+                + GraphTensor::from_id(taken.id, taken_shape, taken.graph_ref);
+            let position = (positions * picked).sum_reduce::<_, Axis<1>>();
+            taken += picked.sum_reduce::<_, Axis<0>>();
+            let fits = position.less_than(cx.constant_expr(capacity).expand_to(position.shape));
+            let slot =
+                (expert * cx.constant_expr(capacity).expand_to(expert.shape) + position + 1.)
+                    * fits
+                    - 1.;
+            buffer = buffer.index_add::<Axis<0>, _, _>(slot, x);
+            slot_tokens = slot_tokens.index_add::<Axis<0>, _, _>(slot, token_ids + 1.);
+            slot_weights = slot_weights.index_add::<Axis<0>, _, _>(slot, exp / sum);
+        }
+
+        // Run each expert over its buffer
+        let h = buffer.dyn_reshape::<(Const<EXPERTS>, Dyn<'-'>, Const<DIM>), _>(&[
+            EXPERTS.into(),
+            capacity,
+            DIM.into(),
+        ]);
+        let act = h.matmul(self.w_gate).swish() * h.matmul(self.w_up);
+        let y = act
+            .matmul(self.w_down)
+            .dyn_reshape::<(Dyn<'-'>, Const<DIM>), _>(&[slots, DIM.into()]);
+
+        // Add each slot's output back into its token. Empty slots hold token -1, so they get skipped.
+        let mut weight_shape = slot_weights.shape;
+        weight_shape.expand(1, DIM);
+        let weighted = y * GraphTensor::from_id(slot_weights.id, weight_shape, y.graph_ref);
+        cx.constant(0.)
+            .expand_to::<(Dyn<'-'>, Const<DIM>)>(ShapeTracker::new(&[tokens, DIM.into()]))
+            .index_add::<Axis<0>, _, _>(slot_tokens - 1., weighted)
+            .dyn_reshape(&[dims[0], dims[1], DIM.into()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::MoE;
+
+    /// Run each token through its top k experts one by one, skipping experts that have already taken `capacity` tokens
+    fn reference<const D: usize, const H: usize, const E: usize, const K: usize>(
+        x: &[f32],
+        router: &[f32],
+        gate: &[f32],
+        up: &[f32],
+        down: &[f32],
+        capacity: usize,
+    ) -> Vec<f32> {
+        let matmul = |a: &[f32], b: &[f32], n: usize| {
+            (0..n)
+                .map(|j| a.iter().enumerate().map(|(i, a)| a * b[i * n + j]).sum())
+                .collect::<Vec<f32>>()
+        };
+        let expert = |x: &[f32], e: usize| {
+            let g = matmul(x, &gate[e * D * H..(e + 1) * D * H], H);
+            let u = matmul(x, &up[e * D * H..(e + 1) * D * H], H);
+            let act = g
+                .iter()
+                .zip(u)
+                .map(|(g, u)| g / (1. + (-g).exp()) * u)
+                .collect::<Vec<_>>();
+            matmul(&act, &down[e * H * D..(e + 1) * H * D], D)
+        };
+        // Each token's picked experts, best first, and their weights
+        let picks = x
+            .chunks(D)
+            .map(|x| {
+                let scores = matmul(x, router, E);
+                let mut order = (0..E).collect::<Vec<_>>();
+                order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+                let exp = order[..K]
+                    .iter()
+                    .map(|e| (scores[*e] - scores[order[0]]).exp())
+                    .collect::<Vec<_>>();
+                let sum = exp.iter().sum::<f32>();
+                order[..K]
+                    .iter()
+                    .zip(exp)
+                    .map(|(e, exp)| (*e, exp / sum))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut out = vec![0.; x.len()];
+        let mut taken = [0; E];
+        for k in 0..K {
+            for (n, (x, picks)) in x.chunks(D).zip(&picks).enumerate() {
+                let (e, weight) = picks[k];
+                taken[e] += 1;
+                if taken[e] <= capacity {
+                    for (o, y) in out[n * D..(n + 1) * D].iter_mut().zip(expert(x, e)) {
+                        *o += weight * y;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_moe() {
+        let mut cx = Graph::new();
+        let model: MoE<4, 6, 5, 2> = InitModule::initialize(&mut cx);
+        let (router, gate, up, down) = (
+            random_vec(4 * 5),
+            random_vec(5 * 4 * 6),
+            random_vec(5 * 4 * 6),
+            random_vec(5 * 6 * 4),
+        );
+        model.router.weight.set(router.clone());
+        model.w_gate.set(gate.clone());
+        model.w_up.set(up.clone());
+        model.w_down.set(down.clone());
+        let (single_data, batch_data) = (random_vec(12), random_vec(24));
+        let single = cx
+            .tensor::<(Dyn<'s'>, luminal::shape::Const<4>)>()
+            .set_dyn(single_data.clone(), &[3, 4]);
+        let batch = cx
+            .tensor::<(luminal::shape::Const<2>, Dyn<'s'>, luminal::shape::Const<4>)>()
+            .set_dyn(batch_data.clone(), &[2, 3, 4]);
+        let single_out = model.forward(single).retrieve();
+        let batch_out = model.forward(batch).retrieve();
+        // Room for ceil(6 tokens * 2 picks / 5 experts * 0.5) = 2 tokens per expert
+        let limited = MoE::<4, 6, 5, 2> {
+            router: crate::Linear {
+                weight: model.router.weight,
+            },
+            capacity_factor: Some(0.5),
+            ..model
+        };
+        let limited_out = limited.forward(batch).retrieve();
+        cx.execute();
+
+        assert_close(
+            &single_out.data(),
+            &reference::<4, 6, 5, 2>(&single_data, &router, &gate, &up, &down, 3),
+        );
+        assert_close(
+            &batch_out.data(),
+            &reference::<4, 6, 5, 2>(&batch_data, &router, &gate, &up, &down, 6),
+        );
+        assert_close(
+            &limited_out.data(),
+            &reference::<4, 6, 5, 2>(&batch_data, &router, &gate, &up, &down, 2),
+        );
+    }
+}
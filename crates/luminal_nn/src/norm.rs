@@ -83,3 +83,271 @@ impl<const DIM: usize> SerializeModule for LayerNorm<DIM> {
         }
     }
 }
+
+/// Batch norm over the channels of images, using the running statistics it was trained with. Compiling with
+/// `FoldBatchNorm` folds it into the weights and bias of a convolution or linear layer right before it, as long as both
+/// layers' parameters are kept.
+pub struct BatchNorm2D<const CH: usize> {
+    pub weight: GraphTensor<R1<CH>>,
+    pub bias: GraphTensor<R1<CH>>,
+    pub running_mean: GraphTensor<R1<CH>>,
+    pub running_var: GraphTensor<R1<CH>>,
+    pub epsilon: f32,
+}
+
+impl<const CH: usize> InitModule for BatchNorm2D<CH> {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            weight: cx.named_tensor("BatchNorm Weight").set(vec![1.; CH]),
+            bias: cx.named_tensor("BatchNorm Bias").set(vec![0.; CH]),
+            running_mean: cx.named_tensor("BatchNorm Mean").set(vec![0.; CH]),
+            running_var: cx.named_tensor("BatchNorm Var").set(vec![1.; CH]),
+            epsilon: 1e-5,
+        }
+    }
+}
+
+impl<const CH: usize> SerializeModule for BatchNorm2D<CH> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+        s.tensor("running_mean", self.running_mean);
+        s.tensor("running_var", self.running_var);
+    }
+}
+
+impl<const CH: usize> BatchNorm2D<CH> {
+    /// The per-channel scale and shift the norm boils down to
+    fn scale_shift(&self) -> (GraphTensor<R1<CH>>, GraphTensor<R1<CH>>) {
+        let scale = self.weight * (self.running_var + self.epsilon).sqrt().recip();
+        (scale, self.bias - self.running_mean * scale)
+    }
+}
+
+impl<const CH: usize, H: Dimension, W: Dimension> Module<GraphTensor<(Const<CH>, H, W)>>
+    for BatchNorm2D<CH>
+{
+    type Output = GraphTensor<(Const<CH>, H, W)>;
+
+    fn forward(&self, input: GraphTensor<(Const<CH>, H, W)>) -> Self::Output {
+        let (scale, shift) = self.scale_shift();
+        input * scale.expand::<_, Axes2<1, 2>>() + shift.expand::<_, Axes2<1, 2>>()
+    }
+}
+
+impl<const CH: usize, B: Dimension, H: Dimension, W: Dimension>
+    Module<GraphTensor<(B, Const<CH>, H, W)>> for BatchNorm2D<CH>
+{
+    type Output = GraphTensor<(B, Const<CH>, H, W)>;
+
+    fn forward(&self, input: GraphTensor<(B, Const<CH>, H, W)>) -> Self::Output {
+        let (scale, shift) = self.scale_shift();
+        input * scale.expand::<_, Axes3<0, 2, 3>>() + shift.expand::<_, Axes3<0, 2, 3>>()
+    }
+}
+
+/// Group norm over the channels of images: channels are split into `GROUPS` groups, and each group is normalized over
+/// its channels and positions, then scaled and shifted per channel.
+pub struct GroupNorm<const GROUPS: usize, const CH: usize> {
+    pub weight: GraphTensor<R1<CH>>,
+    pub bias: GraphTensor<R1<CH>>,
+    pub epsilon: f32,
+}
+
+impl<const GROUPS: usize, const CH: usize> InitModule for GroupNorm<GROUPS, CH> {
+    fn initialize(cx: &mut Graph) -> Self {
+        assert_eq!(CH % GROUPS, 0, "Channels must split evenly into groups");
+        Self {
+            weight: cx.named_tensor("GroupNorm Weight").set(vec![1.; CH]),
+            bias: cx.named_tensor("GroupNorm Bias").set(vec![0.; CH]),
+            epsilon: 1e-5,
+        }
+    }
+}
+
+impl<const GROUPS: usize, const CH: usize> SerializeModule for GroupNorm<GROUPS, CH> {
+    fn serialize(&self, s: &mut Serializer) {
+        s.tensor("weight", self.weight);
+        s.tensor("bias", self.bias);
+    }
+}
+
+impl<const GROUPS: usize, const CH: usize, H: Dimension, W: Dimension>
+    Module<GraphTensor<(Const<CH>, H, W)>> for GroupNorm<GROUPS, CH>
+{
+    type Output = GraphTensor<(Const<CH>, H, W)>;
+
+    fn forward(&self, input: GraphTensor<(Const<CH>, H, W)>) -> Self::Output {
+        // Run through the batched version with a batch of one
+        <Self as Module<GraphTensor<(Const<1>, Const<CH>, H, W)>>>::forward(self, input.expand())
+            .reshape()
+    }
+}
+
+impl<const GROUPS: usize, const CH: usize, B: Dimension, H: Dimension, W: Dimension>
+    Module<GraphTensor<(B, Const<CH>, H, W)>> for GroupNorm<GROUPS, CH>
+{
+    type Output = GraphTensor<(B, Const<CH>, H, W)>;
+
+    fn forward(&self, input: GraphTensor<(B, Const<CH>, H, W)>) -> Self::Output {
+        let dims = input.shape.shape();
+        let [b, h, w] = [0, 2, 3].map(|i| dims[i].small());
+        let normed = input
+            .into_dyn()
+            .reshape(&[b, GROUPS.into(), (h * w * (CH / GROUPS)).simplify()])
+            .layer_norm(2, self.epsilon)
+            .reshape(&[b, CH.into(), h, w])
+            .typed::<(B, Const<CH>, H, W)>();
+        normed * self.weight.expand::<_, Axes3<0, 2, 3>>() + self.bias.expand::<_, Axes3<0, 2, 3>>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{
+        prelude::*,
+        tests::{assert_close, random_vec},
+    };
+
+    use super::{BatchNorm2D, GroupNorm};
+    use crate::{Conv2D, PaddedConv2D};
+
+    /// Random positive values for variances
+    fn random_var(n: usize) -> Vec<f32> {
+        random_vec(n).into_iter().map(|v| v.abs() + 0.5).collect()
+    }
+
+    #[test]
+    fn test_batch_norm_fold() {
+        let mut cx = Graph::new();
+        let conv: Conv2D<3, 4, 2, 2> = InitModule::initialize(&mut cx);
+        let norm: BatchNorm2D<4> = InitModule::initialize(&mut cx);
+        let (weight, bias, mean, var) =
+            (random_vec(4), random_vec(4), random_vec(4), random_var(4));
+        norm.weight.set(weight.clone());
+        norm.bias.set(bias.clone());
+        norm.running_mean.set(mean.clone());
+        norm.running_var.set(var.clone());
+        cx.keep_tensors(params((&conv, &norm)));
+
+        let input = cx.tensor::<R3<3, 6, 6>>().set(random_vec(3 * 6 * 6));
+        let conv_out = conv.forward::<6, 6, 3, 3>(input).retrieve();
+        let mut out = norm.forward(conv.forward::<6, 6, 3, 3>(input)).retrieve();
+        cx.execute();
+        let expected = conv_out
+            .data()
+            .chunks(9)
+            .zip(0..4)
+            .flat_map(|(ch, c)| {
+                ch.iter()
+                    .map(|x| (x - mean[c]) / (var[c] + 1e-5).sqrt() * weight[c] + bias[c])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+        out.drop();
+
+        // The norm's scale moves onto the conv weights, and its shift becomes a bias on the conv's output
+        cx.compile(FoldBatchNorm, &mut out);
+        assert!(cx
+            .get_sources(out.id)
+            .iter()
+            .any(|(src, _, _)| cx.is_op::<luminal::op::SumReduce>(*src)));
+        assert!(!cx.node_indices().any(|n| cx.is_op::<luminal::op::Sqrt>(n)));
+        cx.execute();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_batch_norm_fold_batched() {
+        let mut cx = Graph::new();
+        let conv: PaddedConv2D<3, 4, 3> = InitModule::initialize(&mut cx);
+        let norm: BatchNorm2D<4> = InitModule::initialize(&mut cx);
+        let (weight, bias, mean, var) =
+            (random_vec(4), random_vec(4), random_vec(4), random_var(4));
+        norm.weight.set(weight.clone());
+        norm.bias.set(bias.clone());
+        norm.running_mean.set(mean.clone());
+        norm.running_var.set(var.clone());
+        cx.keep_tensors(params((&conv, &norm)));
+
+        let input = cx.tensor::<R4<2, 3, 5, 5>>().set(random_vec(2 * 3 * 5 * 5));
+        let conv_out = conv.forward(input).retrieve();
+        let mut out = norm.forward(conv.forward(input)).retrieve();
+        cx.execute();
+        let expected = conv_out
+            .data()
+            .chunks(25)
+            .zip((0..4).cycle())
+            .flat_map(|(ch, c)| {
+                ch.iter()
+                    .map(|x| (x - mean[c]) / (var[c] + 1e-5).sqrt() * weight[c] + bias[c])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+        out.drop();
+
+        // The conv's bias and the norm's shift fold into one bias, added before the conv's output is permuted back to
+        // channels first
+        cx.compile(FoldBatchNorm, &mut out);
+        assert!(cx.is_op::<luminal::op::Contiguous>(out.id));
+        let n_muls = cx
+            .node_indices()
+            .filter(|n| cx.is_op::<luminal::op::Mul>(*n))
+            .count();
+        assert_eq!(n_muls, 2);
+        cx.execute();
+        assert_close(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_batch_norm_batched() {
+        let mut cx = Graph::new();
+        let norm: BatchNorm2D<2> = InitModule::initialize(&mut cx);
+        norm.running_mean.set(vec![1., -1.]);
+        norm.running_var.set(vec![4., 0.25]);
+        let input = cx
+            .tensor::<R4<2, 2, 1, 2>>()
+            .set(vec![1., 3., -1., 0., 5., 7., 1., 2.]);
+        let out = norm.forward(input).retrieve();
+        cx.execute();
+        assert_close(&out.data(), &[0., 1., 0., 2., 2., 3., 4., 6.]);
+    }
+
+    #[test]
+    fn test_group_norm() {
+        let mut cx = Graph::new();
+        let norm: GroupNorm<2, 4> = InitModule::initialize(&mut cx);
+        let (weight, bias) = (random_vec(4), random_vec(4));
+        norm.weight.set(weight.clone());
+        norm.bias.set(bias.clone());
+        let data = random_vec(2 * 4 * 3 * 3);
+        let input = cx.tensor::<R4<2, 4, 3, 3>>().set(data.clone());
+        let single = cx.tensor::<R3<4, 3, 3>>().set(data[..36].to_vec());
+        let out = norm.forward(input).retrieve();
+        let single_out = norm.forward(single).retrieve();
+        cx.execute();
+
+        // Each group is two channels of 9 positions
+        let expected = data
+            .chunks(18)
+            .enumerate()
+            .flat_map(|(i, group)| {
+                let mean = group.iter().sum::<f32>() / 18.;
+                let var = group.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / 18.;
+                let first_ch = (i % 2) * 2;
+                group
+                    .iter()
+                    .enumerate()
+                    .map(|(j, x)| {
+                        let c = first_ch + j / 9;
+                        (x - mean) / (var + 1e-5).sqrt() * weight[c] + bias[c]
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_close(&out.data(), &expected);
+        assert_close(&single_out.data(), &expected[..36]);
+    }
+}
@@ -1,6 +1,6 @@
 use std::ops::Mul;
 
-use crate::Linear;
+use crate::{Linear, PagedAttentionInputs};
 use luminal::prelude::*;
 
 /// Multi-head self attention as layed out in [*Attention Is All You Need*](https://arxiv.org/abs/1706.03762).
@@ -139,19 +139,134 @@ impl<
             GraphTensor<(B, S1, Const<DIM>)>,
         ),
     ) -> Self::Output {
-        let values = self
-            .w_v
-            .forward(values)
+        self.attend(keys, queries, values, None)
+    }
+}
+
+// Batched with a padding mask, which has 1s for tokens and 0s for padding that no token attends to
+impl<
+        const DIM: usize,
+        const K_DIM: usize,
+        const V_DIM: usize,
+        const HEADS: usize,
+        S: Dimension,
+        B: Dimension,
+    > Module<(GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>)>
+    for MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    type Output = GraphTensor<(B, S, Const<DIM>)>;
+
+    fn forward(
+        &self,
+        (input, mask): (GraphTensor<(B, S, Const<DIM>)>, GraphTensor<(B, S)>),
+    ) -> Self::Output {
+        self.attend(input, input, input, Some(mask.attention_bias()))
+    }
+}
+
+// Batched over a paged key-value cache. The new tokens' keys and values get written into the pools, then every token
+// attends to its sequence's cached tokens. Also returns the updated pools.
+impl<
+        const DIM: usize,
+        const K_DIM: usize,
+        const V_DIM: usize,
+        const HEADS: usize,
+        B: Dimension,
+        S: Dimension,
+        S1: Dimension,
+        P: Dimension,
+    >
+    Module<(
+        GraphTensor<(B, S, Const<DIM>)>,
+        PagedPools<P, K_DIM, V_DIM>,
+        PagedAttentionInputs<B, S, S1>,
+    )> for MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    type Output = (GraphTensor<(B, S, Const<DIM>)>, PagedPools<P, K_DIM, V_DIM>);
+
+    fn forward(
+        &self,
+        (input, (k_pool, v_pool), paged): (
+            GraphTensor<(B, S, Const<DIM>)>,
+            PagedPools<P, K_DIM, V_DIM>,
+            PagedAttentionInputs<B, S, S1>,
+        ),
+    ) -> Self::Output {
+        let n_new = B::size() * S::size();
+        let write = paged.write_slots.dyn_reshape::<(Dyn<'-'>,), _>(&[n_new]);
+        let k_pool = k_pool.scatter(
+            write,
+            self.w_k.forward(input).dyn_reshape(&[n_new, K_DIM.into()]),
+        );
+        let v_pool = v_pool.scatter(
+            write,
+            self.w_v.forward(input).dyn_reshape(&[n_new, V_DIM.into()]),
+        );
+
+        // Read every cached token back through the block tables. Backends turn the gathers into indexed lookups, like
+        // the CPU's GatherCompiler does, so only the batch's slots get read.
+        let read = paged
+            .read_slots
+            .dyn_reshape::<(Dyn<'-'>,), _>(&[B::size() * S1::size()]);
+        let keys = k_pool
+            .gather(read)
+            .dyn_reshape(&[B::size(), S1::size(), K_DIM.into()]);
+        let values = v_pool
+            .gather(read)
+            .dyn_reshape(&[B::size(), S1::size(), V_DIM.into()]);
+        let out = self.attend_projected(
+            keys,
+            self.w_q.forward(input),
+            values,
+            Some(paged.mask.attention_bias()),
+        );
+        (out, (k_pool, v_pool))
+    }
+}
+
+/// Key and value pools of a paged cache, each with a row per slot
+pub type PagedPools<P, const K_DIM: usize, const V_DIM: usize> = (
+    GraphTensor<(P, Const<K_DIM>)>,
+    GraphTensor<(P, Const<V_DIM>)>,
+);
+
+impl<const DIM: usize, const K_DIM: usize, const V_DIM: usize, const HEADS: usize>
+    MultiHeadSelfAttention<DIM, K_DIM, V_DIM, HEADS>
+{
+    /// Attend from the queries to the keys, adding `bias` to the attention scores of each key
+    fn attend<B: Dimension, S1: Dimension, S2: Dimension>(
+        &self,
+        keys: GraphTensor<(B, S1, Const<DIM>)>,
+        queries: GraphTensor<(B, S2, Const<DIM>)>,
+        values: GraphTensor<(B, S1, Const<DIM>)>,
+        bias: Option<GraphTensor<(B, S1)>>,
+    ) -> GraphTensor<(B, S2, Const<DIM>)> {
+        self.attend_projected(
+            self.w_k.forward(keys),
+            self.w_q.forward(queries),
+            self.w_v.forward(values),
+            bias.map(|b| b.expand::<_, Axis<1>>()),
+        )
+    }
+
+    /// Attend with keys, queries and values that have already been projected, adding `bias` to the attention scores
+    /// of each query and key
+    fn attend_projected<B: Dimension, S1: Dimension, S2: Dimension>(
+        &self,
+        keys: GraphTensor<(B, S1, Const<K_DIM>)>,
+        queries: GraphTensor<(B, S2, Const<K_DIM>)>,
+        values: GraphTensor<(B, S1, Const<V_DIM>)>,
+        bias: Option<GraphTensor<(B, S2, S1)>>,
+    ) -> GraphTensor<(B, S2, Const<DIM>)> {
+        let values = values
             .dyn_reshape::<(B, S1, Const<HEADS>, Dyn<'-'>), _>(&[
                 B::size(),
                 S1::size(),
                 HEADS.into(),
-                (K_DIM / HEADS).into(),
+                (V_DIM / HEADS).into(),
             ])
             .permute::<_, Axes4<0, 2, 1, 3>>();
-        let keys = self
-            .w_k
-            .forward(keys)
+        let keys = keys
             .dyn_reshape::<(B, S1, Const<HEADS>, Dyn<'-'>), _>(&[
                 B::size(),
                 S1::size(),
@@ -159,9 +274,7 @@ impl<
                 (K_DIM / HEADS).into(),
             ])
             .permute::<_, Axes4<0, 2, 3, 1>>();
-        let queries = self
-            .w_q
-            .forward(queries)
+        let queries = queries
             .dyn_reshape::<(B, S2, Const<HEADS>, Dyn<'-'>), _>(&[
                 B::size(),
                 S2::size(),
@@ -170,10 +283,13 @@ impl<
             ])
             .permute::<_, Axes4<0, 2, 1, 3>>();
 
-        let weights = queries
+        let mut weights = queries
             .matmul(keys)
-            .mul((1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32)
-            .softmax::<Axis<3>>();
+            .mul((1.0 / ((K_DIM / HEADS) as f64).sqrt()) as f32);
+        if let Some(bias) = bias {
+            weights += bias.expand::<_, Axis<1>>();
+        }
+        let weights = weights.softmax::<Axis<3>>();
 
         let tokens: GraphTensor<(B, S2, Const<V_DIM>)> = weights
             .matmul(values)
@@ -192,6 +308,7 @@ mod tests {
     };
 
     use super::MultiHeadSelfAttention;
+    use crate::{PagedAttentionInputs, PagedKVCache};
     #[test]
     fn test_self_attention() {
         let mut cx = Graph::new();
@@ -276,4 +393,84 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_self_attention_padding_mask() {
+        let mut cx = Graph::new();
+        let model: MultiHeadSelfAttention<4, 4, 4, 2> = InitModule::initialize(&mut cx);
+        let data = luminal::tests::random_vec(2 * 3 * 4);
+        // The second sequence only has two tokens, so its last position is padding
+        let batch = cx.tensor::<R3<2, 3, 4>>().set(data.clone());
+        let mask = cx.tensor::<R2<2, 3>>().set([[1., 1., 1.], [1., 1., 0.]]);
+        let masked = model.forward((batch, mask)).retrieve();
+        let unpadded = cx.tensor::<R2<2, 4>>().set(data[12..20].to_vec());
+        let unpadded = model.forward(unpadded).retrieve();
+        let unmasked = model.forward(batch).retrieve();
+        cx.execute();
+
+        let masked = masked.data();
+        assert_close(&masked[..12], &unmasked.data()[..12]);
+        assert_close(&masked[12..20], &unpadded.data());
+    }
+
+    #[test]
+    fn test_self_attention_paged_cache() {
+        let mut cx = Graph::new();
+        let model: MultiHeadSelfAttention<4, 4, 4, 2> = InitModule::initialize(&mut cx);
+        let mut cache = PagedKVCache::new(4, 2);
+        let k_pool = cx.named_tensor::<R2<8, 4>>("Keys").set(vec![0.; 32]);
+        let v_pool = cx.named_tensor::<R2<8, 4>>("Values").set(vec![0.; 32]);
+        let paged = PagedAttentionInputs::<Dyn<'b'>, Dyn<'s'>, Dyn<'t'>>::new(&mut cx);
+        let input = cx.tensor::<(Dyn<'b'>, Dyn<'s'>, luminal::shape::Const<4>)>();
+        let (out, (new_k, new_v)) = model.forward((input, (k_pool, v_pool), paged));
+        out.retrieve();
+        new_k.keep();
+        new_v.keep();
+
+        // The last token of each sequence sees all of it, so it matches plain attention over the whole sequence
+        let (a, b) = (
+            luminal::tests::random_vec(16),
+            luminal::tests::random_vec(12),
+        );
+        let refs = [
+            model
+                .forward(cx.tensor::<R2<3, 4>>().set(a[..12].to_vec()))
+                .no_shape(),
+            model
+                .forward(cx.tensor::<R2<2, 4>>().set(b[..8].to_vec()))
+                .no_shape(),
+            model
+                .forward(cx.tensor::<R2<4, 4>>().set(a.clone()))
+                .no_shape(),
+            model
+                .forward(cx.tensor::<R2<3, 4>>().set(b.clone()))
+                .no_shape(),
+        ]
+        .map(|r| r.retrieve());
+
+        // Prefill three tokens of a and two of b, padding b, then decode one more token of each
+        let (seq_a, seq_b) = (cache.add_sequence(), cache.add_sequence());
+        let mut outputs = vec![];
+        for (step, data) in [
+            (
+                vec![(seq_a, 3), (seq_b, 2)],
+                [&a[..12], &b[..8], &[0.; 4]].concat(),
+            ),
+            (vec![(seq_a, 1), (seq_b, 1)], [&a[12..], &b[8..]].concat()),
+        ] {
+            let batch = cache.prepare(&step).unwrap();
+            paged.set(&batch);
+            input.set_dyn(data, &[batch.batch_size, batch.new_len, 4]);
+            cx.execute();
+            outputs.push(out.data());
+            out.drop();
+            transfer_data_same_graph((new_k, new_v), (k_pool, v_pool), &mut cx);
+        }
+
+        let last = |r: GraphTensor<()>| r.data()[r.data().len() - 4..].to_vec();
+        assert_close(&outputs[0][8..12], &last(refs[0]));
+        assert_close(&outputs[0][16..20], &last(refs[1]));
+        assert_close(&outputs[1][..4], &last(refs[2]));
+        assert_close(&outputs[1][4..], &last(refs[3]));
+    }
 }
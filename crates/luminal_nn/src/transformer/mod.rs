@@ -6,6 +6,10 @@ mod decoder;
 pub use decoder::*;
 mod encoder;
 pub use encoder::*;
+mod paged_cache;
+pub use paged_cache::*;
+mod scheduler;
+pub use scheduler::*;
 
 pub struct Transformer<
     const DIM: usize,
@@ -0,0 +1,244 @@
+use luminal::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// Hands out fixed-size blocks of a shared key-value pool to sequences, so many sequences of different lengths can share
+/// one cache without each reserving room for its maximum length.
+///
+/// The pool tensors have `n_slots` rows, and slot `block * block_size + i` holds the `i`th token of a block. Each
+/// sequence has a block table listing its blocks in order. Every attention layer keeps its own pools but shares the
+/// same block tables, so one manager covers the whole model.
+pub struct PagedKVCache {
+    block_size: usize,
+    n_blocks: usize,
+    free_blocks: Vec<usize>,
+    sequences: FxHashMap<usize, SequenceBlocks>,
+    next_sequence: usize,
+}
+
+#[derive(Default)]
+struct SequenceBlocks {
+    blocks: Vec<usize>,
+    len: usize,
+}
+
+/// Data for the inputs of one step over a batch of sequences, padded to the longest sequence in the batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct PagedBatch {
+    pub batch_size: usize,
+    /// Number of new tokens per sequence
+    pub new_len: usize,
+    /// Number of cached tokens per sequence, including the new ones
+    pub total_len: usize,
+    /// Slot each new token's keys and values get written to, shaped [batch_size, new_len]. Padding points past the
+    /// pool so nothing gets written.
+    pub write_slots: Vec<f32>,
+    /// Slot of every cached token, shaped [batch_size, total_len]
+    pub read_slots: Vec<f32>,
+    /// Which cached tokens each new token attends to, shaped [batch_size, new_len, total_len]
+    pub mask: Vec<f32>,
+}
+
+impl PagedKVCache {
+    pub fn new(n_blocks: usize, block_size: usize) -> Self {
+        assert!(block_size > 0, "Blocks need to hold at least one token");
+        Self {
+            block_size,
+            n_blocks,
+            free_blocks: (0..n_blocks).rev().collect(),
+            sequences: FxHashMap::default(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Number of rows in each pool tensor
+    pub fn n_slots(&self) -> usize {
+        self.n_blocks * self.block_size
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn n_free_blocks(&self) -> usize {
+        self.free_blocks.len()
+    }
+
+    /// Start tracking a new, empty sequence and return its id
+    pub fn add_sequence(&mut self) -> usize {
+        let id = self.next_sequence;
+        self.next_sequence += 1;
+        self.sequences.insert(id, SequenceBlocks::default());
+        id
+    }
+
+    /// Stop tracking a sequence and give its blocks back to the pool
+    pub fn remove_sequence(&mut self, sequence: usize) {
+        if let Some(seq) = self.sequences.remove(&sequence) {
+            self.free_blocks.extend(seq.blocks.into_iter().rev());
+        }
+    }
+
+    /// Number of tokens cached for a sequence
+    pub fn len(&self, sequence: usize) -> usize {
+        self.sequence(sequence).len
+    }
+
+    /// Blocks of a sequence, in order
+    pub fn block_table(&self, sequence: usize) -> &[usize] {
+        &self.sequence(sequence).blocks
+    }
+
+    /// Pool slot holding a sequence's token at `position`
+    pub fn slot(&self, sequence: usize, position: usize) -> usize {
+        let seq = self.sequence(sequence);
+        assert!(
+            position < seq.len,
+            "Position {position} is past the end of sequence {sequence}"
+        );
+        seq.blocks[position / self.block_size] * self.block_size + position % self.block_size
+    }
+
+    /// Make room for new tokens in a batch of `(sequence, n_new_tokens)` pairs, and build the inputs for running them.
+    ///
+    /// Fails without changing anything if a sequence is in the batch more than once, or if there aren't enough free blocks
+    /// for the whole batch.
+    pub fn prepare(&mut self, batch: &[(usize, usize)]) -> Result<PagedBatch, LuminalError> {
+        let mut seen = FxHashSet::default();
+        if let Some((s, _)) = batch.iter().find(|(s, _)| !seen.insert(*s)) {
+            return Err(LuminalError::DuplicateSequence(*s));
+        }
+        let blocks_needed = batch
+            .iter()
+            .map(|(s, n)| {
+                let seq = self.sequence(*s);
+                (seq.len + n).div_ceil(self.block_size) - seq.blocks.len()
+            })
+            .collect::<Vec<_>>();
+        let (needed, free) = (blocks_needed.iter().sum(), self.free_blocks.len());
+        if needed > free {
+            return Err(LuminalError::CacheFull { needed, free });
+        }
+        for ((s, n), needed) in batch.iter().zip(blocks_needed) {
+            let seq = self.sequences.get_mut(s).unwrap();
+            seq.len += n;
+            let start = self.free_blocks.len() - needed;
+            seq.blocks.extend(self.free_blocks.drain(start..).rev());
+        }
+
+        let new_len = batch.iter().map(|(_, n)| *n).max().unwrap_or_default();
+        let total_len = batch
+            .iter()
+            .map(|(s, _)| self.len(*s))
+            .max()
+            .unwrap_or_default();
+        let mut out = PagedBatch {
+            batch_size: batch.len(),
+            new_len,
+            total_len,
+            write_slots: vec![self.n_slots() as f32; batch.len() * new_len],
+            read_slots: vec![0.; batch.len() * total_len],
+            mask: vec![0.; batch.len() * new_len * total_len],
+        };
+        for (b, (s, n)) in batch.iter().enumerate() {
+            let len = self.len(*s);
+            let past = len - n;
+            for i in 0..*n {
+                out.write_slots[b * new_len + i] = self.slot(*s, past + i) as f32;
+            }
+            for j in 0..len {
+                out.read_slots[b * total_len + j] = self.slot(*s, j) as f32;
+            }
+            // New tokens attend to everything up to and including themselves. Padding attends to the first token so
+            // its softmax stays well defined.
+            for i in 0..new_len {
+                let visible = if i < *n { past + i + 1 } else { 1 };
+                let row = (b * new_len + i) * total_len;
+                out.mask[row..row + visible].fill(1.);
+            }
+        }
+        Ok(out)
+    }
+
+    fn sequence(&self, sequence: usize) -> &SequenceBlocks {
+        self.sequences
+            .get(&sequence)
+            .unwrap_or_else(|| panic!("Sequence {sequence} isn't in the cache"))
+    }
+}
+
+/// Graph inputs that route attention through a paged cache's block tables, filled in from a `PagedBatch` each step
+#[derive(Clone, Copy)]
+pub struct PagedAttentionInputs<B: Dimension, S: Dimension, S1: Dimension> {
+    pub write_slots: GraphTensor<(B, S)>,
+    pub read_slots: GraphTensor<(B, S1)>,
+    pub mask: GraphTensor<(B, S, S1)>,
+}
+
+impl<B: Dimension, S: Dimension, S1: Dimension> PagedAttentionInputs<B, S, S1> {
+    pub fn new(cx: &mut Graph) -> Self {
+        Self {
+            write_slots: cx.named_tensor("Paged Write Slots"),
+            read_slots: cx.named_tensor("Paged Read Slots"),
+            mask: cx.named_tensor("Paged Attention Mask"),
+        }
+    }
+
+    /// Set the inputs for the next run
+    pub fn set(&self, batch: &PagedBatch) {
+        let (b, s, s1) = (batch.batch_size, batch.new_len, batch.total_len);
+        self.write_slots.set_dyn(batch.write_slots.clone(), &[b, s]);
+        self.read_slots.set_dyn(batch.read_slots.clone(), &[b, s1]);
+        self.mask.set_dyn(batch.mask.clone(), &[b, s, s1]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::*;
+
+    use super::PagedKVCache;
+
+    #[test]
+    fn test_paged_kv_cache_blocks() {
+        let mut cache = PagedKVCache::new(4, 2);
+        let (a, b) = (cache.add_sequence(), cache.add_sequence());
+        let batch = cache.prepare(&[(a, 3), (b, 1)]).unwrap();
+        assert_eq!(cache.block_table(a), &[0, 1]);
+        assert_eq!(cache.block_table(b), &[2]);
+        assert_eq!((batch.new_len, batch.total_len), (3, 3));
+        // Sequence b's padding writes past the 8 slot pool
+        assert_eq!(batch.write_slots, vec![0., 1., 2., 4., 8., 8.]);
+        assert_eq!(batch.read_slots, vec![0., 1., 2., 4., 0., 0.]);
+        assert_eq!(
+            batch.mask,
+            vec![
+                1., 0., 0., 1., 1., 0., 1., 1., 1., // a is causal
+                1., 0., 0., 1., 0., 0., 1., 0., 0., // b only has one token
+            ]
+        );
+
+        // The next token of a lands in the free half of its last block, b needs a new block
+        let batch = cache.prepare(&[(a, 1), (b, 2)]).unwrap();
+        assert_eq!(batch.write_slots, vec![3., 8., 5., 6.]);
+        assert_eq!(cache.block_table(b), &[2, 3]);
+        assert_eq!(cache.n_free_blocks(), 0);
+
+        // Nothing changes when the pool runs out, or when a sequence is listed twice
+        assert_eq!(
+            cache.prepare(&[(a, 1)]),
+            Err(LuminalError::CacheFull { needed: 1, free: 0 })
+        );
+        assert_eq!(
+            cache.prepare(&[(b, 1), (a, 0), (b, 1)]),
+            Err(LuminalError::DuplicateSequence(b))
+        );
+        assert_eq!(cache.len(a), 4);
+
+        // Removed sequences give their blocks back
+        cache.remove_sequence(a);
+        let c = cache.add_sequence();
+        cache.prepare(&[(c, 3)]).unwrap();
+        assert_eq!(cache.block_table(c), &[0, 1]);
+        assert_eq!(cache.slot(c, 2), 2);
+    }
+}
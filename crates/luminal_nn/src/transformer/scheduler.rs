@@ -0,0 +1,415 @@
+use std::{collections::VecDeque, sync::mpsc};
+
+use luminal::prelude::*;
+
+use crate::{PagedAttentionInputs, PagedKVCache};
+
+/// A prompt to generate from, and when to stop
+pub struct GenerationRequest {
+    prompt: Vec<u32>,
+    sampler: Sampler,
+    stop_tokens: Vec<u32>,
+    max_new_tokens: Option<usize>,
+}
+
+impl GenerationRequest {
+    pub fn new(prompt: &[u32]) -> Self {
+        assert!(
+            !prompt.is_empty(),
+            "Requests need at least one prompt token"
+        );
+        Self {
+            prompt: prompt.to_vec(),
+            sampler: Sampler::greedy(),
+            stop_tokens: vec![],
+            max_new_tokens: None,
+        }
+    }
+
+    /// Sampler used to pick each token. Defaults to greedy sampling.
+    pub fn sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Finish once any of these tokens gets sampled. The stop token itself isn't streamed.
+    pub fn stop_tokens(mut self, tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_tokens.extend(tokens);
+        self
+    }
+
+    /// Finish after generating this many tokens past the prompt
+    pub fn max_new_tokens(mut self, max: usize) -> Self {
+        self.max_new_tokens = Some(max);
+        self
+    }
+}
+
+/// What a request's stream receives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamEvent {
+    Token(u32),
+    Finished(StopReason),
+}
+
+/// Tokens generated for one request, as the scheduler produces them. Iterating blocks until the next token is ready, and
+/// ends once the request finishes. Streams can be sent to other threads, so each can be forwarded to its own client.
+pub struct RequestStream {
+    receiver: mpsc::Receiver<StreamEvent>,
+    cancel: CancelHandle,
+    stop_reason: Option<StopReason>,
+}
+
+impl RequestStream {
+    /// Handle that cancels the request. Dropping the stream cancels it too.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Why the request finished, once the stream has ended
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+}
+
+impl Drop for RequestStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+impl Iterator for RequestStream {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        match self.receiver.recv() {
+            Ok(StreamEvent::Token(token)) => Some(token),
+            Ok(StreamEvent::Finished(reason)) => {
+                self.stop_reason = Some(reason);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// A request that's waiting for room or being generated
+struct InFlight {
+    request: GenerationRequest,
+    tokens: Vec<u32>,
+    /// Tokens already in the cache
+    n_processed: usize,
+    sequence: Option<usize>,
+    sender: mpsc::Sender<StreamEvent>,
+    cancel: CancelHandle,
+    finished: Option<StopReason>,
+}
+
+impl InFlight {
+    fn n_generated(&self) -> usize {
+        self.tokens.len() - self.request.prompt.len()
+    }
+
+    fn finish(self, reason: StopReason, cache: &mut PagedKVCache) {
+        if let Some(sequence) = self.sequence {
+            cache.remove_sequence(sequence);
+        }
+        let _ = self.sender.send(StreamEvent::Finished(reason));
+    }
+}
+
+/// Continuous batching over one graph: every step merges all in-flight requests into a single batched forward pass.
+/// Requests join as soon as there's room in the batch and the cache, and leave as soon as they finish, so short requests
+/// never wait on long ones.
+///
+/// `input` takes token ids shaped [batch, tokens] and `logits` gives the next token distributions shaped [batch, tokens,
+/// vocab]. The model's attention reads and writes its cache through `paged`, which lets sequences of different lengths
+/// share a batch, and its pools need `cache.n_slots()` rows. When the cache fills up, the newest requests are pushed
+/// back into the queue and recomputed later.
+pub struct Scheduler<B: Dimension, S: Dimension, S1: Dimension, L: Shape> {
+    input: GraphTensor<(B, S)>,
+    logits: GraphTensor<L>,
+    paged: PagedAttentionInputs<B, S, S1>,
+    cache: PagedKVCache,
+    pools: Option<(Vec<NodeIndex>, Vec<NodeIndex>)>,
+    max_batch_size: usize,
+    waiting: VecDeque<InFlight>,
+    running: Vec<InFlight>,
+}
+
+impl<B: Dimension, S: Dimension, S1: Dimension, L: Shape> Scheduler<B, S, S1, L> {
+    pub fn new(
+        input: GraphTensor<(B, S)>,
+        logits: GraphTensor<L>,
+        paged: PagedAttentionInputs<B, S, S1>,
+        cache: PagedKVCache,
+    ) -> Self {
+        Self {
+            input,
+            logits,
+            paged,
+            cache,
+            pools: None,
+            max_batch_size: 16,
+            waiting: VecDeque::new(),
+            running: vec![],
+        }
+    }
+
+    /// Move the updated pools in `pools_dest` into `pools_src` after every step
+    pub fn kv_cache(mut self, pools_src: impl ToIds, pools_dest: impl ToIds) -> Self {
+        self.pools = Some((pools_src.to_ids(), pools_dest.to_ids()));
+        self
+    }
+
+    /// Most requests to run in one step. Defaults to 16.
+    pub fn max_batch_size(mut self, max: usize) -> Self {
+        assert!(max > 0, "Batches need room for at least one request");
+        self.max_batch_size = max;
+        self
+    }
+
+    /// Queue up a request, returning the stream its tokens come out of
+    pub fn submit(&mut self, request: GenerationRequest) -> RequestStream {
+        let (sender, receiver) = mpsc::channel();
+        let cancel = CancelHandle::default();
+        let in_flight = InFlight {
+            tokens: request.prompt.clone(),
+            request,
+            n_processed: 0,
+            sequence: None,
+            sender,
+            cancel: cancel.clone(),
+            finished: None,
+        };
+        if in_flight.request.max_new_tokens == Some(0) {
+            in_flight.finish(StopReason::MaxTokens, &mut self.cache);
+        } else {
+            self.waiting.push_back(in_flight);
+        }
+        RequestStream {
+            receiver,
+            cancel,
+            stop_reason: None,
+        }
+    }
+
+    /// Whether there's nothing left to generate
+    pub fn is_idle(&self) -> bool {
+        self.waiting.is_empty() && self.running.is_empty()
+    }
+
+    /// Number of requests in the last batch and waiting for room
+    pub fn n_requests(&self) -> (usize, usize) {
+        (self.running.len(), self.waiting.len())
+    }
+
+    /// Run steps until every request has finished
+    pub fn run(&mut self) {
+        while !self.is_idle() {
+            self.step();
+        }
+    }
+
+    /// Generate one token for every running request, after letting in waiting requests that fit
+    pub fn step(&mut self) {
+        // Drop cancelled requests
+        for in_flight in std::mem::take(&mut self.waiting) {
+            if in_flight.cancel.is_cancelled() {
+                in_flight.finish(StopReason::Cancelled, &mut self.cache);
+            } else {
+                self.waiting.push_back(in_flight);
+            }
+        }
+        for in_flight in std::mem::take(&mut self.running) {
+            if in_flight.cancel.is_cancelled() {
+                in_flight.finish(StopReason::Cancelled, &mut self.cache);
+            } else {
+                self.running.push(in_flight);
+            }
+        }
+
+        // Let in waiting requests while their prompts fit
+        let mut free_blocks = self.cache.n_free_blocks();
+        while self.running.len() < self.max_batch_size {
+            let Some(next) = self.waiting.front() else {
+                break;
+            };
+            let blocks = next.tokens.len().div_ceil(self.cache.block_size());
+            if blocks > free_blocks {
+                if self.running.is_empty() {
+                    // Too long to fit in the cache at all
+                    let too_long = self.waiting.pop_front().unwrap();
+                    too_long.finish(StopReason::CacheFull, &mut self.cache);
+                    continue;
+                }
+                break;
+            }
+            free_blocks -= blocks;
+            let mut in_flight = self.waiting.pop_front().unwrap();
+            in_flight.sequence = Some(self.cache.add_sequence());
+            self.running.push(in_flight);
+        }
+
+        // Make room for each request's new tokens, pushing back the newest requests while the cache is full
+        let batch = loop {
+            let step = self
+                .running
+                .iter()
+                .map(|r| (r.sequence.unwrap(), r.tokens.len() - r.n_processed))
+                .collect::<Vec<_>>();
+            if step.is_empty() {
+                return;
+            }
+            match self.cache.prepare(&step) {
+                Ok(batch) => break batch,
+                Err(LuminalError::CacheFull { .. }) => {}
+                Err(e) => panic!("{e}"),
+            }
+            let mut newest = self.running.pop().unwrap();
+            if self.running.is_empty() {
+                // Too long to fit in the cache at all
+                newest.finish(StopReason::CacheFull, &mut self.cache);
+                continue;
+            }
+            self.cache.remove_sequence(newest.sequence.take().unwrap());
+            newest.n_processed = 0;
+            self.waiting.push_front(newest);
+        };
+
+        // Run the new tokens of every request, padding them to the longest
+        let mut input = vec![0.; batch.batch_size * batch.new_len];
+        for (b, r) in self.running.iter().enumerate() {
+            for (i, t) in r.tokens[r.n_processed..].iter().enumerate() {
+                input[b * batch.new_len + i] = *t as f32;
+            }
+        }
+        self.input
+            .set_dyn(input, &[batch.batch_size, batch.new_len]);
+        self.paged.set(&batch);
+        let cx = self.input.graph();
+        cx.execute();
+
+        // Sample from each request's last real position
+        let logits = self.logits.data();
+        let mut logits_shape = self.logits.shape;
+        logits_shape.resolve_global_dyn_dims(&cx.dyn_map);
+        let vocab = logits_shape.shape_usize().last().copied().unwrap_or(1);
+        for (b, r) in self.running.iter_mut().enumerate() {
+            let position = b * batch.new_len + r.tokens.len() - r.n_processed - 1;
+            let logits = &logits[position * vocab..(position + 1) * vocab];
+            let token = r.request.sampler.sample(logits, &r.tokens);
+            r.n_processed = r.tokens.len();
+            if r.request.stop_tokens.contains(&token) {
+                r.finished = Some(StopReason::StopToken(token));
+                continue;
+            }
+            r.tokens.push(token);
+            if r.sender.send(StreamEvent::Token(token)).is_err() {
+                // A dropped stream means nobody is listening anymore
+                r.finished = Some(StopReason::Cancelled);
+            } else if r.request.max_new_tokens.map(|m| r.n_generated() >= m) == Some(true) {
+                r.finished = Some(StopReason::MaxTokens);
+            }
+        }
+        self.logits.drop();
+        if let Some((pools_src, pools_dest)) = &self.pools {
+            transfer_data_same_graph(pools_dest, pools_src, cx);
+        }
+
+        // Retire finished requests
+        for mut r in std::mem::take(&mut self.running) {
+            match r.finished.take() {
+                Some(reason) => r.finish(reason, &mut self.cache),
+                None => self.running.push(r),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::prelude::{Module, *};
+
+    use super::{GenerationRequest, Scheduler};
+    use crate::{Embedding, Linear, MultiHeadSelfAttention, PagedAttentionInputs, PagedKVCache};
+
+    #[test]
+    fn test_scheduler_matches_sequential() {
+        let mut cx = Graph::new();
+        let embedding: Embedding<8, 4> = InitModule::initialize(&mut cx);
+        let attention: MultiHeadSelfAttention<4, 4, 4, 2> = InitModule::initialize(&mut cx);
+        let head: Linear<4, 8> = InitModule::initialize(&mut cx);
+        let k_pool = cx.named_tensor::<R2<16, 4>>("Keys").set(vec![0.; 64]);
+        let v_pool = cx.named_tensor::<R2<16, 4>>("Values").set(vec![0.; 64]);
+        let paged = PagedAttentionInputs::<Dyn<'b'>, Dyn<'s'>, Dyn<'t'>>::new(&mut cx);
+        let input = cx.tensor::<(Dyn<'b'>, Dyn<'s'>)>();
+        let (x, (new_k, new_v)) =
+            attention.forward((embedding.forward(input), (k_pool, v_pool), paged));
+        let logits = head.forward(x).retrieve();
+        new_k.keep();
+        new_v.keep();
+
+        let prompts = [(vec![1, 2], 3), (vec![4], 3), (vec![5, 6], 2)];
+        let generate = |cache: PagedKVCache, batch_size: usize| {
+            let mut scheduler = Scheduler::new(input, logits, paged, cache)
+                .kv_cache((k_pool, v_pool), (new_k, new_v))
+                .max_batch_size(batch_size);
+            let streams = prompts
+                .iter()
+                .map(|(p, max)| scheduler.submit(GenerationRequest::new(p).max_new_tokens(*max)))
+                .collect::<Vec<_>>();
+            scheduler.run();
+            streams
+                .into_iter()
+                .map(|s| s.collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+
+        let sequential = generate(PagedKVCache::new(8, 2), 1);
+        assert_eq!(
+            sequential.iter().map(|t| t.len()).collect::<Vec<_>>(),
+            vec![3, 3, 2]
+        );
+        assert_eq!(generate(PagedKVCache::new(8, 2), 3), sequential);
+        // Four blocks fit every prompt but not every continuation, so the newest request gets pushed back
+        assert_eq!(generate(PagedKVCache::new(4, 2), 3), sequential);
+
+        let mut scheduler = Scheduler::new(input, logits, paged, PagedKVCache::new(8, 2))
+            .kv_cache((k_pool, v_pool), (new_k, new_v));
+        let stopped = GenerationRequest::new(&prompts[0].0).stop_tokens([sequential[0][1]]);
+        let mut stopped = scheduler.submit(stopped);
+        let mut cancelled = scheduler.submit(GenerationRequest::new(&[3]));
+        cancelled.cancel_handle().cancel();
+        scheduler.run();
+        let expected = sequential[0]
+            .iter()
+            .take_while(|t| **t != sequential[0][1])
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(stopped.by_ref().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            stopped.stop_reason(),
+            Some(&StopReason::StopToken(sequential[0][1]))
+        );
+        assert_eq!(cancelled.next(), None);
+        assert_eq!(cancelled.stop_reason(), Some(&StopReason::Cancelled));
+
+        // Dropped streams are cancelled before they take a spot in the batch
+        let mut scheduler = Scheduler::new(input, logits, paged, PagedKVCache::new(8, 2))
+            .kv_cache((k_pool, v_pool), (new_k, new_v))
+            .max_batch_size(1);
+        drop(scheduler.submit(GenerationRequest::new(&[3])));
+        let mut kept = scheduler.submit(GenerationRequest::new(&prompts[0].0).max_new_tokens(3));
+        scheduler.step();
+        assert_eq!(scheduler.n_requests(), (1, 0));
+        scheduler.run();
+        assert_eq!(kept.by_ref().collect::<Vec<_>>(), sequential[0]);
+
+        // Prompts longer than the whole cache finish without generating anything
+        let mut too_long = scheduler.submit(GenerationRequest::new(&[1; 17]));
+        scheduler.run();
+        assert_eq!(too_long.next(), None);
+        assert_eq!(too_long.stop_reason(), Some(&StopReason::CacheFull));
+    }
+}
@@ -0,0 +1,195 @@
+use luminal::prelude::*;
+
+/// Dynamic loss scaling for mixed precision training.
+///
+/// Half precision gradients underflow to zero when they're small, so the loss is multiplied by a large scale before
+/// the backward pass and the gradients are divided by it afterwards. If the scale gets so large that a gradient
+/// overflows, the step is skipped and the scale backs off. After enough steps without overflowing, it grows again.
+///
+/// Weights stay in f32 the whole time. `AutoCast` run before `Autograd` rounds the inputs of matmuls to half precision,
+/// and gradients flowing back through those casts get cast too, so the backward pass runs in half precision as well:
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_training::{sgd_on_graph, Autograd, LossScaler};
+/// let mut cx = Graph::new();
+/// let weight = cx.named_tensor::<R2<2, 2>>("Weight").set([[1., 2.], [3., 4.]]).keep();
+/// let input = cx.tensor::<R1<2>>().set([1., -1.]);
+/// let loss = input.matmul(weight).square().sum_reduce();
+///
+/// cx.compile(AutoCast::new(DType::F16), ());
+/// let mut scaler = LossScaler::new();
+/// let grads = cx.compile(Autograd::new(weight, scaler.scale_loss(loss)), ());
+/// let grads = scaler.unscale(&mut cx, &grads);
+/// let (new_weights, _) = sgd_on_graph(&mut cx, weight, &grads);
+/// cx.keep_tensors(&new_weights);
+///
+/// cx.execute();
+/// // Only take the step if the gradients didn't overflow
+/// if scaler.update() {
+///     transfer_data_same_graph(&new_weights, weight, &mut cx);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct LossScaler {
+    /// Current loss scale
+    pub scale: f32,
+    /// Scale multiplier after `growth_interval` steps in a row without overflowing
+    pub growth_factor: f32,
+    /// Scale multiplier after a step that overflowed
+    pub backoff_factor: f32,
+    pub growth_interval: usize,
+    good_steps: usize,
+    scale_tensor: Option<GraphTensor<()>>,
+    finite: Option<GraphTensor<()>>,
+}
+
+impl Default for LossScaler {
+    fn default() -> Self {
+        Self {
+            scale: 65536.,
+            growth_factor: 2.,
+            backoff_factor: 0.5,
+            growth_interval: 2000,
+            good_steps: 0,
+            scale_tensor: None,
+            finite: None,
+        }
+    }
+}
+
+impl LossScaler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start from a different loss scale
+    pub fn init_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Multiply the loss by the loss scale. Differentiate the scaled loss instead of the loss.
+    pub fn scale_loss(&mut self, loss: GraphTensor<()>) -> GraphTensor<()> {
+        let scale = loss
+            .graph()
+            .named_tensor::<R0>("Loss Scale")
+            .set(self.scale)
+            .keep();
+        self.scale_tensor = Some(scale);
+        loss * scale.expand_to(loss.shape)
+    }
+
+    /// Divide gradients of the scaled loss by the loss scale, and check whether any of them overflowed
+    pub fn unscale(
+        &mut self,
+        graph: &mut Graph,
+        grads: &[(NodeIndex, ShapeTracker)],
+    ) -> Vec<(NodeIndex, ShapeTracker)> {
+        let inv_scale = self
+            .scale_tensor
+            .expect("The loss needs to be scaled before unscaling gradients")
+            .recip()
+            .into_dyn();
+        // Check each element on its own, since anything summed over large gradients could overflow by itself
+        let mut finite = graph.constant(1.);
+        let grads = grads
+            .iter()
+            .map(|(id, shape)| {
+                let grad = DynGraphTensor::from_id(*id, *shape, graph) * inv_scale;
+                let axes = (0..grad.rank()).collect::<Vec<_>>();
+                finite = finite.minimum(grad.is_finite().min_reduce(&axes).typed::<R0>());
+                (grad.id, grad.shape)
+            })
+            .collect();
+        self.finite = Some(finite.retrieve());
+        grads
+    }
+
+    /// Read whether the last execution's gradients were all finite, and adjust the loss scale for the next one.
+    /// Returns whether the step should be taken.
+    pub fn update(&mut self) -> bool {
+        let finite = self
+            .finite
+            .expect("Gradients need to be unscaled before updating");
+        let ok = finite.item() != 0.;
+        finite.drop();
+        if ok {
+            self.good_steps += 1;
+            if self.good_steps == self.growth_interval {
+                self.scale *= self.growth_factor;
+                self.good_steps = 0;
+            }
+        } else {
+            self.scale *= self.backoff_factor;
+            self.good_steps = 0;
+        }
+        if let Some(scale) = self.scale_tensor {
+            // The scale is kept, so its old value needs dropping for the new one to load
+            scale.set(self.scale).drop();
+        }
+        ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_close};
+
+    use crate::{Autograd, LossScaler};
+
+    #[test]
+    fn test_loss_scaling() {
+        let mut cx = Graph::new();
+        let weight = cx.tensor::<R2<2, 2>>().set([[1., 2.], [3., 4.]]).keep();
+        let input = cx.tensor::<R2<1, 2>>().set([[3., 1.]]);
+        let loss = input.matmul(weight).sum_reduce::<_, Axes2<0, 1>>();
+
+        cx.compile(AutoCast::new(DType::F16), ());
+        let mut scaler = LossScaler::new();
+        scaler.growth_interval = 2;
+        let grads = cx.compile(Autograd::new(weight, scaler.scale_loss(loss)), ());
+        let grads = scaler.unscale(&mut cx, &grads);
+        let grad = GraphTensor::<R2<2, 2>>::from_id(grads[0].0, grads[0].1, &mut cx).retrieve();
+
+        // The weight gradient is 3 * 65536 scaled, which overflows f16 in the backward pass
+        cx.execute();
+        assert!(!scaler.update());
+        assert_eq!(scaler.scale, 32768.);
+        grad.drop();
+        cx.execute();
+        assert!(!scaler.update());
+        assert_eq!(scaler.scale, 16384.);
+
+        // Once it fits, the unscaled gradients match the true ones
+        grad.drop();
+        cx.execute();
+        assert_close(&grad.data(), &[3., 3., 1., 1.]);
+        assert!(scaler.update());
+        assert_eq!(scaler.scale, 16384.);
+
+        // And the scale grows back after enough good steps
+        grad.drop();
+        cx.execute();
+        assert!(scaler.update());
+        assert_eq!(scaler.scale, 32768.);
+    }
+
+    #[test]
+    fn test_large_finite_gradients() {
+        let mut cx = Graph::new();
+        let weight = cx.tensor::<R1<2>>().set([1., 2.]).keep();
+        let input = cx.tensor::<R1<2>>().set([1e30, -1e30]);
+        let loss = (input * weight).sum_reduce();
+
+        let mut scaler = LossScaler::new().init_scale(1.);
+        let grads = cx.compile(Autograd::new(weight, scaler.scale_loss(loss)), ());
+        let grads = scaler.unscale(&mut cx, &grads);
+        let grad = GraphTensor::<R1<2>>::from_id(grads[0].0, grads[0].1, &mut cx).retrieve();
+
+        // Squaring these would overflow, but the gradients themselves are fine
+        cx.execute();
+        assert_close(&grad.data(), &[1e30, -1e30]);
+        assert!(scaler.update());
+        assert_eq!(scaler.scale, 1.);
+    }
+}
@@ -6,18 +6,36 @@ use rustc_hash::{FxHashMap, FxHashSet};
 
 use luminal::{
     op::{
-        Add, Contiguous, Exp2, Function, LessThan, Log2, MaxReduce, Mod, Mul, Recip, Sin, Sqrt,
-        SumReduce,
+        Add, Cast, Constant, Contiguous, CumSum, Exp2, Function, IndexAdd, LessThan, Log2,
+        MaxReduce, MinReduce, Mod, Mul, ProdReduce, Recip, Select, Sin, Sqrt, SumReduce,
     },
     prelude::{tinyvec::ArrayVec, *},
 };
 
 #[derive(Clone, Debug)]
-pub struct Autograd(Vec<NodeIndex>, NodeIndex);
+pub struct Autograd {
+    params: Vec<NodeIndex>,
+    loss: NodeIndex,
+    /// Segments (inputs, outputs) whose activations get recomputed during the backward pass
+    checkpoints: Vec<(Vec<NodeIndex>, Vec<NodeIndex>)>,
+}
 
 impl Autograd {
     pub fn new<W: ToIds>(params: W, loss: GraphTensor<()>) -> Self {
-        Self(params.to_ids(), loss.id)
+        Self {
+            params: params.to_ids(),
+            loss: loss.id,
+            checkpoints: vec![],
+        }
+    }
+
+    /// Checkpoint the subgraph running from `inputs` to `outputs`.
+    ///
+    /// Activations inside the segment are not held onto for the backward pass. Instead, when the backward pass
+    /// reaches the segment, they are recomputed from the segment inputs. This trades extra compute for lower peak memory.
+    pub fn checkpoint<I: ToIds, O: ToIds>(mut self, inputs: I, outputs: O) -> Self {
+        self.checkpoints.push((inputs.to_ids(), outputs.to_ids()));
+        self
     }
 }
 
@@ -48,11 +66,34 @@ fn build_dfs_set(
 impl Compiler for Autograd {
     type Output = Vec<(NodeIndex, ShapeTracker)>;
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) -> Vec<(NodeIndex, ShapeTracker)> {
-        let Autograd(params, loss) = self;
+        let Autograd {
+            params,
+            loss,
+            checkpoints,
+        } = self;
         // Build up valid set for nodes we want to pay attention to (everything outside of this set doesn't matter)
         let forward_set = build_dfs_set(&mut params.clone(), graph, Direction::Outgoing);
         let backward_set = build_dfs_set(&mut vec![*loss], graph, Direction::Incoming);
         let valid_set: FxHashSet<_> = forward_set.intersection(&backward_set).copied().collect();
+        let weight_set = params.iter().copied().collect::<FxHashSet<_>>();
+
+        // Find all activations inside checkpointed segments. Segment inputs and weights are always stored.
+        let stored_set = checkpoints
+            .iter()
+            .flat_map(|(i, _)| i.iter().copied())
+            .chain(weight_set.iter().copied())
+            .collect::<FxHashSet<_>>();
+        let mut recompute_set = FxHashSet::default();
+        for (inputs, outputs) in checkpoints {
+            let seg_forward = build_dfs_set(&mut inputs.clone(), graph, Direction::Outgoing);
+            let seg_backward = build_dfs_set(&mut outputs.clone(), graph, Direction::Incoming);
+            recompute_set.extend(
+                seg_forward
+                    .intersection(&seg_backward)
+                    .filter(|n| !stored_set.contains(n)),
+            );
+        }
+        let mut recomputed = FxHashMap::default();
 
         // We have the last loss node, now let's backprop through everything to get the gradient graph
         let mut grads = FxHashMap::default();
@@ -64,7 +105,6 @@ impl Compiler for Autograd {
                 ShapeTracker::new(&[]), // Assume scalar loss for now
             ),
         );
-        let weight_set = params.iter().copied().collect::<FxHashSet<_>>();
         for fwd_node in toposort(&graph.graph, None).unwrap().into_iter().rev() {
             if !valid_set.contains(&fwd_node) {
                 continue;
@@ -96,6 +136,21 @@ impl Compiler for Autograd {
                 let (id, sh) = grads[&fwd_node];
                 GraphTensor::from_id(id, sh, graph_ref)
             };
+            // Get the value of a forward tensor for use in the backward pass, recomputing it if it's checkpointed
+            let grad_id = prev_grad.id;
+            let mut val = |t: GraphTensor<()>| {
+                GraphTensor::<()>::from_id(
+                    recompute(
+                        t.id,
+                        grad_id,
+                        unsafe { graph_ref.as_mut().unwrap() },
+                        &recompute_set,
+                        &mut recomputed,
+                    ),
+                    t.shape,
+                    graph_ref,
+                )
+            };
             if op == TypeId::of::<Add>() {
                 // f(a, b) = a + b
                 // df/da = 1
@@ -110,11 +165,17 @@ impl Compiler for Autograd {
                 // f(a, b) = a * b
                 // df/da = b
                 if valid_set.contains(&inps[0].id) {
-                    add_grad(inps[1] * prev_grad, inps[0], graph, &mut grads);
+                    add_grad(val(inps[1]) * prev_grad, inps[0], graph, &mut grads);
                 }
                 // df/db = a
                 if valid_set.contains(&inps[1].id) {
-                    add_grad(inps[0] * prev_grad, inps[1], graph, &mut grads);
+                    add_grad(val(inps[0]) * prev_grad, inps[1], graph, &mut grads);
+                }
+            } else if op == TypeId::of::<Select>() {
+                // f(a, m) = a where m != 0, else 0
+                // df/da = 1 where m != 0, else 0
+                if valid_set.contains(&inps[0].id) {
+                    add_grad(prev_grad.select(val(inps[1])), inps[0], graph, &mut grads);
                 }
             } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
                 .try_get_op::<SumReduce>(fwd_node)
@@ -139,14 +200,146 @@ impl Compiler for Autograd {
                     prev_grad
                         .shape
                         .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
-                    let reduced = GraphTensor::<()>::from_id(fwd_node, prev_grad.shape, graph_ref);
-                    let grad = inps[0].equals(reduced) * prev_grad;
+                    let reduced = val(GraphTensor::<()>::from_id(
+                        fwd_node,
+                        prev_grad.shape,
+                        graph_ref,
+                    ));
+                    let grad = val(inps[0]).equals(reduced) * prev_grad;
+                    add_grad(grad, inps[0], graph, &mut grads);
+                }
+            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
+                .try_get_op::<MinReduce>(fwd_node)
+                .cloned()
+            {
+                // f(x) = min_reduce(x)
+                // f'(x) = x == min_reduce(x)
+                if valid_set.contains(&inps[0].id) {
+                    prev_grad
+                        .shape
+                        .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
+                    let reduced = val(GraphTensor::<()>::from_id(
+                        fwd_node,
+                        prev_grad.shape,
+                        graph_ref,
+                    ));
+                    let grad = val(inps[0]).equals(reduced) * prev_grad;
+                    add_grad(grad, inps[0], graph, &mut grads);
+                }
+            } else if let Some(op) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
+                .try_get_op::<ProdReduce>(fwd_node)
+                .cloned()
+            {
+                // f(x) = prod_reduce(x)
+                // f'(x) = prod_reduce(x) / x
+                if valid_set.contains(&inps[0].id) {
+                    prev_grad
+                        .shape
+                        .expand(op.0, inps[0].shape.dims[inps[0].shape.indexes[op.0]]);
+                    let reduced = val(GraphTensor::<()>::from_id(
+                        fwd_node,
+                        prev_grad.shape,
+                        graph_ref,
+                    ));
+                    let grad = reduced / val(inps[0]) * prev_grad;
                     add_grad(grad, inps[0], graph, &mut grads);
                 }
-            } else if op == TypeId::of::<Contiguous>() {
+            } else if let Some(CumSum(dim)) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
+                .try_get_op::<CumSum>(fwd_node)
+                .cloned()
+            {
+                // f(x) = cumsum(x)
+                // Each input feeds every output from its position onwards, so its gradient is the sum of those: sum(g) - cumsum(g) + g
+                if valid_set.contains(&inps[0].id) {
+                    let cumsum = graph
+                        .add_op(CumSum(dim))
+                        .input(prev_grad.id, 0, prev_grad.shape)
+                        .finish();
+                    let total = graph
+                        .add_op(SumReduce(dim))
+                        .input(prev_grad.id, 0, prev_grad.shape)
+                        .finish();
+                    let mut total_shape = prev_grad.shape;
+                    total_shape.remove_dim(dim);
+                    total_shape = total_shape.contiguous();
+                    total_shape.expand(dim, prev_grad.shape.dims[prev_grad.shape.indexes[dim]]);
+                    let grad = GraphTensor::<()>::from_id(total, total_shape, graph_ref)
+                        - GraphTensor::<()>::from_id(
+                            cumsum,
+                            prev_grad.shape.contiguous(),
+                            graph_ref,
+                        )
+                        + prev_grad;
+                    add_grad(grad, inps[0], graph, &mut grads);
+                }
+            } else if let Some(IndexAdd(dim)) = unsafe { graph_ref.as_ref().unwrap() } // Needed to get around multiple borrows
+                .try_get_op::<IndexAdd>(fwd_node)
+                .cloned()
+            {
+                // f(x, i, s) = x with s added in at i
+                // df/dx = 1
                 if valid_set.contains(&inps[0].id) {
                     add_grad(prev_grad, inps[0], graph, &mut grads);
                 }
+                // df/ds is the gradient read back out at i, which is a gather along the dimension
+                if valid_set.contains(&inps[2].id) {
+                    let dims = prev_grad
+                        .shape
+                        .shape()
+                        .into_iter()
+                        .map(|d| d.small())
+                        .collect::<Vec<_>>();
+                    let n_indexes = inps[1].shape.shape()[0].small();
+                    // One-hot of which slice each index points at
+                    let mut positions = graph
+                        .constant(1.)
+                        .expand_to::<(Dyn<'-'>,)>(ShapeTracker::new(&[dims[dim]]))
+                        .cumsum::<Axis<0>>()
+                        .no_shape()
+                        - 1.;
+                    positions.shape.expand(0, n_indexes);
+                    let mut indexes = val(inps[1]);
+                    indexes.shape.expand(1, dims[dim]);
+                    let mut one_hot = positions.equals(indexes);
+                    // Line the one-hot and gradient up as [.., index, dim, ..] and sum out the dimension
+                    for (i, d) in dims.iter().enumerate() {
+                        if i < dim {
+                            one_hot.shape.expand(i, *d);
+                        } else if i > dim {
+                            one_hot.shape.expand(i + 1, *d);
+                        }
+                    }
+                    prev_grad.shape.expand(dim, n_indexes);
+                    let masked = one_hot * prev_grad;
+                    let gathered = graph
+                        .add_op(SumReduce(dim + 1))
+                        .input(masked.id, 0, masked.shape)
+                        .finish();
+                    let mut shape = masked.shape;
+                    shape.remove_dim(dim + 1);
+                    add_grad(
+                        GraphTensor::from_id(gathered, shape.contiguous(), graph_ref),
+                        inps[2],
+                        graph,
+                        &mut grads,
+                    );
+                }
+            } else if op == TypeId::of::<Contiguous>() || op == TypeId::of::<Cast>() {
+                // Rounding is treated as the identity, so gradients pass straight through. They're held in the same
+                // format as they go, so the backward pass of a mixed precision region runs at its precision too.
+                if valid_set.contains(&inps[0].id) {
+                    let grad = match graph.try_get_op::<Cast>(fwd_node) {
+                        Some(Cast(dtype)) => {
+                            let cast = graph
+                                .add_op(Cast(*dtype))
+                                .input(prev_grad.id, 0, prev_grad.shape)
+                                .finish();
+                            GraphTensor::from_id(cast, prev_grad.shape.contiguous(), graph_ref)
+                        }
+                        None => prev_grad,
+                    };
+                    add_grad(grad, inps[0], graph, &mut grads);
+                }
             } else {
                 if !valid_set.contains(&inps[0].id) {
                     continue;
@@ -154,23 +347,24 @@ impl Compiler for Autograd {
                 let local_grad = if op == TypeId::of::<Log2>() {
                     // f(x) = log2(x)
                     // f'(x) = 1 / (x * ln(2))
-                    1.0 / (inps[0] * 2_f32.ln())
+                    1.0 / (val(inps[0]) * 2_f32.ln())
                 } else if op == TypeId::of::<Exp2>() {
                     // f(x) = exp2(x)
                     // f'(x) = exp2(x) * ln(2)
-                    inps[0].exp2() * 2_f32.ln()
+                    val(inps[0]).exp2() * 2_f32.ln()
                 } else if op == TypeId::of::<Sin>() {
                     // f(x) = sin(x)
                     // f'(x) = cos(x)
-                    inps[0].cos()
+                    val(inps[0]).cos()
                 } else if op == TypeId::of::<Sqrt>() {
                     // f(x) = sqrt(x)
                     // f'(x) = 1 / (2 * sqrt(x))
-                    1.0 / (2.0 * inps[0].sqrt())
+                    1.0 / (2.0 * val(inps[0]).sqrt())
                 } else if op == TypeId::of::<Recip>() {
                     // f(x) = 1 / x
                     // f'(x) = -1 / x**2
-                    -1.0 / (inps[0] * inps[0])
+                    let x = val(inps[0]);
+                    -1.0 / (x * x)
                 } else {
                     unreachable!()
                 };
@@ -179,8 +373,66 @@ impl Compiler for Autograd {
         }
 
         // Create a gradient array to match 1-1 with the weight array passed in
-        self.0.iter().map(|weight| grads[weight]).collect()
+        params.iter().map(|weight| grads[weight]).collect()
+    }
+}
+
+/// Get a copy of a node for the backward pass. If the node is checkpointed, a fresh copy of it (and any checkpointed
+/// nodes it depends on) is added to the graph, scheduled to run only after `after` has been computed.
+fn recompute(
+    node: NodeIndex,
+    after: NodeIndex,
+    graph: &mut Graph,
+    recompute_set: &FxHashSet<NodeIndex>,
+    recomputed: &mut FxHashMap<NodeIndex, NodeIndex>,
+) -> NodeIndex {
+    if !recompute_set.contains(&node) {
+        return node;
+    }
+    if let Some(new_node) = recomputed.get(&node) {
+        return *new_node;
+    }
+    let Some(op) = clone_primitive(graph.node_weight(node).unwrap()) else {
+        // Can't copy this op, so it'll just have to be stored
+        return node;
+    };
+    let srcs = graph
+        .get_sources(node)
+        .into_iter()
+        .map(|(src, out, sh)| {
+            (
+                recompute(src, after, graph, recompute_set, recomputed),
+                out,
+                sh,
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut new_op = graph.add_boxed_op(op);
+    for (src, out, sh) in srcs {
+        new_op = new_op.input(src, out, sh);
     }
+    let new_node = new_op.finish();
+    graph.add_schedule_dependency(after, new_node);
+    recomputed.insert(node, new_node);
+    new_node
+}
+
+#[allow(clippy::borrowed_box)]
+fn clone_primitive(op: &Box<dyn Operator>) -> Option<Box<dyn Operator>> {
+    macro_rules! try_clone {
+        ($($t:ty),*) => {
+            $(
+                if let Some(op) = op.as_any().downcast_ref::<$t>() {
+                    return Some(Box::new(op.clone()));
+                }
+            )*
+        };
+    }
+    try_clone!(
+        Constant, Contiguous, Log2, Exp2, Sin, Recip, Sqrt, Add, Mul, Mod, LessThan, Select,
+        SumReduce, MaxReduce, MinReduce, ProdReduce, CumSum, IndexAdd, Cast
+    );
+    None
 }
 
 fn add_grad(
@@ -216,6 +468,10 @@ fn add_grad(
             pre_fwd_shape.remove_dim(*dim);
         } else if let Some(MaxReduce(dim)) = graph.try_get_op(fwd.id) {
             pre_fwd_shape.remove_dim(*dim);
+        } else if let Some(MinReduce(dim)) = graph.try_get_op(fwd.id) {
+            pre_fwd_shape.remove_dim(*dim);
+        } else if let Some(ProdReduce(dim)) = graph.try_get_op(fwd.id) {
+            pre_fwd_shape.remove_dim(*dim);
         }
         if grad.shape.shape() != pre_fwd_shape.shape() {
             if !grad.shape.is_contiguous() {
@@ -265,6 +521,76 @@ mod tests {
         assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
     }
 
+    #[test]
+    fn test_autograd_cumsum() {
+        let mut cx = Graph::new();
+        let a = cx
+            .named_tensor::<R2<2, 3>>("Input")
+            .set([[1., 2., 3.], [4., 5., 6.]]);
+        let w = cx
+            .named_tensor::<R2<2, 3>>("Weight")
+            .set([[1., -1., 2.], [0., 3., 1.]]);
+        let b = (a.cumsum::<LAxis<1>>() * w).sum_reduce::<(), _>();
+
+        let grads = cx.compile(Autograd::new(a, b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // Each input's gradient is the sum of the weights from its position onwards
+        assert_exact(&get_vec(grads[0], &mut cx), &[2., 1., 2., 4., 4., 1.]);
+    }
+
+    #[test]
+    fn test_autograd_index_add() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor::<R2<3, 2>>("Input").set(random_vec(6));
+        let indexes = cx.tensor::<R1<3>>().set([2., 0., 2.]);
+        let src = cx.named_tensor::<R2<3, 2>>("Source").set(random_vec(6));
+        let w = cx
+            .named_tensor::<R2<3, 2>>("Weight")
+            .set([[1., 2.], [3., 4.], [5., 6.]]);
+        let b = (a.index_add::<LAxis<0>, _, _>(indexes, src) * w).sum_reduce::<(), _>();
+
+        let grads = cx.compile(Autograd::new((a, src), b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // Each source row gets the weight row it was added into
+        assert_exact(&get_vec(grads[0], &mut cx), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&get_vec(grads[1], &mut cx), &[5., 6., 1., 2., 5., 6.]);
+    }
+
+    #[test]
+    fn test_autograd_min_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("Input").set([10., 5.]);
+        let b = a.min_reduce();
+
+        let grads = cx.compile(Autograd::new(a, b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        let dev = dfdx::prelude::Cpu::default();
+        let d_a = dev.tensor([10., 5.]);
+        let d_b = d_a.trace(Gradients::leaky()).min();
+        let d_grads = d_b.backward();
+
+        assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&d_a).as_vec());
+    }
+
+    #[test]
+    fn test_autograd_prod_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("Input").set([2., -3., 4.]);
+        let b = a.prod_reduce();
+
+        let grads = cx.compile(Autograd::new(a, b), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        assert_close(&get_vec(grads[0], &mut cx), &[-12., 8., -6.]);
+    }
+
     #[test]
     fn test_autograd_matmul() {
         let mut cx = Graph::new();
@@ -285,6 +611,22 @@ mod tests {
         assert_exact(&get_vec(grads[0], &mut cx), &d_grads.get(&w1).as_vec());
     }
 
+    #[test]
+    fn test_autograd_autocast_matmul() {
+        let mut cx = Graph::new();
+        let a = cx.named_tensor("A").set([[2.001, 4.], [3., 1.]]);
+        let input = cx.named_tensor("Input").set([10., 5.]);
+        let output = (input.matmul(a)).sum_reduce();
+
+        cx.compile(AutoCast::new(DType::F16), ());
+        let grads = cx.compile(Autograd::new(a, output), ());
+        cx.keep_tensors(&grads);
+        cx.execute();
+
+        // Casts pass gradients straight through (rounded to f16), so the weight gradient is the input broadcast across each column
+        assert_exact(&get_vec(grads[0], &mut cx), &[10., 10., 5., 5.]);
+    }
+
     #[test]
     fn test_autograd_mlp() {
         let mut cx = Graph::new();
@@ -325,6 +667,74 @@ mod tests {
         );
     }
 
+    /// Nodes left in the MLP from `test_autograd_checkpointed_mlp` after compiling it without checkpoints
+    fn unchecked_mlp_nodes() -> usize {
+        let mut cx = Graph::new();
+        let model = <(
+            luminal_nn::Linear<2, 2>,
+            luminal_nn::ReLU,
+            luminal_nn::Linear<2, 1>,
+        )>::initialize(&mut cx);
+        let input = cx.named_tensor("Input").set([10., 5.]);
+        let output = model.forward(input).sum_reduce().retrieve();
+        let mut grads = cx.compile(Autograd::new(params(model), output), ());
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut grads);
+        cx.graph.node_count()
+    }
+
+    #[test]
+    fn test_autograd_checkpointed_mlp() {
+        let mut cx = Graph::new();
+        let model = <(
+            luminal_nn::Linear<2, 2>,
+            luminal_nn::ReLU,
+            luminal_nn::Linear<2, 1>,
+        )>::initialize(&mut cx);
+        model.0.weight.set([[2., 4.], [3., 1.]]);
+        model.2.weight.set([[6.], [5.]]);
+        let input = cx.named_tensor("Input").set([10., 5.]);
+        let hidden = model.1.forward(model.0.forward(input));
+        let output = model.2.forward(hidden).sum_reduce().retrieve();
+
+        let n_nodes = cx.graph.node_count();
+        let mut grads = cx.compile(
+            Autograd::new(params(model), output).checkpoint(input, output),
+            (),
+        );
+        // The backward pass shouldn't read the stored hidden activation, it gets recomputed
+        assert!(cx
+            .graph
+            .edges_directed(hidden.id, petgraph::Direction::Outgoing)
+            .all(|e| e.target().index() < n_nodes));
+        cx.keep_tensors(&grads);
+        cx.compile(GenericCompiler::default(), &mut grads);
+        // CSE mustn't merge the recomputed activations back into the stored ones
+        assert!(cx.graph.node_count() > unchecked_mlp_nodes());
+        cx.execute();
+
+        let dev = dfdx::prelude::Cpu::default();
+        let mut d_model = dev.build_module::<(
+            dfdx::nn::builders::UnbiasedLinear<2, 2>,
+            dfdx::nn::builders::ReLU,
+            dfdx::nn::builders::UnbiasedLinear<2, 1>,
+        ), f32>();
+        d_model.0.weight = dev.tensor([[2., 4.], [3., 1.]]).permute();
+        d_model.2.weight = dev.tensor([[6.], [5.]]).permute();
+        let inp = dev.tensor([10., 5.]);
+        let out = d_model.forward(inp.trace(Gradients::leaky())).sum();
+        let d_grads = out.backward();
+
+        assert_exact(
+            &get_vec(grads[0], &mut cx),
+            &d_grads.get(&d_model.0.weight).permute().as_vec(),
+        );
+        assert_exact(
+            &get_vec(grads[1], &mut cx),
+            &d_grads.get(&d_model.2.weight).as_vec(),
+        );
+    }
+
     #[test]
     fn test_autograd_layer_norm() {
         let mut cx = Graph::new();
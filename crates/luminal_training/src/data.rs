@@ -0,0 +1,274 @@
+use std::sync::Arc;
+
+use luminal::prelude::*;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+
+/// A collection of training examples. Each example has the same fields, like an input and a target, which are
+/// flat f32s of a fixed size.
+pub trait Dataset {
+    /// Number of examples
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of fields in each example
+    fn fields(&self) -> usize;
+
+    /// Append example `index` to the batch, pushing each field onto its buffer in `fields`
+    fn load(&self, index: usize, fields: &mut [Vec<f32>]);
+}
+
+/// In memory examples, each holding `N` fields
+impl<const N: usize> Dataset for Vec<[Vec<f32>; N]> {
+    fn len(&self) -> usize {
+        self.len()
+    }
+
+    fn fields(&self) -> usize {
+        N
+    }
+
+    fn load(&self, index: usize, fields: &mut [Vec<f32>]) {
+        for (buffer, field) in fields.iter_mut().zip(&self[index]) {
+            buffer.extend_from_slice(field);
+        }
+    }
+}
+
+/// Splits a dataset into batches, optionally shuffled each epoch, and binds them straight to graph inputs.
+///
+/// Each field is bound to the input declared with `Graph::input` at the same position, which needs a leading batch
+/// dimension and static sizes after it. Batch buffers are handed to the graph without copying and reused once it's
+/// done with them, so steady state training doesn't allocate for its inputs.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_training::Batcher;
+/// let mut cx = Graph::new();
+/// let x = cx.input::<(Dyn<'b'>, Const<2>)>("x");
+/// let y = cx.input::<(Dyn<'b'>,)>("y");
+/// let err = (x.sum_reduce::<_, Axis<1>>() - y).retrieve();
+/// let examples = vec![[vec![1., 2.], vec![3.]], [vec![3., 4.], vec![7.]], [vec![5., 6.], vec![0.]]];
+/// let mut batcher = Batcher::new(examples, 2).shuffle(0);
+/// for _ in 0..2 {
+///     while let Some(size) = batcher.bind_next(&mut cx, &["x", "y"]).unwrap() {
+///         cx.execute();
+///         assert_eq!(err.data().len(), size);
+///         err.drop();
+///     }
+/// }
+/// assert_eq!(batcher.epoch(), 2);
+/// ```
+pub struct Batcher<D: Dataset> {
+    dataset: D,
+    batch_size: usize,
+    drop_last: bool,
+    rng: Option<StdRng>,
+    order: Vec<usize>,
+    position: usize,
+    epoch: usize,
+    /// One buffer per field. The graph holds a clone while a batch is bound.
+    buffers: Vec<Arc<Vec<f32>>>,
+}
+
+impl<D: Dataset> Batcher<D> {
+    pub fn new(dataset: D, batch_size: usize) -> Self {
+        assert!(batch_size > 0, "Batch size must be at least 1");
+        Self {
+            order: (0..dataset.len()).collect(),
+            dataset,
+            batch_size,
+            drop_last: false,
+            rng: None,
+            position: 0,
+            epoch: 0,
+            buffers: vec![],
+        }
+    }
+
+    /// Shuffle the examples at the start of every epoch, seeding the shuffle so runs are reproducible
+    pub fn shuffle(mut self, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        self.order.shuffle(&mut rng);
+        self.rng = Some(rng);
+        self
+    }
+
+    /// Skip the last batch of each epoch if it would be smaller than the batch size
+    pub fn drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    pub fn dataset(&self) -> &D {
+        &self.dataset
+    }
+
+    /// Number of epochs finished so far
+    pub fn epoch(&self) -> usize {
+        self.epoch
+    }
+
+    /// Number of batches in each epoch
+    pub fn batches_per_epoch(&self) -> usize {
+        if self.drop_last {
+            self.dataset.len() / self.batch_size
+        } else {
+            self.dataset.len().div_ceil(self.batch_size)
+        }
+    }
+
+    /// Load the next batch, returning each field's data, or None once the epoch is over. The epoch after that starts
+    /// on the next call.
+    pub fn next_batch(&mut self) -> Option<Vec<&[f32]>> {
+        self.fill()?;
+        Some(self.buffers.iter().map(|b| b.as_slice()).collect())
+    }
+
+    /// Load the next batch and bind its fields to the graph inputs named in `inputs`, in order. Returns the number
+    /// of examples bound, or None once the epoch is over.
+    pub fn bind_next(
+        &mut self,
+        cx: &mut Graph,
+        inputs: &[&str],
+    ) -> Result<Option<usize>, LuminalError> {
+        assert_eq!(
+            inputs.len(),
+            self.dataset.fields(),
+            "Every field needs an input to bind to"
+        );
+        // Everything past the batch dimension needs to be known to split the data up
+        let example_shapes = inputs
+            .iter()
+            .map(|name| {
+                cx.inputs
+                    .get(*name)
+                    .map(|(_, declared)| {
+                        declared.shape()[1..]
+                            .iter()
+                            .map(|d| d.to_usize().unwrap_or_default())
+                            .collect::<Vec<_>>()
+                    })
+                    .ok_or_else(|| LuminalError::UnknownInput(name.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(size) = self.fill() else {
+            return Ok(None);
+        };
+        for ((name, buffer), example_shape) in inputs.iter().zip(&self.buffers).zip(example_shapes)
+        {
+            let shape = [vec![size], example_shape].concat();
+            cx.bind_shared(name, SharedData::new(Batch(buffer.clone())), &shape)?;
+        }
+        Ok(Some(size))
+    }
+
+    /// Load the next batch into the field buffers, returning how many examples went in
+    fn fill(&mut self) -> Option<usize> {
+        let remaining = self.order.len() - self.position;
+        if remaining == 0 || (self.drop_last && remaining < self.batch_size) {
+            self.next_epoch();
+            return None;
+        }
+        let size = remaining.min(self.batch_size);
+        // Take the buffers back from the graph if it's done with them, otherwise start fresh ones
+        let mut fields = (0..self.dataset.fields())
+            .map(|i| {
+                let mut buffer = self
+                    .buffers
+                    .get_mut(i)
+                    .and_then(Arc::get_mut)
+                    .map(std::mem::take)
+                    .unwrap_or_default();
+                buffer.clear();
+                buffer
+            })
+            .collect::<Vec<_>>();
+        for index in &self.order[self.position..self.position + size] {
+            self.dataset.load(*index, &mut fields);
+        }
+        self.position += size;
+        self.buffers = fields.into_iter().map(Arc::new).collect();
+        Some(size)
+    }
+
+    fn next_epoch(&mut self) {
+        self.epoch += 1;
+        self.position = 0;
+        if let Some(rng) = &mut self.rng {
+            self.order.shuffle(rng);
+        }
+    }
+}
+
+/// A batch buffer lent to the graph
+struct Batch(Arc<Vec<f32>>);
+
+impl AsRef<[f32]> for Batch {
+    fn as_ref(&self) -> &[f32] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn examples(n: usize) -> Vec<[Vec<f32>; 2]> {
+        (0..n)
+            .map(|i| [vec![i as f32, i as f32 * 10.], vec![i as f32]])
+            .collect()
+    }
+
+    #[test]
+    fn test_batcher_epochs() {
+        let mut batcher = Batcher::new(examples(5), 2).shuffle(42);
+        assert_eq!(batcher.batches_per_epoch(), 3);
+        for epoch in 0..2 {
+            let mut seen = vec![];
+            while let Some(batch) = batcher.next_batch() {
+                assert_eq!(batch[0].len(), batch[1].len() * 2);
+                seen.extend(batch[1].iter().map(|i| *i as usize));
+            }
+            assert_eq!(batcher.epoch(), epoch + 1);
+            seen.sort();
+            assert_eq!(seen, vec![0, 1, 2, 3, 4]);
+        }
+
+        let mut batcher = Batcher::new(examples(5), 2).drop_last(true);
+        assert_eq!(batcher.batches_per_epoch(), 2);
+        let mut sizes = vec![];
+        while let Some(batch) = batcher.next_batch() {
+            sizes.push(batch[1].len());
+        }
+        assert_eq!(sizes, vec![2, 2]);
+    }
+
+    #[test]
+    fn test_batcher_binding() {
+        let mut cx = Graph::new();
+        let x = cx.input::<(Dyn<'b'>, Const<2>)>("x");
+        let y = cx.input::<(Dyn<'b'>,)>("y");
+        let out = (x.sum_reduce::<_, Axis<1>>() - y).retrieve();
+        let mut batcher = Batcher::new(examples(3), 2);
+
+        assert_eq!(batcher.bind_next(&mut cx, &["x", "y"]), Ok(Some(2)));
+        let first = batcher.buffers[0].as_ptr();
+        cx.execute();
+        assert_eq!(out.data(), vec![0., 10.]);
+        out.drop();
+
+        // The graph is done with the first batch, so its buffers get refilled in place
+        assert_eq!(batcher.bind_next(&mut cx, &["x", "y"]), Ok(Some(1)));
+        assert_eq!(batcher.buffers[0].as_ptr(), first);
+        cx.execute();
+        assert_eq!(out.data(), vec![20.]);
+        assert_eq!(batcher.bind_next(&mut cx, &["x", "y"]), Ok(None));
+
+        assert_eq!(
+            batcher.bind_next(&mut cx, &["x", "z"]),
+            Err(LuminalError::UnknownInput("z".to_string()))
+        );
+    }
+}
@@ -1,6 +1,12 @@
+mod amp;
+pub use amp::*;
 mod autograd;
 pub use autograd::*;
+mod data;
+pub use data::*;
 mod loss;
 pub use loss::*;
 mod optimizer;
 pub use optimizer::*;
+mod schedule;
+pub use schedule::*;
@@ -1,5 +1,7 @@
 use luminal::prelude::*;
 
+use crate::LrSchedule;
+
 /// [Stochastic Gradient Descent](https://en.wikipedia.org/wiki/Stochastic_gradient_descent)
 ///
 /// `new_weight = old_weight - (gradient * learning_rate)`
@@ -58,5 +60,176 @@ pub fn sgd_on_graph(
     (new_weights, lr)
 }
 
+/// Scale gradients down so their global L2 norm, taken over every gradient together, is at most `max_norm`.
+/// Gradients already within the limit are left as they are.
+///
+/// Output: (Clipped gradients, Global norm before clipping)
+pub fn clip_grad_norm(
+    graph: &mut Graph,
+    grads: &[(NodeIndex, ShapeTracker)],
+    max_norm: f32,
+) -> (Vec<(NodeIndex, ShapeTracker)>, GraphTensor<()>) {
+    let grads = grads
+        .iter()
+        .map(|(id, shape)| DynGraphTensor::from_id(*id, *shape, graph))
+        .collect::<Vec<_>>();
+    let mut sum_sq = graph.constant(0.);
+    for grad in &grads {
+        let axes = (0..grad.rank()).collect::<Vec<_>>();
+        sum_sq += (*grad * *grad).sum_reduce(&axes).typed::<R0>();
+    }
+    let norm = sum_sq.sqrt();
+    let scale = (max_norm / (norm + 1e-6)).minimum(1.).into_dyn();
+    let clipped = grads
+        .into_iter()
+        .map(|grad| {
+            let clipped = grad * scale;
+            (clipped.id, clipped.shape)
+        })
+        .collect();
+    (clipped, norm)
+}
+
+/// [Stochastic Gradient Descent](https://en.wikipedia.org/wiki/Stochastic_gradient_descent) with a learning rate
+/// schedule and optional gradient clipping.
+///
+/// Configure it, add it to the graph with `build`, then call `step` before each execution to set that step's
+/// learning rate.
+/// ```rust
+/// use luminal::prelude::*;
+/// use luminal_training::{CosineDecay, Sgd, Warmup};
+/// let mut cx = Graph::new();
+/// let weight = cx.tensor::<R1<2>>().set([1., 2.]).keep();
+/// let grad = cx.tensor::<R1<2>>().set([30., 40.]);
+/// let mut sgd = Sgd::new(Warmup::new(10, CosineDecay::new(0.1, 0.01, 1000))).clip_grad_norm(1.);
+/// let new_weights = sgd.build(&mut cx, weight.id, &[(grad.id, grad.shape)]);
+/// sgd.step();
+/// cx.execute();
+/// ```
+pub struct Sgd {
+    schedule: Box<dyn LrSchedule>,
+    max_grad_norm: Option<f32>,
+    /// Learning rate tensor, once built
+    pub lr: Option<GraphTensor<()>>,
+    /// Global norm of the gradients before clipping, once built with clipping on. This is retrieved each execution.
+    pub grad_norm: Option<GraphTensor<()>>,
+    step: usize,
+}
+
+impl Sgd {
+    pub fn new(schedule: impl LrSchedule + 'static) -> Self {
+        Self {
+            schedule: Box::new(schedule),
+            max_grad_norm: None,
+            lr: None,
+            grad_norm: None,
+            step: 0,
+        }
+    }
+
+    /// Clip gradients to a global norm of at most `max_norm` before updating the weights
+    pub fn clip_grad_norm(mut self, max_norm: f32) -> Self {
+        self.max_grad_norm = Some(max_norm);
+        self
+    }
+
+    /// Add the update to the graph, returning the new weights
+    pub fn build(
+        &mut self,
+        graph: &mut Graph,
+        old_weights: impl ToIds,
+        grads: &[(NodeIndex, ShapeTracker)],
+    ) -> Vec<NodeIndex> {
+        let grads = match self.max_grad_norm {
+            Some(max_norm) => {
+                let (clipped, norm) = clip_grad_norm(graph, grads, max_norm);
+                self.grad_norm = Some(norm.retrieve());
+                clipped
+            }
+            None => grads.to_vec(),
+        };
+        let (new_weights, lr) = sgd_on_graph(graph, old_weights, &grads);
+        self.lr = Some(lr.set(self.schedule.lr(self.step)));
+        new_weights
+    }
+
+    /// Number of steps taken so far
+    pub fn steps(&self) -> usize {
+        self.step
+    }
+
+    /// Set the learning rate for the next execution, and move the schedule on a step. The last execution's
+    /// gradient norm is dropped so it gets recomputed.
+    pub fn step(&mut self) {
+        let lr = self
+            .lr
+            .expect("The optimizer needs to be built before stepping");
+        // The learning rate is kept, so its old value needs dropping for the new one to load
+        lr.set(self.schedule.lr(self.step)).drop();
+        if let Some(norm) = self.grad_norm {
+            norm.drop();
+        }
+        self.step += 1;
+    }
+}
+
 // /// Implements the [Adam](https://arxiv.org/abs/1412.6980) algorithm.
 // pub fn adam(grads: &[(NodeIndex, ShapeTracker)]) {}
+
+#[cfg(test)]
+mod tests {
+    use luminal::{prelude::*, tests::assert_close};
+
+    use crate::{Sgd, StepDecay};
+
+    #[test]
+    fn test_clip_grad_norm() {
+        let mut cx = Graph::new();
+        let weights = cx.tensor::<R1<2>>().set([1., 2.]).keep();
+        let bias = cx.tensor::<R0>().set(1.).keep();
+        let grads = [
+            cx.tensor::<R1<2>>().set([0., 3.]).no_shape(),
+            cx.tensor::<R0>().set(4.),
+        ];
+        // The norm is taken over both gradients together
+        let mut sgd = Sgd::new(1.).clip_grad_norm(1.);
+        let new = sgd.build(&mut cx, (weights, bias), &grads.map(|g| (g.id, g.shape)));
+        let new_weights = GraphTensor::<R1<2>>::from_id(new[0], weights.shape, &mut cx);
+        let new_bias = GraphTensor::<R0>::from_id(new[1], bias.shape, &mut cx);
+        cx.execute();
+
+        assert_close(&[sgd.grad_norm.unwrap().item()], &[5.]);
+        assert_close(&new_weights.data(), &[1., 1.4]);
+        assert_close(&[new_bias.item()], &[0.2]);
+
+        // Gradients under the limit aren't touched
+        let mut cx = Graph::new();
+        let weights = cx.tensor::<R1<2>>().set([1., 2.]).keep();
+        let grad = cx.tensor::<R1<2>>().set([0.3, 0.4]);
+        let mut sgd = Sgd::new(1.).clip_grad_norm(1.);
+        let new = sgd.build(&mut cx, weights, &[(grad.id, grad.shape)]);
+        let new_weights = GraphTensor::<R1<2>>::from_id(new[0], weights.shape, &mut cx);
+        cx.execute();
+        assert_close(&new_weights.data(), &[0.7, 1.6]);
+    }
+
+    #[test]
+    fn test_sgd_schedule() {
+        let mut cx = Graph::new();
+        let weights = cx.tensor::<R1<2>>().set([1., 2.]).keep();
+        let grad = cx.tensor::<R1<2>>().set([1., 1.]);
+        let mut sgd = Sgd::new(StepDecay::new(1., 1, 0.5));
+        let new = sgd.build(&mut cx, weights, &[(grad.id, grad.shape)]);
+        let new_weights = GraphTensor::<R1<2>>::from_id(new[0], weights.shape, &mut cx);
+
+        let mut updates = vec![];
+        for _ in 0..3 {
+            sgd.step();
+            cx.execute();
+            updates.push(1. - new_weights.data()[0]);
+            new_weights.drop();
+        }
+        assert_close(&updates, &[1., 0.5, 0.25]);
+        assert_eq!(sgd.steps(), 3);
+    }
+}
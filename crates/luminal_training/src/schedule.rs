@@ -0,0 +1,110 @@
+use std::f32::consts::PI;
+
+/// Learning rate for each optimizer step. Optimizers set their learning rate tensor from this before every step.
+pub trait LrSchedule {
+    fn lr(&self, step: usize) -> f32;
+}
+
+/// A constant learning rate
+impl LrSchedule for f32 {
+    fn lr(&self, _: usize) -> f32 {
+        *self
+    }
+}
+
+/// Decay from `lr` to `min_lr` along half a cosine over `steps`, then hold at `min_lr`
+#[derive(Debug, Clone, Copy)]
+pub struct CosineDecay {
+    pub lr: f32,
+    pub min_lr: f32,
+    pub steps: usize,
+}
+
+impl CosineDecay {
+    pub fn new(lr: f32, min_lr: f32, steps: usize) -> Self {
+        Self { lr, min_lr, steps }
+    }
+}
+
+impl LrSchedule for CosineDecay {
+    fn lr(&self, step: usize) -> f32 {
+        let progress = step.min(self.steps) as f32 / self.steps.max(1) as f32;
+        self.min_lr + 0.5 * (self.lr - self.min_lr) * (1. + (PI * progress).cos())
+    }
+}
+
+/// Multiply the learning rate by `gamma` every `step_size` steps
+#[derive(Debug, Clone, Copy)]
+pub struct StepDecay {
+    pub lr: f32,
+    pub step_size: usize,
+    pub gamma: f32,
+}
+
+impl StepDecay {
+    pub fn new(lr: f32, step_size: usize, gamma: f32) -> Self {
+        Self {
+            lr,
+            step_size,
+            gamma,
+        }
+    }
+}
+
+impl LrSchedule for StepDecay {
+    fn lr(&self, step: usize) -> f32 {
+        self.lr * self.gamma.powi((step / self.step_size.max(1)) as i32)
+    }
+}
+
+/// Ramp the learning rate up linearly over `steps`, then hand over to another schedule, which starts from its own
+/// first step
+#[derive(Debug, Clone, Copy)]
+pub struct Warmup<S> {
+    pub steps: usize,
+    pub schedule: S,
+}
+
+impl<S: LrSchedule> Warmup<S> {
+    pub fn new(steps: usize, schedule: S) -> Self {
+        Self { steps, schedule }
+    }
+}
+
+impl<S: LrSchedule> LrSchedule for Warmup<S> {
+    fn lr(&self, step: usize) -> f32 {
+        if step < self.steps {
+            self.schedule.lr(0) * (step + 1) as f32 / self.steps as f32
+        } else {
+            self.schedule.lr(step - self.steps)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn test_schedules() {
+        let cosine = CosineDecay::new(1., 0.1, 10);
+        assert!(close(cosine.lr(0), 1.));
+        assert!(close(cosine.lr(5), 0.55));
+        assert!(close(cosine.lr(10), 0.1));
+        assert!(close(cosine.lr(20), 0.1));
+
+        let step = StepDecay::new(1., 3, 0.5);
+        let lrs = (0..7).map(|i| step.lr(i)).collect::<Vec<_>>();
+        assert_eq!(lrs, vec![1., 1., 1., 0.5, 0.5, 0.5, 0.25]);
+
+        let warmup = Warmup::new(4, cosine);
+        assert!(close(warmup.lr(0), 0.25));
+        assert!(close(warmup.lr(3), 1.));
+        assert!(close(warmup.lr(9), 0.55));
+        assert!(close(2e-3.lr(100), 2e-3));
+    }
+}
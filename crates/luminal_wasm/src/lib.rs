@@ -0,0 +1,297 @@
+//! JavaScript bindings for building and running graphs in the browser, through wasm-bindgen.
+//!
+//! Build with `wasm-pack build crates/luminal_wasm --target web`. Graphs are either built up from a `GraphBuilder` or
+//! loaded from the bytes of `Graph::to_bytes`, and are compiled for the CPU into a `Model`. Inputs are bound by name
+//! before each run, and outputs are read back by index:
+//! ```js
+//! const cx = new GraphBuilder();
+//! const x = cx.input("x", [1, 2]);
+//! const w = cx.weight("w", [2, 2], new Float32Array([1, 2, 3, 4]));
+//! const model = cx.build([x.matmul(w).relu()]);
+//! model.bind("x", new Float32Array([1, 1]), [1, 2]);
+//! model.run();
+//! model.output(0); // Float32Array [4, 6]
+//! ```
+
+use luminal::prelude::*;
+use luminal_cpu::CPUCompiler;
+use wasm_bindgen::prelude::*;
+
+/// A graph being built up, which turns into a `Model` once its outputs are known
+#[wasm_bindgen]
+pub struct GraphBuilder {
+    graph: Box<Graph>,
+    inputs: Vec<DynGraphTensor>,
+}
+
+/// A tensor in a graph being built
+#[wasm_bindgen]
+#[derive(Clone, Copy)]
+pub struct Tensor(DynGraphTensor);
+
+/// A compiled graph, with the inputs bound for its next run and the outputs of its last one
+#[wasm_bindgen]
+pub struct Model {
+    model: CompiledGraph,
+    inputs: Vec<(String, Vec<f32>, Vec<usize>)>,
+    outputs: Vec<Vec<f32>>,
+}
+
+fn untyped(tensors: &[DynGraphTensor]) -> Vec<GraphTensor<()>> {
+    tensors
+        .iter()
+        .map(|t| GraphTensor::from_id(t.id, t.shape, t.graph_ref))
+        .collect()
+}
+
+impl Model {
+    fn new(model: CompiledGraph) -> Self {
+        Self {
+            model,
+            inputs: vec![],
+            outputs: vec![],
+        }
+    }
+}
+
+impl Default for GraphBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl GraphBuilder {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self {
+            graph: Box::new(Graph::new()),
+            inputs: vec![],
+        }
+    }
+
+    /// Declare an input, bound on each run of the model
+    pub fn input(&mut self, name: &str, shape: &[usize]) -> Tensor {
+        let t = self.graph.input::<()>(name);
+        let shape = ShapeTracker::new(&shape.iter().map(|d| (*d).into()).collect::<Vec<_>>());
+        let t = DynGraphTensor::from_id(t.id, shape, &mut *self.graph);
+        self.graph.inputs.insert(name.to_string(), (t.id, t.shape));
+        self.inputs.push(t);
+        Tensor(t)
+    }
+
+    /// A tensor with fixed data, computed once when the model is built
+    pub fn weight(&mut self, name: &str, shape: &[usize], data: Vec<f32>) -> Tensor {
+        Tensor(self.graph.dyn_tensor(name, shape).set(data))
+    }
+
+    /// Compile the graph for the CPU. Inputs are taken in the order they were declared.
+    pub fn build(self, outputs: Vec<Tensor>) -> Model {
+        let outputs = outputs.into_iter().map(|t| t.0).collect::<Vec<_>>();
+        Model::new(self.graph.freeze(
+            (GenericCompiler::default(), CPUCompiler),
+            &untyped(&self.inputs),
+            &untyped(&outputs),
+        ))
+    }
+
+    /// Save the graph with these outputs, to load later with `Model.fromBytes`
+    #[wasm_bindgen(js_name = toBytes)]
+    pub fn to_bytes(&mut self, outputs: Vec<Tensor>) -> Result<Vec<u8>, String> {
+        let outputs = outputs
+            .iter()
+            .map(|t| (t.0.id, t.0.shape))
+            .collect::<Vec<_>>();
+        self.graph.to_bytes(&outputs).map_err(|e| e.to_string())
+    }
+}
+
+macro_rules! unary_ops {
+    ($($name:ident),*) => {
+        #[wasm_bindgen]
+        impl Tensor {
+            $(
+                pub fn $name(&self) -> Tensor {
+                    Tensor(self.0.$name())
+                }
+            )*
+        }
+    };
+}
+
+unary_ops!(exp, ln, sqrt, recip, sin, cos, abs, relu, gelu, sigmoid, swish, tanh);
+
+#[wasm_bindgen]
+impl Tensor {
+    /// Sizes of each dimension
+    pub fn shape(&self) -> Vec<usize> {
+        self.0.shape.shape_usize()
+    }
+
+    pub fn add(&self, rhs: &Tensor) -> Tensor {
+        Tensor(self.0 + rhs.0)
+    }
+
+    pub fn sub(&self, rhs: &Tensor) -> Tensor {
+        Tensor(self.0 - rhs.0)
+    }
+
+    pub fn mul(&self, rhs: &Tensor) -> Tensor {
+        Tensor(self.0 * rhs.0)
+    }
+
+    pub fn div(&self, rhs: &Tensor) -> Tensor {
+        Tensor(self.0 / rhs.0)
+    }
+
+    pub fn scale(&self, factor: f32) -> Tensor {
+        Tensor(self.0 * factor)
+    }
+
+    pub fn maximum(&self, rhs: &Tensor) -> Tensor {
+        Tensor(self.0.maximum(rhs.0))
+    }
+
+    pub fn matmul(&self, rhs: &Tensor) -> Tensor {
+        Tensor(self.0.matmul(rhs.0))
+    }
+
+    pub fn sum(&self, axes: &[usize]) -> Tensor {
+        Tensor(self.0.sum_reduce(axes))
+    }
+
+    pub fn max(&self, axes: &[usize]) -> Tensor {
+        Tensor(self.0.max_reduce(axes))
+    }
+
+    pub fn mean(&self, axes: &[usize]) -> Tensor {
+        Tensor(self.0.mean_reduce(axes))
+    }
+
+    pub fn softmax(&self, axis: usize) -> Tensor {
+        Tensor(self.0.softmax(axis))
+    }
+
+    #[wasm_bindgen(js_name = layerNorm)]
+    pub fn layer_norm(&self, axis: usize, epsilon: f32) -> Tensor {
+        Tensor(self.0.layer_norm(axis, epsilon))
+    }
+
+    pub fn reshape(&self, shape: &[usize]) -> Tensor {
+        Tensor(self.0.reshape(shape))
+    }
+
+    pub fn permute(&self, axes: &[usize]) -> Tensor {
+        Tensor(self.0.permute(axes))
+    }
+
+    pub fn concat(&self, rhs: &Tensor, axis: usize) -> Tensor {
+        Tensor(self.0.concat_along(rhs.0, axis))
+    }
+}
+
+#[wasm_bindgen]
+impl Model {
+    /// Load a graph saved with `Graph::to_bytes` or `GraphBuilder.toBytes`, and compile it for the CPU
+    #[wasm_bindgen(js_name = fromBytes)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Model, String> {
+        let mut cx = Graph::new();
+        let outputs = cx.load_bytes(bytes).map_err(|e| e.to_string())?;
+        // Inputs go in the order they were saved in
+        let mut inputs = cx.inputs.values().copied().collect::<Vec<_>>();
+        inputs.sort_by_key(|(id, _)| *id);
+        let inputs = inputs
+            .into_iter()
+            .map(|(id, shape)| GraphTensor::from_id(id, shape, &mut cx))
+            .collect::<Vec<_>>();
+        let outputs = outputs
+            .into_iter()
+            .map(|(id, shape)| GraphTensor::from_id(id, shape, &mut cx))
+            .collect::<Vec<_>>();
+        Ok(Model::new(cx.freeze(
+            (GenericCompiler::default(), CPUCompiler),
+            &inputs,
+            &outputs,
+        )))
+    }
+
+    #[wasm_bindgen(js_name = inputNames)]
+    pub fn input_names(&self) -> Vec<String> {
+        self.model.input_names().map(|n| n.to_string()).collect()
+    }
+
+    #[wasm_bindgen(js_name = numOutputs)]
+    pub fn num_outputs(&self) -> usize {
+        self.model.num_outputs()
+    }
+
+    /// Set an input for the next run, replacing whatever it was bound to before
+    pub fn bind(&mut self, name: &str, data: Vec<f32>, shape: Vec<usize>) {
+        self.inputs.retain(|(n, _, _)| n != name);
+        self.inputs.push((name.to_string(), data, shape));
+    }
+
+    /// Run on the bound inputs. Throws if an input is missing or doesn't fit.
+    pub fn run(&mut self) -> Result<(), String> {
+        self.outputs = self
+            .inputs
+            .iter()
+            .fold(self.model.context(), |cx, (name, data, shape)| {
+                cx.with_input(name, data.clone(), shape)
+            })
+            .run()
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// An output of the last run, or undefined if there's no output at `index`
+    pub fn output(&self, index: usize) -> Option<Vec<f32>> {
+        self.outputs.get(index).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use luminal::tests::{assert_close, random_vec};
+
+    use super::*;
+
+    #[test]
+    fn test_build_and_run() {
+        let (x, w) = (random_vec(6), random_vec(12));
+        let mut cx = Graph::new();
+        let reference = cx
+            .tensor::<R2<2, 3>>()
+            .set(x.clone())
+            .matmul(cx.tensor::<R2<3, 4>>().set(w.clone()))
+            .relu()
+            .softmax::<Axis<1>>()
+            .retrieve();
+        cx.execute();
+
+        let mut builder = GraphBuilder::new();
+        let input = builder.input("x", &[2, 3]);
+        let weight = builder.weight("w", &[3, 4], w);
+        let out = input.matmul(&weight).relu().softmax(1);
+        assert_eq!(out.shape(), vec![2, 4]);
+        let bytes = builder.to_bytes(vec![out]).unwrap();
+        let mut model = builder.build(vec![out]);
+        assert_eq!(model.input_names(), vec!["x"]);
+        assert_eq!(
+            model.run(),
+            Err("Input x must be bound before running".to_string())
+        );
+        model.bind("x", x.clone(), vec![2, 3]);
+        model.run().unwrap();
+        assert_close(&model.output(0).unwrap(), &reference.data());
+        assert_eq!(model.output(1), None);
+
+        // The same graph, saved and loaded back
+        let mut model = Model::from_bytes(&bytes).unwrap();
+        assert_eq!(model.num_outputs(), 1);
+        model.bind("x", x, vec![2, 3]);
+        model.run().unwrap();
+        assert_close(&model.output(0).unwrap(), &reference.data());
+        assert!(Model::from_bytes(&[1, 2, 3]).is_err());
+    }
+}
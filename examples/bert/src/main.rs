@@ -0,0 +1,92 @@
+use std::time::Instant;
+
+use clap::Parser;
+use luminal::{disk_tensor::load_safetensors, prelude::*};
+use tokenizers::{PaddingParams, Tokenizer};
+
+mod model;
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Sentences to embed
+    #[clap(
+        short = 's',
+        long = "sentence",
+        default_values_t = [
+            "The cat sits outside".to_string(),
+            "A man is playing guitar".to_string(),
+            "The new movie is awesome".to_string(),
+            "A kitten is sitting in the garden".to_string(),
+        ]
+    )]
+    sentences: Vec<String>,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+    let mut tokenizer = Tokenizer::from_file("setup/tokenizer.json").unwrap();
+    // Pad every sentence to the longest one, so they can run as a batch
+    tokenizer.with_padding(Some(PaddingParams::default()));
+    let encodings = tokenizer
+        .encode_batch(cli_args.sentences.clone(), true)
+        .unwrap();
+    let (batch, seq) = (encodings.len(), encodings[0].get_ids().len());
+    let flatten = |f: fn(&tokenizers::Encoding) -> &[u32]| {
+        encodings
+            .iter()
+            .flat_map(|e| f(e).iter().map(|i| *i as f32))
+            .collect::<Vec<_>>()
+    };
+
+    // Set up graph
+    let now = Instant::now();
+    let mut cx = Graph::new();
+    let mut input_ids = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Input Ids");
+    let mut token_types = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Token Types");
+    let mut mask = cx.named_tensor::<(Dyn<'b'>, Dyn<'s'>)>("Attention Mask");
+    let model = model::Bert::initialize(&mut cx);
+    load_safetensors("setup/model.safetensors", &model, &mut cx);
+    let mut embeddings = model.forward((input_ids, token_types, mask)).retrieve();
+    println!("Defined graph in {}ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            #[cfg(feature = "metal")]
+            luminal_metal::MetalCompiler::<f32>::default(),
+            #[cfg(feature = "cuda")]
+            luminal_cuda::CudaCompiler::<f32>::default(),
+            #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
+            luminal_cpu::CPUCompiler,
+        ),
+        (&mut input_ids, &mut token_types, &mut mask, &mut embeddings),
+    );
+    println!("Compiled graph in {}ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    input_ids.set_dyn(flatten(|e| e.get_ids()), &[batch, seq]);
+    token_types.set_dyn(flatten(|e| e.get_type_ids()), &[batch, seq]);
+    mask.set_dyn(flatten(|e| e.get_attention_mask()), &[batch, seq]);
+    cx.execute();
+    println!(
+        "Embedded {batch} sentences in {}ms",
+        now.elapsed().as_millis()
+    );
+
+    // Embeddings are normalized, so their dot products are cosine similarities
+    let embeddings = embeddings.data();
+    let embeddings = embeddings.chunks(model::HIDDEN_DIM).collect::<Vec<_>>();
+    for (i, a) in cli_args.sentences.iter().enumerate() {
+        for (j, b) in cli_args.sentences.iter().enumerate().skip(i + 1) {
+            let similarity = embeddings[i]
+                .iter()
+                .zip(embeddings[j])
+                .map(|(x, y)| x * y)
+                .sum::<f32>();
+            println!("{similarity:.3}\t{a:?} / {b:?}");
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use luminal::prelude::*;
+use luminal_nn::{Embedding, EncoderEmbedding, FullyConnected, LayerNorm};
+
+// all-MiniLM-L6-v2 Config
+pub const VOCAB_SIZE: usize = 30522;
+pub const MAX_SEQ: usize = 512;
+pub const TOKEN_TYPES: usize = 2;
+pub const HIDDEN_DIM: usize = 384;
+pub const NUM_LAYERS: usize = 6;
+pub const N_HEADS: usize = 12;
+pub const MLP_DIM: usize = 1536;
+pub const LAYER_NORM_EPS: f32 = 1e-12;
+
+pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
+
+/// A post-norm encoder layer, with the biased projections and exact GELU of BERT
+pub struct EncoderLayer {
+    pub query: FullyConnected<HIDDEN_DIM, HIDDEN_DIM>,
+    pub key: FullyConnected<HIDDEN_DIM, HIDDEN_DIM>,
+    pub value: FullyConnected<HIDDEN_DIM, HIDDEN_DIM>,
+    pub attention_out: FullyConnected<HIDDEN_DIM, HIDDEN_DIM>,
+    pub attention_norm: LayerNorm<HIDDEN_DIM>,
+    pub intermediate: FullyConnected<HIDDEN_DIM, MLP_DIM>,
+    pub output: FullyConnected<MLP_DIM, HIDDEN_DIM>,
+    pub output_norm: LayerNorm<HIDDEN_DIM>,
+}
+
+impl<Batch: Dimension, Seq: Dimension>
+    Module<(
+        GraphTensor<(Batch, Seq, Const<HIDDEN_DIM>)>,
+        GraphTensor<(Batch, Seq)>,
+    )> for EncoderLayer
+{
+    type Output = GraphTensor<(Batch, Seq, Const<HIDDEN_DIM>)>;
+
+    fn forward(
+        &self,
+        (x, mask): (
+            GraphTensor<(Batch, Seq, Const<HIDDEN_DIM>)>,
+            GraphTensor<(Batch, Seq)>,
+        ),
+    ) -> Self::Output {
+        let queries = self
+            .query
+            .forward(x)
+            .reshape::<(Batch, Seq, Const<N_HEADS>, Const<HEAD_DIM>)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+        let keys = self
+            .key
+            .forward(x)
+            .reshape::<(Batch, Seq, Const<N_HEADS>, Const<HEAD_DIM>)>()
+            .permute::<_, Axes4<0, 2, 3, 1>>();
+        let values = self
+            .value
+            .forward(x)
+            .reshape::<(Batch, Seq, Const<N_HEADS>, Const<HEAD_DIM>)>()
+            .permute::<_, Axes4<0, 2, 1, 3>>();
+
+        // Padding tokens don't get attended to
+        let attention_weights = (queries.matmul(keys) / (HEAD_DIM as f32).sqrt()
+            + mask.attention_bias().expand::<_, Axes2<1, 2>>())
+        .softmax::<Axis<3>>();
+        let attended = attention_weights
+            .matmul(values)
+            .permute::<_, Axes4<0, 2, 1, 3>>()
+            .reshape::<(Batch, Seq, Const<HIDDEN_DIM>)>();
+        let x = self
+            .attention_norm
+            .forward(x + self.attention_out.forward(attended));
+
+        // Feed forward
+        let y = self
+            .output
+            .forward(self.intermediate.forward(x).gelu_exact());
+        self.output_norm.forward(x + y)
+    }
+}
+
+impl InitModule for EncoderLayer {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            query: InitModule::initialize(cx),
+            key: InitModule::initialize(cx),
+            value: InitModule::initialize(cx),
+            attention_out: InitModule::initialize(cx),
+            attention_norm: LayerNorm::new(true, true, true, LAYER_NORM_EPS, cx),
+            intermediate: InitModule::initialize(cx),
+            output: InitModule::initialize(cx),
+            output_norm: LayerNorm::new(true, true, true, LAYER_NORM_EPS, cx),
+        }
+    }
+}
+
+impl SerializeModule for EncoderLayer {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("attention/self/query", &self.query);
+        s.module("attention/self/key", &self.key);
+        s.module("attention/self/value", &self.value);
+        s.module("attention/output/dense", &self.attention_out);
+        s.module("attention/output/LayerNorm", &self.attention_norm);
+        s.module("intermediate/dense", &self.intermediate);
+        s.module("output/dense", &self.output);
+        s.module("output/LayerNorm", &self.output_norm);
+    }
+}
+
+/// A BERT encoder producing sentence embeddings, by mean pooling the final hidden states of the real tokens
+pub struct Bert {
+    pub embeddings: EncoderEmbedding<VOCAB_SIZE, MAX_SEQ, TOKEN_TYPES, HIDDEN_DIM>,
+    pub layers: Vec<EncoderLayer>,
+}
+
+impl<Batch: Dimension, Seq: Dimension>
+    Module<(
+        GraphTensor<(Batch, Seq)>,
+        GraphTensor<(Batch, Seq)>,
+        GraphTensor<(Batch, Seq)>,
+    )> for Bert
+{
+    type Output = GraphTensor<(Batch, Const<HIDDEN_DIM>)>;
+
+    fn forward(
+        &self,
+        (input_ids, token_types, mask): (
+            GraphTensor<(Batch, Seq)>,
+            GraphTensor<(Batch, Seq)>,
+            GraphTensor<(Batch, Seq)>,
+        ),
+    ) -> Self::Output {
+        let mut x = self.embeddings.forward((input_ids, token_types));
+        for layer in &self.layers {
+            x = layer.forward((x, mask));
+        }
+        // Mean pool over the tokens, then normalize so dot products are cosine similarities
+        let pooled = x.masked_mean_reduce::<_, Axis<1>>(mask.expand::<_, Axis<2>>());
+        pooled
+            / pooled
+                .square()
+                .sum_reduce::<_, Axis<1>>()
+                .sqrt()
+                .maximum(1e-12)
+                .expand::<_, Axis<1>>()
+    }
+}
+
+impl InitModule for Bert {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            embeddings: EncoderEmbedding {
+                word: Embedding {
+                    weight: cx.named_tensor("Word Embedding"),
+                },
+                position: Embedding {
+                    weight: cx.named_tensor("Position Embedding"),
+                },
+                token_type: Embedding {
+                    weight: cx.named_tensor("Token Type Embedding"),
+                },
+                norm: LayerNorm::new(true, true, true, LAYER_NORM_EPS, cx),
+            },
+            layers: (0..NUM_LAYERS)
+                .map(|_| InitModule::initialize(cx))
+                .collect(),
+        }
+    }
+}
+
+impl SerializeModule for Bert {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("embeddings", &self.embeddings);
+        for (i, layer) in self.layers.iter().enumerate() {
+            s.module(&format!("encoder/layer/{i}"), layer);
+        }
+    }
+}
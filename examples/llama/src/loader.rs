@@ -1,4 +1,5 @@
 use itertools::Itertools;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Seek};
 use std::path::Path;
@@ -8,7 +9,10 @@ use luminal::{op::Function, prelude::*};
 #[cfg(feature = "cuda")]
 use luminal_cuda::{CudaData, CudaDevice};
 
-use crate::gguf::*;
+use crate::{
+    gguf::*,
+    model::{N_HEADS, N_KV_HEADS},
+};
 
 #[cfg(feature = "metal")]
 use {
@@ -246,3 +250,131 @@ pub fn q8_load<P: AsRef<Path>, M: SerializeModule>(
     }
     q8_weights
 }
+
+/// Hugging Face's name for one of our (GGUF-style) weight names
+fn hf_name(weight_name: &str) -> String {
+    let parts = weight_name.split('/').collect::<Vec<_>>();
+    match parts.as_slice() {
+        ["token_embd", "weight"] => "model.embed_tokens.weight".to_string(),
+        ["output_norm", "weight"] => "model.norm.weight".to_string(),
+        ["output", "weight"] => "lm_head.weight".to_string(),
+        ["blk", layer, name, "weight"] => {
+            let name = match *name {
+                "attn_q" => "self_attn.q_proj",
+                "attn_k" => "self_attn.k_proj",
+                "attn_v" => "self_attn.v_proj",
+                "attn_output" => "self_attn.o_proj",
+                "attn_norm" => "input_layernorm",
+                "ffn_norm" => "post_attention_layernorm",
+                "ffn_gate" => "mlp.gate_proj",
+                "ffn_up" => "mlp.up_proj",
+                "ffn_down" => "mlp.down_proj",
+                _ => panic!("No Hugging Face name for {weight_name}"),
+            };
+            format!("model.layers.{layer}.{name}.weight")
+        }
+        _ => panic!("No Hugging Face name for {weight_name}"),
+    }
+}
+
+/// Hugging Face checkpoints store each head's query and key rows as [first halves, second halves] for their rotate-half
+/// RoPE. Interleave them back into the (even, odd) pairs our RoPE expects.
+fn interleave_rotary_rows(data: Vec<f32>, n_heads: usize, row_len: usize) -> Vec<f32> {
+    let half = data.len() / row_len / n_heads / 2;
+    let mut out = Vec::with_capacity(data.len());
+    for head in 0..n_heads {
+        for i in 0..half {
+            for j in 0..2 {
+                let row = (head * 2 + j) * half + i;
+                out.extend_from_slice(&data[row * row_len..(row + 1) * row_len]);
+            }
+        }
+    }
+    out
+}
+
+/// Load unquantized weights from a Hugging Face Llama / Mistral checkpoint, which can be split over several
+/// safetensors files. Weights get converted to f32 when the loading nodes run.
+pub fn safetensors_load<P: AsRef<Path>, M: SerializeModule>(
+    paths: &[P],
+    model: &M,
+    graph: &mut Graph,
+) {
+    // Read the tensor table of every shard
+    let mut tensor_infos = HashMap::new();
+    for path in paths {
+        let mut file = File::open(path).unwrap();
+        let mut header_len = [0; 8];
+        file.read_exact(&mut header_len).unwrap();
+        let header_len = u64::from_le_bytes(header_len);
+        let mut header = vec![0; header_len as usize];
+        file.read_exact(&mut header).unwrap();
+        let header: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_slice(&header).unwrap();
+        for (name, info) in header {
+            if name != "__metadata__" {
+                tensor_infos.insert(name, (path.as_ref().to_owned(), 8 + header_len, info));
+            }
+        }
+    }
+
+    for (weight_name, node_index) in param_dict(model) {
+        if let Some(loading_node) = graph
+            .graph
+            .node_weight_mut(node_index)
+            .and_then(|op| op.as_any_mut().downcast_mut::<Function>())
+        {
+            let mut name = hf_name(&weight_name);
+            // Smaller models tie the LM head to the embedding
+            if name == "lm_head.weight" && !tensor_infos.contains_key(&name) {
+                name = "model.embed_tokens.weight".to_string();
+            }
+            let (file_path, data_offset, info) = tensor_infos
+                .get(&name)
+                .unwrap_or_else(|| panic!("{name} not found in checkpoint"))
+                .clone();
+            let dtype = info["dtype"].as_str().unwrap().to_string();
+            let start = info["data_offsets"][0].as_u64().unwrap();
+            let end = info["data_offsets"][1].as_u64().unwrap();
+            let row_len = info["shape"]
+                .as_array()
+                .unwrap()
+                .last()
+                .and_then(|d| d.as_u64())
+                .unwrap_or(1) as usize;
+            let rotary_heads = if name.ends_with("q_proj.weight") {
+                Some(N_HEADS)
+            } else if name.ends_with("k_proj.weight") {
+                Some(N_KV_HEADS)
+            } else {
+                None
+            };
+            loading_node.1 = Box::new(move |_| {
+                let mut bytes = vec![0; (end - start) as usize];
+                let mut file = File::open(&file_path).unwrap();
+                file.seek(std::io::SeekFrom::Start(data_offset + start))
+                    .unwrap();
+                file.read_exact(&mut bytes).unwrap();
+                let data: Vec<f32> = match dtype.as_str() {
+                    "F32" => bytes
+                        .chunks_exact(4)
+                        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                        .collect(),
+                    "F16" => bytes
+                        .chunks_exact(2)
+                        .map(|c| f16::from_le_bytes([c[0], c[1]]).to_f32())
+                        .collect(),
+                    "BF16" => bytes
+                        .chunks_exact(2)
+                        .map(|c| bf16::from_le_bytes([c[0], c[1]]).to_f32())
+                        .collect(),
+                    _ => panic!("Unsupported dtype: {dtype}"),
+                };
+                vec![Tensor::new(match rotary_heads {
+                    Some(n_heads) => interleave_rotary_rows(data, n_heads, row_len),
+                    None => data,
+                })]
+            });
+        }
+    }
+}
@@ -1,20 +1,20 @@
 use std::{
+    fs,
     io::{self, Write},
     marker::PhantomData,
+    path::PathBuf,
     time::Instant,
 };
 
 use clap::Parser;
 use colored::Colorize;
-use itertools::Itertools;
-use tokenizers::Tokenizer;
 
 mod gguf;
 mod loader;
 mod model;
 
 use crate::model::KVCache;
-use luminal::prelude::*;
+use luminal::prelude::{tokenizers::Tokenizer, *};
 
 // Command args parser
 #[derive(Debug, Parser)]
@@ -22,11 +22,15 @@ use luminal::prelude::*;
 pub struct CLIArgs {
     /// Number of tokens to generate
     #[clap(short = 't', long = "gen_tokens", default_value = "128")]
-    gen_tokens: i32,
+    gen_tokens: usize,
 
     /// Prompt for the model
     #[clap(short = 'p', long = "prompt", default_value = include_str!("../prompts/merge_sort.txt"))]
     prompt: String,
+
+    /// Weights to load: a Q8 GGUF file, or a Hugging Face checkpoint directory of safetensors files
+    #[clap(short = 'w', long = "weights", default_value = "setup/llama3-8b.gguf")]
+    weights: PathBuf,
 }
 
 fn main() {
@@ -43,7 +47,10 @@ fn main() {
     let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..model::NUM_LAYERS)
         .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
         .collect();
-    cache_src.set_dyn(vec![], &[1, model::N_KV_HEADS, 0, model::HEAD_DIM]);
+    cache_src.set_dyn(
+        Vec::<f32>::new(),
+        &[1, model::N_KV_HEADS, 0, model::HEAD_DIM],
+    );
     let model = model::Llama::initialize(&mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
@@ -59,10 +66,19 @@ fn main() {
     let now = Instant::now();
 
     // Set up model loading
-    #[cfg(any(feature = "metal", feature = "cuda"))]
-    let q_weights = loader::q8_load("setup/llama3-8b.gguf", &model, &mut cx);
-    #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-    loader::q8_load("setup/llama3-8b.gguf", &model, &mut cx);
+    #[allow(unused_variables)]
+    let q_weights = if cli_args.weights.is_dir() {
+        let mut shards = fs::read_dir(&cli_args.weights)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "safetensors"))
+            .collect::<Vec<_>>();
+        shards.sort();
+        loader::safetensors_load(&shards, &model, &mut cx);
+        vec![]
+    } else {
+        loader::q8_load(&cli_args.weights, &model, &mut cx)
+    };
 
     cx.compile(
         (
@@ -76,7 +92,7 @@ fn main() {
             #[cfg(feature = "cuda")]
             luminal_cuda::CudaQuantizedCompiler::<f16>::new(q_weights),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-            luminal_cpu::CPUCompiler::default(),
+            luminal_cpu::CPUCompiler,
         ),
         (
             &mut input,
@@ -102,85 +118,45 @@ fn main() {
 
     // Now that weights are loaded, delete the loading nodes so they don't run again
     delete_inputs(&cache_src, &mut cx);
-    delete_inputs(&downstream(model_weights, &cx), &mut cx);
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
 
     // Run prompt processing pass
-    let mut input_ids = tokenizer
-        .encode(&cli_args.prompt as &str, false)
-        .unwrap()
-        .get_ids()
-        .to_vec();
-    input_ids.insert(0, 1);
-    input.set_dyn(
-        input_ids.iter().map(|i| *i as f32).collect::<Vec<_>>(),
-        &[1, input_ids.len()],
-    );
-    cx.set_dyn_dim('t', input_ids.len());
+    let mut generator = generate_text(input, logits, &tokenizer, &cli_args.prompt)
+        .kv_cache(&cache_src, &cache_dest)
+        .past_dim('p')
+        .total_dim('t')
+        .max_new_tokens(cli_args.gen_tokens);
+    let n_prompt_tokens = generator.tokens().len();
     print!("Processing Prompt");
     io::stdout().flush().unwrap();
     let now = Instant::now();
-    cx.execute();
+    let first_text = generator.next_text().unwrap_or_default();
     let elapsed_ms = now.elapsed().as_millis();
     println!(
         "\t - {elapsed_ms}ms ({:.2} tok/s, {} prompt tokens)",
-        1000.0 * (input_ids.len() as f64) / (elapsed_ms as f64),
-        input_ids.len()
+        1000.0 * (n_prompt_tokens as f64) / (elapsed_ms as f64),
+        n_prompt_tokens
     );
-    let mut output_ids = vec![sample_index(&logits.data())];
-    logits.drop();
 
     // Decode token
     print!("{}", cli_args.prompt.white().bold());
-    print!(
-        "{}",
-        tokenizer.decode(&output_ids, false).unwrap().bright_green()
-    );
+    print!("{}", first_text.bright_green());
     io::stdout().flush().unwrap();
 
-    // Swap caches
-    transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
-
-    // Decode loop
+    // Decode loop, until we hit an end of sequence token or the token limit
     let start_decode = std::time::Instant::now();
-    let mut prev_output_len = 0;
-    for _ in 0..cli_args.gen_tokens {
-        input.set_dyn(vec![*output_ids.last().unwrap() as f32], &[1, 1]);
-        cx.set_dyn_dim('p', input_ids.len() + output_ids.len() - 1);
-        cx.set_dyn_dim('t', input_ids.len() + output_ids.len());
-        cx.execute();
-
-        // Sample tokens
-        let output_id = sample_index(&logits.data());
-        logits.drop();
-        output_ids.push(output_id);
-
-        // Get the current decoded output
-        let current_output = tokenizer.decode(&output_ids, false).unwrap();
-
-        // Print the new substring added to the decoded output
-        print!("{}", current_output[prev_output_len..].bright_green());
+    while let Some(text) = generator.next_text() {
+        print!("{}", text.bright_green());
         io::stdout().flush().unwrap();
-
-        // Update the previous output
-        prev_output_len = current_output.len();
-
-        // Swap caches
-        transfer_data_same_graph(&cache_dest, &cache_src, &mut cx);
     }
 
     println!();
-    let avg_token_time =
-        start_decode.elapsed().as_micros() as f32 / (output_ids.len() - 1) as f32 / 1000.0;
+    let avg_token_time = start_decode.elapsed().as_micros() as f32
+        / (generator.generated().len().max(2) - 1) as f32
+        / 1000.0;
     println!(
         "\nAverage token generated in {:.2}ms\t - ({:.2} tok/s)",
         avg_token_time,
         1000.0 / avg_token_time
     );
 }
-
-// Currently just an argmax, do actual sampling here
-fn sample_index(dist: &[f32]) -> u32 {
-    dist.iter()
-        .position_max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
-        .unwrap() as u32
-}
@@ -10,6 +10,7 @@ pub const NUM_LAYERS: usize = 32;
 pub const N_HEADS: usize = 32;
 pub const N_KV_HEADS: usize = 8;
 pub const MLP_DIM: usize = 14336;
+pub const ROPE_THETA: f32 = 500_000.;
 
 pub const N_ATTENTION_GROUPS: usize = N_HEADS / N_KV_HEADS;
 pub const HEAD_DIM: usize = HIDDEN_DIM / N_HEADS;
@@ -69,7 +70,7 @@ fn apply_rotary_embeddings_ggml<const N_HEADS: usize, Batch: Dimension, Seq: Dim
 ) -> GraphTensor<(Batch, Const<N_HEADS>, Seq, Const<HEAD_DIM>)> {
     // Get freqs
     let freqs = (input.graph().arange::<Const<HEAD_DIM_OVER_2>>() * 2.0) / (HEAD_DIM as f32);
-    let freqs = 500_000_f32.pow(freqs);
+    let freqs = ROPE_THETA.pow(freqs);
     let pos = input.graph().arange::<Seq>() + prev_seq;
     let emb = pos.expand::<(_, Const<1>), _>().matmul(freqs.expand());
 
@@ -93,10 +94,10 @@ fn apply_rotary_embeddings_ggml<const N_HEADS: usize, Batch: Dimension, Seq: Dim
 }
 
 pub struct SelfAttention {
-    pub q_proj: GraphTensor<R2<HIDDEN_DIM, HIDDEN_DIM>>,
-    pub k_proj: GraphTensor<R2<ATTN_PROJ_DIM, HIDDEN_DIM>>,
-    pub v_proj: GraphTensor<R2<ATTN_PROJ_DIM, HIDDEN_DIM>>,
-    pub o_proj: GraphTensor<R2<HIDDEN_DIM, HIDDEN_DIM>>,
+    pub q_proj: PermutedLinear<HIDDEN_DIM, HIDDEN_DIM>,
+    pub k_proj: PermutedLinear<HIDDEN_DIM, ATTN_PROJ_DIM>,
+    pub v_proj: PermutedLinear<HIDDEN_DIM, ATTN_PROJ_DIM>,
+    pub o_proj: PermutedLinear<HIDDEN_DIM, HIDDEN_DIM>,
 }
 
 impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
@@ -119,18 +120,21 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
         ),
     ) -> Self::Output {
         // Apply the Projections
-        let queries = x
-            .matmul(self.q_proj.permute())
+        let queries = self
+            .q_proj
+            .forward(x)
             .reshape::<(Batch, CurSeq, Const<N_HEADS>, Const<HEAD_DIM>)>()
             .permute::<_, Axes4<0, 2, 1, 3>>();
 
-        let keys = x
-            .matmul(self.k_proj.permute())
+        let keys = self
+            .k_proj
+            .forward(x)
             .reshape::<(Batch, CurSeq, Const<N_KV_HEADS>, Const<HEAD_DIM>)>()
             .permute::<_, Axes4<0, 2, 1, 3>>();
 
-        let values = x
-            .matmul(self.v_proj.permute())
+        let values = self
+            .v_proj
+            .forward(x)
             .reshape::<(Batch, CurSeq, Const<N_KV_HEADS>, Const<HEAD_DIM>)>()
             .permute::<_, Axes4<0, 2, 1, 3>>();
 
@@ -152,7 +156,7 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
             .matmul(repeated_keys.permute())
             .div((HEAD_DIM as f32).sqrt());
 
-        let attention_mask = self.k_proj.graph().triu::<CurSeq>(1) * f16::MIN.to_f32();
+        let attention_mask = self.k_proj.weight.graph().triu::<CurSeq>(1) * f16::MIN.to_f32();
         attention_weights += attention_mask
             .pad::<(CurSeq, TotSeq)>(((0, 0), (TotSeq::size() - CurSeq::size(), 0)))
             .expand();
@@ -165,9 +169,8 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
             // Merge heads
             .permute::<_, Axes5<0, 3, 1, 2, 4>>()
             .reshape::<(Batch, CurSeq, Const<HIDDEN_DIM>)>();
-        let output = output
-            // Apply output projection
-            .matmul(self.o_proj.permute());
+        // Apply output projection
+        let output = self.o_proj.forward(output);
         (output, (keys.contiguous(), values.contiguous())) // Cache needs to be contiguous for transferring to another graph
     }
 }
@@ -175,20 +178,28 @@ impl<Batch: Dimension, CurSeq: Dimension, PrevSeq: Dimension, TotSeq: Dimension>
 impl InitModule for SelfAttention {
     fn initialize(cx: &mut Graph) -> Self {
         Self {
-            q_proj: cx.named_tensor("Q Proj"),
-            k_proj: cx.named_tensor("K Proj"),
-            v_proj: cx.named_tensor("V Proj"),
-            o_proj: cx.named_tensor("O Proj"),
+            q_proj: PermutedLinear {
+                weight: cx.named_tensor("Q Proj"),
+            },
+            k_proj: PermutedLinear {
+                weight: cx.named_tensor("K Proj"),
+            },
+            v_proj: PermutedLinear {
+                weight: cx.named_tensor("V Proj"),
+            },
+            o_proj: PermutedLinear {
+                weight: cx.named_tensor("O Proj"),
+            },
         }
     }
 }
 
 impl SerializeModule for SelfAttention {
     fn serialize(&self, s: &mut Serializer) {
-        s.tensor("attn_q/weight", self.q_proj);
-        s.tensor("attn_v/weight", self.v_proj);
-        s.tensor("attn_k/weight", self.k_proj);
-        s.tensor("attn_output/weight", self.o_proj);
+        s.module("attn_q", &self.q_proj);
+        s.module("attn_v", &self.v_proj);
+        s.module("attn_k", &self.k_proj);
+        s.module("attn_output", &self.o_proj);
     }
 }
 
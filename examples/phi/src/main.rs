@@ -43,7 +43,7 @@ fn main() {
     let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..model::NUM_LAYERS)
         .map(|_| (cx.named_tensor("Key Cache"), cx.named_tensor("Value Cache")))
         .collect();
-    cache_src.set_dyn(vec![], &[1, model::N_HEADS, 0, model::HEAD_DIM]);
+    cache_src.set_dyn(Vec::<f32>::new(), &[1, model::N_HEADS, 0, model::HEAD_DIM]);
     let model = model::Phi::initialize(&mut cx);
     let mut model_weights = params(&model);
     cx.keep_tensors(&model_weights);
@@ -75,7 +75,7 @@ fn main() {
             #[cfg(feature = "cuda")]
             luminal_cuda::CudaQuantizedCompiler::<f32>::new(q_weights),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-            luminal_cpu::CPUCompiler::default(),
+            luminal_cpu::CPUCompiler,
         ),
         (
             &mut input,
@@ -101,7 +101,7 @@ fn main() {
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     // Now that weights are loaded, delete the loading nodes so they don't run again
-    delete_inputs(&downstream(model_weights, &cx), &mut cx);
+    delete_inputs(downstream(model_weights, &cx), &mut cx);
 
     // Run prompt processing pass
     let mut input_ids = tokenizer
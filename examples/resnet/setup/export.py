@@ -0,0 +1,27 @@
+# Exports torchvision's ResNet-18 to safetensors, and optionally preprocesses an image into raw f32s for the example.
+# Usage: python3 export.py [image]
+# Needs torch, torchvision and safetensors (and pillow to preprocess an image).
+import os
+import sys
+
+import torch
+import torchvision
+from safetensors.torch import save_file
+
+DIR = os.path.dirname(os.path.abspath(__file__))
+
+weights = torchvision.models.ResNet18_Weights.DEFAULT
+model = torchvision.models.resnet18(weights=weights)
+# The batch norm step counters aren't needed for inference
+state = {
+    k: v.float().contiguous()
+    for k, v in model.state_dict().items()
+    if not k.endswith("num_batches_tracked")
+}
+save_file(state, os.path.join(DIR, "resnet18.safetensors"))
+
+if len(sys.argv) > 1:
+    from PIL import Image
+
+    image = weights.transforms()(Image.open(sys.argv[1]).convert("RGB"))
+    image.numpy().astype("<f4").tofile(os.path.join(DIR, "image.bin"))
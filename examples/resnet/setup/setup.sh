@@ -0,0 +1,6 @@
+#!/usr/bin/env bash
+SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
+
+echo "Exporting ResNet-18 weights from torchvision..."
+python3 $SCRIPT_DIR/export.py "$@"
+echo "Done!"
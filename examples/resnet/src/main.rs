@@ -0,0 +1,75 @@
+use std::{fs, time::Instant};
+
+use clap::Parser;
+use luminal::{disk_tensor::load_safetensors, prelude::*};
+
+mod model;
+
+// Command args parser
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct CLIArgs {
+    /// Preprocessed image as raw little-endian f32s, laid out [3, 224, 224] (written by setup/export.py)
+    #[clap(short = 'i', long = "image", default_value = "setup/image.bin")]
+    image: String,
+
+    /// Number of top classes to print
+    #[clap(short = 'k', long = "top_k", default_value = "5")]
+    top_k: usize,
+}
+
+fn main() {
+    let cli_args = CLIArgs::parse();
+    let image = fs::read(&cli_args.image)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {e}", cli_args.image))
+        .chunks_exact(4)
+        .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect::<Vec<_>>();
+
+    // Set up graph
+    let now = Instant::now();
+    let mut cx = Graph::new();
+    let input = cx.named_tensor::<R3<3, { model::IMAGE_SIZE }, { model::IMAGE_SIZE }>>("Image");
+    let model = model::ResNet18::initialize(&mut cx);
+    load_safetensors("setup/resnet18.safetensors", &model, &mut cx);
+    let mut weights = params(&model);
+    cx.keep_tensors(&weights);
+    let mut logits = model.forward(input).retrieve();
+    println!("Defined graph in {}ms", now.elapsed().as_millis());
+
+    // Batch norms fold into the convolutions before them
+    let now = Instant::now();
+    cx.compile(
+        (
+            GenericCompiler::default(),
+            FoldBatchNorm,
+            #[cfg(feature = "metal")]
+            luminal_metal::MetalCompiler::<f32>::default(),
+            #[cfg(feature = "cuda")]
+            luminal_cuda::CudaCompiler::<f32>::default(),
+            #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
+            luminal_cpu::CPUCompiler,
+        ),
+        (&mut logits, &mut weights),
+    );
+    println!("Compiled graph in {}ms", now.elapsed().as_millis());
+
+    let now = Instant::now();
+    input.set(image);
+    cx.execute();
+    println!("Ran in {}ms", now.elapsed().as_millis());
+
+    // Softmax the logits and print the most likely classes
+    let logits = logits.data();
+    let max = logits.iter().copied().fold(f32::MIN, f32::max);
+    let total = logits.iter().map(|l| (l - max).exp()).sum::<f32>();
+    let mut classes = logits
+        .iter()
+        .enumerate()
+        .map(|(i, l)| (i, (l - max).exp() / total))
+        .collect::<Vec<_>>();
+    classes.sort_by(|a, b| b.1.total_cmp(&a.1));
+    for (class, prob) in classes.into_iter().take(cli_args.top_k) {
+        println!("Class {class:>4}: {:.2}%", prob * 100.);
+    }
+}
@@ -0,0 +1,186 @@
+use luminal::prelude::*;
+use luminal_nn::{BatchNorm2D, Conv2D, FullyConnected};
+
+// ResNet-18 hyperparams
+pub const IMAGE_SIZE: usize = 224;
+pub const N_CLASSES: usize = 1000;
+
+/// Two 3x3 convolutions with a residual connection around them. When the block changes the number of channels or the
+/// resolution, the residual goes through a strided 1x1 convolution to match.
+pub struct BasicBlock<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> {
+    pub conv1: Conv2D<CH_IN, CH_OUT, 3, 3, STRIDE, STRIDE>,
+    pub bn1: BatchNorm2D<CH_OUT>,
+    pub conv2: Conv2D<CH_OUT, CH_OUT, 3, 3, 1, 1>,
+    pub bn2: BatchNorm2D<CH_OUT>,
+    pub downsample: Option<(
+        Conv2D<CH_IN, CH_OUT, 1, 1, STRIDE, STRIDE>,
+        BatchNorm2D<CH_OUT>,
+    )>,
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> InitModule
+    for BasicBlock<CH_IN, CH_OUT, STRIDE>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            conv1: InitModule::initialize(cx),
+            bn1: InitModule::initialize(cx),
+            conv2: InitModule::initialize(cx),
+            bn2: InitModule::initialize(cx),
+            downsample: if CH_IN != CH_OUT || STRIDE != 1 {
+                Some((InitModule::initialize(cx), InitModule::initialize(cx)))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> SerializeModule
+    for BasicBlock<CH_IN, CH_OUT, STRIDE>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("conv1", &self.conv1);
+        s.module("bn1", &self.bn1);
+        s.module("conv2", &self.conv2);
+        s.module("bn2", &self.bn2);
+        if let Some((conv, bn)) = &self.downsample {
+            s.module("downsample/0", conv);
+            s.module("downsample/1", bn);
+        }
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize>
+    BasicBlock<CH_IN, CH_OUT, STRIDE>
+{
+    /// Run the block on a `DIM_IN` square image, producing a `DIM_OUT` square one. The `_PAD` sizes are the inputs to each
+    /// convolution after padding by one on every side.
+    pub fn forward<
+        const DIM_IN: usize,
+        const DIM_IN_PAD: usize,
+        const DIM_OUT: usize,
+        const DIM_OUT_PAD: usize,
+    >(
+        &self,
+        input: GraphTensor<R3<CH_IN, DIM_IN, DIM_IN>>,
+    ) -> GraphTensor<R3<CH_OUT, DIM_OUT, DIM_OUT>> {
+        let x = self
+            .conv1
+            .forward::<DIM_IN_PAD, DIM_IN_PAD, DIM_OUT, DIM_OUT>(input.pad((
+                (0, 0),
+                (1, 1),
+                (1, 1),
+            )));
+        let x = self.bn1.forward(x).relu();
+        let x = self
+            .conv2
+            .forward::<DIM_OUT_PAD, DIM_OUT_PAD, DIM_OUT, DIM_OUT>(x.pad(((0, 0), (1, 1), (1, 1))));
+        let x = self.bn2.forward(x);
+        let residual = match &self.downsample {
+            Some((conv, bn)) => bn.forward(conv.forward::<DIM_IN, DIM_IN, DIM_OUT, DIM_OUT>(input)),
+            None => input.reshape(),
+        };
+        (x + residual).relu()
+    }
+}
+
+/// A stage of two blocks, the first of which may downsample
+pub struct Layer<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> {
+    pub first: BasicBlock<CH_IN, CH_OUT, STRIDE>,
+    pub second: BasicBlock<CH_OUT, CH_OUT, 1>,
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> InitModule
+    for Layer<CH_IN, CH_OUT, STRIDE>
+{
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            first: InitModule::initialize(cx),
+            second: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> SerializeModule
+    for Layer<CH_IN, CH_OUT, STRIDE>
+{
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("0", &self.first);
+        s.module("1", &self.second);
+    }
+}
+
+impl<const CH_IN: usize, const CH_OUT: usize, const STRIDE: usize> Layer<CH_IN, CH_OUT, STRIDE> {
+    pub fn forward<
+        const DIM_IN: usize,
+        const DIM_IN_PAD: usize,
+        const DIM_OUT: usize,
+        const DIM_OUT_PAD: usize,
+    >(
+        &self,
+        input: GraphTensor<R3<CH_IN, DIM_IN, DIM_IN>>,
+    ) -> GraphTensor<R3<CH_OUT, DIM_OUT, DIM_OUT>> {
+        let x = self
+            .first
+            .forward::<DIM_IN, DIM_IN_PAD, DIM_OUT, DIM_OUT_PAD>(input);
+        self.second
+            .forward::<DIM_OUT, DIM_OUT_PAD, DIM_OUT, DIM_OUT_PAD>(x)
+    }
+}
+
+/// ResNet-18, laid out and named like torchvision's so its exported weights load directly
+pub struct ResNet18 {
+    pub conv1: Conv2D<3, 64, 7, 7, 2, 2>,
+    pub bn1: BatchNorm2D<64>,
+    pub layer1: Layer<64, 64, 1>,
+    pub layer2: Layer<64, 128, 2>,
+    pub layer3: Layer<128, 256, 2>,
+    pub layer4: Layer<256, 512, 2>,
+    pub fc: FullyConnected<512, N_CLASSES>,
+}
+
+impl InitModule for ResNet18 {
+    fn initialize(cx: &mut Graph) -> Self {
+        Self {
+            conv1: InitModule::initialize(cx),
+            bn1: InitModule::initialize(cx),
+            layer1: InitModule::initialize(cx),
+            layer2: InitModule::initialize(cx),
+            layer3: InitModule::initialize(cx),
+            layer4: InitModule::initialize(cx),
+            fc: InitModule::initialize(cx),
+        }
+    }
+}
+
+impl SerializeModule for ResNet18 {
+    fn serialize(&self, s: &mut Serializer) {
+        s.module("conv1", &self.conv1);
+        s.module("bn1", &self.bn1);
+        s.module("layer1", &self.layer1);
+        s.module("layer2", &self.layer2);
+        s.module("layer3", &self.layer3);
+        s.module("layer4", &self.layer4);
+        s.module("fc", &self.fc);
+    }
+}
+
+impl Module<GraphTensor<R3<3, IMAGE_SIZE, IMAGE_SIZE>>> for ResNet18 {
+    type Output = GraphTensor<R1<N_CLASSES>>;
+
+    fn forward(&self, image: GraphTensor<R3<3, IMAGE_SIZE, IMAGE_SIZE>>) -> Self::Output {
+        // Stem: 7x7 stride 2 convolution padded by 3, then a 3x3 stride 2 max pool padded by 1
+        let x = self
+            .conv1
+            .forward::<230, 230, 112, 112>(image.pad(((0, 0), (3, 3), (3, 3))));
+        let x = self.bn1.forward(x).relu();
+        let x = x.max_pool_2d::<R3<64, 56, 56>>((3, 3), (2, 2), (1, 1));
+        // Each stage after the first halves the resolution
+        let x = self.layer1.forward::<56, 58, 56, 58>(x);
+        let x = self.layer2.forward::<56, 58, 28, 30>(x);
+        let x = self.layer3.forward::<28, 30, 14, 16>(x);
+        let x = self.layer4.forward::<14, 16, 7, 9>(x);
+        self.fc.forward(x.global_avg_pool_2d::<R1<512>>())
+    }
+}
@@ -77,7 +77,7 @@ fn main() {
         transfer_data_same_graph(&new_weights, &weights, &mut cx);
 
         // Report progress
-        loss_avg.update(loss.data()[0]);
+        loss_avg.update(loss.item());
         loss.drop();
         acc_avg.update(
             output
@@ -40,7 +40,7 @@ fn main() {
     let mut cache_src: Vec<KVCache<Const<1>, Dyn<'p'>>> = (0..model::DEC_LAYERS)
         .map(|_| (dec_cx.named_tensor("Keys"), dec_cx.named_tensor("Values")))
         .collect();
-    cache_src.set_dyn(vec![], &[1, 6, 64, 0]);
+    cache_src.set_dyn(Vec::<f32>::new(), &[1, 6, 64, 0]);
     let (logits, _, mut cache_dest) = decoder.forward((
         encoder_output,
         text_input,
@@ -67,7 +67,7 @@ fn main() {
             #[cfg(feature = "cuda")]
             luminal_cuda::CudaCompiler::<f32>::default(),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-            luminal_cpu::CPUCompiler::default(),
+            luminal_cpu::CPUCompiler,
         ),
         (&mut audio_input, &mut encoded, &mut encoder_params),
     );
@@ -82,7 +82,7 @@ fn main() {
             #[cfg(feature = "cuda")]
             luminal_cuda::CudaCompiler::<f32>::default(),
             #[cfg(all(not(feature = "metal"), not(feature = "cuda")))]
-            luminal_cpu::CPUCompiler::default(),
+            luminal_cpu::CPUCompiler,
         ),
         (
             &mut text_input,
@@ -116,7 +116,7 @@ fn main() {
     logits.drop();
     transfer_data_same_graph(&cache_dest, &cache_src, &mut dec_cx);
     delete_inputs(&cache_src, &mut dec_cx);
-    delete_inputs(&downstream(decoder_params, &dec_cx), &mut dec_cx);
+    delete_inputs(downstream(decoder_params, &dec_cx), &mut dec_cx);
     println!("\t\t - {}ms", now.elapsed().as_millis());
 
     // Process audio into mel spectrogram
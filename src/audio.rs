@@ -0,0 +1,261 @@
+use std::f64::consts::PI;
+
+use crate::{
+    op::{self, Function},
+    prelude::*,
+};
+
+/// Periodic Hann window, the one spectrograms are usually taken with
+pub fn hann_window(n: usize) -> Vec<f32> {
+    (0..n)
+        .map(|i| (0.5 - 0.5 * (2. * PI * i as f64 / n as f64).cos()) as f32)
+        .collect()
+}
+
+/// Hz to mels on the Slaney scale, linear below 1 kHz and logarithmic above
+fn hz_to_mel(hz: f64) -> f64 {
+    let log_step = 6.4f64.ln() / 27.;
+    if hz >= 1000. {
+        15. + (hz / 1000.).ln() / log_step
+    } else {
+        3. * hz / 200.
+    }
+}
+
+fn mel_to_hz(mel: f64) -> f64 {
+    let log_step = 6.4f64.ln() / 27.;
+    if mel >= 15. {
+        1000. * (log_step * (mel - 15.)).exp()
+    } else {
+        200. * mel / 3.
+    }
+}
+
+/// Triangular mel filters covering 0 Hz to the Nyquist frequency, as a row-major [n_mels, n_fft / 2 + 1] matrix.
+///
+/// These match librosa's defaults (Slaney mel scale and area normalization), which is what Whisper and most speech
+/// models were trained with.
+pub fn mel_filters(sample_rate: usize, n_fft: usize, n_mels: usize) -> Vec<f32> {
+    let bins = n_fft / 2 + 1;
+    let nyquist = sample_rate as f64 / 2.;
+    let bin_hz = (0..bins)
+        .map(|i| i as f64 * nyquist / (bins - 1).max(1) as f64)
+        .collect::<Vec<_>>();
+    // Edges of the filters, evenly spaced in mels
+    let max_mel = hz_to_mel(nyquist);
+    let edges = (0..n_mels + 2)
+        .map(|i| mel_to_hz(max_mel * i as f64 / (n_mels + 1) as f64))
+        .collect::<Vec<_>>();
+    let mut filters = Vec::with_capacity(n_mels * bins);
+    for m in 0..n_mels {
+        let (low, center, high) = (edges[m], edges[m + 1], edges[m + 2]);
+        let norm = 2. / (high - low);
+        filters.extend(bin_hz.iter().map(|hz| {
+            let rising = (hz - low) / (center - low);
+            let falling = (high - hz) / (high - center);
+            (rising.min(falling).max(0.) * norm) as f32
+        }));
+    }
+    filters
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Short-time Fourier transform of the last dimension.
+    ///
+    /// Frames of `n_fft` samples are taken every `hop` samples from the start of the signal (pad it first to center
+    /// them), and weighted by a periodic Hann window before they're transformed. Only the non-negative frequencies are
+    /// kept, since for real signals the rest mirror them.
+    ///
+    /// `Frames` is the shape of the windowed frames, [..., frames, n_fft], and `Dst` the spectrum's,
+    /// [..., frames, n_fft / 2 + 1].
+    #[track_caller]
+    pub fn stft<Frames: Shape, Dst: Shape>(self, n_fft: usize, hop: usize) -> ComplexTensor<Dst> {
+        let frames = self.pool_last_dim::<Frames>(n_fft, hop, 0);
+        let window = broadcast_constant(
+            frames,
+            "Hann Window",
+            hann_window(n_fft),
+            &[n_fft],
+            frames.shape,
+        )
+        .typed::<Frames>();
+        let spectrum = ComplexTensor::from_real(frames * window).fft();
+        let keep_bins = |part: GraphTensor<Frames>| {
+            let mut shape = part.shape;
+            let last = shape.indexes[shape.len() - 1];
+            shape.mask[last].1 = (n_fft / 2 + 1).into();
+            GraphTensor::<Dst>::from_id(part.id, shape, part.graph_ref)
+        };
+        ComplexTensor::new(keep_bins(spectrum.re), keep_bins(spectrum.im))
+    }
+
+    /// Power spectrogram of the last dimension projected onto `n_mels` mel bands.
+    ///
+    /// `Frames` and `Spectrum` are the shapes of the [stft](Self::stft) stages, and `Dst` the mel bands',
+    /// [..., frames, n_mels].
+    #[track_caller]
+    pub fn mel_spectrogram<Frames: Shape, Spectrum: Shape, Dst: Shape>(
+        self,
+        sample_rate: usize,
+        n_fft: usize,
+        hop: usize,
+        n_mels: usize,
+    ) -> GraphTensor<Dst> {
+        let power = self.stft::<Frames, Spectrum>(n_fft, hop).norm_sqr();
+        // Matmul against the filters, with the mel bands put in before the frequencies
+        let bins = n_fft / 2 + 1;
+        let rank = power.shape.len();
+        let mut power_shape = power.shape;
+        power_shape.expand(rank - 1, n_mels);
+        let filters = broadcast_constant(
+            power,
+            "Mel Filters",
+            mel_filters(sample_rate, n_fft, n_mels),
+            &[n_mels, bins],
+            power_shape,
+        );
+        let mul = self
+            .graph()
+            .add_op(op::Mul)
+            .input(power.id, 0, power_shape)
+            .input(filters.id, 0, filters.shape)
+            .finish();
+        let mut shape = power_shape.contiguous();
+        let sum = self
+            .graph()
+            .add_op(op::SumReduce(rank))
+            .input(mul, 0, shape)
+            .finish();
+        shape.remove_dim(rank);
+        GraphTensor::from_id(sum, shape.contiguous(), self.graph_ref)
+    }
+
+    /// Base 10 log of the [mel spectrogram](Self::mel_spectrogram), floored at 1e-10 so silence doesn't give -inf.
+    ///
+    /// This is the features speech models take in, before any model-specific scaling (Whisper, for one, clamps them to
+    /// 8 below their max and maps them to roughly [-1, 1]).
+    #[track_caller]
+    pub fn log_mel_spectrogram<Frames: Shape, Spectrum: Shape, Dst: Shape>(
+        self,
+        sample_rate: usize,
+        n_fft: usize,
+        hop: usize,
+        n_mels: usize,
+    ) -> GraphTensor<Dst> {
+        self.mel_spectrogram::<Frames, Spectrum, Dst>(sample_rate, n_fft, hop, n_mels)
+            .max_f32(1e-10)
+            .log2()
+            * std::f32::consts::LOG10_2
+    }
+}
+
+/// A constant of shape `dims`, read as the trailing dimensions of `shape` and broadcast along the leading ones
+fn broadcast_constant<S: Shape>(
+    like: GraphTensor<S>,
+    name: &str,
+    data: Vec<f32>,
+    dims: &[usize],
+    shape: ShapeTracker,
+) -> DynGraphTensor {
+    let graph = like.graph();
+    let id = graph
+        .add_op(Function(
+            format!("{name} Load"),
+            Box::new(move |_| vec![Tensor::new(data.clone())]),
+        ))
+        .finish();
+    let mut st = ShapeTracker::new(&dims.iter().map(|d| (*d).into()).collect::<Vec<_>>());
+    let full = shape.shape();
+    for (axis, dim) in full[..full.len() - dims.len()].iter().enumerate() {
+        st.expand(axis, dim.clone().small());
+    }
+    DynGraphTensor::from_id(id, st, like.graph_ref)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    crate::test_imports!();
+
+    use super::{hann_window, mel_filters};
+
+    /// Power spectrum of each frame, with the DFT written out
+    fn power_frames(signal: &[f32], n_fft: usize, hop: usize) -> Vec<Vec<f32>> {
+        let window = hann_window(n_fft);
+        (0..=(signal.len() - n_fft) / hop)
+            .map(|f| {
+                let frame = &signal[f * hop..f * hop + n_fft];
+                (0..=n_fft / 2)
+                    .map(|k| {
+                        let (mut re, mut im) = (0., 0.);
+                        for (t, (x, w)) in frame.iter().zip(&window).enumerate() {
+                            let angle = -2. * PI * (k * t) as f32 / n_fft as f32;
+                            re += x * w * angle.cos();
+                            im += x * w * angle.sin();
+                        }
+                        re * re + im * im
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_stft() {
+        let mut cx = Graph::new();
+        let data = random_vec(40);
+        let signal = cx.tensor::<R2<2, 20>>().set(data.clone());
+        let spectrum = signal.stft::<R3<2, 4, 8>, R3<2, 4, 5>>(8, 4).retrieve();
+        let power = spectrum.norm_sqr().retrieve();
+        cx.execute();
+
+        let expected = data
+            .chunks(20)
+            .flat_map(|s| power_frames(s, 8, 4).concat())
+            .collect::<Vec<_>>();
+        assert_eq!(spectrum.re.shape.shape_usize(), vec![2, 4, 5]);
+        assert_close(&power.data(), &expected);
+    }
+
+    #[test]
+    fn test_mel_filters() {
+        let filters = mel_filters(16000, 400, 80);
+        assert_eq!(filters.len(), 80 * 201);
+        assert!(filters.iter().all(|f| *f >= 0.));
+        // Each filter has unit area over Hz, which the bins sample well once the filters are wider than them
+        for filter in filters.chunks(201).skip(40) {
+            let area = filter.iter().sum::<f32>() * 40.;
+            assert!((area - 1.).abs() < 0.1, "{area}");
+        }
+    }
+
+    #[test]
+    fn test_log_mel_spectrogram() {
+        let mut cx = Graph::new();
+        let data = random_vec(64);
+        let signal = cx.tensor::<(Dyn<'s'>,)>().set_dyn(data.clone(), &[64]);
+        let features = signal
+            .log_mel_spectrogram::<(Dyn<'f'>, LConst<16>), (Dyn<'f'>, LConst<9>), (Dyn<'f'>, LConst<6>)>(
+                8000, 16, 8, 6,
+            )
+            .retrieve();
+        cx.execute();
+
+        let filters = mel_filters(8000, 16, 6);
+        let expected = power_frames(&data, 16, 8)
+            .into_iter()
+            .flat_map(|power| {
+                filters
+                    .chunks(9)
+                    .map(|filter| {
+                        let mel = filter.iter().zip(&power).map(|(f, p)| f * p).sum::<f32>();
+                        mel.max(1e-10).log10()
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(expected.len(), 7 * 6);
+        assert_close(&features.data(), &expected);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{fmt::Display, fs, path::PathBuf, sync::Mutex, time::Duration};
+
+// std's clock isn't available in the browser
+#[cfg(target_arch = "wasm32")]
+use instant::Instant;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+use rustc_hash::FxHashMap;
+
+/// What a tuned configuration applies to: an op on a backend, with the concrete shapes of its inputs
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TuneKey {
+    pub backend: String,
+    pub op: String,
+    pub shapes: Vec<Vec<usize>>,
+}
+
+impl TuneKey {
+    pub fn new(backend: &str, op: &str, shapes: Vec<Vec<usize>>) -> Self {
+        Self {
+            backend: backend.to_string(),
+            op: op.to_string(),
+            shapes,
+        }
+    }
+}
+
+impl Display for TuneKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let shapes = self
+            .shapes
+            .iter()
+            .map(|s| {
+                s.iter()
+                    .map(|d| d.to_string())
+                    .collect::<Vec<_>>()
+                    .join("x")
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(f, "{}/{}/{shapes}", self.backend, self.op)
+    }
+}
+
+/// Picks the fastest configuration of an op by timing every candidate on this machine, and remembers the winner.
+///
+/// Backends use this in their compilers to choose things like tile sizes and loop orders, which depend too much on the
+/// hardware to hard code. With a cache file, winners are saved as they're found and loaded on the next run, so each
+/// (backend, op, shape) only gets benchmarked once per machine.
+/// ```rust
+/// use luminal::prelude::*;
+/// let tuner = Autotuner::new();
+/// let key = TuneKey::new("cpu", "sleep", vec![vec![4]]);
+/// let best = tuner.tune(&key, &[2, 0], |ms| std::thread::sleep(std::time::Duration::from_millis(*ms)));
+/// assert_eq!(best, 0);
+/// ```
+#[derive(Debug)]
+pub struct Autotuner {
+    best: Mutex<FxHashMap<String, String>>,
+    path: Option<PathBuf>,
+    runs: usize,
+}
+
+impl Default for Autotuner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Autotuner {
+    /// Tuner that only remembers results in memory
+    pub fn new() -> Self {
+        Self {
+            best: Mutex::default(),
+            path: None,
+            runs: 3,
+        }
+    }
+
+    /// Tuner that loads past results from `path` if it exists, and saves new ones to it
+    pub fn with_cache_file(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let best = fs::read_to_string(&path)
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|l| l.split_once(" = "))
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Self {
+            best: Mutex::new(best),
+            path: Some(path),
+            runs: 3,
+        }
+    }
+
+    /// Number of timed runs per candidate, after one warmup run. The fastest run counts. Defaults to 3.
+    pub fn runs(mut self, runs: usize) -> Self {
+        self.runs = runs.max(1);
+        self
+    }
+
+    /// Name of the best configuration found for `key`, if it's been tuned
+    pub fn best(&self, key: &TuneKey) -> Option<String> {
+        self.best.lock().unwrap().get(&key.to_string()).cloned()
+    }
+
+    /// Return the fastest candidate for `key`, timing `run` on each one unless a winner is already known. Candidates
+    /// are told apart by their `Display` names.
+    pub fn tune<C: Display + Clone>(
+        &self,
+        key: &TuneKey,
+        candidates: &[C],
+        mut run: impl FnMut(&C),
+    ) -> C {
+        assert!(!candidates.is_empty(), "Need something to tune between");
+        if let Some(best) = self.best(key) {
+            if let Some(c) = candidates.iter().find(|c| c.to_string() == best) {
+                return c.clone();
+            }
+        }
+        let mut times = candidates.iter().map(|c| {
+            run(c);
+            (0..self.runs)
+                .map(|_| {
+                    let start = Instant::now();
+                    run(c);
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::MAX)
+        });
+        let mut best = (0, times.next().unwrap());
+        for (i, time) in times.enumerate() {
+            if time < best.1 {
+                best = (i + 1, time);
+            }
+        }
+        let best = candidates[best.0].clone();
+        self.best
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), best.to_string());
+        // A cache that can't be written just means tuning again next time
+        let _ = self.save();
+        best
+    }
+
+    /// Write all known winners to the cache file, if there is one
+    pub fn save(&self) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut lines = self
+            .best
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| format!("{k} = {v}\n"))
+            .collect::<Vec<_>>();
+        lines.sort();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, lines.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread::sleep, time::Duration};
+
+    use super::{Autotuner, TuneKey};
+
+    #[test]
+    fn test_autotune_cache_file() {
+        let path = std::env::temp_dir().join(format!("luminal_tune_{}", uuid::Uuid::new_v4()));
+        let key = TuneKey::new("cpu", "matmul", vec![vec![2, 3], vec![3, 4]]);
+        let tuner = Autotuner::with_cache_file(&path).runs(2);
+        let best = tuner.tune(&key, &["slow", "fast"], |c| {
+            if *c == "slow" {
+                sleep(Duration::from_millis(5));
+            }
+        });
+        assert_eq!(best, "fast");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "cpu/matmul/2x3,3x4 = fast\n"
+        );
+
+        // A new tuner picks the winner up from disk without running anything
+        let tuner = Autotuner::with_cache_file(&path);
+        let best = tuner.tune(&key, &["slow", "fast"], |_| panic!("Should be cached"));
+        assert_eq!(best, "fast");
+        // Unless the winner isn't a candidate anymore
+        assert_eq!(tuner.tune(&key, &["other"], |_| {}), "other");
+        std::fs::remove_file(path).unwrap();
+    }
+}
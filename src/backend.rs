@@ -0,0 +1,487 @@
+use std::fmt::Debug;
+
+use petgraph::{visit::EdgeRef, Direction};
+use rustc_hash::FxHashMap;
+
+use crate::{op::Function, prelude::*};
+
+/// A buffer on a backend's device
+pub type Buffer<D> = <D as Device>::Buffer;
+
+/// A backend's device memory: allocating buffers on it, and moving data between them and the host.
+///
+/// Not in the prelude, since most backends' own APIs have a `Device` and `Buffer` of their own.
+pub trait Device: Debug + Clone + 'static {
+    /// Elements on the device, stored in a `Tensor` between ops
+    type Buffer: Data;
+    /// Host memory each copy op keeps between runs to stage its copies through, like a page-locked buffer. Devices
+    /// copying straight out of host memory use `()`.
+    type Staging: Default;
+    /// Whether buffers are host memory, so running on the device needs no copies
+    const HOST: bool = false;
+    /// A zeroed buffer of `len` elements
+    fn alloc(&self, len: usize) -> Self::Buffer;
+    /// Copy host data into a new buffer, converting it to whatever format the buffer holds
+    fn copy_to_device(&self, staging: &mut Self::Staging, data: &[f32]) -> Self::Buffer;
+    /// Copy a buffer back to the host, waiting for the work writing it
+    fn copy_from_device(&self, staging: &mut Self::Staging, buffer: &Self::Buffer) -> Vec<f32>;
+    /// Whether a tensor is already on the device, so copying it there passes it through
+    fn holds(&self, tensor: &Tensor) -> bool {
+        tensor.is::<Self::Buffer>()
+    }
+    /// Block until all work queued on the device is done
+    fn synchronize(&self) {}
+}
+
+/// A kernel compiled for a device
+pub trait Kernel: Debug + Sized + 'static {
+    type Device: Device;
+    /// Compile kernel source written for the device
+    fn compile(device: &Self::Device, source: &str) -> Result<Self, String>;
+    /// Queue the kernel, reading `inputs` and writing `outputs`, which are allocated to the sizes the op was built with
+    fn launch(
+        &self,
+        device: &Self::Device,
+        inputs: &[&Buffer<Self::Device>],
+        outputs: &mut [Buffer<Self::Device>],
+        dyn_map: &FxHashMap<char, usize>,
+    );
+}
+
+/// Everything a graph is compiled against to run on some hardware: the device its buffers live on, and the passes that
+/// swap primitive ops for the backend's own, which launch its kernels.
+pub trait Backend {
+    type Device: Device;
+    type Compiler: Compiler;
+    fn device(&self) -> Self::Device;
+    fn compiler(&self) -> Self::Compiler;
+    /// Whether the device has no kernel for an op, so it runs on the host between copies like a function
+    #[allow(unused)]
+    fn runs_on_host(graph: &Graph, node: NodeIndex) -> bool {
+        false
+    }
+}
+
+impl Graph {
+    /// Compile the graph to run on a backend. Copies to and from its device go in first, so its compiler only sees
+    /// ops that run on the device.
+    pub fn compile_for<B: Backend, T: ToIdsMut>(
+        &mut self,
+        backend: &B,
+        mut remap: T,
+    ) -> <B::Compiler as Compiler>::Output {
+        self.compile(
+            InsertCopies::new(backend.device(), B::runs_on_host),
+            &mut remap,
+        );
+        self.compile(backend.compiler(), remap)
+    }
+}
+
+/// Ops that run on the host whatever the device: functions, ops running subgraphs, the `CallOutput`s handing on
+/// their outputs, and collectives, which exchange host buffers with the other ranks
+pub fn runs_on_host(graph: &Graph, node: NodeIndex) -> bool {
+    graph.is_op::<Function>(node)
+        || graph.is_op::<CallOutput>(node)
+        || graph.is_op::<AllReduce>(node)
+        || graph.is_op::<AllGather>(node)
+        || runs_subgraph(graph, node)
+}
+
+/// Ops that run subgraphs on the host. Their outputs are handed on by `CallOutput`s, which do the copying back.
+pub fn runs_subgraph(graph: &Graph, node: NodeIndex) -> bool {
+    graph.is_op::<Call>(node) || graph.is_op::<Cond>(node) || graph.is_op::<Loop>(node)
+}
+
+/// Copy a host tensor to the device. Tensors already on it are passed through.
+pub struct CopyToDevice<D: Device> {
+    pub device: D,
+    staging: D::Staging,
+}
+
+impl<D: Device> CopyToDevice<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            staging: Default::default(),
+        }
+    }
+}
+
+impl<D: Device> Debug for CopyToDevice<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CopyToDevice({:?})", self.device)
+    }
+}
+
+impl<D: Device> Operator for CopyToDevice<D> {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let tensor = inp.pop().unwrap().0;
+        if self.device.holds(tensor.borrowed()) {
+            return vec![tensor.cloned()];
+        }
+        let data = tensor
+            .borrowed()
+            .widened_f32s()
+            .unwrap_or_else(|e| panic!("{e}"));
+        vec![Tensor::new(
+            self.device.copy_to_device(&mut self.staging, &data),
+        )]
+    }
+}
+
+/// Copy a device tensor back to the host. Tensors already there are passed through.
+pub struct CopyFromDevice<D: Device> {
+    pub device: D,
+    staging: D::Staging,
+}
+
+impl<D: Device> CopyFromDevice<D> {
+    pub fn new(device: D) -> Self {
+        Self {
+            device,
+            staging: Default::default(),
+        }
+    }
+}
+
+impl<D: Device> Debug for CopyFromDevice<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CopyFromDevice({:?})", self.device)
+    }
+}
+
+impl<D: Device> Operator for CopyFromDevice<D> {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let tensor = inp.pop().unwrap().0;
+        match tensor.borrowed().downcast_ref::<Buffer<D>>() {
+            Some(buffer) => vec![Tensor::new(
+                self.device.copy_from_device(&mut self.staging, buffer),
+            )],
+            None => vec![tensor.cloned()],
+        }
+    }
+}
+
+/// Run a compiled kernel, allocating its outputs on the device
+pub struct KernelOp<K: Kernel> {
+    pub kernel: K,
+    pub device: K::Device,
+    /// Number of elements in each output
+    pub outputs: Vec<BigExpression>,
+}
+
+impl<K: Kernel> Debug for KernelOp<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Kernel({:?})", self.kernel)
+    }
+}
+
+impl<K: Kernel> KernelOp<K> {
+    pub fn new(kernel: K, device: K::Device, outputs: Vec<BigExpression>) -> Self {
+        Self {
+            kernel,
+            device,
+            outputs,
+        }
+    }
+}
+
+impl<K: Kernel> Operator for KernelOp<K> {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let inputs = inp
+            .iter()
+            .map(|(t, _)| t.borrowed().get::<Buffer<K::Device>>())
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut outputs = self
+            .outputs
+            .iter()
+            .map(|n| Ok(self.device.alloc(n.try_exec(dyn_map)?)))
+            .collect::<Result<Vec<_>, LuminalError>>()?;
+        self.kernel
+            .launch(&self.device, &inputs, &mut outputs, dyn_map);
+        Ok(outputs.into_iter().map(Tensor::new).collect())
+    }
+}
+
+/// Put copies between the host and a device wherever data crosses between them: after the outputs of ops running on the
+/// host, before their inputs, and before retrieved tensors. Functions and subgraphs run on the host, along with whatever
+/// `host_ops` picks out, usually ops the device has no kernel for.
+///
+/// Everything else is left for the backend's compiler to move onto the device. A host op feeding straight into a
+/// retrieved tensor has its copies cancel out, so it's retrieved from the op instead. Copies that are already there are
+/// left alone, so backends can run this in their own compilers as well.
+#[derive(Debug, Clone)]
+pub struct InsertCopies<D> {
+    pub device: D,
+    pub host_ops: fn(&Graph, NodeIndex) -> bool,
+}
+
+impl<D: Device> InsertCopies<D> {
+    pub fn new(device: D, host_ops: fn(&Graph, NodeIndex) -> bool) -> Self {
+        Self { device, host_ops }
+    }
+}
+
+impl<D: Device> Compiler for InsertCopies<D> {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        if D::HOST {
+            return;
+        }
+        let on_host =
+            |graph: &Graph, node| runs_on_host(graph, node) || (self.host_ops)(graph, node);
+        for host_op in graph
+            .node_indices()
+            .filter(|n| on_host(graph, *n))
+            .collect::<Vec<_>>()
+        {
+            let outgoing = graph
+                .edges_directed(host_op, Direction::Outgoing)
+                .filter(|e| !graph.is_op::<CopyToDevice<D>>(e.target()))
+                .map(|e| (e.id(), *e.weight(), e.target()))
+                .collect::<Vec<_>>();
+            if !outgoing.is_empty() && !runs_subgraph(graph, host_op) {
+                let copy = graph
+                    .add_op(CopyToDevice::new(self.device.clone()))
+                    .input(host_op, 0, ShapeTracker::new(&[]))
+                    .finish();
+                for (edge, weight, dest) in outgoing {
+                    graph.add_edge(copy, dest, weight);
+                    graph.remove_edge(edge);
+                }
+                if graph.no_delete.remove(&host_op) {
+                    graph.no_delete.insert(copy);
+                }
+                if let Some(w) = graph.to_retrieve.remove(&host_op) {
+                    graph.to_retrieve.insert(copy, w);
+                }
+                if let Some(name) = graph.output_names.remove(&host_op) {
+                    graph.output_names.insert(copy, name);
+                }
+            }
+
+            for (source, edge, weight) in graph
+                .edges_directed(host_op, Direction::Incoming)
+                .filter(|e| {
+                    !e.weight().is_schedule() && !graph.is_op::<CopyFromDevice<D>>(e.source())
+                })
+                .map(|e| (e.source(), e.id(), *e.weight()))
+                .collect::<Vec<_>>()
+            {
+                let (input_order, output_order, shape) = weight.as_data().unwrap();
+                let copy = graph
+                    .add_op(CopyFromDevice::new(self.device.clone()))
+                    .input(source, output_order, shape)
+                    .finish();
+                graph.add_edge(
+                    copy,
+                    host_op,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+                graph.remove_edge(edge);
+            }
+        }
+
+        for (output, (_, shape)) in graph
+            .to_retrieve
+            .iter()
+            .map(|(n, w)| (*n, *w))
+            .filter(|(n, _)| !on_host(graph, *n) && !graph.is_op::<CopyFromDevice<D>>(*n))
+            .collect::<Vec<_>>()
+        {
+            if graph.is_op::<CopyToDevice<D>>(output) {
+                let (src, _, _) = graph.get_sources(output)[0];
+                if graph.no_delete.remove(&output) {
+                    graph.no_delete.insert(src);
+                }
+                if let Some(w) = graph.to_retrieve.remove(&output) {
+                    graph.to_retrieve.insert(src, w);
+                }
+                if let Some(name) = graph.output_names.remove(&output) {
+                    graph.output_names.insert(src, name);
+                }
+            } else {
+                let copy = graph
+                    .add_op(CopyFromDevice::new(self.device.clone()))
+                    .input(output, 0, shape)
+                    .finish();
+                remap(output, copy, &mut ids, graph);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    use rustc_hash::FxHashMap;
+
+    use super::{Backend, Buffer, Device, Kernel, KernelOp};
+
+    /// A device whose buffers are doubled on the way in and halved on the way out, so a missed copy shows up
+    #[derive(Debug, Clone)]
+    struct Doubled;
+
+    #[derive(Debug, Clone)]
+    struct DoubledBuffer(Vec<f32>);
+
+    impl Data for DoubledBuffer {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn size(&self) -> usize {
+            self.0.len() * 4
+        }
+        fn device(&self) -> &'static str {
+            "doubled"
+        }
+    }
+
+    impl Device for Doubled {
+        type Buffer = DoubledBuffer;
+        type Staging = ();
+        fn alloc(&self, len: usize) -> DoubledBuffer {
+            DoubledBuffer(vec![0.; len])
+        }
+        fn copy_to_device(&self, _: &mut (), data: &[f32]) -> DoubledBuffer {
+            DoubledBuffer(data.iter().map(|d| d * 2.).collect())
+        }
+        fn copy_from_device(&self, _: &mut (), buffer: &DoubledBuffer) -> Vec<f32> {
+            buffer.0.iter().map(|d| d / 2.).collect()
+        }
+    }
+
+    /// Elementwise kernels, with the source being the op to apply
+    #[derive(Debug)]
+    struct Elementwise(String);
+
+    impl Kernel for Elementwise {
+        type Device = Doubled;
+        fn compile(_: &Doubled, source: &str) -> Result<Self, String> {
+            match source {
+                "exp2" | "add" => Ok(Self(source.to_string())),
+                _ => Err(format!("Unknown op {source}")),
+            }
+        }
+        fn launch(
+            &self,
+            _: &Doubled,
+            inputs: &[&Buffer<Doubled>],
+            outputs: &mut [Buffer<Doubled>],
+            _: &FxHashMap<char, usize>,
+        ) {
+            for (i, out) in outputs[0].0.iter_mut().enumerate() {
+                // Buffers hold doubled values
+                *out = match self.0.as_str() {
+                    "exp2" => (inputs[0].0[i] / 2.).exp2() * 2.,
+                    _ => inputs[0].0[i] + inputs[1].0[i],
+                };
+            }
+        }
+    }
+
+    /// Swaps Exp2 and Add for kernels
+    #[derive(Debug, Default)]
+    struct KernelCompiler;
+
+    impl Compiler for KernelCompiler {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.node_indices().collect::<Vec<_>>() {
+                let source = if graph.is_op::<Exp2>(node) {
+                    "exp2"
+                } else if graph.is_op::<Add>(node) {
+                    "add"
+                } else {
+                    continue;
+                };
+                let shape = graph.get_sources(node)[0].2;
+                let op = KernelOp::new(
+                    Elementwise::compile(&Doubled, source).unwrap(),
+                    Doubled,
+                    vec![shape.n_elements().simplify()],
+                );
+                *graph.graph.node_weight_mut(node).unwrap() = Box::new(op);
+            }
+        }
+    }
+
+    struct DoubledBackend;
+
+    impl Backend for DoubledBackend {
+        type Device = Doubled;
+        type Compiler = KernelCompiler;
+        fn device(&self) -> Doubled {
+            Doubled
+        }
+        fn compiler(&self) -> KernelCompiler {
+            KernelCompiler
+        }
+        fn runs_on_host(graph: &Graph, node: NodeIndex) -> bool {
+            graph.is_op::<crate::op::Sin>(node)
+        }
+    }
+
+    #[test]
+    fn test_compile_for_backend() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let b = cx.tensor::<R1<3>>().set([0., -1., 4.]);
+        let mut c = (a.exp2() + b).retrieve();
+        cx.execute();
+        let unoptimized = c.data();
+        c.drop();
+
+        cx.compile_for(&DoubledBackend, &mut c);
+        assert!(cx.node_indices().all(|n| !cx.is_op::<Exp2>(n)));
+        cx.execute();
+        assert_close(&c.data(), &unoptimized);
+        assert!(Elementwise::compile(&Doubled, "sin").is_err());
+    }
+
+    #[test]
+    fn test_host_ops_get_copies_once() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let b = cx.tensor::<R1<3>>().set([0., -1., 4.]);
+        let mut c = (a.exp2().sin() + b).retrieve();
+        cx.execute();
+        let unoptimized = c.data();
+        c.drop();
+
+        // Sin has no kernel, so it runs on the host between copies
+        cx.compile_for(&DoubledBackend, &mut c);
+        let copies = |cx: &Graph| {
+            cx.node_indices()
+                .filter(|n| {
+                    cx.is_op::<CopyToDevice<Doubled>>(*n) || cx.is_op::<CopyFromDevice<Doubled>>(*n)
+                })
+                .count()
+        };
+        let count = copies(&cx);
+        cx.compile(
+            InsertCopies::new(Doubled, DoubledBackend::runs_on_host),
+            &mut c,
+        );
+        assert_eq!(copies(&cx), count);
+        cx.execute();
+        assert_close(&c.data(), &unoptimized);
+    }
+}
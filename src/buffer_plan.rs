@@ -0,0 +1,384 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use petgraph::{algo::toposort, visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::prelude::*;
+
+/// Buffers for the ops a backend runs on its device, shared between ops whose outputs are never alive at the same time.
+///
+/// Backends that allocate up front plan their buffers once while compiling, then call `allocate` before each run. Sizes
+/// are in whatever unit the backend allocates in, like bytes.
+#[derive(Debug, Clone, Default)]
+pub struct BufferPlan {
+    /// Size of each buffer, in terms of the graph's dynamic dimensions
+    pub sizes: Vec<BigExpression>,
+    /// Buffers each op writes its outputs to, and buffers it uses for scratch space
+    pub ops: FxHashMap<NodeIndex, (Vec<usize>, Vec<usize>)>,
+}
+
+/// A planned buffer, which can be bigger than the tensor it currently holds
+#[derive(Debug, Clone)]
+pub struct PlannedBuffer<B> {
+    pub buffer: B,
+    /// How much of the buffer this run uses
+    pub len: usize,
+    /// How big the buffer was allocated
+    pub capacity: usize,
+}
+
+impl BufferPlan {
+    /// Plan buffers for every op `buffers` gives the output and scratch buffer sizes of. Ops that don't run on the
+    /// device return `None`.
+    ///
+    /// An op reuses a buffer of the same size once every consumer of the op it came from has run. Outputs of tensors
+    /// that are kept between runs always get buffers of their own.
+    pub fn new(
+        graph: &mut Graph,
+        mut buffers: impl FnMut(
+            &mut Graph,
+            NodeIndex,
+        ) -> Option<(Vec<BigExpression>, Vec<BigExpression>)>,
+    ) -> Self {
+        // First pass - get clear sets for each node
+        #[allow(clippy::type_complexity)]
+        let mut first_pass: FxHashMap<
+            NodeIndex,
+            (
+                BTreeMap<NodeIndex, BTreeSet<NodeIndex>>,
+                BTreeSet<NodeIndex>,
+            ),
+        > = FxHashMap::default();
+        let toposort = toposort(&graph.graph, None).unwrap();
+        // Loop through nodes in graph
+        for node in &toposort {
+            // Run through parents to build new tenative set and clear set
+            let (mut tenative_sets, mut clear_set) = (BTreeMap::default(), BTreeSet::default());
+            for parent in graph
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .filter(|e| !e.weight().is_schedule())
+                .map(|e| e.source())
+            {
+                let parent_children = graph
+                    .graph
+                    .edges_directed(parent, Direction::Outgoing)
+                    .filter(|e| !e.weight().is_schedule())
+                    .map(|e| e.target())
+                    .collect::<BTreeSet<_>>();
+                tenative_sets.insert(parent, parent_children);
+                if let Some((parent_tenative_set, parent_clear_set)) = first_pass.get(&parent) {
+                    for (node_index, new_tenative_set) in
+                        parent_tenative_set.iter().map(|(n, c)| {
+                            let mut c = c.clone();
+                            c.retain(|n| *n != parent);
+                            (*n, c)
+                        })
+                    {
+                        if let Some(set) = tenative_sets.get(&node_index) {
+                            *tenative_sets.get_mut(&node_index).unwrap() =
+                                btreeset_intersection(new_tenative_set, set);
+                        } else {
+                            tenative_sets.insert(node_index, new_tenative_set);
+                        }
+                    }
+                    clear_set.extend(
+                        tenative_sets
+                            .iter()
+                            .filter(|(_, v)| v.is_empty())
+                            .map(|(n, _)| *n),
+                    );
+                    tenative_sets.retain(|_, v| !v.is_empty());
+                    clear_set.extend(parent_clear_set);
+                }
+            }
+            first_pass.insert(*node, (tenative_sets, clear_set));
+        }
+
+        // Second pass - assign buffers
+        let required = toposort
+            .iter()
+            .filter_map(|n| buffers(graph, *n).map(|b| (*n, b)))
+            .collect::<FxHashMap<_, _>>();
+        let mut plan = BufferPlan::default();
+        let mut used = FxHashSet::<NodeIndex>::default();
+        for node in toposort.iter().filter(|n| !graph.no_delete.contains(n)) {
+            let Some((outputs, intermediates)) = required.get(node) else {
+                continue;
+            };
+            plan.ops.insert(*node, (vec![], vec![]));
+            for (required_buffers, output) in [(outputs, true), (intermediates, false)] {
+                for required_buffer in required_buffers {
+                    // Find a buffer of the same size from an op whose consumers have all run
+                    let reusable = first_pass[node]
+                        .1
+                        .iter()
+                        .filter(|i| !graph.no_delete.contains(i))
+                        .filter(|i| !used.contains(i))
+                        .filter(|i| required.contains_key(i))
+                        .flat_map(|i| {
+                            let sizes = if output {
+                                &required[i].0
+                            } else {
+                                &required[i].1
+                            };
+                            sizes.iter().enumerate().map(|(o, b)| (o, *i, b))
+                        })
+                        .find(|(_, _, size)| *size == required_buffer);
+                    let buffer = if let Some((buffer_index, source_node, _)) = reusable {
+                        // Mark the source as used so its buffer can't be taken again
+                        used.insert(source_node);
+                        let source = &plan.ops[&source_node];
+                        if output {
+                            source.0[buffer_index]
+                        } else {
+                            source.1[buffer_index]
+                        }
+                    } else {
+                        plan.sizes.push(required_buffer.clone());
+                        plan.sizes.len() - 1
+                    };
+                    let assigned = plan.ops.get_mut(node).unwrap();
+                    if output {
+                        assigned.0.push(buffer);
+                    } else {
+                        assigned.1.push(buffer);
+                    }
+                }
+            }
+        }
+        // Kept tensors outlive the run, so they get buffers just for them
+        for node in toposort.iter().filter(|n| graph.no_delete.contains(n)) {
+            let Some((outputs, intermediates)) = required.get(node) else {
+                continue;
+            };
+            let mut add = |sizes: &Vec<BigExpression>| {
+                sizes
+                    .iter()
+                    .map(|size| {
+                        plan.sizes.push(size.clone().simplify());
+                        plan.sizes.len() - 1
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let assigned = (add(outputs), add(intermediates));
+            plan.ops.insert(*node, assigned);
+        }
+        plan
+    }
+
+    /// Make sure every buffer fits the current dynamic dimensions, allocating them the first time.
+    ///
+    /// A buffer that's too small gets reallocated with room for each dimension at its maximum from
+    /// `Graph::set_dyn_dim_max`. Buffers never shrink, so a buffer's `len` is how much of it the current run uses, not
+    /// its capacity.
+    pub fn allocate<B>(
+        &self,
+        buffers: &mut Vec<PlannedBuffer<B>>,
+        dyn_map: &FxHashMap<char, usize>,
+        dyn_dim_max: &FxHashMap<char, usize>,
+        mut alloc: impl FnMut(usize) -> B,
+    ) -> Result<(), LuminalError> {
+        // Dimensions with a declared maximum get room for it, so buffers don't need to grow with them
+        let mut reserved = dyn_map.clone();
+        for (dim, max) in dyn_dim_max {
+            let size = reserved.entry(*dim).or_default();
+            *size = (*size).max(*max);
+        }
+        buffers.truncate(self.sizes.len());
+        for (i, size) in self.sizes.iter().enumerate() {
+            let len = size.try_exec(dyn_map)?;
+            if let Some(buffer) = buffers.get_mut(i).filter(|b| b.capacity >= len) {
+                buffer.len = len;
+                continue;
+            }
+            let capacity = size.try_exec(&reserved)?;
+            let buffer = PlannedBuffer {
+                buffer: alloc(capacity),
+                len,
+                capacity,
+            };
+            if i < buffers.len() {
+                buffers[i] = buffer;
+            } else {
+                buffers.push(buffer);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn btreeset_intersection<T: Ord>(mut a: BTreeSet<T>, b: &BTreeSet<T>) -> BTreeSet<T> {
+    a.retain(|i| b.contains(i));
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use itertools::Itertools;
+    use rustc_hash::FxHashMap;
+
+    use super::{BufferPlan, PlannedBuffer};
+    use crate::op::{Sin, Tensor};
+    crate::test_imports!();
+
+    type Buffers = Arc<Mutex<Vec<PlannedBuffer<Arc<Mutex<Vec<f32>>>>>>>;
+
+    /// A planned buffer handed on to the next op, the way a device buffer holds a tensor
+    #[derive(Debug, Clone)]
+    struct Planned {
+        data: Arc<Mutex<Vec<f32>>>,
+        len: usize,
+    }
+
+    impl Planned {
+        fn to_vec(&self) -> Vec<f32> {
+            self.data.lock().unwrap()[..self.len].to_vec()
+        }
+    }
+
+    impl Data for Planned {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn size(&self) -> usize {
+            self.len * 4
+        }
+        fn device(&self) -> &'static str {
+            "planned"
+        }
+    }
+
+    /// Allocates the plan's buffers before each run
+    #[derive(Debug)]
+    struct Allocate {
+        plan: BufferPlan,
+        dyn_dim_max: FxHashMap<char, usize>,
+        buffers: Buffers,
+    }
+
+    impl Operator for Allocate {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            self.try_process(inp, &FxHashMap::default())
+                .unwrap_or_else(|e| panic!("{e}"))
+        }
+        fn try_process(
+            &mut self,
+            _: Vec<(InputTensor, ShapeTracker)>,
+            dyn_map: &FxHashMap<char, usize>,
+        ) -> Result<Vec<Tensor>, LuminalError> {
+            // Fresh buffers are NaN, so reading past what an op wrote shows up
+            self.plan.allocate(
+                &mut self.buffers.lock().unwrap(),
+                dyn_map,
+                &self.dyn_dim_max,
+                |len| Arc::new(Mutex::new(vec![f32::NAN; len])),
+            )?;
+            Ok(vec![])
+        }
+    }
+
+    /// Sin writing into its planned output buffer
+    #[derive(Debug)]
+    struct PlannedSin {
+        buffers: Buffers,
+        output: usize,
+    }
+
+    impl Operator for PlannedSin {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let input = match inp[0].0.borrowed().downcast_ref::<Planned>() {
+                Some(planned) => planned.to_vec(),
+                None => inp[0].0.borrowed().f32s().unwrap().to_vec(),
+            };
+            let buffers = self.buffers.lock().unwrap();
+            let PlannedBuffer { buffer, len, .. } = &buffers[self.output];
+            assert_eq!(*len, input.len());
+            for (out, x) in buffer.lock().unwrap().iter_mut().zip(input) {
+                *out = x.sin();
+            }
+            vec![Tensor::new(Planned {
+                data: buffer.clone(),
+                len: *len,
+            })]
+        }
+    }
+
+    /// Runs Sins in planned buffers
+    #[derive(Debug, Default)]
+    struct PlanSins;
+
+    impl Compiler for PlanSins {
+        type Output = Buffers;
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) -> Buffers {
+            let plan = BufferPlan::new(graph, |graph, node| {
+                graph
+                    .is_op::<Sin>(node)
+                    .then(|| (vec![graph.get_sources(node)[0].2.n_elements()], vec![]))
+            });
+            let roots = graph
+                .node_indices()
+                .filter(|n| graph.get_sources(*n).is_empty())
+                .collect_vec();
+            let buffers = Buffers::default();
+            let dyn_dim_max = graph.dyn_dim_max.clone();
+            let allocate = graph
+                .add_op(Allocate {
+                    plan: plan.clone(),
+                    dyn_dim_max,
+                    buffers: buffers.clone(),
+                })
+                .finish();
+            for root in roots {
+                graph.add_schedule_dependency(allocate, root);
+            }
+            for (node, (outputs, _)) in plan.ops {
+                *graph.graph.node_weight_mut(node).unwrap() = Box::new(PlannedSin {
+                    buffers: buffers.clone(),
+                    output: outputs[0],
+                });
+            }
+            buffers
+        }
+    }
+
+    #[test]
+    fn test_planned_buffers_shrink() {
+        let mut cx = Graph::new();
+        cx.set_dyn_dim_max('s', 4);
+        let a = cx.tensor::<(Dyn<'s'>,)>();
+        let out = a.sin().sin().sin().sin().retrieve();
+        let buffers = cx.compile(PlanSins, ());
+
+        // Grow to the maximum, past it, then shrink back down
+        for (s, capacity) in [(2, 4), (4, 4), (7, 7), (3, 7), (1, 7)] {
+            let data = random_vec(s);
+            a.set_dyn(data.clone(), &[s]);
+            cx.execute();
+            let expected = data
+                .iter()
+                .map(|x| x.sin().sin().sin().sin())
+                .collect::<Vec<_>>();
+            let planned = cx
+                .get_tensor_ref(out.id, 0)
+                .unwrap()
+                .downcast_ref::<Planned>()
+                .unwrap()
+                .clone();
+            assert_eq!(planned.size(), s * 4);
+            assert_close(&planned.to_vec(), &expected);
+            // The third sin reuses the first one's buffer, and the kept output gets its own
+            let buffers = buffers.lock().unwrap();
+            assert_eq!(buffers.len(), 3);
+            for buffer in buffers.iter() {
+                assert_eq!((buffer.len, buffer.capacity), (s, capacity));
+            }
+            out.drop();
+        }
+    }
+}
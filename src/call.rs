@@ -0,0 +1,280 @@
+use std::fmt::Debug;
+
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// Runs a whole separate graph as a single op, so parts of a model can be built and compiled on their own and then composed.
+///
+/// The op's inputs feed the subgraph's input tensors, and it has one output for each of the subgraph's outputs.
+pub struct Call {
+    pub graph: Graph,
+    /// The subgraph's input tensors, and the shapes they were declared with
+    inputs: Vec<(NodeIndex, ShapeTracker)>,
+    /// The subgraph's output tensors, and the shapes they get read out through
+    outputs: Vec<(NodeIndex, ShapeTracker)>,
+}
+
+impl Debug for Call {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Call({} inputs, {} outputs, {} nodes)",
+            self.inputs.len(),
+            self.outputs.len(),
+            self.graph.node_count()
+        )
+    }
+}
+
+impl Operator for Call {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    /// The subgraph runs on the host. Device data going in or out of it is an error, so backends need to copy around it.
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        for ((input, declared), (tensor, st)) in self.inputs.iter().zip(inp) {
+            // Dynamic dimensions in the subgraph take on the sizes of the tensors fed in
+            for (dim, size) in declared.shape().into_iter().zip(st.shape_usize()) {
+                if let Some(c) = dim.to_symbols().pop() {
+                    self.graph.dyn_map.insert(c, size);
+                }
+            }
+            // The subgraph expects its inputs laid out contiguously, so views need to be materialized
+            let tensor = if st.is_reshaped() {
+                Tensor::new(HostTensorView::new(tensor.borrowed().widened_f32s()?, st).to_vec())
+            } else {
+                tensor.cloned()
+            };
+            self.graph.tensors.insert((*input, 0), tensor);
+        }
+        self.graph.try_execute()?;
+        let mut outputs = vec![];
+        for (output, shape) in &self.outputs {
+            let tensor = self
+                .graph
+                .get_tensor_ref(*output, 0)
+                .ok_or(LuminalError::MissingSubgraphOutput(*output))?;
+            tensor.f32s()?;
+            let view = self
+                .graph
+                .get_view(*output, *shape)
+                .ok_or(LuminalError::MissingSubgraphOutput(*output))?;
+            outputs.push(Tensor::new(view));
+        }
+        self.graph
+            .drop_tensors(self.outputs.iter().map(|(o, _)| *o).collect::<Vec<_>>());
+        Ok(outputs)
+    }
+}
+
+/// Hands one output of a `Call` on to the rest of the graph
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallOutput(pub u8);
+
+impl Operator for CallOutput {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+impl Call {
+    /// Wrap a graph, with the tensors that get fed in and the tensors to hand back (which must be retrieved)
+    pub fn new(mut graph: Graph, inputs: &[GraphTensor<()>], outputs: &[GraphTensor<()>]) -> Self {
+        for output in outputs {
+            assert!(
+                graph.to_retrieve.contains_key(&output.id),
+                "Subgraph output {} must be retrieved",
+                output.id.index()
+            );
+        }
+        // The subgraph's inputs are fed fresh each run, so they shouldn't be kept around
+        for input in inputs {
+            graph.no_delete.remove(&input.id);
+        }
+        Self {
+            graph,
+            inputs: inputs.iter().map(|i| (i.id, i.shape)).collect(),
+            outputs: outputs.iter().map(|o| (o.id, o.shape)).collect(),
+        }
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Output shapes are in terms of the subgraph's dynamic dimensions, so swap in the dimensions of the tensors feeding it
+    pub(crate) fn output_shapes(&self, inputs: &[GraphTensor<()>]) -> Vec<ShapeTracker> {
+        let dim_map = self
+            .inputs
+            .iter()
+            .zip(inputs)
+            .flat_map(|((_, inner), outer)| inner.shape().into_iter().zip(outer.shape.shape()))
+            .filter_map(|(inner, outer)| Some((inner.to_symbols().pop()?, outer)))
+            .collect::<Vec<_>>();
+        self.outputs
+            .iter()
+            .map(|(_, shape)| {
+                ShapeTracker::new(
+                    &shape
+                        .shape()
+                        .into_iter()
+                        .map(|d| {
+                            dim_map
+                                .iter()
+                                .fold(d, |d, (c, outer)| d.substitute(*c, outer.clone()))
+                                .small()
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect()
+    }
+}
+
+impl Graph {
+    /// Embed `subgraph` as a single `Call` op.
+    ///
+    /// `inputs` pairs each of the subgraph's input tensors with the tensor in this graph that feeds it, and `outputs` are the
+    /// subgraph's tensors to hand back, which must be retrieved. Returns a tensor in this graph for each output, in order.
+    /// Dynamic dimensions of the outputs that don't come from an input need to be set on this graph as well.
+    /// The subgraph can be compiled on its own beforehand, as long as the ids passed in are the ones after compiling.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut encoder = Graph::new();
+    /// let x = encoder.tensor::<R1<3>>();
+    /// let y = (x * 2.).retrieve();
+    ///
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    /// let out = cx.call(encoder, &[(x.no_shape(), a.no_shape())], &[y.no_shape()]);
+    /// let b = (out[0] + 1.).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![3., 5., 7.]);
+    /// ```
+    #[track_caller]
+    pub fn call(
+        &mut self,
+        subgraph: Graph,
+        inputs: &[(GraphTensor<()>, GraphTensor<()>)],
+        outputs: &[GraphTensor<()>],
+    ) -> Vec<GraphTensor<()>> {
+        let (inner, outer): (Vec<_>, Vec<_>) = inputs.iter().copied().unzip();
+        let call = Call::new(subgraph, &inner, outputs);
+        let shapes = call.output_shapes(&outer);
+        let mut node = self.add_op(call);
+        for input in &outer {
+            node = node.input(input.id, 0, input.shape);
+        }
+        let node = node.finish();
+        self.call_outputs(node, &shapes)
+    }
+
+    /// Split each output of a subgraph op out into its own tensor
+    #[track_caller]
+    pub(crate) fn call_outputs(
+        &mut self,
+        node: NodeIndex,
+        shapes: &[ShapeTracker],
+    ) -> Vec<GraphTensor<()>> {
+        // A loop rather than a map, so the outputs get the caller's location too
+        let mut outputs = vec![];
+        for (i, shape) in shapes.iter().enumerate() {
+            let id = self
+                .add_op(CallOutput(i as u8))
+                .input(node, i as u8, *shape)
+                .finish();
+            outputs.push(GraphTensor::from_id(id, *shape, self));
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_call() {
+        // Encoder with a weight and a dynamic sequence length, compiled on its own
+        let mut encoder = Graph::new();
+        let x = encoder.tensor::<(Dyn<'s'>, LConst<2>)>();
+        let w = encoder
+            .tensor::<R2<2, 2>>()
+            .set([[1., 2.], [3., 4.]])
+            .keep();
+        let mut y = x.matmul(w).retrieve();
+        let mut pooled = y.sum_reduce::<(LConst<2>,), LAxis<0>>().retrieve();
+        encoder.compile(GenericCompiler::default(), (&mut y, &mut pooled));
+
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'t'>, LConst<2>)>();
+        // Feed in a transposed view, which needs to be materialized
+        let a_t = a.permute::<(LConst<2>, Dyn<'t'>), _>();
+        let b = cx.tensor::<(LConst<2>, Dyn<'t'>)>();
+        let input = (a_t + b).permute::<(Dyn<'t'>, LConst<2>), _>();
+        let out = cx.call(
+            encoder,
+            &[(x.no_shape(), input.no_shape())],
+            &[y.no_shape(), pooled.no_shape()],
+        );
+        let (enc_out, enc_pooled) = (out[0].retrieve(), (out[1] * 2.).retrieve());
+
+        for n in [2, 3] {
+            let a_data = (0..n * 2).map(|i| i as f32).collect::<Vec<_>>();
+            a.set_dyn(a_data.clone(), &[n, 2]);
+            b.set_dyn(vec![1.; n * 2], &[2, n]);
+            cx.execute();
+
+            // Reference: rows of (a + 1) times the weight
+            let rows = a_data.chunks(2).map(|r| [r[0] + 1., r[1] + 1.]);
+            let expected = rows
+                .clone()
+                .flat_map(|r| [r[0] + 3. * r[1], 2. * r[0] + 4. * r[1]])
+                .collect::<Vec<_>>();
+            assert_close(&enc_out.data(), &expected);
+            let sums = [
+                expected.iter().step_by(2).sum::<f32>() * 2.,
+                expected.iter().skip(1).step_by(2).sum::<f32>() * 2.,
+            ];
+            assert_close(&enc_pooled.data(), &sums);
+            enc_out.drop();
+            enc_pooled.drop();
+        }
+    }
+
+    #[test]
+    fn test_call_missing_output() {
+        let mut inner = Graph::new();
+        let x = inner.tensor::<R1<2>>();
+        let y = (x * 2.).retrieve();
+        let _z = (y + 1.).retrieve();
+        let mut call = Call::new(inner, &[x.no_shape()], &[y.no_shape()]);
+        // Let the subgraph free its output once its last consumer has run
+        call.graph.to_retrieve.remove(&y.id);
+        call.graph.no_delete.remove(&y.id);
+
+        let error = call
+            .try_process(
+                vec![(
+                    InputTensor::Owned(crate::op::Tensor::new(vec![1., 2.])),
+                    x.shape,
+                )],
+                &rustc_hash::FxHashMap::default(),
+            )
+            .unwrap_err();
+        assert_eq!(error, LuminalError::MissingSubgraphOutput(y.id));
+    }
+}
@@ -0,0 +1,556 @@
+use std::fmt::Write;
+
+use petgraph::algo::toposort;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    op::{
+        Add, Cast, Constant, ConstantValue, Contiguous, CumSum, Exp2, IndexAdd, LessThan, Log2,
+        MaxReduce, MinReduce, Mod, Mul, ProdReduce, Recip, Select, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// How the generated code gets at a node's output
+enum Value {
+    /// Held in a buffer
+    Buffer(String),
+    /// Worked out wherever it's read, from the index `z`
+    Inline(String),
+    /// A single number
+    Literal(String),
+}
+
+/// Rounding to lower precision formats, added to the source when the graph casts
+const ROUNDING: &str = "
+/// Round to the nearest value with `bits` bits of mantissa and exponents down to `min_exp`, ties to even
+#[allow(unused, clippy::all)]
+fn round_to(x: f32, bits: i32, min_exp: i32, max: f32) -> f32 {
+    if !x.is_finite() {
+        return x;
+    }
+    let a = x.abs();
+    let exp = (((a.to_bits() >> 23) & 0xff) as i32 - 127).max(min_exp);
+    let step = 2f32.powi(exp - bits);
+    let rounded = (a / step).round_ties_even() * step;
+    if rounded > max { f32::INFINITY } else { rounded }.copysign(x)
+}
+";
+
+impl Graph {
+    /// Generate a standalone Rust source file computing `outputs`, for deploying a model without luminal.
+    ///
+    /// The file has a single `forward` function, taking a slice for each declared input in the order they were declared and
+    /// returning the data of each output. Every shape is baked in, with dynamic dimensions taking their current values, and
+    /// weights are written into the source. Elementwise ops read by only one op are fused into the loop of that op, so
+    /// chains of elementwise ops, and the multiplies feeding a matmul's sum, don't get buffers of their own. Only primitive
+    /// ops are supported, so this needs to run before any backend compilers.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let x = cx.input::<R1<3>>("x");
+    /// let w = cx.tensor::<R2<3, 2>>().set([[1., 2.], [3., 4.], [5., 6.]]);
+    /// let y = x.matmul(w).relu();
+    /// let source = cx.to_rust_source(&[(y.id, y.shape)]).unwrap();
+    /// assert!(source.contains("pub fn forward(x: &[f32]) -> Vec<Vec<f32>>"));
+    /// ```
+    pub fn to_rust_source(
+        &mut self,
+        outputs: &[(NodeIndex, ShapeTracker)],
+    ) -> Result<String, LuminalError> {
+        // Only nodes the outputs depend on
+        let mut live = FxHashSet::default();
+        let mut stack = outputs.iter().map(|(n, _)| *n).collect::<Vec<_>>();
+        while let Some(node) = stack.pop() {
+            if live.insert(node) {
+                stack.extend(self.get_sources(node).into_iter().map(|(s, _, _)| s));
+            }
+        }
+        let order = toposort(&self.graph, None)
+            .unwrap()
+            .into_iter()
+            .filter(|n| live.contains(n))
+            .collect::<Vec<_>>();
+        // Every read of each node, to decide which ones can be fused into their consumer
+        let mut reads = FxHashMap::<NodeIndex, Vec<ShapeTracker>>::default();
+        for node in &order {
+            for (src, _, shape) in self.get_sources(*node) {
+                reads.entry(src).or_default().push(self.resolved(shape)?);
+            }
+        }
+        let output_ids = outputs.iter().map(|(n, _)| *n).collect::<FxHashSet<_>>();
+        let mut inputs = self
+            .inputs
+            .iter()
+            .map(|(name, (id, shape))| Ok((ident(name), *id, self.resolved(*shape)?)))
+            .collect::<Result<Vec<_>, LuminalError>>()?;
+        inputs.sort_by_key(|(_, id, _)| *id);
+
+        let mut body = String::new();
+        let mut statics = String::new();
+        let mut needs_rounding = false;
+        let mut values = FxHashMap::<(NodeIndex, u8), Value>::default();
+        for (name, id, shape) in &inputs {
+            let len = shape.n_elements().to_usize().unwrap();
+            writeln!(
+                body,
+                "    assert_eq!({name}.len(), {len}, \"{name} should have {len} elements\");"
+            )
+            .unwrap();
+            values.insert((*id, 0), Value::Buffer(name.clone()));
+        }
+        for node in &order {
+            if values.contains_key(&(*node, 0)) {
+                continue;
+            }
+            let op = self.node_weight(*node).unwrap().as_any();
+            let srcs = self
+                .get_sources(*node)
+                .into_iter()
+                .map(|(src, out, shape)| Ok(((src, out), self.resolved(shape)?)))
+                .collect::<Result<Vec<_>, LuminalError>>()?;
+            let src = |i: usize| read(&values, srcs[i].0, &srcs[i].1);
+            let buffer = format!("t{}", node.index());
+            let elementwise = if srcs.is_empty() {
+                if let Some(Constant(value)) = op.downcast_ref::<Constant>() {
+                    let value = match value {
+                        ConstantValue::Float(f) => *f,
+                        ConstantValue::Expression(e) => e.try_exec(&self.dyn_map)? as f32,
+                    };
+                    values.insert((*node, 0), Value::Literal(literal(value)));
+                    continue;
+                }
+                // Any other source is a weight, written into the source
+                let name = format!("{:?}", self.node_weight(*node).unwrap());
+                let tensors = match self.get_tensor_ref(*node, 0) {
+                    Some(t) => vec![t.clone()],
+                    None => self.graph.node_weight_mut(*node).unwrap().process(vec![]),
+                };
+                for (i, tensor) in tensors.iter().enumerate() {
+                    let data = tensor
+                        .as_f32_slice()
+                        .ok_or_else(|| LuminalError::NoCodegen(name.clone()))?;
+                    let weight = format!("W{}_{i}", node.index());
+                    writeln!(
+                        statics,
+                        "\n/// {name}\nstatic {weight}: [f32; {}] = [{}];",
+                        data.len(),
+                        data.iter()
+                            .map(|f| literal(*f))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                    .unwrap();
+                    values.insert((*node, i as u8), Value::Buffer(weight));
+                }
+                continue;
+            } else if op.is::<Contiguous>() {
+                Some(src(0))
+            } else if let Some(Cast(dtype)) = op.downcast_ref::<Cast>() {
+                needs_rounding |= *dtype != DType::F32;
+                Some(match dtype {
+                    DType::F32 => src(0),
+                    DType::F16 => format!("round_to({}, 10, -14, 65504.0)", src(0)),
+                    DType::BF16 => format!("round_to({}, 7, -126, f32::MAX)", src(0)),
+                    DType::U8 => format!("({}).round().clamp(0.0, 255.0)", src(0)),
+                })
+            } else if op.is::<Log2>() {
+                Some(format!("({}).log2()", src(0)))
+            } else if op.is::<Exp2>() {
+                Some(format!("({}).exp2()", src(0)))
+            } else if op.is::<Sin>() {
+                Some(format!("({}).sin()", src(0)))
+            } else if op.is::<Recip>() {
+                Some(format!("({}).recip()", src(0)))
+            } else if op.is::<Sqrt>() {
+                Some(format!("({}).sqrt()", src(0)))
+            } else if op.is::<Add>() {
+                Some(format!("({} + {})", src(0), src(1)))
+            } else if op.is::<Mul>() {
+                Some(format!("({} * {})", src(0), src(1)))
+            } else if op.is::<Mod>() {
+                Some(format!("({} % {})", src(0), src(1)))
+            } else if op.is::<LessThan>() {
+                Some(format!("(({} < {}) as i32 as f32)", src(0), src(1)))
+            } else if op.is::<Select>() {
+                Some(format!(
+                    "(if {} != 0.0 {{ {} }} else {{ 0.0 }})",
+                    src(1),
+                    src(0)
+                ))
+            } else {
+                None
+            };
+            if let Some(expr) = elementwise {
+                // Fuse into the only op reading this one, as long as it reads it straight through
+                let node_reads = reads.get(node).map(|r| r.as_slice()).unwrap_or_default();
+                if !output_ids.contains(node) && node_reads.len() == 1 && is_plain(&node_reads[0]) {
+                    values.insert((*node, 0), Value::Inline(expr));
+                    continue;
+                }
+                let len = srcs[0].1.n_elements().to_usize().unwrap();
+                writeln!(
+                    body,
+                    "    let mut {buffer} = vec![0.0f32; {len}];
+    for (i, out) in {buffer}.iter_mut().enumerate() {{
+        let z = i as i64;
+        *out = {expr};
+    }}"
+                )
+                .unwrap();
+            } else if let Some((dim, init, combine)) = reduction(op) {
+                let (front, size, back) = split_at_dim(&srcs[0].1, dim);
+                writeln!(
+                    body,
+                    "    let mut {buffer} = vec![0.0f32; {}];
+    for i in 0..{front} {{
+        for j in 0..{back} {{
+            let mut acc = {init};
+            for k in 0..{size} {{
+                let z = (i * {} + k * {back} + j) as i64;
+                {};
+            }}
+            {buffer}[i * {back} + j] = acc;
+        }}
+    }}",
+                    front * back,
+                    size * back,
+                    combine.replace("{}", &src(0))
+                )
+                .unwrap();
+            } else if let Some(CumSum(dim)) = op.downcast_ref::<CumSum>() {
+                let (front, size, back) = split_at_dim(&srcs[0].1, *dim);
+                writeln!(
+                    body,
+                    "    let mut {buffer} = vec![0.0f32; {}];
+    for i in 0..{front} {{
+        for k in 0..{size} {{
+            let row = i * {} + k * {back};
+            for j in 0..{back} {{
+                let z = (row + j) as i64;
+                let prev = if k == 0 {{ 0.0 }} else {{ {buffer}[row - {back} + j] }};
+                {buffer}[row + j] = prev + {};
+            }}
+        }}
+    }}",
+                    front * size * back,
+                    size * back,
+                    src(0)
+                )
+                .unwrap();
+            } else if let Some(IndexAdd(dim)) = op.downcast_ref::<IndexAdd>() {
+                let (front, size, back) = split_at_dim(&srcs[0].1, *dim);
+                let n_indexes = srcs[1].1.n_elements().to_usize().unwrap();
+                writeln!(
+                    body,
+                    "    let mut {buffer} = vec![0.0f32; {}];
+    for (i, out) in {buffer}.iter_mut().enumerate() {{
+        let z = i as i64;
+        *out = {};
+    }}
+    for k in 0..{n_indexes} {{
+        let target = {{
+            let z = k as i64;
+            {}
+        }};
+        if !target.is_finite() || target.fract() != 0.0 || target < 0.0 || target as usize >= {size} {{
+            continue;
+        }}
+        for i in 0..{front} {{
+            for j in 0..{back} {{
+                let z = (i * {} + k * {back} + j) as i64;
+                {buffer}[i * {} + target as usize * {back} + j] += {};
+            }}
+        }}
+    }}",
+                    front * size * back,
+                    src(0),
+                    src(1),
+                    n_indexes * back,
+                    size * back,
+                    src(2)
+                )
+                .unwrap();
+            } else {
+                return Err(LuminalError::NoCodegen(format!(
+                    "{:?}",
+                    self.node_weight(*node).unwrap()
+                )));
+            }
+            values.insert((*node, 0), Value::Buffer(buffer));
+        }
+
+        // Outputs come back contiguous, with their views applied
+        let mut returned = vec![];
+        for (i, (id, shape)) in outputs.iter().enumerate() {
+            let shape = self.resolved(*shape)?;
+            writeln!(
+                body,
+                "    let out{i} = (0..{})
+        .map(|i| {{
+            let z = i as i64;
+            {}
+        }})
+        .collect::<Vec<f32>>();",
+                shape.n_elements().to_usize().unwrap(),
+                read(&values, (*id, 0), &shape)
+            )
+            .unwrap();
+            returned.push(format!("out{i}"));
+        }
+
+        let mut source = "// Generated by luminal\n".to_string();
+        if needs_rounding {
+            source.push_str(ROUNDING);
+        }
+        source.push_str(&statics);
+        writeln!(
+            source,
+            "
+/// Run the model. Inputs are {}.
+#[allow(unused, clippy::all)]
+pub fn forward({}) -> Vec<Vec<f32>> {{
+{body}    vec![{}]
+}}",
+            if inputs.is_empty() {
+                "not needed".to_string()
+            } else {
+                inputs
+                    .iter()
+                    .map(|(name, _, shape)| format!("`{name}` {:?}", shape.shape_usize()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            },
+            inputs
+                .iter()
+                .map(|(name, _, _)| format!("{name}: &[f32]"))
+                .collect::<Vec<_>>()
+                .join(", "),
+            returned.join(", ")
+        )
+        .unwrap();
+        Ok(source)
+    }
+
+    /// A shape with every dynamic dimension filled in from the dyn map
+    fn resolved(&self, mut shape: ShapeTracker) -> Result<ShapeTracker, LuminalError> {
+        shape.try_resolve_global_dyn_dims_stack(&self.dyn_map, &mut vec![])?;
+        Ok(shape)
+    }
+}
+
+/// Code reading element `z` of a node's output through a view
+fn read(
+    values: &FxHashMap<(NodeIndex, u8), Value>,
+    src: (NodeIndex, u8),
+    shape: &ShapeTracker,
+) -> String {
+    let (index, valid) = (
+        expr_to_rust(&shape.index_expression()),
+        expr_to_rust(&shape.valid_expression()),
+    );
+    let value = match &values[&src] {
+        Value::Buffer(name) => format!("{name}[({index}) as usize]"),
+        // Only fused when it's read straight through
+        Value::Inline(expr) => expr.clone(),
+        Value::Literal(l) => l.clone(),
+    };
+    if valid == "1" {
+        value
+    } else {
+        format!("(if {valid} != 0 {{ {value} }} else {{ 0.0 }})")
+    }
+}
+
+/// Whether a view reads every element in order, with nothing padded or masked out
+fn is_plain(shape: &ShapeTracker) -> bool {
+    expr_to_rust(&shape.index_expression()) == "z" && expr_to_rust(&shape.valid_expression()) == "1"
+}
+
+/// The dimension a reduce op works on, its starting value, and how it folds in an element
+fn reduction(op: &dyn std::any::Any) -> Option<(usize, &'static str, &'static str)> {
+    if let Some(SumReduce(dim)) = op.downcast_ref::<SumReduce>() {
+        Some((*dim, "0.0f32", "acc += {}"))
+    } else if let Some(MaxReduce(dim)) = op.downcast_ref::<MaxReduce>() {
+        Some((*dim, "f32::NEG_INFINITY", "acc = acc.max({})"))
+    } else if let Some(MinReduce(dim)) = op.downcast_ref::<MinReduce>() {
+        Some((*dim, "f32::INFINITY", "acc = acc.min({})"))
+    } else if let Some(ProdReduce(dim)) = op.downcast_ref::<ProdReduce>() {
+        Some((*dim, "1.0f32", "acc *= {}"))
+    } else {
+        None
+    }
+}
+
+/// Sizes before, along and after a dimension
+fn split_at_dim(shape: &ShapeTracker, dim: usize) -> (usize, usize, usize) {
+    let sh = shape.shape_usize();
+    (
+        sh.iter().take(dim).product::<usize>().max(1),
+        sh[dim],
+        sh.iter().skip(dim + 1).product::<usize>().max(1),
+    )
+}
+
+fn literal(f: f32) -> String {
+    if f.is_nan() {
+        "f32::NAN".to_string()
+    } else if f.is_infinite() {
+        if f > 0. {
+            "f32::INFINITY"
+        } else {
+            "f32::NEG_INFINITY"
+        }
+        .to_string()
+    } else {
+        format!("{f:?}f32")
+    }
+}
+
+/// An input name as a Rust identifier
+fn ident(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if name.starts_with(|c: char| c.is_ascii_digit()) || name.is_empty() {
+        format!("input_{name}")
+    } else {
+        name
+    }
+}
+
+/// Write an index expression as Rust working in i64, where `z` is the index
+fn expr_to_rust(expr: &BigExpression) -> String {
+    let mut symbols = vec![];
+    for term in expr.terms.iter() {
+        let new_symbol = match term {
+            Term::Num(n) => n.to_string(),
+            Term::Var(c) => c.to_string(),
+            Term::Max => format!(
+                "i64::max({}, {})",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
+            Term::Min => format!(
+                "i64::min({}, {})",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
+            Term::Lt | Term::Gte => format!(
+                "(({} {term:?} {}) as i64)",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
+            Term::And | Term::Or => format!(
+                "((({} != 0) {term:?} ({} != 0)) as i64)",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
+            _ => format!(
+                "({} {term:?} {})",
+                symbols.pop().unwrap(),
+                symbols.pop().unwrap()
+            ),
+        };
+        symbols.push(new_symbol);
+    }
+    symbols.pop().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    crate::test_imports!();
+
+    /// Compile generated source with a main printing the outputs, and run it
+    fn run_source(source: &str, args: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        let dir = std::env::temp_dir().join(format!("luminal_codegen_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let main = format!(
+            "{source}\nfn main() {{\n    for out in forward({}) {{\n        println!(\"{{:?}}\", out);\n    }}\n}}\n",
+            args.iter()
+                .map(|a| format!("&{a:?}"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        std::fs::write(dir.join("model.rs"), main).unwrap();
+        let compiled = Command::new("rustc")
+            .args(["--edition", "2021", "-O", "-o"])
+            .arg(dir.join("model"))
+            .arg(dir.join("model.rs"))
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+        let run = Command::new(dir.join("model")).output().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        String::from_utf8(run.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                l.trim_matches(|c| c == '[' || c == ']')
+                    .split(", ")
+                    .map(|f| f.parse().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_rust_source() {
+        let mut cx = Graph::new();
+        let x = cx.input::<(Dyn<'b'>, LConst<3>)>("x");
+        let w = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let probs = x.matmul(w).softmax::<LAxis<1>>().retrieve();
+        let scan = (x.cumsum::<LAxis<1>>().cast::<f16>() + 1.)
+            .max_reduce::<_, LAxis<0>>()
+            .retrieve();
+        let padded = x
+            .slice((.., 1..))
+            .pad::<(Dyn<'b'>, LConst<4>)>(((0, 0), (1, 1)))
+            .sqrt()
+            .retrieve();
+        let input = random_vec(6)
+            .into_iter()
+            .map(|f| f + 0.5)
+            .collect::<Vec<_>>();
+        cx.bind("x", input.clone(), &[2, 3]).unwrap();
+        cx.execute();
+
+        let outputs = [
+            (probs.id, probs.shape),
+            (scan.id, scan.shape),
+            (padded.id, padded.shape),
+        ];
+        let source = cx.to_rust_source(&outputs).unwrap();
+        // The multiplies of the matmul are fused into its sum
+        assert_eq!(source.matches("vec![0.0f32").count(), 9);
+        let out = run_source(&source, &[input]);
+        assert_exact(&out[0], &probs.data());
+        assert_exact(&out[1], &scan.data());
+        assert_exact(&out[2], &padded.data());
+
+        // Backend ops can't be written out
+        let y = cx
+            .add_op(Transfer { from: 0, to: 1 })
+            .input(x.id, 0, x.shape)
+            .finish();
+        assert_eq!(
+            cx.to_rust_source(&[(y, x.shape)]),
+            Err(LuminalError::NoCodegen(
+                "Transfer { from: 0, to: 1 }".to_string()
+            ))
+        );
+    }
+}
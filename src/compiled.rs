@@ -0,0 +1,618 @@
+use std::{panic::Location, sync::Mutex};
+
+use itertools::Itertools;
+use petgraph::Direction;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    graph::{check_input, run_op},
+    op::{Constant, ConstantValue, Function, InputTensor},
+    prelude::*,
+};
+
+/// A graph that's done being built and compiled, ready to serve.
+///
+/// The graph can't be changed anymore, only ran. Each run takes its own inputs and hands back its own outputs, so one
+/// compiled model can be shared between request threads. It's `Send + Sync` because every op it holds is `Send` and
+/// only ran behind its own lock, and tensor data is always `Send + Sync`.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let x = cx.tensor::<(Dyn<'s'>, Const<2>)>();
+/// let w = cx.tensor::<R2<2, 2>>().set([[1., 2.], [3., 4.]]).keep();
+/// let y = x.matmul(w);
+/// let model = cx.freeze(GenericCompiler::default(), &[x.no_shape()], &[y.no_shape()]);
+///
+/// let out = model.run(&[(&[1., 1.], &[1, 2])]);
+/// assert_eq!(out[0], vec![4., 6.]);
+/// ```
+pub struct CompiledGraph {
+    /// The ops that depend on the inputs, in the order they run
+    steps: Vec<Step>,
+    /// Outputs of every node that doesn't depend on the inputs (like weights), computed once when frozen and shared by every run
+    frozen: FxHashMap<(NodeIndex, u8), Tensor>,
+    /// How many times each tensor produced during a run gets consumed
+    consumers: FxHashMap<(NodeIndex, u8), usize>,
+    /// Each input's name, node and declared shape
+    inputs: Vec<(String, NodeIndex, ShapeTracker)>,
+    outputs: Vec<(NodeIndex, ShapeTracker)>,
+    /// Whether every op's outputs get checked for NaNs and infinities, from the graph's `nan_guard`
+    nan_guard: bool,
+}
+
+struct Step {
+    node: NodeIndex,
+    location: Option<&'static Location<'static>>,
+    /// Each op has its own lock, so runs only wait on each other when they reach the same op at the same time
+    op: Mutex<Box<dyn Operator + Send>>,
+    srcs: Vec<(NodeIndex, u8, ShapeTracker)>,
+}
+
+/// The state of a single run: the tensors it has produced so far and its dynamic dimensions
+#[derive(Default)]
+struct RunState {
+    tensors: FxHashMap<(NodeIndex, u8), Tensor>,
+    dyn_map: FxHashMap<char, usize>,
+}
+
+impl Graph {
+    /// Compile the graph and freeze it for serving.
+    ///
+    /// `inputs` are the tensors fed in on each run, and `outputs` are the tensors handed back, both in order. Everything
+    /// that doesn't depend on the inputs, like loading weights, is computed once here. Tensor handles from this graph can't
+    /// be used afterwards.
+    ///
+    /// Panics if an op the outputs depend on can't be moved between threads (see `Operator::into_send`).
+    pub fn freeze<C: Compiler>(
+        mut self,
+        compiler: C,
+        inputs: &[GraphTensor<()>],
+        outputs: &[GraphTensor<()>],
+    ) -> CompiledGraph {
+        // Inputs go by their declared name, or the name of the tensor
+        let declared = |t: &GraphTensor<()>| self.inputs.values().any(|(id, _)| *id == t.id);
+        let mut names = inputs
+            .iter()
+            .map(|t| {
+                self.inputs
+                    .iter()
+                    .find(|(_, (id, _))| *id == t.id)
+                    .map(|(name, _)| name.clone())
+                    .or_else(|| {
+                        self.try_get_op::<Function>(t.id)
+                            .map(|f| f.0.trim_end_matches(" Load").to_string())
+                    })
+                    .unwrap_or_else(|| format!("Input {}", t.id.index()))
+            })
+            .collect::<Vec<_>>();
+        // Only declared names are unique (every `cx.tensor` is named "Tensor"), so repeated ones go by their node instead
+        let repeated = names.iter().duplicates().cloned().collect::<FxHashSet<_>>();
+        for (name, t) in names.iter_mut().zip(inputs) {
+            if repeated.contains(name) && !declared(t) {
+                *name = format!("Input {}", t.id.index());
+            }
+        }
+        let mut ids = inputs
+            .iter()
+            .chain(outputs)
+            .map(|t| t.id)
+            .collect::<Vec<_>>();
+        self.keep_tensors(&ids);
+        self.compile(compiler, &mut ids);
+        self.toposort();
+        let (input_ids, output_ids) = ids.split_at(inputs.len());
+
+        // Only nodes the outputs depend on need to run
+        let mut live = output_ids.iter().copied().collect::<FxHashSet<_>>();
+        for (node, srcs) in self.linearized_graph.as_ref().unwrap().iter().rev() {
+            if live.contains(node) {
+                live.extend(srcs.iter().map(|(s, _, _)| *s));
+            }
+        }
+
+        // A node is dynamic if it's fed by an input, reads or produces something shaped by a dynamic dimension, or depends
+        // on one that is. Ops only get the dimensions of a run while they run, so sources like aranges count by their output.
+        let mut dynamic = FxHashSet::default();
+        for (node, srcs) in self.linearized_graph.as_ref().unwrap() {
+            let reads_dims = srcs.iter().any(|(_, _, st)| has_symbols(st))
+                || self
+                    .edges_directed(*node, Direction::Outgoing)
+                    .filter_map(|e| e.weight().as_data())
+                    .any(|(_, _, st)| has_symbols(&st))
+                || matches!(
+                    self.try_get_op::<Constant>(*node),
+                    Some(Constant(ConstantValue::Expression(_)))
+                );
+            if input_ids.contains(node)
+                || reads_dims
+                || srcs.iter().any(|(s, _, _)| dynamic.contains(s))
+            {
+                dynamic.insert(*node);
+            }
+        }
+
+        // Pull the ops out of the graph
+        let mut steps = vec![];
+        let mut static_steps = vec![];
+        for (node, srcs) in self.linearized_graph.take().unwrap() {
+            if !live.contains(&node) {
+                continue;
+            }
+            let op = self.graph.remove_node(node).unwrap();
+            let name = format!("{op:?}");
+            let step = Step {
+                node,
+                location: self.location(node),
+                op: Mutex::new(op.into_send().unwrap_or_else(|| {
+                    panic!("{name} can't be moved between threads, so it can't be frozen")
+                })),
+                srcs,
+            };
+            if dynamic.contains(&node) {
+                steps.push(step);
+            } else {
+                static_steps.push(step);
+            }
+        }
+
+        // Run the static part once, holding on to what the dynamic part and the outputs need
+        let needed = steps
+            .iter()
+            .flat_map(|s| s.srcs.iter().map(|(n, i, _)| (*n, *i)))
+            .chain(output_ids.iter().map(|o| (*o, 0)))
+            .collect::<FxHashSet<_>>();
+        let mut state = RunState {
+            dyn_map: self.dyn_map.clone(),
+            ..Default::default()
+        };
+        run_steps(
+            &static_steps,
+            &FxHashMap::default(),
+            &consumer_counts(&static_steps),
+            &needed,
+            self.nan_guard,
+            &mut state,
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+        state.tensors.retain(|k, _| needed.contains(k));
+
+        CompiledGraph {
+            consumers: consumer_counts(&steps),
+            steps,
+            frozen: state.tensors,
+            inputs: names
+                .into_iter()
+                .zip(input_ids)
+                .zip(inputs)
+                .map(|((name, i), t)| (name, *i, t.shape))
+                .collect(),
+            outputs: output_ids
+                .iter()
+                .zip(outputs)
+                .map(|(i, t)| (*i, t.shape))
+                .collect(),
+            nan_guard: self.nan_guard,
+        }
+    }
+}
+
+fn has_symbols(st: &ShapeTracker) -> bool {
+    st.dims
+        .iter()
+        .chain(st.mask.iter().flat_map(|(a, b)| [a, b]))
+        .chain(st.padding.iter().flat_map(|(a, b)| [a, b]))
+        .any(|e| !e.to_symbols().is_empty())
+}
+
+fn consumer_counts(steps: &[Step]) -> FxHashMap<(NodeIndex, u8), usize> {
+    let mut consumers = FxHashMap::default();
+    for (n, i, _) in steps.iter().flat_map(|s| &s.srcs) {
+        *consumers.entry((*n, *i)).or_default() += 1;
+    }
+    consumers
+}
+
+/// Run each step that hasn't produced its output yet, freeing tensors after their last consumer unless they're `keep`
+fn run_steps(
+    steps: &[Step],
+    frozen: &FxHashMap<(NodeIndex, u8), Tensor>,
+    consumers: &FxHashMap<(NodeIndex, u8), usize>,
+    keep: &FxHashSet<(NodeIndex, u8)>,
+    nan_guard: bool,
+    state: &mut RunState,
+) -> Result<(), LuminalError> {
+    #[cfg(feature = "tracing")]
+    let _span = crate::trace::execute_span();
+    let mut consumers = consumers.clone();
+    let mut dim_stack = vec![];
+    for step in steps {
+        if state.tensors.contains_key(&(step.node, 0)) {
+            continue;
+        }
+        let mut op = step.op.lock().unwrap();
+        // Tensors on their last use are handed over, the rest are borrowed
+        let mut owned = step
+            .srcs
+            .iter()
+            .map(|(n, i, _)| {
+                let remaining = consumers.get_mut(&(*n, *i)).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 && !keep.contains(&(*n, *i)) {
+                    state.tensors.remove(&(*n, *i))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let srcs = step
+            .srcs
+            .iter()
+            .zip(owned.iter_mut())
+            .map(|((n, i, st), owned)| {
+                let mut st = *st;
+                st.resolve_global_dyn_dims_stack(&state.dyn_map, &mut dim_stack);
+                let tensor = match owned.take() {
+                    Some(t) => InputTensor::Owned(t),
+                    None => InputTensor::Borrowed(
+                        frozen
+                            .get(&(*n, *i))
+                            .or_else(|| state.tensors.get(&(*n, *i)))
+                            .unwrap(),
+                    ),
+                };
+                (tensor, st)
+            })
+            .collect::<Vec<_>>();
+        let outputs = run_op(
+            step.node,
+            op.as_mut(),
+            srcs,
+            &state.dyn_map,
+            step.location,
+            nan_guard,
+        )?;
+        for (i, tensor) in outputs.into_iter().enumerate() {
+            state.tensors.insert((step.node, i as u8), tensor);
+        }
+    }
+    Ok(())
+}
+
+impl CompiledGraph {
+    pub fn num_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn num_outputs(&self) -> usize {
+        self.outputs.len()
+    }
+
+    /// Names of the inputs, in the order they were declared. Inputs that weren't declared with `Graph::input` go by their
+    /// tensor's name, or `Input <node index>` if another input has the same one.
+    pub fn input_names(&self) -> impl Iterator<Item = &str> {
+        self.inputs.iter().map(|(name, _, _)| name.as_str())
+    }
+
+    /// Start a run, to bind inputs to by name
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let x = cx.input::<(Dyn<'s'>,)>("x");
+    /// let y = x * 2.;
+    /// let model = cx.freeze((), &[x.no_shape()], &[y.no_shape()]);
+    /// let out = model.context().with_input("x", vec![1., 2.], &[2]).run().unwrap();
+    /// assert_eq!(out[0], vec![2., 4.]);
+    /// ```
+    pub fn context(&self) -> ExecutionContext<'_> {
+        ExecutionContext {
+            model: self,
+            state: RunState::default(),
+            error: None,
+        }
+    }
+
+    /// Run the graph on a set of inputs, given as `(data, shape)` in the order they were declared. Returns the data of each
+    /// output, in order. Any number of runs can happen at once from different threads.
+    pub fn run(&self, inputs: &[(&[f32], &[usize])]) -> Vec<Vec<f32>> {
+        assert_eq!(
+            inputs.len(),
+            self.inputs.len(),
+            "Expected {} inputs, got {}",
+            self.inputs.len(),
+            inputs.len()
+        );
+        self.inputs
+            .iter()
+            .zip(inputs)
+            .fold(self.context(), |cx, (input, (data, shape))| {
+                cx.bind(input, data.to_vec(), shape)
+            })
+            .run()
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+}
+
+/// The inputs to a single run of a `CompiledGraph`
+pub struct ExecutionContext<'a> {
+    model: &'a CompiledGraph,
+    state: RunState,
+    /// The first problem binding inputs, reported when ran
+    error: Option<LuminalError>,
+}
+
+impl ExecutionContext<'_> {
+    /// Bind data to an input by name. Dynamic dimensions get set from the shape.
+    pub fn with_input(mut self, name: &str, data: Vec<f32>, shape: &[usize]) -> Self {
+        match self.model.inputs.iter().find(|(n, _, _)| n == name) {
+            Some(input) => self.bind(input, data, shape),
+            None => {
+                self.error
+                    .get_or_insert(LuminalError::UnknownInput(name.to_string()));
+                self
+            }
+        }
+    }
+
+    fn bind(
+        mut self,
+        (name, id, declared): &(String, NodeIndex, ShapeTracker),
+        data: Vec<f32>,
+        shape: &[usize],
+    ) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        match check_input(name, declared, &data, shape, &mut self.state.dyn_map) {
+            Ok(()) => {
+                self.state.tensors.insert((*id, 0), Tensor::new(data));
+            }
+            Err(e) => self.error = Some(e),
+        }
+        self
+    }
+
+    /// Run the graph once every input is bound. Returns the data of each output, in order.
+    pub fn run(mut self) -> Result<Vec<Vec<f32>>, LuminalError> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        let model = self.model;
+        if let Some((name, _, _)) = model
+            .inputs
+            .iter()
+            .find(|(_, id, _)| !self.state.tensors.contains_key(&(*id, 0)))
+        {
+            return Err(LuminalError::UnboundInput(name.clone()));
+        }
+        let keep = model.outputs.iter().map(|(id, _)| (*id, 0)).collect();
+        run_steps(
+            &model.steps,
+            &model.frozen,
+            &model.consumers,
+            &keep,
+            model.nan_guard,
+            &mut self.state,
+        )?;
+        Ok(model
+            .outputs
+            .iter()
+            .map(|(id, shape)| {
+                let tensor = model
+                    .frozen
+                    .get(&(*id, 0))
+                    .or_else(|| self.state.tensors.get(&(*id, 0)))
+                    .unwrap_or_else(|| panic!("Output {} wasn't computed", id.index()));
+                let mut shape = *shape;
+                shape.resolve_global_dyn_dims(&self.state.dyn_map);
+                HostTensorView::new(tensor.widened_f32s().unwrap(), shape).to_vec()
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use crate::op::Tensor;
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_compiled_graph_threads() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<(Dyn<'s'>, LConst<3>)>();
+        let w = cx.tensor::<R2<3, 2>>().set(random_vec(6)).keep();
+        let y = (x.matmul(w) * 2.).retrieve();
+        // Averaging over the sequence reads each run's own sequence length
+        let pooled = y.mean_reduce::<_, LAxis<0>>();
+        // Reference results from the unfrozen graph
+        let inputs = (1..=4).map(|n| random_vec(n * 3)).collect::<Vec<_>>();
+        let expected = inputs
+            .iter()
+            .map(|data| {
+                x.set_dyn(data.clone(), &[data.len() / 3, 3]);
+                pooled.retrieve();
+                cx.execute();
+                let out = (y.data(), pooled.data());
+                y.drop();
+                pooled.drop();
+                out
+            })
+            .collect::<Vec<_>>();
+
+        let model = Arc::new(cx.freeze(
+            GenericCompiler::default(),
+            &[x.no_shape()],
+            &[y.no_shape(), pooled.no_shape()],
+        ));
+        std::thread::scope(|s| {
+            for (data, (y, pooled)) in inputs.iter().zip(&expected) {
+                let model = model.clone();
+                s.spawn(move || {
+                    for _ in 0..3 {
+                        let out = model.run(&[(data, &[data.len() / 3, 3])]);
+                        assert_exact(&out[0], y);
+                        assert_exact(&out[1], pooled);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_compiled_graph_loads_once() {
+        let mut cx = Graph::new();
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counter = loads.clone();
+        let w = cx.named_tensor::<R1<2>>("Weight");
+        cx.get_op_mut::<Function>(w.id).1 = Box::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            vec![Tensor::new(vec![1., 2.])]
+        });
+        let x = cx.tensor::<R1<2>>();
+        let y = (x * w.exp()).retrieve();
+        // Not an output, so this never runs
+        cx.tensor::<R1<2>>().exp().retrieve();
+        let model = cx.freeze((), &[x.no_shape()], &[y.no_shape()]);
+        for i in 0..3 {
+            let out = model.run(&[(&[i as f32, 1.], &[2])]);
+            assert_close(&out[0], &[i as f32 * 1_f32.exp(), 2_f32.exp()]);
+        }
+
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    /// Holds on to something that can't leave its thread
+    #[derive(Debug)]
+    struct Local(std::rc::Rc<f32>);
+
+    impl Operator for Local {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            vec![Tensor::new(
+                inp[0]
+                    .0
+                    .borrowed()
+                    .f32s()
+                    .unwrap()
+                    .iter()
+                    .map(|v| v * *self.0)
+                    .collect::<Vec<_>>(),
+            )]
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Local(2.0) can't be moved between threads, so it can't be frozen")]
+    fn test_freeze_rejects_ops_tied_to_a_thread() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R1<3>>();
+        let y = cx
+            .add_op(Local(std::rc::Rc::new(2.)))
+            .input(x.id, 0, x.shape)
+            .finish();
+        let y = GraphTensor::<R1<3>>::from_id(y, x.shape, &mut cx).retrieve();
+        cx.freeze((), &[x.no_shape()], &[y.no_shape()]);
+    }
+
+    #[test]
+    fn test_execution_context() {
+        let mut cx = Graph::new();
+        let ids = cx.input::<(Dyn<'s'>,)>("input_ids");
+        let mask = cx.input::<(Dyn<'s'>,)>("mask");
+        let out = ids * mask;
+        let model = cx.freeze((), &[ids.no_shape(), mask.no_shape()], &[out.no_shape()]);
+        assert_eq!(
+            model.input_names().collect::<Vec<_>>(),
+            ["input_ids", "mask"]
+        );
+
+        // Inputs can be bound in any order
+        let run = model
+            .context()
+            .with_input("mask", vec![1., 0., 1.], &[3])
+            .with_input("input_ids", vec![4., 5., 6.], &[3])
+            .run();
+        assert_eq!(run, Ok(vec![vec![4., 0., 6.]]));
+
+        assert_eq!(
+            model
+                .context()
+                .with_input("input_ids", vec![1.], &[1])
+                .run(),
+            Err(LuminalError::UnboundInput("mask".to_string()))
+        );
+        assert_eq!(
+            model.context().with_input("tokens", vec![1.], &[1]).run(),
+            Err(LuminalError::UnknownInput("tokens".to_string()))
+        );
+        let err = model
+            .context()
+            .with_input("input_ids", vec![1., 2.], &[3])
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Input input_ids was given shape [3], expected [s]"
+        );
+    }
+
+    #[test]
+    fn test_unnamed_inputs() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>();
+        let b = cx.tensor::<R1<2>>();
+        let c = cx.named_tensor::<R1<2>>("c");
+        let out = a + b * 2. + c * 3.;
+        let model = cx.freeze(
+            (),
+            &[a.no_shape(), b.no_shape(), c.no_shape()],
+            &[out.no_shape()],
+        );
+        let names = model.input_names().collect::<Vec<_>>();
+        assert_eq!(names[2], "c");
+        assert_ne!(names[0], names[1]);
+
+        let out = model.run(&[(&[1., 2.], &[2]), (&[3., 4.], &[2]), (&[5., 6.], &[2])]);
+        assert_eq!(out[0], vec![22., 28.]);
+        let out = model
+            .context()
+            .with_input(names[1], vec![3., 4.], &[2])
+            .with_input(names[0], vec![1., 2.], &[2])
+            .with_input("c", vec![0., 0.], &[2])
+            .run();
+        assert_eq!(out, Ok(vec![vec![7., 10.]]));
+    }
+
+    #[test]
+    fn test_compiled_graph_nan_guard() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<(Dyn<'s'>,)>();
+        let y = x.log2().retrieve();
+        cx.set_nan_guard(true);
+        let model = cx.freeze((), &[x.no_shape()], &[y.no_shape()]);
+        assert_eq!(model.run(&[(&[1., 2.], &[2])]), vec![vec![0., 1.]]);
+        let err = model
+            .context()
+            .with_input("Tensor", vec![1., -2.], &[2])
+            .run()
+            .unwrap_err();
+        assert!(
+            matches!(err, LuminalError::NonFinite { node, index: 1, .. } if node == y.id),
+            "Expected a NaN error, got {err}"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Input Tensor was given shape [4], expected [3]")]
+    fn test_compiled_graph_checks_shapes() {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<R1<3>>();
+        let y = (x * 2.).retrieve();
+        let model = cx.freeze((), &[x.no_shape()], &[y.no_shape()]);
+        model.run(&[(&[1., 2., 3., 4.], &[4])]);
+    }
+}
@@ -6,7 +6,7 @@ use colored::Colorize;
 use itertools::Itertools;
 use petgraph::{
     algo::toposort,
-    stable_graph::{EdgeIndex, EdgeReference, StableGraph},
+    stable_graph::{EdgeIndex, StableGraph},
     visit::EdgeRef,
     Direction,
 };
@@ -16,6 +16,12 @@ use uuid::Uuid;
 
 use crate::prelude::*;
 
+// std's clock isn't available in the browser
+#[cfg(target_arch = "wasm32")]
+use instant::Instant;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
 pub trait ToIdsMut {
     fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex>;
 }
@@ -218,7 +224,7 @@ impl<C: Compiler + Debug> Compiler for Timed<C> {
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, remap: T) {
         let compiler_name = format!("{:?}", self.0).bold();
         println!("Starting {compiler_name}");
-        let start = std::time::Instant::now();
+        let start = Instant::now();
         self.0.compile(graph, remap);
         let finished_millis = start.elapsed().as_millis();
         let minutes = finished_millis / 60_000;
@@ -252,7 +258,14 @@ macro_rules! tuple_impls {
         > Compiler for ($($name,)+) {
             type Output = ( $($name::Output, )+ );
             fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut remap: T) -> Self::Output {
-                ( $(self.$idx.compile(graph, &mut remap), )+ )
+                ( $({
+                    #[cfg(feature = "tracing")]
+                    let _span = crate::trace::pass_span::<$name>();
+                    let recorded = crate::debug::recorded_passes(graph);
+                    let output = self.$idx.compile(graph, &mut remap);
+                    crate::debug::record_pass(graph, crate::debug::pass_name::<$name>, recorded);
+                    output
+                }, )+ )
             }
         }
     };
@@ -330,19 +343,24 @@ impl Graph {
     ///     .finish();
     /// let b = GraphTensor::<R1<3>>::from_id(b_id, a.shape, a.graph());
     /// ```
-    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp {
-        self.linearized_graph = None;
-        NewOp {
-            new_op_id: self.graph.add_node(Box::new(op)),
-            graph_ref: self,
-            num_srcs: 0,
-        }
+    #[track_caller]
+    pub fn add_op<O: Operator + 'static>(&mut self, op: O) -> NewOp<'_> {
+        self.add_boxed_op(Box::new(op))
     }
     /// Add op on the graph, and get back a NewOp. Just like add_op, except a boxed op is expected.
-    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp {
+    #[track_caller]
+    pub fn add_boxed_op(&mut self, op: Box<dyn Operator + 'static>) -> NewOp<'_> {
         self.linearized_graph = None;
+        let new_op_id = self.graph.add_node(op);
+        // Removed nodes' indexes get reused, so drop anything inferred for the old one
+        self.shapes.remove(&new_op_id);
+        self.locations
+            .insert(new_op_id, std::panic::Location::caller());
+        if let Some(device) = self.current_device {
+            self.devices.insert(new_op_id, device);
+        }
         NewOp {
-            new_op_id: self.graph.add_node(op),
+            new_op_id,
             graph_ref: self,
             num_srcs: 0,
         }
@@ -377,8 +395,8 @@ impl Graph {
     ) {
         let mut new_graph = StableGraph::default();
         let mut id_map = FxHashMap::default();
-        for (id, node) in self.graph.node_indices().zip(self.graph.node_weights()) {
-            id_map.insert(id, new_graph.add_node(format!("{node:?} | {}", id.index())));
+        for id in self.graph.node_indices() {
+            id_map.insert(id, new_graph.add_node(self.node_label(id)));
         }
 
         let mut schedule_edges = vec![];
@@ -428,13 +446,34 @@ impl Graph {
         (new_graph, schedule_edges, id_map)
     }
 
-    pub fn check_node_type<T: Operator + 'static>(&self, node: NodeIndex) -> bool {
+    /// Check if a node's op is a T. Ops are matched by type, so a custom op can't be mistaken for a primitive with the same name
+    pub fn is_op<T: Operator + 'static>(&self, node: NodeIndex) -> bool {
         self.node_weight(node)
             .expect("Node not found in graph!")
             .as_any()
             .is::<T>()
     }
 
+    /// Same as `is_op`
+    pub fn check_node_type<T: Operator + 'static>(&self, node: NodeIndex) -> bool {
+        self.is_op::<T>(node)
+    }
+
+    /// Op, index and build location of a node, as shown in debug output
+    pub fn node_label(&self, node: NodeIndex) -> String {
+        let mut label = format!("{:?} | {}", self.node_weight(node).unwrap(), node.index());
+        if let Some(location) = self.location(node) {
+            label.push_str(&format!(" @ {}:{}", location.file(), location.line()));
+        }
+        label
+    }
+
+    /// The graph in Graphviz DOT format, with each node labelled by its op, index and where it was built
+    pub fn to_dot(&self) -> String {
+        let (g, e, _) = self.debug_graph(false);
+        graph_to_dot(&g, &e, &[])
+    }
+
     pub fn display(&self) {
         let (g, e, _) = self.debug_graph(false);
         display_graph(&g, &e, &[]);
@@ -467,7 +506,7 @@ impl Graph {
         }
     }
 
-    /// Get the sources of a node given it's id
+    /// Get the sources of a node given it's id, sorted by input index
     pub fn get_sources(&self, node_id: NodeIndex) -> Vec<(NodeIndex, u8, ShapeTracker)> {
         self.graph
             .edges_directed(node_id, Direction::Incoming)
@@ -477,6 +516,48 @@ impl Graph {
             .collect()
     }
 
+    /// Estimated cost of running a node once, with dynamic dimensions filled in from the dyn map. None if the op has no
+    /// estimate or its inputs use dimensions that aren't set yet.
+    pub fn op_cost(&self, node_id: NodeIndex) -> Option<OpCost> {
+        self.node_weight(node_id)?
+            .cost(&self.resolved_source_shapes(node_id)?)
+    }
+
+    /// Input shapes of a node with dynamic dimensions filled in, or None if any of them aren't set
+    pub(crate) fn resolved_source_shapes(&self, node_id: NodeIndex) -> Option<Vec<ShapeTracker>> {
+        let mut stack = vec![];
+        self.get_sources(node_id)
+            .into_iter()
+            .map(|(_, _, mut st)| {
+                st.try_resolve_global_dyn_dims_stack(&self.dyn_map, &mut stack)
+                    .ok()
+                    .map(|_| st)
+            })
+            .collect()
+    }
+
+    /// Get the sources of a node, making sure there's exactly one source for each input index from 0 to n.
+    /// The nth element is always the nth input, so non-commutative ops get their inputs in the order they were built with.
+    pub fn get_sources_ordered(
+        &self,
+        node_id: NodeIndex,
+    ) -> Result<Vec<(NodeIndex, u8, ShapeTracker)>, LuminalError> {
+        let mut orders = self
+            .graph
+            .edges_directed(node_id, Direction::Incoming)
+            .filter_map(|e| e.weight().as_data().map(|(i, _, _)| i))
+            .collect::<Vec<_>>();
+        orders.sort();
+        if orders.iter().enumerate().any(|(i, o)| *o as usize != i) {
+            return Err(LuminalError::BadInputOrder {
+                node: node_id,
+                op: format!("{:?}", self.node_weight(node_id).unwrap()),
+                orders,
+            });
+        }
+        Ok(self.get_sources(node_id))
+    }
+
     /// Get the dests of a node given it's id
     #[allow(clippy::borrowed_box)]
     pub fn get_dests(&self, node_id: NodeIndex) -> Vec<(NodeIndex, &Box<dyn Operator>)> {
@@ -492,7 +573,16 @@ impl Graph {
         self.node_weight(node).unwrap().as_any().downcast_ref::<T>()
     }
     pub fn get_op<T: Operator + 'static>(&self, node: NodeIndex) -> &T {
-        self.try_get_op(node).unwrap()
+        self.checked_op(node).unwrap_or_else(|e| panic!("{e}"))
+    }
+    /// Downcast a node's op, erroring with the op that was actually found if it isn't a T
+    pub fn checked_op<T: Operator + 'static>(&self, node: NodeIndex) -> Result<&T, LuminalError> {
+        self.try_get_op(node)
+            .ok_or_else(|| LuminalError::WrongOpType {
+                node,
+                expected: std::any::type_name::<T>(),
+                found: format!("{:?}", self.node_weight(node).unwrap()),
+            })
     }
     pub fn try_get_op_mut<T: Operator + 'static>(&mut self, node: NodeIndex) -> Option<&mut T> {
         self.node_weight_mut(node)
@@ -501,7 +591,15 @@ impl Graph {
             .downcast_mut::<T>()
     }
     pub fn get_op_mut<T: Operator + 'static>(&mut self, node: NodeIndex) -> &mut T {
-        self.try_get_op_mut(node).unwrap()
+        self.checked_op_mut(node).unwrap_or_else(|e| panic!("{e}"))
+    }
+    /// Mutably downcast a node's op, erroring with the op that was actually found if it isn't a T
+    pub fn checked_op_mut<T: Operator + 'static>(
+        &mut self,
+        node: NodeIndex,
+    ) -> Result<&mut T, LuminalError> {
+        self.checked_op::<T>(node)?;
+        Ok(self.try_get_op_mut(node).unwrap())
     }
 }
 
@@ -511,6 +609,21 @@ pub fn display_graph(
     schedule_edges: &[EdgeIndex],
     mark_nodes: &[NodeIndex],
 ) {
+    let url = format!(
+        "https://dreampuf.github.io/GraphvizOnline/#{}",
+        urlencoding::encode(&graph_to_dot(graph, schedule_edges, mark_nodes))
+    );
+    if let Err(e) = webbrowser::open(&url) {
+        panic!("Error displaying graph: {:?}", e);
+    }
+}
+
+/// Write a debug graph in DOT format, with schedule edges in green and marked nodes highlighted
+pub fn graph_to_dot(
+    graph: &petgraph::stable_graph::StableGraph<String, u8, petgraph::Directed, u32>,
+    schedule_edges: &[EdgeIndex],
+    mark_nodes: &[NodeIndex],
+) -> String {
     let mut graph_string =
         petgraph::dot::Dot::with_config(&graph, &[petgraph::dot::Config::EdgeIndexLabel])
             .to_string();
@@ -529,14 +642,7 @@ pub fn display_graph(
             ),
         );
     }
-
-    let url = format!(
-        "https://dreampuf.github.io/GraphvizOnline/#{}",
-        urlencoding::encode(&graph_string)
-    );
-    if let Err(e) = webbrowser::open(&url) {
-        panic!("Error displaying graph: {:?}", e);
-    }
+    graph_string
 }
 
 pub struct NewOp<'a> {
@@ -547,7 +653,28 @@ pub struct NewOp<'a> {
 
 impl<'a> NewOp<'a> {
     pub fn finish(self) -> NodeIndex {
-        self.new_op_id
+        self.try_finish().unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Finish the op, checking that the input shapes and formats are compatible with it and storing the output shape it
+    /// infers. On a mismatch the op is removed from the graph.
+    pub fn try_finish(self) -> Result<NodeIndex, LuminalError> {
+        let dtype = check_input_shapes(self.graph_ref, self.new_op_id)
+            .and_then(|_| crate::dtype::produced_dtype(self.graph_ref, self.new_op_id));
+        match dtype {
+            Ok(DType::F32) => {}
+            Ok(dtype) => {
+                self.graph_ref.dtypes.insert(self.new_op_id, dtype);
+            }
+            Err(e) => {
+                self.graph_ref.remove_node(self.new_op_id);
+                return Err(e);
+            }
+        }
+        if self.num_srcs > 0 {
+            self.graph_ref.store_shape(self.new_op_id);
+        }
+        Ok(self.new_op_id)
     }
 
     pub fn input(mut self, id: NodeIndex, from_output: u8, shape: ShapeTracker) -> Self {
@@ -565,6 +692,115 @@ impl<'a> NewOp<'a> {
     }
 }
 
+/// Make sure elementwise ops get inputs of the same shape, reduces get a dimension that exists, and every input is read
+/// through a view of the whole output its op inferred
+fn check_input_shapes(graph: &Graph, node: NodeIndex) -> Result<(), LuminalError> {
+    let op = graph.node_weight(node).unwrap();
+    let inputs = graph
+        .edges_directed(node, Direction::Incoming)
+        .filter_map(|e| {
+            e.weight()
+                .as_data()
+                .map(|(i, output, s)| (i, e.source(), output, s))
+        })
+        .sorted_by_key(|(i, _, _, _)| *i)
+        .collect::<Vec<_>>();
+    // A view without slicing or padding reads exactly the elements its source holds. Views like pooling windows
+    // deliberately read past the end under a mask, so those aren't checked, and neither are the empty views copies
+    // take their whole input through.
+    for (_, src, output, view) in &inputs {
+        let Some(shape) = graph.shapes.get(src).filter(|_| *output == 0) else {
+            continue;
+        };
+        if view.is_empty() || view.is_sliced() || view.is_padded() {
+            continue;
+        }
+        if let (Some(a), Some(b)) = (
+            shape.n_elements().to_usize(),
+            view.n_physical_elements().to_usize(),
+        ) {
+            if a != b {
+                return Err(LuminalError::ShapeMismatch {
+                    op: format!("{op:?}"),
+                    location: graph.location(node),
+                    inputs: vec![(
+                        format!("{:?}", graph.node_weight(*src).unwrap()),
+                        shape.shape().iter().map(|d| d.to_string()).collect(),
+                    )],
+                });
+            }
+        }
+    }
+    let inputs = inputs
+        .into_iter()
+        .map(|(_, src, _, s)| (src, s))
+        .collect::<Vec<_>>();
+    let mismatch = || LuminalError::ShapeMismatch {
+        op: format!("{op:?}"),
+        location: graph.location(node),
+        inputs: inputs
+            .iter()
+            .map(|(src, s)| {
+                (
+                    format!("{:?}", graph.node_weight(*src).unwrap()),
+                    s.shape().iter().map(|d| d.to_string()).collect(),
+                )
+            })
+            .collect(),
+    };
+    let any = op.as_any();
+    if any.is::<Add>()
+        || any.is::<Mul>()
+        || any.is::<Mod>()
+        || any.is::<LessThan>()
+        || any.is::<Select>()
+    {
+        for (_, b) in inputs.iter().skip(1) {
+            let a = inputs[0].1;
+            if a.len() != b.len() {
+                return Err(mismatch());
+            }
+            // Symbolic dimensions can only be compared once they're known
+            for (a, b) in a.shape().into_iter().zip(b.shape()) {
+                if let (Some(a), Some(b)) = (a.to_usize(), b.to_usize()) {
+                    if a != b {
+                        return Err(mismatch());
+                    }
+                }
+            }
+        }
+    }
+    let reduce_dim = any
+        .downcast_ref::<SumReduce>()
+        .map(|r| r.0)
+        .or_else(|| any.downcast_ref::<MaxReduce>().map(|r| r.0))
+        .or_else(|| any.downcast_ref::<MinReduce>().map(|r| r.0))
+        .or_else(|| any.downcast_ref::<ProdReduce>().map(|r| r.0))
+        .or_else(|| any.downcast_ref::<CumSum>().map(|r| r.0));
+    if let Some(dim) = reduce_dim {
+        if inputs.iter().any(|(_, s)| dim >= s.len()) {
+            return Err(mismatch());
+        }
+    }
+    if let Some(IndexAdd(dim)) = any.downcast_ref::<IndexAdd>() {
+        let (dest, indexes, src) = (inputs[0].1, inputs[1].1, inputs[2].1);
+        if *dim >= dest.len() || indexes.len() != 1 || src.len() != dest.len() {
+            return Err(mismatch());
+        }
+        // The source matches the destination everywhere but the indexed dimension, which has one slice per index
+        let mut expected = dest.shape();
+        expected[*dim] = indexes.shape()[0].clone();
+        for (a, b) in expected.into_iter().zip(src.shape()) {
+            if let (Some(a), Some(b)) = (a.to_usize(), b.to_usize()) {
+                if a != b {
+                    return Err(mismatch());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Transfer all external references from one node to another (this may happen because one node is about to be removed / merged into another)
 pub fn remap<T: ToIdsMut>(from: NodeIndex, to: NodeIndex, mut ids: T, graph: &mut Graph) {
     for id in ids.to_ids_mut() {
@@ -576,10 +812,42 @@ pub fn remap<T: ToIdsMut>(from: NodeIndex, to: NodeIndex, mut ids: T, graph: &mu
     if graph.no_delete.remove(&from) {
         graph.no_delete.insert(to);
     }
+    // Transfer retained
+    if graph.retained.remove(&from) {
+        graph.retained.insert(to);
+    }
+    // Transfer pinned, along with its data. Anything else held for the old node was made by the op being replaced, and
+    // has to go before its index gets reused by a new node.
+    let pinned = graph.pinned.remove(&from);
+    if pinned {
+        graph.pinned.insert(to);
+    }
+    let held = graph
+        .tensors
+        .keys()
+        .filter(|(n, _)| *n == from)
+        .copied()
+        .collect::<Vec<_>>();
+    for (_, output) in held {
+        let tensor = graph.tensors.remove(&(from, output)).unwrap();
+        if pinned {
+            graph.tensors.insert((to, output), tensor);
+        }
+    }
     // Transfer to_retrieve
     if let Some(w) = graph.to_retrieve.remove(&from) {
         graph.to_retrieve.insert(to, w);
     }
+    if let Some(name) = graph.output_names.remove(&from) {
+        graph.output_names.insert(to, name);
+    }
+    // Transfer device placement and format
+    if let Some(device) = graph.devices.remove(&from) {
+        graph.devices.entry(to).or_insert(device);
+    }
+    if let Some(dtype) = graph.dtypes.remove(&from) {
+        graph.dtypes.entry(to).or_insert(dtype);
+    }
 }
 
 pub fn move_outgoing_edge<N, E: Clone>(
@@ -683,51 +951,81 @@ impl GraphSearch {
     }
 }
 
+/// Something left to check while matching a pattern
+#[derive(Clone, Copy)]
+enum MatchGoal {
+    /// This part of the pattern is this node
+    Place(NodeIndex, NodeIndex),
+    /// This parent in the pattern is one of the sources of this node, at the given input if the pattern asks for one
+    Source(NodeIndex, Option<u8>, NodeIndex),
+}
+
 fn backtrack_match(
     pattern_root: NodeIndex,
     pattern_graph: &StableGraph<(Uuid, SelectOp), Option<u8>>,
     main_root: NodeIndex,
     main_graph: &mut MainGraph,
 ) -> Option<FxHashMap<NodeIndex, NodeIndex>> {
-    fn get_parents<N, E>(
-        graph: &petgraph::stable_graph::StableGraph<N, E>,
-        node_index: NodeIndex,
-        edge_filter: fn(&EdgeReference<'_, E>) -> bool,
-    ) -> Vec<NodeIndex> {
-        graph
-            .edges_directed(node_index, Direction::Incoming)
-            .filter(edge_filter)
-            .map(|e| e.source())
-            .collect()
-    }
-
-    if !test_node(
-        &pattern_graph.node_weight(pattern_root).unwrap().1,
+    solve_match(
+        vec![MatchGoal::Place(pattern_root, main_root)],
+        FxHashMap::default(),
+        pattern_graph,
         main_graph,
-        main_root,
-    ) {
-        return None;
-    }
+    )
+}
 
-    let mut mapping = FxHashMap::default();
-    mapping.insert(pattern_root, main_root);
-    let main_parents = get_parents(main_graph, main_root, |e| !e.weight().is_schedule());
-    'pattern_loop: for pattern_parent in get_parents(pattern_graph, pattern_root, |_| true) {
-        for parent in main_parents.iter() {
-            if mapping.values().any(|&v| v == *parent) {
-                // This main node was used already, skip it
-                continue;
+/// Meet every goal, trying each way of placing a pattern node among a node's sources until the rest of the pattern
+/// fits too
+fn solve_match(
+    mut goals: Vec<MatchGoal>,
+    mut mapping: FxHashMap<NodeIndex, NodeIndex>,
+    pattern_graph: &StableGraph<(Uuid, SelectOp), Option<u8>>,
+    main_graph: &mut MainGraph,
+) -> Option<FxHashMap<NodeIndex, NodeIndex>> {
+    let Some(goal) = goals.pop() else {
+        return Some(mapping);
+    };
+    match goal {
+        MatchGoal::Place(pattern_node, main_node) => {
+            if let Some(placed) = mapping.get(&pattern_node) {
+                // Reached along another path already, which has to have landed on the same node
+                return if *placed == main_node {
+                    solve_match(goals, mapping, pattern_graph, main_graph)
+                } else {
+                    None
+                };
             }
-            if let Some(new_mapping) =
-                backtrack_match(pattern_parent, pattern_graph, *parent, main_graph)
+            if mapping.values().any(|m| *m == main_node)
+                || !test_node(
+                    &pattern_graph.node_weight(pattern_node).unwrap().1,
+                    main_graph,
+                    main_node,
+                )
             {
-                mapping.extend(new_mapping.into_iter());
-                continue 'pattern_loop;
+                return None;
             }
+            mapping.insert(pattern_node, main_node);
+            goals.extend(
+                pattern_graph
+                    .edges_directed(pattern_node, Direction::Incoming)
+                    .map(|e| MatchGoal::Source(e.source(), *e.weight(), main_node)),
+            );
+            solve_match(goals, mapping, pattern_graph, main_graph)
+        }
+        MatchGoal::Source(pattern_parent, input, main_node) => {
+            let main_parents = main_graph
+                .edges_directed(main_node, Direction::Incoming)
+                .filter_map(|e| e.weight().as_data().map(|(i, _, _)| (e.source(), i)))
+                // Pattern needs this parent at a particular input
+                .filter(|(_, i)| input.map_or(true, |input| input == *i))
+                .collect::<Vec<_>>();
+            main_parents.into_iter().find_map(|(parent, _)| {
+                let mut goals = goals.clone();
+                goals.push(MatchGoal::Place(pattern_parent, parent));
+                solve_match(goals, mapping.clone(), pattern_graph, main_graph)
+            })
         }
-        return None;
     }
-    Some(mapping)
 }
 
 fn test_node(
@@ -889,7 +1187,17 @@ impl SelectGraph {
             Some(fakes.into().into_iter().map(|i| i.into()).collect());
     }
 
-    pub fn connect(mut self, b: Self) -> Self {
+    /// Feed this node into `b`, at any of `b`'s inputs
+    pub fn connect(self, b: Self) -> Self {
+        self.connect_at(b, None)
+    }
+
+    /// Feed this node into `b` at a specific input index
+    pub fn connect_input(self, b: Self, input: u8) -> Self {
+        self.connect_at(b, Some(input))
+    }
+
+    fn connect_at(mut self, b: Self, input: Option<u8>) -> Self {
         // Add b graph to a graph
         let mut node_map = FxHashMap::default();
         let mut a_nodes = FxHashMap::default();
@@ -913,7 +1221,7 @@ impl SelectGraph {
                 *b.graph.edge_weight(edge).unwrap(),
             );
         }
-        self.graph.add_edge(self.node, node_map[&b.node], None);
+        self.graph.add_edge(self.node, node_map[&b.node], input);
         self.node = node_map[&b.node];
         self.id = b.id;
         self
@@ -947,6 +1255,12 @@ pub fn unary<T: Operator + 'static>(node: SelectGraph) -> SelectGraph {
     node.connect(op::<T>())
 }
 
+/// Match an op with inputs `a` and `b` in either order. Use `ordered_binary` for non-commutative ops.
 pub fn binary<T: Operator + 'static>(a: SelectGraph, b: SelectGraph) -> SelectGraph {
     b.connect(a.connect(op::<T>()))
 }
+
+/// Match an op with `a` as its first input and `b` as its second
+pub fn ordered_binary<T: Operator + 'static>(a: SelectGraph, b: SelectGraph) -> SelectGraph {
+    b.connect_input(a.connect_input(op::<T>(), 0), 1)
+}
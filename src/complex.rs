@@ -0,0 +1,311 @@
+use std::ops::{Add, Mul, Neg, Sub};
+
+#[cfg(feature = "fft")]
+use std::fmt::Debug;
+
+#[cfg(feature = "fft")]
+use rustfft::{num_complex::Complex32, FftDirection, FftPlanner};
+
+use crate::prelude::*;
+
+/// Complex values as a pair of f32 tensors, one of the real parts and one of the imaginary parts. Every backend and view
+/// works on them as they are, and complex ops are built out of ops on the parts.
+///
+/// There's no complex dtype: the graph only ever sees two real tensors, so complex data goes in and comes out split into
+/// its parts rather than as interleaved complex64 buffers, and only the ops here know the parts belong together. The
+/// Fourier transforms need the `fft` feature.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = ComplexTensor::new(cx.tensor::<R1<2>>().set([1., 0.]), cx.tensor::<R1<2>>().set([0., 1.]));
+/// let squared = (a * a).retrieve();
+/// cx.execute();
+/// assert_eq!(squared.data(), vec![(1., 0.), (-1., 0.)]);
+/// ```
+#[derive(Clone, Copy)]
+pub struct ComplexTensor<S: Shape> {
+    pub re: GraphTensor<S>,
+    pub im: GraphTensor<S>,
+}
+
+impl<S: Shape> ComplexTensor<S> {
+    pub fn new(re: GraphTensor<S>, im: GraphTensor<S>) -> Self {
+        Self { re, im }
+    }
+
+    /// Real values, with no imaginary part
+    #[track_caller]
+    pub fn from_real(re: GraphTensor<S>) -> Self {
+        let im = re.graph().constant(0.).expand_to(re.shape.contiguous());
+        Self { re, im }
+    }
+
+    pub fn retrieve(self) -> Self {
+        Self {
+            re: self.re.retrieve(),
+            im: self.im.retrieve(),
+        }
+    }
+
+    /// Each element's real and imaginary parts
+    pub fn data(&self) -> Vec<(f32, f32)> {
+        self.re.data().into_iter().zip(self.im.data()).collect()
+    }
+
+    #[track_caller]
+    pub fn conj(self) -> Self {
+        Self {
+            re: self.re,
+            im: -self.im,
+        }
+    }
+
+    /// Magnitude of each element
+    #[track_caller]
+    pub fn abs(self) -> GraphTensor<S> {
+        self.norm_sqr().sqrt()
+    }
+
+    /// Squared magnitude of each element, which is the power in a spectrum
+    #[track_caller]
+    pub fn norm_sqr(self) -> GraphTensor<S> {
+        self.re * self.re + self.im * self.im
+    }
+
+    /// Discrete Fourier transform along the last dimension
+    #[cfg(feature = "fft")]
+    #[track_caller]
+    pub fn fft(self) -> Self {
+        self.transform(false)
+    }
+
+    /// Inverse discrete Fourier transform along the last dimension, scaled by 1 / N so it undoes `fft`
+    #[cfg(feature = "fft")]
+    #[track_caller]
+    pub fn ifft(self) -> Self {
+        self.transform(true)
+    }
+
+    #[cfg(feature = "fft")]
+    #[track_caller]
+    fn transform(self, inverse: bool) -> Self {
+        let shape = self.re.shape.contiguous();
+        let graph = self.re.graph();
+        let fft = graph
+            .add_op(Fft::new(inverse))
+            .input(self.re.id, 0, self.re.shape)
+            .input(self.im.id, 0, self.im.shape)
+            .finish();
+        let mut part = |i| {
+            let id = graph.add_op(FftOutput(i)).input(fft, i, shape).finish();
+            GraphTensor::from_id(id, shape, self.re.graph_ref)
+        };
+        Self {
+            re: part(0),
+            im: part(1),
+        }
+    }
+}
+
+impl<S: Shape> Add for ComplexTensor<S> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self {
+            re: self.re + rhs.re,
+            im: self.im + rhs.im,
+        }
+    }
+}
+
+impl<S: Shape> Sub for ComplexTensor<S> {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self {
+            re: self.re - rhs.re,
+            im: self.im - rhs.im,
+        }
+    }
+}
+
+impl<S: Shape> Mul for ComplexTensor<S> {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self {
+            re: self.re * rhs.re - self.im * rhs.im,
+            im: self.re * rhs.im + self.im * rhs.re,
+        }
+    }
+}
+
+impl<S: Shape> Mul<f32> for ComplexTensor<S> {
+    type Output = Self;
+    fn mul(self, rhs: f32) -> Self {
+        Self {
+            re: self.re * rhs,
+            im: self.im * rhs,
+        }
+    }
+}
+
+impl<S: Shape> Neg for ComplexTensor<S> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self {
+            re: -self.re,
+            im: -self.im,
+        }
+    }
+}
+
+/// Discrete Fourier transform along the last dimension, run on the host with rustfft. Takes the real and imaginary parts
+/// as two inputs, and gives them back as two outputs.
+#[cfg(feature = "fft")]
+pub struct Fft {
+    pub inverse: bool,
+    planner: FftPlanner<f32>,
+}
+
+#[cfg(feature = "fft")]
+impl Fft {
+    pub fn new(inverse: bool) -> Self {
+        Self {
+            inverse,
+            planner: FftPlanner::new(),
+        }
+    }
+}
+
+#[cfg(feature = "fft")]
+impl Debug for Fft {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", if self.inverse { "Ifft" } else { "Fft" })
+    }
+}
+
+#[cfg(feature = "fft")]
+impl Operator for Fft {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        let n = input_shapes[0].shape().last()?.to_usize()?;
+        let elements = input_shapes[0].n_elements().to_usize()?;
+        Some(OpCost {
+            flops: 5 * elements * (n.max(2) as f32).log2().ceil() as usize,
+            bytes_read: elements * 8,
+            bytes_written: elements * 8,
+        })
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let view = |i: usize| {
+            HostTensorView::new(
+                inp[i].0.borrowed().f32s().unwrap_or_else(|e| panic!("{e}")),
+                inp[i].1,
+            )
+        };
+        let (re, im) = (view(0), view(1));
+        let n = re.shape().last().copied().unwrap_or(1);
+        let mut buffer = re
+            .iter()
+            .zip(im.iter())
+            .map(|(re, im)| Complex32::new(re, im))
+            .collect::<Vec<_>>();
+        if n > 0 && !buffer.is_empty() {
+            let direction = if self.inverse {
+                FftDirection::Inverse
+            } else {
+                FftDirection::Forward
+            };
+            self.planner.plan_fft(n, direction).process(&mut buffer);
+        }
+        let scale = if self.inverse { 1. / n as f32 } else { 1. };
+        vec![
+            Tensor::new(buffer.iter().map(|c| c.re * scale).collect::<Vec<_>>()),
+            Tensor::new(buffer.iter().map(|c| c.im * scale).collect::<Vec<_>>()),
+        ]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+#[cfg(feature = "fft")]
+/// Hands the real or imaginary part of an `Fft` on to the rest of the graph
+#[derive(Debug, Clone, PartialEq)]
+pub struct FftOutput(pub u8);
+
+#[cfg(feature = "fft")]
+impl Operator for FftOutput {
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    /// The DFT written out, one sum per output
+    #[cfg(feature = "fft")]
+    fn dft(re: &[f32], im: &[f32], n: usize) -> (Vec<f32>, Vec<f32>) {
+        use std::f32::consts::PI;
+        let (mut out_re, mut out_im) = (vec![], vec![]);
+        for (re, im) in re.chunks(n).zip(im.chunks(n)) {
+            for k in 0..n {
+                let (mut sum_re, mut sum_im) = (0., 0.);
+                for t in 0..n {
+                    let angle = -2. * PI * (k * t) as f32 / n as f32;
+                    sum_re += re[t] * angle.cos() - im[t] * angle.sin();
+                    sum_im += re[t] * angle.sin() + im[t] * angle.cos();
+                }
+                out_re.push(sum_re);
+                out_im.push(sum_im);
+            }
+        }
+        (out_re, out_im)
+    }
+
+    #[cfg(feature = "fft")]
+    #[test]
+    fn test_fft() {
+        let mut cx = Graph::new();
+        let (re_data, im_data) = (random_vec(24), random_vec(24));
+        let re = cx.tensor::<R2<6, 4>>().set(re_data.clone());
+        let im = cx.tensor::<R2<6, 4>>().set(im_data.clone());
+        // Read through a permute, so the transform runs along what was the first dimension
+        let x = ComplexTensor::new(re.permute::<R2<4, 6>, _>(), im.permute::<R2<4, 6>, _>());
+        let spectrum = x.fft().retrieve();
+        let round_trip = x.fft().ifft().retrieve();
+        cx.execute();
+
+        let transpose = |d: &[f32]| (0..24).map(|i| d[(i % 6) * 4 + i / 6]).collect::<Vec<_>>();
+        let (expected_re, expected_im) = dft(&transpose(&re_data), &transpose(&im_data), 6);
+        assert_close(&spectrum.re.data(), &expected_re);
+        assert_close(&spectrum.im.data(), &expected_im);
+        assert_close(&round_trip.re.data(), &transpose(&re_data));
+        assert_close(&round_trip.im.data(), &transpose(&im_data));
+    }
+
+    #[test]
+    fn test_complex_arithmetic() {
+        let mut cx = Graph::new();
+        let a = ComplexTensor::new(
+            cx.tensor::<R1<2>>().set([1., 3.]),
+            cx.tensor::<R1<2>>().set([2., -1.]),
+        );
+        let b = ComplexTensor::from_real(cx.tensor::<R1<2>>().set([0.5, 2.]));
+        let product = (a * (b + a.conj())).retrieve();
+        let scaled = (-(a - b) * 2.).retrieve();
+        let abs = a.abs().retrieve();
+        cx.execute();
+
+        // (1 + 2i)(1.5 - 2i) = 5.5 + i, (3 - i)(5 + i) = 16 - 2i
+        assert_close(&product.re.data(), &[5.5, 16.]);
+        assert_close(&product.im.data(), &[1., -2.]);
+        assert_eq!(scaled.data(), vec![(-1., -4.), (-2., 2.)]);
+        assert_close(&abs.data(), &[5f32.sqrt(), 10f32.sqrt()]);
+    }
+}
@@ -0,0 +1,430 @@
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// Runs one of two subgraphs depending on a scalar predicate. Only the chosen branch runs.
+///
+/// Input 0 is the predicate, and the rest of the inputs get fed to whichever branch runs. Both branches take the same
+/// inputs and produce outputs of the same shapes.
+#[derive(Debug)]
+pub struct Cond {
+    pub then_branch: Call,
+    pub else_branch: Call,
+}
+
+/// Read the first element of a tensor through its view
+fn first_element(tensor: &InputTensor, st: &ShapeTracker) -> Result<f32, LuminalError> {
+    if st.valid_expression().exec_single_var(0) == 0 {
+        return Ok(0.);
+    }
+    Ok(tensor.borrowed().f32s()?[st.index_expression().exec_single_var(0)])
+}
+
+impl Operator for Cond {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        mut inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let (predicate, st) = inp.remove(0);
+        if first_element(&predicate, &st)? != 0. {
+            self.then_branch.try_process(inp, dyn_map)
+        } else {
+            self.else_branch.try_process(inp, dyn_map)
+        }
+    }
+}
+
+impl Graph {
+    /// Run `then_branch` if the first element of `predicate` is nonzero, and `else_branch` otherwise, feeding `inputs` into the chosen branch.
+    ///
+    /// Both branches need to take inputs of the same shapes and produce outputs of the same shapes. Returns a tensor for each output.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut double = Graph::new();
+    /// let x = double.tensor::<R1<2>>();
+    /// let y = (x * 2.).retrieve();
+    /// let double = Call::new(double, &[x.no_shape()], &[y.no_shape()]);
+    ///
+    /// let mut negate = Graph::new();
+    /// let x = negate.tensor::<R1<2>>();
+    /// let y = (-x).retrieve();
+    /// let negate = Call::new(negate, &[x.no_shape()], &[y.no_shape()]);
+    ///
+    /// let mut cx = Graph::new();
+    /// let predicate = cx.tensor::<R0>().set(vec![0.]);
+    /// let a = cx.tensor::<R1<2>>().set([1., 2.]);
+    /// let out = cx.cond(predicate.no_shape(), &[a.no_shape()], double, negate)[0].retrieve();
+    /// cx.execute();
+    /// assert_eq!(out.data(), vec![-1., -2.]);
+    /// ```
+    #[track_caller]
+    pub fn cond(
+        &mut self,
+        predicate: GraphTensor<()>,
+        inputs: &[GraphTensor<()>],
+        then_branch: Call,
+        else_branch: Call,
+    ) -> Vec<GraphTensor<()>> {
+        for branch in [&then_branch, &else_branch] {
+            assert_eq!(
+                branch.num_inputs(),
+                inputs.len(),
+                "Each branch needs to take every input"
+            );
+        }
+        assert_eq!(
+            then_branch.num_outputs(),
+            else_branch.num_outputs(),
+            "Both branches need the same outputs"
+        );
+        let shapes = then_branch.output_shapes(inputs);
+        let mut node = self
+            .add_op(Cond {
+                then_branch,
+                else_branch,
+            })
+            .input(predicate.id, 0, predicate.shape);
+        for input in inputs {
+            node = node.input(input.id, 0, input.shape);
+        }
+        let node = node.finish();
+        self.call_outputs(node, &shapes)
+    }
+
+    /// Run a loop, starting from the carried state in `init`.
+    ///
+    /// Returns the final carried state, then each stacked per-iteration output (with a leading dimension of `max_iters`), then
+    /// the number of iterations that ran as a scalar.
+    #[track_caller]
+    pub fn scan(&mut self, body: Loop, init: &[GraphTensor<()>]) -> Vec<GraphTensor<()>> {
+        let body_shapes = body.body.output_shapes(init);
+        // Carried state comes out contiguous
+        let mut shapes = init
+            .iter()
+            .map(|i| {
+                ShapeTracker::new(
+                    &i.shape
+                        .shape()
+                        .into_iter()
+                        .map(|d| d.small())
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        for i in body.scanned() {
+            let mut dims = vec![Expression::from(body.max_iters)];
+            dims.extend(body_shapes[i].shape().into_iter().map(|d| d.small()));
+            shapes.push(ShapeTracker::new(&dims));
+        }
+        shapes.push(ShapeTracker::new(&[]));
+        let mut node = self.add_op(body);
+        for input in init {
+            node = node.input(input.id, 0, input.shape);
+        }
+        let node = node.finish();
+        self.call_outputs(node, &shapes)
+    }
+}
+
+/// Runs a subgraph over and over, feeding its outputs back in as its inputs, so a whole loop (like autoregressive decoding
+/// with sampling) can run without coming back out to the caller between steps.
+///
+/// The loop runs on the host, so a body compiled for a device copies its data to and from it every iteration. GPU
+/// compilers copy the loop's own inputs and outputs like a function's, and device data that reaches it anyway is an error.
+///
+/// The body's first outputs are the new values of the carried state, in the same order and with the same shapes as its inputs.
+/// Its other outputs are per-iteration outputs, stacked along a new leading dimension of size `max_iters`. One of them can be
+/// marked as a stop flag with [`Loop::until`], in which case the loop ends after the first iteration where it's nonzero.
+/// ```rust
+/// use luminal::prelude::*;
+/// // Count up by one, stopping once we reach 3
+/// let mut body = Graph::new();
+/// let x = body.tensor::<R1<1>>();
+/// let next = (x + 1.).retrieve();
+/// let done = next.greater_than_equal(body.constant(3.).expand()).retrieve();
+/// let body = Call::new(body, &[x.no_shape()], &[next.no_shape(), done.no_shape(), next.no_shape()]);
+///
+/// let mut cx = Graph::new();
+/// let init = cx.tensor::<R1<1>>().set([0.]);
+/// let out = cx.scan(Loop::new(body, 1, 5).until(1), &[init.no_shape()]);
+/// let (last, steps, n_iters) = (out[0].retrieve(), out[1].retrieve(), out[2].retrieve());
+/// cx.execute();
+/// assert_eq!(last.data(), vec![3.]);
+/// assert_eq!(steps.data(), vec![1., 2., 3., 0., 0.]);
+/// assert_eq!(n_iters.data(), vec![3.]);
+/// ```
+#[derive(Debug)]
+pub struct Loop {
+    pub body: Call,
+    /// How many of the body's inputs (and leading outputs) are carried state
+    n_carried: usize,
+    max_iters: usize,
+    /// The body output that ends the loop when nonzero
+    stop: Option<usize>,
+}
+
+impl Loop {
+    /// Loop `body` at most `max_iters` times. All of its inputs are carried state, updated by its first `n_carried` outputs.
+    pub fn new(body: Call, n_carried: usize, max_iters: usize) -> Self {
+        assert_eq!(
+            body.num_inputs(),
+            n_carried,
+            "Every body input must be carried state"
+        );
+        assert!(
+            body.num_outputs() >= n_carried,
+            "The body needs an output for each piece of carried state"
+        );
+        Self {
+            body,
+            n_carried,
+            max_iters,
+            stop: None,
+        }
+    }
+
+    /// Stop once the body's output at `output` has a nonzero first element
+    pub fn until(mut self, output: usize) -> Self {
+        assert!(
+            output >= self.n_carried && output < self.body.num_outputs(),
+            "The stop flag can't be carried state"
+        );
+        self.stop = Some(output);
+        self
+    }
+
+    /// Indexes of the body outputs that get stacked up across iterations
+    fn scanned(&self) -> impl Iterator<Item = usize> + '_ {
+        (self.n_carried..self.body.num_outputs()).filter(|i| Some(*i) != self.stop)
+    }
+}
+
+impl Operator for Loop {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        // Carried state comes back out of the body contiguous
+        let carried_shapes = inp
+            .iter()
+            .map(|(_, st)| {
+                ShapeTracker::new(
+                    &st.shape_usize()
+                        .into_iter()
+                        .map(Expression::from)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut stacked = vec![vec![]; self.scanned().count()];
+        let mut carried: Option<Vec<Tensor>> = None;
+        let mut n_iters = 0;
+        while n_iters < self.max_iters {
+            let outputs = match carried.take() {
+                None => self.body.try_process(
+                    inp.iter()
+                        .map(|(t, st)| (InputTensor::Borrowed(t.borrowed()), *st))
+                        .collect(),
+                    dyn_map,
+                )?,
+                Some(state) => self.body.try_process(
+                    state
+                        .into_iter()
+                        .map(InputTensor::Owned)
+                        .zip(carried_shapes.iter().copied())
+                        .collect(),
+                    dyn_map,
+                )?,
+            };
+            n_iters += 1;
+            let stop = match self.stop {
+                Some(i) => Some(outputs[i].f32s()?.first().copied().unwrap_or_default() != 0.),
+                None => None,
+            };
+            for (data, i) in stacked.iter_mut().zip(self.scanned()) {
+                data.extend_from_slice(outputs[i].f32s()?);
+            }
+            carried = Some(outputs.into_iter().take(self.n_carried).collect::<Vec<_>>());
+            if stop == Some(true) {
+                break;
+            }
+        }
+        let mut outputs =
+            carried.unwrap_or_else(|| inp.into_iter().map(|(t, _)| t.cloned()).collect());
+        // Iterations that didn't run are left as zeros
+        for data in &mut stacked {
+            let per_iter = data.len() / n_iters.max(1);
+            data.resize(per_iter * self.max_iters, 0.);
+        }
+        outputs.extend(stacked.into_iter().map(Tensor::new));
+        outputs.push(Tensor::new(vec![n_iters as f32]));
+        Ok(outputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    crate::test_imports!();
+
+    /// Scale then shift, counting how many times it runs
+    fn affine(scale: f32, shift: f32) -> (Call, Arc<AtomicUsize>) {
+        let mut cx = Graph::new();
+        let x = cx.tensor::<(Dyn<'s'>,)>();
+        let y = x * scale + shift;
+        let n_runs = Arc::new(AtomicUsize::new(0));
+        let counter = n_runs.clone();
+        let counted = cx
+            .add_op(Function(
+                "Count".to_string(),
+                Box::new(move |mut inp| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    vec![inp.pop().unwrap().0.cloned()]
+                }),
+            ))
+            .input(y.id, 0, y.shape)
+            .finish();
+        let y = GraphTensor::<(Dyn<'s'>,)>::from_id(counted, y.shape, &mut cx).retrieve();
+        (Call::new(cx, &[x.no_shape()], &[y.no_shape()]), n_runs)
+    }
+
+    #[test]
+    fn test_cond() {
+        let mut cx = Graph::new();
+        let predicate = cx.tensor::<R1<2>>();
+        let a = cx.tensor::<(Dyn<'t'>,)>().set_dyn(vec![1., 2., 3.], &[3]);
+        let ((then_branch, then_runs), (else_branch, else_runs)) =
+            (affine(2., 1.), affine(-1., 0.));
+        let out = cx.cond(
+            predicate.no_shape(),
+            &[a.no_shape()],
+            then_branch,
+            else_branch,
+        )[0]
+        .retrieve();
+
+        predicate.set([1., 0.]);
+        cx.execute();
+        assert_exact(&out.data(), &[3., 5., 7.]);
+        assert_eq!(
+            (
+                then_runs.load(Ordering::SeqCst),
+                else_runs.load(Ordering::SeqCst)
+            ),
+            (1, 0)
+        );
+
+        out.drop();
+        predicate.set([0., 1.]);
+        cx.execute();
+        assert_exact(&out.data(), &[-1., -2., -3.]);
+        assert_eq!(
+            (
+                then_runs.load(Ordering::SeqCst),
+                else_runs.load(Ordering::SeqCst)
+            ),
+            (1, 1)
+        );
+    }
+
+    /// Decode by repeatedly picking the most likely next token from a transition table, until hitting token 5
+    fn decode_loop(max_iters: usize) -> Loop {
+        let mut cx = Graph::new();
+        let token = cx.tensor::<R1<1>>();
+        let table = cx
+            .tensor::<R2<8, 8>>()
+            .set(
+                (0..8)
+                    .flat_map(|i| (0..8).map(move |j| if j == (i * 3 + 1) % 8 { 1. } else { 0. }))
+                    .collect::<Vec<_>>(),
+            )
+            .keep();
+        let one_hot = token
+            .expand::<(LConst<1>, LConst<8>), LAxis<1>>()
+            .equals(cx.arange::<LConst<8>>().expand());
+        let next = one_hot.matmul(table).argmax().retrieve();
+        let done = next.equals(cx.constant(5.).expand()).retrieve();
+        let body = Call::new(
+            cx,
+            &[token.no_shape()],
+            &[next.no_shape(), done.no_shape(), next.no_shape()],
+        );
+        Loop::new(body, 1, max_iters).until(1)
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut cx = Graph::new();
+        let start = cx.tensor::<R1<1>>();
+        let out = cx.scan(decode_loop(6), &[start.no_shape()]);
+        let (last, tokens, n_iters) = (out[0].retrieve(), out[1].retrieve(), out[2].retrieve());
+
+        // 0 -> 1 -> 4 -> 5 stops early
+        start.set([0.]);
+        cx.execute();
+        assert_exact(&tokens.data(), &[1., 4., 5., 0., 0., 0.]);
+        assert_exact(&last.data(), &[5.]);
+        assert_exact(&n_iters.data(), &[3.]);
+
+        // 6 -> 3 -> 2 -> 7 -> 6 -> 3 -> 2 never hits the stop token
+        cx.drop_tensors((last, tokens, n_iters));
+        start.set([6.]);
+        cx.execute();
+        assert_exact(&tokens.data(), &[3., 2., 7., 6., 3., 2.]);
+        assert_exact(&last.data(), &[2.]);
+        assert_exact(&n_iters.data(), &[6.]);
+    }
+
+    #[test]
+    fn test_scan_device_body() {
+        #[derive(Debug, Clone)]
+        struct DeviceBuffer;
+        impl Data for DeviceBuffer {
+            fn as_any(&self) -> &dyn std::any::Any {
+                self
+            }
+            fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+                self
+            }
+            fn device(&self) -> &'static str {
+                "device"
+            }
+        }
+
+        // A body that leaves its output on a device is reported instead of panicking
+        let mut body = Graph::new();
+        let x = body.tensor::<R1<1>>();
+        let y = body
+            .add_op(Function(
+                "ToDevice".to_string(),
+                Box::new(|_| vec![crate::op::Tensor::new(DeviceBuffer)]),
+            ))
+            .input(x.id, 0, x.shape)
+            .finish();
+        let y = GraphTensor::<R1<1>>::from_id(y, x.shape, &mut body).retrieve();
+        let body = Call::new(body, &[x.no_shape()], &[y.no_shape()]);
+
+        let mut cx = Graph::new();
+        let start = cx.tensor::<R1<1>>().set([0.]);
+        let out = cx.scan(Loop::new(body, 1, 3), &[start.no_shape()]);
+        out[0].retrieve();
+        let Err(LuminalError::OpFailed { error, .. }) = cx.try_execute() else {
+            panic!("The loop should have failed");
+        };
+        assert!(matches!(*error, LuminalError::WrongData { .. }));
+    }
+}
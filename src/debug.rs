@@ -0,0 +1,517 @@
+use std::{fmt::Display, path::Path};
+
+use itertools::Itertools;
+use petgraph::{algo::toposort, visit::EdgeRef, Direction};
+use regex::Regex;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{prelude::*, stats::op_name};
+
+/// Largest difference allowed between paired outputs when comparing their values
+const TOLERANCE: f32 = 1e-4;
+
+/// What changed between two versions of a graph, like before and after an optimization. Nodes are matched by index,
+/// which passes keep stable for the nodes they don't touch.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphDiff {
+    /// Nodes only in the second graph, with their op
+    pub added: Vec<(NodeIndex, String)>,
+    /// Nodes only in the first graph, with their op
+    pub removed: Vec<(NodeIndex, String)>,
+    /// Nodes whose op changed, with the old and new op
+    pub retyped: Vec<(NodeIndex, String, String)>,
+    /// Retrieved outputs that don't line up between the graphs
+    pub outputs: Vec<OutputMismatch>,
+}
+
+/// A retrieved output of the first graph that the second graph doesn't reproduce. Outputs are paired up in execution
+/// order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputMismatch {
+    /// The second graph retrieves fewer outputs
+    Missing(NodeIndex),
+    /// The second graph retrieves an extra output
+    Extra(NodeIndex),
+    /// Paired outputs have different shapes
+    Shape {
+        a: NodeIndex,
+        b: NodeIndex,
+        a_shape: Vec<String>,
+        b_shape: Vec<String>,
+    },
+    /// Paired outputs both hold data, and disagree at `index`
+    Value {
+        a: NodeIndex,
+        b: NodeIndex,
+        index: usize,
+        a_value: f32,
+        b_value: Option<f32>,
+    },
+}
+
+impl GraphDiff {
+    /// Whether the graphs have the same ops and outputs
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.retyped.is_empty()
+            && self.outputs.is_empty()
+    }
+}
+
+impl Display for GraphDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes");
+        }
+        for (node, op) in &self.removed {
+            writeln!(f, "- {op} ({})", node.index())?;
+        }
+        for (node, op) in &self.added {
+            writeln!(f, "+ {op} ({})", node.index())?;
+        }
+        for (node, old, new) in &self.retyped {
+            writeln!(f, "~ {old} -> {new} ({})", node.index())?;
+        }
+        for mismatch in &self.outputs {
+            writeln!(f, "! {mismatch}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for OutputMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputMismatch::Missing(node) => {
+                write!(f, "Output {} has no counterpart", node.index())
+            }
+            OutputMismatch::Extra(node) => write!(f, "Output {} is new", node.index()),
+            OutputMismatch::Shape {
+                a,
+                b,
+                a_shape,
+                b_shape,
+            } => write!(
+                f,
+                "Output {} is [{}], but {} is [{}]",
+                a.index(),
+                a_shape.join(", "),
+                b.index(),
+                b_shape.join(", ")
+            ),
+            OutputMismatch::Value {
+                a,
+                b,
+                index,
+                a_value,
+                b_value,
+            } => {
+                write!(
+                    f,
+                    "Output {} is {a_value} at index {index}, but {} is ",
+                    a.index(),
+                    b.index()
+                )?;
+                match b_value {
+                    Some(b_value) => write!(f, "{b_value}"),
+                    None => write!(f, "missing"),
+                }
+            }
+        }
+    }
+}
+
+/// The parts of a graph a diff looks at, which can be held onto while the graph keeps changing
+#[derive(Debug, Clone, Default)]
+pub struct GraphSnapshot {
+    /// Op of each node
+    pub ops: FxHashMap<NodeIndex, String>,
+    /// Retrieved nodes in execution order, with their shapes
+    pub outputs: Vec<(NodeIndex, Vec<String>)>,
+    /// The graph in DOT format
+    pub dot: String,
+}
+
+impl GraphSnapshot {
+    pub fn new(graph: &Graph) -> Self {
+        Self {
+            ops: graph
+                .node_indices()
+                .map(|n| (n, op_name(graph.node_weight(n).unwrap().as_ref())))
+                .collect(),
+            outputs: toposort(&graph.graph, None)
+                .unwrap()
+                .into_iter()
+                .filter_map(|n| {
+                    let (_, shape) = graph.to_retrieve.get(&n)?;
+                    Some((n, shape.shape().iter().map(|d| d.to_string()).collect()))
+                })
+                .collect(),
+            dot: graph.to_dot(),
+        }
+    }
+
+    /// Structural changes from this snapshot to `other`
+    pub fn diff(&self, other: &GraphSnapshot) -> GraphDiff {
+        let mut diff = GraphDiff::default();
+        for (node, op) in self.ops.iter().sorted_by_key(|(n, _)| **n) {
+            match other.ops.get(node) {
+                None => diff.removed.push((*node, op.clone())),
+                Some(new) if new != op => diff.retyped.push((*node, op.clone(), new.clone())),
+                _ => {}
+            }
+        }
+        for (node, op) in other.ops.iter().sorted_by_key(|(n, _)| **n) {
+            if !self.ops.contains_key(node) {
+                diff.added.push((*node, op.clone()));
+            }
+        }
+        for (a, b) in self.outputs.iter().zip(&other.outputs) {
+            if a.1 != b.1 {
+                diff.outputs.push(OutputMismatch::Shape {
+                    a: a.0,
+                    b: b.0,
+                    a_shape: a.1.clone(),
+                    b_shape: b.1.clone(),
+                });
+            }
+        }
+        for (node, _) in self.outputs.iter().skip(other.outputs.len()) {
+            diff.outputs.push(OutputMismatch::Missing(*node));
+        }
+        for (node, _) in other.outputs.iter().skip(self.outputs.len()) {
+            diff.outputs.push(OutputMismatch::Extra(*node));
+        }
+        diff
+    }
+}
+
+/// Compare two versions of a graph, usually one before and one after an optimizer ran.
+///
+/// Reports the nodes that were added, removed or changed op, and checks that both graphs retrieve the same outputs with
+/// the same shapes. If both graphs have been executed, the values of paired outputs are compared too.
+/// ```rust
+/// use luminal::prelude::*;
+/// let build = |cx: &mut Graph| {
+///     let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+///     (a * 1.).exp2().retrieve()
+/// };
+/// let (mut before, mut after) = (Graph::new(), Graph::new());
+/// build(&mut before);
+/// let mut out = build(&mut after);
+/// after.compile(GenericCompiler::default(), &mut out);
+/// before.execute();
+/// after.execute();
+/// let diff = luminal::debug::diff(&before, &after);
+/// // Multiplying by one got folded away, and the result didn't change
+/// assert!(!diff.removed.is_empty());
+/// assert!(diff.outputs.is_empty());
+/// ```
+pub fn diff(a: &Graph, b: &Graph) -> GraphDiff {
+    let (a_snapshot, b_snapshot) = (GraphSnapshot::new(a), GraphSnapshot::new(b));
+    let mut diff = a_snapshot.diff(&b_snapshot);
+    for ((a_node, a_shape), (b_node, b_shape)) in a_snapshot.outputs.iter().zip(&b_snapshot.outputs)
+    {
+        if a_shape != b_shape {
+            continue;
+        }
+        let (Some(a_data), Some(b_data)) = (
+            a.get_view(*a_node, a.to_retrieve[a_node].1),
+            b.get_view(*b_node, b.to_retrieve[b_node].1),
+        ) else {
+            continue;
+        };
+        let mismatch = a_data
+            .iter()
+            .enumerate()
+            .find_map(|(i, x)| match b_data.get(i) {
+                Some(y) if (x - y).abs() <= TOLERANCE || (x.is_nan() && y.is_nan()) => None,
+                y => Some((i, *x, y.copied())),
+            });
+        if let Some((index, a_value, b_value)) = mismatch {
+            diff.outputs.push(OutputMismatch::Value {
+                a: *a_node,
+                b: *b_node,
+                index,
+                a_value,
+                b_value,
+            });
+        }
+    }
+    diff
+}
+
+/// The graph as text that only depends on its structure, for golden tests of what passes do to a graph.
+///
+/// Nodes are numbered in a topological order that breaks ties by what each node prints as rather than by its index, so
+/// the same graph built or rewritten in a different order prints the same. Each line is a node with the views it reads
+/// its inputs through: broadcast dimensions end in `*`, permutes follow the dimensions as `^(..)`, and slices and padding
+/// are written on the dimension they apply to.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R2<2, 3>>();
+/// a.permute::<_, Axes2<1, 0>>().exp2().retrieve();
+/// assert_eq!(
+///     luminal::debug::canonical_text(&cx),
+///     "%0 = Tensor Load\n%1 = Exp2(%0[3, 2]^(1, 0)) retrieve[3, 2]"
+/// );
+/// ```
+pub fn canonical_text(graph: &Graph) -> String {
+    let mut ids = FxHashMap::default();
+    let mut lines = vec![];
+    let mut remaining = graph.node_indices().collect::<FxHashSet<_>>();
+    while !remaining.is_empty() {
+        // Of the nodes whose inputs are all numbered, the one that prints first goes next
+        let (node, line) = remaining
+            .iter()
+            .filter(|n| {
+                graph
+                    .graph
+                    .edges_directed(**n, Direction::Incoming)
+                    .all(|e| ids.contains_key(&e.source()))
+            })
+            .map(|n| (*n, canonical_line(graph, *n, &ids)))
+            .min_by(|(a, a_line), (b, b_line)| a_line.cmp(b_line).then(a.cmp(b)))
+            .expect("Graph has a cycle");
+        lines.push(format!("%{} = {line}", ids.len()));
+        ids.insert(node, ids.len());
+        remaining.remove(&node);
+    }
+    lines.join("\n")
+}
+
+fn canonical_line(graph: &Graph, node: NodeIndex, ids: &FxHashMap<NodeIndex, usize>) -> String {
+    let inputs = graph
+        .get_sources(node)
+        .into_iter()
+        .map(|(src, output, shape)| {
+            let output = if output == 0 {
+                String::new()
+            } else {
+                format!(".{output}")
+            };
+            format!("%{}{output}{}", ids[&src], canonical_shape(&shape))
+        })
+        .join(", ");
+    let mut line = format!("{:?}", graph.node_weight(node).unwrap());
+    if !inputs.is_empty() {
+        line.push_str(&format!("({inputs})"));
+    }
+    let after = graph
+        .graph
+        .edges_directed(node, Direction::Incoming)
+        .filter(|e| e.weight().is_schedule())
+        .map(|e| format!("%{}", ids[&e.source()]))
+        .sorted()
+        .join(", ");
+    if !after.is_empty() {
+        line.push_str(&format!(" after {after}"));
+    }
+    if let Some((output, shape)) = graph.to_retrieve.get(&node) {
+        let output = if *output == 0 {
+            String::new()
+        } else {
+            format!(".{output}")
+        };
+        line.push_str(&format!(" retrieve{output}{}", canonical_shape(shape)));
+    } else if graph.no_delete.contains(&node) {
+        line.push_str(" keep");
+    }
+    line
+}
+
+fn canonical_shape(shape: &ShapeTracker) -> String {
+    let dims = shape
+        .indexes
+        .iter()
+        .map(|i| {
+            let mut dim = shape.dims[*i].to_string();
+            let (start, end) = shape.mask[*i];
+            if start != 0 || end != i32::MAX {
+                let end = if end == i32::MAX {
+                    String::new()
+                } else {
+                    end.to_string()
+                };
+                dim.push_str(&format!("[{start}..{end}]"));
+            }
+            let (before, after) = shape.padding[*i];
+            if before != 0 || after != 0 {
+                dim.push_str(&format!("+({before}, {after})"));
+            }
+            if shape.fake[*i] {
+                dim.push('*');
+            }
+            dim
+        })
+        .join(", ");
+    if shape.indexes.iter().enumerate().all(|(a, b)| a == *b) {
+        format!("[{dims}]")
+    } else {
+        format!("[{dims}]^({})", shape.indexes.iter().join(", "))
+    }
+}
+
+/// What one compiler pass did to a graph
+#[derive(Debug, Clone)]
+pub struct PassStep {
+    pub pass: String,
+    pub diff: GraphDiff,
+    /// The graph after the pass ran, in DOT format
+    pub dot: String,
+}
+
+/// Compile a graph like `Graph::compile`, recording the graph after every individual pass. Tuples of passes are
+/// stepped into, so each pass in a compiler stack gets its own step.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+/// let mut b = (a * 1.).exp2().retrieve();
+/// let steps = luminal::debug::compile_passes(&mut cx, GenericCompiler::default(), &mut b);
+/// for step in steps.iter().filter(|s| !s.diff.is_empty()) {
+///     println!("{}:\n{}", step.pass, step.diff);
+/// }
+/// ```
+pub fn compile_passes<T: ToIdsMut, C: Compiler>(
+    graph: &mut Graph,
+    compiler: C,
+    remap: T,
+) -> Vec<PassStep> {
+    graph.pass_snapshots = Some(vec![("Start".to_string(), GraphSnapshot::new(graph))]);
+    compiler.compile(graph, remap);
+    record_pass(graph, pass_name::<C>, Some(1));
+    graph.toposort();
+    graph.reset();
+    graph
+        .pass_snapshots
+        .take()
+        .unwrap()
+        .into_iter()
+        .tuple_windows()
+        .map(|((_, before), (pass, after))| PassStep {
+            diff: before.diff(&after),
+            pass,
+            dot: after.dot,
+        })
+        .collect()
+}
+
+/// Write the graph after each step to `dir` as numbered DOT files, skipping passes that didn't change anything
+pub fn dump_passes(steps: &[PassStep], dir: impl AsRef<Path>) -> std::io::Result<()> {
+    let dir = dir.as_ref();
+    std::fs::create_dir_all(dir)?;
+    for (i, step) in steps.iter().enumerate() {
+        if !step.diff.is_empty() {
+            std::fs::write(dir.join(format!("{i:03}_{}.dot", step.pass)), &step.dot)?;
+        }
+    }
+    Ok(())
+}
+
+/// Number of passes recorded so far, if passes are being recorded
+pub(crate) fn recorded_passes(graph: &Graph) -> Option<usize> {
+    graph.pass_snapshots.as_ref().map(|s| s.len())
+}
+
+/// Snapshot the graph after a pass ran, if passes are being recorded. Passes made of other passes, like tuples, aren't
+/// recorded themselves when the passes inside them already were since `recorded_before`.
+pub(crate) fn record_pass(
+    graph: &mut Graph,
+    name: impl FnOnce() -> String,
+    recorded_before: Option<usize>,
+) {
+    if recorded_passes(graph).is_none() || recorded_passes(graph) != recorded_before {
+        return;
+    }
+    let snapshot = GraphSnapshot::new(graph);
+    graph
+        .pass_snapshots
+        .as_mut()
+        .unwrap()
+        .push((name(), snapshot));
+}
+
+/// Name of a compiler without module paths, so tuples of passes stay readable
+pub(crate) fn pass_name<C>() -> String {
+    Regex::new(r"\b[a-z_][a-z0-9_]*::")
+        .unwrap()
+        .replace_all(std::any::type_name::<C>(), "")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+    use crate::debug::{compile_passes, diff, dump_passes, OutputMismatch};
+    use itertools::Itertools;
+
+    #[test]
+    fn test_graph_diff() {
+        let build = |cx: &mut Graph| {
+            let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+            (a * 1.).exp2().retrieve()
+        };
+        let (mut before, mut after) = (Graph::new(), Graph::new());
+        let b = build(&mut before);
+        let mut out = build(&mut after);
+        after.compile(GenericCompiler::default(), &mut out);
+        let d = diff(&before, &after);
+        assert_eq!(
+            d.removed
+                .iter()
+                .map(|(_, op)| op.as_str())
+                .sorted()
+                .collect::<Vec<_>>(),
+            vec!["Constant", "Mul"]
+        );
+        assert!(d.added.is_empty() && d.retyped.is_empty() && d.outputs.is_empty());
+        assert!(d.to_string().contains("- Mul"));
+
+        // Changing an op shows up as a retype, and its output no longer matches once both graphs run
+        *after.node_weight_mut(out.id).unwrap() = Box::new(crate::op::Sin);
+        before.execute();
+        after.execute();
+        let d = diff(&before, &after);
+        assert_eq!(
+            d.retyped,
+            vec![(b.id, "Exp2".to_string(), "Sin".to_string())]
+        );
+        assert!(matches!(
+            d.outputs[..],
+            [OutputMismatch::Value { index: 0, a_value, .. }] if a_value == 2.
+        ));
+
+        // Outputs that stop being retrieved are caught
+        after.to_retrieve.clear();
+        assert_eq!(
+            diff(&before, &after).outputs,
+            vec![OutputMismatch::Missing(b.id)]
+        );
+    }
+
+    #[test]
+    fn test_compile_passes() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let mut b = (a * 1.).exp2().retrieve();
+        let steps = compile_passes(&mut cx, GenericCompiler::default(), &mut b);
+        assert!(steps.len() > 1);
+        assert!(steps.iter().all(|s| !s.pass.starts_with('(')));
+        // Some pass removed the multiply by one, and the final graph still runs
+        assert!(steps
+            .iter()
+            .any(|s| s.diff.removed.iter().any(|(_, op)| op == "Mul")));
+        assert!(cx.pass_snapshots.is_none());
+        cx.execute();
+        assert_close(&b.data(), &[2., 4., 8.]);
+
+        let dir = std::env::temp_dir().join(format!("luminal_passes_{}", uuid::Uuid::new_v4()));
+        dump_passes(&steps, &dir).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(files, steps.iter().filter(|s| !s.diff.is_empty()).count());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,1021 @@
+//! Device placement: nodes tagged with the device they run on, and the transfers between them.
+//!
+//! Devices are numbers on the graph's nodes. `InsertTransfers` puts a `Transfer` wherever data crosses between two of
+//! them, which hands its buffer on as it is while both devices are host memory. Binding device numbers to backend
+//! devices with [`BindDevices`] turns the transfers between them into [`DeviceCopy`]s, which copy each buffer out of
+//! one device and into the other.
+
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+};
+
+use itertools::Itertools;
+use petgraph::{
+    algo::{has_path_connecting, toposort},
+    visit::EdgeRef,
+    Direction,
+};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{backend::Device, prelude::*};
+
+/// Move a tensor from one device to another.
+///
+/// The buffer is passed through as it is laid out, so consumers keep reading it through their own views. Between
+/// devices bound with [`BindDevices`] this becomes a [`DeviceCopy`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transfer {
+    pub from: usize,
+    pub to: usize,
+}
+
+impl Operator for Transfer {
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        transfer_cost(input_shapes)
+    }
+    fn process(&mut self, mut inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        vec![inp.pop().unwrap().0.cloned()]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+fn transfer_cost(input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+    let bytes = input_shapes.first()?.n_physical_elements().to_usize()? * 4;
+    Some(OpCost {
+        flops: 0,
+        bytes_read: bytes,
+        bytes_written: bytes,
+    })
+}
+
+/// A backend device bound to a device number, with its type erased so one graph can span different kinds of device
+pub trait BoundDevice: Debug + Send {
+    /// Whether a tensor is one of this device's buffers
+    fn holds(&self, tensor: &Tensor) -> bool;
+    /// Copy host data into a new buffer on the device
+    fn upload(&mut self, data: &[f32]) -> Tensor;
+    /// Copy one of this device's buffers back to the host
+    fn download(&mut self, tensor: &Tensor) -> Result<Vec<f32>, LuminalError>;
+    fn boxed_clone(&self) -> Box<dyn BoundDevice>;
+}
+
+/// A device along with the staging memory for its copies
+struct Bound<D: Device> {
+    device: D,
+    staging: D::Staging,
+}
+
+impl<D: Device> Debug for Bound<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.device)
+    }
+}
+
+impl<D: Device + Send> BoundDevice for Bound<D>
+where
+    D::Staging: Send,
+{
+    fn holds(&self, tensor: &Tensor) -> bool {
+        self.device.holds(tensor)
+    }
+    fn upload(&mut self, data: &[f32]) -> Tensor {
+        Tensor::new(self.device.copy_to_device(&mut self.staging, data))
+    }
+    fn download(&mut self, tensor: &Tensor) -> Result<Vec<f32>, LuminalError> {
+        Ok(self
+            .device
+            .copy_from_device(&mut self.staging, tensor.get::<D::Buffer>()?))
+    }
+    fn boxed_clone(&self) -> Box<dyn BoundDevice> {
+        Box::new(Bound {
+            device: self.device.clone(),
+            staging: D::Staging::default(),
+        })
+    }
+}
+
+/// Copy a tensor's buffer out of one device and into another. Either side left unbound is host memory.
+///
+/// Data already on the destination is passed through, and host data (like weights set on the host) is uploaded
+/// straight away.
+#[derive(Debug)]
+pub struct DeviceCopy {
+    pub from: usize,
+    pub to: usize,
+    source: Option<Box<dyn BoundDevice>>,
+    dest: Option<Box<dyn BoundDevice>>,
+}
+
+impl Operator for DeviceCopy {
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        transfer_cost(input_shapes)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        mut inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let tensor = inp.pop().unwrap().0;
+        if self
+            .dest
+            .as_ref()
+            .is_some_and(|d| d.holds(tensor.borrowed()))
+        {
+            return Ok(vec![tensor.cloned()]);
+        }
+        let data = match &mut self.source {
+            Some(source) if source.holds(tensor.borrowed()) => {
+                source.download(tensor.borrowed())?
+            }
+            _ => tensor.borrowed().widened_f32s()?.into_owned(),
+        };
+        Ok(vec![match &mut self.dest {
+            Some(dest) => dest.upload(&data),
+            None => Tensor::new(data),
+        }])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Bind device numbers to backend devices, and swap every `Transfer` touching a bound device for a [`DeviceCopy`]
+/// between them. `Offload`'s copies onto a bound device upload to it. Run after the transfers are in, from `InsertTransfers`, `PipelineSplit` or `Offload`, and before
+/// compiling each device's ops for its backend.
+#[derive(Debug, Default)]
+pub struct BindDevices {
+    devices: FxHashMap<usize, Box<dyn BoundDevice>>,
+}
+
+impl BindDevices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bind a device number to a backend device
+    pub fn device<D: Device + Send>(mut self, number: usize, device: D) -> Self
+    where
+        D::Staging: Send,
+    {
+        self.devices.insert(
+            number,
+            Box::new(Bound {
+                device,
+                staging: D::Staging::default(),
+            }),
+        );
+        self
+    }
+}
+
+impl Compiler for BindDevices {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        for node in graph.node_indices().collect_vec() {
+            if let Some(copy) = graph.try_get_op_mut::<StartCopy>(node) {
+                copy.dest = self.devices.get(&copy.to).map(|d| d.boxed_clone());
+                continue;
+            }
+            let Some(Transfer { from, to }) = graph.try_get_op::<Transfer>(node).cloned() else {
+                continue;
+            };
+            let bind = |n| self.devices.get(&n).map(|d| d.boxed_clone());
+            let (source, dest) = (bind(from), bind(to));
+            if source.is_none() && dest.is_none() {
+                continue;
+            }
+            *graph.graph.node_weight_mut(node).unwrap() = Box::new(DeviceCopy {
+                from,
+                to,
+                source,
+                dest,
+            });
+        }
+    }
+}
+
+impl Graph {
+    /// Place every node built inside `f` on a device
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    /// let b = cx.with_device(1, |_| a.exp2() * 2.).retrieve();
+    /// cx.compile(InsertTransfers, b);
+    /// assert_eq!(cx.devices[&b.id], 1);
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![4., 8., 16.]);
+    /// ```
+    pub fn with_device<R>(&mut self, device: usize, f: impl FnOnce(&mut Graph) -> R) -> R {
+        let outer = self.current_device.replace(device);
+        let out = f(self);
+        self.current_device = outer;
+        out
+    }
+
+    /// Place nodes on a device
+    pub fn place<T: ToIds>(&mut self, nodes: T, device: usize) {
+        for node in nodes.to_ids() {
+            self.devices.insert(node, device);
+        }
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Place the op producing this tensor on a device
+    pub fn on_device(self, device: usize) -> Self {
+        self.graph().devices.insert(self.id, device);
+        self
+    }
+}
+
+impl DynGraphTensor {
+    /// Place the op producing this tensor on a device
+    pub fn on_device(self, device: usize) -> Self {
+        self.graph().devices.insert(self.id, device);
+        self
+    }
+}
+
+/// Give every node a device and insert a `Transfer` wherever data crosses from one device to another.
+///
+/// Nodes placed with `on_device`, `place` or `with_device` stay where they are. Other ops run on the device of the
+/// first op feeding them, or device 0 if only sources feed them, and sources like weights live on the device of their
+/// first consumer. A tensor read on several other devices is transferred once to each of them.
+#[derive(Debug, Default)]
+pub struct InsertTransfers;
+
+impl Compiler for InsertTransfers {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        let order = toposort(&graph.graph, None).unwrap();
+        let is_source = |graph: &Graph, node| {
+            graph
+                .edges_directed(node, Direction::Incoming)
+                .next()
+                .is_none()
+        };
+        // Ops first, in order, so each one can follow its inputs
+        for node in &order {
+            if graph.devices.contains_key(node) || is_source(graph, *node) {
+                continue;
+            }
+            let device = graph
+                .get_sources(*node)
+                .into_iter()
+                .filter(|(src, _, _)| !is_source(graph, *src))
+                .find_map(|(src, _, _)| graph.devices.get(&src).copied())
+                .unwrap_or_default();
+            graph.devices.insert(*node, device);
+        }
+        for node in &order {
+            if graph.devices.contains_key(node) {
+                continue;
+            }
+            let device = graph
+                .edges_directed(*node, Direction::Outgoing)
+                .filter(|e| e.weight().as_data().is_some())
+                .min_by_key(|e| order.iter().position(|n| *n == e.target()))
+                .and_then(|e| graph.devices.get(&e.target()).copied())
+                .unwrap_or_default();
+            graph.devices.insert(*node, device);
+        }
+
+        for node in order {
+            let from = graph.devices[&node];
+            let crossing = graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter_map(|e| Some((e.id(), e.target(), e.weight().as_data()?)))
+                .filter(|(_, target, _)| graph.devices[target] != from)
+                .collect_vec();
+            let mut transfers = FxHashMap::default();
+            for (edge, target, (input_order, output_order, shape)) in crossing {
+                let to = graph.devices[&target];
+                let transfer = *transfers.entry((output_order, to)).or_insert_with(|| {
+                    let transfer = graph
+                        .add_op(Transfer { from, to })
+                        .input(node, output_order, shape)
+                        .finish();
+                    graph.devices.insert(transfer, to);
+                    transfer
+                });
+                graph.remove_edge(edge);
+                graph.add_edge(
+                    transfer,
+                    target,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Split a graph into stages running one after another on devices 0, 1, 2..., then insert the transfers between them.
+///
+/// Ops are cut into stages in execution order, so only the activations at each boundary move between devices.
+/// Ops already placed on a device keep their placement.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R1<4>>().set([1., 2., 3., 4.]);
+/// let b = a.exp2().sqrt().sin().cos().retrieve();
+/// cx.compile(PipelineSplit::even(2), b);
+/// assert_eq!(cx.devices[&b.id], 1);
+/// cx.execute();
+/// ```
+#[derive(Debug)]
+pub struct PipelineSplit {
+    stages: Stages,
+}
+
+#[derive(Debug)]
+enum Stages {
+    Even(usize),
+    Budgets(Vec<usize>),
+}
+
+impl PipelineSplit {
+    /// Split into `devices` stages doing roughly the same amount of work each
+    pub fn even(devices: usize) -> Self {
+        assert!(devices > 0, "Need at least one device to split across");
+        Self {
+            stages: Stages::Even(devices),
+        }
+    }
+
+    /// Fill each device with kept weights up to its memory budget in bytes, then move on to the next one. The last
+    /// device takes whatever is left over, so a model too big for the first devices can spill onto the host.
+    pub fn by_memory(budgets: Vec<usize>) -> Self {
+        assert!(
+            !budgets.is_empty(),
+            "Need at least one device to split across"
+        );
+        Self {
+            stages: Stages::Budgets(budgets),
+        }
+    }
+}
+
+impl Compiler for PipelineSplit {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, ids: T) {
+        let ops = toposort(&graph.graph, None)
+            .unwrap()
+            .into_iter()
+            .filter(|n| {
+                graph
+                    .edges_directed(*n, Direction::Incoming)
+                    .next()
+                    .is_some()
+            })
+            .collect_vec();
+        match &self.stages {
+            Stages::Even(devices) => {
+                // Ops without an estimate yet still count for something
+                let work = ops
+                    .iter()
+                    .map(|n| {
+                        graph
+                            .op_cost(*n)
+                            .map(|c| c.flops)
+                            .unwrap_or_default()
+                            .max(1)
+                    })
+                    .collect_vec();
+                let total = work.iter().sum::<usize>();
+                let mut done = 0;
+                for (node, work) in ops.iter().zip(work) {
+                    // Stage by where the middle of this op's work falls
+                    let stage = ((done * 2 + work) * devices / (total * 2)).min(devices - 1);
+                    graph.devices.entry(*node).or_insert(stage);
+                    done += work;
+                }
+            }
+            Stages::Budgets(budgets) => {
+                let mut stage = 0;
+                let mut used = 0;
+                let mut counted = FxHashSet::default();
+                for node in ops {
+                    // Weights are counted on their first consumer
+                    let weights = graph
+                        .get_sources(node)
+                        .into_iter()
+                        .filter(|(src, _, _)| {
+                            graph.no_delete.contains(src)
+                                && !graph.devices.contains_key(src)
+                                && graph
+                                    .edges_directed(*src, Direction::Incoming)
+                                    .next()
+                                    .is_none()
+                        })
+                        .filter(|(src, _, _)| counted.insert(*src))
+                        .map(|(src, _, _)| graph.output_bytes(src))
+                        .sum::<usize>();
+                    if used > 0 && used + weights > budgets[stage] && stage + 1 < budgets.len() {
+                        stage += 1;
+                        used = 0;
+                    }
+                    used += weights;
+                    graph.devices.entry(node).or_insert(stage);
+                }
+            }
+        }
+        InsertTransfers.compile(graph, ids);
+    }
+}
+
+/// Keep weights on the host and stream them to the device just before the layer using them runs.
+///
+/// Each weight's transfer is scheduled while the layer before it is still running, and after the layer `prefetch + 1`
+/// places back is done with its weights, whose buffers are freed once their last consumer runs. With the default
+/// prefetch of 1 the device holds at most two layers of streamed weights at once.
+///
+/// Copies run on another thread while the layers before them compute: a `StartCopy` kicks each one off, and an
+/// `AwaitCopy` waits for it once the previous layer is done. Copies onto the host reuse the buffers freed by earlier
+/// layers, so with a prefetch of 1 they take turns between two buffers.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let input = cx.tensor::<R1<4>>().set([1., 2., 3., 4.]);
+/// let w1 = cx.tensor::<R2<4, 4>>().set(vec![0.5; 16]).keep();
+/// let w2 = cx.tensor::<R2<4, 4>>().set(vec![0.25; 16]).keep();
+/// let out = input.matmul(w1).matmul(w2).retrieve();
+/// // Device 1 is the host here
+/// cx.compile(Offload::new((w1, w2), 1), out);
+/// cx.execute();
+/// assert_eq!(out.data(), vec![5.; 4]);
+/// ```
+#[derive(Debug)]
+pub struct Offload {
+    weights: Vec<NodeIndex>,
+    host: usize,
+    prefetch: usize,
+}
+
+impl Offload {
+    /// Stream `weights` from the `host` device
+    pub fn new<T: ToIds>(weights: T, host: usize) -> Self {
+        Self {
+            weights: weights.to_ids(),
+            host,
+            prefetch: 1,
+        }
+    }
+
+    /// Number of layers ahead to load weights. Each extra layer holds one more set of weights on the device.
+    pub fn prefetch(mut self, layers: usize) -> Self {
+        self.prefetch = layers;
+        self
+    }
+}
+
+impl Compiler for Offload {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, ids: T) {
+        graph.place(self.weights.clone(), self.host);
+        InsertTransfers.compile(graph, ids);
+
+        let order = toposort(&graph.graph, None).unwrap();
+        let position = order
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (*n, i))
+            .collect::<FxHashMap<_, _>>();
+        // Streamed weights in the order they're needed, with their first and last consumers
+        let streams = order
+            .iter()
+            .filter(|n| {
+                graph.try_get_op::<Transfer>(**n).is_some()
+                    && graph
+                        .get_sources(**n)
+                        .iter()
+                        .any(|(src, _, _)| self.weights.contains(src))
+            })
+            .map(|transfer| {
+                let (first, last) = graph
+                    .edges_directed(*transfer, Direction::Outgoing)
+                    .map(|e| e.target())
+                    .minmax_by_key(|n| position[n])
+                    .into_option()
+                    .unwrap();
+                (*transfer, first, last)
+            })
+            .sorted_by_key(|(_, first, _)| position[first])
+            .collect_vec();
+
+        for (i, (_, first, last)) in streams.iter().enumerate() {
+            // Load ahead while this layer runs
+            if let Some((next, _, _)) = streams.get(i + self.prefetch) {
+                if !has_path_connecting(&graph.graph, *first, *next, None) {
+                    graph.add_schedule_dependency(*next, *first);
+                }
+            }
+            // But not so far ahead that this layer's weights are still held
+            if let Some((next, _, _)) = streams.get(i + self.prefetch + 1) {
+                if !has_path_connecting(&graph.graph, *next, *last, None) {
+                    graph.add_schedule_dependency(*last, *next);
+                }
+            }
+        }
+
+        // Copy in the background, and only wait for each copy once the layer before has run
+        let pool = BufferPool::default();
+        for (i, (transfer, _, _)) in streams.iter().enumerate() {
+            let Transfer { from, to } = graph.get_op::<Transfer>(*transfer).clone();
+            let (weight, _, _) = graph.get_sources(*transfer)[0];
+            share(graph, weight);
+            *graph.graph.node_weight_mut(*transfer).unwrap() = Box::new(StartCopy {
+                from,
+                to,
+                dest: None,
+                pool: pool.clone(),
+            });
+            let wait = graph
+                .add_op(AwaitCopy)
+                .input(*transfer, 0, ShapeTracker::new(&[]))
+                .finish();
+            graph.devices.insert(wait, to);
+            for (edge, target, weight) in graph
+                .edges_directed(*transfer, Direction::Outgoing)
+                .filter(|e| e.target() != wait && e.weight().as_data().is_some())
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .collect_vec()
+            {
+                graph.remove_edge(edge);
+                graph.add_edge(wait, target, weight);
+            }
+            if let Some((_, _, last)) = i.checked_sub(1).map(|i| streams[i]) {
+                if !has_path_connecting(&graph.graph, wait, last, None) {
+                    graph.add_schedule_dependency(last, wait);
+                }
+            }
+        }
+    }
+}
+
+/// Hand a weight's host data to the copy thread as `SharedData`, so starting a copy doesn't copy it on the spot
+fn share(graph: &mut Graph, weight: NodeIndex) {
+    if let Some(tensor) = graph.tensors.get_mut(&(weight, 0)) {
+        if let Some(data) = tensor.downcast_mut::<Vec<f32>>() {
+            *tensor = Tensor::new(SharedData::new(std::mem::take(data)));
+        }
+    }
+}
+
+/// Host buffers handed back by streamed weights once their last consumer is done with them, for the next copies to
+/// reuse. Each `Offload` gets one, so with a prefetch of 1 its copies take turns between two buffers.
+#[derive(Debug, Clone, Default)]
+struct BufferPool {
+    free: Arc<Mutex<Vec<Vec<f32>>>>,
+    allocated: Arc<AtomicUsize>,
+}
+
+impl BufferPool {
+    /// A buffer of `len` elements, reusing a freed one if there is one
+    fn take(&self, len: usize) -> Vec<f32> {
+        let mut buffer = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Vec::new()
+        });
+        buffer.resize(len, 0.);
+        buffer
+    }
+}
+
+/// A streamed weight in a buffer from the pool, going back to it once dropped
+struct Pooled {
+    data: Vec<f32>,
+    pool: BufferPool,
+}
+
+impl AsRef<[f32]> for Pooled {
+    fn as_ref(&self) -> &[f32] {
+        &self.data
+    }
+}
+
+impl Drop for Pooled {
+    fn drop(&mut self) {
+        let data = std::mem::take(&mut self.data);
+        self.pool.free.lock().unwrap().push(data);
+    }
+}
+
+type CopyHandle = JoinHandle<Result<Tensor, LuminalError>>;
+
+/// A copy running in the background, picked up by an [`AwaitCopy`]
+#[derive(Debug, Clone)]
+struct PendingCopy(Arc<Mutex<Option<CopyHandle>>>);
+
+impl Data for PendingCopy {
+    fn as_any(&self) -> &dyn std::any::Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+        self
+    }
+    fn device(&self) -> &'static str {
+        "pending copy"
+    }
+}
+
+/// Start copying a streamed weight onto its device on another thread, so ops keep running while it loads. Copies onto
+/// the host go into buffers reused from earlier layers.
+#[derive(Debug)]
+pub struct StartCopy {
+    pub from: usize,
+    pub to: usize,
+    dest: Option<Box<dyn BoundDevice>>,
+    pool: BufferPool,
+}
+
+impl StartCopy {
+    /// How many buffers copies have had to allocate, rather than reusing one freed by an earlier layer
+    pub fn buffers_allocated(&self) -> usize {
+        self.pool.allocated.load(Ordering::Relaxed)
+    }
+}
+
+impl Operator for StartCopy {
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        transfer_cost(input_shapes)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let tensor = inp[0].0.borrowed();
+        // Weights set after compiling aren't shared yet, so they get copied here
+        let source = match tensor.downcast_ref::<SharedData>() {
+            Some(shared) => shared.clone(),
+            None => SharedData::new(tensor.widened_f32s()?.into_owned()),
+        };
+        let mut dest = self.dest.as_ref().map(|d| d.boxed_clone());
+        let pool = self.pool.clone();
+        let handle = std::thread::spawn(move || {
+            let data = (*source.0).as_ref();
+            Ok(match &mut dest {
+                Some(dest) => dest.upload(data),
+                None => {
+                    let mut buffer = pool.take(data.len());
+                    buffer.copy_from_slice(data);
+                    Tensor::new(SharedData::new(Pooled { data: buffer, pool }))
+                }
+            })
+        });
+        Ok(vec![Tensor::new(PendingCopy(Arc::new(Mutex::new(Some(
+            handle,
+        )))))])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Wait for a [`StartCopy`] to finish, handing on the copied buffer
+#[derive(Debug, Clone, PartialEq)]
+pub struct AwaitCopy;
+
+impl Operator for AwaitCopy {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let pending = inp[0].0.borrowed().get::<PendingCopy>()?;
+        let handle = pending
+            .0
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(LuminalError::WrongData {
+                expected: "a copy still running",
+                found: "one already waited for".to_string(),
+            })?;
+        let copied = handle.join().map_err(|panic| {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "the copy thread panicked".to_string());
+            LuminalError::CopyFailed(message)
+        })?;
+        Ok(vec![copied?])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use itertools::Itertools;
+    crate::test_imports!();
+
+    use crate::backend::Device;
+
+    /// Host memory counting the elements copied in and out of it
+    #[derive(Debug, Clone, Default)]
+    struct Counted {
+        uploaded: Arc<AtomicUsize>,
+        downloaded: Arc<AtomicUsize>,
+    }
+
+    #[derive(Debug, Clone)]
+    struct CountedBuffer(Vec<f32>);
+
+    impl Data for CountedBuffer {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn size(&self) -> usize {
+            self.0.len() * 4
+        }
+        fn device(&self) -> &'static str {
+            "counted"
+        }
+    }
+
+    impl Device for Counted {
+        type Buffer = CountedBuffer;
+        type Staging = ();
+        fn alloc(&self, len: usize) -> CountedBuffer {
+            CountedBuffer(vec![0.; len])
+        }
+        fn copy_to_device(&self, _: &mut (), data: &[f32]) -> CountedBuffer {
+            self.uploaded.fetch_add(data.len(), Ordering::Relaxed);
+            CountedBuffer(data.to_vec())
+        }
+        fn copy_from_device(&self, _: &mut (), buffer: &CountedBuffer) -> Vec<f32> {
+            self.downloaded.fetch_add(buffer.0.len(), Ordering::Relaxed);
+            buffer.0.clone()
+        }
+    }
+
+    fn transfers(cx: &Graph) -> Vec<(usize, usize)> {
+        cx.node_indices()
+            .filter_map(|n| cx.try_get_op::<Transfer>(n))
+            .map(|t| (t.from, t.to))
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn test_insert_transfers() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let b = a.exp2().on_device(1);
+        let c = (b + a).retrieve();
+        let d = (b * a).on_device(2).retrieve();
+        cx.compile(InsertTransfers, (c, d));
+
+        // a lives with its first consumer on 1, c follows b there, and both a and b go over to 2
+        assert_eq!(cx.devices[&a.id], 1);
+        assert_eq!(cx.devices[&c.id], 1);
+        assert_eq!(transfers(&cx), vec![(1, 2), (1, 2)]);
+        cx.execute();
+        assert_exact(&c.data(), &[3., 6., 11.]);
+        assert_exact(&d.data(), &[2., 8., 24.]);
+    }
+
+    /// Doubles a tensor already on the counted device, like a kernel would
+    #[derive(Debug)]
+    struct DoubleOnDevice;
+
+    impl Operator for DoubleOnDevice {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<crate::op::Tensor> {
+            let buffer = inp[0].0.borrowed().get::<CountedBuffer>().unwrap();
+            vec![crate::op::Tensor::new(CountedBuffer(
+                buffer.0.iter().map(|x| x * 2.).collect(),
+            ))]
+        }
+    }
+
+    #[test]
+    fn test_bind_devices() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let b = a.exp2();
+        let doubled = cx.add_op(DoubleOnDevice).input(b.id, 0, b.shape).finish();
+        cx.place(doubled, 1);
+        let c = (GraphTensor::<R1<3>>::from_id(doubled, b.shape, b.graph_ref) + 1.)
+            .on_device(0)
+            .retrieve();
+        let device = Counted::default();
+        cx.compile(
+            (
+                InsertTransfers,
+                BindDevices::new().device(1, device.clone()),
+            ),
+            c,
+        );
+
+        // Both transfers touch the bound device, so both became copies
+        assert_eq!(transfers(&cx), vec![]);
+        let copies = cx
+            .node_indices()
+            .filter_map(|n| cx.try_get_op::<DeviceCopy>(n))
+            .map(|t| (t.from, t.to))
+            .sorted()
+            .collect_vec();
+        assert_eq!(copies, vec![(0, 1), (1, 0)]);
+        cx.execute();
+        assert_exact(&c.data(), &[5., 9., 17.]);
+        assert_eq!(device.uploaded.load(Ordering::Relaxed), 3);
+        assert_eq!(device.downloaded.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_pipeline_split() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R2<2, 4>>().set(random_vec(8));
+        let w1 = cx.tensor::<R2<4, 8>>().set(random_vec(32)).keep();
+        let w2 = cx.tensor::<R2<8, 8>>().set(random_vec(64)).keep();
+        let w3 = cx.tensor::<R2<8, 2>>().set(random_vec(16)).keep();
+        let out = input
+            .matmul(w1)
+            .relu()
+            .matmul(w2)
+            .relu()
+            .matmul(w3)
+            .retrieve();
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+
+        cx.compile(PipelineSplit::even(2), out);
+        assert_eq!(cx.devices[&w1.id], 0);
+        assert_eq!(cx.devices[&w3.id], 1);
+        // Only the activations at the boundary cross over
+        assert_eq!(transfers(&cx), vec![(0, 1)]);
+        cx.execute();
+        assert_exact(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_split_by_memory() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R1<4>>().set(random_vec(4));
+        let weights = (0..4)
+            .map(|_| cx.tensor::<R2<4, 4>>().set(random_vec(16)).keep())
+            .collect_vec();
+        let mut x = input;
+        for w in &weights {
+            x = x.matmul(*w).relu();
+        }
+        let out = x.retrieve();
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+
+        // Two weights fit on the first device, and the rest spill onto the second
+        cx.compile(PipelineSplit::by_memory(vec![2 * 16 * 4, 16 * 4]), out);
+        let placed = weights.iter().map(|w| cx.devices[&w.id]).collect_vec();
+        assert_eq!(placed, vec![0, 0, 1, 1]);
+        cx.execute();
+        assert_exact(&out.data(), &expected);
+    }
+
+    #[test]
+    fn test_offload() {
+        let device_peaks = [0, 1].map(|prefetch| {
+            let mut cx = Graph::new();
+            let input = cx.tensor::<R1<8>>().set(random_vec(8));
+            let weights = (0..4)
+                .map(|_| cx.tensor::<R2<8, 8>>().set(random_vec(64)).keep())
+                .collect_vec();
+            let mut x = input;
+            for w in &weights {
+                x = x.matmul(*w).relu();
+            }
+            let out = x.retrieve();
+            cx.execute();
+            let expected = out.data();
+            out.drop();
+
+            cx.compile(Offload::new(weights, 1).prefetch(prefetch), out);
+            let copies = cx
+                .node_indices()
+                .filter_map(|n| cx.try_get_op::<StartCopy>(n))
+                .collect_vec();
+            assert_eq!(
+                copies.iter().map(|c| (c.from, c.to)).collect_vec(),
+                vec![(1, 0); 4]
+            );
+            cx.execute();
+            assert_exact(&out.data(), &expected);
+            out.drop();
+            cx.execute();
+            assert_exact(&out.data(), &expected);
+            // Each copy reuses the buffer of the layer `prefetch + 1` back, across runs too
+            let copies = cx
+                .node_indices()
+                .filter_map(|n| cx.try_get_op::<StartCopy>(n))
+                .collect_vec();
+            assert_eq!(copies[0].buffers_allocated(), prefetch + 1);
+            let stats = cx.stats();
+            assert_eq!(stats.device_peak_memory[&1], 4 * 64 * 4);
+            stats.device_peak_memory[&0]
+        });
+        // Loading one layer ahead holds exactly one more weight on the device, and never all of them
+        assert_eq!(device_peaks[1] - device_peaks[0], 64 * 4);
+        assert!(device_peaks[1] < 4 * 64 * 4);
+    }
+
+    #[test]
+    fn test_offload_freeze() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R1<8>>();
+        let weights = (0..3)
+            .map(|_| cx.tensor::<R2<8, 8>>().set(random_vec(64)).keep())
+            .collect_vec();
+        let mut x = input;
+        for w in &weights {
+            x = x.matmul(*w).relu();
+        }
+        let out = x.retrieve();
+        let data = random_vec(8);
+        input.set(data.clone());
+        cx.execute();
+        let expected = out.data();
+        out.drop();
+
+        // Streaming copies can be moved between threads, so the graph can still be frozen
+        let model = cx.freeze(
+            Offload::new(weights, 1),
+            &[input.no_shape()],
+            &[out.no_shape()],
+        );
+        for _ in 0..2 {
+            assert_exact(&model.run(&[(&data, &[8])])[0], &expected);
+        }
+    }
+
+    /// A device whose uploads always fail
+    #[derive(Debug, Clone)]
+    struct Failing;
+
+    impl Device for Failing {
+        type Buffer = CountedBuffer;
+        type Staging = ();
+        fn alloc(&self, len: usize) -> CountedBuffer {
+            CountedBuffer(vec![0.; len])
+        }
+        fn copy_to_device(&self, _: &mut (), _: &[f32]) -> CountedBuffer {
+            panic!("device lost")
+        }
+        fn copy_from_device(&self, _: &mut (), buffer: &CountedBuffer) -> Vec<f32> {
+            buffer.0.clone()
+        }
+    }
+
+    #[test]
+    fn test_offload_failed_copy() {
+        let mut cx = Graph::new();
+        let input = cx.tensor::<R1<4>>().set(random_vec(4));
+        let weight = cx.tensor::<R2<4, 4>>().set(random_vec(16)).keep();
+        let out = input.matmul(weight).retrieve();
+        cx.compile(
+            (
+                Offload::new(vec![weight], 1),
+                BindDevices::new().device(0, Failing),
+            ),
+            out,
+        );
+        // The panic on the copy thread comes back as an error instead of taking down the run
+        let error = cx.try_execute().unwrap_err().to_string();
+        assert!(error.contains("Device copy failed: device lost"), "{error}");
+    }
+}
@@ -0,0 +1,394 @@
+use std::{
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use memmap2::Mmap;
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// The element type of a tensor stored on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskDType {
+    F32,
+    F16,
+    BF16,
+}
+
+impl DiskDType {
+    /// Size of a single element in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            DiskDType::F32 => 4,
+            DiskDType::F16 | DiskDType::BF16 => 2,
+        }
+    }
+}
+
+/// A weight stored in a file, which isn't read until an op consumes it.
+///
+/// The weight can sit anywhere in the file as long as it's a contiguous run of f32, f16 or bf16 elements, so weights in
+/// other formats (like unquantized GGUF tensors) can be pointed at with `DiskTensor::new`. Only safetensors files have a
+/// loader here, and quantized formats aren't supported.
+///
+/// The file gets memory-mapped the first time this op runs. Aligned f32 weights are handed out as `SharedData` pointing
+/// straight into the map, so the OS can page them back out when memory gets tight. Half precision weights are converted
+/// to f32 on each run, and the converted copy is dropped along with the rest of the intermediate tensors.
+#[derive(Clone)]
+pub struct DiskTensor {
+    pub path: PathBuf,
+    /// Byte offset of the tensor within the file
+    pub offset: usize,
+    pub n_elements: usize,
+    pub dtype: DiskDType,
+    mmap: Option<Arc<Mmap>>,
+}
+
+impl std::fmt::Debug for DiskTensor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DiskTensor({}@{})", self.path.display(), self.offset)
+    }
+}
+
+impl DiskTensor {
+    pub fn new(path: impl AsRef<Path>, offset: usize, n_elements: usize, dtype: DiskDType) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            offset,
+            n_elements,
+            dtype,
+            mmap: None,
+        }
+    }
+
+    /// Whether the backing file is currently mapped
+    pub fn is_mapped(&self) -> bool {
+        self.mmap.is_some()
+    }
+
+    /// Unmap the backing file. It will be mapped again the next time this op runs.
+    pub fn unload(&mut self) {
+        self.mmap = None;
+    }
+}
+
+/// A region of a memory map, viewed as f32s
+struct MappedF32 {
+    mmap: Arc<Mmap>,
+    offset: usize,
+    len: usize,
+}
+
+impl AsRef<[f32]> for MappedF32 {
+    fn as_ref(&self) -> &[f32] {
+        // Safety: alignment and length are checked when this is created
+        unsafe {
+            std::slice::from_raw_parts(self.mmap.as_ptr().add(self.offset) as *const f32, self.len)
+        }
+    }
+}
+
+impl Operator for DiskTensor {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        _: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let mmap = match &self.mmap {
+            Some(mmap) => mmap.clone(),
+            None => {
+                let mmap = File::open(&self.path)
+                    .and_then(|file| unsafe { Mmap::map(&file) })
+                    .map_err(|e| {
+                        LuminalError::BadWeightFile(format!(
+                            "Failed to map {}: {e}",
+                            self.path.display()
+                        ))
+                    })?;
+                self.mmap.insert(Arc::new(mmap)).clone()
+            }
+        };
+        // The file may have changed since the tensor was pointed at it, so don't trust the range
+        let end = self
+            .n_elements
+            .checked_mul(self.dtype.size())
+            .and_then(|len| len.checked_add(self.offset))
+            .filter(|end| *end <= mmap.len())
+            .ok_or_else(|| {
+                LuminalError::BadWeightFile(format!(
+                    "{} is {} bytes long, too short for {} {:?} elements at byte {}",
+                    self.path.display(),
+                    mmap.len(),
+                    self.n_elements,
+                    self.dtype,
+                    self.offset
+                ))
+            })?;
+        let bytes = &mmap[self.offset..end];
+        let data = match self.dtype {
+            DiskDType::F32
+                if cfg!(target_endian = "little") && bytes.as_ptr() as usize % 4 == 0 =>
+            {
+                return Ok(vec![Tensor::new(SharedData::new(MappedF32 {
+                    mmap,
+                    offset: self.offset,
+                    len: self.n_elements,
+                }))]);
+            }
+            DiskDType::F32 => bytes
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect::<Vec<_>>(),
+            DiskDType::F16 => bytes
+                .chunks_exact(2)
+                .map(|c| f16::from_le_bytes([c[0], c[1]]).to_f32())
+                .collect(),
+            DiskDType::BF16 => bytes
+                .chunks_exact(2)
+                .map(|c| bf16::from_le_bytes([c[0], c[1]]).to_f32())
+                .collect(),
+        };
+        Ok(vec![Tensor::new(data)])
+    }
+
+    fn custom(&mut self, key: &str, _: Box<dyn std::any::Any>) -> Option<Box<dyn std::any::Any>> {
+        if key == "unload" {
+            self.unload();
+        }
+        None
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Point every weight of a model at its tensor in a safetensors file. Nothing is read until the graph runs.
+///
+/// Panics if a weight is missing from the file, if the file holds a different number of elements for it than the
+/// model's tensor has, or if the header points past the end of the file.
+pub fn load_safetensors<M: SerializeModule>(path: impl AsRef<Path>, model: &M, graph: &mut Graph) {
+    let path = path.as_ref();
+    // Header is a little-endian u64 length followed by a json table of tensors
+    let mut file = File::open(path).unwrap();
+    let file_len = file.metadata().unwrap().len();
+    let mut header_len = [0; 8];
+    file.read_exact(&mut header_len).unwrap();
+    let header_len = u64::from_le_bytes(header_len);
+    // Check the length against the file before trusting it with an allocation
+    assert!(
+        header_len <= file_len - 8,
+        "{} has a {header_len} byte header, but is only {file_len} bytes long",
+        path.display()
+    );
+    let header_len = header_len as usize;
+    let mut header = vec![0; header_len];
+    file.read_exact(&mut header).unwrap();
+    let header: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_slice(&header).unwrap();
+
+    let mut weights = Serializer::default();
+    model.serialize(&mut weights);
+    for (weight_name, node) in weights.state {
+        let name = weight_name.replace('/', ".");
+        let info = header
+            .get(&name)
+            .unwrap_or_else(|| panic!("{name} not found in {}", path.display()));
+        let dtype = match info["dtype"].as_str().unwrap() {
+            "F32" => DiskDType::F32,
+            "F16" => DiskDType::F16,
+            "BF16" => DiskDType::BF16,
+            d => panic!("Unsupported dtype for {name}: {d}"),
+        };
+        let start = info["data_offsets"][0].as_u64().unwrap();
+        let end = info["data_offsets"][1].as_u64().unwrap();
+        assert!(
+            start <= end && end <= file_len - 8 - header_len as u64,
+            "{name} is stored at bytes {start}..{end} of the data, past the end of {}",
+            path.display()
+        );
+        let (start, end) = (start as usize, end as usize);
+        let n_elements = info["shape"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d.as_u64().unwrap() as usize)
+            .product::<usize>();
+        assert_eq!(
+            end - start,
+            n_elements * dtype.size(),
+            "{name} has {n_elements} elements, but takes up {} bytes in {}",
+            end - start,
+            path.display()
+        );
+        if let Some(expected) = weights.shapes[&weight_name].n_elements().to_usize() {
+            assert_eq!(
+                n_elements,
+                expected,
+                "{name} has {n_elements} elements in {}, but the model expects {expected}",
+                path.display()
+            );
+        }
+        *graph.graph.node_weight_mut(node).unwrap() = Box::new(DiskTensor::new(
+            path,
+            8 + header_len + start,
+            n_elements,
+            dtype,
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    crate::test_imports!();
+
+    struct Model {
+        a: GraphTensor<R1<3>>,
+        inner: Inner,
+    }
+
+    struct Inner {
+        b: GraphTensor<R2<2, 2>>,
+    }
+
+    /// `Model` with too many elements in `a`
+    struct Mismatched {
+        a: GraphTensor<R1<4>>,
+        inner: Inner,
+    }
+
+    impl SerializeModule for Mismatched {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("a", self.a);
+            s.module("inner", &self.inner);
+        }
+    }
+
+    impl SerializeModule for Model {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("a", self.a);
+            s.module("inner", &self.inner);
+        }
+    }
+
+    impl SerializeModule for Inner {
+        fn serialize(&self, s: &mut Serializer) {
+            s.tensor("b", self.b);
+        }
+    }
+
+    #[test]
+    fn test_load_safetensors() {
+        let mut data = vec![];
+        for f in [1., 2., 3.] {
+            data.extend(f32::to_le_bytes(f));
+        }
+        for f in [-1., 0.5, 4., 8.] {
+            data.extend(f16::from_f32(f).to_le_bytes());
+        }
+        let header = r#"{"a":{"dtype":"F32","shape":[3],"data_offsets":[0,12]},"inner.b":{"dtype":"F16","shape":[2,2],"data_offsets":[12,20]}}"#;
+        let path = std::env::temp_dir().join(format!("{}.safetensors", uuid::Uuid::new_v4()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&(header.len() as u64).to_le_bytes())
+            .unwrap();
+        file.write_all(header.as_bytes()).unwrap();
+        file.write_all(&data).unwrap();
+        drop(file);
+
+        let mut cx = Graph::new();
+        let model = Model {
+            a: cx.tensor(),
+            inner: Inner { b: cx.tensor() },
+        };
+        load_safetensors(&path, &model, &mut cx);
+        assert!(!cx.get_op::<DiskTensor>(model.a.id).is_mapped());
+        let a = model.a.retrieve();
+        let b = model.inner.b.sum_reduce::<_, LAxis<1>>().retrieve();
+        cx.execute();
+
+        assert_exact(&a.data(), &[1., 2., 3.]);
+        assert_exact(&b.data(), &[-0.5, 12.]);
+
+        // A model expecting a different number of elements gets caught before anything is read
+        let mut cx = Graph::new();
+        let mismatched = Mismatched {
+            a: cx.tensor(),
+            inner: Inner { b: cx.tensor() },
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            load_safetensors(&path, &mismatched, &mut cx)
+        }));
+        std::fs::remove_file(path).unwrap();
+        let message = *result.unwrap_err().downcast::<String>().unwrap();
+        assert!(
+            message.contains("a has 3 elements in") && message.contains("but the model expects 4"),
+            "{message}"
+        );
+    }
+
+    /// Write a safetensors file holding `a` with 3 f32s, declaring the given header length
+    fn write_truncated(header_len: u64, data_len: usize) -> PathBuf {
+        let header = r#"{"a":{"dtype":"F32","shape":[3],"data_offsets":[0,12]}}"#;
+        let path = std::env::temp_dir().join(format!("{}.safetensors", uuid::Uuid::new_v4()));
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&header_len.to_le_bytes()).unwrap();
+        file.write_all(header.as_bytes()).unwrap();
+        file.write_all(&vec![0; data_len]).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_safetensors_out_of_bounds() {
+        struct Single {
+            a: GraphTensor<R1<3>>,
+        }
+        impl SerializeModule for Single {
+            fn serialize(&self, s: &mut Serializer) {
+                s.tensor("a", self.a);
+            }
+        }
+        let header_len = r#"{"a":{"dtype":"F32","shape":[3],"data_offsets":[0,12]}}"#.len() as u64;
+        let load = |path: &PathBuf| {
+            let mut cx = Graph::new();
+            let model = Single { a: cx.tensor() };
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                load_safetensors(path, &model, &mut cx)
+            }));
+            std::fs::remove_file(path).unwrap();
+            *result.unwrap_err().downcast::<String>().unwrap()
+        };
+
+        // A header length larger than the file is caught before it's allocated
+        let message = load(&write_truncated(u64::MAX, 12));
+        assert!(message.contains("byte header, but is only"), "{message}");
+
+        // So is a tensor running past the end of the data
+        let message = load(&write_truncated(header_len, 8));
+        assert!(message.contains("past the end of"), "{message}");
+
+        // A file that shrinks after loading gets reported when the tensor is read
+        let path = write_truncated(header_len, 12);
+        let mut cx = Graph::new();
+        let model = Single { a: cx.tensor() };
+        load_safetensors(&path, &model, &mut cx);
+        model.a.retrieve();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(8 + header_len + 4)
+            .unwrap();
+        let error = cx.try_execute().unwrap_err().to_string();
+        std::fs::remove_file(path).unwrap();
+        assert!(error.contains("too short for 3 F32 elements"), "{error}");
+    }
+}
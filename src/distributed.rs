@@ -0,0 +1,483 @@
+//! Tensor parallelism across processes: collectives that exchange shards between the ranks of a [`ProcessGroup`].
+//!
+//! Each process builds and runs the graph for its own rank, holding only its own shards of the weights. Collective ops
+//! hand their input to the group, which exchanges it with the other ranks and gives back the result. [`TcpGroup`]
+//! connects the ranks in a ring over TCP, like gloo does, and other transports (like NCCL) can be plugged in by
+//! implementing [`Communicator`].
+
+use std::{
+    fmt::Debug,
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use itertools::Itertools;
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+/// A way for the ranks of a group to exchange data. Every rank has to make the same calls, in the same order.
+pub trait Communicator: Send {
+    /// This process's position in the group
+    fn rank(&self) -> usize;
+    /// How many ranks are in the group
+    fn world_size(&self) -> usize;
+    /// Sum `data` elementwise across every rank, in place
+    fn all_reduce(&mut self, data: &mut [f32]) -> Result<(), LuminalError>;
+    /// Every rank's `data`, in rank order
+    fn all_gather(&mut self, data: &[f32]) -> Result<Vec<Vec<f32>>, LuminalError>;
+}
+
+/// This process's connection to the other ranks, shared by every collective op in its graph
+#[derive(Clone)]
+pub struct ProcessGroup(Arc<Mutex<Box<dyn Communicator>>>);
+
+impl ProcessGroup {
+    pub fn new(communicator: impl Communicator + 'static) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(communicator))))
+    }
+
+    /// Join a group connected over TCP, see [`TcpGroup::connect`]
+    pub fn tcp(
+        rank: usize,
+        world_size: usize,
+        master: impl ToSocketAddrs,
+    ) -> Result<Self, LuminalError> {
+        TcpGroup::connect(rank, world_size, master).map(Self::new)
+    }
+
+    pub fn rank(&self) -> usize {
+        self.0.lock().unwrap().rank()
+    }
+
+    pub fn world_size(&self) -> usize {
+        self.0.lock().unwrap().world_size()
+    }
+
+    pub fn all_reduce(&self, data: &mut [f32]) -> Result<(), LuminalError> {
+        self.0.lock().unwrap().all_reduce(data)
+    }
+
+    pub fn all_gather(&self, data: &[f32]) -> Result<Vec<Vec<f32>>, LuminalError> {
+        self.0.lock().unwrap().all_gather(data)
+    }
+}
+
+impl Debug for ProcessGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ProcessGroup(rank {} of {})",
+            self.rank(),
+            self.world_size()
+        )
+    }
+}
+
+/// Ranks connected in a ring over TCP. Collectives pass chunks around the ring, so each rank sends and receives about
+/// twice its data for an all-reduce, however many ranks there are.
+pub struct TcpGroup {
+    rank: usize,
+    world_size: usize,
+    /// Streams to the next and previous ranks around the ring, or None for a group of one
+    ring: Option<(TcpStream, TcpStream)>,
+}
+
+impl TcpGroup {
+    /// Connect to the other ranks. Every rank calls this with the same world size and `master` address. Rank 0 listens
+    /// on it, collects where every other rank can be reached, and tells each rank who comes next around the ring.
+    /// Ranks retry connecting until rank 0 is up.
+    pub fn connect(
+        rank: usize,
+        world_size: usize,
+        master: impl ToSocketAddrs,
+    ) -> Result<Self, LuminalError> {
+        assert!(
+            rank < world_size,
+            "Rank {rank} is outside a group of {world_size}"
+        );
+        if world_size == 1 {
+            return Ok(Self {
+                rank,
+                world_size,
+                ring: None,
+            });
+        }
+        let ring_listener = TcpListener::bind("0.0.0.0:0").map_err(io_error)?;
+        let ring_port = ring_listener.local_addr().map_err(io_error)?.port();
+
+        let next_address = if rank == 0 {
+            let master = TcpListener::bind(master).map_err(io_error)?;
+            let mut peers = (1..world_size)
+                .map(|_| {
+                    let (mut stream, address) = master.accept().map_err(io_error)?;
+                    let peer_rank = read_u64(&mut stream)? as usize;
+                    let peer_port = read_u64(&mut stream)? as u16;
+                    Ok((peer_rank, SocketAddr::new(address.ip(), peer_port), stream))
+                })
+                .collect::<Result<Vec<_>, LuminalError>>()?;
+            peers.sort_by_key(|(r, _, _)| *r);
+            if !peers.iter().map(|(r, _, _)| *r).eq(1..world_size) {
+                return Err(LuminalError::Collective(format!(
+                    "Expected ranks 1 to {}, got {:?}",
+                    world_size - 1,
+                    peers.iter().map(|(r, _, _)| r).collect_vec()
+                )));
+            }
+            for i in 0..peers.len() {
+                // The last rank wraps around to rank 0, at the address it reached rank 0 on
+                let next = match peers.get(i + 1) {
+                    Some((_, address, _)) => *address,
+                    None => {
+                        let ip = peers[i].2.local_addr().map_err(io_error)?.ip();
+                        SocketAddr::new(ip, ring_port)
+                    }
+                };
+                write_bytes(&mut peers[i].2, next.to_string().as_bytes())?;
+            }
+            peers[0].1
+        } else {
+            let mut stream =
+                connect_retrying(&master.to_socket_addrs().map_err(io_error)?.collect_vec())?;
+            write_u64(&mut stream, rank as u64)?;
+            write_u64(&mut stream, ring_port as u64)?;
+            String::from_utf8(read_bytes(&mut stream)?)
+                .ok()
+                .and_then(|a| a.parse::<SocketAddr>().ok())
+                .ok_or_else(|| LuminalError::Collective("Bad address from rank 0".to_string()))?
+        };
+
+        // Connecting doesn't wait for the other side to accept, so every rank can connect forward and then accept
+        let mut next = connect_retrying(&[next_address])?;
+        write_u64(&mut next, rank as u64)?;
+        let (mut prev, _) = ring_listener.accept().map_err(io_error)?;
+        let prev_rank = read_u64(&mut prev)? as usize;
+        if prev_rank != (rank + world_size - 1) % world_size {
+            return Err(LuminalError::Collective(format!(
+                "Rank {rank} expected rank {} before it in the ring, got {prev_rank}",
+                (rank + world_size - 1) % world_size
+            )));
+        }
+        for stream in [&next, &prev] {
+            stream.set_nodelay(true).map_err(io_error)?;
+        }
+        Ok(Self {
+            rank,
+            world_size,
+            ring: Some((next, prev)),
+        })
+    }
+
+    /// Send `data` to the next rank while receiving the previous rank's data
+    fn exchange(&mut self, data: &[f32]) -> Result<Vec<f32>, LuminalError> {
+        let (next, prev) = self.ring.as_mut().unwrap();
+        std::thread::scope(|s| {
+            // Sending on another thread, so ranks can't all block on full send buffers
+            let sender = s.spawn(|| write_bytes(next, &to_bytes(data)));
+            let received = read_bytes(prev);
+            sender.join().unwrap()?;
+            Ok(from_bytes(&received?))
+        })
+    }
+}
+
+impl Communicator for TcpGroup {
+    fn rank(&self) -> usize {
+        self.rank
+    }
+
+    fn world_size(&self) -> usize {
+        self.world_size
+    }
+
+    fn all_reduce(&mut self, data: &mut [f32]) -> Result<(), LuminalError> {
+        let (rank, n) = (self.rank, self.world_size);
+        let len = data.len();
+        let chunk = |i: usize| i * len / n..(i + 1) * len / n;
+        // Reduce-scatter: after n - 1 steps, each rank has the sum of chunk rank + 1
+        for step in 0..n - 1 {
+            let received = self.exchange(&data[chunk((rank + n - step) % n)])?;
+            for (d, r) in data[chunk((rank + n - step - 1) % n)]
+                .iter_mut()
+                .zip(received)
+            {
+                *d += r;
+            }
+        }
+        // All-gather: pass the summed chunks around the ring
+        for step in 0..n - 1 {
+            let received = self.exchange(&data[chunk((rank + 1 + n - step) % n)])?;
+            data[chunk((rank + n - step) % n)].copy_from_slice(&received);
+        }
+        Ok(())
+    }
+
+    fn all_gather(&mut self, data: &[f32]) -> Result<Vec<Vec<f32>>, LuminalError> {
+        let (rank, n) = (self.rank, self.world_size);
+        let mut shards = vec![vec![]; n];
+        shards[rank] = data.to_vec();
+        // Pass each shard on to the next rank, one step at a time
+        for step in 0..n - 1 {
+            let received = self.exchange(&shards[(rank + n - step) % n])?;
+            shards[(rank + n - step - 1) % n] = received;
+        }
+        Ok(shards)
+    }
+}
+
+fn io_error(e: std::io::Error) -> LuminalError {
+    LuminalError::Collective(e.to_string())
+}
+
+fn connect_retrying(addresses: &[SocketAddr]) -> Result<TcpStream, LuminalError> {
+    // About a minute
+    for _ in 0..600 {
+        match TcpStream::connect(addresses) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => {
+                std::thread::sleep(Duration::from_millis(100))
+            }
+            Err(e) => return Err(io_error(e)),
+        }
+    }
+    Err(LuminalError::Collective(format!(
+        "Couldn't connect to {addresses:?}"
+    )))
+}
+
+fn write_u64(stream: &mut TcpStream, n: u64) -> Result<(), LuminalError> {
+    stream.write_all(&n.to_le_bytes()).map_err(io_error)
+}
+
+fn read_u64(stream: &mut TcpStream) -> Result<u64, LuminalError> {
+    let mut bytes = [0; 8];
+    stream.read_exact(&mut bytes).map_err(io_error)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Write a length, then the bytes
+fn write_bytes(stream: &mut TcpStream, bytes: &[u8]) -> Result<(), LuminalError> {
+    write_u64(stream, bytes.len() as u64)?;
+    stream.write_all(bytes).map_err(io_error)
+}
+
+fn read_bytes(stream: &mut TcpStream) -> Result<Vec<u8>, LuminalError> {
+    let mut bytes = vec![0; read_u64(stream)? as usize];
+    stream.read_exact(&mut bytes).map_err(io_error)?;
+    Ok(bytes)
+}
+
+fn to_bytes(data: &[f32]) -> Vec<u8> {
+    data.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Sum a tensor across every rank of a group, giving each rank the full sum
+#[derive(Debug, Clone)]
+pub struct AllReduce(pub ProcessGroup);
+
+impl Operator for AllReduce {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        let n = input_shapes[0].n_elements().to_usize()?;
+        Some(OpCost {
+            flops: n,
+            bytes_read: n * 4,
+            bytes_written: n * 4,
+        })
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let mut data = host_data(&inp[0])?;
+        self.0.all_reduce(&mut data)?;
+        Ok(vec![Tensor::new(data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Concatenate a tensor from every rank of a group along `axis`, in rank order, giving each rank the whole tensor.
+/// Every rank's shard has to be the same shape.
+#[derive(Debug, Clone)]
+pub struct AllGather {
+    pub group: ProcessGroup,
+    pub axis: usize,
+}
+
+impl Operator for AllGather {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut dims = input_shapes[0]
+            .shape()
+            .into_iter()
+            .map(|d| d.small())
+            .collect_vec();
+        dims[self.axis] = (dims[self.axis] * self.group.world_size()).simplify();
+        Some(ShapeTracker::new(&dims))
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        let bytes = input_shapes[0].n_elements().to_usize()? * 4 * self.group.world_size();
+        Some(OpCost {
+            flops: 0,
+            bytes_read: bytes,
+            bytes_written: bytes,
+        })
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        self.try_process(inp, &FxHashMap::default())
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let data = host_data(&inp[0])?;
+        let shards = self.group.all_gather(&data)?;
+        if shards.iter().any(|s| s.len() != data.len()) {
+            return Err(LuminalError::Collective(format!(
+                "All-gathered shards need to be the same size, got {:?}",
+                shards.iter().map(|s| s.len()).collect_vec()
+            )));
+        }
+        let dims = inp[0].1.shape_usize();
+        let outer = dims[..self.axis].iter().product::<usize>().max(1);
+        // Each shard contributes a block of its axis and everything after it, for every index before the axis
+        let block = data.len() / outer;
+        let mut out = Vec::with_capacity(data.len() * shards.len());
+        for o in 0..outer {
+            for shard in &shards {
+                out.extend_from_slice(&shard[o * block..(o + 1) * block]);
+            }
+        }
+        Ok(vec![Tensor::new(out)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// A tensor's data in its logical order
+fn host_data((tensor, shape): &(InputTensor, ShapeTracker)) -> Result<Vec<f32>, LuminalError> {
+    let data = tensor.borrowed().widened_f32s()?;
+    Ok(HostTensorView::new(data, *shape).to_vec())
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Sum this tensor across every rank of `group`
+    #[track_caller]
+    pub fn all_reduce(self, group: &ProcessGroup) -> GraphTensor<S> {
+        let id = self
+            .graph()
+            .add_op(AllReduce(group.clone()))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(id, self.shape.contiguous(), self.graph_ref)
+    }
+}
+
+impl DynGraphTensor {
+    /// Sum this tensor across every rank of `group`
+    #[track_caller]
+    pub fn all_reduce(self, group: &ProcessGroup) -> Self {
+        let id = self
+            .graph()
+            .add_op(AllReduce(group.clone()))
+            .input(self.id, 0, self.shape)
+            .finish();
+        Self::from_id(id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Concatenate this tensor from every rank of `group` along `axis`, in rank order
+    #[track_caller]
+    pub fn all_gather(self, group: &ProcessGroup, axis: usize) -> Self {
+        let op = AllGather {
+            group: group.clone(),
+            axis,
+        };
+        let shape = op.infer_shape(&[self.shape]).unwrap();
+        let id = self
+            .graph()
+            .add_op(op)
+            .input(self.id, 0, self.shape)
+            .finish();
+        Self::from_id(id, shape, self.graph_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use itertools::Itertools;
+    crate::test_imports!();
+
+    /// A free port on localhost for rank 0 to listen on
+    pub(crate) fn free_address() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_collectives() {
+        let (a, b, c) = (random_vec(6), random_vec(6), random_vec(6));
+        let shards = [a.clone(), b.clone(), c.clone()];
+        let master = free_address();
+        // One thread per rank, each with its own graph
+        let results = std::thread::scope(|s| {
+            (0..3)
+                .map(|rank| {
+                    let (shard, master) = (shards[rank].clone(), master.clone());
+                    s.spawn(move || {
+                        let group = ProcessGroup::tcp(rank, 3, master).unwrap();
+                        let mut cx = Graph::new();
+                        let x = cx.tensor::<R2<2, 3>>().set(shard);
+                        // Shards read through views still line up
+                        let summed = x.permute::<R2<3, 2>, _>().all_reduce(&group).retrieve();
+                        let gathered = x.into_dyn().all_gather(&group, 1).retrieve();
+                        cx.execute();
+                        (summed.data(), gathered.shape.shape_usize(), gathered.data())
+                    })
+                })
+                .collect_vec()
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect_vec()
+        });
+
+        let mut cx = Graph::new();
+        let (a, b, c) = (
+            cx.tensor::<R2<2, 3>>().set(a),
+            cx.tensor::<R2<2, 3>>().set(b),
+            cx.tensor::<R2<2, 3>>().set(c),
+        );
+        let reference = (a + b + c).permute::<R2<3, 2>, _>().retrieve();
+        let concat = a
+            .concat_along::<R2<2, 6>, LAxis<1>, _>(b)
+            .concat_along::<R2<2, 9>, LAxis<1>, _>(c)
+            .retrieve();
+        cx.execute();
+        for (summed, gathered_shape, gathered) in results {
+            assert_close(&summed, &reference.data());
+            assert_eq!(gathered_shape, vec![2, 9]);
+            assert_exact(&gathered, &concat.data());
+        }
+    }
+}
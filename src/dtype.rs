@@ -0,0 +1,215 @@
+use itertools::Itertools;
+use petgraph::{visit::EdgeRef, Direction};
+
+use crate::{op, prelude::*};
+
+impl Graph {
+    /// Format a node's output is held in
+    pub fn dtype(&self, node: NodeIndex) -> DType {
+        self.dtypes.get(&node).copied().unwrap_or(DType::F32)
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Hold this tensor in a lower precision format, like weights stored in f16. `InsertCasts` converts the data to it.
+    pub fn with_dtype(self, dtype: DType) -> Self {
+        self.graph().dtypes.insert(self.id, dtype);
+        self
+    }
+}
+
+impl DynGraphTensor {
+    /// Hold this tensor in a lower precision format, like weights stored in f16. `InsertCasts` converts the data to it.
+    pub fn with_dtype(self, dtype: DType) -> Self {
+        self.graph().dtypes.insert(self.id, dtype);
+        self
+    }
+}
+
+/// The format a node's op actually produces, given the formats of its inputs.
+///
+/// Everything computes in f32, so arithmetic gives f32 whatever it's fed, while casts give their format and copies keep
+/// their input's. Arithmetic can't take u8 data, which has to be cast or dequantized first.
+pub(crate) fn produced_dtype(graph: &Graph, node: NodeIndex) -> Result<DType, LuminalError> {
+    let op = graph.node_weight(node).unwrap().as_any();
+    if let Some(op::Cast(dtype)) = op.downcast_ref::<op::Cast>() {
+        return Ok(*dtype);
+    }
+    let inputs = graph
+        .edges_directed(node, Direction::Incoming)
+        .filter_map(|e| e.weight().as_data().map(|(i, _, _)| (i, e.source())))
+        .sorted_by_key(|(i, _)| *i)
+        .map(|(_, src)| src)
+        .collect::<Vec<_>>();
+    if op.is::<op::Contiguous>()
+        || op.is::<Transfer>()
+        || op.is::<DeviceCopy>()
+        || op.is::<StartCopy>()
+        || op.is::<AwaitCopy>()
+    {
+        return Ok(inputs
+            .first()
+            .map(|i| graph.dtype(*i))
+            .unwrap_or(DType::F32));
+    }
+    let arithmetic = op.is::<op::Log2>()
+        || op.is::<op::Exp2>()
+        || op.is::<op::Sin>()
+        || op.is::<op::Recip>()
+        || op.is::<op::Sqrt>()
+        || op.is::<op::Add>()
+        || op.is::<op::Mul>()
+        || op.is::<op::Mod>()
+        || op.is::<op::LessThan>()
+        || op.is::<op::Select>()
+        || op.is::<op::SumReduce>()
+        || op.is::<op::MaxReduce>()
+        || op.is::<op::MinReduce>()
+        || op.is::<op::ProdReduce>()
+        || op.is::<op::CumSum>();
+    if arithmetic {
+        if let Some(input) = inputs.iter().find(|i| graph.dtype(**i) == DType::U8) {
+            return Err(LuminalError::UnsupportedDType {
+                op: format!("{:?}", graph.node_weight(node).unwrap()),
+                input: format!("{:?}", graph.node_weight(*input).unwrap()),
+                dtype: DType::U8,
+                location: graph.location(node),
+            });
+        }
+    }
+    Ok(DType::F32)
+}
+
+/// Round tensors marked with `with_dtype` to their format, wherever their op doesn't already produce it.
+///
+/// A cast goes after each of them, taking over its consumers, so only what reads the tensor sees the lower precision.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let w = cx.tensor::<R1<2>>().set([1.0001, 2.]).with_dtype(DType::F16);
+/// let mut out = (w * 1.).retrieve();
+/// cx.compile(InsertCasts, &mut out);
+/// cx.execute();
+/// assert_eq!(out.data(), vec![1., 2.]);
+/// ```
+#[derive(Debug, Default)]
+pub struct InsertCasts;
+
+impl Compiler for InsertCasts {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for (node, dtype) in graph
+            .dtypes
+            .iter()
+            .map(|(n, d)| (*n, *d))
+            .sorted_by_key(|(n, _)| *n)
+            .collect::<Vec<_>>()
+        {
+            if !graph.contains_node(node) || produced_dtype(graph, node) == Ok(dtype) {
+                continue;
+            }
+            let consumers = graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter_map(|e| Some((e.id(), e.target(), e.weight().as_data()?)))
+                .collect::<Vec<_>>();
+            let Some((output_order, shape)) = consumers
+                .first()
+                .map(|(_, _, (_, o, s))| (*o, *s))
+                .or_else(|| graph.to_retrieve.get(&node).copied())
+            else {
+                continue;
+            };
+            // The whole buffer gets cast as it's laid out, so consumers keep reading it through their own views
+            let cast = graph
+                .add_op(op::Cast(dtype))
+                .input(
+                    node,
+                    output_order,
+                    ShapeTracker::new(&[shape.n_physical_elements().into()]),
+                )
+                .finish();
+            for (edge, target, (input_order, _, shape)) in consumers {
+                graph.remove_edge(edge);
+                graph.add_edge(
+                    cast,
+                    target,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape,
+                    },
+                );
+            }
+            remap(node, cast, &mut ids, graph);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_dtypes_follow_ops() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = a.cast::<f16>();
+        let c = b.permute::<R2<3, 2>, _>().contiguous();
+        let d = c.exp2();
+        assert_eq!(cx.dtype(a.id), DType::F32);
+        assert_eq!(cx.dtype(b.id), DType::F16);
+        assert_eq!(cx.dtype(c.id), DType::F16);
+        assert_eq!(cx.dtype(d.id), DType::F32);
+
+        // Arithmetic on u8 data is caught as it's built
+        let q = cx.tensor::<R1<3>>().with_dtype(DType::U8);
+        let op = cx.add_op(Exp2).input(q.id, 0, q.shape);
+        let err = op.try_finish().unwrap_err();
+        assert!(matches!(
+            err,
+            LuminalError::UnsupportedDType {
+                dtype: DType::U8,
+                ..
+            }
+        ));
+        assert_eq!(cx.node_count(), 5);
+    }
+
+    #[test]
+    fn test_insert_casts() {
+        let mut cx = Graph::new();
+        let data = [1.0001, 2.5, -3.0003, 4., 5.0001, 6.];
+        let w = cx
+            .tensor::<R2<2, 3>>()
+            .set([[1.0001, 2.5, -3.0003], [4., 5.0001, 6.]])
+            .with_dtype(DType::BF16)
+            .keep();
+        let mut out = w.permute::<R2<3, 2>, _>().expand::<R3<2, 3, 2>, _>() * 2.;
+        let mut kept = w.retrieve();
+        let x = cx.tensor::<R1<2>>().set([0.1, 0.2]).with_dtype(DType::F16);
+        let mut y = x.retrieve();
+        out.retrieve();
+        cx.compile(InsertCasts, (&mut out, &mut kept, &mut y));
+
+        // Each marked tensor is cast once, and the cast takes over being kept and retrieved
+        assert_eq!(
+            cx.node_indices()
+                .filter_map(|n| cx.try_get_op::<Cast>(n))
+                .count(),
+            2
+        );
+        assert!(cx.is_op::<Cast>(kept.id));
+        assert!(cx.no_delete.contains(&kept.id));
+        assert_eq!(cx.dtype(kept.id), DType::BF16);
+        assert_eq!(cx.dtype(w.id), DType::F32);
+        cx.execute();
+
+        let rounded = data.map(|x| DType::BF16.round(x));
+        assert_exact(&kept.data(), &rounded);
+        let expected = (0..2)
+            .flat_map(|_| (0..3).flat_map(|i| (0..2).map(move |j| rounded[j * 3 + i] * 2.)))
+            .collect::<Vec<_>>();
+        assert_exact(&out.data(), &expected);
+        assert_exact(&y.data(), &[0.1, 0.2].map(|x| DType::F16.round(x)));
+    }
+}
@@ -0,0 +1,628 @@
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+
+use crate::{op, prelude::*};
+
+/// A tensor on the graph whose shape is only known at runtime.
+///
+/// This has the same ops as [`GraphTensor`], but axes and shapes are passed in as values instead of types, so it can be used
+/// to build models whose architecture is read from a config file. Typed and runtime-shaped tensors convert back and forth
+/// freely, so the two can be mixed. Binary ops broadcast their inputs like numpy, so a `[3]` tensor can be added to a
+/// `[2, 3]` one without expanding it first.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// // Shapes read from a config
+/// let (seq, hidden) = (3, 4);
+/// let x = cx.dyn_tensor("Input", &[seq, hidden]).set(vec![1.; 12]);
+/// let w = cx.dyn_tensor("Weight", &[hidden, 2]).set(vec![0.5; 8]);
+/// let y = x.matmul(w).softmax(1).retrieve();
+/// cx.execute();
+/// assert_eq!(y.data(), vec![0.5; 6]);
+/// // Converting to a typed tensor checks the rank and any static sizes
+/// let typed = y.typed::<R2<3, 2>>();
+/// ```
+#[derive(Clone, Copy)]
+pub struct DynGraphTensor {
+    pub id: NodeIndex,
+    pub graph_ref: *mut Graph,
+    pub shape: ShapeTracker,
+}
+
+impl<S: Shape> From<GraphTensor<S>> for DynGraphTensor {
+    fn from(t: GraphTensor<S>) -> Self {
+        Self::from_id(t.id, t.shape, t.graph_ref)
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Erase this tensor's shape type, so its shape is only tracked at runtime
+    pub fn into_dyn(self) -> DynGraphTensor {
+        self.into()
+    }
+}
+
+impl Graph {
+    /// Create a new runtime-shaped tensor with a name. Dimensions can be sizes or symbols.
+    #[track_caller]
+    pub fn dyn_tensor<E: Into<Expression> + Copy>(
+        &mut self,
+        name: &str,
+        shape: &[E],
+    ) -> DynGraphTensor {
+        let t = self.named_tensor::<()>(name);
+        DynGraphTensor::from_id(
+            t.id,
+            ShapeTracker::new(&shape.iter().map(|d| (*d).into()).collect::<Vec<_>>()),
+            self,
+        )
+    }
+}
+
+impl DynGraphTensor {
+    pub fn from_id(id: NodeIndex, shape: ShapeTracker, graph_ref: *mut Graph) -> Self {
+        Self {
+            id,
+            graph_ref,
+            shape,
+        }
+    }
+
+    /// Give this tensor a shape type. Panics if the rank or any statically known size doesn't match.
+    pub fn typed<S: Shape>(self) -> GraphTensor<S> {
+        let dims = self.dims();
+        assert_eq!(
+            dims.len(),
+            S::NUM_DIMS,
+            "Can't view a rank {} tensor as rank {}",
+            dims.len(),
+            S::NUM_DIMS
+        );
+        for (i, (actual, expected)) in dims.iter().zip(S::realized_shape()).enumerate() {
+            if let (Some(a), Some(e)) = (actual.to_usize(), expected.to_usize()) {
+                assert_eq!(a, e, "Dimension {i} is {a}, not {e}");
+            }
+        }
+        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+    }
+
+    fn untyped(self) -> GraphTensor<()> {
+        GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+    }
+
+    /// Get a mutable reference to the graph this tensor belongs to
+    #[allow(clippy::mut_from_ref)]
+    pub fn graph(&self) -> &mut Graph {
+        unsafe { self.graph_ref.as_mut().unwrap() }
+    }
+
+    /// Current size of each dimension
+    pub fn dims(&self) -> Vec<Expression> {
+        self.shape.shape().into_iter().map(|d| d.small()).collect()
+    }
+
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
+    /// Mark this tensor to not be deleted
+    pub fn keep(self) -> Self {
+        self.untyped().keep();
+        self
+    }
+
+    /// Mark this tensor to be retrieved later
+    pub fn retrieve(self) -> Self {
+        self.untyped().retrieve();
+        self
+    }
+
+    /// Remove this tensor's data from the graph
+    pub fn drop(&self) {
+        self.untyped().drop();
+    }
+
+    /// Set the value of the tensor. Symbolic dimensions get set from the sizes of the data's shape.
+    pub fn set_dyn<T: Data + Clone>(self, data: T, shape: &[usize]) -> Self {
+        assert_eq!(
+            self.rank(),
+            shape.len(),
+            "Number of dimensions do not match!"
+        );
+        for (d, s) in self.dims().iter().zip(shape) {
+            if let Some(c) = d.to_symbols().pop() {
+                self.graph().dyn_map.insert(c, *s);
+            }
+        }
+        self.graph().get_op_mut::<Function>(self.id).1 =
+            Box::new(move |_| vec![Tensor::new(data.to_owned())]);
+        self
+    }
+
+    /// Set the value of a tensor with no symbolic dimensions
+    pub fn set(self, data: Vec<f32>) -> Self {
+        let shape = self
+            .dims()
+            .iter()
+            .map(|d| d.to_usize().expect("Use set_dyn for symbolic dimensions"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "Data doesn't match the tensor's shape"
+        );
+        self.set_dyn(data, &shape)
+    }
+
+    /// Get the contiguous data of the tensor
+    pub fn data(&self) -> Vec<f32> {
+        self.untyped().data()
+    }
+
+    // Movement
+
+    /// Reorder the dimensions, so dimension `i` of the result is dimension `axes[i]` of this tensor
+    pub fn permute(mut self, axes: &[usize]) -> Self {
+        assert_eq!(axes.len(), self.rank(), "Permute needs every axis");
+        self.shape.permute(axes);
+        self
+    }
+
+    /// Broadcast along a new dimension of `size` inserted at `axis`
+    pub fn expand(mut self, axis: usize, size: impl Into<Expression>) -> Self {
+        self.shape.expand(axis, size);
+        self
+    }
+
+    /// Broadcast both tensors to a common shape, the way numpy does. Shapes are lined up from their last dimension,
+    /// missing leading dimensions are added, and dimensions of size 1 are stretched to match the other side.
+    ///
+    /// Symbolic dimensions can only be checked once they're known, so they're assumed to match.
+    #[track_caller]
+    pub fn try_broadcast_with(self, rhs: DynGraphTensor) -> Result<(Self, Self), LuminalError> {
+        let (a, b) = (self.dims(), rhs.dims());
+        let rank = a.len().max(b.len());
+        // Missing leading dimensions act like ones
+        let dim = |dims: &[Expression], i: usize| {
+            (i + dims.len())
+                .checked_sub(rank)
+                .map(|i| dims[i])
+                .unwrap_or(1.into())
+        };
+        let mut dims = Vec::with_capacity(rank);
+        for i in 0..rank {
+            let (x, y) = (dim(&a, i), dim(&b, i));
+            dims.push(match (x.to_usize(), y.to_usize()) {
+                _ if x == y => x,
+                (_, Some(1)) => x,
+                (Some(1), _) => y,
+                (Some(_), Some(_)) => {
+                    return Err(LuminalError::BroadcastMismatch {
+                        lhs: a.iter().map(|d| d.to_string()).collect(),
+                        rhs: b.iter().map(|d| d.to_string()).collect(),
+                        dim: i,
+                        location: Some(std::panic::Location::caller()),
+                    })
+                }
+                (Some(_), None) => x,
+                _ if x.is_unknown() => y,
+                _ => x,
+            });
+        }
+        Ok((self.broadcast_to(&dims), rhs.broadcast_to(&dims)))
+    }
+
+    #[track_caller]
+    fn broadcast_with(self, rhs: DynGraphTensor) -> (Self, Self) {
+        self.try_broadcast_with(rhs)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Stretch to `dims`, which this shape has already been checked to broadcast to
+    fn broadcast_to(mut self, dims: &[Expression]) -> Self {
+        let lead = dims.len() - self.rank();
+        let stretch = self
+            .dims()
+            .iter()
+            .enumerate()
+            .filter(|(i, d)| d.to_usize() == Some(1) && dims[lead + i].to_usize() != Some(1))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        // Stretched dimensions get swapped for fake ones, which would lose any slicing or padding on them
+        if stretch.iter().any(|i| {
+            let ind = self.shape.indexes[*i];
+            self.shape.mask[ind] != (0.into(), i32::MAX.into())
+                || self.shape.padding[ind] != (0.into(), 0.into())
+        }) {
+            self = self.contiguous();
+        }
+        for i in stretch {
+            self.shape.remove_dim(i);
+            self.shape.expand(i, dims[lead + i]);
+        }
+        for (i, d) in dims[..lead].iter().enumerate() {
+            self.shape.expand(i, *d);
+        }
+        self
+    }
+
+    /// View the data with a new shape holding the same number of elements
+    #[track_caller]
+    pub fn reshape<E: Into<Expression> + Copy>(self, shape: &[E]) -> Self {
+        let t = self.contiguous();
+        Self::from_id(
+            t.id,
+            ShapeTracker::new(&shape.iter().map(|d| (*d).into()).collect::<Vec<_>>()),
+            t.graph_ref,
+        )
+    }
+
+    #[track_caller]
+    pub fn contiguous(self) -> Self {
+        self.untyped().contiguous().into()
+    }
+
+    /// Slice each dimension to start..end
+    #[track_caller]
+    pub fn slice<S: Into<Expression> + Copy, E: Into<Expression> + Copy>(
+        mut self,
+        ranges: &[(S, E)],
+    ) -> Self {
+        let ranges = ranges
+            .iter()
+            .map(|(s, e)| ((*s).into(), (*e).into()))
+            .collect::<Vec<(Expression, Expression)>>();
+        // Padding and slicing the same dimension is unsupported, so those need to be made contiguous first
+        if ranges.iter().enumerate().any(|(i, range)| {
+            let ind = self.shape.indexes[i];
+            *range != (0.into(), i32::MAX.into()) && self.shape.padding[ind] != (0.into(), 0.into())
+        }) {
+            self = self.contiguous();
+        }
+        self.shape.slice(&ranges);
+        self
+    }
+
+    /// Pad each dimension with zeros before and after
+    #[track_caller]
+    pub fn pad<S: Into<Expression> + Copy, E: Into<Expression> + Copy>(
+        self,
+        padding: &[(S, E)],
+    ) -> Self {
+        self.untyped()
+            .pad::<()>(
+                padding
+                    .iter()
+                    .map(|(s, e)| ((*s).into(), (*e).into()))
+                    .collect::<Vec<(Expression, Expression)>>(),
+            )
+            .into()
+    }
+
+    /// Join two tensors along an axis
+    #[track_caller]
+    pub fn concat_along(self, rhs: DynGraphTensor, axis: usize) -> Self {
+        let mut a_padding = vec![(Expression::default(), Expression::default()); self.rank()];
+        a_padding[axis].1 = rhs.dims()[axis];
+        let mut b_padding = vec![(Expression::default(), Expression::default()); rhs.rank()];
+        b_padding[axis].0 = self.dims()[axis];
+        let out = self.pad(&a_padding) + rhs.pad(&b_padding);
+        // Merge the sizes so the output isn't viewed as padded
+        let mut dims = self.dims();
+        dims[axis] += rhs.dims()[axis];
+        Self::from_id(out.id, ShapeTracker::new(&dims), out.graph_ref)
+    }
+
+    // Reductions
+
+    #[track_caller]
+    fn reduce(self, axes: &[usize], op: impl Fn(usize) -> Box<dyn Operator>) -> Self {
+        let (mut id, mut shape) = (self.id, self.shape);
+        let mut axes = axes.to_vec();
+        axes.sort();
+        for dim in axes.into_iter().rev() {
+            id = self
+                .graph()
+                .add_boxed_op(op(dim))
+                .input(id, 0, shape)
+                .finish();
+            shape.remove_dim(dim);
+            shape = shape.contiguous();
+        }
+        Self::from_id(id, shape, self.graph_ref)
+    }
+
+    #[track_caller]
+    pub fn sum_reduce(self, axes: &[usize]) -> Self {
+        self.reduce(axes, |d| Box::new(op::SumReduce(d)))
+    }
+
+    #[track_caller]
+    pub fn max_reduce(self, axes: &[usize]) -> Self {
+        self.reduce(axes, |d| Box::new(op::MaxReduce(d)))
+    }
+
+    #[track_caller]
+    pub fn min_reduce(self, axes: &[usize]) -> Self {
+        self.reduce(axes, |d| Box::new(op::MinReduce(d)))
+    }
+
+    #[track_caller]
+    pub fn prod_reduce(self, axes: &[usize]) -> Self {
+        self.reduce(axes, |d| Box::new(op::ProdReduce(d)))
+    }
+
+    #[track_caller]
+    pub fn mean_reduce(self, axes: &[usize]) -> Self {
+        let dims = self.dims();
+        let n = axes.iter().fold(Expression::from(1), |n, a| n * dims[*a]);
+        let sum = self.sum_reduce(axes);
+        let mut div = self.graph().constant_expr(n).recip().into_dyn();
+        for (i, d) in sum.dims().into_iter().enumerate() {
+            div = div.expand(i, d);
+        }
+        sum * div
+    }
+
+    /// Broadcast a reduced tensor back out along the reduced axes of `like`
+    fn expand_like(mut self, axes: &[usize], like: DynGraphTensor) -> Self {
+        let dims = like.dims();
+        let mut axes = axes.to_vec();
+        axes.sort();
+        for a in axes {
+            self.shape.expand(a, dims[a]);
+        }
+        self
+    }
+
+    /// Applies a softmax function along an axis
+    #[track_caller]
+    pub fn softmax(self, axis: usize) -> Self {
+        let m = self - self.max_reduce(&[axis]).expand_like(&[axis], self);
+        let exp = m.exp();
+        exp / exp.sum_reduce(&[axis]).expand_like(&[axis], self)
+    }
+
+    /// Applies a layer norm along an axis
+    #[track_caller]
+    pub fn layer_norm(self, axis: usize, epsilon: f32) -> Self {
+        let centered = self - self.mean_reduce(&[axis]).expand_like(&[axis], self);
+        let inv_std = ((centered * centered).mean_reduce(&[axis]) + epsilon)
+            .sqrt()
+            .recip();
+        centered * inv_std.expand_like(&[axis], self)
+    }
+
+    /// Matrix multiply the last two dimensions. `rhs` is either a single matrix or has the same leading dimensions.
+    #[track_caller]
+    pub fn matmul(self, rhs: DynGraphTensor) -> Self {
+        let (n, m) = (self.rank(), rhs.rank());
+        assert!(n >= 2 && m >= 2, "Matmul needs matrices");
+        assert!(
+            m == 2 || m == n,
+            "Right hand side must be a single matrix or batched like the left"
+        );
+        let lhs_dims = self.dims();
+        let rhs_dims = rhs.dims();
+        if let (Some(a), Some(b)) = (lhs_dims[n - 1].to_usize(), rhs_dims[m - 2].to_usize()) {
+            assert_eq!(a, b, "Matmul inner dimensions don't match");
+        }
+        // Permute the right hand side's last two dimensions, then broadcast both to [.., M, N, K] and sum out K
+        let mut axes = (0..m).collect::<Vec<_>>();
+        axes.swap(m - 1, m - 2);
+        let mut w = rhs.permute(&axes);
+        for (i, d) in lhs_dims[..n - m].iter().enumerate() {
+            w = w.expand(i, *d);
+        }
+        let w = w.expand(n - 2, lhs_dims[n - 2]);
+        let x = self.expand(n - 1, rhs_dims[m - 1]);
+        (x * w).sum_reduce(&[n])
+    }
+
+    /// Round to the precision of `T`
+    #[track_caller]
+    pub fn cast<T: op::HasDType>(self) -> Self {
+        self.untyped().cast::<T>().into()
+    }
+
+    // Comparisons, which broadcast like the arithmetic ops
+
+    #[track_caller]
+    pub fn less_than(self, rhs: DynGraphTensor) -> Self {
+        let (a, b) = self.broadcast_with(rhs);
+        a.untyped().less_than(b.untyped()).into()
+    }
+
+    #[track_caller]
+    pub fn greater_than(self, rhs: DynGraphTensor) -> Self {
+        let (a, b) = self.broadcast_with(rhs);
+        a.untyped().greater_than(b.untyped()).into()
+    }
+
+    #[track_caller]
+    pub fn equals(self, rhs: DynGraphTensor) -> Self {
+        let (a, b) = self.broadcast_with(rhs);
+        a.untyped().equals(b.untyped()).into()
+    }
+
+    #[track_caller]
+    pub fn maximum(self, rhs: DynGraphTensor) -> Self {
+        let (a, b) = self.broadcast_with(rhs);
+        a.untyped().maximum(b.untyped()).into()
+    }
+
+    #[track_caller]
+    pub fn minimum(self, rhs: DynGraphTensor) -> Self {
+        let (a, b) = self.broadcast_with(rhs);
+        a.untyped().minimum(b.untyped()).into()
+    }
+}
+
+/// Forward unary ops to the typed implementations, which only ever look at the runtime shape
+macro_rules! unary_ops {
+    ($($(#[$attr:meta])* $name:ident),*) => {
+        impl DynGraphTensor {
+            $(
+                $(#[$attr])*
+                #[track_caller]
+                pub fn $name(self) -> Self {
+                    self.untyped().$name().into()
+                }
+            )*
+        }
+    };
+}
+
+unary_ops!(
+    log2, exp2, exp, ln, recip, sin, cos, square, sqrt, abs, sign, floor, ceil, round, erf, relu,
+    sigmoid, swish, tanh, gelu, is_finite
+);
+
+macro_rules! binary_ops {
+    ($($trait:ident $fn:ident),*) => {
+        $(
+            impl $trait for DynGraphTensor {
+                type Output = DynGraphTensor;
+                #[track_caller]
+                fn $fn(self, rhs: DynGraphTensor) -> Self::Output {
+                    let (a, b) = self.broadcast_with(rhs);
+                    a.untyped().$fn(b.untyped()).into()
+                }
+            }
+
+            impl $trait<f32> for DynGraphTensor {
+                type Output = DynGraphTensor;
+                #[track_caller]
+                fn $fn(self, rhs: f32) -> Self::Output {
+                    self.untyped().$fn(rhs).into()
+                }
+            }
+
+            impl $trait<DynGraphTensor> for f32 {
+                type Output = DynGraphTensor;
+                #[track_caller]
+                fn $fn(self, rhs: DynGraphTensor) -> Self::Output {
+                    self.$fn(rhs.untyped()).into()
+                }
+            }
+        )*
+    };
+}
+
+binary_ops!(Add add, Sub sub, Mul mul, Div div, Rem rem);
+
+impl Neg for DynGraphTensor {
+    type Output = DynGraphTensor;
+    #[track_caller]
+    fn neg(self) -> Self::Output {
+        self * -1.
+    }
+}
+
+impl ToId for DynGraphTensor {
+    fn to_id(&self) -> NodeIndex {
+        self.id
+    }
+}
+
+impl ToIds for DynGraphTensor {
+    fn to_ids(&self) -> Vec<NodeIndex> {
+        vec![self.id]
+    }
+}
+
+impl ToIdsMut for DynGraphTensor {
+    fn to_ids_mut(&mut self) -> Vec<&mut NodeIndex> {
+        vec![&mut self.id]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_dyn_matches_typed() {
+        let mut cx = Graph::new();
+        let (x_data, w_data) = (random_vec(2 * 3 * 4), random_vec(4 * 5));
+        // Typed
+        let x = cx.tensor::<R3<2, 3, 4>>().set(x_data.clone());
+        let w = cx.tensor::<R2<4, 5>>().set(w_data.clone());
+        let typed = (x.matmul(w).softmax::<LAxis<2>>()
+            + x.sum_reduce::<_, LAxis<2>>().expand::<_, LAxis<2>>())
+        .layer_norm::<LAxis<1>, _>(1e-5)
+        .retrieve();
+        // Runtime shaped, with a symbolic sequence dimension
+        let dx = cx.dyn_tensor("X", &['b'.into(), 's'.into(), Expression::from(4)]);
+        dx.set_dyn(x_data, &[2, 3, 4]);
+        let dw = cx.dyn_tensor("W", &[4, 5]).set(w_data);
+        let dynamic = (dx.matmul(dw).softmax(2) + dx.sum_reduce(&[2]).expand(2, 5))
+            .layer_norm(1, 1e-5)
+            .retrieve();
+        cx.execute();
+
+        assert_eq!(dynamic.rank(), 3);
+        assert_close(&dynamic.data(), &typed.data());
+    }
+
+    #[test]
+    fn test_dyn_conversions() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+        let b = a.into_dyn().permute(&[1, 0]).slice(&[(1, 3), (0, 2)]);
+        let c = b
+            .concat_along(a.into_dyn().slice(&[(0, 1), (0, 2)]), 0)
+            .reshape(&[6]);
+        let typed = (c.typed::<(Dyn<'-'>,)>() * 2.).retrieve();
+        let batched = cx.dyn_tensor("Batch", &[2, 2, 3]).set(random_vec(12));
+        let rhs = cx.dyn_tensor("Rhs", &[2, 3, 2]).set(random_vec(12));
+        let mut out = batched.matmul(rhs).retrieve();
+        cx.compile(GenericCompiler::default(), &mut out);
+        cx.execute();
+
+        assert_exact(&typed.data(), &[4., 10., 6., 12., 2., 4.]);
+        assert_eq!(out.dims(), vec![Expression::from(2); 3]);
+    }
+
+    #[test]
+    fn test_dyn_broadcasting() {
+        let mut cx = Graph::new();
+        let a = cx
+            .dyn_tensor("A", &[2, 3])
+            .set(vec![1., 2., 3., 4., 5., 6.]);
+        let row = cx.dyn_tensor("Row", &[3]).set(vec![10., 20., 30.]);
+        let col = cx.dyn_tensor("Col", &[2, 1]).set(vec![1., 2.]);
+        let sum = (a + row).retrieve();
+        let outer = (col * row).retrieve();
+        // A size 1 slice keeps its offset when it's stretched
+        let centered = (a - a.slice(&[(0, 2), (2, 3)])).retrieve();
+        let bigger = a.greater_than(col * 2.).retrieve();
+        let s = cx.dyn_tensor("S", &['s'.into(), Expression::from(1)]);
+        s.set_dyn(vec![1., 2.], &[2, 1]);
+        let symbolic = (row + s).retrieve();
+        cx.execute();
+
+        assert_eq!(sum.dims(), vec![Expression::from(2), 3.into()]);
+        assert_exact(&sum.data(), &[11., 22., 33., 14., 25., 36.]);
+        assert_exact(&outer.data(), &[10., 20., 30., 20., 40., 60.]);
+        assert_exact(&centered.data(), &[-2., -1., 0., -2., -1., 0.]);
+        assert_exact(&bigger.data(), &[0., 0., 1., 0., 1., 1.]);
+        assert_eq!(symbolic.dims(), vec![Expression::from('s'), 3.into()]);
+        assert_exact(&symbolic.data(), &[11., 21., 31., 12., 22., 32.]);
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "Can't broadcast [2, 3] with [2], dimension 1 doesn't match, built at"
+    )]
+    fn test_broadcast_mismatch() {
+        let mut cx = Graph::new();
+        let _ = cx.dyn_tensor("A", &[2, 3]) + cx.dyn_tensor("B", &[2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Dimension 1 is 3, not 4")]
+    fn test_typed_checks_sizes() {
+        let mut cx = Graph::new();
+        cx.dyn_tensor("A", &[2, 3]).typed::<R2<2, 4>>();
+    }
+}
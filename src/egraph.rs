@@ -0,0 +1,699 @@
+use std::{fmt::Display, str::FromStr};
+
+use egg::{
+    define_language, merge_option, rewrite, Analysis, CostFunction, DidMerge, Extractor, Id,
+    Language, Runner, Symbol,
+};
+use itertools::Itertools;
+use petgraph::{algo::toposort, stable_graph::EdgeIndex, visit::EdgeRef, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    op::{Constant, ConstantValue, SumReduce},
+    prelude::*,
+};
+
+/// Simplify chains of elementwise ops with equality saturation. Each connected group of primitive elementwise ops gets
+/// loaded into an e-graph, every rewrite rule is applied until nothing new turns up (or a limit is hit), and the cheapest
+/// equivalent graph is swapped in if it beats the original.
+///
+/// Rules cover identities like `x * 1 -> x`, constant folding, and refactorings like `a * b + a * c -> a * (b + c)`.
+/// Reductions, views and anything that isn't a primitive elementwise op are left alone, and act as the inputs and outputs
+/// of each group. Identities that only hold on part of the domain are only applied where the ops around them stay on it:
+/// `exp2(log2(x))` is NaN for negative `x`, so it's only dropped from `exp2(log2(exp2(x)))`.
+///
+/// Afterwards, sums of products that contract like a matmul, such as `sum(a * b)` over a middle dimension, get their
+/// views permuted into the matmul form backends' matmul compilers look for: summed over the last dimension, with the
+/// left hand side broadcast along the columns and the right hand side along the rows.
+#[derive(Debug)]
+pub struct EGraphSimplifier {
+    /// Most rounds of rule applications per group
+    pub iter_limit: usize,
+    /// Most e-nodes per group before saturation stops early
+    pub node_limit: usize,
+}
+
+impl Default for EGraphSimplifier {
+    fn default() -> Self {
+        Self {
+            iter_limit: 10,
+            node_limit: 10_000,
+        }
+    }
+}
+
+/// A float usable as an e-graph leaf, compared by its bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+struct Float(u32);
+
+impl Float {
+    fn new(f: f32) -> Self {
+        Self(f.to_bits())
+    }
+
+    fn get(self) -> f32 {
+        f32::from_bits(self.0)
+    }
+}
+
+impl FromStr for Float {
+    type Err = std::num::ParseFloatError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Float::new)
+    }
+}
+
+impl Display for Float {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+define_language! {
+    /// Primitive elementwise ops. Constants are broadcast scalars, and symbols stand in for the group's inputs.
+    enum Elementwise {
+        "log2" = Log2(Id),
+        "exp2" = Exp2(Id),
+        "sin" = Sin(Id),
+        "sqrt" = Sqrt(Id),
+        "recip" = Recip(Id),
+        "+" = Add([Id; 2]),
+        "*" = Mul([Id; 2]),
+        "%" = Mod([Id; 2]),
+        "<" = LessThan([Id; 2]),
+        Num(Float),
+        Symbol(Symbol),
+    }
+}
+
+type EGraph = egg::EGraph<Elementwise, ConstantFold>;
+type Rewrite = egg::Rewrite<Elementwise, ConstantFold>;
+
+/// Tracks which e-classes are known constants, and folds ops on them
+#[derive(Default)]
+struct ConstantFold;
+
+impl Analysis<Elementwise> for ConstantFold {
+    type Data = Option<Float>;
+
+    fn make(egraph: &EGraph, enode: &Elementwise) -> Self::Data {
+        let x = |i: &Id| egraph[*i].data.map(|f| f.get());
+        let value = match enode {
+            Elementwise::Num(f) => return Some(*f),
+            Elementwise::Log2(a) => x(a)?.log2(),
+            Elementwise::Exp2(a) => x(a)?.exp2(),
+            Elementwise::Sin(a) => x(a)?.sin(),
+            Elementwise::Sqrt(a) => x(a)?.sqrt(),
+            Elementwise::Recip(a) => x(a)?.recip(),
+            Elementwise::Add([a, b]) => x(a)? + x(b)?,
+            Elementwise::Mul([a, b]) => x(a)? * x(b)?,
+            Elementwise::Mod([a, b]) => x(a)? % x(b)?,
+            Elementwise::LessThan([a, b]) => (x(a)? < x(b)?) as i32 as f32,
+            Elementwise::Symbol(_) => return None,
+        };
+        // NaNs aren't equal to themselves, so they'd stop classes from merging
+        (!value.is_nan()).then(|| Float::new(value))
+    }
+
+    fn merge(&mut self, to: &mut Self::Data, from: Self::Data) -> DidMerge {
+        merge_option(to, from, |_, _| DidMerge(false, false))
+    }
+
+    fn modify(egraph: &mut EGraph, id: Id) {
+        if let Some(c) = egraph[id].data {
+            let added = egraph.add(Elementwise::Num(c));
+            egraph.union(id, added);
+        }
+    }
+}
+
+fn rules() -> Vec<Rewrite> {
+    vec![
+        rewrite!("commute-add"; "(+ ?a ?b)" => "(+ ?b ?a)"),
+        rewrite!("commute-mul"; "(* ?a ?b)" => "(* ?b ?a)"),
+        rewrite!("assoc-add"; "(+ ?a (+ ?b ?c))" => "(+ (+ ?a ?b) ?c)"),
+        rewrite!("assoc-mul"; "(* ?a (* ?b ?c))" => "(* (* ?a ?b) ?c)"),
+        rewrite!("add-0"; "(+ ?a 0)" => "?a"),
+        rewrite!("mul-1"; "(* ?a 1)" => "?a"),
+        rewrite!("factor"; "(+ (* ?a ?b) (* ?a ?c))" => "(* ?a (+ ?b ?c))"),
+        // exp2(log2(x)) is only x where x isn't negative, and log2(exp2(x)) only where exp2(x) doesn't overflow or
+        // underflow, so these only drop pairs wrapped in an op that keeps them there
+        rewrite!("exp2-log2"; "(exp2 (log2 (exp2 ?a)))" => "(exp2 ?a)"),
+        rewrite!("log2-exp2"; "(log2 (exp2 (log2 ?a)))" => "(log2 ?a)"),
+        rewrite!("exp2-add"; "(* (exp2 ?a) (exp2 ?b))" => "(exp2 (+ ?a ?b))"),
+        rewrite!("recip-recip"; "(recip (recip ?a))" => "?a"),
+    ]
+}
+
+/// Cheapest equivalent ops. Transcendental ops cost more than arithmetic, and inputs and constants are free.
+struct OpCostFn;
+
+impl OpCostFn {
+    fn weight(enode: &Elementwise) -> usize {
+        match enode {
+            Elementwise::Num(_) | Elementwise::Symbol(_) => 0,
+            Elementwise::Add(_)
+            | Elementwise::Mul(_)
+            | Elementwise::Mod(_)
+            | Elementwise::LessThan(_) => 1,
+            _ => 4,
+        }
+    }
+}
+
+impl CostFunction<Elementwise> for OpCostFn {
+    type Cost = usize;
+    fn cost<C: FnMut(Id) -> usize>(&mut self, enode: &Elementwise, mut costs: C) -> usize {
+        enode.fold(Self::weight(enode), |sum, id| sum + costs(id))
+    }
+}
+
+/// Load a primitive elementwise op into the e-graph
+fn to_enode(op: &dyn Operator, children: &[Id]) -> Option<Elementwise> {
+    let op = op.as_any();
+    Some(if op.is::<Log2>() {
+        Elementwise::Log2(children[0])
+    } else if op.is::<Exp2>() {
+        Elementwise::Exp2(children[0])
+    } else if op.is::<Sin>() {
+        Elementwise::Sin(children[0])
+    } else if op.is::<Sqrt>() {
+        Elementwise::Sqrt(children[0])
+    } else if op.is::<Recip>() {
+        Elementwise::Recip(children[0])
+    } else if op.is::<Add>() {
+        Elementwise::Add([children[0], children[1]])
+    } else if op.is::<Mul>() {
+        Elementwise::Mul([children[0], children[1]])
+    } else if op.is::<Mod>() {
+        Elementwise::Mod([children[0], children[1]])
+    } else if op.is::<LessThan>() {
+        Elementwise::LessThan([children[0], children[1]])
+    } else {
+        return None;
+    })
+}
+
+fn to_op(enode: &Elementwise) -> Box<dyn Operator> {
+    match enode {
+        Elementwise::Log2(_) => Box::new(Log2),
+        Elementwise::Exp2(_) => Box::new(Exp2),
+        Elementwise::Sin(_) => Box::new(Sin),
+        Elementwise::Sqrt(_) => Box::new(Sqrt),
+        Elementwise::Recip(_) => Box::new(Recip),
+        Elementwise::Add(_) => Box::new(Add),
+        Elementwise::Mul(_) => Box::new(Mul),
+        Elementwise::Mod(_) => Box::new(Mod),
+        Elementwise::LessThan(_) => Box::new(LessThan),
+        Elementwise::Num(_) | Elementwise::Symbol(_) => unreachable!("Leaves aren't ops"),
+    }
+}
+
+/// The value of a constant read as a broadcast scalar
+fn broadcast_constant(graph: &Graph, node: NodeIndex, shape: ShapeTracker) -> Option<f32> {
+    let Some(Constant(ConstantValue::Float(f))) = graph
+        .node_weight(node)
+        .unwrap()
+        .as_any()
+        .downcast_ref::<Constant>()
+    else {
+        return None;
+    };
+    let broadcast = shape
+        .indexes
+        .iter()
+        .all(|i| shape.fake[*i] || shape.dims[*i].to_usize() == Some(1));
+    (broadcast && !shape.is_sliced() && !shape.is_padded()).then_some(*f)
+}
+
+/// Where an op reads one of its inputs from
+type Source = (NodeIndex, u8, ShapeTracker);
+
+impl Compiler for EGraphSimplifier {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        let in_group = |graph: &Graph, node: NodeIndex| {
+            !graph.no_delete.contains(&node)
+                && to_enode(
+                    graph.node_weight(node).unwrap().as_ref(),
+                    &[Id::from(0usize); 2],
+                )
+                .is_some()
+                && graph
+                    .edges_directed(node, Direction::Incoming)
+                    .chain(graph.edges_directed(node, Direction::Outgoing))
+                    .all(|e| !e.weight().is_schedule())
+        };
+        let sources = |graph: &Graph, node: NodeIndex| -> Vec<Source> {
+            graph
+                .edges_directed(node, Direction::Incoming)
+                .filter_map(|e| {
+                    e.weight()
+                        .as_data()
+                        .map(|(i, o, s)| (i, (e.source(), o, s)))
+                })
+                .sorted_by_key(|(i, _)| *i)
+                .map(|(_, s)| s)
+                .collect()
+        };
+        // Ops inside a group read each other's outputs whole, so every op in a group has the same shape
+        let is_internal = |graph: &Graph, (src, output, shape): Source, dest: NodeIndex| {
+            in_group(graph, src)
+                && in_group(graph, dest)
+                && output == 0
+                && !shape.is_reshaped()
+                && sources(graph, src)[0].2.shape() == shape.shape()
+        };
+
+        // Split the elementwise ops into connected groups
+        let order = toposort(&graph.graph, None).unwrap();
+        let mut group_of = FxHashMap::<NodeIndex, usize>::default();
+        let mut groups = Vec::<Vec<NodeIndex>>::new();
+        for node in order.iter().copied().filter(|n| in_group(graph, *n)) {
+            let linked = sources(graph, node)
+                .into_iter()
+                .filter(|s| is_internal(graph, *s, node))
+                .map(|(src, _, _)| group_of[&src])
+                .unique()
+                .collect_vec();
+            let group = match linked.first() {
+                Some(g) => *g,
+                None => {
+                    groups.push(vec![]);
+                    groups.len() - 1
+                }
+            };
+            for other in linked.into_iter().skip(1) {
+                for n in std::mem::take(&mut groups[other]) {
+                    group_of.insert(n, group);
+                    groups[group].push(n);
+                }
+            }
+            group_of.insert(node, group);
+            groups[group].push(node);
+        }
+
+        for group in groups.into_iter().filter(|g| !g.is_empty()) {
+            let members = group.iter().copied().collect::<FxHashSet<_>>();
+            let group = order
+                .iter()
+                .copied()
+                .filter(|n| members.contains(n))
+                .collect_vec();
+
+            // Load the group, with everything it reads from outside as symbols
+            let mut egraph = EGraph::default();
+            let mut classes = FxHashMap::<NodeIndex, Id>::default();
+            let mut inputs = Vec::<Source>::new();
+            let mut old_cost = 0;
+            for node in &group {
+                let children = sources(graph, *node)
+                    .into_iter()
+                    .map(|source| {
+                        if is_internal(graph, source, *node) {
+                            classes[&source.0]
+                        } else if let Some(f) = broadcast_constant(graph, source.0, source.2) {
+                            egraph.add(Elementwise::Num(Float::new(f)))
+                        } else {
+                            let index = match inputs.iter().position(|s| *s == source) {
+                                Some(i) => i,
+                                None => {
+                                    inputs.push(source);
+                                    inputs.len() - 1
+                                }
+                            };
+                            egraph.add(Elementwise::Symbol(format!("in{index}").into()))
+                        }
+                    })
+                    .collect_vec();
+                let enode =
+                    to_enode(graph.node_weight(*node).unwrap().as_ref(), &children).unwrap();
+                old_cost += OpCostFn::weight(&enode);
+                classes.insert(*node, egraph.add(enode));
+            }
+            // Outputs are the ops read by anything outside the group
+            let roots = group
+                .iter()
+                .copied()
+                .filter(|n| {
+                    graph.edges_directed(*n, Direction::Outgoing).any(|e| {
+                        match e.weight().as_data() {
+                            Some((_, o, s)) => !is_internal(graph, (*n, o, s), e.target()),
+                            None => true,
+                        }
+                    })
+                })
+                .collect_vec();
+            if roots.is_empty() {
+                continue;
+            }
+
+            let runner = Runner::default()
+                .with_egraph(egraph)
+                .with_iter_limit(self.iter_limit)
+                .with_node_limit(self.node_limit)
+                .run(&rules());
+            let extractor = Extractor::new(&runner.egraph, OpCostFn);
+            let find = |node: NodeIndex| runner.egraph.find(classes[&node]);
+
+            // Only swap in the new ops if they're cheaper than the group as it is
+            let mut new_cost = 0;
+            let mut seen = FxHashSet::default();
+            let mut stack = roots.iter().map(|r| find(*r)).collect_vec();
+            let dims = sources(graph, group[0])[0].2.contiguous();
+            for root in &roots {
+                // Roots that turn into constants or views need copying out to a real buffer
+                new_cost += match extractor.find_best_node(find(*root)) {
+                    Elementwise::Num(_) => 1,
+                    Elementwise::Symbol(s) => needs_copy(inputs[symbol_index(*s)], dims) as usize,
+                    _ => 0,
+                };
+            }
+            while let Some(class) = stack.pop() {
+                if seen.insert(class) {
+                    let best = extractor.find_best_node(class);
+                    new_cost += OpCostFn::weight(best);
+                    stack.extend(best.children().iter().map(|c| runner.egraph.find(*c)));
+                }
+            }
+            if new_cost >= old_cost {
+                continue;
+            }
+
+            // Build the cheapest version of each output
+            let mut built = FxHashMap::<Id, Source>::default();
+            let mut replacements = vec![];
+            for root in &roots {
+                let location = graph.location(*root);
+                let mut new_nodes = vec![];
+                let (src, output, shape) = build(
+                    find(*root),
+                    &extractor,
+                    &runner.egraph,
+                    &inputs,
+                    dims,
+                    &mut built,
+                    &mut new_nodes,
+                    graph,
+                );
+                let new = if !needs_copy((src, output, shape), dims) {
+                    src
+                } else {
+                    let copy = graph.graph.add_node(Box::new(Contiguous));
+                    graph.graph.add_edge(
+                        src,
+                        copy,
+                        Dependency::Data {
+                            input_order: 0,
+                            output_order: output,
+                            shape,
+                        },
+                    );
+                    new_nodes.push(copy);
+                    copy
+                };
+                if let Some(location) = location {
+                    for n in new_nodes {
+                        graph.locations.insert(n, location);
+                    }
+                }
+                replacements.push((*root, new));
+            }
+            for (root, new) in replacements {
+                move_outgoing_edge(root, new, &mut graph.graph);
+                remap(root, new, &mut ids, graph);
+            }
+            // Constants only the old ops read go with them
+            let constants = group
+                .iter()
+                .flat_map(|n| sources(graph, *n))
+                .map(|(src, _, _)| src)
+                .filter(|src| graph.is_op::<Constant>(*src) && !graph.no_delete.contains(src))
+                .collect::<FxHashSet<_>>();
+            for node in group {
+                graph.remove_node(node);
+            }
+            for constant in constants {
+                if graph
+                    .edges_directed(constant, Direction::Outgoing)
+                    .next()
+                    .is_none()
+                {
+                    graph.remove_node(constant);
+                }
+            }
+        }
+        matmul_form(graph);
+    }
+}
+
+/// Permute the views of sums of products that contract like a matmul into the form matmul compilers match
+fn matmul_form(graph: &mut Graph) {
+    for sum in graph.node_indices().collect_vec() {
+        let Some(SumReduce(axis)) = graph.try_get_op::<SumReduce>(sum).cloned() else {
+            continue;
+        };
+        let [(mul, _, view)] = graph.get_sources(sum)[..] else {
+            continue;
+        };
+        if !graph.is_op::<Mul>(mul)
+            || view.is_reshaped()
+            || graph.no_delete.contains(&mul)
+            || graph.edges_directed(mul, Direction::Outgoing).count() != 1
+        {
+            continue;
+        }
+        let Some((a, b)) = graph
+            .edges_directed(mul, Direction::Incoming)
+            .filter_map(|e| {
+                let (input_order, output_order, st) = e.weight().as_data()?;
+                Some((input_order, (e.id(), (e.source(), output_order, st))))
+            })
+            .sorted_by_key(|(i, _)| *i)
+            .map(|(_, e)| e)
+            .collect_tuple()
+        else {
+            continue;
+        };
+        // Both sides vary along the summed dimension, the right hand side along the last of the others, and the left
+        // hand side along the rest
+        let fake = |(_, (_, _, st)): (EdgeIndex, Source), axis: usize| st.fake[st.indexes[axis]];
+        let others = (0..view.len()).filter(|i| *i != axis).collect_vec();
+        let Some((&last, rest)) = others.split_last() else {
+            continue;
+        };
+        let (lhs, rhs) = if fake(a, last) { (a, b) } else { (b, a) };
+        if fake(a, axis)
+            || fake(b, axis)
+            || !fake(lhs, last)
+            || fake(rhs, last)
+            || rest.iter().any(|i| fake(lhs, *i) || !fake(rhs, *i))
+            || (axis == view.len() - 1 && lhs == a)
+        {
+            continue;
+        }
+
+        let order = others.iter().copied().chain([axis]).collect_vec();
+        for (input_order, (edge, (src, output_order, mut shape))) in
+            [lhs, rhs].into_iter().enumerate()
+        {
+            shape.permute(&order);
+            graph.graph.remove_edge(edge);
+            graph.graph.add_edge(
+                src,
+                mul,
+                Dependency::Data {
+                    input_order: input_order as u8,
+                    output_order,
+                    shape,
+                },
+            );
+        }
+        let sum_edge = graph
+            .edges_directed(sum, Direction::Incoming)
+            .find(|e| !e.weight().is_schedule())
+            .unwrap()
+            .id();
+        let mut permuted = view;
+        permuted.permute(&order);
+        *graph.graph.edge_weight_mut(sum_edge).unwrap() = Dependency::Data {
+            input_order: 0,
+            output_order: 0,
+            shape: permuted.contiguous(),
+        };
+        graph.get_op_mut::<SumReduce>(sum).0 = view.len() - 1;
+        graph.store_shape(mul);
+    }
+}
+
+/// Which of a group's inputs a symbol stands for
+fn symbol_index(symbol: Symbol) -> usize {
+    symbol.as_str()[2..].parse().unwrap()
+}
+
+/// Whether an input needs copying into a new buffer to be used as an output of a group with shape `dims`
+fn needs_copy((_, output, shape): Source, dims: ShapeTracker) -> bool {
+    output != 0 || shape.is_reshaped() || shape.shape() != dims.shape()
+}
+
+/// Add the cheapest ops computing an e-class to the graph, reusing anything already built
+#[allow(clippy::too_many_arguments)]
+fn build(
+    class: Id,
+    extractor: &Extractor<OpCostFn, Elementwise, ConstantFold>,
+    egraph: &EGraph,
+    inputs: &[Source],
+    dims: ShapeTracker,
+    built: &mut FxHashMap<Id, Source>,
+    new_nodes: &mut Vec<NodeIndex>,
+    graph: &mut Graph,
+) -> Source {
+    let class = egraph.find(class);
+    if let Some(source) = built.get(&class) {
+        return *source;
+    }
+    let source = match extractor.find_best_node(class) {
+        Elementwise::Symbol(s) => inputs[symbol_index(*s)],
+        Elementwise::Num(f) => {
+            let node = graph
+                .graph
+                .add_node(Box::new(Constant(ConstantValue::Float(f.get()))));
+            new_nodes.push(node);
+            let mut shape = ShapeTracker::new(&[]);
+            for (i, d) in dims.dims.iter().enumerate() {
+                shape.expand(i, *d);
+            }
+            (node, 0, shape)
+        }
+        enode => {
+            let enode = enode.clone();
+            let inputs = enode
+                .children()
+                .iter()
+                .map(|c| build(*c, extractor, egraph, inputs, dims, built, new_nodes, graph))
+                .collect_vec();
+            let node = graph.graph.add_node(to_op(&enode));
+            for (i, (src, output, shape)) in inputs.into_iter().enumerate() {
+                graph.graph.add_edge(
+                    src,
+                    node,
+                    Dependency::Data {
+                        input_order: i as u8,
+                        output_order: output,
+                        shape,
+                    },
+                );
+            }
+            new_nodes.push(node);
+            (node, 0, dims)
+        }
+    };
+    built.insert(class, source);
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    fn count<T: Operator + 'static>(cx: &Graph) -> usize {
+        cx.node_indices().filter(|n| cx.is_op::<T>(*n)).count()
+    }
+
+    #[test]
+    fn test_egraph_identities() {
+        let mut cx = Graph::new();
+        let data = random_vec(4)
+            .into_iter()
+            .map(|i| i + 1.)
+            .collect::<Vec<_>>();
+        let a = cx.tensor::<R1<4>>().set(data.clone());
+        let b_data = vec![-1.5, 0.5, 2., -0.25];
+        let b = cx.tensor::<R1<4>>().set(b_data.clone());
+        let c = cx.tensor::<R1<4>>().set(random_vec(4));
+        let mut out = (
+            (a * 1.)
+                .exp2()
+                .log2()
+                .exp2()
+                .sum_reduce::<_, LAxis<0>>()
+                .retrieve(),
+            ((a * 2.) * 3.).sum_reduce::<_, LAxis<0>>().retrieve(),
+            (a * b + a * c).sum_reduce::<_, LAxis<0>>().retrieve(),
+            (b.exp2() * c.exp2()).sum_reduce::<_, LAxis<0>>().retrieve(),
+            // Could be negative, so this pair has to stay
+            b.log2().exp2().retrieve(),
+        );
+        cx.execute();
+        let expected = (out.0.data(), out.1.data(), out.2.data(), out.3.data());
+
+        cx.compile(EGraphSimplifier::default(), &mut out);
+        assert_eq!(count::<Log2>(&cx), 1);
+        // One multiply each for the folded constants and the factored sum, and none for the rest
+        assert_eq!(count::<Mul>(&cx), 2);
+        assert_eq!(count::<Exp2>(&cx), 3);
+        cx.execute();
+        assert_close(&out.0.data(), &expected.0);
+        assert_close(&out.1.data(), &expected.1);
+        assert_close(&out.2.data(), &expected.2);
+        assert_close(&out.3.data(), &expected.3);
+        for (x, y) in b_data.iter().zip(out.4.data()) {
+            assert!(if *x < 0. {
+                y.is_nan()
+            } else {
+                (x - y).abs() < 1e-5
+            });
+        }
+    }
+
+    #[test]
+    fn test_egraph_matmul_form() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        // a[i, k] * b[k, j] summed over k, the middle dimension
+        let mut out = (a.expand::<R3<2, 3, 4>, _>() * b.expand::<R3<2, 3, 4>, _>())
+            .sum_reduce::<_, LAxis<1>>()
+            .retrieve();
+        let expected = a.matmul(b).retrieve();
+        cx.execute();
+        let expected_data = expected.data();
+
+        cx.compile(EGraphSimplifier::default(), &mut out);
+        assert_eq!(cx.get_op::<SumReduce>(out.id).0, 2);
+        let srcs = cx.get_sources(cx.get_sources(out.id)[0].0);
+        let fakes = srcs
+            .iter()
+            .map(|(_, _, st)| (0..3).map(|i| st.fake[st.indexes[i]]).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fakes,
+            vec![vec![false, true, false], vec![true, false, false]]
+        );
+        cx.execute();
+        assert_close(&out.data(), &expected_data);
+    }
+
+    #[test]
+    fn test_egraph_views() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let b = cx.tensor::<R2<3, 2>>().set(random_vec(6));
+        let permuted = a.permute::<_, LAxes2<1, 0>>() * 1.;
+        let mut out = (
+            (permuted + b).sum_reduce::<_, LAxis<1>>().retrieve(),
+            (a * 1.).exp2().retrieve(),
+        );
+        cx.execute();
+        let expected = (out.0.data(), out.1.data());
+
+        cx.compile(EGraphSimplifier::default(), &mut out);
+        // The permuted view gets read straight into the add, and the retrieved exp2 reads the input directly
+        assert_eq!(count::<Mul>(&cx), 0);
+        assert_eq!(count::<Contiguous>(&cx), 0);
+        assert_eq!(count::<Constant>(&cx), 0);
+        cx.execute();
+        assert_close(&out.0.data(), &expected.0);
+        assert_close(&out.1.data(), &expected.1);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{fmt::Display, panic::Location};
+
+use crate::prelude::*;
+
+/// Errors that can come up while building or running a graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LuminalError {
+    /// A dynamic dimension was used before being set with `Graph::set_dyn_dim`
+    UnknownDimension(char),
+    /// A node ran before one of its inputs had data
+    MissingInput {
+        node: NodeIndex,
+        op: String,
+        input: NodeIndex,
+        location: Option<&'static Location<'static>>,
+    },
+    /// A node's input edges don't cover each input index exactly once
+    BadInputOrder {
+        node: NodeIndex,
+        op: String,
+        orders: Vec<u8>,
+    },
+    /// A tensor's data wasn't the type it was expected to be
+    WrongData {
+        expected: &'static str,
+        found: String,
+    },
+    /// An op wasn't the type it was expected to be
+    WrongOpType {
+        node: NodeIndex,
+        expected: &'static str,
+        found: String,
+    },
+    /// A custom op was used without being registered with `Graph::register_op`
+    UnregisteredOp(String),
+    /// An op was connected to inputs with incompatible shapes. Holds the op, and each input's op and shape
+    ShapeMismatch {
+        op: String,
+        inputs: Vec<(String, Vec<String>)>,
+        location: Option<&'static Location<'static>>,
+    },
+    /// Two tensors were combined whose shapes can't be broadcast together. Holds both shapes and the first
+    /// dimension, counting from the front of the output, where they disagree
+    BroadcastMismatch {
+        lhs: Vec<String>,
+        rhs: Vec<String>,
+        dim: usize,
+        location: Option<&'static Location<'static>>,
+    },
+    /// An op was fed data in a format it can't compute on
+    UnsupportedDType {
+        op: String,
+        input: String,
+        dtype: DType,
+        location: Option<&'static Location<'static>>,
+    },
+    /// An op can't be written to a graph file, because it isn't a primitive op
+    UnserializableOp(String),
+    /// A graph file couldn't be read or written
+    BadGraphFile(String),
+    /// An .npy or .npz file couldn't be read or written
+    BadNpyFile(String),
+    /// A weight file couldn't be read, or doesn't hold the bytes a `DiskTensor` points at
+    BadWeightFile(String),
+    /// Source can't be generated for an op, because it isn't a primitive op
+    NoCodegen(String),
+    /// Data was bound to an input that was never declared
+    UnknownInput(String),
+    /// A declared input wasn't bound before running
+    UnboundInput(String),
+    /// Data bound to an input doesn't fit the shape it was declared with
+    InputShapeMismatch {
+        name: String,
+        expected: Vec<String>,
+        found: Vec<usize>,
+    },
+    /// An op failed while processing its inputs
+    OpFailed {
+        node: NodeIndex,
+        op: String,
+        input_shapes: Vec<Vec<usize>>,
+        location: Option<&'static Location<'static>>,
+        /// What the op reported
+        error: Box<LuminalError>,
+    },
+    /// A tensor was ran without ever being given a value
+    UnsetTensor {
+        node: NodeIndex,
+        op: String,
+        location: Option<&'static Location<'static>>,
+    },
+    /// A subgraph run by a `Call` didn't leave one of its outputs behind
+    MissingSubgraphOutput(NodeIndex),
+    /// A collective op couldn't exchange data with the other ranks of its group
+    Collective(String),
+    /// A copy between devices running in the background failed
+    CopyFailed(String),
+    /// A paged cache doesn't have enough free blocks for a batch
+    CacheFull { needed: usize, free: usize },
+    /// A batch listed the same cache sequence more than once
+    DuplicateSequence(usize),
+    /// An op produced a NaN or infinity while the NaN guard was on
+    NonFinite {
+        node: NodeIndex,
+        op: String,
+        input_shapes: Vec<Vec<usize>>,
+        /// Where the op was built, if it was added by an hl_op
+        location: Option<&'static Location<'static>>,
+        /// First element that isn't finite
+        index: usize,
+        /// "NaN", "inf" or "-inf"
+        value: &'static str,
+    },
+}
+
+impl Display for LuminalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LuminalError::UnknownDimension(d) => {
+                write!(f, "Dynamic dimension '{d}' must be set before running")
+            }
+            LuminalError::MissingInput {
+                node,
+                op,
+                input,
+                location,
+            } => {
+                write!(
+                    f,
+                    "{op} ({}) is missing input {}",
+                    node.index(),
+                    input.index()
+                )?;
+                write_location(f, location)
+            }
+            LuminalError::BadInputOrder { node, op, orders } => write!(
+                f,
+                "{op} ({}) has inputs at positions {orders:?}, expected 0..{}",
+                node.index(),
+                orders.len()
+            ),
+            LuminalError::WrongOpType {
+                node,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected node {} to be {expected}, found {found}",
+                node.index()
+            ),
+            LuminalError::WrongData { expected, found } => {
+                write!(f, "Expected {expected}, found {found}")
+            }
+            LuminalError::UnregisteredOp(op) => {
+                write!(f, "{op} must be registered before it's used")
+            }
+            LuminalError::ShapeMismatch {
+                op,
+                inputs,
+                location,
+            } => {
+                write!(
+                    f,
+                    "{op} has incompatible input shapes: {}",
+                    inputs
+                        .iter()
+                        .map(|(op, shape)| format!("{op} [{}]", shape.join(", ")))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+                write_location(f, location)
+            }
+            LuminalError::BroadcastMismatch {
+                lhs,
+                rhs,
+                dim,
+                location,
+            } => {
+                write!(
+                    f,
+                    "Can't broadcast [{}] with [{}], dimension {dim} doesn't match",
+                    lhs.join(", "),
+                    rhs.join(", ")
+                )?;
+                write_location(f, location)
+            }
+            LuminalError::UnsupportedDType {
+                op,
+                input,
+                dtype,
+                location,
+            } => {
+                write!(f, "{op} can't compute on {dtype:?} data from {input}")?;
+                write_location(f, location)
+            }
+            LuminalError::UnserializableOp(op) => {
+                write!(f, "{op} isn't a primitive op, so it can't be saved")
+            }
+            LuminalError::BadGraphFile(message) => write!(f, "Bad graph file: {message}"),
+            LuminalError::BadNpyFile(message) => write!(f, "Bad numpy file: {message}"),
+            LuminalError::MissingSubgraphOutput(output) => {
+                write!(f, "Subgraph output {} wasn't computed", output.index())
+            }
+            LuminalError::BadWeightFile(message) => write!(f, "Bad weight file: {message}"),
+            LuminalError::Collective(message) => write!(f, "Collective failed: {message}"),
+            LuminalError::CopyFailed(message) => write!(f, "Device copy failed: {message}"),
+            LuminalError::CacheFull { needed, free } => {
+                write!(
+                    f,
+                    "Cache needs {needed} more blocks, but only {free} are free"
+                )
+            }
+            LuminalError::DuplicateSequence(sequence) => {
+                write!(f, "Sequence {sequence} is in the batch more than once")
+            }
+            LuminalError::NoCodegen(op) => {
+                write!(
+                    f,
+                    "{op} isn't a primitive op, so source can't be generated for it"
+                )
+            }
+            LuminalError::UnknownInput(name) => write!(f, "There's no input named {name}"),
+            LuminalError::UnboundInput(name) => {
+                write!(f, "Input {name} must be bound before running")
+            }
+            LuminalError::InputShapeMismatch {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Input {name} was given shape {found:?}, expected [{}]",
+                expected.join(", ")
+            ),
+            LuminalError::OpFailed {
+                node,
+                op,
+                input_shapes,
+                location,
+                error,
+            } => {
+                write!(
+                    f,
+                    "{op} ({}) failed with input shapes {input_shapes:?}",
+                    node.index()
+                )?;
+                write_location(f, location)?;
+                write!(f, ": {error}")
+            }
+            LuminalError::UnsetTensor { node, op, location } => {
+                write!(
+                    f,
+                    "{op} ({}) has no value. You must set a value for this tensor",
+                    node.index()
+                )?;
+                write_location(f, location)
+            }
+            LuminalError::NonFinite {
+                node,
+                op,
+                input_shapes,
+                location,
+                index,
+                value,
+            } => {
+                write!(
+                    f,
+                    "{op} ({}) produced {value} at index {index} with input shapes {input_shapes:?}",
+                    node.index()
+                )?;
+                write_location(f, location)
+            }
+        }
+    }
+}
+
+/// Point at the model code that built a node, when it's known
+fn write_location(
+    f: &mut std::fmt::Formatter<'_>,
+    location: &Option<&'static Location<'static>>,
+) -> std::fmt::Result {
+    match location {
+        Some(location) => write!(f, ", built at {location}"),
+        None => Ok(()),
+    }
+}
+
+impl std::error::Error for LuminalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LuminalError::OpFailed { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,512 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::prelude::*;
+
+/// Autoregressive decoding loop, yielding one sampled token per iteration.
+///
+/// The first iteration runs the whole prompt through the graph, and every iteration after feeds in only the last sampled token.
+/// After each run the logits are dropped and, if a kv cache is attached, the new cache is moved back into the cache inputs.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let input = cx.tensor::<(Const<1>, Dyn<'s'>)>();
+/// let logits = (input + 1.)
+///     .expand::<(Const<1>, Dyn<'s'>, Const<8>), Axis<2>>()
+///     .equals(cx.arange::<Const<8>>().expand::<_, Axes2<0, 1>>())
+///     .retrieve();
+/// let tokens = generate(input, logits, &[1, 2]).take(3).collect::<Vec<_>>();
+/// assert_eq!(tokens, vec![3, 4, 5]);
+/// ```
+pub struct Generator<I: Shape, L: Shape> {
+    input: GraphTensor<I>,
+    logits: GraphTensor<L>,
+    cache: Option<(Vec<NodeIndex>, Vec<NodeIndex>)>,
+    past_dim: Option<char>,
+    total_dim: Option<char>,
+    sampler: Sampler,
+    tokens: Vec<u32>,
+    n_prompt: usize,
+    n_processed: usize,
+    stop_tokens: Vec<u32>,
+    stop_strings: Vec<String>,
+    max_new_tokens: Option<usize>,
+    cancel: CancelHandle,
+    stop_reason: Option<StopReason>,
+    text_end: Option<usize>,
+    detokenizer: Option<Detokenizer>,
+    n_text_bytes: usize,
+}
+
+/// Why a generator stopped producing tokens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    /// One of the stop tokens was sampled
+    StopToken(u32),
+    /// The generated text contained one of the stop strings
+    StopString(String),
+    /// The maximum number of new tokens was generated
+    MaxTokens,
+    /// Generation was cancelled through a `CancelHandle`
+    Cancelled,
+    /// The request's tokens didn't fit in the KV cache even with nothing else running, like a prompt longer than the
+    /// whole cache
+    CacheFull,
+}
+
+/// Shared flag that stops a generator before its next step. It can be cloned and sent to other threads, so a server can
+/// cancel a stream when its client disconnects.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Stop the generator before it runs another step. A step that's already running finishes first.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether `cancel` has been called on this handle or any of its clones
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Turns a run of token ids back into text
+type Detokenizer = Box<dyn Fn(&[u32]) -> String>;
+
+/// Start generating from a prompt. `input` takes in token ids along its last dimension, and `logits` holds the next token distribution along its last dimension.
+pub fn generate<I: Shape, L: Shape>(
+    input: GraphTensor<I>,
+    logits: GraphTensor<L>,
+    prompt: &[u32],
+) -> Generator<I, L> {
+    Generator {
+        input,
+        logits,
+        cache: None,
+        past_dim: None,
+        total_dim: None,
+        sampler: Sampler::greedy(),
+        tokens: prompt.to_vec(),
+        n_prompt: prompt.len(),
+        n_processed: 0,
+        stop_tokens: vec![],
+        stop_strings: vec![],
+        max_new_tokens: None,
+        cancel: CancelHandle::default(),
+        stop_reason: None,
+        text_end: None,
+        detokenizer: None,
+        n_text_bytes: 0,
+    }
+}
+
+impl<I: Shape, L: Shape> Generator<I, L> {
+    /// Move `cache_dest` into `cache_src` after every step
+    pub fn kv_cache(mut self, cache_src: impl ToIds, cache_dest: impl ToIds) -> Self {
+        self.cache = Some((cache_src.to_ids(), cache_dest.to_ids()));
+        self
+    }
+
+    /// Dynamic dimension to set to the number of tokens already in the cache
+    pub fn past_dim(mut self, dim: char) -> Self {
+        self.past_dim = Some(dim);
+        self
+    }
+
+    /// Dynamic dimension to set to the total number of tokens, including the ones being fed in
+    pub fn total_dim(mut self, dim: char) -> Self {
+        self.total_dim = Some(dim);
+        self
+    }
+
+    /// Sampler used to pick the next token from the last position's logits. Defaults to greedy sampling.
+    pub fn sampler(mut self, sampler: Sampler) -> Self {
+        self.sampler = sampler;
+        self
+    }
+
+    /// Stop generating once any of these tokens gets sampled. The stop token itself isn't yielded or added to the tokens.
+    pub fn stop_tokens(mut self, tokens: impl IntoIterator<Item = u32>) -> Self {
+        self.stop_tokens.extend(tokens);
+        self
+    }
+
+    /// Tokens generation stops at
+    pub fn stop_token_ids(&self) -> &[u32] {
+        &self.stop_tokens
+    }
+
+    /// Stop generating once the generated text contains any of these strings. The text is cut off before the stop string,
+    /// though the token that completed it is still yielded. Without a detokenizer there's no text to look in, so these are
+    /// ignored.
+    pub fn stop_strings<T: Into<String>>(mut self, strings: impl IntoIterator<Item = T>) -> Self {
+        self.stop_strings.extend(
+            strings
+                .into_iter()
+                .map(Into::into)
+                .filter(|s| !s.is_empty()),
+        );
+        self
+    }
+
+    /// Stop after generating this many tokens past the prompt
+    pub fn max_new_tokens(mut self, max: usize) -> Self {
+        self.max_new_tokens = Some(max);
+        self
+    }
+
+    /// Handle that cancels this generator, possibly from another thread
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Why generation stopped, or `None` if it can still go on
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    /// Turns generated tokens back into text, for `text` and `next_text`
+    pub fn detokenizer(mut self, detokenizer: impl Fn(&[u32]) -> String + 'static) -> Self {
+        self.detokenizer = Some(Box::new(detokenizer));
+        self
+    }
+
+    /// All tokens so far, including the prompt
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Tokens generated after the prompt
+    pub fn generated(&self) -> &[u32] {
+        &self.tokens[self.n_prompt..]
+    }
+
+    /// Text of the tokens generated after the prompt, cut off before any stop string. Panics if there's no detokenizer.
+    pub fn text(&self) -> String {
+        let detokenizer = self
+            .detokenizer
+            .as_ref()
+            .expect("Generator needs a detokenizer to produce text");
+        let mut text = detokenizer(self.generated());
+        if let Some(end) = self.text_end {
+            text.truncate(end);
+        }
+        text
+    }
+
+    /// Generate a token and return the text it added. Text that might still turn out to be part of a character or a stop
+    /// string is held back, giving an empty string, and comes out once the following tokens settle it. After the last
+    /// token, whatever was held back is returned before `None`.
+    pub fn next_text(&mut self) -> Option<String> {
+        let finished = self.next().is_none();
+        let text = self.text();
+        let mut end = text.len();
+        if !finished && self.stop_reason.is_none() {
+            // Incomplete UTF-8 sequences decode to replacement characters, so hold them back until they're finished
+            end = text.trim_end_matches(char::REPLACEMENT_CHARACTER).len();
+            end -= partial_stop_len(&text[..end], &self.stop_strings);
+        }
+        if end <= self.n_text_bytes || !text.is_char_boundary(self.n_text_bytes) {
+            return if finished { None } else { Some(String::new()) };
+        }
+        let new = text[self.n_text_bytes..end].to_string();
+        self.n_text_bytes = end;
+        Some(new)
+    }
+
+    fn reached_max_tokens(&self) -> bool {
+        self.max_new_tokens
+            .map(|max| self.generated().len() >= max)
+            .unwrap_or_default()
+    }
+
+    /// Look for stop strings in the generated text, and remember where the first one starts
+    fn check_stop_strings(&mut self) {
+        if self.stop_strings.is_empty() || self.detokenizer.is_none() {
+            return;
+        }
+        let text = self.text();
+        if let Some((start, stop)) = self
+            .stop_strings
+            .iter()
+            .filter_map(|s| text.find(s.as_str()).map(|i| (i, s)))
+            .min_by_key(|(i, _)| *i)
+        {
+            self.text_end = Some(start);
+            self.stop_reason = Some(StopReason::StopString(stop.clone()));
+        }
+    }
+}
+
+/// Length of the longest end of `text` that's the start of a stop string
+fn partial_stop_len(text: &str, stop_strings: &[String]) -> usize {
+    stop_strings
+        .iter()
+        .flat_map(|s| {
+            (1..s.len())
+                .filter(|i| s.is_char_boundary(*i))
+                .map(|i| &s[..i])
+        })
+        .filter(|prefix| text.ends_with(prefix))
+        .map(str::len)
+        .max()
+        .unwrap_or_default()
+}
+
+impl<I: Shape, L: Shape> Iterator for Generator<I, L> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.stop_reason.is_none() {
+            if self.cancel.is_cancelled() {
+                self.stop_reason = Some(StopReason::Cancelled);
+            } else if self.reached_max_tokens() {
+                self.stop_reason = Some(StopReason::MaxTokens);
+            }
+        }
+        let new_tokens = &self.tokens[self.n_processed..];
+        if new_tokens.is_empty() || self.stop_reason.is_some() {
+            return None;
+        }
+        let cx = self.input.graph();
+        if let Some(dim) = self.past_dim {
+            cx.set_dyn_dim(dim, self.n_processed);
+        }
+        if let Some(dim) = self.total_dim {
+            cx.set_dyn_dim(dim, self.tokens.len());
+        }
+        let mut shape = vec![1; I::NUM_DIMS];
+        *shape.last_mut().unwrap() = new_tokens.len();
+        self.input.set_dyn(
+            new_tokens.iter().map(|t| *t as f32).collect::<Vec<_>>(),
+            &shape,
+        );
+        self.n_processed = self.tokens.len();
+        cx.execute();
+
+        // Sample from the last position
+        let logits = self.logits.data();
+        let mut logits_shape = self.logits.shape;
+        logits_shape.resolve_global_dyn_dims(&cx.dyn_map);
+        let vocab = logits_shape.shape_usize().last().copied().unwrap_or(1);
+        let token = self
+            .sampler
+            .sample(&logits[logits.len() - vocab..], &self.tokens);
+        self.logits.drop();
+        if let Some((cache_src, cache_dest)) = &self.cache {
+            transfer_data_same_graph(cache_dest, cache_src, cx);
+        }
+        if self.stop_tokens.contains(&token) {
+            self.stop_reason = Some(StopReason::StopToken(token));
+            return None;
+        }
+        self.tokens.push(token);
+        self.check_stop_strings();
+        if self.stop_reason.is_none() && self.reached_max_tokens() {
+            self.stop_reason = Some(StopReason::MaxTokens);
+        }
+        Some(token)
+    }
+}
+
+/// Start generating from a text prompt, encoded with `tokenizer` including its special tokens. Generation stops at any of
+/// the tokenizer's special tokens, and the generated text can be streamed out with `Generator::next_text`.
+#[cfg(feature = "tokenizers")]
+pub fn generate_text<I: Shape, L: Shape>(
+    input: GraphTensor<I>,
+    logits: GraphTensor<L>,
+    tokenizer: &tokenizers::Tokenizer,
+    prompt: &str,
+) -> Generator<I, L> {
+    let encoding = tokenizer
+        .encode(prompt, true)
+        .unwrap_or_else(|e| panic!("Failed to encode prompt: {e}"));
+    let tokenizer = tokenizer.clone();
+    generate(input, logits, encoding.get_ids())
+        .stop_tokens(special_token_ids(&tokenizer))
+        .detokenizer(move |tokens| {
+            tokenizer
+                .decode(tokens, true)
+                .unwrap_or_else(|e| panic!("Failed to decode tokens: {e}"))
+        })
+}
+
+/// Ids of a tokenizer's special tokens, like beginning and end of sequence markers
+#[cfg(feature = "tokenizers")]
+pub fn special_token_ids(tokenizer: &tokenizers::Tokenizer) -> Vec<u32> {
+    let mut ids = tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+    ids.sort();
+    ids
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_generate_kv_cache() {
+        let mut cx = Graph::new();
+        let input = cx.named_tensor::<(LConst<1>, Dyn<'s'>)>("Input");
+        let cache_src = cx.named_tensor::<(LConst<1>, Dyn<'p'>)>("Cache");
+        cache_src.set_dyn(Vec::<f32>::new(), &[1, 0]);
+        let cache_dest = cache_src
+            .concat_along::<(LConst<1>, Dyn<'t'>), LAxis<1>, _>(input)
+            .keep();
+        // Next token is the sum of all tokens so far
+        let logits = cache_dest
+            .sum_reduce::<_, LAxis<1>>()
+            .expand::<(LConst<1>, LConst<16>), LAxis<1>>()
+            .equals(cx.arange::<LConst<16>>().expand::<_, LAxis<0>>())
+            .retrieve();
+
+        let mut generator = generate(input, logits, &[1, 2])
+            .kv_cache(cache_src, cache_dest)
+            .past_dim('p')
+            .total_dim('t');
+        assert_eq!(generator.by_ref().take(2).collect::<Vec<_>>(), vec![3, 6]);
+        assert_eq!(generator.tokens(), &[1, 2, 3, 6]);
+        assert_eq!(cx.dyn_map[&'p'], 2);
+        assert_eq!(cx.dyn_map[&'t'], 3);
+        assert_exact(&cache_src.data(), &[1., 2., 3.]);
+    }
+
+    type Tokens = GraphTensor<(LConst<1>, Dyn<'s'>)>;
+    type Logits = GraphTensor<(LConst<1>, Dyn<'s'>, LConst<8>)>;
+
+    /// Graph whose next token is always the last token plus one
+    fn counting_graph(cx: &mut Graph) -> (Tokens, Logits) {
+        let input = cx.tensor::<(LConst<1>, Dyn<'s'>)>();
+        let logits = (input + 1.)
+            .expand::<(LConst<1>, Dyn<'s'>, LConst<8>), LAxis<2>>()
+            .equals(cx.arange::<LConst<8>>().expand::<_, LAxes2<0, 1>>())
+            .retrieve();
+        (input, logits)
+    }
+
+    #[test]
+    fn test_generate_stop_tokens_and_text() {
+        let mut cx = Graph::new();
+        let (input, logits) = counting_graph(&mut cx);
+        // Tokens 4 and 5 together make up one character
+        let mut generator = generate(input, logits, &[1])
+            .stop_tokens([6])
+            .detokenizer(|tokens| {
+                let mut text = String::new();
+                for (i, t) in tokens.iter().enumerate() {
+                    match t {
+                        4 if i + 1 == tokens.len() => text.push(char::REPLACEMENT_CHARACTER),
+                        4 => {}
+                        5 => text.push('é'),
+                        t => text.push_str(&t.to_string()),
+                    }
+                }
+                text
+            });
+        let mut pieces = vec![];
+        while let Some(piece) = generator.next_text() {
+            pieces.push(piece);
+        }
+        assert_eq!(pieces, vec!["2", "3", "", "é"]);
+        assert_eq!(generator.generated(), &[2, 3, 4, 5]);
+        assert_eq!(generator.text(), "23é");
+        assert_eq!(generator.next(), None);
+    }
+
+    #[test]
+    fn test_generate_stop_strings_and_limits() {
+        let mut cx = Graph::new();
+        let (input, logits) = counting_graph(&mut cx);
+        let digits = |tokens: &[u32]| tokens.iter().map(|t| t.to_string()).collect::<String>();
+        let collect = |stop: &str, max: usize| {
+            let mut generator = generate(input, logits, &[0])
+                .detokenizer(digits)
+                .stop_strings([stop])
+                .max_new_tokens(max);
+            let mut pieces = vec![];
+            while let Some(piece) = generator.next_text() {
+                pieces.push(piece);
+            }
+            (pieces, generator.text(), generator.stop_reason().cloned())
+        };
+
+        // "3" is held back until it's clear it doesn't start "35"
+        let (pieces, text, reason) = collect("35", 4);
+        assert_eq!(pieces, vec!["1", "2", "", "34"]);
+        assert_eq!(text, "1234");
+        assert_eq!(reason, Some(StopReason::MaxTokens));
+
+        // The stop string and anything after it never come out
+        let (pieces, text, reason) = collect("34", 6);
+        assert_eq!(pieces, vec!["1", "2", "", ""]);
+        assert_eq!(text, "12");
+        assert_eq!(reason, Some(StopReason::StopString("34".to_string())));
+
+        // Held back text is flushed when generation ends partway through a stop string
+        let mut generator = generate(input, logits, &[0])
+            .detokenizer(digits)
+            .stop_strings(["23"])
+            .stop_tokens([3]);
+        let mut pieces = vec![];
+        while let Some(piece) = generator.next_text() {
+            pieces.push(piece);
+        }
+        assert_eq!(pieces, vec!["1", "", "2"]);
+        assert_eq!(generator.stop_reason(), Some(&StopReason::StopToken(3)));
+
+        // Stop strings can't match without text to match them in
+        let tokens = generate(input, logits, &[0])
+            .stop_strings(["2"])
+            .max_new_tokens(3)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![1, 2, 3]);
+
+        let mut generator = generate(input, logits, &[0]);
+        let handle = generator.cancel_handle();
+        assert_eq!(generator.next(), Some(1));
+        std::thread::spawn(move || handle.cancel()).join().unwrap();
+        assert_eq!(generator.next(), None);
+        assert_eq!(generator.stop_reason(), Some(&StopReason::Cancelled));
+    }
+
+    #[cfg(feature = "tokenizers")]
+    #[test]
+    fn test_generate_text() {
+        use tokenizers::{
+            models::wordlevel::WordLevel, pre_tokenizers::whitespace::Whitespace, AddedToken,
+            Tokenizer,
+        };
+        let vocab = ["<s>", "a", "b", "c", "d", "e", "f", "</s>"]
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (w.to_string(), i as u32))
+            .collect();
+        let mut tokenizer = Tokenizer::new(
+            WordLevel::builder()
+                .vocab(vocab)
+                .unk_token("<s>".to_string())
+                .build()
+                .unwrap(),
+        );
+        tokenizer.with_pre_tokenizer(Whitespace {});
+        tokenizer.add_special_tokens(&[
+            AddedToken::from("<s>", true),
+            AddedToken::from("</s>", true),
+        ]);
+
+        let mut cx = Graph::new();
+        let (input, logits) = counting_graph(&mut cx);
+        let mut generator = generate_text(input, logits, &tokenizer, "c");
+        assert_eq!(generator.stop_token_ids(), &[0, 7]);
+        assert_eq!(generator.tokens(), &[3]);
+        while generator.next_text().is_some() {}
+        assert_eq!(generator.text(), "d e f");
+    }
+}
@@ -3,13 +3,17 @@ use std::collections::{HashMap, HashSet};
 use itertools::Itertools;
 use petgraph::{
     algo::toposort,
-    stable_graph::{NodeIndex, StableGraph},
+    stable_graph::{EdgeIndex, NodeIndex, StableGraph},
     visit::EdgeRef,
     Direction,
 };
+use rustc_hash::FxHashMap;
 
 use crate::{
-    op::{Add, Constant, ConstantValue, Function, MaxReduce, Mul, Operator, Recip, SumReduce},
+    op::{
+        Add, Cast, Constant, ConstantValue, Contiguous, DType, Exp2, Function, LessThan, Log2,
+        MaxReduce, MinReduce, Mod, Mul, Operator, ProdReduce, Recip, Select, Sin, Sqrt, SumReduce,
+    },
     prelude::*,
 };
 
@@ -28,20 +32,16 @@ pub struct CSE;
 impl Compiler for CSE {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
-        // Look for nodes that have the exact same srcs
+        // Look for nodes that have the exact same srcs. Nodes scheduled after different nodes are kept apart, since
+        // copies like recomputed activations are only there to run later.
         // Loop cause I'm lazy
         let mut eliminated = true;
         while eliminated {
             eliminated = false;
-            let mut srcs_set: HashMap<Vec<NodeIndex>, Vec<NodeIndex>> = HashMap::new();
+            let mut srcs_set: HashMap<(Vec<NodeIndex>, Vec<NodeIndex>), Vec<NodeIndex>> =
+                HashMap::new();
             for node in graph.graph.node_indices().collect_vec() {
-                if graph
-                    .graph
-                    .node_weight(node)
-                    .unwrap()
-                    .as_any()
-                    .is::<Function>()
-                {
+                if graph.is_op::<Function>(node) {
                     continue;
                 }
                 let srcs = graph
@@ -51,6 +51,14 @@ impl Compiler for CSE {
                     .sorted_by_key(|e| e.weight().as_data().unwrap().0)
                     .map(|e| e.source())
                     .collect_vec();
+                let after = graph
+                    .graph
+                    .edges_directed(node, petgraph::Direction::Incoming)
+                    .filter(|e| e.weight().is_schedule())
+                    .map(|e| e.source())
+                    .sorted()
+                    .collect_vec();
+                let srcs = (srcs, after);
 
                 if let Some(other_nodes) = srcs_set.get(&srcs) {
                     for other_node in other_nodes {
@@ -58,7 +66,9 @@ impl Compiler for CSE {
                         let Some(b) = graph.graph.node_weight(*other_node) else {
                             continue;
                         };
-                        if format!("{a:?}") != format!("{b:?}") {
+                        if a.as_any().type_id() != b.as_any().type_id()
+                            || format!("{a:?}") != format!("{b:?}")
+                        {
                             // Sloppy way to check if ops are equal, but we only expect primops here so it's ok
                             continue;
                         }
@@ -100,7 +110,7 @@ impl Compiler for CSE {
     }
 }
 
-/// Remove maxreduces and sumreduces that don't do anything
+/// Remove reductions over a single element, since they don't do anything
 #[derive(Default)]
 pub struct RemoveSingleReductions;
 
@@ -108,23 +118,13 @@ impl Compiler for RemoveSingleReductions {
     type Output = ();
     fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
         for node in graph.graph.node_indices().collect::<Vec<_>>() {
-            let dim = if let Some(red) = graph
-                .graph
-                .node_weight(node)
-                .unwrap()
-                .as_any()
+            let any = graph.graph.node_weight(node).unwrap().as_any();
+            let dim = any
                 .downcast_ref::<SumReduce>()
-            {
-                Some(red.0)
-            } else {
-                graph
-                    .graph
-                    .node_weight(node)
-                    .unwrap()
-                    .as_any()
-                    .downcast_ref::<MaxReduce>()
-                    .map(|red| red.0)
-            };
+                .map(|red| red.0)
+                .or_else(|| any.downcast_ref::<MaxReduce>().map(|red| red.0))
+                .or_else(|| any.downcast_ref::<MinReduce>().map(|red| red.0))
+                .or_else(|| any.downcast_ref::<ProdReduce>().map(|red| red.0));
             if let Some(dim) = dim {
                 if graph
                     .graph
@@ -176,6 +176,56 @@ impl Compiler for RemoveUnusedNodes {
     }
 }
 
+/// Fold contiguous ops into the views of their consumers, wherever the two views can be combined into one shape tracker.
+///
+/// Afterwards permutes, expands and slices only live on edges, so other compilers can match computation ops directly.
+#[derive(Default, Debug)]
+pub struct ContiguousElimination;
+
+impl Compiler for ContiguousElimination {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for node in graph.graph.node_indices().collect_vec() {
+            if !graph.is_op::<Contiguous>(node)
+                || graph.no_delete.contains(&node)
+                || ids.to_ids_mut().iter().any(|i| **i == node)
+            {
+                continue;
+            }
+            let Some((src, src_output, view)) = graph.get_sources(node).pop() else {
+                continue;
+            };
+            for (edge, dest, (input_order, _, shape)) in graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .filter_map(|e| e.weight().as_data().map(|d| (e.id(), e.target(), d)))
+                .collect_vec()
+            {
+                if let Some(shape) = view.compose(shape) {
+                    graph.graph.remove_edge(edge);
+                    graph.graph.add_edge(
+                        src,
+                        dest,
+                        Dependency::Data {
+                            input_order,
+                            output_order: src_output,
+                            shape,
+                        },
+                    );
+                }
+            }
+            if graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .next()
+                .is_none()
+            {
+                graph.graph.remove_node(node);
+            }
+        }
+    }
+}
+
 /// Enforce the graph gets ran in strictly depth-first order
 #[derive(Default, Debug)]
 pub struct DepthFirst;
@@ -487,10 +537,628 @@ impl Compiler for ArithmeticElimination {
     }
 }
 
+/// Mixed precision: cast the inputs of every matmul to a lower precision format, leaving the matmul to accumulate in f32
+/// and the rest of the graph in full precision. Matmuls whose outputs are marked with `skip` are left alone.
+///
+/// The casts store their outputs in half precision buffers, which backends with a mixed precision matmul (like
+/// `luminal_cpu`'s) read directly. Other backends widen them back to f32 as they read them.
+#[derive(Debug)]
+pub struct AutoCast {
+    pub dtype: DType,
+    skip: HashSet<NodeIndex>,
+}
+
+impl AutoCast {
+    pub fn new(dtype: DType) -> Self {
+        Self {
+            dtype,
+            skip: HashSet::new(),
+        }
+    }
+
+    /// Keep the matmuls producing these tensors in full precision
+    pub fn skip(mut self, outputs: impl ToIds) -> Self {
+        self.skip.extend(outputs.to_ids());
+        self
+    }
+}
+
+impl Compiler for AutoCast {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        if self.dtype == DType::F32 {
+            return;
+        }
+        for mul in graph.node_indices().collect_vec() {
+            if !graph.is_op::<Mul>(mul) {
+                continue;
+            }
+            // A matmul is a broadcasted multiply that only feeds a sum over its last dimension
+            let dests = graph
+                .graph
+                .edges_directed(mul, Direction::Outgoing)
+                .map(|e| e.target())
+                .collect_vec();
+            let [sum] = dests[..] else {
+                continue;
+            };
+            let srcs = graph.get_sources(mul);
+            // Scalar multiplies, like scaling a loss, can't be matmuls
+            let Some(last) = srcs[0].2.len().checked_sub(1) else {
+                continue;
+            };
+            if self.skip.contains(&sum)
+                || graph.try_get_op::<SumReduce>(sum).map(|s| s.0) != Some(last)
+                || !srcs
+                    .iter()
+                    .all(|(_, _, st)| !st.fake[st.indexes[last]] && st.fake.iter().any(|f| *f))
+                || srcs[0]
+                    .2
+                    .indexes
+                    .iter()
+                    .zip(&srcs[1].2.indexes)
+                    .all(|(a, b)| srcs[0].2.fake[*a] == srcs[1].2.fake[*b])
+            {
+                continue;
+            }
+            for edge in graph
+                .graph
+                .edges_directed(mul, Direction::Incoming)
+                .map(|e| e.id())
+                .collect_vec()
+            {
+                let (src, Some((input_order, output_order, shape))) = (
+                    graph.graph.edge_endpoints(edge).unwrap().0,
+                    graph.graph.edge_weight(edge).unwrap().as_data(),
+                ) else {
+                    continue;
+                };
+                if graph.try_get_op::<Cast>(src) == Some(&Cast(self.dtype)) {
+                    continue;
+                }
+                // Cast the data before it's broadcasted, then broadcast the cast data the same way
+                let fake_axes = (0..shape.len())
+                    .filter(|i| shape.fake[shape.indexes[*i]])
+                    .collect_vec();
+                let mut inner = shape;
+                for axis in fake_axes.iter().rev() {
+                    inner.remove_dim(*axis);
+                }
+                let cast = graph
+                    .add_op(Cast(self.dtype))
+                    .input(src, output_order, inner)
+                    .finish();
+                let mut cast_shape = inner.contiguous();
+                for axis in fake_axes {
+                    cast_shape.expand(axis, shape.dims[shape.indexes[axis]]);
+                }
+                graph.graph.remove_edge(edge);
+                graph.graph.add_edge(
+                    cast,
+                    mul,
+                    Dependency::Data {
+                        input_order,
+                        output_order: 0,
+                        shape: cast_shape,
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Fold an inference batch norm into the matmul right before it. The norm's per-channel scale is multiplied into the
+/// matmul's weights, and its shift, along with any per-channel bias added to the matmul's output, becomes a single bias.
+/// Batch norms after convolutions and linear layers (like `BatchNorm2D` in luminal_nn) boil down to this, including
+/// batched convolutions that permute their output back to channels first.
+///
+/// The weights and norm parameters are read when the graph is compiled, so they need to be kept with their data set by
+/// then. The folded weights and bias are computed once there and kept from then on, so no multiplies run on the weights.
+#[derive(Debug, Default)]
+pub struct FoldBatchNorm;
+
+impl Compiler for FoldBatchNorm {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for scale_mul in graph.node_indices().collect_vec() {
+            if !graph.contains_node(scale_mul) || !graph.is_op::<Mul>(scale_mul) {
+                continue;
+            }
+            let Some(fold) = NormFold::find(graph, scale_mul) else {
+                continue;
+            };
+            fold.apply(graph, &mut ids);
+        }
+    }
+}
+
+/// A node read through a view
+type View = (NodeIndex, u8, ShapeTracker);
+
+/// A batch norm's scale (and maybe shift) applied to a matmul's output, matched by `FoldBatchNorm`
+struct NormFold {
+    scale_mul: NodeIndex,
+    scale: View,
+    /// The add of the norm's shift after the scale, and the shift
+    shift: Option<(NodeIndex, View)>,
+    /// A contiguous copy of the matmul's output the scale is applied to
+    copy: Option<NodeIndex>,
+    /// The add of a bias to the matmul's output, and the bias
+    bias: Option<(NodeIndex, View)>,
+    sum: NodeIndex,
+    mm: NodeIndex,
+    /// The channel's axis in the matmul's output
+    mm_axis: usize,
+    /// The matmul's input edge carrying the weights the scale folds into, and its input order
+    operand: (EdgeIndex, View, u8),
+}
+
+impl NormFold {
+    fn find(graph: &Graph, scale_mul: NodeIndex) -> Option<Self> {
+        let srcs = graph.get_sources(scale_mul);
+        // One side is (a copy of) a matmul's output read straight through, the other only varies along one dimension
+        let out_input = (0..2).find(|i| {
+            let (src, _, st) = srcs[*i];
+            !st.is_reshaped()
+                && (graph.is_op::<SumReduce>(src)
+                    || graph.is_op::<Add>(src)
+                    || graph.is_op::<Contiguous>(src))
+        })?;
+        let (mut node, _, out_st) = srcs[out_input];
+        let scale = srcs[1 - out_input];
+        let scale_axis = channel_axis(&scale.2)?;
+        let mut axis = source_axis(&out_st, scale_axis, &output_dims(graph, node)?)?;
+        // The shift added after the scale, if it only varies along the same dimension
+        let shift = graph
+            .graph
+            .edges_directed(scale_mul, Direction::Outgoing)
+            .exactly_one()
+            .ok()
+            .and_then(|e| {
+                let add = e.target();
+                let (_, _, st) = e.weight().as_data()?;
+                let shift = graph
+                    .get_sources(add)
+                    .into_iter()
+                    .find(|(src, _, _)| *src != scale_mul)?;
+                let dims = output_dims(graph, scale_mul)?;
+                (graph.is_op::<Add>(add)
+                    && !st.is_reshaped()
+                    && !graph.no_delete.contains(&scale_mul)
+                    && source_axis(&st, channel_axis(&shift.2)?, &dims) == Some(scale_axis))
+                .then_some((add, shift))
+            });
+
+        // Walk back to the matmul, through a copy and then a bias
+        let is_private = |n: NodeIndex| {
+            !graph.no_delete.contains(&n)
+                && graph.edges_directed(n, Direction::Outgoing).count() == 1
+        };
+        let mut copy = None;
+        if graph.is_op::<Contiguous>(node) {
+            if !is_private(node) {
+                return None;
+            }
+            let (src, _, st) = graph.get_sources(node)[0];
+            if st.fake.iter().any(|f| *f) || st.is_sliced() || st.is_padded() {
+                return None;
+            }
+            axis = source_axis(&st, axis, &output_dims(graph, src)?)?;
+            copy = Some(node);
+            node = src;
+        }
+        let mut bias = None;
+        if graph.is_op::<Add>(node) {
+            let srcs = graph.get_sources(node);
+            let sum_input = (0..2).find(|i| {
+                let (src, _, st) = srcs[*i];
+                graph.is_op::<SumReduce>(src) && !st.is_reshaped()
+            })?;
+            let (sum, _, st) = srcs[sum_input];
+            let bias_view = srcs[1 - sum_input];
+            if !is_private(node) || channel_axis(&bias_view.2) != Some(axis) {
+                return None;
+            }
+            axis = source_axis(&st, axis, &output_dims(graph, sum)?)?;
+            bias = Some((node, bias_view));
+            node = sum;
+        }
+        let sum = node;
+        if !graph.is_op::<SumReduce>(sum) || !is_private(sum) {
+            return None;
+        }
+        let mm = matmul_mul(graph, sum)?;
+        if graph.no_delete.contains(&mm) {
+            return None;
+        }
+
+        // Rows scale the left hand side and columns scale the right hand side
+        let rank = graph.get_sources(mm)[0].2.len();
+        let other = match axis {
+            a if a + 2 == rank => rank - 3,
+            a if a + 3 == rank => rank - 2,
+            _ => return None,
+        };
+        let operand = graph
+            .graph
+            .edges_directed(mm, Direction::Incoming)
+            .filter_map(|e| {
+                let (input_order, output_order, st) = e.weight().as_data()?;
+                Some((e.id(), (e.source(), output_order, st), input_order))
+            })
+            .find(|(_, (_, _, st), _)| !st.fake[st.indexes[axis]] && st.fake[st.indexes[other]])?;
+        Some(Self {
+            scale_mul,
+            scale,
+            shift,
+            copy,
+            bias,
+            sum,
+            mm,
+            mm_axis: axis,
+            operand,
+        })
+    }
+
+    /// Compute the folded weights and bias, and swap them in for the norm
+    fn apply<T: ToIdsMut>(self, graph: &mut Graph, mut ids: T) {
+        let (operand_edge, (src, src_output, op_st), input_order) = self.operand;
+        // The weights as the matmul reads them, before they're broadcasted
+        let fake_axes = (0..op_st.len())
+            .filter(|i| op_st.fake[op_st.indexes[*i]])
+            .collect_vec();
+        let mut inner = op_st;
+        for a in fake_axes.iter().rev() {
+            inner.remove_dim(*a);
+        }
+        let inner_axis = self.mm_axis - fake_axes.iter().filter(|a| **a < self.mm_axis).count();
+        let Some(scale) = channel_values(graph, self.scale) else {
+            return;
+        };
+        let Some(mut weights) = view_values(graph, (src, src_output, inner)) else {
+            return;
+        };
+        let shift = match self.shift {
+            Some((_, shift)) => match channel_values(graph, shift) {
+                Some(shift) => Some(shift),
+                None => return,
+            },
+            None => None,
+        };
+        let bias = match self.bias {
+            Some((_, bias)) => match channel_values(graph, bias) {
+                Some(bias) => Some(bias),
+                None => return,
+            },
+            None => None,
+        };
+        let dims = inner.shape_usize();
+        let stride = dims[inner_axis + 1..].iter().product::<usize>();
+        for (i, w) in weights.iter_mut().enumerate() {
+            *w *= scale[(i / stride) % dims[inner_axis]];
+        }
+
+        // The matmul reads the folded weights instead, broadcasted the same way
+        let weights = graph
+            .add_op(Function(
+                "Folded Weight Load".to_string(),
+                Box::new(move |_| vec![Tensor::new(weights.clone())]),
+            ))
+            .finish();
+        graph.no_delete.insert(weights);
+        let mut folded_shape = inner.contiguous();
+        for a in fake_axes {
+            folded_shape.expand(a, op_st.dims[op_st.indexes[a]]);
+        }
+        graph.graph.remove_edge(operand_edge);
+        graph.graph.add_edge(
+            weights,
+            self.mm,
+            Dependency::Data {
+                input_order,
+                output_order: 0,
+                shape: folded_shape,
+            },
+        );
+
+        // The scaled bias and the shift add up to one bias on the matmul's output
+        let mut out = self.sum;
+        if bias.is_some() || shift.is_some() {
+            let channels = scale.len();
+            let folded = (0..channels)
+                .map(|c| {
+                    bias.as_ref().map_or(0., |b| b[c] * scale[c])
+                        + shift.as_ref().map_or(0., |s| s[c])
+                })
+                .collect_vec();
+            let folded = graph
+                .add_op(Function(
+                    "Folded Bias Load".to_string(),
+                    Box::new(move |_| vec![Tensor::new(folded.clone())]),
+                ))
+                .finish();
+            graph.no_delete.insert(folded);
+            let mut sum_shape = graph.get_sources(self.sum)[0].2;
+            sum_shape.remove_dim(sum_shape.len() - 1);
+            let sum_shape = sum_shape.contiguous();
+            let consumer = graph
+                .graph
+                .edges_directed(self.sum, Direction::Outgoing)
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .next()
+                .unwrap();
+            let mut bias_shape = ShapeTracker::new(&[channels.into()]);
+            for (i, dim) in sum_shape.shape().into_iter().enumerate() {
+                if i != self.mm_axis {
+                    bias_shape.expand(i, dim.small());
+                }
+            }
+            out = graph
+                .add_op(Add)
+                .input(self.sum, 0, sum_shape)
+                .input(folded, 0, bias_shape)
+                .finish();
+            match self.bias {
+                // The new bias replaces the old one
+                Some((add, _)) => {
+                    move_outgoing_edge(add, out, &mut graph.graph);
+                    remap(add, out, &mut ids, graph);
+                    graph.remove_node(add);
+                }
+                None => {
+                    graph.graph.remove_edge(consumer.0);
+                    graph.graph.add_edge(out, consumer.1, consumer.2);
+                }
+            }
+        }
+
+        // Whatever read the norm's output reads the folded output
+        let feeder = self.copy.unwrap_or(out);
+        let last = self.shift.map_or(self.scale_mul, |(add, _)| add);
+        move_outgoing_edge(last, feeder, &mut graph.graph);
+        remap(last, feeder, &mut ids, graph);
+        graph.remove_node(last);
+        if last != self.scale_mul {
+            graph.remove_node(self.scale_mul);
+        }
+        let unread = [Some(src), Some(self.scale.0)]
+            .into_iter()
+            .chain([self.shift, self.bias].map(|s| s.map(|(_, (n, _, _))| n)))
+            .flatten();
+        for node in unread.collect_vec() {
+            remove_unread(graph, node);
+        }
+    }
+}
+
+/// The only axis a view varies along, if it varies along just one
+fn channel_axis(st: &ShapeTracker) -> Option<usize> {
+    (0..st.len())
+        .filter(|i| !st.fake[st.indexes[*i]])
+        .exactly_one()
+        .ok()
+}
+
+/// The shape of a node's output, if it's static
+fn output_dims(graph: &Graph, node: NodeIndex) -> Option<Vec<usize>> {
+    let (_, _, st) = graph.get_sources(node).into_iter().next()?;
+    let mut dims = st
+        .shape()
+        .iter()
+        .map(|d| d.to_usize())
+        .collect::<Option<Vec<_>>>()?;
+    if let Some(SumReduce(axis)) = graph.try_get_op::<SumReduce>(node) {
+        dims.remove(*axis);
+    }
+    Some(dims)
+}
+
+/// The axis of a source's output (with dimensions `src_dims`) that an axis of a view of it lines up with, when the view
+/// only permutes or reshapes the output without merging that axis with others
+fn source_axis(st: &ShapeTracker, axis: usize, src_dims: &[usize]) -> Option<usize> {
+    let dims = st
+        .dims
+        .iter()
+        .map(|d| d.to_usize())
+        .collect::<Option<Vec<_>>>()?;
+    let physical = st.indexes[axis];
+    let before = dims[..physical].iter().product::<usize>();
+    (0..src_dims.len()).find(|j| {
+        src_dims[..*j].iter().product::<usize>() == before && src_dims[*j] == dims[physical]
+    })
+}
+
+/// The logical elements of a constant seen through a view
+fn view_values(graph: &mut Graph, (node, output, mut st): View) -> Option<Vec<f32>> {
+    st.try_resolve_global_dyn_dims_stack(&FxHashMap::default(), &mut vec![])
+        .ok()?;
+    let data = constant_data(graph, node, output)?;
+    Some(HostTensorView::new(data.as_f32_slice()?, st).to_vec())
+}
+
+/// The values of a constant varying along one axis of a view, in order along that axis
+fn channel_values(graph: &mut Graph, (node, output, mut st): View) -> Option<Vec<f32>> {
+    let axis = channel_axis(&st)?;
+    for a in (0..st.len()).rev().filter(|a| *a != axis) {
+        st.remove_dim(a);
+    }
+    view_values(graph, (node, output, st))
+}
+
+/// The data a node produces that doesn't change between runs: kept sources, float constants, and anything computed only
+/// from those with static shapes
+pub(crate) fn constant_data(graph: &mut Graph, node: NodeIndex, output: u8) -> Option<Tensor> {
+    if let Some(tensor) = graph.tensors.get(&(node, output)) {
+        return Some(tensor.clone());
+    }
+    let srcs = graph.get_sources(node);
+    if srcs.is_empty()
+        && !graph.no_delete.contains(&node)
+        && !matches!(
+            graph.try_get_op::<Constant>(node),
+            Some(Constant(ConstantValue::Float(_)))
+        )
+    {
+        return None;
+    }
+    let mut inputs = vec![];
+    for (src, src_output, mut st) in srcs {
+        st.try_resolve_global_dyn_dims_stack(&FxHashMap::default(), &mut vec![])
+            .ok()?;
+        inputs.push((
+            InputTensor::Owned(constant_data(graph, src, src_output)?),
+            st,
+        ));
+    }
+    // Tensors that were never set load nothing
+    graph
+        .node_weight_mut(node)
+        .unwrap()
+        .try_process(inputs, &FxHashMap::default())
+        .ok()?
+        .into_iter()
+        .nth(output as usize)
+        .filter(|t| t.as_f32_slice().is_some())
+}
+
+/// Remove a node nothing reads or keeps anymore, along with whatever only it read
+fn remove_unread(graph: &mut Graph, node: NodeIndex) {
+    if !graph.contains_node(node)
+        || graph.no_delete.contains(&node)
+        || graph
+            .edges_directed(node, Direction::Outgoing)
+            .next()
+            .is_some()
+    {
+        return;
+    }
+    let srcs = graph.get_sources(node);
+    graph.tensors.retain(|(n, _), _| *n != node);
+    graph.remove_node(node);
+    for (src, _, _) in srcs {
+        remove_unread(graph, src);
+    }
+}
+
+/// The multiply feeding a matmul's sum, if this node is the sum of a matmul
+pub(crate) fn matmul_mul(graph: &Graph, sum: NodeIndex) -> Option<NodeIndex> {
+    let srcs = graph.get_sources(sum);
+    let (mul, _, st) = srcs.first()?;
+    let last = st.len() - 1;
+    if graph.try_get_op::<SumReduce>(sum)?.0 != last
+        || !graph.is_op::<Mul>(*mul)
+        || graph
+            .graph
+            .edges_directed(*mul, Direction::Outgoing)
+            .count()
+            != 1
+    {
+        return None;
+    }
+    let mul_srcs = graph.get_sources(*mul);
+    let [(_, _, a), (_, _, b)] = mul_srcs[..] else {
+        return None;
+    };
+    // Both sides are broadcast, along different dimensions, and neither along the summed one
+    if a.fake[a.indexes[last]]
+        || b.fake[b.indexes[last]]
+        || !a.fake.iter().any(|f| *f)
+        || !b.fake.iter().any(|f| *f)
+        || a.indexes
+            .iter()
+            .zip(&b.indexes)
+            .all(|(x, y)| a.fake[*x] == b.fake[*y])
+    {
+        return None;
+    }
+    Some(*mul)
+}
+
+/// Recompute cheap elementwise ops once for each of their consumers, where the cost model says that beats writing out an
+/// intermediate and reading it back. Backends only fuse an op into a consumer it feeds alone, so this lets them fuse
+/// intermediates that are shared. Run it after CSE, which would merge the copies back together.
+#[derive(Debug)]
+pub struct RecomputeIntermediates {
+    /// How many flops moving one byte is worth. Higher values recompute more.
+    pub flops_per_byte: f32,
+}
+
+impl Default for RecomputeIntermediates {
+    fn default() -> Self {
+        Self { flops_per_byte: 4. }
+    }
+}
+
+impl Compiler for RecomputeIntermediates {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        // Consumers first, so copies of an op show up as extra consumers of its inputs
+        for node in toposort(&graph.graph, None).unwrap().into_iter().rev() {
+            if graph.no_delete.contains(&node)
+                || clone_elementwise(graph.node_weight(node).unwrap().as_ref()).is_none()
+            {
+                continue;
+            }
+            let outgoing = graph
+                .graph
+                .edges_directed(node, Direction::Outgoing)
+                .map(|e| (e.id(), e.target(), *e.weight()))
+                .collect_vec();
+            if outgoing.len() < 2 || outgoing.iter().any(|(_, _, w)| w.as_data().is_none()) {
+                continue;
+            }
+            let Some(cost) = graph.op_cost(node) else {
+                continue;
+            };
+            // Materializing runs the op once, writes the output and reads it back in every consumer. Recomputing runs
+            // the op and reads its inputs in every consumer, but never touches the output.
+            let consumers = outgoing.len() as f32;
+            let flops = cost.flops as f32;
+            let read = cost.bytes_read as f32 * self.flops_per_byte;
+            let written = cost.bytes_written as f32 * self.flops_per_byte;
+            if consumers * (flops + read) >= flops + read + written * (1. + consumers) {
+                continue;
+            }
+            let incoming = graph
+                .graph
+                .edges_directed(node, Direction::Incoming)
+                .map(|e| (e.source(), *e.weight()))
+                .collect_vec();
+            for (edge, target, weight) in outgoing.into_iter().skip(1) {
+                let copy = graph.graph.add_node(
+                    clone_elementwise(graph.node_weight(node).unwrap().as_ref()).unwrap(),
+                );
+                for (src, w) in &incoming {
+                    graph.graph.add_edge(*src, copy, *w);
+                }
+                if let Some(location) = graph.location(node) {
+                    graph.locations.insert(copy, location);
+                }
+                graph.graph.remove_edge(edge);
+                graph.graph.add_edge(copy, target, weight);
+            }
+        }
+    }
+}
+
+/// Copy an op if it's a primitive elementwise op
+fn clone_elementwise(op: &dyn Operator) -> Option<Box<dyn Operator>> {
+    let op = op.as_any();
+    macro_rules! try_clone {
+        ($($t:ty),*) => {
+            $(if let Some(o) = op.downcast_ref::<$t>() {
+                return Some(Box::new(o.clone()));
+            })*
+        };
+    }
+    try_clone!(Log2, Exp2, Sin, Sqrt, Recip, Add, Mul, Mod, LessThan, Select);
+    None
+}
+
 fn constant(num: f32) -> SelectGraph {
     let mut n = op::<Constant>();
     n.check(move |o, _| {
-        if let Some(Constant(ConstantValue::Float(f), _)) = o.as_any().downcast_ref::<Constant>() {
+        if let Some(Constant(ConstantValue::Float(f))) = o.as_any().downcast_ref::<Constant>() {
             *f == num
         } else {
             false
@@ -2,11 +2,25 @@
 
 use crate::prelude::*;
 use std::{
+    future::Future,
     io::Write,
     ops::{Deref, DerefMut},
+    panic::Location,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
     time::Duration,
 };
 
+// std's clock isn't available in the browser
+#[cfg(target_arch = "wasm32")]
+use instant::Instant;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
 use super::compiler_utils::{ToIds, ToIdsMut};
 use colored::Colorize;
 use itertools::Itertools;
@@ -25,10 +39,20 @@ pub struct Graph {
     pub tensors: FxHashMap<(NodeIndex, u8), Tensor>,
     /// A map of dynamic dimensions to concrete dimension sizes
     pub dyn_map: FxHashMap<char, usize>,
+    /// Largest each dynamic dimension is expected to get, for backends that size buffers once up front
+    pub dyn_dim_max: FxHashMap<char, usize>,
     /// Edge weights: (Input index, Output index, Input shape)
     pub graph: MainGraph,
     /// Tensors marked in this set will not get deleted when the graph is ran
     pub no_delete: FxHashSet<NodeIndex>,
+    /// When tensors not marked otherwise get freed during execution
+    pub retention: Retention,
+    /// Tensors held until the next execution starts, regardless of the graph's retention policy
+    pub retained: FxHashSet<NodeIndex>,
+    /// Data of the tensors held from the last execution
+    retained_tensors: FxHashMap<(NodeIndex, u8), Tensor>,
+    /// Tensors marked in this set stay resident across executions. While a pinned tensor has data, nodes that only feed into it are skipped
+    pub pinned: FxHashSet<NodeIndex>,
     /// Tensors marked in this set need to be retrieved later (mostly for optimizers to insert copy back calls, the graph itself doesn't treat these differently)
     pub to_retrieve: FxHashMap<NodeIndex, (u8, ShapeTracker)>,
     /// A list of current node to run, source nodes, and view nodes to delete after execution.
@@ -36,6 +60,87 @@ pub struct Graph {
     pub(crate) linearized_graph: Option<Vec<(NodeIndex, Vec<(NodeIndex, u8, ShapeTracker)>)>>,
     /// Cached consumers (for execution only)
     consumers_map: Option<FxHashMap<(NodeIndex, u8), usize>>,
+    /// Source shapes of the nodes in `linearized_graph` with dynamic dimensions filled in, reused between executions
+    resolved_shapes: ResolvedShapes,
+    /// Whether debug ops log their tensors when ran. Shared with the ops themselves
+    pub(crate) debug: Arc<AtomicBool>,
+    /// Shape functions and lowerings for custom ops
+    pub registry: OpRegistry,
+    /// Inputs declared with `Graph::input`, by name. These need to be bound with `Graph::bind` before each run
+    pub inputs: FxHashMap<String, (NodeIndex, ShapeTracker)>,
+    /// Names given to retrieved tensors with `GraphTensor::output`, which `Graph::outputs` reports them under
+    pub output_names: FxHashMap<NodeIndex, String>,
+    /// Whether backend compilers should keep the accumulation order of reductions and matmuls fixed
+    pub deterministic: bool,
+    /// Whether every op's outputs get checked for NaNs and infinities as it runs
+    pub nan_guard: bool,
+    /// Where in the model code each node was built, for nodes added by hl_ops
+    pub locations: FxHashMap<NodeIndex, &'static Location<'static>>,
+    /// Device each node runs on. Nodes without one get placed when `InsertTransfers` runs
+    pub devices: FxHashMap<NodeIndex, usize>,
+    /// Format each node's output is held in, for nodes not in f32. Worked out as ops are added, or set with `with_dtype`
+    pub dtypes: FxHashMap<NodeIndex, DType>,
+    /// Shape of each op's first output, inferred from its inputs as it's added. Sources, and ops that can't infer theirs
+    /// like functions, have none
+    pub shapes: FxHashMap<NodeIndex, ShapeTracker>,
+    /// Queue each node's work goes on, for backends with several. Filled in by `AssignQueues`
+    pub queues: FxHashMap<NodeIndex, usize>,
+    /// The backend's queues, set with `Graph::set_queues`
+    pub(crate) queue_state: crate::queue::QueueState,
+    /// Device new nodes get placed on, inside `Graph::with_device`
+    pub(crate) current_device: Option<usize>,
+    /// The graph after each compiler pass, while `debug::compile_passes` is recording them
+    pub(crate) pass_snapshots: Option<Vec<(String, crate::debug::GraphSnapshot)>>,
+}
+
+/// Source shapes each node runs with, for one set of dynamic dimensions. A node's shapes get resolved the first time it
+/// runs, and are thrown out when the graph is sorted again or the dimensions change.
+#[derive(Debug, Default)]
+struct ResolvedShapes {
+    dyn_map: FxHashMap<char, usize>,
+    /// By position in `linearized_graph`
+    shapes: Vec<Option<Vec<ShapeTracker>>>,
+}
+
+impl ResolvedShapes {
+    /// Source shapes of the node at `position` in the run order, resolving them if they aren't yet
+    fn get(
+        &mut self,
+        position: usize,
+        src_ids: &[(NodeIndex, u8, ShapeTracker)],
+        stack: &mut Vec<i64>,
+    ) -> Result<&[ShapeTracker], LuminalError> {
+        if self.shapes[position].is_none() {
+            let mut shapes = src_ids.iter().map(|(_, _, st)| *st).collect_vec();
+            for st in &mut shapes {
+                st.try_resolve_global_dyn_dims_stack(&self.dyn_map, stack)?;
+            }
+            self.shapes[position] = Some(shapes);
+        }
+        Ok(self.shapes[position].as_deref().unwrap())
+    }
+}
+
+/// What a tensor going into or coming out of a graph looks like, for tooling that only has the graph to go on
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorSignature {
+    pub name: String,
+    pub node: NodeIndex,
+    /// Dimensions as seen through the tensor's view, with dynamic ones left symbolic
+    pub shape: Vec<BigExpression>,
+    pub dtype: DType,
+}
+
+/// When a tensor's data gets freed during execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Retention {
+    /// Freed as soon as the last op reading it has ran, so peak memory only covers the tensors that are still needed
+    #[default]
+    FreeAfterLastUse,
+    /// Held until the next execution starts, so it can be inspected once the run is done
+    UntilNextRun,
+    /// Held across executions until dropped. This is what `keep()` and `retrieve()` do
+    Keep,
 }
 
 /// A dependency between two nodes
@@ -86,7 +191,21 @@ impl Graph {
 
     /// Try to get the tensor data in the graph
     pub fn get_tensor_ref(&self, id: NodeIndex, ind: u8) -> Option<&Tensor> {
-        self.tensors.get(&(id, ind))
+        self.tensors
+            .get(&(id, ind))
+            .or_else(|| self.retained_tensors.get(&(id, ind)))
+    }
+
+    /// Get a tensor's data with its view (permutes, slices, expands, padding) applied, as a contiguous row-major vec.
+    ///
+    /// Returns None if the tensor has no data on the host, usually because it wasn't marked with `retrieve()` or `keep()` before execution.
+    pub fn get<S: Shape>(&self, tensor: &GraphTensor<S>) -> Option<Vec<f32>> {
+        self.get_view(tensor.id, tensor.shape)
+    }
+
+    /// Get a node's data viewed through a shape tracker
+    pub(crate) fn get_view(&self, id: NodeIndex, st: ShapeTracker) -> Option<Vec<f32>> {
+        self.host_view(id, st).map(|v| v.to_vec())
     }
 
     /// Delete the tensor data from the graph
@@ -103,37 +222,221 @@ impl Graph {
         }
     }
 
+    /// Set the retention policy for every tensor that isn't kept or given its own policy. `Retention::Keep` can only be set per tensor
+    pub fn set_retention(&mut self, retention: Retention) {
+        assert!(
+            retention != Retention::Keep,
+            "Keeping every tensor would stop the graph from rerunning, use execute_no_delete instead"
+        );
+        self.retention = retention;
+    }
+
+    /// Set when these tensors get freed during execution, overriding the graph's policy
+    pub fn set_tensor_retention<T: ToIds>(&mut self, tensors: T, retention: Retention) {
+        for id in tensors.to_ids() {
+            self.no_delete.remove(&id);
+            self.retained.remove(&id);
+            match retention {
+                Retention::FreeAfterLastUse => {}
+                Retention::UntilNextRun => {
+                    self.retained.insert(id);
+                }
+                Retention::Keep => {
+                    self.no_delete.insert(id);
+                }
+            }
+        }
+    }
+
+    /// Pin tensors so their data survives execution, and their upstream nodes aren't rerun while they hold data
+    pub fn pin_tensors<T: ToIds>(&mut self, tensors: T) {
+        for id in tensors.to_ids() {
+            self.no_delete.insert(id);
+            self.pinned.insert(id);
+        }
+    }
+
+    /// Unpin tensors and drop their data, so they get recomputed on the next execution
+    pub fn unpin_tensors<T: ToIds>(&mut self, tensors: T) {
+        for id in tensors.to_ids() {
+            self.pinned.remove(&id);
+            self.no_delete.remove(&id);
+            self.tensors.retain(|(n, _), _| *n != id);
+        }
+    }
+
     /// Set a tensor's data
     pub fn set_tensor(&mut self, id: NodeIndex, ind: u8, tensor: Tensor) {
         self.tensors.insert((id, ind), tensor);
     }
 
+    /// Turn logging from `GraphTensor::debug` ops on or off. Debug ops do nothing while this is off.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug.store(debug, Ordering::Relaxed);
+    }
+
+    /// Ask backend compilers for ops that always accumulate in the same order, so repeated runs give bit-identical
+    /// results on any machine and with any number of threads, at some cost in speed. Needs to be set before compiling.
+    ///
+    /// The primitive ops always reduce serially in index order, so this only changes what backends swap in for them,
+    /// like tuned or multithreaded matmul kernels.
+    pub fn set_deterministic(&mut self, deterministic: bool) {
+        self.deterministic = deterministic;
+    }
+
+    /// Check the outputs of every op for NaNs and infinities as the graph runs, stopping at the first op producing one.
+    /// `execute` panics with the op, its input shapes and where it was built, and `try_execute` returns the same as a
+    /// `LuminalError::NonFinite`. Scanning every output slows execution down, so this is meant for debugging.
+    pub fn set_nan_guard(&mut self, nan_guard: bool) {
+        self.nan_guard = nan_guard;
+    }
+
+    /// Where in the model code a node was built, if it was added by an hl_op
+    pub fn location(&self, node: NodeIndex) -> Option<&'static Location<'static>> {
+        self.locations.get(&node).copied()
+    }
+
     /// Set a dynamic dimension
     pub fn set_dyn_dim(&mut self, dimension: char, val: usize) {
         self.dyn_map.insert(dimension, val);
     }
 
+    /// Declare the largest a dynamic dimension will get, like the context length for a sequence dimension. Backends that
+    /// plan their buffers with `BufferPlan` allocate them at this size once, and reuse them as the dimension grows or
+    /// shrinks instead of reallocating every time it changes. Going past it still works, at the cost of a reallocation.
+    pub fn set_dyn_dim_max(&mut self, dimension: char, max: usize) {
+        self.dyn_dim_max.insert(dimension, max);
+    }
+
     /// Create a new tensor with shape S
+    #[track_caller]
     pub fn tensor<S: Shape>(&mut self) -> GraphTensor<S> {
         self.named_tensor("Tensor")
     }
 
     /// Create a new tensor with shape S and a name. This name will show up on the graph when displayed
+    #[track_caller]
     pub fn named_tensor<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
         let name = name.to_string();
+        let id = self.graph.add_node(Box::new(Function(
+            format!("{name} Load"),
+            // Loads nothing until it's set
+            Box::new(|_| vec![]),
+        )));
+        self.locations.insert(id, Location::caller());
         GraphTensor {
-            id: self.graph.add_node(Box::new(Function(
-                format!("{name} Load"),
-                Box::new(move |_| panic!("You must set a value for this tensor! ({name})")),
-            ))),
+            id,
             graph_ref: self,
             shape: S::to_tracker(),
             _phantom: Default::default(),
         }
     }
 
+    /// Declare a named input, which gets fed fresh data with `Graph::bind` before each run
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let ids = cx.input::<(Dyn<'s'>,)>("input_ids");
+    /// let out = (ids * 2.).retrieve();
+    /// cx.bind("input_ids", vec![1., 2., 3.], &[3]).unwrap();
+    /// cx.execute();
+    /// assert_eq!(out.data(), vec![2., 4., 6.]);
+    /// ```
+    #[track_caller]
+    pub fn input<S: Shape>(&mut self, name: &str) -> GraphTensor<S> {
+        let t = self.named_tensor::<S>(name);
+        self.inputs.insert(name.to_string(), (t.id, t.shape));
+        t
+    }
+
+    /// Feed data to a declared input for the next run. Dynamic dimensions get set from the shape.
+    pub fn bind(
+        &mut self,
+        name: &str,
+        data: Vec<f32>,
+        shape: &[usize],
+    ) -> Result<(), LuminalError> {
+        let id = self.check_binding(name, &data, shape)?;
+        self.tensors.insert((id, 0), Tensor::new(data));
+        Ok(())
+    }
+
+    /// Feed shared data to a declared input without copying it. The graph lets go of its reference once the input's
+    /// consumers have run, so the caller can get the buffer back and refill it for the next run.
+    pub fn bind_shared(
+        &mut self,
+        name: &str,
+        data: SharedData,
+        shape: &[usize],
+    ) -> Result<(), LuminalError> {
+        let id = self.check_binding(name, (*data.0).as_ref(), shape)?;
+        self.tensors.insert((id, 0), Tensor::new(data));
+        Ok(())
+    }
+
+    fn check_binding(
+        &mut self,
+        name: &str,
+        data: &[f32],
+        shape: &[usize],
+    ) -> Result<NodeIndex, LuminalError> {
+        let (id, declared) = *self
+            .inputs
+            .get(name)
+            .ok_or_else(|| LuminalError::UnknownInput(name.to_string()))?;
+        check_input(name, &declared, data, shape, &mut self.dyn_map)?;
+        Ok(id)
+    }
+
+    /// Signatures of the declared inputs, in the order they were declared
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let ids = cx.input::<(Dyn<'b'>, Dyn<'s'>)>("input_ids");
+    /// (ids * 2.).output("logits");
+    /// let inputs = cx.inputs();
+    /// assert_eq!(inputs[0].name, "input_ids");
+    /// assert_eq!(inputs[0].shape, vec![BigExpression::from('b'), 's'.into()]);
+    /// assert_eq!(cx.outputs()[0].name, "logits");
+    /// ```
+    pub fn inputs(&self) -> Vec<TensorSignature> {
+        self.inputs
+            .iter()
+            .map(|(name, (id, shape))| self.signature(name.clone(), *id, *shape))
+            .sorted_by_key(|s| s.node)
+            .collect()
+    }
+
+    /// Signatures of the retrieved tensors, in the order they were added. Ones not named with `GraphTensor::output` go by
+    /// their op's name.
+    pub fn outputs(&self) -> Vec<TensorSignature> {
+        self.to_retrieve
+            .iter()
+            .map(|(id, (_, shape))| {
+                let name = self
+                    .output_names
+                    .get(id)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{:?}", self.graph.node_weight(*id).unwrap()));
+                self.signature(name, *id, *shape)
+            })
+            .sorted_by_key(|s| s.node)
+            .collect()
+    }
+
+    fn signature(&self, name: String, node: NodeIndex, shape: ShapeTracker) -> TensorSignature {
+        TensorSignature {
+            name,
+            node,
+            shape: shape.shape(),
+            dtype: self.dtype(node),
+        }
+    }
+
     /// Compile the graph using the given compiler
     pub fn compile<T: ToIdsMut, C: Compiler>(&mut self, compiler: C, remap: T) -> C::Output {
+        #[cfg(feature = "tracing")]
+        let _span = crate::trace::pass_span::<C>();
         let output = compiler.compile(self, remap);
         self.toposort();
         self.reset();
@@ -146,10 +449,17 @@ impl Graph {
             petgraph::algo::toposort(&self.graph, None)
                 .unwrap()
                 .into_iter()
-                .map(|node| (node, self.get_sources(node)))
+                .map(|node| {
+                    let srcs = self
+                        .get_sources_ordered(node)
+                        .unwrap_or_else(|e| panic!("{e}"));
+                    (node, srcs)
+                })
                 .collect(),
         );
 
+        self.resolved_shapes = ResolvedShapes::default();
+
         // Refresh the internal remaining consumers map
         self.consumers_map = Some(
             self.graph
@@ -187,80 +497,260 @@ impl Graph {
 
     /// Clear any remaining tensors that may be around from old executions
     pub fn reset(&mut self) {
-        self.tensors.retain(|(n, _), _| self.no_delete.contains(n));
+        self.finish_run(false);
     }
 
-    /// Execute the graph.
-    pub fn execute(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear
+    /// Get ready to run, dropping whatever was held from the last execution. With `retain_all`, every tensor is held
+    /// through the run.
+    fn start_run(&mut self, retain_all: bool) -> Run {
         if self.linearized_graph.is_none() {
             self.toposort();
         }
-        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
-        let mut dim_stack = Vec::new();
+        let n_nodes = self.linearized_graph.as_ref().unwrap().len();
+        if self.resolved_shapes.shapes.len() != n_nodes
+            || self.resolved_shapes.dyn_map != self.dyn_map
+        {
+            self.resolved_shapes = ResolvedShapes {
+                dyn_map: self.dyn_map.clone(),
+                shapes: vec![None; n_nodes],
+            };
+        }
+        self.retained_tensors.clear();
+        Run {
+            consumers: self.consumers_map.as_ref().unwrap().clone(),
+            held: self.held_tensors(retain_all),
+            skip: self.pinned_skips(),
+            dim_stack: vec![],
+        }
+    }
+
+    /// Drop the tensors left over from a run, holding onto the ones retained until the next run
+    fn finish_run(&mut self, retain_all: bool) {
+        self.queue_state.finish();
+        for (key, tensor) in std::mem::take(&mut self.tensors) {
+            if self.no_delete.contains(&key.0) {
+                self.tensors.insert(key, tensor);
+            } else if retain_all
+                || self.retention == Retention::UntilNextRun
+                || self.retained.contains(&key.0)
+            {
+                self.retained_tensors.insert(key, tensor);
+            }
+        }
+    }
 
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap() {
+    /// Tensors that can't be freed during a run
+    fn held_tensors(&self, retain_all: bool) -> FxHashSet<NodeIndex> {
+        if retain_all || self.retention == Retention::UntilNextRun {
+            self.graph.node_indices().collect()
+        } else {
+            self.no_delete.union(&self.retained).copied().collect()
+        }
+    }
+
+    /// Nodes that don't need to run because every path from them to an output goes through a pinned tensor that already has data
+    fn pinned_skips(&self) -> FxHashSet<NodeIndex> {
+        let mut skip = FxHashSet::default();
+        if self.pinned.is_empty() {
+            return skip;
+        }
+        let mut runs = FxHashSet::default();
+        for (node, _) in self.linearized_graph.as_ref().unwrap().iter().rev() {
             if self.tensors.contains_key(&(*node, 0)) {
                 continue;
             }
+            let mut consumers = self
+                .graph
+                .neighbors_directed(*node, Direction::Outgoing)
+                .peekable();
+            if self.no_delete.contains(node)
+                || consumers.peek().is_none()
+                || consumers.any(|c| runs.contains(&c))
+            {
+                runs.insert(*node);
+            } else {
+                skip.insert(*node);
+            }
+        }
+        skip
+    }
 
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
+    /// Execute the graph.
+    pub fn execute(&mut self) {
+        self.try_execute().unwrap_or_else(|e| panic!("{e}"));
+    }
 
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
-            }
+    /// Whether a node still needs to run in this run: it doesn't have data yet, and doesn't only feed pinned tensors that do
+    fn needs_run(&self, node: NodeIndex, run: &Run) -> bool {
+        !self.tensors.contains_key(&(node, 0)) && !run.skip.contains(&node)
+    }
 
-            // Execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
-            }
+    /// Run the node at `position` in the run order if it needs to, then free whatever nothing else is going to read.
+    /// Returns whether it ran.
+    fn run_node(&mut self, position: usize, run: &mut Run) -> Result<bool, LuminalError> {
+        let (node, src_ids) = &self.linearized_graph.as_ref().unwrap()[position];
+        let node = *node;
+        if !self.needs_run(node, run) {
+            return Ok(false);
+        }
+        let location = self.location(node);
+        if let Some((input, _, _)) = src_ids
+            .iter()
+            .find(|(id, ind, _)| !self.tensors.contains_key(&(*id, *ind)))
+        {
+            return Err(LuminalError::MissingInput {
+                node,
+                op: format!("{:?}", self.graph.node_weight(node).unwrap()),
+                input: *input,
+                location,
+            });
+        }
+        let shapes = self
+            .resolved_shapes
+            .get(position, src_ids, &mut run.dim_stack)?;
+        let srcs = get_source_tensors(
+            &run.held,
+            &mut self.tensors,
+            src_ids,
+            shapes,
+            &run.consumers,
+        );
 
-            // Bookkeep remaining consumers
-            for (id, ind, _) in src_ids {
-                *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
-            }
+        self.queue_state.before(&self.graph, &self.queues, node);
+        let op = self.graph.node_weight_mut(node).unwrap();
+        let tensors = run_op(
+            node,
+            op.as_mut(),
+            srcs,
+            &self.dyn_map,
+            location,
+            self.nan_guard,
+        );
+        self.queue_state.after(&self.graph, &self.queues, node);
+        let tensors = tensors?;
+        let n_outputs = tensors.len();
+        for (i, tensor) in tensors.into_iter().enumerate() {
+            self.tensors.insert((node, i as u8), tensor);
         }
-        self.reset();
+
+        // Bookkeep remaining consumers, freeing anything that's no longer needed
+        release_tensors(
+            &mut self.tensors,
+            node,
+            n_outputs,
+            src_ids,
+            &mut run.consumers,
+            &run.held,
+        );
+        Ok(true)
     }
 
-    /// Execute the graph without deleting intermediate tensors
-    pub fn execute_no_delete(&mut self) {
-        // Track the number of views pointing to each tensor so we know when to clear;
-        if self.linearized_graph.is_none() {
-            self.toposort();
+    /// Execute the graph as a future, which runs one node per poll and yields back to the executor in between,
+    /// so an async server doesn't have a worker thread blocked for the whole run.
+    ///
+    /// Each node still runs to completion inside its poll, so nothing overlaps: data transfers and compute happen one
+    /// after the other just like in `execute`. Dropping the future before it finishes stops the run and resets the graph.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// async fn run(cx: &mut Graph) {
+    ///     cx.execute_async().await;
+    /// }
+    /// ```
+    pub fn execute_async(&mut self) -> ExecuteFuture<'_> {
+        ExecuteFuture {
+            run: self.start_run(false),
+            graph: self,
+            position: 0,
         }
-        let mut dim_stack = Vec::new();
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
-            if self.tensors.contains_key(&(*node, 0)) {
-                continue;
-            }
-            let mut srcs = src_ids
-                .iter()
-                .map(|(id, ind, st)| {
-                    (
-                        InputTensor::Borrowed(self.tensors.get(&(*id, *ind)).unwrap()),
-                        *st,
-                    )
-                })
-                .collect_vec();
+    }
 
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+    /// Run the graph once over a batch of independent examples.
+    ///
+    /// The graph should be built with `batch_dim` as the leading dimension of each input and output. Every input's examples get packed
+    /// along that dimension, the graph runs once, and each output (which must be retrieved) is split back up per example.
+    /// Returns each example's outputs, in the order of `outputs`. The outputs are dropped afterwards so the next batch recomputes them.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<(Dyn<'b'>, Const<2>)>();
+    /// let b = (a * 2.).sum_reduce::<(Dyn<'b'>,), _>().retrieve();
+    /// let out = cx.execute_batch('b', &[(a.id, vec![vec![1., 2.], vec![3., 4.]])], b);
+    /// assert_eq!(out, vec![vec![vec![6.]], vec![vec![14.]]]);
+    /// ```
+    pub fn execute_batch<T: ToIds>(
+        &mut self,
+        batch_dim: char,
+        inputs: &[(NodeIndex, Vec<Vec<f32>>)],
+        outputs: T,
+    ) -> Vec<Vec<Vec<f32>>> {
+        let batch_size = inputs.first().map(|(_, b)| b.len()).unwrap_or_default();
+        for (input, examples) in inputs {
+            assert_eq!(
+                examples.len(),
+                batch_size,
+                "Every input needs the same number of examples"
+            );
+            // Fed in like a bound input, so the input's loader is left alone and the batch is freed once it's used
+            self.tensors
+                .insert((*input, 0), Tensor::new(examples.concat()));
+        }
+        self.dyn_map.insert(batch_dim, batch_size);
+        self.execute();
+
+        let mut batch = vec![vec![]; batch_size];
+        let outputs = outputs.to_ids();
+        for output in &outputs {
+            let (_, shape) = *self
+                .to_retrieve
+                .get(output)
+                .unwrap_or_else(|| panic!("Batch output {} isn't retrieved", output.index()));
+            let data = self.get_view(*output, shape).unwrap();
+            let example_size = data.len() / batch_size.max(1);
+            for (i, example) in batch.iter_mut().enumerate() {
+                example.push(data[i * example_size..(i + 1) * example_size].to_vec());
             }
+        }
+        self.drop_tensors(outputs);
+        batch
+    }
 
-            // All sources are ready, execute
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
+    /// Execute the graph, returning an error naming the failing node and its input shapes instead of panicking. Ops
+    /// report what went wrong through `Operator::try_process`, so ops that only implement `process` and panic still panic.
+    pub fn try_execute(&mut self) -> Result<(), LuminalError> {
+        if let Some(name) = self
+            .inputs
+            .iter()
+            .find(|(_, (id, _))| !self.tensors.contains_key(&(*id, 0)))
+            .map(|(name, _)| name)
+        {
+            return Err(LuminalError::UnboundInput(name.clone()));
+        }
+        #[cfg(feature = "tracing")]
+        let _span = crate::trace::execute_span();
+        // Track the number of views pointing to each tensor so we know when to clear
+        let mut run = self.start_run(false);
+        let n_nodes = self.linearized_graph.as_ref().unwrap().len();
+        let result =
+            (0..n_nodes).try_for_each(|position| self.run_node(position, &mut run).map(|_| ()));
+        self.reset();
+        result
+    }
+
+    /// Execute the graph without deleting intermediate tensors
+    pub fn execute_no_delete(&mut self) {
+        #[cfg(feature = "tracing")]
+        let _span = crate::trace::execute_span();
+        let mut run = self.start_run(true);
+        for position in 0..self.linearized_graph.as_ref().unwrap().len() {
+            if let Err(e) = self.run_node(position, &mut run) {
+                self.queue_state.finish();
+                panic!("{e}");
             }
         }
+        self.queue_state.finish();
     }
 
-    /// Execute the graph with debug prints
+    /// Execute the graph with debug prints. Every tensor is held until the next execution so it can be inspected afterwards
     pub fn execute_debug(&mut self) {
         fn format_duration(duration: &Duration) -> String {
             if duration.as_secs() > 0 {
@@ -271,14 +761,14 @@ impl Graph {
                 format!("{}µs", duration.as_micros())
             }
         }
-        // Track the number of views pointing to each tensor so we know when to clear
-        if self.linearized_graph.is_none() {
-            self.toposort();
-        }
-        let mut dim_stack = Vec::new();
-        let mut consumers = self.consumers_map.as_ref().unwrap().clone();
+        // Everything is held so it can be inspected after a debug run
+        let mut run = self.start_run(true);
         let mut op_times = FxHashMap::default();
-        let width = term_size::dimensions().unwrap().0;
+        // Output that isn't going to a terminal (or is in the browser) gets a fixed width
+        #[cfg(not(target_arch = "wasm32"))]
+        let width = term_size::dimensions().map_or(80, |(w, _)| w);
+        #[cfg(target_arch = "wasm32")]
+        let width: usize = 80;
 
         println!(
             "{:->2$} Executing {:->2$}",
@@ -286,65 +776,45 @@ impl Graph {
             "",
             (width.saturating_sub(" Executing ".len())) / 2
         );
-        let start = std::time::Instant::now();
-        for (node, src_ids) in self.linearized_graph.as_ref().unwrap().iter() {
-            if self.tensors.contains_key(&(*node, 0)) {
+        let start = Instant::now();
+        for position in 0..self.linearized_graph.as_ref().unwrap().len() {
+            let node = self.linearized_graph.as_ref().unwrap()[position].0;
+            if !self.needs_run(node, &run) {
                 continue;
             }
-            let op_name = format!("{:?} | {}", self.node_weight(*node).unwrap(), node.index());
+            let op_name = self.node_label(node);
             print!("{}", op_name.bold().bright_green());
+            std::io::stdout().flush().unwrap();
 
-            let mut srcs =
-                get_source_tensors(&self.no_delete, &mut self.tensors, src_ids, &consumers);
-
-            // Substitute in the dyn dims
-            for (_, st) in srcs.iter_mut() {
-                st.resolve_global_dyn_dims_stack(&self.dyn_map, &mut dim_stack);
+            // Execute
+            let now = Instant::now();
+            if let Err(e) = self.run_node(position, &mut run) {
+                println!();
+                self.finish_run(true);
+                panic!("{e}");
             }
-
-            // All sources are ready
-            let mut shapes_string = srcs
+            let elapsed = now.elapsed();
+            let mut shapes_string = self.resolved_shapes.shapes[position]
                 .iter()
-                .map(|(_, s)| {
-                    format!(
-                        "{:?}",
-                        s.shape()
-                            .into_iter()
-                            .map(|i| i.to_usize().unwrap())
-                            .collect::<Vec<_>>()
-                    )
-                })
+                .flatten()
+                .map(|s| format!("{:?}", s.shape_usize()))
                 .join(", ");
             if !shapes_string.is_empty() {
                 shapes_string = format!(" ({shapes_string})");
             }
-            print!("{shapes_string}");
-            std::io::stdout().flush().unwrap();
-            // Execute
-            let now = std::time::Instant::now();
-            let tensors = self.graph.node_weight_mut(*node).unwrap().process(srcs);
-            let elapsed = now.elapsed();
             println!(
-                "{:.>1$}",
+                "{shapes_string}{:.>1$}",
                 format_duration(&elapsed).bold(),
                 width
                     .saturating_sub(op_name.len())
                     .saturating_sub(shapes_string.len()),
             );
-            for (i, tensor) in tensors.into_iter().enumerate() {
-                self.tensors.insert((*node, i as u8), tensor);
-            }
-            let timed_op_name = format!("{:?}", self.node_weight(*node).unwrap());
+            let timed_op_name = format!("{:?}", self.node_weight(node).unwrap());
             if let Some(t) = op_times.get_mut(&timed_op_name) {
                 *t += elapsed;
             } else {
                 op_times.insert(timed_op_name, elapsed);
             }
-
-            // Check if we can delete the source tensors now
-            for (id, ind, _) in src_ids {
-                *consumers.get_mut(&(*id, *ind)).unwrap() -= 1;
-            }
         }
 
         // Print out total times
@@ -364,7 +834,7 @@ impl Graph {
             );
         }
         println!("Total: {}", format_duration(&start.elapsed()).bold());
-        self.reset();
+        self.finish_run(true);
     }
 }
 
@@ -381,17 +851,160 @@ impl DerefMut for Graph {
     }
 }
 
+/// A graph execution in progress. See `Graph::execute_async`.
+pub struct ExecuteFuture<'a> {
+    graph: &'a mut Graph,
+    position: usize,
+    run: Run,
+}
+
+impl Future for ExecuteFuture<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        let n_nodes = this.graph.linearized_graph.as_ref().unwrap().len();
+        // Run the next node that needs to run
+        while this.position < n_nodes {
+            this.position += 1;
+            match this.graph.run_node(this.position - 1, &mut this.run) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => panic!("{e}"),
+            }
+        }
+        if this.position >= n_nodes {
+            this.graph.reset();
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for ExecuteFuture<'_> {
+    fn drop(&mut self) {
+        // Cancelled partway through, so clear out what the run left behind
+        let n_nodes = self.graph.linearized_graph.as_ref().map_or(0, |g| g.len());
+        if self.position < n_nodes && !std::thread::panicking() {
+            self.graph.reset();
+        }
+    }
+}
+
+/// Bookkeeping for one execution of the graph
+struct Run {
+    /// How many of each tensor's consumers are still to run
+    consumers: FxHashMap<(NodeIndex, u8), usize>,
+    /// Tensors that can't be freed during the run
+    held: FxHashSet<NodeIndex>,
+    /// Nodes that don't need to run because of pinned tensors
+    skip: FxHashSet<NodeIndex>,
+    dim_stack: Vec<i64>,
+}
+
+/// Run an op on its sources with the dimensions of the run. Failures, a source that was never given a value, and (with
+/// the NaN guard on) NaNs or infinities in the outputs are returned as errors naming the node.
+pub(crate) fn run_op(
+    node: NodeIndex,
+    op: &mut dyn Operator,
+    srcs: Vec<(InputTensor, ShapeTracker)>,
+    dyn_map: &FxHashMap<char, usize>,
+    location: Option<&'static Location<'static>>,
+    nan_guard: bool,
+) -> Result<Vec<Tensor>, LuminalError> {
+    let input_shapes = srcs.iter().map(|(_, st)| st.shape_usize()).collect_vec();
+    #[cfg(feature = "tracing")]
+    let _span = crate::trace::op_span(node, op, &srcs);
+    let tensors = match op.try_process(srcs, dyn_map) {
+        Ok(tensors) => tensors,
+        Err(error) => {
+            return Err(LuminalError::OpFailed {
+                node,
+                op: format!("{op:?}"),
+                input_shapes,
+                location,
+                error: Box::new(error),
+            })
+        }
+    };
+    // Tensors that haven't been set load nothing. Other ops without inputs, like ones allocating a backend's buffers,
+    // can have nothing to hand on.
+    if input_shapes.is_empty() && tensors.is_empty() && op.as_any().is::<crate::op::Function>() {
+        return Err(LuminalError::UnsetTensor {
+            node,
+            op: format!("{op:?}"),
+            location,
+        });
+    }
+    if nan_guard {
+        for data in tensors.iter().filter_map(|t| t.as_f32_slice()) {
+            if let Some((index, value)) = data.iter().find_position(|v| !v.is_finite()) {
+                return Err(LuminalError::NonFinite {
+                    node,
+                    op: format!("{op:?}"),
+                    input_shapes,
+                    location,
+                    index,
+                    value: if value.is_nan() {
+                        "NaN"
+                    } else if value.is_sign_positive() {
+                        "inf"
+                    } else {
+                        "-inf"
+                    },
+                });
+            }
+        }
+    }
+    Ok(tensors)
+}
+
+/// Check input data against the shape it was declared with, setting any dynamic dimensions
+pub(crate) fn check_input(
+    name: &str,
+    declared: &ShapeTracker,
+    data: &[f32],
+    shape: &[usize],
+    dyn_map: &mut FxHashMap<char, usize>,
+) -> Result<(), LuminalError> {
+    let dims = declared.shape();
+    let mismatch = || LuminalError::InputShapeMismatch {
+        name: name.to_string(),
+        expected: dims.iter().map(|d| d.to_string()).collect(),
+        found: shape.to_vec(),
+    };
+    if dims.len() != shape.len() || data.len() != shape.iter().product::<usize>() {
+        return Err(mismatch());
+    }
+    for (dim, size) in dims.iter().zip(shape) {
+        if let Some(n) = dim.to_usize() {
+            if n != *size {
+                return Err(mismatch());
+            }
+        }
+    }
+    for (dim, size) in dims.iter().zip(shape) {
+        if let Some(c) = dim.to_symbols().pop() {
+            dyn_map.insert(c, *size);
+        }
+    }
+    Ok(())
+}
+
 /// Get source tensor array for a node
 fn get_source_tensors<'a>(
-    no_delete: &'a FxHashSet<NodeIndex>,
+    held: &'a FxHashSet<NodeIndex>,
     tensors: *mut FxHashMap<(NodeIndex, u8), Tensor>,
     src_ids: &'a [(NodeIndex, u8, ShapeTracker)],
+    shapes: &[ShapeTracker],
     consumers: &'a FxHashMap<(NodeIndex, u8), usize>,
 ) -> Vec<(InputTensor<'a>, ShapeTracker)> {
     let mut srcs = vec![];
-    for (id, ind, sh) in src_ids {
+    for ((id, ind, _), sh) in src_ids.iter().zip(shapes) {
         let id = &(*id, *ind);
-        if consumers[id] == 1 && !no_delete.contains(&id.0) {
+        if consumers[id] == 1 && !held.contains(&id.0) {
             srcs.push((
                 InputTensor::Owned(unsafe { tensors.as_mut().unwrap() }.remove(id).unwrap()),
                 *sh,
@@ -405,3 +1018,28 @@ fn get_source_tensors<'a>(
     }
     srcs
 }
+
+/// Count a node's sources as consumed, and free the tensors nothing else reads (including the node's own unread outputs) unless they're held
+fn release_tensors(
+    tensors: &mut FxHashMap<(NodeIndex, u8), Tensor>,
+    node: NodeIndex,
+    n_outputs: usize,
+    src_ids: &[(NodeIndex, u8, ShapeTracker)],
+    consumers: &mut FxHashMap<(NodeIndex, u8), usize>,
+    held: &FxHashSet<NodeIndex>,
+) {
+    for (id, ind, _) in src_ids {
+        let remaining = consumers.get_mut(&(*id, *ind)).unwrap();
+        *remaining -= 1;
+        if *remaining == 0 && !held.contains(id) {
+            tensors.remove(&(*id, *ind));
+        }
+    }
+    if !held.contains(&node) {
+        for i in 0..n_outputs as u8 {
+            if consumers.get(&(node, i)).copied().unwrap_or_default() == 0 {
+                tensors.remove(&(node, i));
+            }
+        }
+    }
+}
@@ -47,6 +47,31 @@ impl<S: Shape> GraphTensor<S> {
         self
     }
 
+    /// Retrieve this tensor as a named output of the graph, which `Graph::outputs` reports it under
+    pub fn output(self, name: &str) -> Self {
+        self.graph().output_names.insert(self.id, name.to_string());
+        self.retrieve()
+    }
+
+    /// Set when this tensor's data gets freed during execution, overriding the graph's retention policy
+    pub fn retain(self, retention: Retention) -> Self {
+        self.graph().set_tensor_retention(self.id, retention);
+        self
+    }
+
+    /// Keep this tensor's data resident across executions. Once it's computed, the nodes that only feed into it won't run again
+    /// until it's unpinned or dropped, so an encoder can run once and be reused by many decoder executions.
+    pub fn pin(self) -> Self {
+        self.graph().pin_tensors(self.id);
+        self
+    }
+
+    /// Stop pinning this tensor and drop its data
+    pub fn unpin(self) -> Self {
+        self.graph().unpin_tensors(self.id);
+        self
+    }
+
     /// Remove this tensor's data from the graph.
     pub fn drop(&self) {
         self.graph().drop_tensors(self.id);
@@ -95,25 +120,9 @@ impl<S: Shape> GraphTensor<S> {
 
     /// Get the contiguous data of the tensor
     pub fn data(&self) -> Vec<f32> {
-        let tensor = self.graph().get_tensor_ref(self.id, 0).unwrap();
-        let orig_data = tensor.downcast_ref::<Vec<f32>>().unwrap();
-        let mut st = self.shape;
-        if !st.is_reshaped() {
-            return orig_data.clone();
-        }
-        st.resolve_global_dyn_dims(&self.graph().dyn_map);
-        let mut data = vec![0.; st.n_elements().to_usize().unwrap()];
-        let (ind, val) = (
-            st.index_expression_no_simplify(),
-            st.valid_expression_no_simplify(),
-        );
-        #[allow(unused_mut)]
-        for (i, mut r) in data.iter_mut().enumerate() {
-            if val.exec_single_var(i) != 0 {
-                *r = orig_data[ind.exec_single_var(i)];
-            }
-        }
-        data
+        self.graph()
+            .get(self)
+            .expect("Tensor has no data. Mark it with retrieve() or keep() before executing")
     }
 }
 
@@ -127,7 +136,7 @@ impl<S: ConstShape> GraphTensor<S> {
     }
 
     /// Set the tensor with a generating closure to be ran at runtime
-    pub fn set_deferred(self, loader: impl Fn() -> Vec<f32> + 'static) -> Self {
+    pub fn set_deferred(self, loader: impl Fn() -> Vec<f32> + Send + 'static) -> Self {
         self.graph().get_op_mut::<Function>(self.id).1 =
             Box::new(move |_| vec![Tensor::new(loader())]);
         self
@@ -352,6 +361,16 @@ impl<S: Shape> ToData<S, Vec<f32>> for Vec<f32> {
         self
     }
 }
+impl<S: Shape> ToData<S, SharedData> for SharedData {
+    fn to_data_vec(self) -> SharedData {
+        self
+    }
+}
+impl<S: Shape> ToData<S, SharedData> for std::sync::Arc<[f32]> {
+    fn to_data_vec(self) -> SharedData {
+        self.into()
+    }
+}
 impl ToData<R0, Vec<f32>> for f32 {
     fn to_data_vec(self) -> Vec<f32> {
         vec![self]
@@ -10,6 +10,7 @@ use std::ops::{Add, Div, Mul, Rem, Sub};
 impl<S: Shape> Add for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(mut self, mut rhs: GraphTensor<S>) -> Self::Output {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let new_id = self
@@ -25,12 +26,14 @@ impl<S: Shape> Add for GraphTensor<S> {
 impl<S: Shape> Add<GraphTensor<S>> for f32 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(self, rhs: GraphTensor<S>) -> Self::Output {
         rhs + self
     }
 }
 
 impl<S: Shape> AddAssign for GraphTensor<S> {
+    #[track_caller]
     fn add_assign(&mut self, rhs: Self) {
         *self = *self + rhs;
     }
@@ -39,6 +42,7 @@ impl<S: Shape> AddAssign for GraphTensor<S> {
 impl<S: Shape> Sub for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: GraphTensor<S>) -> Self::Output {
         self + -rhs
     }
@@ -47,12 +51,14 @@ impl<S: Shape> Sub for GraphTensor<S> {
 impl<S: Shape> Sub<GraphTensor<S>> for f32 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: GraphTensor<S>) -> Self::Output {
         self + -rhs
     }
 }
 
 impl<S: Shape> SubAssign for GraphTensor<S> {
+    #[track_caller]
     fn sub_assign(&mut self, rhs: Self) {
         *self = *self - rhs;
     }
@@ -61,6 +67,7 @@ impl<S: Shape> SubAssign for GraphTensor<S> {
 impl<S: Shape> Mul for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(mut self, mut rhs: GraphTensor<S>) -> Self::Output {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let new_id = self
@@ -76,12 +83,14 @@ impl<S: Shape> Mul for GraphTensor<S> {
 impl<S: Shape> Mul<GraphTensor<S>> for f32 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(self, rhs: GraphTensor<S>) -> Self::Output {
         rhs * self
     }
 }
 
 impl<S: Shape> MulAssign for GraphTensor<S> {
+    #[track_caller]
     fn mul_assign(&mut self, rhs: Self) {
         *self = *self * rhs;
     }
@@ -91,6 +100,7 @@ impl<S: Shape> MulAssign for GraphTensor<S> {
 impl<S: Shape> Div<GraphTensor<S>> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: GraphTensor<S>) -> Self::Output {
         self * rhs.recip()
     }
@@ -100,12 +110,14 @@ impl<S: Shape> Div<GraphTensor<S>> for GraphTensor<S> {
 impl<S: Shape> Div<GraphTensor<S>> for f32 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: GraphTensor<S>) -> Self::Output {
         self * rhs.recip()
     }
 }
 
 impl<S: Shape> DivAssign for GraphTensor<S> {
+    #[track_caller]
     fn div_assign(&mut self, rhs: Self) {
         *self = *self / rhs;
     }
@@ -114,6 +126,7 @@ impl<S: Shape> DivAssign for GraphTensor<S> {
 impl<S: Shape> Rem<GraphTensor<S>> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn rem(mut self, mut rhs: GraphTensor<S>) -> Self::Output {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let new_id = self
@@ -126,7 +139,17 @@ impl<S: Shape> Rem<GraphTensor<S>> for GraphTensor<S> {
     }
 }
 
+impl<S: Shape> Rem<GraphTensor<S>> for f32 {
+    type Output = GraphTensor<S>;
+
+    #[track_caller]
+    fn rem(self, rhs: GraphTensor<S>) -> Self::Output {
+        rhs.graph().constant(self).expand_to(rhs.shape) % rhs
+    }
+}
+
 impl<S: Shape> RemAssign for GraphTensor<S> {
+    #[track_caller]
     fn rem_assign(&mut self, rhs: Self) {
         *self = *self % rhs;
     }
@@ -135,6 +158,7 @@ impl<S: Shape> RemAssign for GraphTensor<S> {
 impl<S: Shape> Add<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(self, rhs: f32) -> Self::Output {
         self + self.graph().constant(rhs).expand_to(self.shape)
     }
@@ -146,6 +170,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn add(self, rhs: GenericExpression<St>) -> Self::Output {
         self + self.graph().constant_expr(rhs).expand_to(self.shape)
     }
@@ -154,6 +179,7 @@ where
 impl<S: Shape> Sub<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: f32) -> Self::Output {
         self - self.graph().constant(rhs).expand_to(self.shape)
     }
@@ -165,6 +191,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn sub(self, rhs: GenericExpression<St>) -> Self::Output {
         self - self.graph().constant_expr(rhs).expand_to(self.shape)
     }
@@ -173,6 +200,7 @@ where
 impl<S: Shape> Mul<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(self, rhs: f32) -> Self::Output {
         self * self.graph().constant(rhs).expand_to(self.shape)
     }
@@ -184,6 +212,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn mul(self, rhs: GenericExpression<St>) -> Self::Output {
         self * self.graph().constant_expr(rhs).expand_to(self.shape)
     }
@@ -193,6 +222,7 @@ where
 impl<S: Shape> Div<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: f32) -> Self::Output {
         self * self.graph().constant(rhs.recip()).expand_to(self.shape)
     }
@@ -204,6 +234,7 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn div(self, rhs: GenericExpression<St>) -> Self::Output {
         self / self.graph().constant_expr(rhs).expand_to(self.shape)
     }
@@ -212,6 +243,7 @@ where
 impl<S: Shape> Rem<f32> for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn rem(self, rhs: f32) -> Self::Output {
         self % self.graph().constant(rhs).expand_to(self.shape)
     }
@@ -223,13 +255,137 @@ where
 {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn rem(self, rhs: GenericExpression<St>) -> Self::Output {
         self % self.graph().constant_expr(rhs).expand_to(self.shape)
     }
 }
 
+impl<S: Shape> AddAssign<f32> for GraphTensor<S> {
+    #[track_caller]
+    fn add_assign(&mut self, rhs: f32) {
+        *self = *self + rhs;
+    }
+}
+
+impl<S: Shape> SubAssign<f32> for GraphTensor<S> {
+    #[track_caller]
+    fn sub_assign(&mut self, rhs: f32) {
+        *self = *self - rhs;
+    }
+}
+
+impl<S: Shape> MulAssign<f32> for GraphTensor<S> {
+    #[track_caller]
+    fn mul_assign(&mut self, rhs: f32) {
+        *self = *self * rhs;
+    }
+}
+
+impl<S: Shape> DivAssign<f32> for GraphTensor<S> {
+    #[track_caller]
+    fn div_assign(&mut self, rhs: f32) {
+        *self = *self / rhs;
+    }
+}
+
+/// The right hand side of an elementwise op: a scalar, or another tensor. Tensors of other shapes get broadcast to the
+/// left hand side's like numpy does it (see `broadcast_to`), so shapes that can't broadcast fail to compile where their
+/// sizes are known.
+pub trait BinaryRhs<S: Shape> {
+    /// Get a tensor to combine with `lhs`
+    fn to_tensor(self, lhs: GraphTensor<S>) -> GraphTensor<S>;
+    /// The value, if this is a plain float
+    fn as_f32(&self) -> Option<f32> {
+        None
+    }
+}
+
+impl<S: Shape, R: BroadcastableTo<S>> BinaryRhs<S> for GraphTensor<R> {
+    #[track_caller]
+    fn to_tensor(self, _: GraphTensor<S>) -> GraphTensor<S> {
+        if std::any::TypeId::of::<R>() == std::any::TypeId::of::<S>() {
+            // Already the same shape, whatever sizes its dimensions turned out to have
+            GraphTensor::from_id(self.id, self.shape, self.graph_ref)
+        } else {
+            self.broadcast_to()
+        }
+    }
+}
+
+impl<S: Shape> BinaryRhs<S> for f32 {
+    #[track_caller]
+    fn to_tensor(self, lhs: GraphTensor<S>) -> GraphTensor<S> {
+        lhs.graph().constant(self).expand_to(lhs.shape)
+    }
+    fn as_f32(&self) -> Option<f32> {
+        Some(*self)
+    }
+}
+
+impl<S: Shape, St: ExpressionStorage> BinaryRhs<S> for GenericExpression<St>
+where
+    GenericExpression<Vec<Term>>: From<GenericExpression<St>>,
+{
+    #[track_caller]
+    fn to_tensor(self, lhs: GraphTensor<S>) -> GraphTensor<S> {
+        lhs.graph().constant_expr(self).expand_to(lhs.shape)
+    }
+}
+
+// Elementwise ops broadcasting their right hand side
+impl<S: Shape> GraphTensor<S> {
+    /// Add a scalar, or a tensor broadcast to this one's shape like numpy does it
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+    /// let bias = cx.tensor::<R1<3>>().set([10., 20., 30.]);
+    /// let column = cx.tensor::<R2<2, 1>>().set([[1.], [-1.]]);
+    /// let b = a.broadcast_add(bias).broadcast_mul(column).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![11., 22., 33., -14., -25., -36.]);
+    /// ```
+    /// Shapes that can't be broadcast fail to compile:
+    /// ```compile_fail
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<2, 3>>();
+    /// let b = a.broadcast_add(cx.tensor::<R1<2>>());
+    /// ```
+    #[track_caller]
+    pub fn broadcast_add(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self + rhs.to_tensor(self)
+    }
+
+    /// Subtract a scalar, or a tensor broadcast to this one's shape like numpy does it
+    #[track_caller]
+    pub fn broadcast_sub(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self - rhs.to_tensor(self)
+    }
+
+    /// Multiply by a scalar, or a tensor broadcast to this one's shape like numpy does it
+    #[track_caller]
+    pub fn broadcast_mul(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self * rhs.to_tensor(self)
+    }
+
+    /// Divide by a scalar, or a tensor broadcast to this one's shape like numpy does it
+    #[track_caller]
+    pub fn broadcast_div(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self / rhs.to_tensor(self)
+    }
+
+    /// Take the remainder with a scalar, or a tensor broadcast to this one's shape like numpy does it
+    #[track_caller]
+    pub fn broadcast_rem(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self % rhs.to_tensor(self)
+    }
+}
+
 // Comparisons (based on https://github.com/tinygrad/tinygrad/blob/3e0c2d256fe9f4f5f85cd3e4d8733a51d7b4a984/tinygrad/tensor.py#L653)
 impl<S: Shape> GraphTensor<S> {
+    #[track_caller]
     pub fn less_than(mut self, mut rhs: GraphTensor<S>) -> GraphTensor<S> {
         resolve_local_dyn_dims(&mut self.shape, &mut rhs.shape, false);
         let new_id = self
@@ -241,61 +397,110 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
     }
 
+    #[track_caller]
     pub fn greater_than(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         rhs.less_than(self)
     }
 
+    #[track_caller]
     pub fn less_than_equal(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -self.greater_than(rhs) + 1.0
     }
 
+    #[track_caller]
     pub fn greater_than_equal(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -self.less_than(rhs) + 1.0
     }
 
+    #[track_caller]
     pub fn not_equals(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         self.less_than(rhs) + self.greater_than(rhs)
     }
 
+    #[track_caller]
     pub fn equals(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -self.not_equals(rhs) + 1.0
     }
 
-    /// Raise the tensor to a power
-    pub fn pow<T>(self, e: T) -> GraphTensor<S>
-    where
-        Self: Mul<T, Output = Self>,
-    {
+    /// Keep the elements where `mask` is nonzero, and zero out the rest. Unlike multiplying by the mask, infinities and
+    /// NaNs that get masked out become zeros.
+    #[track_caller]
+    pub fn select(mut self, mut mask: GraphTensor<S>) -> GraphTensor<S> {
+        resolve_local_dyn_dims(&mut self.shape, &mut mask.shape, false);
+        let new_id = self
+            .graph()
+            .add_op(op::Select)
+            .input(self.id, 0, self.shape)
+            .input(mask.id, 0, mask.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
+    /// Raise the tensor to a power. Small integer powers are exact (and keep the sign of negative bases), other powers go through exp and ln.
+    #[track_caller]
+    pub fn pow(self, e: impl BinaryRhs<S>) -> GraphTensor<S> {
+        if let Some(n) = e.as_f32().filter(|n| n.fract() == 0. && n.abs() <= 16.) {
+            // Square and multiply
+            let (mut result, mut base, mut k) = (None, self, n.abs() as u32);
+            while k > 0 {
+                if k & 1 == 1 {
+                    result = Some(result.map(|r| r * base).unwrap_or(base));
+                }
+                k >>= 1;
+                if k > 0 {
+                    base = base * base;
+                }
+            }
+            let result = result.unwrap_or_else(|| self.graph().constant(1.).expand_to(self.shape));
+            return if n < 0. { result.recip() } else { result };
+        }
         // Approximate, see full impl here: https://github.com/tinygrad/tinygrad/blob/a32c67760140dd26b60d7932268f2e62e96a66e0/tinygrad/tensor.py#L568
-        self.abs().ln().mul(e).exp()
+        self.abs().ln().mul(e.to_tensor(self)).exp()
     }
 }
 
 // Clipping ops (min, max, clip)
 impl<S: Shape> GraphTensor<S> {
     /// Take the elementwise maximum of two tensors
+    #[track_caller]
     pub fn max(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         (self.less_than(rhs) * rhs) + (rhs.less_than_equal(self) * self)
     }
 
     /// Take the elementwise maximum of a tensor and a float
+    #[track_caller]
     pub fn max_f32(self, rhs: f32) -> GraphTensor<S> {
-        self.max(self.graph().constant(rhs).expand_to(self.shape))
+        self.maximum(rhs)
+    }
+
+    /// Take the elementwise maximum with another tensor or a scalar
+    #[track_caller]
+    pub fn maximum(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self.max(rhs.to_tensor(self))
+    }
+
+    /// Take the elementwise minimum with another tensor or a scalar
+    #[track_caller]
+    pub fn minimum(self, rhs: impl BinaryRhs<S>) -> GraphTensor<S> {
+        self.min(rhs.to_tensor(self))
     }
 
     /// Take the elementwise minimum of two tensors
+    #[track_caller]
     pub fn min(self, rhs: GraphTensor<S>) -> GraphTensor<S> {
         -(-self).max(-rhs)
     }
 
     /// Take the elementwise minimum of a tensor and a float
+    #[track_caller]
     pub fn min_f32(self, rhs: f32) -> GraphTensor<S> {
         -(-self).max_f32(-rhs)
     }
 
     /// Clip a tensor in a range
+    #[track_caller]
     pub fn clip(self, min: f32, max: f32) -> GraphTensor<S> {
-        self.min_f32(min).max_f32(max)
+        self.clamp(min, max)
     }
 }
 
@@ -304,6 +509,7 @@ pub trait F32Pow {
 }
 
 impl F32Pow for f32 {
+    #[track_caller]
     fn pow<S: Shape>(self, e: GraphTensor<S>) -> GraphTensor<S> {
         e.mul(self.abs().ln()).exp().recip()
     }
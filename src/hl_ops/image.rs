@@ -0,0 +1,116 @@
+use crate::prelude::*;
+
+impl<S: Shape> GraphTensor<S> {
+    /// Normalize each channel of images laid out as [..., C, H, W] to `(x - mean[c]) / std[c]`, the way vision models
+    /// expect their inputs. This is a multiply and an add by per-channel constants, which stay in the graph for the
+    /// compilers to fuse with the ops around them.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R3<2, 1, 2>>().set([[[0., 1.]], [[0.5, 1.5]]]);
+    /// let b = a.normalize_channels(&[0.5, 1.], &[0.5, 0.25]).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![-1., 1., -2., 2.]);
+    /// ```
+    #[track_caller]
+    pub fn normalize_channels(self, mean: &[f32], std: &[f32]) -> GraphTensor<S> {
+        assert_eq!(
+            mean.len(),
+            std.len(),
+            "Need a mean and a standard deviation for each channel"
+        );
+        let dims = self.shape.shape();
+        assert!(
+            dims.len() >= 3,
+            "Images need to be laid out as [..., C, H, W]"
+        );
+        if let Some(channels) = dims[dims.len() - 3].to_usize() {
+            assert_eq!(
+                channels,
+                mean.len(),
+                "Got normalization for {} channels, but the images have {channels}",
+                mean.len()
+            );
+        }
+        let scale = self
+            .graph()
+            .dyn_tensor("Channel Scale", &[mean.len(), 1, 1])
+            .set(std.iter().map(|s| 1. / s).collect());
+        let shift = self
+            .graph()
+            .dyn_tensor("Channel Shift", &[mean.len(), 1, 1])
+            .set(mean.iter().zip(std).map(|(m, s)| -m / s).collect());
+        (self.into_dyn() * scale + shift).typed()
+    }
+
+    /// View images laid out as [..., H, W, C] (how they're decoded) as [..., C, H, W] (how most models take them)
+    #[track_caller]
+    pub fn nhwc_to_nchw<Dst: Shape>(self) -> GraphTensor<Dst> {
+        self.permute_last_three([2, 0, 1])
+    }
+
+    /// View images laid out as [..., C, H, W] as [..., H, W, C]
+    #[track_caller]
+    pub fn nchw_to_nhwc<Dst: Shape>(self) -> GraphTensor<Dst> {
+        self.permute_last_three([1, 2, 0])
+    }
+
+    #[track_caller]
+    fn permute_last_three<Dst: Shape>(self, order: [usize; 3]) -> GraphTensor<Dst> {
+        let rank = self.shape.len();
+        assert!(
+            rank >= 3,
+            "Images need height, width and channel dimensions"
+        );
+        let axes = (0..rank - 3)
+            .chain(order.map(|i| rank - 3 + i))
+            .collect::<Vec<_>>();
+        self.into_dyn().permute(&axes).typed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_image_preprocessing() {
+        let mut cx = Graph::new();
+        // A batch of two 2x2 RGB images as decoded, with pixels from 0 to 255
+        let pixels = (0..24).map(|i| (i * 11 % 256) as f32).collect::<Vec<_>>();
+        let images = cx.tensor::<(Dyn<'b'>, LConst<2>, LConst<2>, LConst<3>)>();
+        images.set_dyn(pixels.clone(), &[2, 2, 2, 3]);
+        let (mean, std) = ([0.485, 0.456, 0.406], [0.229, 0.224, 0.225]);
+        let nchw = images.nhwc_to_nchw::<(Dyn<'b'>, LConst<3>, LConst<2>, LConst<2>)>();
+        let inputs = (nchw * (1. / 255.))
+            .interpolate_bilinear::<(Dyn<'b'>, LConst<3>, LConst<4>, LConst<4>)>(4, 4)
+            .normalize_channels(&mean, &std)
+            .retrieve();
+        let round_trip = nchw
+            .nchw_to_nhwc::<(Dyn<'b'>, LConst<2>, LConst<2>, LConst<3>)>()
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&round_trip.data(), &pixels);
+        // Bilinear upsampling by 2 along each dimension blends neighbors 3:1
+        let lerp = |a: f32, b: f32, x: usize| match x {
+            0 => a,
+            1 => 0.75 * a + 0.25 * b,
+            2 => 0.25 * a + 0.75 * b,
+            _ => b,
+        };
+        let mut expected = vec![];
+        for image in pixels.chunks(12) {
+            for c in 0..3 {
+                let p = |y: usize, x: usize| image[(y * 2 + x) * 3 + c] / 255.;
+                for y in 0..4 {
+                    for x in 0..4 {
+                        let value = lerp(lerp(p(0, 0), p(0, 1), x), lerp(p(1, 0), p(1, 1), x), y);
+                        expected.push((value - mean[c]) / std[c]);
+                    }
+                }
+            }
+        }
+        assert_close(&inputs.data(), &expected);
+    }
+}
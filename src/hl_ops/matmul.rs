@@ -1,5 +1,16 @@
 use crate::prelude::*;
 
+/// Matrix multiplication, broadcasting any leading batch dimensions.
+///
+/// The inner dimensions are part of the tensors' types, so multiplying tensors with different static inner sizes fails to
+/// compile instead of failing when the kernel runs:
+/// ```compile_fail
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R2<2, 3>>();
+/// let b = cx.tensor::<R2<4, 5>>();
+/// let c = a.matmul(b);
+/// ```
 #[diagnostic::on_unimplemented(
     message = "`{Self}` and `GraphTensor<{S}>` cannot be matrix multiplied! The last dimension of the left hand tensor and the second to last dimension of the right hand tensor must match.",
     label = "Left hand tensor: `{Self}`"
@@ -12,6 +23,7 @@ pub trait Matmul<S: Shape> {
 // ABxBC -> AC
 impl<A: Dimension, B: Dimension, C: Dimension> Matmul<(B, C)> for GraphTensor<(A, B)> {
     type Output = GraphTensor<(A, C)>;
+    #[track_caller]
     fn matmul(self, rhs: GraphTensor<(B, C)>) -> Self::Output {
         // Broadcasted Multiply
         let mul = self.expand::<(A, C, B), _>()
@@ -25,6 +37,7 @@ impl<A: Dimension, B: Dimension, C: Dimension> Matmul<(B, C)> for GraphTensor<(A
 // AxAB -> B
 impl<A: Dimension, B: Dimension> Matmul<(A, B)> for GraphTensor<(A,)> {
     type Output = GraphTensor<(B,)>;
+    #[track_caller]
     fn matmul(self, rhs: GraphTensor<(A, B)>) -> Self::Output {
         let s: GraphTensor<(Const<1>, A)> = self.expand();
 
@@ -41,6 +54,7 @@ impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension> Matmul<(C, D)>
     for GraphTensor<(A, B, C)>
 {
     type Output = GraphTensor<(A, B, D)>;
+    #[track_caller]
     fn matmul(self, rhs: GraphTensor<(C, D)>) -> Self::Output {
         // Reshape
         let w: GraphTensor<(D, C)> = rhs.permute::<_, Axes2<1, 0>>();
@@ -58,6 +72,7 @@ impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension> Matmul<(A, C, D)>
     for GraphTensor<(A, B, C)>
 {
     type Output = GraphTensor<(A, B, D)>;
+    #[track_caller]
     fn matmul(self, rhs: GraphTensor<(A, C, D)>) -> Self::Output {
         // Reshape
         let w: GraphTensor<(A, D, C)> = rhs.permute::<_, Axes3<0, 2, 1>>();
@@ -75,6 +90,7 @@ impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension, E: Dimension> Matmu
     for GraphTensor<(A, B, C, D)>
 {
     type Output = GraphTensor<(A, B, C, E)>;
+    #[track_caller]
     fn matmul(self, rhs: GraphTensor<(A, B, D, E)>) -> Self::Output {
         // Reshape
         let w: GraphTensor<(A, B, E, D)> = rhs.permute::<_, Axes4<0, 1, 3, 2>>();
@@ -92,6 +108,7 @@ impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension, E: Dimension, F: Di
     Matmul<(A, B, C, E, F)> for GraphTensor<(A, B, C, D, E)>
 {
     type Output = GraphTensor<(A, B, C, D, F)>;
+    #[track_caller]
     fn matmul(self, rhs: GraphTensor<(A, B, C, E, F)>) -> Self::Output {
         // Reshape
         let w: GraphTensor<(A, B, C, F, E)> = rhs.permute::<_, Axes5<0, 1, 2, 4, 3>>();
@@ -106,6 +123,7 @@ impl<A: Dimension, B: Dimension, C: Dimension, D: Dimension, E: Dimension, F: Di
 
 impl<A: Dimension> GraphTensor<(A,)> {
     /// Simple dot product of two vectors
+    #[track_caller]
     pub fn dot(self, rhs: GraphTensor<(A,)>) -> GraphTensor<R0> {
         (self * rhs).sum_reduce()
     }
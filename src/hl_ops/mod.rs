@@ -1,8 +1,11 @@
 // The high level interface implemented on GraphTensor. All of these ops get translated to primitive ops.
 pub mod binary;
+pub mod image;
 pub mod matmul;
 pub use matmul::*;
 pub mod movement;
 pub mod other;
+pub mod pool;
 pub mod reduction;
 pub mod unary;
+pub mod upsample;
@@ -2,6 +2,7 @@ use crate::{op, prelude::*};
 
 impl<S: Shape> GraphTensor<S> {
     /// Swap dimensions of the tensor
+    #[track_caller]
     pub fn permute<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
     where
         S: PermuteShapeTo<Dst, Ax>,
@@ -12,6 +13,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Broadcast tensor along new dimensions
+    #[track_caller]
     pub fn expand<Dst: Shape, Ax: Axes>(mut self) -> GraphTensor<Dst>
     where
         S: BroadcastShapeTo<Dst, Ax>,
@@ -27,6 +29,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Broadcast tensor along new dimensions (with explicitly given dest shape)
+    #[track_caller]
     pub fn expand_to<Dst: Shape>(mut self, shape: ShapeTracker) -> GraphTensor<Dst> {
         for (i, s) in shape.indexes.iter().map(|i| shape.dims[*i]).enumerate() {
             if self.shape.len() <= i || self.shape.dims[self.shape.indexes[i]] != s {
@@ -37,7 +40,65 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    /// Broadcast to `Dst` following numpy's rules: new dimensions are added on the left, and dimensions of size 1 are
+    /// stretched out to match.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<3, 1>>();
+    /// let b = a.broadcast_to::<R3<2, 3, 4>>();
+    /// ```
+    /// Sizes that don't line up fail to compile:
+    /// ```compile_fail
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<3, 2>>();
+    /// let b = a.broadcast_to::<R3<2, 3, 4>>();
+    /// ```
+    #[track_caller]
+    pub fn broadcast_to<Dst: Shape>(self) -> GraphTensor<Dst>
+    where
+        S: BroadcastableTo<Dst>,
+    {
+        #[allow(clippy::let_unit_value)]
+        let _ = <S as BroadcastableTo<Dst>>::TYPE_CHECK;
+        let (src, dst) = (self.dims(), Dst::realized_shape());
+        let offset = dst.len() - src.len();
+        // Dimensions of size 1 that need stretching get swapped out for broadcasted ones
+        let stretched = src
+            .iter()
+            .zip(&dst[offset..])
+            .enumerate()
+            .filter(|(_, (s, d))| {
+                if let (Some(s), Some(d)) = (s.to_usize(), d.to_usize()) {
+                    assert!(
+                        s == 1 || s == d,
+                        "Can't broadcast a dimension of size {s} to {d}"
+                    );
+                }
+                s.to_usize() == Some(1) && d.to_usize() != Some(1)
+            })
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut t = self;
+        // Removing a real dimension only keeps the right indexes once the data is contiguous
+        if stretched.iter().any(|i| !t.shape.fake[t.shape.indexes[*i]]) {
+            t = t.contiguous();
+        }
+        for i in stretched.iter().rev() {
+            t.shape.remove_dim(*i);
+        }
+        for i in stretched {
+            t.shape.expand(i, dst[offset + i]);
+        }
+        for (i, d) in dst[..offset].iter().enumerate() {
+            t.shape.expand(i, *d);
+        }
+        GraphTensor::from_id(t.id, t.shape, t.graph_ref)
+    }
+
     /// Convert tensor to a new shape with an equivalent number of elements
+    #[track_caller]
     pub fn reshape<N: Shape>(mut self) -> GraphTensor<N> {
         // Insert contiguous call
         self = self.contiguous();
@@ -49,6 +110,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Dynamically reshape with annotations for the shape tracker
+    #[track_caller]
     pub fn dyn_reshape<N: Shape, T>(mut self, shape: &[T]) -> GraphTensor<N>
     where
         for<'a> Expression: From<&'a T>,
@@ -65,6 +127,84 @@ impl<S: Shape> GraphTensor<S> {
         )
     }
 
+    /// Merge the adjacent axes `Ax` into a single dimension
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R3<2, 3, 4>>();
+    /// let b = a.merge_dims::<R2<6, 4>, Axes2<0, 1>>();
+    /// ```
+    /// Static sizes that don't multiply out fail to compile:
+    /// ```compile_fail
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R3<2, 3, 4>>();
+    /// let b = a.merge_dims::<R2<5, 4>, Axes2<0, 1>>();
+    /// ```
+    #[track_caller]
+    pub fn merge_dims<Dst: Shape, Ax: Axes<Array = [usize; 2]>>(self) -> GraphTensor<Dst>
+    where
+        S: MergeDimsTo<Dst, Ax>,
+    {
+        #[allow(clippy::let_unit_value)]
+        let _ = <S as MergeDimsTo<Dst, Ax>>::TYPE_CHECK;
+        let [a, b] = Ax::as_array();
+        let mut dims = self.dims();
+        let merged = dims[a] * dims[b];
+        dims.splice(a..=b, [merged]);
+        self.reshape_dims(&dims)
+    }
+
+    /// Split the axis `Ax` into the two dimensions that take its place in `Dst`. One of them can be `Dyn<'-'>`, in which case
+    /// it's worked out from the other.
+    #[track_caller]
+    pub fn split_dim<Dst: Shape, Ax: Axes<Array = [usize; 1]>>(self) -> GraphTensor<Dst>
+    where
+        S: SplitDimTo<Dst, Ax>,
+    {
+        #[allow(clippy::let_unit_value)]
+        let _ = <S as SplitDimTo<Dst, Ax>>::TYPE_CHECK;
+        let [a] = Ax::as_array();
+        let mut dims = self.dims();
+        let new_dims = Dst::realized_shape();
+        let (mut x, mut y) = (new_dims[a], new_dims[a + 1]);
+        if x.to_symbols().contains(&'-') {
+            x = dims[a] / y;
+        } else if y.to_symbols().contains(&'-') {
+            y = dims[a] / x;
+        }
+        dims.splice(a..=a, [x, y]);
+        self.reshape_dims(&dims)
+    }
+
+    /// Flatten every dimension into one
+    #[track_caller]
+    pub fn flatten<D: Dimension>(self) -> GraphTensor<(D,)>
+    where
+        S: FlattenTo<D>,
+    {
+        #[allow(clippy::let_unit_value)]
+        let _ = <S as FlattenTo<D>>::TYPE_CHECK;
+        let n_elements = self
+            .dims()
+            .into_iter()
+            .fold(Expression::from(1), |a, b| a * b);
+        self.reshape_dims(&[n_elements])
+    }
+
+    /// Current size of each dimension
+    fn dims(&self) -> Vec<Expression> {
+        self.shape.shape().into_iter().map(|d| d.small()).collect()
+    }
+
+    /// Lay the data out contiguously and view it with new dimensions
+    #[track_caller]
+    fn reshape_dims<Dst: Shape>(self, dims: &[Expression]) -> GraphTensor<Dst> {
+        let t = self.contiguous();
+        GraphTensor::from_id(t.id, ShapeTracker::new(dims), t.graph_ref)
+    }
+
+    #[track_caller]
     pub fn realize<Dst: Shape<Concrete = <<S as HasShape>::Shape as Shape>::Concrete>>(
         self,
     ) -> GraphTensor<Dst>
@@ -74,6 +214,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn sync_shape(self) -> Self {
         GraphTensor::from_id(
             self.id,
@@ -82,6 +223,7 @@ impl<S: Shape> GraphTensor<S> {
         )
     }
 
+    #[track_caller]
     pub fn contiguous(self) -> GraphTensor<S> {
         if !self.shape.is_reshaped() {
             return self;
@@ -95,6 +237,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Take a slice of the original tensor. Any dimension with bounds becomes a dynamic dimension
+    #[track_caller]
     pub fn slice<Slice: SliceOfShape<S>>(
         mut self,
         slice: Slice,
@@ -113,6 +256,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Cut out 'size' elements every 'spacing' elements in the last dimension. 'size' must be smaller than the last dimension
+    #[track_caller]
     pub fn excise<Dst: Shape>(mut self, spacing: usize, size: usize) -> GraphTensor<Dst> {
         let n_dims = self.shape.len();
         // Pad out to a multiple of spacing + size
@@ -140,6 +284,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Pool elements along the last dimension, pools are exposed as a new dimension
+    #[track_caller]
     pub fn pool_last_dim<Dst: Shape>(
         mut self,
         kernel: impl Into<BigExpression>,
@@ -185,6 +330,7 @@ impl<S: Shape> GraphTensor<S> {
         }
     }
 
+    #[track_caller]
     pub fn pad<Dst: Shape>(mut self, padding: impl PadOfShape<S>) -> GraphTensor<Dst> {
         let padding = padding.to_pad_vec();
         // This exists because currently padding and slicing on the same dimension (even on opposite sides) is unsupported
@@ -199,6 +345,7 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(self.id, self.shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn concat_along<Dst: Shape, Ax: Axes<Array = [usize; 1]>, Rhs: Shape>(
         self,
         rhs: GraphTensor<Rhs>,
@@ -514,4 +661,62 @@ mod tests {
 
         assert_close(&c.data(), &d_c.as_vec());
     }
+
+    #[test]
+    fn test_merge_split_flatten() {
+        let mut cx = Graph::new();
+        let data = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+        let a = cx.tensor::<R3<2, 3, 2>>().set(data.clone());
+        let merged = a.merge_dims::<R2<6, 2>, LAxes2<0, 1>>().retrieve();
+        // Merging a permuted view goes through its logical layout
+        let merged_t = a
+            .permute::<R3<2, 2, 3>, LAxes3<0, 2, 1>>()
+            .merge_dims::<R2<2, 6>, LAxes2<1, 2>>()
+            .retrieve();
+        let split = merged
+            .split_dim::<(LConst<3>, Dyn<'-'>, LConst<2>), LAxis<0>>()
+            .retrieve();
+        let flat = a.flatten::<LConst<12>>().retrieve();
+        // Dynamic dimensions get merged symbolically
+        let b = cx
+            .tensor::<(Dyn<'s'>, LConst<2>)>()
+            .set_dyn(vec![1., 2., 3., 4., 5., 6.], &[3, 2]);
+        let b_flat = b.flatten::<Dyn<'-'>>().retrieve();
+        cx.execute();
+
+        assert_exact(&merged.data(), &data);
+        assert_exact(
+            &merged_t.data(),
+            &[0., 2., 4., 1., 3., 5., 6., 8., 10., 7., 9., 11.],
+        );
+        assert_eq!(split.shape.shape_usize(), vec![3, 2, 2]);
+        assert_exact(&split.data(), &data);
+        assert_exact(&flat.data(), &data);
+        assert_eq!(b_flat.shape.n_elements().to_usize(), None);
+        assert_exact(&b_flat.data(), &[1., 2., 3., 4., 5., 6.]);
+    }
+
+    #[test]
+    fn test_broadcast_to() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 1>>().set([[1.], [2.], [3.]]);
+        let b = a.broadcast_to::<R3<2, 3, 2>>().retrieve();
+        // A size 1 dimension moved by a permute
+        let c = cx
+            .tensor::<R2<1, 3>>()
+            .set([[1., 2., 3.]])
+            .permute::<R2<3, 1>, _>()
+            .broadcast_to::<R2<3, 2>>()
+            .retrieve();
+        let d = cx
+            .tensor::<(Dyn<'s'>, LConst<1>)>()
+            .set_dyn(vec![4., 5.], &[2, 1])
+            .broadcast_to::<(Dyn<'s'>, LConst<3>)>()
+            .retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 1., 2., 2., 3., 3., 1., 1., 2., 2., 3., 3.]);
+        assert_exact(&c.data(), &[1., 1., 2., 2., 3., 3.]);
+        assert_exact(&d.data(), &[4., 4., 4., 5., 5., 5.]);
+    }
 }
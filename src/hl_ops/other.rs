@@ -10,6 +10,7 @@ use crate::{
 
 impl<S: Shape> GraphTensor<S> {
     /// Cumulative sum last dimension
+    #[track_caller]
     pub fn cumsum_last_dim(mut self) -> Self {
         let axis = self.shape.len() - 1;
         if !self.shape.is_contiguous() {
@@ -33,9 +34,74 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Cumulative product last dimension
+    #[track_caller]
     pub fn cumprod_last_dim(self) -> Self {
         self.ln().cumsum_last_dim().exp()
     }
+
+    /// Cumulative sum along axes, using a dedicated scan op. Unlike `cumsum_last_dim`, this doesn't need to
+    /// pool out a copy of the input for every position, so it stays linear in the length of the axis.
+    #[track_caller]
+    pub fn cumsum<Ax: Axes>(self) -> Self
+    where
+        S: HasAxes<Ax>,
+    {
+        let mut shape = self.shape;
+        let mut id = self.id;
+        for dim in Ax::as_array() {
+            id = self
+                .graph()
+                .add_op(op::CumSum(dim))
+                .input(id, 0, shape)
+                .finish();
+            shape = shape.contiguous();
+        }
+        GraphTensor::from_id(id, shape, self.graph_ref)
+    }
+
+    /// Add slices of `src` into this tensor at `indexes` along `Ax`, summing wherever indexes repeat. `src` has this tensor's
+    /// shape, except along `Ax` where it has one slice per index. Indexes outside of this tensor, or that aren't whole numbers,
+    /// are skipped.
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.Tensor.index_add_
+    #[track_caller]
+    pub fn index_add<Ax: Axes<Array = [usize; 1]>, B: Dimension, Src: Shape>(
+        self,
+        indexes: GraphTensor<(B,)>,
+        src: GraphTensor<Src>,
+    ) -> Self
+    where
+        S: HasAxes<Ax>,
+    {
+        let axis = Ax::as_array()[0];
+        let (dims, src_dims) = (self.shape.shape(), src.shape.shape());
+        assert_eq!(
+            src_dims.len(),
+            dims.len(),
+            "index_add source needs the same number of dimensions as the destination"
+        );
+        for (i, (dim, src_dim)) in dims.iter().zip(&src_dims).enumerate() {
+            let expected = if i == axis {
+                indexes.shape.shape()[0].clone()
+            } else {
+                dim.clone()
+            };
+            if let (Some(a), Some(b)) = (src_dim.to_usize(), expected.to_usize()) {
+                assert_eq!(
+                    a, b,
+                    "index_add source has {a} elements along dimension {i}, but needs {b}"
+                );
+            }
+        }
+        let new_id = self
+            .graph()
+            .add_op(op::IndexAdd(axis))
+            .input(self.id, 0, self.shape)
+            .input(indexes.id, 0, indexes.shape)
+            .input(src.id, 0, src.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
 }
 
 impl From<f32> for ConstantValue {
@@ -71,40 +137,52 @@ impl From<&Expression> for ConstantValue {
 
 impl Graph {
     /// A scalar constant
+    #[track_caller]
     pub fn constant(&mut self, i: impl Into<ConstantValue>) -> GraphTensor<R0> {
         GraphTensor::from_id(
-            self.add_op(Constant(i.into(), &self.dyn_map)).finish(),
+            self.add_op(Constant(i.into())).finish(),
             ShapeTracker::new(&[]),
             self,
         )
     }
 
     /// A scalar constant evaluated from an expression at runtime
+    #[track_caller]
     pub fn constant_expr<E: Into<BigExpression>>(&mut self, expr: E) -> GraphTensor<R0> {
         GraphTensor::from_id(
-            self.add_op(Constant(
-                ConstantValue::Expression(expr.into().simplify()),
-                &self.dyn_map,
-            ))
-            .finish(),
+            self.add_op(Constant(ConstantValue::Expression(expr.into().simplify())))
+                .finish(),
             ShapeTracker::new(&[]),
             self,
         )
     }
 
     /// ARange from 0 to N
+    #[track_caller]
     pub fn arange<N: Dimension>(&mut self) -> GraphTensor<(N,)> {
-        if N::size().to_usize().map(|i| i == 1).unwrap_or_default() {
+        let arange = self.arange_expr(N::size());
+        GraphTensor::from_id(arange.id, arange.shape, self)
+    }
+
+    /// ARange from 0 to a size given as an expression, like the length of a tensor's dimension
+    #[track_caller]
+    pub fn arange_expr<E: Into<Expression>>(&mut self, size: E) -> GraphTensor<(Dyn<'-'>,)> {
+        let size = size.into();
+        if size.to_usize().map(|i| i == 1).unwrap_or_default() {
             // Single number ARange is just 0
-            self.constant(0.).expand()
+            self.constant(0.).expand_to(ShapeTracker::new(&[size]))
         } else {
-            self.constant(1.).expand().cumsum_last_dim() - 1.
+            self.constant(1.)
+                .expand_to::<(Dyn<'-'>,)>(ShapeTracker::new(&[size]))
+                .cumsum_last_dim()
+                - 1.
         }
     }
 
     /// Lower left-hand triangle of 1s. Currently required to be square
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.tril
+    #[track_caller]
     pub fn tril<S: Dimension>(&mut self, diagonal: i32) -> GraphTensor<(S, S)> {
         let horizontal = self.arange::<S>().expand::<(S, S), Axis<0>>();
         let vertical = self.arange::<S>().expand::<(S, S), Axis<1>>();
@@ -115,6 +193,7 @@ impl Graph {
     /// Upper right-hand triangle of 1s
     ///
     /// Same API as https://pytorch.org/docs/stable/generated/torch.triu
+    #[track_caller]
     pub fn triu<S: Dimension>(&mut self, diagonal: i32) -> GraphTensor<(S, S)> {
         let horizontal = self.arange::<S>().expand::<(S, S), Axis<0>>();
         let vertical = self.arange::<S>().expand::<(S, S), Axis<1>>();
@@ -124,19 +203,156 @@ impl Graph {
 }
 
 impl<S: Dimension, const DIM: usize> GraphTensor<(S, Const<DIM>)> {
-    /// Gather a batch of vectors from a matrix
+    /// Gather a batch of vectors from a matrix. The number of rows and indexes are read from the shapes rather than `S`
+    /// and `B`, so matrices and indexes that were reshaped to dynamic sizes work too.
+    #[track_caller]
     pub fn gather<B: Dimension>(self, indexes: GraphTensor<(B,)>) -> GraphTensor<(B, Const<DIM>)> {
-        let one_hot = indexes
-            .graph()
-            .arange::<S>()
-            .expand::<(B, S), _>()
-            .equals(indexes.expand());
-        (one_hot.expand::<(B, S, Const<DIM>), _>() * self.expand()).sum_reduce::<_, Axis<1>>()
+        let (rows, batch) = (
+            self.shape.shape()[0].small(),
+            indexes.shape.shape()[0].small(),
+        );
+        let positions = self.graph().arange_expr(rows);
+        let (mut position_shape, mut index_shape) = (positions.shape, indexes.shape);
+        position_shape.expand(0, batch);
+        index_shape.expand(1, rows);
+        let one_hot =
+            GraphTensor::<(B, S)>::from_id(positions.id, position_shape, self.graph_ref).equals(
+                GraphTensor::from_id(indexes.id, index_shape, self.graph_ref),
+            );
+        let (mut one_hot_shape, mut matrix_shape) = (one_hot.shape, self.shape);
+        one_hot_shape.expand(2, DIM);
+        matrix_shape.expand(0, batch);
+        (GraphTensor::<(B, S, Const<DIM>)>::from_id(one_hot.id, one_hot_shape, self.graph_ref)
+            * GraphTensor::from_id(self.id, matrix_shape, self.graph_ref))
+        .sum_reduce::<_, Axis<1>>()
+    }
+
+    /// Overwrite rows of a matrix with a batch of vectors, the reverse of `gather`. Indexes outside of the matrix are
+    /// skipped, and each index should only show up once.
+    ///
+    /// The rows are written with `index_add` rather than a one-hot matmul, so it costs a pass over the matrix instead of
+    /// a multiply per row and index.
+    #[track_caller]
+    pub fn scatter<B: Dimension>(
+        self,
+        indexes: GraphTensor<(B,)>,
+        rows: GraphTensor<(B, Const<DIM>)>,
+    ) -> GraphTensor<(S, Const<DIM>)> {
+        // Sized from the shapes, like gather
+        let (n_rows, batch) = (
+            self.shape.shape()[0].small(),
+            indexes.shape.shape()[0].small(),
+        );
+        let cx = self.graph();
+        let (zeros, ones) = (cx.constant(0.), cx.constant(1.));
+        let (mut zeros_shape, mut ones_shape) = (zeros.shape, ones.shape);
+        zeros_shape.expand(0, n_rows);
+        ones_shape.expand(0, batch);
+        let written = GraphTensor::<(S,)>::from_id(zeros.id, zeros_shape, self.graph_ref)
+            .index_add::<Axis<0>, _, _>(
+                indexes,
+                GraphTensor::<(B,)>::from_id(ones.id, ones_shape, self.graph_ref),
+            );
+        (self * (1. - written).expand()).index_add::<Axis<0>, _, _>(indexes, rows)
+    }
+}
+
+/// A matrix with sizes only known at runtime
+type MatrixTensor = GraphTensor<(Dyn<'-'>, Dyn<'-'>)>;
+
+impl<S: Shape> GraphTensor<S> {
+    /// Zero out everything above the `diagonal`th diagonal of the last two dimensions
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.tril
+    #[track_caller]
+    pub fn tril(self, diagonal: i32) -> GraphTensor<S> {
+        let (row, col) = self.matrix_positions();
+        self.select_matrix((col - (diagonal as f32 + 1.)).less_than(row))
+    }
+
+    /// Zero out everything below the `diagonal`th diagonal of the last two dimensions
+    ///
+    /// Same API as https://pytorch.org/docs/stable/generated/torch.triu
+    #[track_caller]
+    pub fn triu(self, diagonal: i32) -> GraphTensor<S> {
+        let (row, col) = self.matrix_positions();
+        self.select_matrix((col - (diagonal as f32 - 1.)).greater_than(row))
+    }
+
+    /// Take the main diagonal of the last two dimensions, which must be square
+    #[track_caller]
+    pub fn diag(self) -> GraphTensor<<S as ReduceShape<S::LastAxis>>::Reduced>
+    where
+        S: ReduceShape<S::LastAxis>,
+    {
+        let (row, col) = self.matrix_positions();
+        self.select_matrix(row.equals(col)).sum_reduce()
+    }
+
+    /// Turn a padding mask, with 1s for tokens to attend to and 0s for padding, into an additive attention bias. Tokens
+    /// get 0 and padding gets the lowest float, so adding it to the attention scores before the softmax ignores padding.
+    #[track_caller]
+    pub fn attention_bias(self) -> GraphTensor<S> {
+        (self - 1.) * f32::MAX
+    }
+
+    /// Row and column index of every element of the matrices in the last two dimensions
+    #[track_caller]
+    fn matrix_positions(self) -> (MatrixTensor, MatrixTensor) {
+        let dims = self.shape.shape();
+        let n = dims.len();
+        assert!(n >= 2, "Need at least two dimensions to have a matrix");
+        let (rows, cols) = (dims[n - 2].small(), dims[n - 1].small());
+        let (row, col) = (
+            self.graph().arange_expr(rows),
+            self.graph().arange_expr(cols),
+        );
+        let (mut row_shape, mut col_shape) = (row.shape, col.shape);
+        row_shape.expand(1, cols);
+        col_shape.expand(0, rows);
+        (
+            GraphTensor::from_id(row.id, row_shape, self.graph_ref),
+            GraphTensor::from_id(col.id, col_shape, self.graph_ref),
+        )
+    }
+
+    /// Keep the elements of the matrices in the last two dimensions where `mask` is 1, and zero out the rest
+    #[track_caller]
+    fn select_matrix(self, mask: MatrixTensor) -> GraphTensor<S> {
+        let mut mask_shape = mask.shape;
+        let dims = self.shape.shape();
+        for (i, dim) in dims[..dims.len() - 2].iter().enumerate() {
+            mask_shape.expand(i, dim.small());
+        }
+        self.select(GraphTensor::from_id(mask.id, mask_shape, self.graph_ref))
+    }
+}
+
+impl<A: Dimension> GraphTensor<(A,)> {
+    /// Turn class indexes into one-hot vectors of length `C`. Indexes outside of `C` give all zeros.
+    #[track_caller]
+    pub fn one_hot<C: Dimension>(self) -> GraphTensor<(A, C)> {
+        self.graph()
+            .arange::<C>()
+            .expand::<(A, C), _>()
+            .equals(self.expand())
+    }
+}
+
+impl<A: Dimension, B: Dimension> GraphTensor<(A, B)> {
+    /// Turn a batch of class indexes into one-hot vectors of length `C`. Indexes outside of `C` give all zeros.
+    #[track_caller]
+    pub fn one_hot<C: Dimension>(self) -> GraphTensor<(A, B, C)> {
+        self.graph()
+            .arange::<C>()
+            .expand::<(A, B, C), _>()
+            .equals(self.expand())
     }
 }
 
 impl<S: Shape> GraphTensor<S> {
     /// Print the value of this tensor when the graph is ran
+    #[track_caller]
     pub fn print<T: ToString>(&self, message: T) -> Self {
         let message = message.to_string();
         let id = self
@@ -146,7 +362,7 @@ impl<S: Shape> GraphTensor<S> {
                 Box::new(move |inp| {
                     for (i, (tensor, tracker)) in inp.iter().enumerate() {
                         println!("{message} ({})", i + 1);
-                        let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                        let d = tensor.borrowed().as_f32_slice().unwrap();
                         println!(
                             "Elements: {} Start: {:?} Mid: {:?} End: {:?}",
                             d.len(),
@@ -166,8 +382,68 @@ impl<S: Shape> GraphTensor<S> {
         *self
     }
 
+    /// Log this tensor's shape, dtype, min, max, mean and NaN count when the graph is ran, if debugging is turned on with `Graph::set_debug`.
+    ///
+    /// The logging op only reads the tensor, which is returned as it is, so this can sit in the middle of a model without
+    /// copying anything and be compared between optimized and unoptimized runs.
+    #[track_caller]
+    pub fn debug<T: ToString>(self, name: T) -> Self {
+        let name = name.to_string();
+        let enabled = self.graph().debug.clone();
+        let id = self
+            .graph()
+            .add_op(op::Function(
+                format!("Debug {name}"),
+                Box::new(move |inp| {
+                    let (tensor, shape) = &inp[0];
+                    if enabled.load(std::sync::atomic::Ordering::Relaxed) {
+                        println!("{}", name.bold().bright_blue());
+                        println!("Shape: {:?}", shape.shape_usize());
+                        if let Some(d) = tensor.borrowed().as_f32_slice() {
+                            let (ind, val) = (shape.index_expression(), shape.valid_expression());
+                            let mut stack = vec![];
+                            let data = (0..shape.n_elements().to_usize().unwrap())
+                                .map(|i| {
+                                    if val.exec_single_var_stack(i, &mut stack) != 0 {
+                                        d[ind.exec_single_var_stack(i, &mut stack)]
+                                    } else {
+                                        0.
+                                    }
+                                })
+                                .collect::<Vec<_>>();
+                            let nans = data.iter().filter(|i| i.is_nan()).count();
+                            let finite = data.iter().filter(|i| !i.is_nan());
+                            println!(
+                                "DType: f32 Min: {} Max: {} Mean: {} NaNs: {}",
+                                finite.clone().copied().fold(f32::INFINITY, f32::min),
+                                finite.clone().copied().fold(f32::NEG_INFINITY, f32::max),
+                                finite.clone().sum::<f32>() / (data.len() - nans).max(1) as f32,
+                                if nans > 0 {
+                                    nans.to_string().bold().red()
+                                } else {
+                                    nans.to_string().normal()
+                                }
+                            );
+                        } else {
+                            println!("DType: {:?}", tensor.borrowed());
+                        }
+                    }
+                    vec![]
+                }),
+            ))
+            .input(self.id, 0, self.shape)
+            .finish();
+        self.graph().no_delete.insert(id);
+        self
+    }
+
     /// Check the tensor value against a binary file
-    pub fn diff(&self, file: impl Fn() -> Option<PathBuf> + 'static, threshold: f32) -> Self {
+    #[track_caller]
+    pub fn diff(
+        &self,
+        file: impl Fn() -> Option<PathBuf> + Send + 'static,
+        threshold: f32,
+    ) -> Self {
         let id = self
             .graph()
             .add_op(op::Function(
@@ -178,7 +454,7 @@ impl<S: Shape> GraphTensor<S> {
                     };
                     // Get tensor data and file data
                     let (tensor, shape) = inp.pop().unwrap();
-                    let d = tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap();
+                    let d = tensor.borrowed().as_f32_slice().unwrap();
                     let mut data = vec![0.; d.len()];
                     let (ind, val) = (shape.index_expression(), shape.valid_expression());
                     let mut stack = vec![];
@@ -341,6 +617,31 @@ impl<S: Shape> GraphTensor<S> {
 #[cfg(test)]
 mod tests {
     crate::test_imports!();
+    #[test]
+    fn test_debug() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 3>>()
+            .set([[1., 2., 3.], [4., 5., f32::NAN]]);
+        let transposed = a.permute::<_, LAxes2<1, 0>>();
+        // The tensor comes back as it was, rather than as a copy made by the debug op
+        assert_eq!(transposed.debug("Transposed").id, transposed.id);
+        let b = transposed.sum_reduce::<_, LAxis<1>>().retrieve();
+        cx.execute();
+        let d_dev = Cpu::default();
+        let d_b = d_dev
+            .tensor([[1., 2., 3.], [4., 5., f32::NAN]])
+            .permute::<_, dfdx::shapes::Axes2<1, 0>>()
+            .sum::<_, dfdx::shapes::Axis<1>>();
+        assert_exact(&b.data()[..2], &d_b.as_vec()[..2]);
+
+        b.drop();
+        cx.set_debug(true);
+        cx.execute();
+        assert_exact(&b.data()[..2], &d_b.as_vec()[..2]);
+        assert!(b.data()[2].is_nan());
+    }
+
     #[test]
     fn test_arange() {
         let mut cx = Graph::new();
@@ -429,4 +730,164 @@ mod tests {
                 .collect::<Vec<_>>(),
         );
     }
+
+    #[test]
+    fn test_cumsum() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+        let last = a.cumsum::<LAxis<1>>().retrieve();
+        let first = a.cumsum::<LAxis<0>>().retrieve();
+        let both = a.cumsum::<LAxes2<0, 1>>().retrieve();
+        let permuted = a.permute::<R2<3, 2>, _>().cumsum::<LAxis<1>>().retrieve();
+        let pooled = a.cumsum_last_dim().retrieve();
+        cx.execute();
+
+        assert_exact(&last.data(), &[1., 3., 6., 4., 9., 15.]);
+        assert_exact(&last.data(), &pooled.data());
+        assert_exact(&first.data(), &[1., 2., 3., 5., 7., 9.]);
+        assert_exact(&both.data(), &[1., 3., 6., 5., 12., 21.]);
+        assert_exact(&permuted.data(), &[1., 5., 2., 7., 3., 9.]);
+    }
+
+    #[test]
+    fn test_index_add() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 2>>().set([[1., 2.], [3., 4.], [5., 6.]]);
+        let rows = cx.tensor::<R1<6>>().set([2., 0., 2., 7., f32::NAN, 1.5]);
+        let src = cx.tensor::<R2<6, 2>>().set([
+            [10., 20.],
+            [30., 40.],
+            [50., 60.],
+            [70., 80.],
+            [90., 100.],
+            [110., 120.],
+        ]);
+        let added_rows = a.index_add::<LAxis<0>, _, _>(rows, src).retrieve();
+        let cols = cx.tensor::<R1<2>>().set([1., 1.]);
+        // A permuted source is read through its view
+        let col_src = cx
+            .tensor::<R2<2, 3>>()
+            .set([[10., 30., 50.], [20., 40., 60.]])
+            .permute::<R2<3, 2>, _>();
+        let added_cols = a.index_add::<LAxis<1>, _, _>(cols, col_src).retrieve();
+        cx.execute();
+
+        // Row 2 gets both the first and third source rows, and 7, NaN and 1.5 aren't rows
+        assert_exact(&added_rows.data(), &[31., 42., 3., 4., 65., 86.]);
+        assert_exact(&added_cols.data(), &[1., 32., 3., 74., 5., 116.]);
+    }
+
+    #[test]
+    #[should_panic(expected = "index_add source has 3 elements along dimension 1, but needs 2")]
+    fn test_index_add_source_shape() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<3, 2>>();
+        let rows = cx.tensor::<R1<4>>();
+        let src = cx.tensor::<R2<4, 3>>();
+        a.index_add::<LAxis<0>, _, _>(rows, src);
+    }
+
+    #[test]
+    fn test_one_hot() {
+        let mut cx = Graph::new();
+        let labels = cx.tensor::<R1<3>>().set([2., 0., 5.]);
+        let one_hot = labels.one_hot::<LConst<4>>().retrieve();
+        let tokens = cx
+            .tensor::<(LConst<2>, Dyn<'s'>)>()
+            .set_dyn(vec![1., 0., 2., 2.], &[2, 2]);
+        let batch_one_hot = tokens.one_hot::<Dyn<'v'>>().retrieve();
+        cx.set_dyn_dim('v', 3);
+        cx.execute();
+
+        assert_exact(
+            &one_hot.data(),
+            &[0., 0., 1., 0., 1., 0., 0., 0., 0., 0., 0., 0.],
+        );
+        assert_exact(
+            &batch_one_hot.data(),
+            &[0., 1., 0., 1., 0., 0., 0., 0., 1., 0., 0., 1.],
+        );
+    }
+
+    #[test]
+    fn test_scatter() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<4, 2>>()
+            .set([[1., 2.], [3., 4.], [5., 6.], [7., 8.]]);
+        let indexes = cx.tensor::<(Dyn<'b'>,)>().set_dyn(vec![2., 9., 0.], &[3]);
+        let rows = cx
+            .tensor::<(Dyn<'b'>, LConst<2>)>()
+            .set_dyn(vec![-1., -2., -3., -4., -5., -6.], &[3, 2]);
+        let b = a.scatter(indexes, rows).retrieve();
+        let round_trip = b.gather(indexes).retrieve();
+        cx.execute();
+
+        // Index 9 is outside the matrix, so its row goes nowhere and gathers as zeros
+        assert_exact(&b.data(), &[-5., -6., 3., 4., -1., -2., 7., 8.]);
+        assert_exact(&round_trip.data(), &[-1., -2., 0., 0., -5., -6.]);
+    }
+
+    #[test]
+    fn test_tril_triu_diag_tensor() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R3<2, 3, 3>>()
+            .set((1..=18).map(|i| i as f32).collect::<Vec<_>>());
+        let lower = a.tril(0).retrieve();
+        let upper = a.triu(1).retrieve();
+        let diag = a.diag().retrieve();
+        // Non-square, with a dynamic dimension
+        let b = cx
+            .tensor::<(LConst<2>, Dyn<'n'>)>()
+            .set_dyn(vec![1., 2., 3., 4., 5., 6.], &[2, 3]);
+        let b_lower = b.tril(-1).retrieve();
+        let b_upper = b.triu(-1).retrieve();
+        // Infinities and NaNs that get masked out become zeros, and ones that are kept stay as they are
+        let c = cx
+            .tensor::<R2<2, 2>>()
+            .set([[1., f32::NAN], [f32::NEG_INFINITY, 2.]]);
+        let c_lower = c.tril(0).retrieve();
+        let c_upper = c.triu(0).retrieve();
+        let c_diag = c.diag().retrieve();
+        cx.execute();
+
+        assert_exact(
+            &lower.data(),
+            &[
+                1., 0., 0., 4., 5., 0., 7., 8., 9., 10., 0., 0., 13., 14., 0., 16., 17., 18.,
+            ],
+        );
+        assert_exact(
+            &upper.data(),
+            &[
+                0., 2., 3., 0., 0., 6., 0., 0., 0., 0., 11., 12., 0., 0., 15., 0., 0., 0.,
+            ],
+        );
+        assert_exact(&diag.data(), &[1., 5., 9., 10., 14., 18.]);
+        assert_exact(&b_lower.data(), &[0., 0., 0., 4., 0., 0.]);
+        assert_exact(&b_upper.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&c_lower.data(), &[1., 0., f32::NEG_INFINITY, 2.]);
+        let c_upper = c_upper.data();
+        assert!(c_upper[1].is_nan());
+        assert_exact(&[c_upper[0], c_upper[2], c_upper[3]], &[1., 0., 2.]);
+        assert_exact(&c_diag.data(), &[1., 2.]);
+    }
+
+    #[test]
+    fn test_attention_bias() {
+        let mut cx = Graph::new();
+        let mask = cx.tensor::<R2<2, 3>>().set([[1., 1., 0.], [1., 0., 0.]]);
+        let scores = cx.tensor::<R2<2, 3>>().set([[1., 2., 30.], [4., -5., 60.]]);
+        let weights = (scores + mask.attention_bias())
+            .softmax::<LAxis<1>>()
+            .retrieve();
+        cx.execute();
+
+        let e = 1_f32.exp();
+        assert_close(
+            &weights.data(),
+            &[1. / (1. + e), e / (1. + e), 0., 1., 0., 0.],
+        );
+    }
 }
@@ -0,0 +1,321 @@
+use crate::{op, prelude::*};
+
+/// How pooling windows get combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PoolKind {
+    Max,
+    Avg,
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Max pool windows along the last dimension. Padding is filled with the lowest float, so it never wins.
+    ///
+    /// The last dimension of `Dst` is the number of windows: `(len + 2 * padding - kernel) / stride + 1`.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R1<5>>().set([1., 5., 2., 4., 3.]);
+    /// let b = a.max_pool_1d::<R1<2>>(2, 2, 0).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![5., 4.]);
+    /// ```
+    #[track_caller]
+    pub fn max_pool_1d<Dst: Shape>(
+        self,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+    ) -> GraphTensor<Dst> {
+        self.pool_1d(PoolKind::Max, kernel, stride, padding)
+    }
+
+    /// Average pool windows along the last dimension. Padding counts as zeros in the average.
+    ///
+    /// The last dimension of `Dst` is the number of windows: `(len + 2 * padding - kernel) / stride + 1`.
+    #[track_caller]
+    pub fn avg_pool_1d<Dst: Shape>(
+        self,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+    ) -> GraphTensor<Dst> {
+        self.pool_1d(PoolKind::Avg, kernel, stride, padding)
+    }
+
+    /// Max pool windows over the last two dimensions. Kernel, stride and padding are given as (rows, columns).
+    ///
+    /// The last two dimensions of `Dst` are the number of windows along each: `(len + 2 * padding - kernel) / stride + 1`.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx
+    ///     .tensor::<R2<2, 4>>()
+    ///     .set([[1., 2., 3., 4.], [5., 6., 7., 8.]]);
+    /// let b = a.max_pool_2d::<R2<1, 2>>((2, 2), (2, 2), (0, 0)).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![6., 8.]);
+    /// ```
+    #[track_caller]
+    pub fn max_pool_2d<Dst: Shape>(
+        self,
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+    ) -> GraphTensor<Dst> {
+        self.pool_2d(PoolKind::Max, kernel, stride, padding)
+    }
+
+    /// Average pool windows over the last two dimensions. Kernel, stride and padding are given as (rows, columns), and
+    /// padding counts as zeros in the average.
+    ///
+    /// The last two dimensions of `Dst` are the number of windows along each: `(len + 2 * padding - kernel) / stride + 1`.
+    #[track_caller]
+    pub fn avg_pool_2d<Dst: Shape>(
+        self,
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+    ) -> GraphTensor<Dst> {
+        self.pool_2d(PoolKind::Avg, kernel, stride, padding)
+    }
+
+    /// Average over the whole of the last two dimensions, dropping them. This is the global average pool that image
+    /// classifiers run before their fully connected head.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx
+    ///     .tensor::<R3<2, 1, 2>>()
+    ///     .set([[[1., 3.]], [[5., 9.]]]);
+    /// let b = a.global_avg_pool_2d::<R1<2>>().retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![2., 7.]);
+    /// ```
+    #[track_caller]
+    pub fn global_avg_pool_2d<Dst: Shape>(self) -> GraphTensor<Dst> {
+        let n_dims = self.shape.len();
+        let dims = self.shape.shape();
+        let area = dims[n_dims - 2].clone() * dims[n_dims - 1].clone();
+        self.pool_reduce(PoolKind::Avg, &[n_dims - 2, n_dims - 1], area)
+            .into_dyn()
+            .typed()
+    }
+
+    #[track_caller]
+    fn pool_1d<Dst: Shape>(
+        self,
+        kind: PoolKind,
+        kernel: usize,
+        stride: usize,
+        padding: usize,
+    ) -> GraphTensor<Dst> {
+        let n_dims = self.shape.len();
+        // [.., len] -> [.., windows, kernel] -> [.., windows]
+        let windows = self
+            .pool_pad(kind, &[padding])
+            .pool_last_dim::<()>(kernel, stride, 0);
+        windows
+            .pool_reduce(kind, &[n_dims], kernel)
+            .into_dyn()
+            .typed()
+    }
+
+    #[track_caller]
+    fn pool_2d<Dst: Shape>(
+        self,
+        kind: PoolKind,
+        (kernel_y, kernel_x): (usize, usize),
+        (stride_y, stride_x): (usize, usize),
+        (padding_y, padding_x): (usize, usize),
+    ) -> GraphTensor<Dst> {
+        let n_dims = self.shape.len();
+        // [.., rows, cols] -> [.., rows, cols_out, kernel_x]
+        let mut windows = self
+            .pool_pad(kind, &[padding_y, padding_x])
+            .pool_last_dim::<()>(kernel_x, stride_x, 0);
+        // Move the rows last to pool them too: [.., cols_out, kernel_x, rows_out, kernel_y]
+        let mut axes = (0..n_dims + 1).collect::<Vec<_>>();
+        let rows = axes.remove(n_dims - 2);
+        axes.push(rows);
+        windows.shape.permute(&axes);
+        let windows = windows.pool_last_dim::<()>(kernel_y, stride_y, 0);
+        // Reduce both kernels, leaving [.., cols_out, rows_out], then swap back
+        let mut out = windows.pool_reduce(kind, &[n_dims - 1, n_dims + 1], kernel_x * kernel_y);
+        let mut axes = (0..n_dims).collect::<Vec<_>>();
+        axes.swap(n_dims - 2, n_dims - 1);
+        out.shape.permute(&axes);
+        out.into_dyn().typed()
+    }
+
+    /// Pad the trailing dimensions on both sides, with a fill that doesn't affect the pool
+    #[track_caller]
+    fn pool_pad(self, kind: PoolKind, padding: &[usize]) -> GraphTensor<()> {
+        let mut pad = vec![(Expression::from(0), Expression::from(0)); self.shape.len()];
+        for (p, n) in pad.iter_mut().rev().zip(padding.iter().rev()) {
+            *p = (Expression::from(*n), Expression::from(*n));
+        }
+        let padded = self.pad::<()>(pad.clone());
+        if kind == PoolKind::Avg || padding.iter().all(|p| *p == 0) {
+            return padded;
+        }
+        // Padding fills with zeros, so push the padded region down to the lowest float for max pooling
+        let mask = self
+            .graph()
+            .constant(1.)
+            .expand_to::<S>(self.shape.contiguous());
+        padded + (mask.pad::<()>(pad).contiguous() - 1.) * f32::MAX
+    }
+
+    /// Reduce the kernel dimensions of pooled windows. The result is untyped, so callers check it against their
+    /// output shape with `DynGraphTensor::typed`.
+    #[track_caller]
+    fn pool_reduce(
+        self,
+        kind: PoolKind,
+        dims: &[usize],
+        kernel_size: impl Into<BigExpression>,
+    ) -> GraphTensor<()> {
+        let (mut id, mut shape) = (self.id, self.shape);
+        // Highest dimension first so the lower ones don't move
+        for dim in dims.iter().rev() {
+            id = match kind {
+                PoolKind::Max => self.graph().add_op(op::MaxReduce(*dim)),
+                PoolKind::Avg => self.graph().add_op(op::SumReduce(*dim)),
+            }
+            .input(id, 0, shape)
+            .finish();
+            shape.remove_dim(*dim);
+            shape = shape.contiguous();
+        }
+        let out = GraphTensor::<()>::from_id(id, shape, self.graph_ref);
+        match kind {
+            PoolKind::Max => out,
+            PoolKind::Avg => {
+                let size = self.graph().constant_expr(kernel_size).recip();
+                out * size.expand_to(shape)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    /// Pool each row of a [rows, cols] matrix with a naive loop
+    fn reference_pool_2d(
+        data: &[f32],
+        (rows, cols): (usize, usize),
+        kernel: (usize, usize),
+        stride: (usize, usize),
+        padding: (usize, usize),
+        max: bool,
+    ) -> Vec<f32> {
+        let out_rows = (rows + 2 * padding.0 - kernel.0) / stride.0 + 1;
+        let out_cols = (cols + 2 * padding.1 - kernel.1) / stride.1 + 1;
+        let mut out = vec![];
+        for r in 0..out_rows {
+            for c in 0..out_cols {
+                let mut window = vec![];
+                for kr in 0..kernel.0 {
+                    for kc in 0..kernel.1 {
+                        let (y, x) = (r * stride.0 + kr, c * stride.1 + kc);
+                        if y < padding.0
+                            || x < padding.1
+                            || y - padding.0 >= rows
+                            || x - padding.1 >= cols
+                        {
+                            window.push(if max { f32::MIN } else { 0. });
+                        } else {
+                            window.push(data[(y - padding.0) * cols + x - padding.1]);
+                        }
+                    }
+                }
+                out.push(if max {
+                    window.into_iter().fold(f32::MIN, f32::max)
+                } else {
+                    window.iter().sum::<f32>() / window.len() as f32
+                });
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_pool_1d() {
+        let mut cx = Graph::new();
+        // Negative values make sure padding doesn't leak into the max
+        let data = [-1., -5., -2., -4., -3., -6.];
+        let a = cx.tensor::<R2<1, 6>>().set([data]);
+        let max = a.max_pool_1d::<R2<1, 3>>(3, 2, 1).retrieve();
+        let avg = a.avg_pool_1d::<R2<1, 3>>(3, 2, 1).retrieve();
+        let max_strided = a.max_pool_1d::<R2<1, 2>>(2, 3, 0).retrieve();
+        cx.execute();
+
+        assert_exact(&max.data(), &[-1., -2., -3.]);
+        assert_close(&avg.data(), &[-2., -11. / 3., -13. / 3.]);
+        assert_exact(&max_strided.data(), &[-1., -3.]);
+    }
+
+    #[test]
+    fn test_global_avg_pool_2d() {
+        let mut cx = Graph::new();
+        let data = random_vec(2 * 3 * 4);
+        let a = cx
+            .tensor::<(LConst<2>, Dyn<'h'>, LConst<4>)>()
+            .set_dyn(data.clone(), &[2, 3, 4]);
+        let b = a.global_avg_pool_2d::<R1<2>>().retrieve();
+        cx.execute();
+
+        let expected = data
+            .chunks(12)
+            .map(|c| c.iter().sum::<f32>() / 12.)
+            .collect::<Vec<_>>();
+        assert_close(&b.data(), &expected);
+    }
+
+    #[test]
+    fn test_pool_2d() {
+        let mut cx = Graph::new();
+        let data = random_vec(2 * 5 * 7);
+        let a = cx.tensor::<R3<2, 5, 7>>().set(data.clone());
+        let max = a
+            .max_pool_2d::<R3<2, 3, 3>>((3, 3), (2, 3), (1, 1))
+            .retrieve();
+        let avg = a
+            .avg_pool_2d::<R3<2, 2, 4>>((2, 2), (2, 2), (0, 1))
+            .retrieve();
+        cx.execute();
+
+        let (mut max_ref, mut avg_ref) = (vec![], vec![]);
+        for channel in data.chunks(5 * 7) {
+            max_ref.extend(reference_pool_2d(
+                channel,
+                (5, 7),
+                (3, 3),
+                (2, 3),
+                (1, 1),
+                true,
+            ));
+            avg_ref.extend(reference_pool_2d(
+                channel,
+                (5, 7),
+                (2, 2),
+                (2, 2),
+                (0, 1),
+                false,
+            ));
+        }
+        assert_exact(&max.data(), &max_ref);
+        assert_close(&avg.data(), &avg_ref);
+    }
+
+    #[test]
+    #[should_panic(expected = "Dimension 1 is 2, not 3")]
+    fn test_pool_checks_output_shape() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<1, 6>>();
+        // 6 columns in windows of 3 with a stride of 3 is only 2 windows
+        a.max_pool_1d::<R2<1, 3>>(3, 3, 0);
+    }
+}
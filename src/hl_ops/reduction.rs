@@ -6,6 +6,7 @@ use crate::{
 };
 
 impl<S: Shape> GraphTensor<S> {
+    #[track_caller]
     pub fn sum_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
@@ -19,12 +20,14 @@ impl<S: Shape> GraphTensor<S> {
                 .add_op(op::SumReduce(dim))
                 .input(new_id, 0, shape)
                 .finish();
-            // Reduce shape
+            // Reduce shape. The reduced output is a fresh contiguous buffer
             shape.remove_dim(dim);
+            shape = shape.contiguous();
         }
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
 
+    #[track_caller]
     pub fn max_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
@@ -38,12 +41,74 @@ impl<S: Shape> GraphTensor<S> {
                 .add_op(op::MaxReduce(dim))
                 .input(new_id, 0, shape)
                 .finish();
-            // Reduce shape
+            // Reduce shape. The reduced output is a fresh contiguous buffer
             shape.remove_dim(dim);
+            shape = shape.contiguous();
         }
         GraphTensor::from_id(new_id, shape, self.graph_ref)
     }
 
+    #[track_caller]
+    pub fn min_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let mut shape = self.shape;
+
+        let mut new_id = self.id;
+        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
+            new_id = self
+                .graph()
+                .add_op(op::MinReduce(dim))
+                .input(new_id, 0, shape)
+                .finish();
+            // Reduce shape. The reduced output is a fresh contiguous buffer
+            shape.remove_dim(dim);
+            shape = shape.contiguous();
+        }
+        GraphTensor::from_id(new_id, shape, self.graph_ref)
+    }
+
+    #[track_caller]
+    pub fn prod_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let mut shape = self.shape;
+
+        let mut new_id = self.id;
+        for dim in Ax::as_array().into_iter().collect_vec().into_iter().rev() {
+            new_id = self
+                .graph()
+                .add_op(op::ProdReduce(dim))
+                .input(new_id, 0, shape)
+                .finish();
+            // Reduce shape. The reduced output is a fresh contiguous buffer
+            shape.remove_dim(dim);
+            shape = shape.contiguous();
+        }
+        GraphTensor::from_id(new_id, shape, self.graph_ref)
+    }
+
+    /// Computes ln(sum(exp(x))) along axes, shifting by the max first so large inputs don't overflow.
+    ///
+    /// Infinite maxes aren't shifted by, since subtracting them from themselves gives NaN, so all -inf gives -inf and any
+    /// +inf gives +inf.
+    #[track_caller]
+    pub fn logsumexp<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let max = self.max_reduce::<Dst, Ax>();
+        let shift = max.select(max.is_finite());
+        (self - shift.expand_to(self.shape))
+            .exp()
+            .sum_reduce::<Dst, Ax>()
+            .ln()
+            + shift
+    }
+
+    #[track_caller]
     pub fn mean_reduce<Dst: Shape, Ax: Axes>(self) -> GraphTensor<Dst>
     where
         S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
@@ -60,6 +125,7 @@ impl<S: Shape> GraphTensor<S> {
 
             // Divide by size of dimension
             let div_tensor = self.graph().constant_expr(shape.remove_dim(dim)).id;
+            shape = shape.contiguous();
             let mul_tensor = self
                 .graph()
                 .add_op(op::Recip)
@@ -84,6 +150,43 @@ impl<S: Shape> GraphTensor<S> {
         }
         GraphTensor::from_id(node_id, shape, self.graph_ref)
     }
+
+    /// Variance along axes, dividing by the number of elements minus `correction` (0 for the population variance, 1 for the sample variance).
+    ///
+    /// Uses two passes, subtracting the mean before squaring, so large offsets don't swamp the result like `mean(x^2) - mean(x)^2` would.
+    #[track_caller]
+    pub fn var_reduce<Dst: Shape, Ax: Axes>(self, correction: f32) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let centered = self - self.mean_reduce::<Dst, Ax>().expand_to(self.shape);
+        let sum_sq = (centered * centered).sum_reduce::<Dst, Ax>();
+        let n = Ax::as_array()
+            .into_iter()
+            .map(|dim| self.shape.shape()[dim].clone())
+            .fold(Expression::from(1), |a, b| a * b);
+        sum_sq / (self.graph().constant_expr(n).expand_to(sum_sq.shape) - correction)
+    }
+
+    /// Standard deviation along axes. See `var_reduce` for `correction`.
+    #[track_caller]
+    pub fn std_reduce<Dst: Shape, Ax: Axes>(self, correction: f32) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        self.var_reduce::<Dst, Ax>(correction).sqrt()
+    }
+
+    /// Mean along axes, only counting elements where `mask` is 1. This is how padding tokens get left out when mean
+    /// pooling a batch of sequences. Rows that are entirely masked out come out as 0.
+    #[track_caller]
+    pub fn masked_mean_reduce<Dst: Shape, Ax: Axes>(self, mask: GraphTensor<S>) -> GraphTensor<Dst>
+    where
+        S: HasAxes<Ax> + ReduceShapeTo<Dst, Ax>,
+    {
+        let sum = (self * mask).sum_reduce::<Dst, Ax>();
+        sum / mask.sum_reduce::<Dst, Ax>().maximum(1e-9)
+    }
 }
 
 #[cfg(test)]
@@ -126,6 +229,24 @@ mod tests {
         assert_close(&b.data(), &d_b.as_vec());
     }
 
+    #[test]
+    fn test_min_reduce() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor::<R2<2, 3>>();
+        a.set(a_data.clone());
+        let b = a.min_reduce::<_, LAxis<1>>();
+        b.retrieve();
+
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_b = d_a.min::<_, DAxis<1>>();
+
+        assert_close(&b.data(), &d_b.as_vec());
+    }
+
     #[test]
     fn test_mean_reduce() {
         let mut cx = Graph::new();
@@ -143,4 +264,115 @@ mod tests {
 
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_var_std_reduce() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let var = a.var_reduce::<_, LAxis<1>>(0.).retrieve();
+        let std = a.std_reduce::<_, LAxis<1>>(1.).retrieve();
+        let var_all = a.var_reduce::<(), LAxes2<0, 1>>(1.).retrieve();
+        // A large offset shouldn't change the variance
+        let offset = (a + 10000.).var_reduce::<_, LAxis<1>>(0.).retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data.clone(), (DConst::<2>, DConst::<3>));
+        let d_var = d_a.var::<_, DAxis<1>>();
+        assert_close(&var.data(), &d_var.as_vec());
+        assert_close(&offset.data(), &d_var.as_vec());
+        let sample_std = (d_var * 1.5).sqrt();
+        assert_close(&std.data(), &sample_std.as_vec());
+        let mean = a_data.iter().sum::<f32>() / 6.;
+        let sample_var = a_data.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / 5.;
+        assert_close(&var_all.data(), &[sample_var]);
+    }
+
+    #[test]
+    fn test_prod_reduce() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set([[1., -2., 3.], [0.5, 4., 2.]]);
+        let b = a.prod_reduce::<_, LAxis<1>>().retrieve();
+        let c = a.prod_reduce::<(), LAxes2<0, 1>>().retrieve();
+        let d = a
+            .permute::<R2<3, 2>, _>()
+            .prod_reduce::<_, LAxis<1>>()
+            .retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &[-6., 4.]);
+        assert_close(&c.data(), &[-24.]);
+        assert_close(&d.data(), &[0.5, -8., 6.]);
+    }
+
+    #[test]
+    fn test_logsumexp() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let b = a.logsumexp::<_, LAxis<1>>().retrieve();
+        // Would overflow without subtracting the max
+        let large = cx.tensor::<R1<2>>().set([1000., 1000.]);
+        let c = large.logsumexp::<(), LAxis<0>>().retrieve();
+        let infinite = cx
+            .tensor::<R2<2, 2>>()
+            .set([[f32::NEG_INFINITY, f32::NEG_INFINITY], [f32::INFINITY, 0.]])
+            .logsumexp::<_, LAxis<1>>()
+            .retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        let d_b = d_a.logsumexp::<_, DAxis<1>>();
+        assert_close(&b.data(), &d_b.as_vec());
+        assert_close(&c.data(), &[1000. + 2_f32.ln()]);
+        assert_eq!(infinite.data(), vec![f32::NEG_INFINITY, f32::INFINITY]);
+    }
+
+    #[test]
+    fn test_reduce_views() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(12);
+        let a = cx.tensor::<R2<3, 4>>().set(a_data.clone());
+        let b = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        // Reduce a slice, and an expanded tensor along the dimension that's kept
+        let sliced = a
+            .slice((.., Expression::from(1)..))
+            .realize::<R2<3, 3>>()
+            .sum_reduce::<_, LAxis<0>>();
+        let expanded = b.expand::<R2<3, 2>, _>().max_reduce::<_, LAxis<0>>();
+        let sliced_out = (sliced * 1.).retrieve();
+        let expanded_out = (expanded + cx.tensor::<R1<2>>().set([0., 1.])).retrieve();
+        cx.execute();
+
+        assert_eq!(sliced.shape.shape_usize(), vec![3]);
+        assert_eq!(expanded.shape.shape_usize(), vec![2]);
+        assert!(sliced.shape.is_contiguous() && expanded.shape.is_contiguous());
+        let d_dev = Cpu::default();
+        let d_a = d_dev.tensor_from_vec(a_data, (DConst::<3>, DConst::<4>));
+        let d_b = d_a
+            .slice((.., 1..))
+            .realize::<Rank2<3, 3>>()
+            .sum::<_, DAxis<0>>();
+        assert_close(&sliced_out.data(), &d_b.as_vec());
+        assert_exact(&expanded_out.data(), &[3., 4.]);
+    }
+
+    #[test]
+    fn test_masked_mean_reduce() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R3<3, 3, 2>>()
+            .set((0..18).map(|i| i as f32).collect::<Vec<_>>());
+        let mask = cx
+            .tensor::<R2<3, 3>>()
+            .set([[1., 1., 1.], [1., 0., 0.], [0., 0., 0.]]);
+        let b = a
+            .masked_mean_reduce::<_, LAxis<1>>(mask.expand::<_, LAxis<2>>())
+            .retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &[2., 3., 6., 7., 0., 0.]);
+    }
 }
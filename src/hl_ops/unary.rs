@@ -4,6 +4,7 @@ use std::ops::{Add, Mul, Neg};
 impl<S: Shape> Neg for GraphTensor<S> {
     type Output = GraphTensor<S>;
 
+    #[track_caller]
     fn neg(self) -> Self::Output {
         self * -1.0
     }
@@ -11,6 +12,7 @@ impl<S: Shape> Neg for GraphTensor<S> {
 
 impl<S: Shape> GraphTensor<S> {
     /// Base 2 log
+    #[track_caller]
     pub fn log2(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -21,6 +23,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Base 2 exp
+    #[track_caller]
     pub fn exp2(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -31,16 +34,19 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Natural exp
+    #[track_caller]
     pub fn exp(self) -> GraphTensor<S> {
         (self * (1.0 / f32::ln(2.))).exp2()
     }
 
     /// Natural log
+    #[track_caller]
     pub fn ln(self) -> GraphTensor<S> {
         self.log2() * f32::ln(2.)
     }
 
     /// Take the reciprocal of each element
+    #[track_caller]
     pub fn recip(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -51,6 +57,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// The sin(x) function
+    #[track_caller]
     pub fn sin(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -61,16 +68,19 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// The cos(x) function
+    #[track_caller]
     pub fn cos(self) -> GraphTensor<S> {
         ((std::f32::consts::PI / 2.) - self).sin()
     }
 
     /// Square every element in the tensor
+    #[track_caller]
     pub fn square(self) -> GraphTensor<S> {
         self * self
     }
 
     /// The square root function
+    #[track_caller]
     pub fn sqrt(self) -> GraphTensor<S> {
         let new_id = self
             .graph()
@@ -80,7 +90,23 @@ impl<S: Shape> GraphTensor<S> {
         GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
     }
 
+    /// Convert to `T` (`f32`, `f16` or `bf16`). Half precision outputs are stored in half precision buffers, which ops
+    /// downstream widen back to f32 unless they can compute on them directly, like mixed precision matmuls.
+    #[track_caller]
+    pub fn cast<T: op::HasDType>(self) -> GraphTensor<S> {
+        if T::DTYPE == op::DType::F32 {
+            return self;
+        }
+        let new_id = self
+            .graph()
+            .add_op(op::Cast(T::DTYPE))
+            .input(self.id, 0, self.shape)
+            .finish();
+        GraphTensor::from_id(new_id, self.shape.contiguous(), self.graph_ref)
+    }
+
     /// Scale so std is 1.0
+    #[track_caller]
     pub fn std_norm<Ax: Axes, T>(self, epsilon: T) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
@@ -98,6 +124,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Center so mean is 0.0
+    #[track_caller]
     pub fn mean_norm<Ax: Axes>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
@@ -109,6 +136,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Applies a layer norm along an axis
+    #[track_caller]
     pub fn layer_norm<Ax: Axes, T>(self, epsilon: T) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
@@ -120,6 +148,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Applies a softmax function along an axis
+    #[track_caller]
     pub fn softmax<Ax: Axes>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
@@ -136,6 +165,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Applies a log softmax function along an axis
+    #[track_caller]
     pub fn log_softmax<Ax: Axes>(self) -> GraphTensor<S>
     where
         <S as ReduceShape<Ax>>::Reduced: Shape,
@@ -153,6 +183,7 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Get the indicies of the max elements along the last axis
+    #[track_caller]
     pub fn argmax(self) -> GraphTensor<<S as ReduceShape<<S as Shape>::LastAxis>>::Reduced> {
         // Get one-hot along last dimension
         let x_equal = self.equals(self.max_reduce::<_, S::LastAxis>().expand_to(self.shape));
@@ -173,47 +204,122 @@ impl<S: Shape> GraphTensor<S> {
     }
 
     /// Take the absolute value
+    #[track_caller]
     pub fn abs(self) -> GraphTensor<S> {
         self.relu() + (-self).relu()
     }
 
-    /// Get the sign of each element, '1' for positive and '-1' for negative
+    /// Get the sign of each element, '1' for positive, '-1' for negative and '0' for zero
+    #[track_caller]
     pub fn sign(self) -> GraphTensor<S> {
-        self / (self.abs() + 1e-10)
+        let zero = self.graph().constant(0.).expand_to(self.shape);
+        self.greater_than(zero) - self.less_than(zero)
+    }
+
+    /// 1 where the value is finite, and 0 where it's NaN or infinite. Comparisons with NaN are always false, so this is
+    /// built from two of them.
+    #[track_caller]
+    pub fn is_finite(self) -> GraphTensor<S> {
+        let inf = self.graph().constant(f32::INFINITY).expand_to(self.shape);
+        self.less_than(inf) * (-self).less_than(inf)
+    }
+
+    /// Round towards zero
+    #[track_caller]
+    pub fn trunc(self) -> GraphTensor<S> {
+        self - self % 1.
+    }
+
+    /// Round down to the nearest integer
+    #[track_caller]
+    pub fn floor(self) -> GraphTensor<S> {
+        // The remainder takes the sign of the input, so negative non-integers need to go one further down
+        let rem = self % 1.;
+        let zero = self.graph().constant(0.).expand_to(rem.shape);
+        self - rem - rem.less_than(zero)
+    }
+
+    /// Round up to the nearest integer
+    #[track_caller]
+    pub fn ceil(self) -> GraphTensor<S> {
+        let rem = self % 1.;
+        let zero = self.graph().constant(0.).expand_to(rem.shape);
+        self - rem + rem.greater_than(zero)
+    }
+
+    /// Round to the nearest integer, with halves rounded away from zero
+    #[track_caller]
+    pub fn round(self) -> GraphTensor<S> {
+        // Adding 0.5 before flooring can round up in f32 (like 0.49999997 + 0.5 == 1.0), so compare the fraction instead
+        let whole = self.trunc();
+        let frac = self - whole;
+        let half = self.graph().constant(0.5).expand_to(frac.shape);
+        whole + frac.abs().greater_than_equal(half) * self.sign()
+    }
+
+    /// Clamp every element to the range [min, max]
+    #[track_caller]
+    pub fn clamp(self, min: f32, max: f32) -> GraphTensor<S> {
+        self.maximum(min).minimum(max)
+    }
+
+    /// The Gauss error function
+    #[allow(clippy::excessive_precision)]
+    #[track_caller]
+    pub fn erf(self) -> GraphTensor<S> {
+        // Abramowitz and Stegun 7.1.26, max error of 1.5e-7
+        let x = self.abs();
+        let t = (x * 0.3275911 + 1.).recip();
+        let poly = ((((t * 1.061405429 - 1.453152027) * t + 1.421413741) * t - 0.284496736) * t
+            + 0.254829592)
+            * t;
+        (1. - poly * (-(x * x)).exp()) * self.sign()
     }
 
     /// The Rectified Linear Unit activation function
+    #[track_caller]
     pub fn relu(self) -> GraphTensor<S> {
         self.max_f32(0.)
     }
 
     /// The sigmoid activation function
+    #[track_caller]
     pub fn sigmoid(self) -> GraphTensor<S> {
         // Based on https://github.com/tinygrad/tinygrad/blob/9d142430cbe61121c864c0015f1de83c94a7d2c0/tinygrad/mlops.py#L70
         1. / (1. + (-self).exp())
     }
 
     /// The swish activation function
+    #[track_caller]
     pub fn swish(self) -> GraphTensor<S> {
         self * self.sigmoid()
     }
 
     /// The tanh activation function
+    #[track_caller]
     pub fn tanh(self) -> GraphTensor<S> {
         (self * 2.0).sigmoid() * 2.0 - 1.0
     }
 
     /// The leaky relu activation function
+    #[track_caller]
     pub fn leaky_relu(self, neg_slope: f32) -> GraphTensor<S> {
         self.relu() - (self * -neg_slope).relu()
     }
 
     /// The Gaussian Error Linear Unit activation function
     #[allow(clippy::excessive_precision)]
+    #[track_caller]
     pub fn gelu(self) -> GraphTensor<S> {
         // Based on https://github.com/tinygrad/tinygrad/blob/9fc4465557831b614b56dd645eebc940ca0fa1bb/tinygrad/tensor.py#L1162C26-L1162C104
         0.5 * self * (1. + (0.7978845608 * self * (1. + 0.044715 * self * self)).tanh())
     }
+
+    /// The Gaussian Error Linear Unit activation function, using the exact erf formulation instead of the tanh approximation
+    #[track_caller]
+    pub fn gelu_exact(self) -> GraphTensor<S> {
+        0.5 * self * (1. + (self * std::f32::consts::FRAC_1_SQRT_2).erf())
+    }
 }
 
 #[cfg(test)]
@@ -385,4 +491,56 @@ mod tests {
         let d_b = d_a.tanh();
         assert_close(&b.data(), &d_b.as_vec());
     }
+
+    #[test]
+    fn test_rounding() {
+        let data = [
+            -2.5, -1.7, -1., -0.2, 0., 0.3, 1.5, 2.7, 0.49999997, 8388609.,
+        ];
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<10>>().set(data);
+        let floor = a.floor().retrieve();
+        let ceil = a.ceil().retrieve();
+        let round = a.round().retrieve();
+        let trunc = a.trunc().retrieve();
+        let sign = a.sign().retrieve();
+        let clamp = a.clamp(-1., 1.).retrieve();
+        cx.execute();
+
+        let apply = |f: fn(f32) -> f32| data.iter().map(|x| f(*x)).collect::<Vec<_>>();
+        assert_exact(&floor.data(), &apply(f32::floor));
+        assert_exact(&ceil.data(), &apply(f32::ceil));
+        assert_exact(&round.data(), &apply(f32::round));
+        assert_exact(&trunc.data(), &apply(f32::trunc));
+        assert_exact(&sign.data(), &[-1., -1., -1., -1., 0., 1., 1., 1., 1., 1.]);
+        assert_exact(&clamp.data(), &apply(|x| x.clamp(-1., 1.)));
+    }
+
+    #[test]
+    fn test_erf() {
+        let mut cx = Graph::new();
+        let a_data = random_vec(6);
+        let a = cx.tensor::<R1<4>>().set([-2., 0., 0.5, 1.]);
+        let b = a.erf().retrieve();
+        let c = cx.tensor::<R2<2, 3>>().set(a_data.clone());
+        let d = c.gelu_exact().retrieve();
+        cx.execute();
+
+        assert_close(&b.data(), &[-0.9953223, 0., 0.5204999, 0.8427008]);
+        let d_dev = Cpu::default();
+        let d_c = d_dev.tensor_from_vec(a_data, (DConst::<2>, DConst::<3>));
+        assert_close(&d.data(), &d_c.accurate_gelu().as_vec());
+    }
+
+    #[test]
+    fn test_is_finite() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R1<5>>()
+            .set([1., f32::NAN, f32::INFINITY, -f32::INFINITY, -1e30]);
+        let b = a.is_finite().retrieve();
+        cx.execute();
+
+        assert_exact(&b.data(), &[1., 0., 0., 0., 1.]);
+    }
 }
@@ -0,0 +1,147 @@
+use crate::prelude::*;
+
+impl<S: Shape> GraphTensor<S> {
+    /// Upsample the last two dimensions by repeating each element `scale` times along both.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<1, 2>>().set([[1., 2.]]);
+    /// let b = a.upsample_nearest::<R2<2, 4>>(2).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![1., 1., 2., 2., 1., 1., 2., 2.]);
+    /// ```
+    #[track_caller]
+    pub fn upsample_nearest<Dst: Shape>(mut self, scale: usize) -> GraphTensor<Dst> {
+        let n_dims = self.shape.len();
+        let mut dims = self
+            .shape
+            .shape()
+            .into_iter()
+            .map(|d| d.small())
+            .collect::<Vec<_>>();
+        // [.., h, w] -> [.., h, scale, w, scale]
+        self.shape.expand(n_dims, scale);
+        self.shape.expand(n_dims - 1, scale);
+        let upsampled = self.contiguous();
+        for d in &mut dims[n_dims - 2..] {
+            *d = (*d * scale).simplify();
+        }
+        GraphTensor::<()>::from_id(upsampled.id, ShapeTracker::new(&dims), self.graph_ref)
+            .into_dyn()
+            .typed()
+    }
+
+    /// Resize the last two dimensions to `out_h` x `out_w` with bilinear interpolation, sampling at pixel centers
+    /// (`align_corners=False` in PyTorch). The input's last two dimensions need to be known when building the graph.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<1, 2>>().set([[1., 3.]]);
+    /// let b = a.interpolate_bilinear::<R2<1, 4>>(1, 4).retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![1., 1.5, 2.5, 3.]);
+    /// ```
+    #[track_caller]
+    pub fn interpolate_bilinear<Dst: Shape>(self, out_h: usize, out_w: usize) -> GraphTensor<Dst> {
+        let dims = self.shape.shape();
+        let n_dims = dims.len();
+        let [h, w] = [n_dims - 2, n_dims - 1].map(|i| {
+            dims[i]
+                .to_usize()
+                .expect("Bilinear interpolation needs the input's height and width to be known")
+        });
+        // Interpolation along each dimension is a matmul with a weight matrix
+        let rows = self
+            .graph()
+            .dyn_tensor("Bilinear Rows", &[h, out_h])
+            .set(bilinear_weights(h, out_h));
+        let cols = self
+            .graph()
+            .dyn_tensor("Bilinear Columns", &[w, out_w])
+            .set(bilinear_weights(w, out_w));
+        let mut swap = (0..n_dims).collect::<Vec<_>>();
+        swap.swap(n_dims - 2, n_dims - 1);
+        // [.., h, w] -> [.., h, out_w] -> [.., out_w, out_h] -> [.., out_h, out_w]
+        self.into_dyn()
+            .matmul(cols)
+            .permute(&swap)
+            .matmul(rows)
+            .permute(&swap)
+            .typed()
+    }
+}
+
+/// The weight of each input position (rows) in each output position (columns) when linearly resizing a dimension
+fn bilinear_weights(input: usize, output: usize) -> Vec<f32> {
+    let mut weights = vec![0.; input * output];
+    let scale = input as f32 / output as f32;
+    for o in 0..output {
+        let src = ((o as f32 + 0.5) * scale - 0.5).max(0.);
+        let lower = (src as usize).min(input - 1);
+        let upper = (lower + 1).min(input - 1);
+        let frac = src - lower as f32;
+        weights[lower * output + o] += 1. - frac;
+        weights[upper * output + o] += frac;
+    }
+    weights
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_upsample_nearest() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R3<2, 2, 2>>()
+            .set([[[1., 2.], [3., 4.]], [[5., 6.], [7., 8.]]]);
+        // Upsample a transposed view, which has to be read through its strides
+        let b = a
+            .permute::<_, LAxes3<0, 2, 1>>()
+            .upsample_nearest::<R3<2, 6, 6>>(3)
+            .retrieve();
+        cx.execute();
+
+        let mut expected = vec![];
+        for channel in [[[1., 3.], [2., 4.]], [[5., 7.], [6., 8.]]] {
+            for row in channel {
+                for _ in 0..3 {
+                    for v in row {
+                        expected.extend([v; 3]);
+                    }
+                }
+            }
+        }
+        assert_exact(&b.data(), &expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "Dimension 2 is 4, not 6")]
+    fn test_upsample_nearest_checks_output_shape() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<1, 2, 2>>();
+        a.upsample_nearest::<R3<1, 4, 6>>(2);
+    }
+
+    #[test]
+    fn test_interpolate_bilinear() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<1, 2, 2>>().set([[[1., 2.], [3., 4.]]]);
+        let up = a.interpolate_bilinear::<R3<1, 4, 4>>(4, 4).retrieve();
+        let down = a
+            .upsample_nearest::<R3<1, 4, 4>>(2)
+            .interpolate_bilinear::<R3<1, 2, 2>>(2, 2)
+            .retrieve();
+        cx.execute();
+
+        // Matches torch.nn.functional.interpolate(mode="bilinear", align_corners=False)
+        assert_close(
+            &up.data(),
+            &[
+                1., 1.25, 1.75, 2., 1.5, 1.75, 2.25, 2.5, 2.5, 2.75, 3.25, 3.5, 3., 3.25, 3.75, 4.,
+            ],
+        );
+        assert_close(&down.data(), &[1., 2., 3., 4.]);
+    }
+}
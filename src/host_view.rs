@@ -0,0 +1,270 @@
+use std::{borrow::Cow, fmt::Debug, ops::Index};
+
+use crate::prelude::*;
+
+/// Retrieved data seen through a tensor's view, without copying it.
+///
+/// Permutes, slices, expands and padding are applied as elements are read, so indexing and iterating always give the
+/// tensor's logical elements in row-major order, whatever layout the data has on the host. Half precision data gets
+/// widened to f32 first.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]).keep();
+/// cx.execute();
+/// let t = a.permute::<R2<3, 2>, Axes2<1, 0>>();
+/// let view = t.view();
+/// assert_eq!(view.shape(), &[3, 2]);
+/// assert_eq!(view[[2, 1]], 6.);
+/// assert_eq!(view.to_vec(), vec![1., 4., 2., 5., 3., 6.]);
+/// ```
+pub struct HostTensorView<'a> {
+    /// Borrowed f32 data, or half precision data widened to f32
+    data: Cow<'a, [f32]>,
+    shape: Vec<usize>,
+    /// Physical index and validity of each logical element, or None when the view is the data as laid out
+    exprs: Option<(BigExpression, BigExpression)>,
+}
+
+/// Padded elements aren't stored anywhere, so indexing one returns a reference to this
+const PADDING: f32 = 0.;
+
+impl<'a> HostTensorView<'a> {
+    /// View `data` through a shape tracker. Dynamic dimensions in the tracker need to already be resolved.
+    pub fn new(data: impl Into<Cow<'a, [f32]>>, st: ShapeTracker) -> Self {
+        Self {
+            data: data.into(),
+            shape: st.shape_usize(),
+            exprs: st.is_reshaped().then(|| {
+                (
+                    st.index_expression_no_simplify(),
+                    st.valid_expression_no_simplify(),
+                )
+            }),
+        }
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    /// Number of logical elements
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The element at a multi-dimensional index, or None if the index is out of bounds
+    pub fn get(&self, index: &[usize]) -> Option<f32> {
+        self.flat_index(index).map(|i| *self.element(i))
+    }
+
+    /// Every element in row-major order
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = f32> + '_ {
+        (0..self.len()).map(|i| *self.element(i))
+    }
+
+    /// The only element of a view holding exactly one
+    pub fn item(&self) -> f32 {
+        assert_eq!(
+            self.len(),
+            1,
+            "item() needs a tensor with one element, this one has shape {:?}",
+            self.shape
+        );
+        *self.element(0)
+    }
+
+    /// Copy the elements out into a contiguous row-major vec
+    pub fn to_vec(&self) -> Vec<f32> {
+        match self.exprs {
+            Some(_) => self.iter().collect(),
+            None => self.data.to_vec(),
+        }
+    }
+
+    fn flat_index(&self, index: &[usize]) -> Option<usize> {
+        if index.len() != self.shape.len() || index.iter().zip(&self.shape).any(|(i, d)| i >= d) {
+            return None;
+        }
+        Some(
+            index
+                .iter()
+                .zip(&self.shape)
+                .fold(0, |acc, (i, d)| acc * d + i),
+        )
+    }
+
+    fn element(&self, logical: usize) -> &f32 {
+        match &self.exprs {
+            Some((ind, val)) => {
+                if val.exec_single_var(logical) != 0 {
+                    &self.data[ind.exec_single_var(logical)]
+                } else {
+                    &PADDING
+                }
+            }
+            None => &self.data[logical],
+        }
+    }
+}
+
+impl<const N: usize> Index<[usize; N]> for HostTensorView<'_> {
+    type Output = f32;
+
+    fn index(&self, index: [usize; N]) -> &f32 {
+        let flat = self.flat_index(&index).unwrap_or_else(|| {
+            panic!(
+                "Index {index:?} is out of bounds for a view of shape {:?}",
+                self.shape
+            )
+        });
+        self.element(flat)
+    }
+}
+
+impl Debug for HostTensorView<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HostTensorView")
+            .field("shape", &self.shape)
+            .field("contiguous", &self.exprs.is_none())
+            .finish()
+    }
+}
+
+impl Graph {
+    /// View a node's data through a shape tracker, without copying it.
+    ///
+    /// Returns None if the node has no f32 data on the host.
+    pub fn host_view(&self, id: NodeIndex, mut st: ShapeTracker) -> Option<HostTensorView<'_>> {
+        let data = self.get_tensor_ref(id, 0)?.widened_f32s().ok()?;
+        st.resolve_global_dyn_dims(&self.dyn_map);
+        Some(HostTensorView::new(data, st))
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// View the tensor's data with its permutes, slices, expands and padding applied, without copying it
+    pub fn view(&self) -> HostTensorView<'_> {
+        self.graph()
+            .host_view(self.id, self.shape)
+            .expect("Tensor has no data. Mark it with retrieve() or keep() before executing")
+    }
+}
+
+impl<S: SingleElementShape> GraphTensor<S> {
+    /// The value of a tensor holding a single element, like a loss, after executing. Only scalars and shapes of all 1s
+    /// have this, so it can't be called on a tensor that might hold more.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    /// let loss = a.sum_reduce().retrieve();
+    /// cx.execute();
+    /// assert_eq!(loss.item(), 6.);
+    /// ```
+    /// Tensors that can hold more than one element don't have it:
+    /// ```compile_fail
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]).retrieve();
+    /// cx.execute();
+    /// a.item();
+    /// ```
+    pub fn item(&self) -> f32 {
+        self.view().item()
+    }
+}
+
+impl DynGraphTensor {
+    /// View the tensor's data with its permutes, slices, expands and padding applied, without copying it
+    pub fn view(&self) -> HostTensorView<'_> {
+        self.graph()
+            .host_view(self.id, self.shape)
+            .expect("Tensor has no data. Mark it with retrieve() or keep() before executing")
+    }
+
+    /// The value of a tensor holding a single element, like a loss, after executing. Panics if it holds more.
+    pub fn item(&self) -> f32 {
+        self.view().item()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_host_view() {
+        let mut cx = Graph::new();
+        let a = cx
+            .tensor::<R2<2, 3>>()
+            .set([[1., 2., 3.], [4., 5., 6.]])
+            .keep();
+        cx.execute();
+
+        // Untouched data is read straight through
+        let view = a.view();
+        assert_eq!(view.shape(), &[2, 3]);
+        assert_eq!(view.get(&[1, 2]), Some(6.));
+        assert_eq!(view.get(&[2, 0]), None);
+        assert_eq!(view.get(&[0]), None);
+
+        let padded = a.pad::<R2<3, 3>>(&[(0, 1), (0, 0)]);
+        let view = padded.view();
+        assert_eq!(view.shape(), &[3, 3]);
+        assert_eq!(view[[1, 0]], 4.);
+        assert_eq!(view[[2, 1]], 0.);
+        assert_eq!(view.iter().len(), 9);
+        assert_exact(&view.to_vec(), &[1., 2., 3., 4., 5., 6., 0., 0., 0.]);
+        let sliced = a.slice((.., 1..));
+        assert_exact(&sliced.view().to_vec(), &[2., 3., 5., 6.]);
+
+        let expanded = a
+            .into_dyn()
+            .slice(&[(1, 2), (0, 3)])
+            .expand(0, 2)
+            .permute(&[2, 0, 1]);
+        let view = expanded.view();
+        assert_eq!(view.shape(), &[3, 2, 1]);
+        assert_exact(&view.to_vec(), &expanded.data());
+        assert_exact(&view.iter().collect::<Vec<_>>(), &[4., 4., 5., 5., 6., 6.]);
+    }
+
+    #[test]
+    fn test_item() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+        let loss = a.square().mean_reduce::<_, LAxes2<0, 1>>().retrieve();
+        // A single element picked out by a slice
+        let picked = a.slice((1..2, 2..3)).realize::<R2<1, 1>>().retrieve();
+        let dyn_loss = a.into_dyn().sum_reduce(&[0, 1]).retrieve();
+        cx.execute();
+
+        let data = a.data();
+        assert_item_close(loss, data.iter().map(|x| x * x).sum::<f32>() / 6.);
+        assert_eq!(picked.item(), data[5]);
+        assert_item_close_precision(dyn_loss.typed::<R0>(), data.iter().sum(), 1e-5);
+    }
+
+    #[test]
+    #[should_panic(expected = "item() needs a tensor with one element, this one has shape [2, 3]")]
+    fn test_item_shape() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![0.; 6]).keep();
+        cx.execute();
+        a.into_dyn().item();
+    }
+
+    #[test]
+    #[should_panic(expected = "Index [0, 3] is out of bounds for a view of shape [2, 3]")]
+    fn test_host_view_bounds() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set(vec![0.; 6]).keep();
+        cx.execute();
+        let _ = a.view()[[0, 3]];
+    }
+}
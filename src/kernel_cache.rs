@@ -0,0 +1,265 @@
+use std::{
+    fmt::Display,
+    fs,
+    hash::{Hash, Hasher},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use rustc_hash::{FxHashMap, FxHasher};
+
+use crate::prelude::*;
+
+/// Everything a compiled kernel depends on: the op, the index expressions it reads its inputs with, and the formats
+/// they're in. Backends that generate source can put the whole source in the key instead of picking out the parts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct KernelKey {
+    pub backend: String,
+    pub op: String,
+    /// Index and valid expressions of each input
+    pub expressions: Vec<String>,
+    pub dtypes: Vec<DType>,
+    pub source: String,
+}
+
+impl KernelKey {
+    pub fn new(backend: &str, op: &str) -> Self {
+        Self {
+            backend: backend.to_string(),
+            op: op.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Add how each input is indexed
+    pub fn with_shapes(mut self, shapes: &[ShapeTracker]) -> Self {
+        for shape in shapes {
+            self.expressions
+                .push(format!("{:?}", shape.index_expression()));
+            self.expressions
+                .push(format!("{:?}", shape.valid_expression()));
+        }
+        self
+    }
+
+    pub fn with_dtypes(mut self, dtypes: &[DType]) -> Self {
+        self.dtypes.extend_from_slice(dtypes);
+        self
+    }
+
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = source.to_string();
+        self
+    }
+
+    /// Stable across runs, unlike the std hasher
+    fn stable_hash(&self) -> u64 {
+        let mut hasher = FxHasher::default();
+        self.to_string().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Display for KernelKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Kernels built by another version of luminal might not match what this one expects
+        writeln!(f, "luminal {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(f, "{}/{}", self.backend, self.op)?;
+        writeln!(f, "{}", self.expressions.join(", "))?;
+        writeln!(f, "{:?}", self.dtypes)?;
+        write!(f, "{}", self.source)
+    }
+}
+
+/// Compiled kernels, kept in memory and optionally on disk, so a model doesn't need its kernels compiled again every
+/// time a process starts.
+///
+/// Kernels are stored as whatever bytes the backend's compiler produces, like PTX, one file per kernel under
+/// `<dir>/<backend>/`. Each file holds its whole key, so a hash collision is a miss rather than the wrong kernel.
+/// ```rust
+/// use luminal::prelude::*;
+/// let cache = KernelCache::new();
+/// let key = KernelKey::new("cuda", "Exp2").with_dtypes(&[DType::F16]);
+/// let ptx = cache.get_or_compile(&key, || Ok::<_, ()>(b"...".to_vec())).unwrap();
+/// // Found this time, so compile isn't called
+/// let again = cache.get_or_compile(&key, || Err(())).unwrap();
+/// assert_eq!(ptx, again);
+/// ```
+#[derive(Debug, Default)]
+pub struct KernelCache {
+    dir: Option<PathBuf>,
+    kernels: Mutex<FxHashMap<KernelKey, Arc<[u8]>>>,
+}
+
+impl KernelCache {
+    /// Cache that only keeps kernels in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cache that loads and saves kernels under `dir`
+    pub fn with_dir(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+            ..Default::default()
+        }
+    }
+
+    /// Cache in the directory set by `LUMINAL_CACHE_DIR`, falling back to `luminal/kernels` in the user's cache
+    /// directory. Kernels only stay in memory if neither is known.
+    pub fn from_env() -> Self {
+        let dir = std::env::var_os("LUMINAL_CACHE_DIR")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("XDG_CACHE_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+                    .map(|d| d.join("luminal").join("kernels"))
+            });
+        Self {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    fn path(&self, key: &KernelKey) -> Option<PathBuf> {
+        self.dir.as_ref().map(|d| {
+            d.join(&key.backend)
+                .join(format!("{:016x}", key.stable_hash()))
+        })
+    }
+
+    /// A kernel compiled for `key` before, by this process or an earlier one
+    pub fn get(&self, key: &KernelKey) -> Option<Arc<[u8]>> {
+        if let Some(kernel) = self.kernels.lock().unwrap().get(key) {
+            return Some(kernel.clone());
+        }
+        let bytes = fs::read(self.path(key)?).ok()?;
+        // A corrupt length is a miss, not an overflow
+        let header_len = u64::from_le_bytes(bytes.get(..8)?.try_into().unwrap());
+        let header_end = 8usize.checked_add(usize::try_from(header_len).ok()?)?;
+        let header = bytes.get(8..header_end)?;
+        if header != key.to_string().as_bytes() {
+            return None;
+        }
+        let kernel: Arc<[u8]> = bytes[header_end..].into();
+        self.kernels
+            .lock()
+            .unwrap()
+            .insert(key.clone(), kernel.clone());
+        Some(kernel)
+    }
+
+    /// The kernel for `key`, running `compile` and saving what it returns if it isn't cached yet
+    pub fn get_or_compile<E>(
+        &self,
+        key: &KernelKey,
+        compile: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Arc<[u8]>, E> {
+        if let Some(kernel) = self.get(key) {
+            return Ok(kernel);
+        }
+        let kernel: Arc<[u8]> = compile()?.into();
+        self.kernels
+            .lock()
+            .unwrap()
+            .insert(key.clone(), kernel.clone());
+        // A kernel that can't be saved just gets compiled again next time
+        let _ = self.save(key, &kernel);
+        Ok(kernel)
+    }
+
+    fn save(&self, key: &KernelKey, kernel: &[u8]) -> std::io::Result<()> {
+        let Some(path) = self.path(key) else {
+            return Ok(());
+        };
+        let header = key.to_string();
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header.as_bytes());
+        bytes.extend(kernel);
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Other processes could be reading the same kernel, so it only shows up once it's all written
+        let partial = path.with_extension(format!("{}.partial", std::process::id()));
+        fs::write(&partial, bytes)?;
+        fs::rename(partial, path)
+    }
+
+    /// Forget every kernel, deleting the ones on disk
+    pub fn clear(&self) -> std::io::Result<()> {
+        self.kernels.lock().unwrap().clear();
+        match &self.dir {
+            Some(dir) if dir.exists() => fs::remove_dir_all(dir),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_kernel_cache() {
+        let dir = std::env::temp_dir().join(format!("luminal_kernels_{}", uuid::Uuid::new_v4()));
+        let a = ShapeTracker::new(&[4.into(), 'a'.into()]);
+        let mut b = a;
+        b.permute(&[1, 0]);
+        let key = KernelKey::new("cuda", "Add")
+            .with_shapes(&[a, a])
+            .with_dtypes(&[DType::F16]);
+        let compiles = Cell::new(0);
+        let compile = |kernel: &str| {
+            compiles.set(compiles.get() + 1);
+            Ok::<_, ()>(kernel.as_bytes().to_vec())
+        };
+
+        let cache = KernelCache::with_dir(&dir);
+        let kernel = cache.get_or_compile(&key, || compile("add")).unwrap();
+        assert_eq!(&*kernel, b"add");
+        // Different indexing or formats are different kernels
+        let permuted = KernelKey::new("cuda", "Add")
+            .with_shapes(&[a, b])
+            .with_dtypes(&[DType::F16]);
+        cache
+            .get_or_compile(&permuted, || compile("add_t"))
+            .unwrap();
+        let f32_key = KernelKey {
+            dtypes: vec![DType::F32],
+            ..key.clone()
+        };
+        cache
+            .get_or_compile(&f32_key, || compile("add_f32"))
+            .unwrap();
+        assert_eq!(compiles.get(), 3);
+
+        // A new process finds them on disk
+        let cache = KernelCache::with_dir(&dir);
+        assert_eq!(
+            &*cache.get_or_compile(&key, || compile("")).unwrap(),
+            b"add"
+        );
+        assert_eq!(&*cache.get(&permuted).unwrap(), b"add_t");
+        assert_eq!(compiles.get(), 3);
+        // Errors aren't cached
+        let other = KernelKey::new("cuda", "Mul");
+        assert_eq!(cache.get_or_compile(&other, || Err(())), Err(()));
+        assert!(cache.get(&other).is_none());
+        // So are corrupt files
+        let corrupt = KernelKey::new("cuda", "Corrupt");
+        let path = cache.path(&corrupt).unwrap();
+        let mut bytes = u64::MAX.to_le_bytes().to_vec();
+        bytes.extend(corrupt.to_string().as_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        assert!(cache.get(&corrupt).is_none());
+        assert_eq!(
+            &*cache.get_or_compile(&corrupt, || compile("fixed")).unwrap(),
+            b"fixed"
+        );
+
+        cache.clear().unwrap();
+        assert!(!dir.exists());
+        assert!(KernelCache::with_dir(&dir).get(&key).is_none());
+    }
+}
@@ -1,25 +1,94 @@
+#[cfg(feature = "fft")]
+pub mod audio;
+pub mod autotune;
+pub mod backend;
+pub mod buffer_plan;
+pub mod call;
+pub mod codegen;
+pub mod compiled;
 pub mod compiler_utils;
+pub mod complex;
+pub mod control_flow;
+pub mod debug;
+pub mod device;
+#[cfg(feature = "disk")]
+pub mod disk_tensor;
+pub mod distributed;
+pub mod dtype;
+pub mod dyn_graph_tensor;
+pub mod egraph;
+pub mod error;
+pub mod generate;
 pub mod generic_compiler;
 pub mod graph;
 pub mod graph_tensor;
 pub mod hl_ops;
+pub mod host_view;
+pub mod kernel_cache;
 pub mod module;
+#[cfg(feature = "ndarray")]
+pub mod ndarray_interop;
+#[cfg(feature = "npy")]
+pub mod npy;
 pub mod op;
+pub mod pass_manager;
+pub mod pipeline;
+pub mod queue;
+pub mod registry;
+pub mod sample;
+#[cfg(feature = "serialize")]
+pub mod serialize;
 pub mod shape;
+pub mod sparse;
+pub mod stats;
+#[cfg(feature = "tracing")]
+pub mod trace;
+pub mod verify;
 
 pub mod tests;
 
 pub mod prelude {
+    #[cfg(feature = "fft")]
+    pub use crate::audio::*;
+    pub use crate::autotune::*;
+    pub use crate::backend::{
+        Backend, CopyFromDevice, CopyToDevice, InsertCopies, Kernel, KernelOp,
+    };
+    pub use crate::buffer_plan::*;
+    pub use crate::call::*;
+    pub use crate::compiled::*;
     pub use crate::compiler_utils::*;
+    pub use crate::complex::*;
+    pub use crate::control_flow::*;
+    pub use crate::device::*;
+    pub use crate::distributed::*;
+    pub use crate::dtype::*;
+    pub use crate::dyn_graph_tensor::*;
+    pub use crate::egraph::*;
+    pub use crate::error::*;
+    pub use crate::generate::*;
     pub use crate::generic_compiler::*;
     pub use crate::graph::*;
     pub use crate::graph_tensor::*;
     pub use crate::hl_ops::*;
+    pub use crate::host_view::*;
+    pub use crate::kernel_cache::*;
     pub use crate::module::*;
+    #[cfg(feature = "npy")]
+    pub use crate::npy::*;
     pub use crate::op::*;
+    pub use crate::pass_manager::*;
+    pub use crate::pipeline::*;
+    pub use crate::queue::*;
+    pub use crate::registry::*;
+    pub use crate::sample::*;
     pub use crate::shape::*;
+    pub use crate::sparse::*;
+    pub use crate::stats::*;
     pub use half::{bf16, f16};
     pub use petgraph;
     pub use petgraph::stable_graph::NodeIndex;
     pub use tinyvec;
+    #[cfg(feature = "tokenizers")]
+    pub use tokenizers;
 }
@@ -39,7 +39,7 @@ pub fn transfer_data(
     dests: impl ToIds,
     dest_graph: &mut Graph,
 ) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = src_graph.tensors.remove(&(src, output_num)) {
             dest_graph.tensors.insert((dest, output_num), tensor);
@@ -53,7 +53,7 @@ pub fn transfer_data(
 
 /// Transfer data from one set of nodes to another set in the same graph
 pub fn transfer_data_same_graph(srcs: impl ToIds, dests: impl ToIds, graph: &mut Graph) {
-    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids().into_iter()) {
+    for (src, dest) in srcs.to_ids().into_iter().zip(dests.to_ids()) {
         let mut output_num = 0;
         while let Some(tensor) = graph.tensors.remove(&(src, output_num)) {
             graph.tensors.insert((dest, output_num), tensor);
@@ -240,6 +240,8 @@ impl<T: SerializeModule> SerializeModule for &T {
 pub struct Serializer {
     current_path: Vec<String>,
     pub state: FxHashMap<String, NodeIndex>,
+    /// Shape of each tensor, by the same names as `state`
+    pub shapes: FxHashMap<String, ShapeTracker>,
 }
 
 impl Serializer {
@@ -249,7 +251,9 @@ impl Serializer {
             self.current_path.push(name.to_string());
         }
         // Insert tensor id
-        self.state.insert(self.current_path.join("/"), tensor.id);
+        let path = self.current_path.join("/");
+        self.shapes.insert(path.clone(), tensor.shape);
+        self.state.insert(path, tensor.id);
         if !name.is_empty() {
             // Remove new path component
             self.current_path.pop();
@@ -0,0 +1,140 @@
+use ndarray::{ArrayD, ArrayViewD, IxDyn, ShapeBuilder};
+
+use crate::prelude::*;
+
+impl<S: Shape> GraphTensor<S> {
+    /// Set the value of the tensor from an ndarray array. Dynamic dimensions are resolved from the array's shape.
+    ///
+    /// Arrays in standard (row-major) layout hand their buffer over without a copy. Other layouts are copied into row-major order.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx
+    ///     .tensor::<(Dyn<'a'>, Const<2>)>()
+    ///     .set_ndarray(ndarray::ArrayD::zeros(vec![3, 2]));
+    /// ```
+    pub fn set_ndarray(self, array: ArrayD<f32>) -> Self {
+        let shape = array.shape().to_vec();
+        let data = if array.is_standard_layout() {
+            let len = array.len();
+            let (mut data, offset) = array.into_raw_vec_and_offset();
+            data.drain(..offset.unwrap_or_default());
+            data.truncate(len);
+            data
+        } else {
+            array.iter().copied().collect()
+        };
+        self.set_dyn(data, &shape)
+    }
+
+    /// Get a strided view into the tensor's data, with this tensor's permutes, expands and slices applied.
+    ///
+    /// Returns None if the tensor has no data, or if its shape can't be represented with strides (padding).
+    pub fn ndarray_view(&self) -> Option<ArrayViewD<'_, f32>> {
+        let data = self.graph().get_tensor_ref(self.id, 0)?.as_f32_slice()?;
+        let mut st = self.shape;
+        if st.is_padded() {
+            return None;
+        }
+        st.resolve_global_dyn_dims(&self.graph().dyn_map);
+        // Row-major strides of the physical dimensions. Fake dimensions don't take up memory.
+        let mut physical_strides = vec![0; st.len()];
+        let mut stride = 1;
+        for i in (0..st.len()).rev() {
+            if !st.fake[i] {
+                physical_strides[i] = stride;
+                stride *= st.dims[i].to_usize()?;
+            }
+        }
+        let (mut shape, mut strides, mut offset) = (vec![], vec![], 0);
+        for i in st.indexes {
+            let (start, end) = (st.mask[i].0.to_usize()?, st.mask[i].1.to_usize()?);
+            let end = end.min(st.dims[i].to_usize()?);
+            shape.push(end.saturating_sub(start));
+            strides.push(physical_strides[i]);
+            offset += start * physical_strides[i];
+        }
+        ArrayViewD::from_shape(IxDyn(&shape).strides(IxDyn(&strides)), data.get(offset..)?).ok()
+    }
+
+    /// Get the tensor's data as an ndarray array. The tensor must have been retrieved or kept before execution.
+    pub fn retrieve_ndarray(&self) -> ArrayD<f32> {
+        if let Some(view) = self.ndarray_view() {
+            return view.to_owned();
+        }
+        let mut st = self.shape;
+        st.resolve_global_dyn_dims(&self.graph().dyn_map);
+        ArrayD::from_shape_vec(st.shape_usize(), self.data()).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+    use ndarray::{s, Array, Axis as NAxis, IxDyn};
+
+    #[test]
+    fn test_set_ndarray() {
+        let mut cx = Graph::new();
+        let arr = Array::from_shape_vec((2, 3), vec![1., 2., 3., 4., 5., 6.])
+            .unwrap()
+            .into_dyn();
+        let a = cx
+            .tensor::<(Dyn<'a'>, LConst<3>)>()
+            .set_ndarray(arr.clone());
+        // Permuted layout needs to be copied into row-major order
+        let b = cx
+            .tensor::<(LConst<3>, Dyn<'b'>)>()
+            .set_ndarray(arr.clone().reversed_axes());
+        // Sliced arrays drop the elements outside of the slice
+        let c = cx
+            .tensor::<R1<2>>()
+            .set_ndarray(arr.slice_move(s![1, 1..]).into_dyn());
+        let (a, b, c) = (a.retrieve(), b.retrieve(), c.retrieve());
+        cx.execute();
+
+        assert_eq!(cx.dyn_map[&'a'], 2);
+        assert_eq!(cx.dyn_map[&'b'], 2);
+        assert_exact(&a.data(), &[1., 2., 3., 4., 5., 6.]);
+        assert_exact(&b.data(), &[1., 4., 2., 5., 3., 6.]);
+        assert_exact(&c.data(), &[5., 6.]);
+    }
+
+    #[test]
+    fn test_retrieve_ndarray() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+        let b = (a * 2.).retrieve();
+        let permuted = b.permute::<R2<3, 2>, LAxes2<1, 0>>();
+        let sliced = b.slice((.., 1..));
+        let expanded = b.expand::<R3<2, 2, 3>, LAxis<1>>();
+        let padded = b.pad::<R2<2, 4>>(((0, 0), (1, 0)));
+        cx.execute();
+
+        let b_arr = b.retrieve_ndarray();
+        assert_eq!(b_arr.shape(), &[2, 3]);
+        assert_exact(b_arr.as_slice().unwrap(), &[2., 4., 6., 8., 10., 12.]);
+
+        assert_eq!(permuted.ndarray_view().unwrap(), b_arr.t());
+        assert_eq!(
+            sliced.ndarray_view().unwrap(),
+            b_arr
+                .slice(s![.., 1..])
+                .into_dimensionality::<IxDyn>()
+                .unwrap()
+        );
+        assert_eq!(
+            expanded.ndarray_view().unwrap(),
+            b_arr
+                .insert_axis(NAxis(1))
+                .broadcast(vec![2, 2, 3])
+                .unwrap()
+        );
+        // Padding can't be viewed, so it gets materialized
+        assert!(padded.ndarray_view().is_none());
+        assert_exact(
+            padded.retrieve_ndarray().as_slice().unwrap(),
+            &[0., 2., 4., 6., 0., 8., 10., 12.],
+        );
+    }
+}
@@ -0,0 +1,386 @@
+use std::{
+    io::{Cursor, Read, Write},
+    path::Path,
+};
+
+use itertools::Itertools;
+use regex::Regex;
+use rustc_hash::FxHashMap;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::prelude::*;
+
+/// An array read from or written to numpy's .npy format, with its data converted to f32 in row-major order.
+///
+/// Any numeric numpy dtype can be read. f16 and u8 arrays keep their format in `dtype`, so they're written back the same
+/// way and graph tensors set from them hold it. Everything else is written as f32.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<(Dyn<'s'>, Const<2>)>();
+/// let array = NpyArray::new(vec![1., 2., 3., 4.], vec![2, 2]);
+/// let b = (a.set_npy(NpyArray::from_bytes(&array.to_bytes()).unwrap()) * 2.).retrieve();
+/// cx.execute();
+/// assert_eq!(b.npy().data, vec![2., 4., 6., 8.]);
+/// assert_eq!(b.npy().shape, vec![2, 2]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct NpyArray {
+    pub data: Vec<f32>,
+    pub shape: Vec<usize>,
+    pub dtype: DType,
+}
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+impl NpyArray {
+    /// An f32 array
+    pub fn new(data: Vec<f32>, shape: Vec<usize>) -> Self {
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "Got {} elements for shape {shape:?}",
+            data.len()
+        );
+        Self {
+            data,
+            shape,
+            dtype: DType::F32,
+        }
+    }
+
+    /// Parse the contents of a .npy file
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, LuminalError> {
+        if bytes.get(..6) != Some(MAGIC) {
+            return Err(bad("Not an .npy file"));
+        }
+        // Version 1 has a two byte header length, later versions four
+        let (start, len_bytes) = (8, if bytes.get(6) == Some(&1) { 2 } else { 4 });
+        let header_len = bytes
+            .get(start..start + len_bytes)
+            .map(|b| b.iter().rev().fold(0, |len, b| len << 8 | *b as usize))
+            .ok_or_else(|| bad("File is too short"))?;
+        let data_start = start + len_bytes + header_len;
+        let header = bytes
+            .get(start + len_bytes..data_start)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .ok_or_else(|| bad("Header is cut off or isn't text"))?;
+        let field = |pattern: &str| {
+            Regex::new(pattern)
+                .unwrap()
+                .captures(header)
+                .map(|c| c[1].to_string())
+                .ok_or_else(|| bad(&format!("Header has no {pattern}")))
+        };
+        let descr = field(r"'descr':\s*'([^']*)'")?;
+        let fortran_order = field(r"'fortran_order':\s*(True|False)")? == "True";
+        let shape = field(r"'shape':\s*\(([^)]*)\)")?
+            .split(',')
+            .map(str::trim)
+            .filter(|d| !d.is_empty())
+            .map(|d| d.parse::<usize>().map_err(|_| bad("Shape isn't integers")))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut descr = descr.chars();
+        let little_endian = descr.next() != Some('>');
+        let kind = descr.next().unwrap_or_default();
+        let size = descr
+            .as_str()
+            .parse::<usize>()
+            .map_err(|_| bad("Unknown dtype"))?;
+        let convert = converter(kind, size)
+            .ok_or_else(|| bad(&format!("Can't read {kind}{size} elements")))?;
+        let n_elements = shape.iter().product::<usize>();
+        let raw = bytes
+            .get(data_start..data_start + n_elements * size)
+            .ok_or_else(|| bad("Data is cut off"))?;
+        let mut data = raw
+            .chunks_exact(size)
+            .map(|chunk| {
+                let mut element = [0; 8];
+                element[..size].copy_from_slice(chunk);
+                if !little_endian {
+                    element[..size].reverse();
+                }
+                convert(&element[..size])
+            })
+            .collect::<Vec<_>>();
+        if fortran_order {
+            data = column_major_to_row_major(&data, &shape);
+        }
+        let dtype = match (kind, size) {
+            ('f', 2) => DType::F16,
+            ('u', 1) => DType::U8,
+            _ => DType::F32,
+        };
+        Ok(Self { data, shape, dtype })
+    }
+
+    /// The contents of a .npy file holding this array
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (descr, elements) = match self.dtype {
+            DType::F16 => (
+                "<f2",
+                self.data
+                    .iter()
+                    .flat_map(|x| f16::from_f32(*x).to_le_bytes())
+                    .collect::<Vec<_>>(),
+            ),
+            DType::U8 => (
+                "|u1",
+                self.data
+                    .iter()
+                    .map(|x| DType::U8.round(*x) as u8)
+                    .collect(),
+            ),
+            // numpy has no bf16, but f32 holds its values exactly
+            DType::F32 | DType::BF16 => (
+                "<f4",
+                self.data.iter().flat_map(|x| x.to_le_bytes()).collect(),
+            ),
+        };
+        let shape = match self.shape.as_slice() {
+            [d] => format!("({d},)"),
+            dims => format!("({})", dims.iter().join(", ")),
+        };
+        let mut header =
+            format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': {shape}, }}");
+        // The data starts on a multiple of 64 bytes, after a header padded with spaces and ended with a newline
+        let prefix_len = if header.len() + 11 > u16::MAX as usize {
+            12
+        } else {
+            10
+        };
+        let padding = (64 - (prefix_len + header.len() + 1) % 64) % 64;
+        header.extend(std::iter::repeat(' ').take(padding));
+        header.push('\n');
+
+        let mut bytes = MAGIC.to_vec();
+        if prefix_len == 10 {
+            bytes.extend([1, 0]);
+            bytes.extend((header.len() as u16).to_le_bytes());
+        } else {
+            bytes.extend([2, 0]);
+            bytes.extend((header.len() as u32).to_le_bytes());
+        }
+        bytes.extend(header.as_bytes());
+        bytes.extend(elements);
+        bytes
+    }
+
+    /// Read a .npy file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, LuminalError> {
+        Self::from_bytes(&std::fs::read(path).map_err(|e| bad(&e.to_string()))?)
+    }
+
+    /// Write this array to a .npy file
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), LuminalError> {
+        std::fs::write(path, self.to_bytes()).map_err(|e| bad(&e.to_string()))
+    }
+}
+
+fn bad(message: &str) -> LuminalError {
+    LuminalError::BadNpyFile(message.to_string())
+}
+
+/// Turns the little-endian bytes of an element of a numpy dtype into an f32
+fn converter(kind: char, size: usize) -> Option<fn(&[u8]) -> f32> {
+    Some(match (kind, size) {
+        ('f', 2) => |b| f16::from_le_bytes([b[0], b[1]]).to_f32(),
+        ('f', 4) => |b| f32::from_le_bytes(b.try_into().unwrap()),
+        ('f', 8) => |b| f64::from_le_bytes(b.try_into().unwrap()) as f32,
+        ('i', 1) => |b| b[0] as i8 as f32,
+        ('i', 2) => |b| i16::from_le_bytes([b[0], b[1]]) as f32,
+        ('i', 4) => |b| i32::from_le_bytes(b.try_into().unwrap()) as f32,
+        ('i', 8) => |b| i64::from_le_bytes(b.try_into().unwrap()) as f32,
+        ('u', 1) | ('b', 1) => |b| b[0] as f32,
+        ('u', 2) => |b| u16::from_le_bytes([b[0], b[1]]) as f32,
+        ('u', 4) => |b| u32::from_le_bytes(b.try_into().unwrap()) as f32,
+        ('u', 8) => |b| u64::from_le_bytes(b.try_into().unwrap()) as f32,
+        _ => return None,
+    })
+}
+
+fn column_major_to_row_major(data: &[f32], shape: &[usize]) -> Vec<f32> {
+    let mut strides = vec![1; shape.len()];
+    for i in 1..shape.len() {
+        strides[i] = strides[i - 1] * shape[i - 1];
+    }
+    (0..data.len())
+        .map(|mut index| {
+            let mut offset = 0;
+            for (dim, stride) in shape.iter().zip(&strides).rev() {
+                offset += index % dim * stride;
+                index /= dim;
+            }
+            data[offset]
+        })
+        .collect()
+}
+
+/// Read the arrays in a .npz file (what `np.savez` and `np.savez_compressed` write), by name
+pub fn load_npz(path: impl AsRef<Path>) -> Result<FxHashMap<String, NpyArray>, LuminalError> {
+    let file = std::fs::File::open(path).map_err(|e| bad(&e.to_string()))?;
+    read_npz(file)
+}
+
+fn read_npz(
+    reader: impl Read + std::io::Seek,
+) -> Result<FxHashMap<String, NpyArray>, LuminalError> {
+    let mut archive = ZipArchive::new(reader).map_err(|e| bad(&e.to_string()))?;
+    let mut arrays = FxHashMap::default();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(|e| bad(&e.to_string()))?;
+        let name = file.name().trim_end_matches(".npy").to_string();
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)
+            .map_err(|e| bad(&e.to_string()))?;
+        arrays.insert(name, NpyArray::from_bytes(&bytes)?);
+    }
+    Ok(arrays)
+}
+
+/// Write arrays to a .npz file under their names, uncompressed like `np.savez`
+pub fn save_npz(path: impl AsRef<Path>, arrays: &[(&str, &NpyArray)]) -> Result<(), LuminalError> {
+    let mut zip = ZipWriter::new(Cursor::new(vec![]));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for (name, array) in arrays {
+        zip.start_file(format!("{name}.npy"), options)
+            .map_err(|e| bad(&e.to_string()))?;
+        zip.write_all(&array.to_bytes())
+            .map_err(|e| bad(&e.to_string()))?;
+    }
+    let bytes = zip.finish().map_err(|e| bad(&e.to_string()))?.into_inner();
+    std::fs::write(path, bytes).map_err(|e| bad(&e.to_string()))
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Set the value of the tensor from an array read with `NpyArray::load` or `load_npz`. Dynamic dimensions are
+    /// resolved from the array's shape, and f16 or u8 arrays are held in that format.
+    pub fn set_npy(self, array: NpyArray) -> Self {
+        let tensor = if array.dtype == DType::F32 {
+            self
+        } else {
+            self.with_dtype(array.dtype)
+        };
+        tensor.set_dyn(array.data, &array.shape)
+    }
+
+    /// The tensor's data as an array to save with `NpyArray::save` or `save_npz`. The tensor must have been retrieved or
+    /// kept before execution.
+    pub fn npy(&self) -> NpyArray {
+        let shape = self
+            .shape
+            .shape()
+            .iter()
+            .map(|d| {
+                d.exec(&self.graph().dyn_map)
+                    .expect("The tensor's shape isn't known")
+            })
+            .collect();
+        NpyArray {
+            data: self.data(),
+            shape,
+            dtype: self.graph().dtype(self.id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    crate::test_imports!();
+
+    use super::read_npz;
+
+    #[test]
+    fn test_npy_round_trip() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'b'>, LConst<3>)>();
+        let data = random_vec(6);
+        let array = NpyArray::new(data.clone(), vec![2, 3]);
+        let b = (a.set_npy(NpyArray::from_bytes(&array.to_bytes()).unwrap()) * 2.)
+            .permute::<(LConst<3>, Dyn<'b'>), _>()
+            .retrieve();
+        cx.execute();
+
+        let out = NpyArray::from_bytes(&b.npy().to_bytes()).unwrap();
+        assert_eq!(out.shape, vec![3, 2]);
+        assert_eq!(out.dtype, DType::F32);
+        let expected = (0..6)
+            .map(|i| data[(i % 2) * 3 + i / 2] * 2.)
+            .collect::<Vec<_>>();
+        assert_exact(&out.data, &expected);
+
+        // Formats numpy has are kept, and scalars have an empty shape
+        for (dtype, value) in [(DType::F16, 0.5), (DType::U8, 200.)] {
+            let array = NpyArray {
+                data: vec![value],
+                shape: vec![],
+                dtype,
+            };
+            let bytes = array.to_bytes();
+            assert_eq!(bytes.len() % 64, dtype.size() % 64);
+            assert_eq!(NpyArray::from_bytes(&bytes).unwrap(), array);
+        }
+    }
+
+    #[test]
+    fn test_npy_dtypes() {
+        // A big-endian f64 array in column-major order, as numpy would write np.asfortranarray of [[1, 2, 3], [4, 5, 6]]
+        let header = "{'descr': '>f8', 'fortran_order': True, 'shape': (2, 3), }";
+        let mut bytes = b"\x93NUMPY\x01\x00".to_vec();
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        for x in [1., 4., 2., 5., 3., 6.] {
+            bytes.extend(f64::to_be_bytes(x));
+        }
+        let array = NpyArray::from_bytes(&bytes).unwrap();
+        assert_eq!(array.shape, vec![2, 3]);
+        assert_exact(&array.data, &[1., 2., 3., 4., 5., 6.]);
+
+        // Integers, in a version 2 file
+        let header = "{'descr': '<i2', 'fortran_order': False, 'shape': (3,), }";
+        let mut bytes = b"\x93NUMPY\x02\x00".to_vec();
+        bytes.extend((header.len() as u32).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        for x in [-3i16, 0, 7] {
+            bytes.extend(x.to_le_bytes());
+        }
+        assert_exact(&NpyArray::from_bytes(&bytes).unwrap().data, &[-3., 0., 7.]);
+
+        assert!(matches!(
+            NpyArray::from_bytes(b"PK\x03\x04"),
+            Err(LuminalError::BadNpyFile(_))
+        ));
+        assert!(NpyArray::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_npz() {
+        let weight = NpyArray::new(random_vec(4), vec![2, 2]);
+        let bias = NpyArray {
+            data: vec![1., 2.],
+            shape: vec![2],
+            dtype: DType::U8,
+        };
+        let path = std::env::temp_dir().join(format!("luminal_{}.npz", std::process::id()));
+        save_npz(&path, &[("weight", &weight), ("bias", &bias)]).unwrap();
+        let arrays = load_npz(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(arrays.len(), 2);
+        assert_eq!(arrays["weight"], weight);
+        assert_eq!(arrays["bias"], bias);
+
+        // np.savez_compressed deflates each array
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        zip.start_file("weight.npy", options).unwrap();
+        zip.write_all(&weight.to_bytes()).unwrap();
+        let bytes = zip.finish().unwrap().into_inner();
+        assert_eq!(read_npz(Cursor::new(bytes)).unwrap()["weight"], weight);
+    }
+}
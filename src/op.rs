@@ -1,6 +1,6 @@
 use std::{
     any::Any,
-    borrow::BorrowMut,
+    borrow::{BorrowMut, Cow},
     fmt::Debug,
     sync::{Arc, Mutex},
 };
@@ -10,7 +10,8 @@ use crate::prelude::*;
 use dyn_clone::{clone_trait_object, DynClone};
 use rustc_hash::FxHashMap;
 
-/// A tensor with data. The data can be anything that implements the Data trait
+/// A tensor with data. The data can be anything that implements the Data trait, which describes its dtype, size, device
+/// and bytes. Backend types like device buffers are still reached by downcasting, through the checked `get` accessors.
 #[derive(Debug, Clone)]
 pub struct Tensor {
     data: Box<dyn Data>,
@@ -31,16 +32,111 @@ impl Tensor {
     pub fn is<T: Data>(&self) -> bool {
         self.data.as_any().is::<T>()
     }
+    /// The data as a `T`, or an error describing what it actually is
+    pub fn get<T: Data>(&self) -> Result<&T, LuminalError> {
+        let found = self.describe();
+        self.downcast_ref().ok_or(LuminalError::WrongData {
+            expected: std::any::type_name::<T>(),
+            found,
+        })
+    }
+    /// The data as a mutable `T`, or an error describing what it actually is
+    pub fn get_mut<T: Data>(&mut self) -> Result<&mut T, LuminalError> {
+        let found = self.describe();
+        self.downcast_mut().ok_or(LuminalError::WrongData {
+            expected: std::any::type_name::<T>(),
+            found,
+        })
+    }
+    /// Get the data as a slice of f32s, if it's either a `Vec<f32>` or `SharedData`
+    pub fn as_f32_slice(&self) -> Option<&[f32]> {
+        if let Some(v) = self.downcast_ref::<Vec<f32>>() {
+            return Some(v);
+        }
+        self.downcast_ref::<SharedData>().map(|d| (*d.0).as_ref())
+    }
+    /// The data as f32s on the host, or an error describing what it actually is
+    pub fn f32s(&self) -> Result<&[f32], LuminalError> {
+        self.as_f32_slice().ok_or_else(|| LuminalError::WrongData {
+            expected: "f32 data on the host",
+            found: self.describe(),
+        })
+    }
+    /// The data as f32s on the host, widening half precision buffers, or an error describing what it actually is
+    pub fn widened_f32s(&self) -> Result<Cow<'_, [f32]>, LuminalError> {
+        if let Some(data) = self.downcast_ref::<Vec<f16>>() {
+            return Ok(Cow::Owned(data.iter().map(|x| x.to_f32()).collect()));
+        }
+        if let Some(data) = self.downcast_ref::<Vec<bf16>>() {
+            return Ok(Cow::Owned(data.iter().map(|x| x.to_f32()).collect()));
+        }
+        self.f32s().map(Cow::Borrowed)
+    }
+    pub fn dtype(&self) -> DType {
+        self.data.dtype()
+    }
+    /// Number of elements
+    pub fn len(&self) -> usize {
+        self.data.size() / self.dtype().size()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.data.size() == 0
+    }
+    /// Kind of memory the data is in, like "cpu" or "cuda"
+    pub fn device(&self) -> &'static str {
+        self.data.device()
+    }
+    /// The data's bytes, if it's in host memory
+    pub fn bytes(&self) -> Option<&[u8]> {
+        self.data.bytes()
+    }
+    fn describe(&self) -> String {
+        format!(
+            "{} {:?} elements on {}",
+            self.len(),
+            self.dtype(),
+            self.device()
+        )
+    }
 }
 
-/// Some sort of data, for instance a Vec<f32> on CPU, CudaSlice<f32> on Nvidia GPUs, or metal::Buffer for Apple GPUs
-pub trait Data: Any + Debug + DynClone {
+/// Some sort of data, for instance a Vec<f32> on CPU, CudaSlice<f32> on Nvidia GPUs, or metal::Buffer for Apple GPUs.
+///
+/// Besides the data itself, it describes what it holds, so a tensor of the wrong kind can be reported instead of just
+/// failing to downcast.
+pub trait Data: Any + Debug + DynClone + Send + Sync {
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Size of the data in bytes. Data that isn't in host memory needs to override this.
+    fn size(&self) -> usize {
+        self.bytes().map_or(0, <[u8]>::len)
+    }
+    /// Format of each element
+    fn dtype(&self) -> DType {
+        DType::F32
+    }
+    /// Kind of memory the data is in, like "cpu" or "cuda"
+    fn device(&self) -> &'static str {
+        "cpu"
+    }
+    /// The data's bytes, if it's in host memory
+    fn bytes(&self) -> Option<&[u8]> {
+        None
+    }
 }
 
 clone_trait_object!(Data);
 
+/// Bytes of f32s, in native byte order
+fn f32_bytes(data: &[f32]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
+
+/// Bytes of f16s or bf16s, in native byte order
+fn half_bytes<T: Copy>(data: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data)) }
+}
+
 impl Data for Vec<f32> {
     fn as_any(&self) -> &dyn Any {
         self
@@ -48,6 +144,84 @@ impl Data for Vec<f32> {
     fn as_any_mut(&mut self) -> &mut dyn Any {
         self
     }
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(f32_bytes(self))
+    }
+}
+
+impl Data for Vec<f16> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn dtype(&self) -> DType {
+        DType::F16
+    }
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(half_bytes(self))
+    }
+}
+
+impl Data for Vec<bf16> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn dtype(&self) -> DType {
+        DType::BF16
+    }
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(half_bytes(self))
+    }
+}
+
+/// Read-only f32 data owned outside of the graph, like an `Arc<[f32]>` or a memory-mapped weight file.
+///
+/// Cloning only clones the reference, so setting this as a tensor's data never duplicates the underlying buffer.
+/// ```rust
+/// use luminal::prelude::*;
+/// use std::sync::Arc;
+/// let weights: Arc<[f32]> = vec![1., 2., 3.].into();
+/// let mut cx = Graph::new();
+/// let a = cx
+///     .tensor::<(Dyn<'a'>,)>()
+///     .set_dyn(SharedData::from(weights.clone()), &[3]);
+/// ```
+#[derive(Clone)]
+pub struct SharedData(pub Arc<dyn AsRef<[f32]> + Send + Sync>);
+
+impl SharedData {
+    pub fn new<T: AsRef<[f32]> + Send + Sync + 'static>(data: T) -> Self {
+        Self(Arc::new(data))
+    }
+}
+
+impl From<Arc<[f32]>> for SharedData {
+    fn from(value: Arc<[f32]>) -> Self {
+        Self::new(value)
+    }
+}
+
+impl Debug for SharedData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SharedData({} elements)", (*self.0).as_ref().len())
+    }
+}
+
+impl Data for SharedData {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn bytes(&self) -> Option<&[u8]> {
+        Some(f32_bytes((*self.0).as_ref()))
+    }
 }
 
 /// Either an owned or borrowed tensor that gets consumed by ops
@@ -82,11 +256,38 @@ impl<'a> InputTensor<'a> {
 pub trait Operator: Debug + as_any::AsAny {
     /// Process the input tensors and produce output tensors
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>;
+    /// Process the input tensors, returning an error instead of panicking if they can't be. This is what the graph runs
+    /// ops with, passing in the dynamic dimensions of the run. Ops that can fail on their inputs (like getting data of
+    /// the wrong type), or that need dimensions their input shapes don't carry, should implement it.
+    #[allow(unused)]
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        Ok(self.process(inp))
+    }
     /// Implement custom functionality
     #[allow(unused)]
     fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
         None
     }
+    /// The shape of the first output given the shapes of the inputs, or None if it can't be known from them (like for loads)
+    #[allow(unused)]
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        None
+    }
+    /// Rough cost of running once on inputs of these shapes, or None if there's no estimate. Dynamic dimensions need to be
+    /// resolved first.
+    #[allow(unused)]
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        None
+    }
+    /// This op as one that can be moved between threads, or None if it can't be. Only ops that give themselves back
+    /// here can be frozen into a `CompiledGraph`, so ops that are `Send` should return `Some(self)`.
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        None
+    }
 }
 
 impl<T: Operator> Operator for Box<T> {
@@ -96,6 +297,22 @@ impl<T: Operator> Operator for Box<T> {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         <T as Operator>::process(self, inp)
     }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        <T as Operator>::try_process(self, inp, dyn_map)
+    }
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        <T as Operator>::infer_shape(self, input_shapes)
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        <T as Operator>::cost(self, input_shapes)
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        <T as Operator>::into_send(*self)
+    }
 }
 impl<T: Operator> Operator for Arc<Mutex<T>> {
     fn custom(&mut self, key: &str, input: Box<dyn Any>) -> Option<Box<dyn Any>> {
@@ -104,13 +321,65 @@ impl<T: Operator> Operator for Arc<Mutex<T>> {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         <T as Operator>::process(self.lock().unwrap().borrow_mut(), inp)
     }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        <T as Operator>::try_process(self.lock().unwrap().borrow_mut(), inp, dyn_map)
+    }
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        <T as Operator>::infer_shape(&self.lock().unwrap(), input_shapes)
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        <T as Operator>::cost(&self.lock().unwrap(), input_shapes)
+    }
+}
+
+/// Estimated work done by one run of an op, so compilers can compare plans. Bytes count 32 bit elements, and reads count
+/// the physical elements of each input, so broadcasts are only read once.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OpCost {
+    pub flops: usize,
+    pub bytes_read: usize,
+    pub bytes_written: usize,
+}
+
+impl OpCost {
+    /// An op doing `flops_per_element` operations for each element of its output, which is shaped like its first input
+    pub fn elementwise(input_shapes: &[ShapeTracker], flops_per_element: usize) -> Option<Self> {
+        let n = input_shapes.first()?.n_elements().to_usize()?;
+        Some(Self {
+            flops: n * flops_per_element,
+            bytes_read: bytes_read(input_shapes)?,
+            bytes_written: n * 4,
+        })
+    }
+
+    /// An op reducing away dimension `dim` of its first input, with one operation per input element
+    pub fn reduce(input_shapes: &[ShapeTracker], dim: usize) -> Option<Self> {
+        let n = input_shapes.first()?.n_elements().to_usize()?;
+        let dim_size = input_shapes[0].shape()[dim].to_usize()?;
+        Some(Self {
+            flops: n,
+            bytes_read: bytes_read(input_shapes)?,
+            bytes_written: n / dim_size.max(1) * 4,
+        })
+    }
+}
+
+fn bytes_read(input_shapes: &[ShapeTracker]) -> Option<usize> {
+    input_shapes
+        .iter()
+        .map(|s| s.n_physical_elements().to_usize().map(|n| n * 4))
+        .sum()
 }
 
 /// An opaque function running on CPU that takes in Vec<f32> tensors and outputs Vec<f32> tensors
 #[allow(clippy::type_complexity)]
 pub struct Function(
     pub String,
-    pub Box<dyn Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor>>,
+    pub Box<dyn Fn(Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> + Send>,
 );
 
 impl PartialEq for Function {
@@ -123,6 +392,9 @@ impl Operator for Function {
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
         (self.1)(inp)
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
 }
 
 impl Debug for Function {
@@ -138,9 +410,10 @@ pub enum ConstantValue {
     Float(f32),
 }
 
-/// Produces a single number constant from an expression or a float
+/// Produces a single number constant from an expression or a float. Expressions are evaluated with the dimensions of
+/// each run.
 #[derive(Clone, PartialEq)]
-pub struct Constant(pub ConstantValue, pub *const FxHashMap<char, usize>);
+pub struct Constant(pub ConstantValue);
 impl Debug for Constant {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "Constant(",)?;
@@ -153,13 +426,24 @@ impl Debug for Constant {
 }
 
 impl Operator for Constant {
-    fn process(&mut self, _: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        vec![Tensor::new(vec![match &self.0 {
-            ConstantValue::Expression(e) => {
-                e.exec(unsafe { self.1.as_ref().unwrap() }).unwrap() as f32
-            }
+    fn infer_shape(&self, _: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(ShapeTracker::new(&[]))
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        _: Vec<(InputTensor, ShapeTracker)>,
+        dyn_map: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        Ok(vec![Tensor::new(vec![match &self.0 {
+            ConstantValue::Expression(e) => e.try_exec(dyn_map)? as f32,
             ConstantValue::Float(f) => *f,
-        }])]
+        }])])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
@@ -169,91 +453,276 @@ impl Operator for Constant {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Contiguous;
 impl Operator for Contiguous {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 0)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         // Copy data over to new tensor
-        let inp_data = get_vec(&inp[0].0);
+        let inp_data = get_vec(&inp[0].0)?;
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i);
+            *out = get_index(&inp_data, &expr, &mut stack, i);
+        }
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Formats tensor values can be held in.
+///
+/// Half precision (`F16` and `BF16`) tensors are stored in half precision buffers, taking half the memory. Ops without
+/// a half precision path widen them to f32 as they read them. `U8` values are rounded, but still held as f32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum DType {
+    F32,
+    F16,
+    BF16,
+    U8,
+}
+
+impl DType {
+    /// Round a value to the nearest one this format can represent
+    pub fn round(&self, x: f32) -> f32 {
+        match self {
+            DType::F32 => x,
+            DType::F16 => f16::from_f32(x).to_f32(),
+            DType::BF16 => bf16::from_f32(x).to_f32(),
+            DType::U8 => x.round().clamp(0., 255.),
+        }
+    }
+
+    /// Bytes taken by each element
+    pub fn size(&self) -> usize {
+        match self {
+            DType::F32 => 4,
+            DType::F16 | DType::BF16 => 2,
+            DType::U8 => 1,
         }
-        vec![Tensor::new(out_data)]
+    }
+}
+
+/// Element types tensors can be cast to
+pub trait HasDType {
+    const DTYPE: DType;
+}
+
+impl HasDType for f32 {
+    const DTYPE: DType = DType::F32;
+}
+
+impl HasDType for f16 {
+    const DTYPE: DType = DType::F16;
+}
+
+impl HasDType for bf16 {
+    const DTYPE: DType = DType::BF16;
+}
+
+/// Convert values to another format. Half precision outputs are `Vec<f16>` or `Vec<bf16>` buffers, and `U8` ones are
+/// rounded but kept as f32.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cast(pub DType);
+impl Operator for Cast {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        let mut cost = OpCost::elementwise(input_shapes, 1)?;
+        if matches!(self.0, DType::F16 | DType::BF16) {
+            cost.bytes_written = cost.bytes_written / 4 * 2;
+        }
+        Some(cost)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let inp_data = get_vec(&inp[0].0)?;
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        let values = (0..inp[0].1.n_elements().to_usize().unwrap())
+            .map(|i| get_index(&inp_data, &expr, &mut stack, i));
+        Ok(vec![match self.0 {
+            DType::F16 => Tensor::new(values.map(f16::from_f32).collect::<Vec<_>>()),
+            DType::BF16 => Tensor::new(values.map(bf16::from_f32).collect::<Vec<_>>()),
+            dtype => Tensor::new(values.map(|x| dtype.round(x)).collect::<Vec<_>>()),
+        }])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Log2;
 impl Operator for Log2 {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
+        let inp_data = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).log2();
+            *out = get_index(&inp_data, &expr, &mut stack, i).log2();
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Exp2;
 impl Operator for Exp2 {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
+        let inp_data = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).exp2();
+            *out = get_index(&inp_data, &expr, &mut stack, i).exp2();
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Sin;
 impl Operator for Sin {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
+        let inp_data = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).sin();
+            *out = get_index(&inp_data, &expr, &mut stack, i).sin();
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Recip;
 impl Operator for Recip {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
+        let inp_data = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).recip();
+            *out = get_index(&inp_data, &expr, &mut stack, i).recip();
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Sqrt;
 impl Operator for Sqrt {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
-        let inp_data = get_vec(&inp[0].0);
+        let inp_data = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(inp_data, &expr, &mut stack, i).sqrt();
+            *out = get_index(&inp_data, &expr, &mut stack, i).sqrt();
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
@@ -262,65 +731,165 @@ impl Operator for Sqrt {
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Add;
 impl Operator for Add {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let (lhs, rhs) = (get_vec(&inp[0].0)?, get_vec(&inp[1].0)?);
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) + get_index(rhs, &rexpr, &mut stack, i);
+            *out = get_index(&lhs, &lexpr, &mut stack, i) + get_index(&rhs, &rexpr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Mul;
 impl Operator for Mul {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let (lhs, rhs) = (get_vec(&inp[0].0)?, get_vec(&inp[1].0)?);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) * get_index(rhs, &rexpr, &mut stack, i);
+            *out = get_index(&lhs, &lexpr, &mut stack, i) * get_index(&rhs, &rexpr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct Mod;
 impl Operator for Mod {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let (lhs, rhs) = (get_vec(&inp[0].0)?, get_vec(&inp[1].0)?);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = get_index(lhs, &lexpr, &mut stack, i) % get_index(rhs, &rexpr, &mut stack, i);
+            *out = get_index(&lhs, &lexpr, &mut stack, i) % get_index(&rhs, &rexpr, &mut stack, i);
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, Default, PartialEq)]
 pub struct LessThan;
 impl Operator for LessThan {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
-        let (lhs, rhs) = (get_vec(&inp[0].0), get_vec(&inp[1].0));
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let (lhs, rhs) = (get_vec(&inp[0].0)?, get_vec(&inp[1].0)?);
         let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
         let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
         let mut stack = vec![];
         for (i, out) in out_data.iter_mut().enumerate() {
-            *out = (get_index(lhs, &lexpr, &mut stack, i) < get_index(rhs, &rexpr, &mut stack, i))
+            *out = (get_index(&lhs, &lexpr, &mut stack, i) < get_index(&rhs, &rexpr, &mut stack, i))
                 as i32 as f32;
         }
-        vec![Tensor::new(out_data)]
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// The first input where the second is nonzero, and 0 everywhere else. Unlike multiplying by a 0/1 mask, infinities and
+/// NaNs that get masked out become 0 rather than NaN.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Select;
+impl Operator for Select {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let (lhs, rhs) = (get_vec(&inp[0].0)?, get_vec(&inp[1].0)?);
+        let mut out_data = vec![0.; inp[0].1.n_elements().to_usize().unwrap()];
+        let lexpr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let rexpr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        let mut stack = vec![];
+        for (i, out) in out_data.iter_mut().enumerate() {
+            if get_index(&rhs, &rexpr, &mut stack, i) != 0. {
+                *out = get_index(&lhs, &lexpr, &mut stack, i);
+            }
+        }
+        Ok(vec![Tensor::new(out_data)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
@@ -329,37 +898,115 @@ impl Operator for LessThan {
 #[derive(Debug, Clone, PartialEq)]
 pub struct SumReduce(pub usize);
 impl Operator for SumReduce {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut shape = input_shapes[0].contiguous();
+        shape.remove_dim(self.0);
+        Some(shape)
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::reduce(input_shapes, self.0)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let sh = inp[0].1.shape_usize();
         let front_size = sh.iter().take(self.0).product::<usize>().max(1);
         let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
         let dim_size = sh[self.0];
         let mut result = vec![0.0; front_size * back_size];
-        let input = get_vec(&inp[0].0);
+        let input = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
         for i in 0..front_size {
             for j in 0..back_size {
                 for k in 0..dim_size {
                     let orig_index = i * dim_size * back_size + k * back_size + j;
-                    result[i * back_size + j] += get_index(input, &expr, &mut stack, orig_index);
+                    result[i * back_size + j] += get_index(&input, &expr, &mut stack, orig_index);
                 }
             }
         }
-        vec![Tensor::new(result)]
+        Ok(vec![Tensor::new(result)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
     }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct MaxReduce(pub usize);
 impl Operator for MaxReduce {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut shape = input_shapes[0].contiguous();
+        shape.remove_dim(self.0);
+        Some(shape)
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::reduce(input_shapes, self.0)
+    }
     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
         let sh = inp[0].1.shape_usize();
         let front_size = sh.iter().take(self.0).product::<usize>().max(1);
         let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
         let dim_size = sh[self.0];
         let mut result = vec![-f32::INFINITY; front_size * back_size];
-        let input = get_vec(&inp[0].0);
+        let input = get_vec(&inp[0].0)?;
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+
+        for i in 0..front_size {
+            for j in 0..back_size {
+                for k in 0..dim_size {
+                    let orig_index = i * dim_size * back_size + k * back_size + j;
+                    let new_index = i * back_size + j;
+                    result[new_index] =
+                        result[new_index].max(get_index(&input, &expr, &mut stack, orig_index));
+                }
+            }
+        }
+        Ok(vec![Tensor::new(result)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinReduce(pub usize);
+impl Operator for MinReduce {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut shape = input_shapes[0].contiguous();
+        shape.remove_dim(self.0);
+        Some(shape)
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::reduce(input_shapes, self.0)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
+        let dim_size = sh[self.0];
+        let mut result = vec![f32::INFINITY; front_size * back_size];
+        let input = get_vec(&inp[0].0)?;
         let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
         let mut stack = vec![];
 
@@ -369,16 +1016,173 @@ impl Operator for MaxReduce {
                     let orig_index = i * dim_size * back_size + k * back_size + j;
                     let new_index = i * back_size + j;
                     result[new_index] =
-                        result[new_index].max(get_index(input, &expr, &mut stack, orig_index));
+                        result[new_index].min(get_index(&input, &expr, &mut stack, orig_index));
+                }
+            }
+        }
+        Ok(vec![Tensor::new(result)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProdReduce(pub usize);
+impl Operator for ProdReduce {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut shape = input_shapes[0].contiguous();
+        shape.remove_dim(self.0);
+        Some(shape)
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::reduce(input_shapes, self.0)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
+        let dim_size = sh[self.0];
+        let mut result = vec![1.0; front_size * back_size];
+        let input = get_vec(&inp[0].0)?;
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        for i in 0..front_size {
+            for j in 0..back_size {
+                for k in 0..dim_size {
+                    let orig_index = i * dim_size * back_size + k * back_size + j;
+                    result[i * back_size + j] *= get_index(&input, &expr, &mut stack, orig_index);
+                }
+            }
+        }
+        Ok(vec![Tensor::new(result)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Running sum along a dimension. The output has the same shape as the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CumSum(pub usize);
+impl Operator for CumSum {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        OpCost::elementwise(input_shapes, 1)
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
+        let dim_size = sh[self.0];
+        let mut result = vec![0.0; front_size * dim_size * back_size];
+        let input = get_vec(&inp[0].0)?;
+        let expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let mut stack = vec![];
+        // Go a whole row of the trailing dimensions at a time, so both the running sums and the output are read in order
+        for i in 0..front_size {
+            for k in 0..dim_size {
+                let row = i * dim_size * back_size + k * back_size;
+                for j in 0..back_size {
+                    let prev = if k == 0 {
+                        0.0
+                    } else {
+                        result[row - back_size + j]
+                    };
+                    result[row + j] = prev + get_index(&input, &expr, &mut stack, row + j);
                 }
             }
         }
-        vec![Tensor::new(result)]
+        Ok(vec![Tensor::new(result)])
     }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Add slices of a source into a destination at integer indexes along a dimension, summing wherever indexes repeat.
+///
+/// Inputs are the destination, the indexes (1D), and the source. The source has the destination's shape, except along the
+/// dimension, where it has one slice per index. Indexes outside the destination, or that aren't whole numbers, are skipped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexAdd(pub usize);
+impl Operator for IndexAdd {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        Some(input_shapes[0].contiguous())
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        panic_on_error(self.try_process(inp, &FxHashMap::default()))
+    }
+    fn try_process(
+        &mut self,
+        inp: Vec<(InputTensor, ShapeTracker)>,
+        _: &FxHashMap<char, usize>,
+    ) -> Result<Vec<Tensor>, LuminalError> {
+        let sh = inp[0].1.shape_usize();
+        let front_size = sh.iter().take(self.0).product::<usize>().max(1);
+        let back_size = sh.iter().skip(self.0 + 1).product::<usize>().max(1);
+        let dim_size = sh[self.0];
+        let n_indexes = inp[1].1.n_elements().to_usize().unwrap();
+        let (dest, indexes, src) = (
+            get_vec(&inp[0].0)?,
+            get_vec(&inp[1].0)?,
+            get_vec(&inp[2].0)?,
+        );
+        let dest_expr = (inp[0].1.index_expression(), inp[0].1.valid_expression());
+        let ind_expr = (inp[1].1.index_expression(), inp[1].1.valid_expression());
+        let src_expr = (inp[2].1.index_expression(), inp[2].1.valid_expression());
+        let mut stack = vec![];
+        let mut result = (0..front_size * dim_size * back_size)
+            .map(|i| get_index(&dest, &dest_expr, &mut stack, i))
+            .collect::<Vec<_>>();
+        for k in 0..n_indexes {
+            let target = get_index(&indexes, &ind_expr, &mut stack, k);
+            if !target.is_finite()
+                || target.fract() != 0.
+                || target < 0.
+                || target as usize >= dim_size
+            {
+                continue;
+            }
+            for i in 0..front_size {
+                let out_row = i * dim_size * back_size + target as usize * back_size;
+                let src_row = i * n_indexes * back_size + k * back_size;
+                for j in 0..back_size {
+                    result[out_row + j] += get_index(&src, &src_expr, &mut stack, src_row + j);
+                }
+            }
+        }
+        Ok(vec![Tensor::new(result)])
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// An input's data as f32s, widening half precision inputs
+fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> Result<Cow<'a, [f32]>, LuminalError> {
+    tensor.borrowed().widened_f32s()
 }
 
-fn get_vec<'a>(tensor: &'a InputTensor<'a>) -> &'a Vec<f32> {
-    tensor.borrowed().downcast_ref::<Vec<f32>>().unwrap()
+/// For ops implementing `try_process`, ran outside of a graph with no dynamic dimensions set
+fn panic_on_error(result: Result<Vec<Tensor>, LuminalError>) -> Vec<Tensor> {
+    result.unwrap_or_else(|e| panic!("{e}"))
 }
 
 fn get_index(
@@ -0,0 +1,283 @@
+use std::fmt::Debug;
+
+use crate::{
+    debug::{record_pass, recorded_passes, GraphSnapshot, PassStep},
+    prelude::*,
+};
+
+/// Passes named in this comma separated environment variable are skipped by every `PassManager`
+pub const DISABLE_PASSES_VAR: &str = "LUMINAL_DISABLE_PASSES";
+
+/// An ordered list of named compiler passes, which can be reordered and toggled without changing the compiler's type.
+///
+/// Passes can be turned off with `disable`, or without recompiling by listing their names in the
+/// `LUMINAL_DISABLE_PASSES` environment variable. With `report` on, compiling returns what each pass changed.
+/// ```rust
+/// use luminal::prelude::*;
+/// let passes = PassManager::new()
+///     .add("RemoveUnusedNodes", RemoveUnusedNodes)
+///     .add("CSE", CSE)
+///     .insert_before("CSE", "ArithmeticElimination", ArithmeticElimination)
+///     .disable("RemoveUnusedNodes")
+///     .report(true);
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+/// let mut b = (a * 1.).exp2().retrieve();
+/// let steps = cx.compile(passes, &mut b);
+/// assert_eq!(steps.len(), 2);
+/// assert!(!steps[0].diff.is_empty());
+/// ```
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Pass>,
+    report: bool,
+}
+
+struct Pass {
+    name: String,
+    compiler: Box<dyn DynCompiler>,
+    enabled: bool,
+}
+
+/// Object safe version of `Compiler`, so passes of different types can be stored together
+trait DynCompiler {
+    fn compile_ids(&self, graph: &mut Graph, ids: &mut Vec<NodeIndex>);
+}
+
+impl<C: Compiler> DynCompiler for C {
+    fn compile_ids(&self, graph: &mut Graph, ids: &mut Vec<NodeIndex>) {
+        self.compile(graph, ids);
+    }
+}
+
+impl Debug for PassManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.passes.iter().map(|p| {
+                if p.enabled {
+                    p.name.clone()
+                } else {
+                    format!("{} (disabled)", p.name)
+                }
+            }))
+            .finish()
+    }
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a pass to the end. Names need to be unique.
+    pub fn add(mut self, name: &str, pass: impl Compiler + 'static) -> Self {
+        let index = self.passes.len();
+        self.insert(index, name, pass);
+        self
+    }
+
+    /// Add a pass right before the pass named `anchor`
+    pub fn insert_before(
+        mut self,
+        anchor: &str,
+        name: &str,
+        pass: impl Compiler + 'static,
+    ) -> Self {
+        let index = self.index(anchor);
+        self.insert(index, name, pass);
+        self
+    }
+
+    /// Add a pass right after the pass named `anchor`
+    pub fn insert_after(mut self, anchor: &str, name: &str, pass: impl Compiler + 'static) -> Self {
+        let index = self.index(anchor) + 1;
+        self.insert(index, name, pass);
+        self
+    }
+
+    /// Take a pass out entirely
+    pub fn remove(mut self, name: &str) -> Self {
+        let index = self.index(name);
+        self.passes.remove(index);
+        self
+    }
+
+    /// Turn a pass back on
+    pub fn enable(mut self, name: &str) -> Self {
+        let index = self.index(name);
+        self.passes[index].enabled = true;
+        self
+    }
+
+    /// Skip a pass, keeping its place in the order
+    pub fn disable(mut self, name: &str) -> Self {
+        let index = self.index(name);
+        self.passes[index].enabled = false;
+        self
+    }
+
+    /// Whether compiling returns what each pass changed. Snapshotting the graph after every pass slows compiling down,
+    /// so this is off by default.
+    pub fn report(mut self, report: bool) -> Self {
+        self.report = report;
+        self
+    }
+
+    /// Names of all passes, in the order they run
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|p| p.name.as_str()).collect()
+    }
+
+    /// Whether a pass will run, taking `LUMINAL_DISABLE_PASSES` into account
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.passes[self.index(name)].enabled && !env_disabled().iter().any(|n| n == name)
+    }
+
+    fn index(&self, name: &str) -> usize {
+        self.passes
+            .iter()
+            .position(|p| p.name == name)
+            .unwrap_or_else(|| panic!("No pass named {name}, found {:?}", self.names()))
+    }
+
+    fn insert(&mut self, index: usize, name: &str, pass: impl Compiler + 'static) {
+        assert!(
+            self.passes.iter().all(|p| p.name != name),
+            "There's already a pass named {name}"
+        );
+        self.passes.insert(
+            index,
+            Pass {
+                name: name.to_string(),
+                compiler: Box::new(pass),
+                enabled: true,
+            },
+        );
+    }
+}
+
+impl Compiler for PassManager {
+    /// What each pass that ran changed, if reporting is on
+    type Output = Vec<PassStep>;
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut remap: T) -> Vec<PassStep> {
+        let mut ids = remap
+            .to_ids_mut()
+            .into_iter()
+            .map(|i| *i)
+            .collect::<Vec<_>>();
+        let disabled = env_disabled();
+        let mut steps = vec![];
+        let mut before = self.report.then(|| GraphSnapshot::new(graph));
+        for pass in self
+            .passes
+            .iter()
+            .filter(|p| p.enabled && !disabled.contains(&p.name))
+        {
+            #[cfg(feature = "tracing")]
+            let _span = crate::trace::named_pass_span(&pass.name);
+            let recorded = recorded_passes(graph);
+            pass.compiler.compile_ids(graph, &mut ids);
+            record_pass(graph, || pass.name.clone(), recorded);
+            if let Some(before) = &mut before {
+                let after = GraphSnapshot::new(graph);
+                steps.push(PassStep {
+                    pass: pass.name.clone(),
+                    diff: before.diff(&after),
+                    dot: after.dot.clone(),
+                });
+                *before = after;
+            }
+        }
+        for (id, new) in remap.to_ids_mut().into_iter().zip(ids) {
+            *id = new;
+        }
+        steps
+    }
+}
+
+fn env_disabled() -> Vec<String> {
+    std::env::var(DISABLE_PASSES_VAR)
+        .unwrap_or_default()
+        .split(',')
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    /// Logs its name whenever it runs
+    struct Tally(
+        &'static str,
+        std::rc::Rc<std::cell::RefCell<Vec<&'static str>>>,
+    );
+
+    impl Compiler for Tally {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, _: &mut Graph, _: T) {
+            self.1.borrow_mut().push(self.0);
+        }
+    }
+
+    #[test]
+    fn test_pass_manager_order() {
+        let ran = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let passes = PassManager::new()
+            .add("a", Tally("a", ran.clone()))
+            .add("c", Tally("c", ran.clone()))
+            .insert_before("c", "b", Tally("b", ran.clone()))
+            .insert_after("c", "d", Tally("d", ran.clone()))
+            .disable("b")
+            .remove("d");
+        assert_eq!(passes.names(), vec!["a", "b", "c"]);
+        assert!(!passes.is_enabled("b"));
+        let mut cx = Graph::new();
+        passes.compile(&mut cx, ());
+        assert_eq!(*ran.borrow(), vec!["a", "c"]);
+
+        let passes = passes.enable("b");
+        passes.compile(&mut cx, ());
+        assert_eq!(ran.borrow()[2..], ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_pass_manager_report() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let mut b = (a * 1.).exp2().retrieve();
+        let passes = PassManager::new()
+            .add("ArithmeticElimination", ArithmeticElimination)
+            .add("CSE", CSE)
+            .report(true);
+        let steps = cx.compile(passes, &mut b);
+        assert_eq!(
+            steps.iter().map(|s| s.pass.as_str()).collect::<Vec<_>>(),
+            vec!["ArithmeticElimination", "CSE"]
+        );
+        assert!(steps[0].diff.removed.iter().any(|(_, op)| op == "Mul"));
+        assert!(steps[1].diff.is_empty());
+        // Outputs replaced by a pass get remapped
+        cx.execute();
+        assert_close(&b.data(), &[2., 4., 8.]);
+
+        // Passes run through a manager show up individually when recording
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let mut b = (a * 1.).exp2().retrieve();
+        let passes = PassManager::new()
+            .add("Generic", GenericCompiler::default())
+            .add("Elimination", ArithmeticElimination);
+        let steps = crate::debug::compile_passes(&mut cx, passes, &mut b);
+        assert_eq!(
+            steps.iter().map(|s| s.pass.as_str()).collect::<Vec<_>>(),
+            vec![
+                "RemoveUnusedNodes",
+                "ArithmeticElimination",
+                "CSE",
+                "Elimination"
+            ]
+        );
+    }
+}
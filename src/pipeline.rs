@@ -0,0 +1,164 @@
+use petgraph::Direction;
+use rustc_hash::FxHashSet;
+
+use crate::prelude::*;
+
+/// A model split into stages running on their own devices, with its input split into micro-batches flowing through
+/// them one after another.
+///
+/// Only the forward pass is pipelined. A backward pass built over it isn't placed on the stages' devices or ordered
+/// by micro-batch, so training through a pipeline runs its gradients like any other graph.
+pub struct Pipeline {
+    /// Outputs of every micro-batch joined back together, on the last stage's device
+    pub output: DynGraphTensor,
+    /// The nodes each stage added for each micro-batch, indexed by stage then micro-batch
+    pub cells: Vec<Vec<Vec<NodeIndex>>>,
+}
+
+impl Graph {
+    /// Run `input` through `stages` in `micro_batches` slices of its first dimension. Stage `i` runs on device `i`, and
+    /// each stage takes micro-batches in order, so while one stage works on a micro-batch the stage before it can
+    /// start the next. Run `InsertTransfers` afterwards to move activations between stages.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let x = cx.dyn_tensor("Input", &[4, 2]).set(vec![1., 2., 3., 4., 5., 6., 7., 8.]);
+    /// let w = cx.dyn_tensor("Weight", &[2, 2]).set(vec![1., 0., 0., 1.]);
+    /// let first = |x: DynGraphTensor| x.matmul(w);
+    /// let second = |x: DynGraphTensor| x * 2.;
+    /// let pipeline = cx.pipeline(x, 2, &[&first, &second]);
+    /// let out = pipeline.output.retrieve();
+    /// cx.compile(InsertTransfers, ());
+    /// cx.execute();
+    /// assert_eq!(out.data(), vec![2., 4., 6., 8., 10., 12., 14., 16.]);
+    /// ```
+    #[track_caller]
+    pub fn pipeline(
+        &mut self,
+        input: DynGraphTensor,
+        micro_batches: usize,
+        stages: &[&dyn Fn(DynGraphTensor) -> DynGraphTensor],
+    ) -> Pipeline {
+        assert!(
+            micro_batches > 0 && !stages.is_empty(),
+            "A pipeline needs at least one stage and micro-batch"
+        );
+        let batch = input.dims()[0];
+        let mut cells: Vec<Vec<Vec<NodeIndex>>> = vec![vec![]; stages.len()];
+        let mut outputs = vec![];
+        for m in 0..micro_batches {
+            let mut ranges = vec![(Expression::from(0), Expression::from(i32::MAX)); input.rank()];
+            ranges[0] = (
+                (batch * m / micro_batches).simplify(),
+                (batch * (m + 1) / micro_batches).simplify(),
+            );
+            let mut x = input.slice(&ranges);
+            for (stage, f) in stages.iter().enumerate() {
+                let before = self.node_indices().collect::<FxHashSet<_>>();
+                x = self.with_device(stage, |_| f(x));
+                let cell = self
+                    .node_indices()
+                    .filter(|n| !before.contains(n))
+                    .collect::<Vec<_>>();
+                // Each stage finishes one micro-batch before starting on the next
+                if let Some(prev) = cells[stage].last() {
+                    let prev_out = outputs_of(self, prev);
+                    for root in roots_of(self, &cell) {
+                        for out in &prev_out {
+                            self.add_schedule_dependency(*out, root);
+                        }
+                    }
+                }
+                cells[stage].push(cell);
+            }
+            outputs.push(x);
+        }
+        let last = stages.len() - 1;
+        let output = self.with_device(last, |_| {
+            outputs
+                .into_iter()
+                .reduce(|a, b| a.concat_along(b, 0))
+                .unwrap()
+        });
+        Pipeline { output, cells }
+    }
+}
+
+/// Nodes in a cell whose inputs all come from outside it
+fn roots_of(graph: &Graph, cell: &[NodeIndex]) -> Vec<NodeIndex> {
+    cell.iter()
+        .copied()
+        .filter(|n| {
+            graph
+                .neighbors_directed(*n, Direction::Incoming)
+                .all(|src| !cell.contains(&src))
+        })
+        .collect()
+}
+
+/// Nodes in a cell that nothing else in it reads from
+fn outputs_of(graph: &Graph, cell: &[NodeIndex]) -> Vec<NodeIndex> {
+    cell.iter()
+        .copied()
+        .filter(|n| {
+            graph
+                .neighbors_directed(*n, Direction::Outgoing)
+                .all(|dst| !cell.contains(&dst))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use petgraph::algo::has_path_connecting;
+
+    crate::test_imports!();
+
+    #[test]
+    fn test_pipeline() {
+        let (x, w1, w2) = (random_vec(24), random_vec(32), random_vec(24));
+        let mut cx = Graph::new();
+        let reference = cx
+            .tensor::<R2<6, 4>>()
+            .set(x.clone())
+            .matmul(cx.tensor::<R2<4, 8>>().set(w1.clone()))
+            .relu()
+            .matmul(cx.tensor::<R2<8, 3>>().set(w2.clone()))
+            .retrieve();
+        cx.execute();
+
+        let mut cx = Graph::new();
+        let input = cx.dyn_tensor("Input", &[6, 4]).set(x);
+        let w1 = cx.dyn_tensor("W1", &[4, 8]).set(w1);
+        let w2 = cx.dyn_tensor("W2", &[8, 3]).set(w2);
+        let first = |x: DynGraphTensor| x.matmul(w1).relu();
+        let second = |x: DynGraphTensor| x.matmul(w2);
+        let pipeline = cx.pipeline(input, 3, &[&first, &second]);
+        let out = pipeline.output.retrieve();
+        cx.compile(InsertTransfers, ());
+
+        for (stage, cells) in pipeline.cells.iter().enumerate() {
+            assert_eq!(cells.len(), 3);
+            for cell in cells {
+                assert!(cell.iter().all(|n| cx.devices[n] == stage));
+            }
+            // Micro-batches go through each stage in order
+            for m in 0..2 {
+                assert!(has_path_connecting(
+                    &cx.graph,
+                    cells[m][0],
+                    cells[m + 1][0],
+                    None
+                ));
+            }
+        }
+        // Only activations move, once per micro-batch
+        let transfers = cx
+            .node_indices()
+            .filter_map(|n| cx.try_get_op::<Transfer>(n))
+            .collect::<Vec<_>>();
+        assert_eq!(transfers, vec![&Transfer { from: 0, to: 1 }; 3]);
+        cx.execute();
+        assert_close(&out.data(), &reference.data());
+    }
+}
@@ -0,0 +1,219 @@
+use petgraph::{algo::toposort, Direction};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::prelude::*;
+
+/// A point in a queue's work, for other queues to wait on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Event(pub usize);
+
+/// A backend's queues (streams), that ops put their work on without waiting for it to finish.
+///
+/// The executor picks the queue each op runs on from `Graph::queues`, and has a queue wait for another one wherever an
+/// op reads something made on a different queue, so independent branches of the graph can run at the same time. Ops
+/// find out which queue to use from the backend, which is told before each one runs.
+pub trait Queues: std::fmt::Debug {
+    /// Put the work of the op about to run on `queue`
+    fn select(&mut self, queue: usize);
+    /// Mark the work queued on `queue` so far
+    fn record(&mut self, queue: usize) -> Event;
+    /// Hold anything queued on `queue` after this until the work before `event` is done
+    fn wait(&mut self, queue: usize, event: Event);
+    /// Block until all queued work is done
+    fn synchronize(&mut self);
+}
+
+/// The graph's queues, and the events recorded on them during the current run
+#[derive(Debug, Default)]
+pub(crate) struct QueueState {
+    queues: Option<Box<dyn Queues>>,
+    /// Recorded after each node that's read on another queue
+    events: FxHashMap<NodeIndex, Event>,
+    /// Events each queue already waits for
+    waited: FxHashSet<(usize, Event)>,
+}
+
+impl QueueState {
+    /// Wait for the node's inputs made on other queues, then switch to its queue
+    pub(crate) fn before(
+        &mut self,
+        graph: &MainGraph,
+        assigned: &FxHashMap<NodeIndex, usize>,
+        node: NodeIndex,
+    ) {
+        let Some(queues) = &mut self.queues else {
+            return;
+        };
+        let queue = |n| assigned.get(&n).copied().unwrap_or_default();
+        for src in graph.neighbors_directed(node, Direction::Incoming) {
+            if let Some(event) = self.events.get(&src) {
+                if queue(src) != queue(node) && self.waited.insert((queue(node), *event)) {
+                    queues.wait(queue(node), *event);
+                }
+            }
+        }
+        queues.select(queue(node));
+    }
+
+    /// Record an event after a node if anything on another queue reads it
+    pub(crate) fn after(
+        &mut self,
+        graph: &MainGraph,
+        assigned: &FxHashMap<NodeIndex, usize>,
+        node: NodeIndex,
+    ) {
+        let Some(queues) = &mut self.queues else {
+            return;
+        };
+        let queue = |n| assigned.get(&n).copied().unwrap_or_default();
+        if graph
+            .neighbors_directed(node, Direction::Outgoing)
+            .any(|c| queue(c) != queue(node))
+        {
+            self.events.insert(node, queues.record(queue(node)));
+        }
+    }
+
+    /// Wait for everything queued during the run
+    pub(crate) fn finish(&mut self) {
+        if let Some(queues) = &mut self.queues {
+            queues.synchronize();
+        }
+        self.events.clear();
+        self.waited.clear();
+    }
+}
+
+impl Graph {
+    /// Run ops on a backend's queues, as assigned by `AssignQueues`. Each run waits for all of them before returning.
+    pub fn set_queues(&mut self, queues: impl Queues + 'static) {
+        self.queue_state.queues = Some(Box::new(queues));
+    }
+}
+
+/// Spread the graph's nodes over a number of queues, so independent branches can run at the same time.
+///
+/// A node goes on the queue of an input it directly follows, so chains of ops stay on one queue and only don't when the
+/// graph branches. Otherwise it goes on whichever queue has the fewest nodes so far.
+#[derive(Debug)]
+pub struct AssignQueues(pub usize);
+
+impl Compiler for AssignQueues {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+        assert!(self.0 > 0, "Need at least one queue");
+        graph.queues.clear();
+        // Last node put on each queue, and how many nodes are on it
+        let (mut tails, mut sizes) = (vec![None; self.0], vec![0; self.0]);
+        for node in toposort(&graph.graph, None).unwrap() {
+            let queue = graph
+                .neighbors_directed(node, Direction::Incoming)
+                .find_map(|src| {
+                    let queue = graph.queues[&src];
+                    (tails[queue] == Some(src)).then_some(queue)
+                })
+                .unwrap_or_else(|| (0..self.0).min_by_key(|q| sizes[*q]).unwrap());
+            graph.queues.insert(node, queue);
+            tails[queue] = Some(node);
+            sizes[queue] += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    crate::test_imports!();
+
+    /// Queues that log what they're asked to do
+    #[derive(Debug, Clone, Default)]
+    struct Log(Rc<RefCell<Vec<String>>>);
+
+    impl Queues for Log {
+        fn select(&mut self, queue: usize) {
+            self.0.borrow_mut().push(format!("select {queue}"));
+        }
+        fn record(&mut self, queue: usize) -> Event {
+            let mut log = self.0.borrow_mut();
+            log.push(format!("record {queue}"));
+            Event(log.len())
+        }
+        fn wait(&mut self, queue: usize, event: Event) {
+            self.0
+                .borrow_mut()
+                .push(format!("wait {queue} {}", event.0));
+        }
+        fn synchronize(&mut self) {
+            self.0.borrow_mut().push("synchronize".to_string());
+        }
+    }
+
+    #[test]
+    fn test_branches_on_separate_queues() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>();
+        let b = cx.tensor::<R1<3>>();
+        let c = a.exp2().sqrt();
+        let d = b.sin().recip();
+        let mut e = (c + d).retrieve();
+        cx.compile(AssignQueues(2), &mut e);
+
+        // Each branch stays on its own queue, and they're joined on one of them
+        let queues = cx.queues.clone();
+        let queue = |n: NodeIndex| queues[&n];
+        assert_eq!(queue(a.id), queue(c.id));
+        assert_eq!(queue(b.id), queue(d.id));
+        assert_ne!(queue(c.id), queue(d.id));
+        let other = if queue(e.id) == queue(c.id) { d } else { c };
+        assert_ne!(queue(e.id), queue(other.id));
+
+        let log = Log::default();
+        cx.set_queues(log.clone());
+        for _ in 0..2 {
+            a.set([1., 2., 3.]);
+            b.set([4., 5., 6.]);
+            log.0.borrow_mut().clear();
+            cx.execute();
+            let expected = [1., 2., 3.]
+                .into_iter()
+                .zip([4., 5., 6.])
+                .map(|(a, b): (f32, f32)| a.exp2().sqrt() + b.sin().recip())
+                .collect::<Vec<_>>();
+            assert_close(&e.data(), &expected);
+            e.drop();
+
+            // Only the join waits, once, for the other branch
+            let log = log.0.borrow();
+            let recorded = log
+                .iter()
+                .position(|l| l == &format!("record {}", queue(other.id)))
+                .unwrap();
+            assert_eq!(
+                log.iter()
+                    .filter(|l| l.starts_with("wait"))
+                    .collect::<Vec<_>>(),
+                vec![&format!("wait {} {}", queue(e.id), recorded + 1)]
+            );
+            assert_eq!(log.last().unwrap(), "synchronize");
+        }
+    }
+
+    #[test]
+    fn test_one_queue() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let mut b = (a.exp2() + a.sin()).retrieve();
+        cx.compile(AssignQueues(1), &mut b);
+        let log = Log::default();
+        cx.set_queues(log.clone());
+        cx.execute();
+
+        assert!(cx.queues.values().all(|q| *q == 0));
+        assert!(!log
+            .0
+            .borrow()
+            .iter()
+            .any(|l| l.starts_with("wait") || l.starts_with("record")));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{any::TypeId, sync::Arc};
+
+use rustc_hash::FxHashMap;
+
+use crate::prelude::*;
+
+type ShapeFn = Arc<dyn Fn(&dyn Operator, &[ShapeTracker]) -> Vec<ShapeTracker>>;
+type LoweringFn = Arc<dyn Fn(&mut Graph, NodeIndex) -> NodeIndex>;
+
+/// A custom op registered with a graph
+pub struct RegisteredOp {
+    pub name: &'static str,
+    shape_fn: ShapeFn,
+    lowerings: FxHashMap<String, LoweringFn>,
+}
+
+impl RegisteredOp {
+    /// Add a hook that swaps this op out for ops a backend understands. The hook gets the node to replace, builds the
+    /// replacement from the node's sources, and returns the node producing the same output. The old node is then removed.
+    pub fn lowering(
+        &mut self,
+        backend: impl ToString,
+        lower: impl Fn(&mut Graph, NodeIndex) -> NodeIndex + 'static,
+    ) -> &mut Self {
+        self.lowerings.insert(backend.to_string(), Arc::new(lower));
+        self
+    }
+}
+
+/// Custom ops known to a graph, keyed by their type
+#[derive(Default)]
+pub struct OpRegistry {
+    ops: FxHashMap<TypeId, RegisteredOp>,
+}
+
+impl std::fmt::Debug for OpRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.ops.values().map(|o| o.name))
+            .finish()
+    }
+}
+
+impl OpRegistry {
+    /// Get the registration for an op, if its type was registered
+    pub fn get(&self, op: &dyn Operator) -> Option<&RegisteredOp> {
+        self.ops.get(&op.as_any().type_id())
+    }
+}
+
+impl Graph {
+    /// Register a custom op type along with a function giving its output shapes from its input shapes.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// #[derive(Debug)]
+    /// struct Square;
+    /// impl Operator for Square {
+    ///     fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+    ///         let data = inp[0].0.borrowed().as_f32_slice().unwrap();
+    ///         vec![Tensor::new(data.iter().map(|i| i * i).collect::<Vec<_>>())]
+    ///     }
+    /// }
+    ///
+    /// let mut cx = Graph::new();
+    /// cx.register_op::<Square>("Square", |_, inp| vec![inp[0].contiguous()]);
+    /// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    /// let b = cx.custom_op::<_, R1<3>>(Square, &[(a.id, a.shape)]).unwrap().retrieve();
+    /// cx.execute();
+    /// assert_eq!(b.data(), vec![1., 4., 9.]);
+    /// ```
+    pub fn register_op<O: Operator + 'static>(
+        &mut self,
+        name: &'static str,
+        shape_fn: impl Fn(&O, &[ShapeTracker]) -> Vec<ShapeTracker> + 'static,
+    ) -> &mut RegisteredOp {
+        self.registry
+            .ops
+            .entry(TypeId::of::<O>())
+            .or_insert(RegisteredOp {
+                name,
+                shape_fn: Arc::new(move |op, inp| {
+                    shape_fn(op.as_any().downcast_ref::<O>().unwrap(), inp)
+                }),
+                lowerings: FxHashMap::default(),
+            })
+    }
+
+    /// Get the output shapes of a node from the shapes of its inputs. Registered ops use their shape function, and every
+    /// other op its own `infer_shape`, which only knows about the first output.
+    pub fn output_shapes(&self, node: NodeIndex) -> Option<Vec<ShapeTracker>> {
+        let op = self.node_weight(node)?;
+        let shapes = self
+            .get_sources(node)
+            .into_iter()
+            .map(|(_, _, s)| s)
+            .collect::<Vec<_>>();
+        if let Some(reg) = self.registry.get(op.as_ref()) {
+            return Some((reg.shape_fn)(op.as_ref(), &shapes));
+        }
+        op.infer_shape(&shapes).map(|s| vec![s])
+    }
+
+    /// Store the shape of a node's first output, once its inputs are in
+    pub(crate) fn store_shape(&mut self, node: NodeIndex) {
+        if let Some(shape) = self.output_shapes(node).and_then(|s| s.into_iter().next()) {
+            self.shapes.insert(node, shape);
+        }
+    }
+
+    /// Add a registered custom op reading from some (node, shape) inputs, getting back its first output
+    #[track_caller]
+    pub fn custom_op<O: Operator + 'static, S: Shape>(
+        &mut self,
+        op: O,
+        inputs: &[(NodeIndex, ShapeTracker)],
+    ) -> Result<GraphTensor<S>, LuminalError> {
+        let Some(reg) = self.registry.ops.get(&TypeId::of::<O>()) else {
+            return Err(LuminalError::UnregisteredOp(
+                std::any::type_name::<O>().to_string(),
+            ));
+        };
+        let shapes = inputs.iter().map(|(_, s)| *s).collect::<Vec<_>>();
+        let output = (reg.shape_fn)(&op, &shapes)[0];
+        let mut new_op = self.add_op(op);
+        for (id, shape) in inputs {
+            new_op = new_op.input(*id, 0, *shape);
+        }
+        let id = new_op.try_finish()?;
+        Ok(GraphTensor::from_id(id, output, self))
+    }
+}
+
+/// Replace every registered custom op that has a lowering for this backend. Run this before the backend's own compilers.
+#[derive(Debug)]
+pub struct LowerCustomOps(pub &'static str);
+
+impl Compiler for LowerCustomOps {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for node in graph.node_indices().collect::<Vec<_>>() {
+            let lowering = graph
+                .registry
+                .get(graph.node_weight(node).unwrap().as_ref())
+                .and_then(|r| r.lowerings.get(self.0).cloned());
+            if let Some(lower) = lowering {
+                let new_node = lower(graph, node);
+                move_outgoing_edge(node, new_node, &mut graph.graph);
+                remap(node, new_node, &mut ids, graph);
+                graph.remove_node(node);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+    use crate::op::Tensor;
+
+    #[derive(Debug)]
+    struct Square;
+
+    impl Operator for Square {
+        fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+            let data = inp[0].0.borrowed().as_f32_slice().unwrap();
+            vec![Tensor::new(data.iter().map(|i| i * i).collect::<Vec<_>>())]
+        }
+    }
+
+    #[test]
+    fn test_custom_op() {
+        let mut cx = Graph::new();
+        assert!(matches!(
+            cx.custom_op::<_, R1<3>>(Square, &[]),
+            Err(LuminalError::UnregisteredOp(_))
+        ));
+        cx.register_op::<Square>("Square", |_, inp| vec![inp[0].contiguous()])
+            .lowering("cpu", |cx, node| {
+                let (src, out, shape) = cx.get_sources(node)[0];
+                cx.add_op(Mul)
+                    .input(src, out, shape)
+                    .input(src, out, shape)
+                    .finish()
+            });
+
+        let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+        let mut b = cx
+            .custom_op::<_, R2<2, 3>>(Square, &[(a.id, a.shape)])
+            .unwrap()
+            .retrieve();
+        assert_eq!(cx.output_shapes(b.id).unwrap()[0].shape_usize(), vec![2, 3]);
+        cx.execute();
+        let unlowered = b.data();
+
+        // Unregistered backends leave the op alone
+        cx.compile(LowerCustomOps("metal"), &mut b);
+        assert!(cx.is_op::<Square>(b.id));
+        cx.compile(LowerCustomOps("cpu"), &mut b);
+        assert!(cx.is_op::<Mul>(b.id));
+        cx.execute();
+        assert_exact(&b.data(), &unlowered);
+        assert_exact(&unlowered, &[1., 4., 9., 16., 25., 36.]);
+    }
+}
@@ -0,0 +1,292 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::prelude::*;
+
+/// Modifies next-token logits in place before sampling. `tokens` are all tokens seen so far.
+pub trait LogitsProcessor {
+    fn process(&self, logits: &mut [f32], tokens: &[u32]);
+}
+
+/// Divide logits by a temperature. Higher temperatures flatten the distribution.
+#[derive(Debug, Clone, Copy)]
+pub struct Temperature(pub f32);
+
+impl LogitsProcessor for Temperature {
+    fn process(&self, logits: &mut [f32], _: &[u32]) {
+        logits.iter_mut().for_each(|l| *l /= self.0);
+    }
+}
+
+/// Only keep the k most likely tokens
+#[derive(Debug, Clone, Copy)]
+pub struct TopK(pub usize);
+
+impl LogitsProcessor for TopK {
+    fn process(&self, logits: &mut [f32], _: &[u32]) {
+        if self.0 == 0 || self.0 >= logits.len() {
+            return;
+        }
+        let mut sorted = logits.to_vec();
+        sorted.sort_by(|a, b| b.total_cmp(a));
+        let threshold = sorted[self.0 - 1];
+        // Everything above the threshold is kept, and the slots left over go to the first ties at it
+        let mut ties = self.0 - logits.iter().filter(|l| **l > threshold).count();
+        for l in logits.iter_mut() {
+            if *l == threshold && ties > 0 {
+                ties -= 1;
+            } else if *l <= threshold || l.is_nan() {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+    }
+}
+
+/// Only keep the smallest set of most likely tokens whose probabilities add up to at least p (nucleus sampling)
+#[derive(Debug, Clone, Copy)]
+pub struct TopP(pub f32);
+
+impl LogitsProcessor for TopP {
+    fn process(&self, logits: &mut [f32], _: &[u32]) {
+        let probs = softmax(logits);
+        let mut order = (0..logits.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| probs[*b].total_cmp(&probs[*a]));
+        let mut cumulative = 0.;
+        for (n, i) in order.into_iter().enumerate() {
+            // Always keep at least one token
+            if n > 0 && cumulative >= self.0 {
+                logits[i] = f32::NEG_INFINITY;
+            }
+            cumulative += probs[i];
+        }
+    }
+}
+
+/// Penalize tokens that have already appeared. Positive logits are divided by the penalty and negative ones multiplied.
+#[derive(Debug, Clone, Copy)]
+pub struct RepetitionPenalty(pub f32);
+
+impl LogitsProcessor for RepetitionPenalty {
+    fn process(&self, logits: &mut [f32], tokens: &[u32]) {
+        let mut seen = vec![false; logits.len()];
+        let n_logits = logits.len();
+        for t in tokens.iter().map(|t| *t as usize).filter(|t| *t < n_logits) {
+            if !seen[t] {
+                seen[t] = true;
+                logits[t] = if logits[t] > 0. {
+                    logits[t] / self.0
+                } else {
+                    logits[t] * self.0
+                };
+            }
+        }
+    }
+}
+
+/// Picks the next token from a set of logits, after running them through a chain of processors
+pub struct Sampler {
+    processors: Vec<Box<dyn LogitsProcessor>>,
+    greedy: bool,
+    rng: StdRng,
+}
+
+impl Sampler {
+    /// Always pick the most likely token
+    pub fn greedy() -> Self {
+        SamplerBuilder::default().build()
+    }
+
+    /// Process the logits and pick a token. `tokens` are all tokens seen so far.
+    pub fn sample(&mut self, logits: &[f32], tokens: &[u32]) -> u32 {
+        let mut logits = logits.to_vec();
+        for processor in &self.processors {
+            processor.process(&mut logits, tokens);
+        }
+        if self.greedy {
+            return argmax(&logits);
+        }
+        let probs = softmax(&logits);
+        let mut target = self.rng.gen::<f32>() * probs.iter().sum::<f32>();
+        for (i, p) in probs.iter().enumerate() {
+            if target < *p {
+                return i as u32;
+            }
+            target -= p;
+        }
+        // Float error can leave us just past the end
+        probs.iter().rposition(|p| *p > 0.).unwrap_or_default() as u32
+    }
+}
+
+/// Configure a sampler. Without a temperature (or with a temperature of 0) sampling is greedy.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut sampler = SamplerBuilder::default()
+///     .temperature(0.7)
+///     .top_k(40)
+///     .top_p(0.9)
+///     .repetition_penalty(1.1)
+///     .seed(0)
+///     .build();
+/// let token = sampler.sample(&[0.1, 3.0, -1.0], &[]);
+/// ```
+#[derive(Default)]
+pub struct SamplerBuilder {
+    temperature: Option<f32>,
+    top_k: Option<usize>,
+    top_p: Option<f32>,
+    repetition_penalty: Option<f32>,
+    seed: Option<u64>,
+    processors: Vec<Box<dyn LogitsProcessor>>,
+}
+
+impl SamplerBuilder {
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn top_k(mut self, k: usize) -> Self {
+        self.top_k = Some(k);
+        self
+    }
+
+    pub fn top_p(mut self, p: f32) -> Self {
+        self.top_p = Some(p);
+        self
+    }
+
+    pub fn repetition_penalty(mut self, penalty: f32) -> Self {
+        self.repetition_penalty = Some(penalty);
+        self
+    }
+
+    /// Seed the random number generator, for reproducible sampling
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Add a custom processor, ran after the built-in ones
+    pub fn processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    pub fn build(self) -> Sampler {
+        let greedy = self.temperature.map(|t| t <= 0.).unwrap_or(true);
+        let mut processors: Vec<Box<dyn LogitsProcessor>> = vec![];
+        if let Some(penalty) = self.repetition_penalty {
+            processors.push(Box::new(RepetitionPenalty(penalty)));
+        }
+        if let Some(t) = self.temperature.filter(|t| *t > 0.) {
+            processors.push(Box::new(Temperature(t)));
+        }
+        if let Some(k) = self.top_k {
+            processors.push(Box::new(TopK(k)));
+        }
+        if let Some(p) = self.top_p {
+            processors.push(Box::new(TopP(p)));
+        }
+        processors.extend(self.processors);
+        Sampler {
+            processors,
+            greedy,
+            rng: self
+                .seed
+                .map(StdRng::seed_from_u64)
+                .unwrap_or_else(StdRng::from_entropy),
+        }
+    }
+}
+
+impl<S: Shape> GraphTensor<S> {
+    /// Turn logits into a probability distribution along the last dimension, scaled by a temperature.
+    /// This keeps the distribution on device so only the probabilities need to be sampled from on the host.
+    pub fn temperature_softmax(self, temperature: f32) -> GraphTensor<S>
+    where
+        S: ReduceShape<S::LastAxis>,
+    {
+        (self * (1. / temperature)).softmax::<S::LastAxis>()
+    }
+}
+
+fn argmax(dist: &[f32]) -> u32 {
+    dist.iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |(ind, max), (i, v)| {
+            if *v > max {
+                (i, *v)
+            } else {
+                (ind, max)
+            }
+        })
+        .0 as u32
+}
+
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = logits.iter().map(|l| (l - max).exp()).collect::<Vec<_>>();
+    let sum = exp.iter().sum::<f32>();
+    exp.into_iter().map(|e| e / sum).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_processors() {
+        let mut logits = vec![1., 4., 3., 2.];
+        TopK(2).process(&mut logits, &[]);
+        assert_exact(&logits, &[f32::NEG_INFINITY, 4., 3., f32::NEG_INFINITY]);
+        // Ties don't push out larger logits after them
+        let mut logits = vec![3., 3., 5.];
+        TopK(2).process(&mut logits, &[]);
+        assert_exact(&logits, &[3., f32::NEG_INFINITY, 5.]);
+
+        let mut logits = vec![0., 10., 9., -5.];
+        TopP(0.5).process(&mut logits, &[]);
+        assert_exact(
+            &logits,
+            &[f32::NEG_INFINITY, 10., f32::NEG_INFINITY, f32::NEG_INFINITY],
+        );
+
+        let mut logits = vec![2., -2., 1.];
+        RepetitionPenalty(2.).process(&mut logits, &[0, 1, 1]);
+        assert_exact(&logits, &[1., -4., 1.]);
+    }
+
+    #[test]
+    fn test_sampler() {
+        assert_eq!(Sampler::greedy().sample(&[0.1, 3.0, -1.0], &[]), 1);
+        // Repetition penalty pushes us onto the next most likely token
+        let mut sampler = SamplerBuilder::default().repetition_penalty(10.).build();
+        assert_eq!(sampler.sample(&[0.1, 3.0, 2.0], &[1]), 2);
+        // Top k of 1 is greedy, no matter the temperature
+        let mut sampler = SamplerBuilder::default()
+            .temperature(100.)
+            .top_k(1)
+            .seed(0)
+            .build();
+        assert!((0..100).all(|_| sampler.sample(&[0.1, 3.0, 2.0], &[]) == 1));
+        // High temperature sampling should hit every token
+        let mut sampler = SamplerBuilder::default().temperature(100.).seed(0).build();
+        let mut counts = [0; 3];
+        for _ in 0..300 {
+            counts[sampler.sample(&[0.1, 3.0, 2.0], &[]) as usize] += 1;
+        }
+        assert!(counts.iter().all(|c| *c > 50));
+    }
+
+    #[test]
+    fn test_temperature_softmax() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+        let b = a.temperature_softmax(2.).retrieve();
+        cx.execute();
+
+        let d_dev = Cpu::default();
+        let d_b = (d_dev.tensor([1., 2., 3.]) / 2.).softmax();
+        assert_close(&b.data(), &d_b.as_vec());
+    }
+}
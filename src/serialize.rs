@@ -0,0 +1,366 @@
+use std::{io::Read, path::Path, sync::Arc};
+
+use petgraph::Direction;
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    op::{
+        Add, Cast, Constant, ConstantValue, Contiguous, CumSum, Exp2, Function, IndexAdd, LessThan,
+        Log2, MaxReduce, MinReduce, Mod, Mul, ProdReduce, Recip, Select, Sin, Sqrt, SumReduce,
+    },
+    prelude::*,
+};
+
+/// A node as written to a graph file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op")]
+enum NodeDef {
+    /// A declared input, fed on each run
+    Input {
+        name: String,
+        shape: Box<ShapeTracker>,
+    },
+    /// A source whose data is stored in the file, at `start..end` of the data section in elements
+    Weight {
+        name: String,
+        start: usize,
+        end: usize,
+    },
+    Constant {
+        value: ConstantValue,
+    },
+    Contiguous,
+    Cast {
+        dtype: DType,
+    },
+    Log2,
+    Exp2,
+    Sin,
+    Recip,
+    Sqrt,
+    Add,
+    Mul,
+    Mod,
+    LessThan,
+    Select,
+    SumReduce {
+        dim: usize,
+    },
+    MaxReduce {
+        dim: usize,
+    },
+    MinReduce {
+        dim: usize,
+    },
+    ProdReduce {
+        dim: usize,
+    },
+    CumSum {
+        dim: usize,
+    },
+    IndexAdd {
+        dim: usize,
+    },
+}
+
+/// Header of a graph file. Nodes are referred to by their position in `nodes`.
+#[derive(Debug, Serialize, Deserialize)]
+struct GraphFile {
+    nodes: Vec<NodeDef>,
+    edges: Vec<(usize, usize, Dependency)>,
+    outputs: Vec<(usize, ShapeTracker)>,
+}
+
+impl Graph {
+    /// Write the graph out to a file, along with the data of every weight, so it can be loaded and ran without the
+    /// code that built it.
+    ///
+    /// Files are laid out like safetensors: a little-endian u64 header length, a json header holding the ops and how
+    /// they connect, then the weights as little-endian f32s. Only primitive ops can be written, so save before running
+    /// backend compilers. Sources that aren't declared inputs or constants are stored as weights, which runs their
+    /// loaders.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let x = cx.input::<(Dyn<'s'>, Const<2>)>("x");
+    /// let w = cx.tensor::<R2<2, 2>>().set([[1., 2.], [3., 4.]]);
+    /// let y = x.matmul(w).exp2();
+    /// let bytes = cx.to_bytes(&[(y.id, y.shape)]).unwrap();
+    ///
+    /// let mut cx = Graph::new();
+    /// let outputs = cx.load_bytes(&bytes).unwrap();
+    /// let y = GraphTensor::<()>::from_id(outputs[0].0, outputs[0].1, &mut cx).retrieve();
+    /// cx.bind("x", vec![1., 0.], &[1, 2]).unwrap();
+    /// cx.execute();
+    /// assert_eq!(y.data(), vec![2., 4.]);
+    /// ```
+    pub fn to_bytes(
+        &mut self,
+        outputs: &[(NodeIndex, ShapeTracker)],
+    ) -> Result<Vec<u8>, LuminalError> {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|(name, (id, shape))| (*id, (name.clone(), *shape)))
+            .collect::<FxHashMap<_, _>>();
+        let order = self.node_indices().collect::<Vec<_>>();
+        let position = order
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (*n, i))
+            .collect::<FxHashMap<_, _>>();
+        let mut data = vec![];
+        let mut nodes = vec![];
+        for node in &order {
+            let is_source = self
+                .graph
+                .edges_directed(*node, Direction::Incoming)
+                .next()
+                .is_none();
+            let def = if let Some((name, shape)) = inputs.get(node) {
+                NodeDef::Input {
+                    name: name.clone(),
+                    shape: Box::new(*shape),
+                }
+            } else if let Some(def) = primitive(self.node_weight(*node).unwrap().as_ref()) {
+                def
+            } else if is_source {
+                let name = format!("{:?}", self.node_weight(*node).unwrap());
+                let tensor = match self.get_tensor_ref(*node, 0) {
+                    Some(t) => t.clone(),
+                    None => self
+                        .graph
+                        .node_weight_mut(*node)
+                        .unwrap()
+                        .process(vec![])
+                        .pop()
+                        .ok_or_else(|| LuminalError::UnserializableOp(name.clone()))?,
+                };
+                let weight = tensor
+                    .as_f32_slice()
+                    .ok_or_else(|| LuminalError::UnserializableOp(name.clone()))?;
+                let start = data.len();
+                data.extend_from_slice(weight);
+                NodeDef::Weight {
+                    name: name.trim_end_matches(" Load").to_string(),
+                    start,
+                    end: data.len(),
+                }
+            } else {
+                return Err(LuminalError::UnserializableOp(format!(
+                    "{:?}",
+                    self.node_weight(*node).unwrap()
+                )));
+            };
+            nodes.push(def);
+        }
+        let file = GraphFile {
+            nodes,
+            edges: self
+                .graph
+                .edge_indices()
+                .map(|e| {
+                    let (src, dst) = self.graph.edge_endpoints(e).unwrap();
+                    (position[&src], position[&dst], self.graph[e])
+                })
+                .collect(),
+            outputs: outputs
+                .iter()
+                .map(|(id, shape)| (position[id], *shape))
+                .collect(),
+        };
+        let header = serde_json::to_vec(&file).unwrap();
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header);
+        bytes.extend(data.iter().flat_map(|f| f.to_le_bytes()));
+        Ok(bytes)
+    }
+
+    /// Write the graph to a file. See `to_bytes` for the format.
+    pub fn save(
+        &mut self,
+        path: impl AsRef<Path>,
+        outputs: &[(NodeIndex, ShapeTracker)],
+    ) -> Result<(), LuminalError> {
+        let bytes = self.to_bytes(outputs)?;
+        std::fs::write(path, bytes).map_err(|e| LuminalError::BadGraphFile(e.to_string()))
+    }
+
+    /// Add the nodes of a graph written with `to_bytes` to this one, returning its outputs
+    pub fn load_bytes(
+        &mut self,
+        bytes: &[u8],
+    ) -> Result<Vec<(NodeIndex, ShapeTracker)>, LuminalError> {
+        let bad = |message: &str| LuminalError::BadGraphFile(message.to_string());
+        let header_len = bytes
+            .get(..8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| bad("File is too short"))?;
+        let header = bytes
+            .get(8..8 + header_len)
+            .ok_or_else(|| bad("Header is cut off"))?;
+        let file: GraphFile = serde_json::from_slice(header)
+            .map_err(|e| LuminalError::BadGraphFile(e.to_string()))?;
+        let data = Arc::new(
+            bytes[8 + header_len..]
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+                .collect::<Vec<_>>(),
+        );
+
+        let graph = self;
+        let mut ids = vec![];
+        for node in file.nodes {
+            let id = match node {
+                NodeDef::Input { name, shape } => {
+                    let id = graph.input::<()>(&name).id;
+                    graph.inputs.insert(name, (id, *shape));
+                    id
+                }
+                NodeDef::Weight { name, start, end } => {
+                    if end < start || end > data.len() {
+                        return Err(bad("Weight data is out of bounds"));
+                    }
+                    let data = data.clone();
+                    graph
+                        .add_op(Function(
+                            format!("{name} Load"),
+                            Box::new(move |_| vec![Tensor::new(data[start..end].to_vec())]),
+                        ))
+                        .finish()
+                }
+                NodeDef::Constant { value } => graph.add_op(Constant(value)).finish(),
+                NodeDef::Contiguous => graph.add_op(Contiguous).finish(),
+                NodeDef::Cast { dtype } => graph.add_op(Cast(dtype)).finish(),
+                NodeDef::Log2 => graph.add_op(Log2).finish(),
+                NodeDef::Exp2 => graph.add_op(Exp2).finish(),
+                NodeDef::Sin => graph.add_op(Sin).finish(),
+                NodeDef::Recip => graph.add_op(Recip).finish(),
+                NodeDef::Sqrt => graph.add_op(Sqrt).finish(),
+                NodeDef::Add => graph.add_op(Add).finish(),
+                NodeDef::Mul => graph.add_op(Mul).finish(),
+                NodeDef::Mod => graph.add_op(Mod).finish(),
+                NodeDef::LessThan => graph.add_op(LessThan).finish(),
+                NodeDef::Select => graph.add_op(Select).finish(),
+                NodeDef::SumReduce { dim } => graph.add_op(SumReduce(dim)).finish(),
+                NodeDef::MaxReduce { dim } => graph.add_op(MaxReduce(dim)).finish(),
+                NodeDef::MinReduce { dim } => graph.add_op(MinReduce(dim)).finish(),
+                NodeDef::ProdReduce { dim } => graph.add_op(ProdReduce(dim)).finish(),
+                NodeDef::CumSum { dim } => graph.add_op(CumSum(dim)).finish(),
+                NodeDef::IndexAdd { dim } => graph.add_op(IndexAdd(dim)).finish(),
+            };
+            ids.push(id);
+        }
+        let node = |i: usize| {
+            ids.get(i)
+                .copied()
+                .ok_or_else(|| bad("Edge to a missing node"))
+        };
+        for (src, dst, dependency) in file.edges {
+            let (src, dst) = (node(src)?, node(dst)?);
+            graph.add_edge(src, dst, dependency);
+        }
+        // Ops went in before their inputs, so their shapes can only be inferred now
+        for id in &ids {
+            if !graph.get_sources(*id).is_empty() {
+                graph.store_shape(*id);
+            }
+        }
+        let outputs = file
+            .outputs
+            .into_iter()
+            .map(|(i, shape)| Ok((node(i)?, shape)))
+            .collect::<Result<Vec<_>, LuminalError>>()?;
+        Ok(outputs)
+    }
+
+    /// Add the nodes of a graph saved with `save` to this one, returning its outputs
+    pub fn load(
+        &mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<(NodeIndex, ShapeTracker)>, LuminalError> {
+        let mut bytes = vec![];
+        std::fs::File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|e| LuminalError::BadGraphFile(e.to_string()))?;
+        self.load_bytes(&bytes)
+    }
+}
+
+/// The file entry for a primitive op, if it is one
+fn primitive(op: &dyn Operator) -> Option<NodeDef> {
+    let op = op.as_any();
+    macro_rules! unit {
+        ($($name:ident),*) => {
+            $(if op.is::<$name>() {
+                return Some(NodeDef::$name);
+            })*
+        };
+    }
+    macro_rules! reduce {
+        ($($name:ident),*) => {
+            $(if let Some($name(dim)) = op.downcast_ref::<$name>() {
+                return Some(NodeDef::$name { dim: *dim });
+            })*
+        };
+    }
+    unit!(Contiguous, Log2, Exp2, Sin, Recip, Sqrt, Add, Mul, Mod, LessThan, Select);
+    reduce!(SumReduce, MaxReduce, MinReduce, ProdReduce, CumSum, IndexAdd);
+    if let Some(Cast(dtype)) = op.downcast_ref::<Cast>() {
+        return Some(NodeDef::Cast { dtype: *dtype });
+    }
+    if let Some(Constant(value)) = op.downcast_ref::<Constant>() {
+        return Some(NodeDef::Constant {
+            value: value.clone(),
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_graph_file() {
+        let mut cx = Graph::new();
+        let x = cx.input::<(Dyn<'b'>, LConst<3>)>("x");
+        let w = cx.tensor::<R2<3, 4>>().set(random_vec(12));
+        let out = x.matmul(w).softmax::<LAxis<1>>() * x.sum_reduce::<_, LAxis<1>>().expand();
+        let out = out.cast::<f16>().retrieve();
+        let path = std::env::temp_dir().join(format!("luminal_graph_{}.bin", std::process::id()));
+        cx.save(&path, &[(out.id, out.shape)]).unwrap();
+        let input = random_vec(6);
+        cx.bind("x", input.clone(), &[2, 3]).unwrap();
+        cx.execute();
+
+        let mut loaded = Graph::new();
+        let outputs = loaded.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let loaded_out =
+            GraphTensor::<()>::from_id(outputs[0].0, outputs[0].1, &mut loaded).retrieve();
+        loaded.bind("x", input, &[2, 3]).unwrap();
+        loaded.execute();
+        assert_exact(&loaded_out.data(), &out.data());
+
+        assert!(matches!(
+            Graph::new().load_bytes(&[1, 0]),
+            Err(LuminalError::BadGraphFile(_))
+        ));
+        // Backend ops can't be written out
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R1<2>>().set([1., 2.]);
+        let b = a
+            .graph()
+            .add_op(Transfer { from: 0, to: 1 })
+            .input(a.id, 0, a.shape)
+            .finish();
+        assert_eq!(
+            cx.to_bytes(&[(b, a.shape)]),
+            Err(LuminalError::UnserializableOp(
+                "Transfer { from: 0, to: 1 }".to_string()
+            ))
+        );
+    }
+}
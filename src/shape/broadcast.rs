@@ -50,8 +50,6 @@ macro_rules! length {
     ($x:tt $($xs:tt)*) => {1 + length!($($xs)*)};
 }
 
-pub(crate) use length;
-
 // Defines all reduce/broadcast rules recursively
 macro_rules! broadcast_to_all {
     ([$($s1:ident)*] [$($s2:ident)*] [$($ax:tt)*] [] [$axis:tt $($axes:tt)*]) => {
@@ -107,3 +105,37 @@ impl<Src: Shape, Dst: Shape, Ax: Axes> ReduceStridesTo<Dst, Ax> for Src where
     Self: ReduceShapeTo<Dst, Ax>
 {
 }
+
+/// Marker for shapes that can be broadcast to `Dst` the way numpy does it: dimensions line up from the right, and each one
+/// must either match or have a size of 1. Where sizes are known at compile time, they're checked.
+pub trait BroadcastableTo<Dst: Shape>: Shape {
+    const TYPE_CHECK: ();
+}
+
+impl<Src: Shape, Dst: Shape> BroadcastableTo<Dst> for Src {
+    const TYPE_CHECK: () = {
+        assert!(
+            Src::NUM_DIMS <= Dst::NUM_DIMS,
+            "Can't broadcast to a shape with fewer dimensions"
+        );
+        assert!(
+            broadcast_compatible(Src::STATIC_DIMS, Dst::STATIC_DIMS),
+            "Dimensions being broadcast must either match or have a size of 1"
+        );
+    };
+}
+
+/// Whether each of `src`'s dimensions, aligned from the right, can broadcast to `dst`'s. Unknown sizes always pass.
+const fn broadcast_compatible(src: &[Option<usize>], dst: &[Option<usize>]) -> bool {
+    let offset = dst.len() - src.len();
+    let mut i = 0;
+    while i < src.len() {
+        if let (Some(s), Some(d)) = (src[i], dst[i + offset]) {
+            if s != 1 && s != d {
+                return false;
+            }
+        }
+        i += 1;
+    }
+    true
+}
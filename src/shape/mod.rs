@@ -3,8 +3,10 @@ mod broadcast;
 mod padding;
 mod permute;
 mod realize;
+mod reshape;
 mod slice;
 mod symbolic;
+pub mod testing;
 mod tracker;
 
 pub use axes::*;
@@ -12,6 +14,7 @@ pub use broadcast::*;
 pub use padding::*;
 pub use permute::*;
 pub use realize::*;
+pub use reshape::*;
 pub use slice::*;
 pub use symbolic::*;
 pub use tracker::*;
@@ -22,6 +25,8 @@ pub use tracker::*;
 pub trait Dimension:
     'static + Copy + Clone + std::fmt::Debug + Send + Sync + Eq + PartialEq
 {
+    /// The size, if it's known at compile time
+    const STATIC_SIZE: Option<usize> = None;
     fn size() -> Expression;
 }
 
@@ -44,6 +49,7 @@ impl<const C: char> Dimension for Dyn<C> {
 #[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
 pub struct Const<const M: usize>;
 impl<const M: usize> Dimension for Const<M> {
+    const STATIC_SIZE: Option<usize> = Some(M);
     fn size() -> Expression {
         M.into()
     }
@@ -150,6 +156,9 @@ pub trait Shape:
     /// The number of dimensions the shape has
     const NUM_DIMS: usize;
 
+    /// The size of each dimension, where it's known at compile time
+    const STATIC_DIMS: &'static [Option<usize>];
+
     /// Is `[usize; Self::NUM_DIMS]`, but that is not usable yet.
     type Concrete: std::fmt::Debug
         + Clone
@@ -222,6 +231,7 @@ macro_rules! shape {
     (($($D:tt $Idx:tt),*), rank=$Num:expr, all=$All:tt, all_but_last=$AllButLast:ty) => {
         impl<$($D: Dimension, )*> Shape for ($($D, )*) {
             const NUM_DIMS: usize = $Num;
+            const STATIC_DIMS: &'static [Option<usize>] = &[$($D::STATIC_SIZE, )*];
             type Concrete = [usize; $Num];
             type AllAxes = $All<$($Idx,)*>;
             type AllButLast = $AllButLast;
@@ -244,6 +254,7 @@ macro_rules! shape {
 
         impl Shape for [usize; $Num] {
             const NUM_DIMS: usize = $Num;
+            const STATIC_DIMS: &'static [Option<usize>] = &[None; $Num];
             type Concrete = Self;
             type AllAxes = $All<$($Idx,)*>;
             type AllButLast = $AllButLast;
@@ -263,6 +274,7 @@ macro_rules! shape {
 
 impl Shape for () {
     const NUM_DIMS: usize = 0;
+    const STATIC_DIMS: &'static [Option<usize>] = &[];
     type Concrete = [usize; 0];
     type AllAxes = Axis<0>;
     type LastAxis = Axis<0>;
@@ -302,6 +314,17 @@ impl<Src: ConstShape, Dst: ConstShape> AssertSameNumel<Dst> for Src {
     const TYPE_CHECK: () = assert!(Src::NUMEL == Dst::NUMEL);
 }
 
+/// Marker for shapes that always hold exactly one element: scalars, and shapes where every dimension is 1
+pub trait SingleElementShape: Shape {}
+
+impl SingleElementShape for () {}
+impl SingleElementShape for (Const<1>,) {}
+impl SingleElementShape for (Const<1>, Const<1>) {}
+impl SingleElementShape for (Const<1>, Const<1>, Const<1>) {}
+impl SingleElementShape for (Const<1>, Const<1>, Const<1>, Const<1>) {}
+impl SingleElementShape for (Const<1>, Const<1>, Const<1>, Const<1>, Const<1>) {}
+impl SingleElementShape for (Const<1>, Const<1>, Const<1>, Const<1>, Const<1>, Const<1>) {}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ReshapeDim {
     /// A known size for the dim
@@ -0,0 +1,112 @@
+use super::*;
+
+/// Marker for shapes that become `Dst` when the adjacent axes `Ax` are merged into one. Where all the sizes are known at
+/// compile time, the merged dimension is checked to be the product of the two.
+pub trait MergeDimsTo<Dst: Shape, Ax>: Shape {
+    const TYPE_CHECK: ();
+}
+
+/// Marker for shapes that become `Dst` when the axis `Ax` is split in two. Where all the sizes are known at compile time,
+/// the split dimensions are checked to multiply out to the original.
+pub trait SplitDimTo<Dst: Shape, Ax>: Shape {
+    const TYPE_CHECK: ();
+}
+
+/// Marker for shapes that can be flattened into the single dimension `D`. Where all the sizes are known at compile time,
+/// `D` is checked to hold every element.
+pub trait FlattenTo<D: Dimension>: Shape {
+    const TYPE_CHECK: ();
+}
+
+/// Whether `whole` is the product of `parts`. Unknown sizes can't be checked, so they always pass.
+const fn product_matches(whole: Option<usize>, parts: &[Option<usize>]) -> bool {
+    let Some(whole) = whole else {
+        return true;
+    };
+    let mut product = 1;
+    let mut i = 0;
+    while i < parts.len() {
+        match parts[i] {
+            Some(p) => product *= p,
+            None => return true,
+        }
+        i += 1;
+    }
+    product == whole
+}
+
+macro_rules! merge_dims {
+    ([$($Pre:ident)*] $A:ident $B:ident [$($Post:ident)*], $Ax:ty) => {
+        impl<$($Pre: Dimension, )* $A: Dimension, $B: Dimension, M: Dimension, $($Post: Dimension, )*>
+            MergeDimsTo<($($Pre, )* M, $($Post, )*), $Ax> for ($($Pre, )* $A, $B, $($Post, )*)
+        {
+            const TYPE_CHECK: () = assert!(
+                product_matches(M::STATIC_SIZE, &[$A::STATIC_SIZE, $B::STATIC_SIZE]),
+                "Merged dimension must be the product of the dimensions being merged"
+            );
+        }
+    };
+}
+
+macro_rules! split_dim {
+    ([$($Pre:ident)*] $A:ident [$($Post:ident)*], $Ax:ty) => {
+        impl<$($Pre: Dimension, )* $A: Dimension, X: Dimension, Y: Dimension, $($Post: Dimension, )*>
+            SplitDimTo<($($Pre, )* X, Y, $($Post, )*), $Ax> for ($($Pre, )* $A, $($Post, )*)
+        {
+            const TYPE_CHECK: () = assert!(
+                product_matches($A::STATIC_SIZE, &[X::STATIC_SIZE, Y::STATIC_SIZE]),
+                "Split dimensions must multiply out to the dimension being split"
+            );
+        }
+    };
+}
+
+macro_rules! flatten {
+    ($($D:ident)*) => {
+        impl<$($D: Dimension, )* F: Dimension> FlattenTo<F> for ($($D, )*) {
+            const TYPE_CHECK: () = assert!(
+                product_matches(F::STATIC_SIZE, &[$($D::STATIC_SIZE),*]),
+                "Flattened dimension must hold every element"
+            );
+        }
+    };
+}
+
+merge_dims!([] D1 D2 [], Axes2<0, 1>);
+merge_dims!([] D1 D2 [D3], Axes2<0, 1>);
+merge_dims!([D1] D2 D3 [], Axes2<1, 2>);
+merge_dims!([] D1 D2 [D3 D4], Axes2<0, 1>);
+merge_dims!([D1] D2 D3 [D4], Axes2<1, 2>);
+merge_dims!([D1 D2] D3 D4 [], Axes2<2, 3>);
+merge_dims!([] D1 D2 [D3 D4 D5], Axes2<0, 1>);
+merge_dims!([D1] D2 D3 [D4 D5], Axes2<1, 2>);
+merge_dims!([D1 D2] D3 D4 [D5], Axes2<2, 3>);
+merge_dims!([D1 D2 D3] D4 D5 [], Axes2<3, 4>);
+merge_dims!([] D1 D2 [D3 D4 D5 D6], Axes2<0, 1>);
+merge_dims!([D1] D2 D3 [D4 D5 D6], Axes2<1, 2>);
+merge_dims!([D1 D2] D3 D4 [D5 D6], Axes2<2, 3>);
+merge_dims!([D1 D2 D3] D4 D5 [D6], Axes2<3, 4>);
+merge_dims!([D1 D2 D3 D4] D5 D6 [], Axes2<4, 5>);
+
+split_dim!([] D1 [], Axis<0>);
+split_dim!([] D1 [D2], Axis<0>);
+split_dim!([D1] D2 [], Axis<1>);
+split_dim!([] D1 [D2 D3], Axis<0>);
+split_dim!([D1] D2 [D3], Axis<1>);
+split_dim!([D1 D2] D3 [], Axis<2>);
+split_dim!([] D1 [D2 D3 D4], Axis<0>);
+split_dim!([D1] D2 [D3 D4], Axis<1>);
+split_dim!([D1 D2] D3 [D4], Axis<2>);
+split_dim!([D1 D2 D3] D4 [], Axis<3>);
+split_dim!([] D1 [D2 D3 D4 D5], Axis<0>);
+split_dim!([D1] D2 [D3 D4 D5], Axis<1>);
+split_dim!([D1 D2] D3 [D4 D5], Axis<2>);
+split_dim!([D1 D2 D3] D4 [D5], Axis<3>);
+split_dim!([D1 D2 D3 D4] D5 [], Axis<4>);
+
+flatten!(D1);
+flatten!(D1 D2);
+flatten!(D1 D2 D3);
+flatten!(D1 D2 D3 D4);
+flatten!(D1 D2 D3 D4 D5);
+flatten!(D1 D2 D3 D4 D5 D6);
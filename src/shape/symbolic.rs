@@ -328,6 +328,20 @@ impl<S: ExpressionStorage> GenericExpression<S> {
     pub fn exec(&self, variables: &FxHashMap<char, usize>) -> Option<usize> {
         self.exec_stack(variables, &mut Vec::new())
     }
+    /// Evaluate the expression given variables, naming the first variable that's missing if it can't be
+    pub fn try_exec(
+        &self,
+        variables: &FxHashMap<char, usize>,
+    ) -> Result<usize, crate::prelude::LuminalError> {
+        self.exec(variables).ok_or_else(|| {
+            crate::prelude::LuminalError::UnknownDimension(
+                self.to_symbols()
+                    .into_iter()
+                    .find(|s| !variables.contains_key(s))
+                    .unwrap_or('-'),
+            )
+        })
+    }
     /// Evaluate the expression given variables. This function requires a stack to be given for use as storage
     pub fn exec_stack(
         &self,
@@ -0,0 +1,275 @@
+//! Helpers for checking shape trackers against a plain dense reference
+
+use crate::prelude::*;
+
+/// A movement op that can be applied to both a shape tracker and a dense array
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MovementOp {
+    Permute(Vec<usize>),
+    /// Insert a broadcasted dimension of some size at an axis
+    Expand(usize, usize),
+    /// Slice an axis to start..end
+    Slice(usize, usize, usize),
+    /// Pad an axis with zeros before and after
+    Pad(usize, usize, usize),
+    /// Remove an axis. All slices along the axis must be the same, so it must either be expanded or have a size of 1.
+    RemoveDim(usize),
+}
+
+/// A dense row-major array, used as the reference for what a shape tracker should produce
+#[derive(Debug, Clone, PartialEq)]
+pub struct DenseArray {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+impl DenseArray {
+    /// An array filled with 1, 2, 3, ...
+    pub fn arange(shape: &[usize]) -> Self {
+        Self {
+            shape: shape.to_vec(),
+            data: (1..=shape.iter().product::<usize>())
+                .map(|i| i as f32)
+                .collect(),
+        }
+    }
+
+    fn strides(&self) -> Vec<usize> {
+        let mut strides = vec![1; self.shape.len()];
+        for i in (0..self.shape.len().saturating_sub(1)).rev() {
+            strides[i] = strides[i + 1] * self.shape[i + 1];
+        }
+        strides
+    }
+
+    /// Build a new array by looking up each output index's element in this array (None gives a zero)
+    fn map_indexes(&self, shape: Vec<usize>, f: impl Fn(&[usize]) -> Option<Vec<usize>>) -> Self {
+        let strides = self.strides();
+        let n = shape.iter().product::<usize>();
+        let mut index = vec![0; shape.len()];
+        let mut data = Vec::with_capacity(n);
+        for i in 0..n {
+            let mut rem = i;
+            for d in (0..shape.len()).rev() {
+                index[d] = rem % shape[d];
+                rem /= shape[d];
+            }
+            data.push(
+                f(&index)
+                    .map(|src| {
+                        self.data[src.iter().zip(&strides).map(|(a, b)| a * b).sum::<usize>()]
+                    })
+                    .unwrap_or_default(),
+            );
+        }
+        Self { shape, data }
+    }
+
+    pub fn apply(&self, op: &MovementOp) -> Self {
+        match op {
+            MovementOp::Permute(axes) => {
+                self.map_indexes(axes.iter().map(|a| self.shape[*a]).collect(), |ind| {
+                    let mut src = vec![0; ind.len()];
+                    for (i, a) in axes.iter().enumerate() {
+                        src[*a] = ind[i];
+                    }
+                    Some(src)
+                })
+            }
+            MovementOp::Expand(axis, size) => {
+                let mut shape = self.shape.clone();
+                shape.insert(*axis, *size);
+                self.map_indexes(shape, |ind| {
+                    let mut src = ind.to_vec();
+                    src.remove(*axis);
+                    Some(src)
+                })
+            }
+            MovementOp::Slice(axis, start, end) => {
+                let mut shape = self.shape.clone();
+                shape[*axis] = (*end).min(shape[*axis]).saturating_sub(*start);
+                self.map_indexes(shape, |ind| {
+                    let mut src = ind.to_vec();
+                    src[*axis] += start;
+                    Some(src)
+                })
+            }
+            MovementOp::Pad(axis, before, after) => {
+                let mut shape = self.shape.clone();
+                shape[*axis] += before + after;
+                self.map_indexes(shape, |ind| {
+                    let mut src = ind.to_vec();
+                    src[*axis] = ind[*axis].checked_sub(*before)?;
+                    (src[*axis] < self.shape[*axis]).then_some(src)
+                })
+            }
+            MovementOp::RemoveDim(axis) => {
+                let mut shape = self.shape.clone();
+                shape.remove(*axis);
+                self.map_indexes(shape, |ind| {
+                    let mut src = ind.to_vec();
+                    src.insert(*axis, 0);
+                    Some(src)
+                })
+            }
+        }
+    }
+}
+
+/// Physical data viewed through a shape tracker, the way a graph tensor sees it
+#[derive(Debug, Clone)]
+pub struct TrackedArray {
+    pub data: Vec<f32>,
+    pub tracker: ShapeTracker,
+}
+
+impl TrackedArray {
+    pub fn new(array: &DenseArray) -> Self {
+        Self {
+            data: array.data.clone(),
+            tracker: ShapeTracker::new(
+                &array
+                    .shape
+                    .iter()
+                    .map(|d| Expression::from(*d))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+
+    /// The logical shape
+    pub fn shape(&self) -> Vec<usize> {
+        self.tracker.shape_usize()
+    }
+
+    /// Read out the logical elements through the tracker's index and valid expressions
+    pub fn materialize(&self) -> DenseArray {
+        let (ind, val) = (
+            self.tracker.index_expression(),
+            self.tracker.valid_expression(),
+        );
+        let mut stack = vec![];
+        DenseArray {
+            shape: self.shape(),
+            data: (0..self.tracker.n_elements().to_usize().unwrap())
+                .map(|i| {
+                    if val.exec_single_var_stack(i, &mut stack) != 0 {
+                        self.data[ind.exec_single_var_stack(i, &mut stack)]
+                    } else {
+                        0.
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Copy the data out into a fresh contiguous layout, like a `Contiguous` op
+    pub fn make_contiguous(&mut self) {
+        *self = Self::new(&self.materialize());
+    }
+
+    /// Apply a movement op to the tracker. Like the high level ops, this goes contiguous first whenever the tracker can't
+    /// represent the op directly (slicing a padded dimension, padding a sliced one, or removing a real dimension).
+    pub fn apply(&mut self, op: &MovementOp) {
+        let st = &mut self.tracker;
+        match op {
+            MovementOp::Permute(axes) => st.permute(axes),
+            MovementOp::Expand(axis, size) => st.expand(*axis, *size),
+            MovementOp::Slice(axis, start, end) => {
+                let i = st.indexes[*axis];
+                if st.padding[i] != (0.into(), 0.into()) {
+                    self.make_contiguous();
+                }
+                let mut mask =
+                    vec![(Expression::from(0), Expression::from(i32::MAX)); self.tracker.len()];
+                mask[*axis] = ((*start).into(), (*end).into());
+                self.tracker.slice(&mask);
+            }
+            MovementOp::Pad(axis, before, after) => {
+                let i = st.indexes[*axis];
+                if st.mask[i] != (0.into(), i32::MAX.into()) {
+                    self.make_contiguous();
+                }
+                let mut padding =
+                    vec![(Expression::from(0), Expression::from(0)); self.tracker.len()];
+                padding[*axis] = ((*before).into(), (*after).into());
+                self.tracker.pad(&padding);
+            }
+            MovementOp::RemoveDim(axis) => {
+                if !st.fake[st.indexes[*axis]] {
+                    self.make_contiguous();
+                }
+                self.tracker.remove_dim(*axis);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Turn random numbers into an op that's valid for the current shape
+    fn to_op(array: &TrackedArray, (kind, a, b, c): (u8, u8, u8, u8)) -> Option<MovementOp> {
+        let (a, b, c) = (a as usize, b as usize, c as usize);
+        let shape = array.shape();
+        let axis = a % shape.len();
+        match kind {
+            0 => {
+                let mut axes = (0..shape.len()).collect::<Vec<_>>();
+                for i in (1..axes.len()).rev() {
+                    axes.swap(i, (b + c * i) % (i + 1));
+                }
+                Some(MovementOp::Permute(axes))
+            }
+            1 if shape.len() < 6 => Some(MovementOp::Expand(a % (shape.len() + 1), b % 3 + 1)),
+            2 if shape[axis] > 0 => {
+                let start = b % shape[axis];
+                Some(MovementOp::Slice(
+                    axis,
+                    start,
+                    start + 1 + c % (shape[axis] - start),
+                ))
+            }
+            3 => Some(MovementOp::Pad(axis, b % 3, c % 3)),
+            4 if shape.len() > 1 => {
+                let i = array.tracker.indexes[axis];
+                let unpadded = array.tracker.padding[i] == (0.into(), 0.into());
+                (shape[axis] == 1 || (array.tracker.fake[i] && unpadded))
+                    .then_some(MovementOp::RemoveDim(axis))
+            }
+            _ => None,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_tracker_matches_dense(
+            shape in prop::collection::vec(1usize..4, 1..4),
+            ops in prop::collection::vec((0u8..5, any::<u8>(), any::<u8>(), any::<u8>()), 0..8),
+        ) {
+            let mut reference = DenseArray::arange(&shape);
+            let mut tracked = TrackedArray::new(&reference);
+            for seed in ops {
+                let Some(op) = to_op(&tracked, seed) else {
+                    continue;
+                };
+                reference = reference.apply(&op);
+                tracked.apply(&op);
+                prop_assert_eq!(tracked.materialize(), reference.clone(), "after {:?}", op);
+                // Simplifying the tracker shouldn't change any flat index
+                let (st, simple) = (tracked.tracker, tracked.tracker.simplify());
+                let (ind, val) = (st.index_expression_no_simplify(), st.valid_expression_no_simplify());
+                let (s_ind, s_val) = (simple.index_expression_no_simplify(), simple.valid_expression_no_simplify());
+                for i in 0..reference.data.len() {
+                    let valid = val.exec_single_var(i) != 0;
+                    prop_assert_eq!(valid, s_val.exec_single_var(i) != 0, "{:?} simplified to {:?}", st, simple);
+                    if valid {
+                        prop_assert_eq!(ind.exec_single_var(i), s_ind.exec_single_var(i), "{:?} simplified to {:?}", st, simple);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -133,7 +133,7 @@ impl ShapeTracker {
 
     /// Create an expression to translate logical indexes into physical indexes
     pub fn index_expression(&self) -> BigExpression {
-        self.index_expression_no_simplify().simplify()
+        self.simplify().index_expression_no_simplify().simplify()
     }
 
     /// If this expression evaluates to 0, the logical index is invalid. Otherwise it is valid. No simplification
@@ -147,7 +147,8 @@ impl ShapeTracker {
         for i in self.indexes.into_iter().rev() {
             let (bottom_slice, top_slice) = self.mask[i];
             let logical_sh = pad_mask_dim(self.dims[i], self.padding[i], self.mask[i]);
-            if !self.fake[i] {
+            // Fake dims don't index into memory, but padding them still adds invalid regions
+            if !self.fake[i] || self.padding[i] != (0.into(), 0.into()) {
                 let dim_ind = (logical.clone() / acc.clone()) % logical_sh.clone();
                 let greater_than = self.padding[i].0.big() - bottom_slice;
                 if greater_than != 0 {
@@ -169,7 +170,101 @@ impl ShapeTracker {
 
     /// If this expression evaluates to 0, the logical index is invalid. Otherwise it is valid
     pub fn valid_expression(&self) -> BigExpression {
-        self.valid_expression_no_simplify().simplify()
+        self.simplify().valid_expression_no_simplify().simplify()
+    }
+
+    /// Fold a view taken on the contiguous output of this view into a single view over the original data.
+    ///
+    /// Returns None if the two can't be represented as one tracker, for instance when `view` reshapes the data, or when
+    /// this view is sliced or padded and `view` does anything more than read it straight through.
+    pub fn compose(&self, view: ShapeTracker) -> Option<ShapeTracker> {
+        if !view.is_reshaped() && view.shape() == self.shape() {
+            return Some(*self);
+        }
+        if self.is_sliced() || self.is_padded() {
+            return None;
+        }
+        // The real dims of the outer view must be exactly this view's logical dims
+        let real = (0..view.len())
+            .filter(|i| !view.fake[*i])
+            .collect::<Vec<_>>();
+        let shape = self.shape();
+        if real.len() != self.len()
+            || real
+                .iter()
+                .zip(&shape)
+                .any(|(i, s)| view.dims[*i].big() != *s)
+        {
+            return None;
+        }
+        let mut composed = *self;
+        let mut physical = vec![0; view.len()];
+        for (p, physical) in physical.iter_mut().enumerate() {
+            *physical = if view.fake[p] {
+                // Expanded in the outer view, so add a new fake dim
+                composed.dims.push(view.dims[p]);
+                composed.fake.push(true);
+                composed.mask.push((0.into(), i32::MAX.into()));
+                composed.padding.push((0.into(), 0.into()));
+                composed.dims.len() - 1
+            } else {
+                self.indexes[real.iter().position(|r| *r == p).unwrap()]
+            };
+            composed.mask[*physical] = view.mask[p];
+            composed.padding[*physical] = view.padding[p];
+        }
+        composed.indexes = view.indexes.iter().map(|i| physical[*i]).collect();
+        Some(composed)
+    }
+
+    /// Simplify the view without changing which physical element (if any) each flat logical index maps to.
+    ///
+    /// No-op slices are dropped, size 1 dimensions that don't carry an offset are removed, and neighbouring dimensions
+    /// that are laid out contiguously (or are both fake) are merged. The logical shape can lose dimensions, so this is only
+    /// for building index and valid expressions, not for ops that need the per-axis shape.
+    pub fn simplify(mut self) -> Self {
+        let no_padding = |st: &Self, i: usize| st.padding[i].0 == 0 && st.padding[i].1 == 0;
+        let no_mask = |st: &Self, i: usize| st.mask[i].0 == 0 && st.mask[i].1 == i32::MAX;
+        // Drop slices that cover the whole dimension
+        for i in 0..self.len() {
+            let full = (self.dims[i] + self.padding[i].0 + self.padding[i].1).to_usize();
+            if let (Some(end), Some(full)) = (self.mask[i].1.to_usize(), full) {
+                if self.mask[i].0 == 0 && end >= full {
+                    self.mask[i].1 = i32::MAX.into();
+                }
+            }
+        }
+        // Remove size 1 dimensions
+        let mut axis = 0;
+        while axis < self.len() {
+            let i = self.indexes[axis];
+            let removable = no_padding(&self, i)
+                && if self.fake[i] {
+                    pad_mask_dim(self.dims[i], self.padding[i], self.mask[i]) == 1
+                } else {
+                    self.dims[i] == 1 && self.mask[i].0 == 0
+                };
+            if removable && self.len() > 1 {
+                self.remove_dim(axis);
+            } else {
+                axis += 1;
+            }
+        }
+        // Merge neighbouring dimensions
+        let mut axis = 0;
+        while axis + 1 < self.len() {
+            let (i, j) = (self.indexes[axis], self.indexes[axis + 1]);
+            let untouched = [i, j]
+                .iter()
+                .all(|i| no_padding(&self, *i) && no_mask(&self, *i));
+            if untouched && self.fake[i] == self.fake[j] && (self.fake[i] || j == i + 1) {
+                self.dims[i] = self.dims[i] * self.dims[j];
+                self.remove_dim(axis + 1);
+            } else {
+                axis += 1;
+            }
+        }
+        self
     }
 
     /// The number of elements in this tensor, including padding and mask
@@ -240,8 +335,12 @@ impl ShapeTracker {
     /// Take a slice
     pub fn slice(&mut self, mask: &[(Expression, Expression)]) {
         for (ind, (b, t)) in mask.iter().enumerate().map(|(i, m)| (self.indexes[i], m)) {
-            self.mask[ind].0 = self.mask[ind].0.max(b.max(0));
-            self.mask[ind].1 = self.mask[ind].1.min(t.max(0));
+            // Slices are relative to any previous slice
+            let start = self.mask[ind].0;
+            self.mask[ind].0 = start + b.max(0);
+            if *t != i32::MAX {
+                self.mask[ind].1 = self.mask[ind].1.min(start + t.max(0));
+            }
         }
     }
 
@@ -284,17 +383,39 @@ impl ShapeTracker {
         dyn_dim_map: &FxHashMap<char, usize>,
         stack: &mut Vec<i64>,
     ) {
-        for d in self.dims.iter_mut() {
-            *d = d.exec_stack(dyn_dim_map, stack).unwrap().into();
+        if let Err(e) = self.try_resolve_global_dyn_dims_stack(dyn_dim_map, stack) {
+            panic!("{e}");
         }
-        for (a, b) in self.padding.iter_mut() {
-            *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
-            *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+    }
+
+    /// Given a dyn dim map, resolve global dyn dims into known dims, erroring if a dimension isn't in the map
+    pub fn try_resolve_global_dyn_dims_stack(
+        &mut self,
+        dyn_dim_map: &FxHashMap<char, usize>,
+        stack: &mut Vec<i64>,
+    ) -> Result<(), LuminalError> {
+        let resolve = |e: &mut Expression, stack: &mut Vec<i64>| {
+            *e = e
+                .exec_stack(dyn_dim_map, stack)
+                .ok_or_else(|| {
+                    LuminalError::UnknownDimension(
+                        e.to_symbols()
+                            .into_iter()
+                            .find(|s| !dyn_dim_map.contains_key(s))
+                            .unwrap_or('-'),
+                    )
+                })?
+                .into();
+            Ok(())
+        };
+        for d in self.dims.iter_mut() {
+            resolve(d, stack)?;
         }
-        for (a, b) in self.mask.iter_mut() {
-            *a = a.exec_stack(dyn_dim_map, stack).unwrap().into();
-            *b = b.exec_stack(dyn_dim_map, stack).unwrap().into();
+        for (a, b) in self.padding.iter_mut().chain(self.mask.iter_mut()) {
+            resolve(a, stack)?;
+            resolve(b, stack)?;
         }
+        Ok(())
     }
 
     pub fn is_sliced(&self) -> bool {
@@ -360,6 +481,32 @@ mod tests {
         println!("Val: {:?}", tracker.valid_expression());
     }
 
+    #[test]
+    fn test_simplify() {
+        // [2, 3, 4] -> expand to [2, 3, 5, 4] -> add a size 1 dim -> full slice on the first dim
+        let mut tracker = ShapeTracker::new(&[2.into(), 3.into(), 4.into()]);
+        tracker.expand(2, 5);
+        tracker.expand(0, 1);
+        tracker.slice(&[(0.into(), 1.into()), (0.into(), 2.into())]);
+        let simple = tracker.simplify();
+        // Size 1 dim is removed and the two leading real dims are merged
+        assert_eq!(simple.shape_usize(), vec![6, 5, 4]);
+        assert!(!simple.is_sliced());
+        let (a, b) = (
+            tracker.index_expression_no_simplify(),
+            simple.index_expression_no_simplify(),
+        );
+        assert!(b.terms.len() < a.terms.len());
+        for i in 0..120 {
+            assert_eq!(a.exec_single_var(i), b.exec_single_var(i));
+        }
+
+        // Permuted dims can't be merged
+        let mut tracker = ShapeTracker::new(&[2.into(), 3.into()]);
+        tracker.permute(&[1, 0]);
+        assert_eq!(tracker.simplify().len(), 2);
+    }
+
     #[test]
     fn test_symbolic_idx() {
         let mut cx = Graph::new();
@@ -375,4 +522,47 @@ mod tests {
 
         println!("x0: {:?}", x0.shape.index_expression());
     }
+
+    /// Read out the elements of a view of `data`, with zeros where it's invalid
+    fn read(tracker: &ShapeTracker, data: &[usize]) -> Vec<usize> {
+        let (ind, val) = (tracker.index_expression(), tracker.valid_expression());
+        (0..tracker.n_elements().to_usize().unwrap())
+            .map(|i| {
+                if val.exec_single_var(i) != 0 {
+                    data[ind.exec_single_var(i)]
+                } else {
+                    0
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nested_slice() {
+        let data = (1..=10).collect::<Vec<_>>();
+        let mut tracker = ShapeTracker::new(&[10.into()]);
+        tracker.slice(&[(2.into(), 8.into())]);
+        assert_eq!(read(&tracker, &data), [3, 4, 5, 6, 7, 8]);
+        // The second slice is relative to the first
+        tracker.slice(&[(1.into(), 4.into())]);
+        assert_eq!(tracker.shape_usize(), [3]);
+        assert_eq!(read(&tracker, &data), [4, 5, 6]);
+        // An unbounded end keeps the current one
+        tracker.slice(&[(1.into(), i32::MAX.into())]);
+        assert_eq!(read(&tracker, &data), [5, 6]);
+        // Ends past the current one don't grow the view
+        tracker.slice(&[(0.into(), 5.into())]);
+        assert_eq!(read(&tracker, &data), [5, 6]);
+    }
+
+    #[test]
+    fn test_pad_expanded_dim() {
+        let data = vec![1, 2];
+        let mut tracker = ShapeTracker::new(&[2.into()]);
+        tracker.expand(0, 2);
+        tracker.pad(&[(1.into(), 1.into()), (0.into(), 0.into())]);
+        assert_eq!(tracker.shape_usize(), [4, 2]);
+        // The padded rows read zeros instead of the expanded data
+        assert_eq!(read(&tracker, &data), [0, 0, 1, 2, 1, 2, 0, 0]);
+    }
 }
@@ -0,0 +1,331 @@
+use std::any::Any;
+
+use itertools::Itertools;
+use petgraph::Direction;
+
+use crate::{
+    generic_compiler::{constant_data, matmul_mul},
+    op::Function,
+    prelude::*,
+};
+
+/// A matrix in compressed sparse row format, holding only its nonzero elements
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsrMatrix {
+    pub rows: usize,
+    pub cols: usize,
+    /// Where each row's elements start in `cols_of` and `values`, with one extra entry for where the last row ends
+    pub row_starts: Vec<usize>,
+    /// Column of each nonzero element
+    pub cols_of: Vec<u32>,
+    pub values: Vec<f32>,
+}
+
+impl CsrMatrix {
+    /// Compress a row-major dense matrix, dropping its zeros
+    pub fn from_dense(rows: usize, cols: usize, data: &[f32]) -> Self {
+        assert_eq!(
+            data.len(),
+            rows * cols,
+            "Dense data doesn't match its shape"
+        );
+        let mut matrix = Self {
+            rows,
+            cols,
+            row_starts: vec![0],
+            cols_of: vec![],
+            values: vec![],
+        };
+        for row in data.chunks(cols.max(1)).take(rows) {
+            for (col, value) in row.iter().enumerate().filter(|(_, v)| **v != 0.) {
+                matrix.cols_of.push(col as u32);
+                matrix.values.push(*value);
+            }
+            matrix.row_starts.push(matrix.values.len());
+        }
+        matrix
+    }
+
+    pub fn to_dense(&self) -> Vec<f32> {
+        let mut data = vec![0.; self.rows * self.cols];
+        for (row, (start, end)) in self.row_starts.iter().tuple_windows().enumerate() {
+            for i in *start..*end {
+                data[row * self.cols + self.cols_of[i] as usize] = self.values[i];
+            }
+        }
+        data
+    }
+
+    /// Number of stored elements
+    pub fn nnz(&self) -> usize {
+        self.values.len()
+    }
+
+    /// The fraction of elements that are zero
+    pub fn sparsity(&self) -> f32 {
+        1. - self.nnz() as f32 / (self.rows * self.cols).max(1) as f32
+    }
+}
+
+impl Data for CsrMatrix {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self.row_starts.as_slice())
+            + std::mem::size_of_val(self.cols_of.as_slice())
+            + std::mem::size_of_val(self.values.as_slice())
+    }
+}
+
+/// Compress a dense matrix into a `CsrMatrix`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToCsr;
+
+impl Operator for ToCsr {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let (tensor, st) = &inp[0];
+        let view = HostTensorView::new(
+            tensor.borrowed().f32s().unwrap_or_else(|e| panic!("{e}")),
+            *st,
+        );
+        let [rows, cols] = view.shape()[..] else {
+            panic!(
+                "Only matrices can be made sparse, got shape {:?}",
+                view.shape()
+            );
+        };
+        vec![Tensor::new(CsrMatrix::from_dense(
+            rows,
+            cols,
+            &view.to_vec(),
+        ))]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Multiply dense rows by the transpose of a sparse matrix: an input of shape [..., K] and a `CsrMatrix` of shape [N, K]
+/// give an output of shape [..., N]. This is `a.matmul(w)` with `w` of shape [K, N] stored transposed, so each output
+/// only reads the inputs its row of weights doesn't skip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseMatMul;
+
+impl Operator for SparseMatMul {
+    fn infer_shape(&self, input_shapes: &[ShapeTracker]) -> Option<ShapeTracker> {
+        let mut shape = input_shapes[0].shape();
+        *shape.last_mut()? = input_shapes[1].shape()[0].clone();
+        Some(ShapeTracker::new(
+            &shape.into_iter().map(|d| d.small()).collect::<Vec<_>>(),
+        ))
+    }
+    fn cost(&self, input_shapes: &[ShapeTracker]) -> Option<OpCost> {
+        // Without the weights there's no telling how many elements are stored, so assume it's dense
+        let rows =
+            input_shapes[0].n_elements().to_usize()? / input_shapes[1].shape()[1].to_usize()?;
+        let weights = input_shapes[1].n_elements().to_usize()?;
+        Some(OpCost {
+            flops: 2 * rows * weights,
+            bytes_read: (input_shapes[0].n_elements().to_usize()? + weights) * 4,
+            bytes_written: rows * input_shapes[1].shape()[0].to_usize()? * 4,
+        })
+    }
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let weights = inp[1]
+            .0
+            .borrowed()
+            .get::<CsrMatrix>()
+            .unwrap_or_else(|e| panic!("{e}"));
+        let input = HostTensorView::new(
+            inp[0].0.borrowed().f32s().unwrap_or_else(|e| panic!("{e}")),
+            inp[0].1,
+        )
+        .to_vec();
+        let mut out = vec![0.; input.len() / weights.cols.max(1) * weights.rows];
+        for (row, out) in input
+            .chunks(weights.cols.max(1))
+            .zip(out.chunks_mut(weights.rows.max(1)))
+        {
+            for (o, (start, end)) in out
+                .iter_mut()
+                .zip(weights.row_starts.iter().tuple_windows())
+            {
+                *o = (*start..*end)
+                    .map(|i| row[weights.cols_of[i] as usize] * weights.values[i])
+                    .sum();
+            }
+        }
+        vec![Tensor::new(out)]
+    }
+    fn into_send(self: Box<Self>) -> Option<Box<dyn Operator + Send>> {
+        Some(self)
+    }
+}
+
+/// Swap matmuls against kept weights that are mostly zeros for `SparseMatMul`s, converting the weights with `ToCsr`.
+///
+/// Weights are read when the graph is compiled, so they need their data set by then, and they're kept as CSR from the
+/// first run on. Only weights at least `min_sparsity` zeros are swapped, since below that the index lookups and scattered
+/// reads cost more than skipping the zeros saves.
+/// ```rust
+/// use luminal::prelude::*;
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R2<1, 4>>().set([[1., 2., 3., 4.]]);
+/// let w = cx
+///     .tensor::<R2<4, 2>>()
+///     .set([[0., 1.], [0., 0.], [2., 0.], [0., 0.]])
+///     .keep();
+/// let mut b = a.matmul(w).retrieve();
+/// cx.compile(SparsifyWeights::default(), &mut b);
+/// cx.execute();
+/// assert_eq!(b.data(), vec![6., 1.]);
+/// ```
+#[derive(Debug)]
+pub struct SparsifyWeights {
+    pub min_sparsity: f32,
+}
+
+impl Default for SparsifyWeights {
+    fn default() -> Self {
+        Self { min_sparsity: 0.75 }
+    }
+}
+
+impl Compiler for SparsifyWeights {
+    type Output = ();
+    fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+        for sum in graph.node_indices().collect_vec() {
+            if !graph.contains_node(sum) || !graph.is_op::<SumReduce>(sum) {
+                continue;
+            }
+            let Some(mul) = matmul_mul(graph, sum) else {
+                continue;
+            };
+            let srcs = graph.get_sources(mul);
+            let rank = srcs[0].2.len();
+            // The weight is only broadcast along the batch and row dimensions, and the input only along the columns
+            let is_weight =
+                |st: &ShapeTracker| (0..rank).all(|i| st.fake[st.indexes[i]] == (i + 2 < rank));
+            let is_input =
+                |st: &ShapeTracker| (0..rank).all(|i| st.fake[st.indexes[i]] == (i + 2 == rank));
+            let Some(((input, input_out, input_st), (weight, weight_out, weight_st))) =
+                [(0, 1), (1, 0)]
+                    .into_iter()
+                    .map(|(i, w)| (srcs[i], srcs[w]))
+                    .find(|((_, _, i), (w, _, w_st))| {
+                        is_input(i)
+                            && is_weight(w_st)
+                            && graph.no_delete.contains(w)
+                            && graph.is_op::<Function>(*w)
+                    })
+            else {
+                continue;
+            };
+            let (Some(n), Some(k)) = (
+                weight_st.shape()[rank - 2].to_usize(),
+                weight_st.shape()[rank - 1].to_usize(),
+            ) else {
+                continue;
+            };
+            let mut weight_inner = weight_st;
+            for axis in (0..rank - 2).rev() {
+                weight_inner.remove_dim(axis);
+            }
+            let Some(dense) = constant_data(graph, weight, weight_out) else {
+                continue;
+            };
+            let dense = HostTensorView::new(dense.f32s().unwrap(), weight_inner).to_vec();
+            if CsrMatrix::from_dense(n, k, &dense).sparsity() < self.min_sparsity {
+                continue;
+            }
+
+            let csr = graph
+                .add_op(ToCsr)
+                .input(weight, weight_out, weight_inner)
+                .finish();
+            // Convert once and hold onto the result, and free the dense weights if nothing else needs them
+            graph.no_delete.insert(csr);
+            if graph.edges_directed(weight, Direction::Outgoing).count() == 2
+                && !graph.to_retrieve.contains_key(&weight)
+            {
+                graph.no_delete.remove(&weight);
+            }
+            let mut input_inner = input_st;
+            input_inner.remove_dim(rank - 2);
+            let sparse = graph
+                .add_op(SparseMatMul)
+                .input(input, input_out, input_inner)
+                .input(csr, 0, ShapeTracker::new(&[n.into(), k.into()]))
+                .finish();
+            move_outgoing_edge(sum, sparse, &mut graph.graph);
+            remap(sum, sparse, &mut ids, graph);
+            graph.remove_node(sum);
+            graph.remove_node(mul);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    /// Random weights with roughly `sparsity` of them zeroed
+    fn pruned(n: usize, sparsity: f32) -> Vec<f32> {
+        random_vec(n)
+            .into_iter()
+            .enumerate()
+            .map(|(i, w)| {
+                if (i * 7919 % 100) as f32 / 100. < sparsity {
+                    0.
+                } else {
+                    w
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_csr_round_trip() {
+        let dense = pruned(12, 0.5);
+        let csr = CsrMatrix::from_dense(3, 4, &dense);
+        assert_eq!(csr.nnz(), dense.iter().filter(|d| **d != 0.).count());
+        assert_eq!(csr.row_starts.len(), 4);
+        assert_exact(&csr.to_dense(), &dense);
+    }
+
+    #[test]
+    fn test_sparsify_weights() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<R3<2, 3, 8>>().set(random_vec(48));
+        let sparse = cx.tensor::<R2<8, 5>>().set(pruned(40, 0.9)).keep();
+        let dense = cx.tensor::<R2<8, 5>>().set(random_vec(40)).keep();
+        let mut b = a.matmul(sparse).retrieve();
+        let mut c = a.matmul(dense).retrieve();
+        cx.execute();
+        let (b_dense, c_dense) = (b.data(), c.data());
+        b.drop();
+        c.drop();
+
+        cx.compile(SparsifyWeights::default(), (&mut b, &mut c));
+        // Only the mostly zero weights are swapped, and only their sparse copy is held onto
+        assert_eq!(
+            cx.node_indices()
+                .filter(|n| cx.is_op::<SparseMatMul>(*n))
+                .collect::<Vec<_>>(),
+            vec![b.id]
+        );
+        assert!(!cx.no_delete.contains(&sparse.id));
+        assert!(cx.no_delete.contains(&dense.id));
+        for _ in 0..2 {
+            cx.execute();
+            assert_close(&b.data(), &b_dense);
+            assert_close(&c.data(), &c_dense);
+            b.drop();
+            c.drop();
+        }
+    }
+}
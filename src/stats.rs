@@ -0,0 +1,234 @@
+use std::fmt::Display;
+
+use itertools::Itertools;
+use petgraph::{algo::toposort, Direction};
+use rustc_hash::FxHashMap;
+
+use crate::{op::Constant, prelude::*};
+
+/// How much work one run of a graph does, estimated from the cost of each op with the current dynamic dimensions.
+/// Compare these against a device's peak FLOPs and memory bandwidth to see how close a run gets to them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GraphStats {
+    /// Summed over every op with a cost estimate
+    pub total: OpCost,
+    /// Cost of every op with an estimate, in execution order
+    pub ops: Vec<(NodeIndex, String, OpCost)>,
+    /// Ops without a cost estimate, which aren't counted in the totals. Loads and constants aren't included.
+    pub unknown_ops: Vec<(NodeIndex, String)>,
+    /// Number of elements in kept source tensors, like loaded weights
+    pub parameters: usize,
+    /// Most bytes held at once when running in execution order, with every tensor freed after its last consumer unless
+    /// it's kept
+    pub peak_memory: usize,
+    /// Most bytes held at once on each device. Nodes without a device count towards device 0.
+    pub device_peak_memory: FxHashMap<usize, usize>,
+}
+
+impl GraphStats {
+    /// Costs summed by op name, most flops first
+    pub fn by_op(&self) -> Vec<(String, OpCost)> {
+        let mut by_op = FxHashMap::<String, OpCost>::default();
+        for (_, name, cost) in &self.ops {
+            let total = by_op.entry(name.clone()).or_default();
+            total.flops += cost.flops;
+            total.bytes_read += cost.bytes_read;
+            total.bytes_written += cost.bytes_written;
+        }
+        by_op
+            .into_iter()
+            .sorted_by(|(a, ac), (b, bc)| bc.flops.cmp(&ac.flops).then(a.cmp(b)))
+            .collect()
+    }
+
+    /// Flops per byte moved over the whole graph
+    pub fn arithmetic_intensity(&self) -> f32 {
+        self.total.flops as f32 / (self.total.bytes_read + self.total.bytes_written).max(1) as f32
+    }
+}
+
+impl Display for GraphStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "FLOPs: {}", self.total.flops)?;
+        writeln!(f, "Bytes read: {}", self.total.bytes_read)?;
+        writeln!(f, "Bytes written: {}", self.total.bytes_written)?;
+        writeln!(f, "Parameters: {}", self.parameters)?;
+        writeln!(f, "Peak memory: {} bytes", self.peak_memory)?;
+        for (name, cost) in self.by_op() {
+            writeln!(
+                f,
+                "  {name}: {} FLOPs, {} bytes read, {} bytes written",
+                cost.flops, cost.bytes_read, cost.bytes_written
+            )?;
+        }
+        if !self.unknown_ops.is_empty() {
+            writeln!(f, "{} ops without an estimate", self.unknown_ops.len())?;
+        }
+        Ok(())
+    }
+}
+
+impl Graph {
+    /// Estimate the FLOPs, memory traffic, parameter count and peak memory of one run. Dynamic dimensions need to be set
+    /// for ops using them to be counted.
+    /// ```rust
+    /// use luminal::prelude::*;
+    /// let mut cx = Graph::new();
+    /// let a = cx.tensor::<R2<2, 3>>();
+    /// let w = cx.tensor::<R2<3, 4>>().keep();
+    /// a.matmul(w).retrieve();
+    /// let stats = cx.stats();
+    /// assert_eq!(stats.total.flops, 2 * 2 * 3 * 4);
+    /// assert_eq!(stats.parameters, 3 * 4);
+    /// ```
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats::default();
+        let order = toposort(&self.graph, None).unwrap();
+        let is_source = |node| {
+            self.graph
+                .edges_directed(node, Direction::Incoming)
+                .next()
+                .is_none()
+        };
+        let mut consumers = FxHashMap::default();
+        for node in &order {
+            for (src, _, _) in self.get_sources(*node) {
+                *consumers.entry(src).or_insert(0) += 1;
+            }
+        }
+
+        let device = |node| self.devices.get(&node).copied().unwrap_or_default();
+        let mut device_live = FxHashMap::<usize, usize>::default();
+
+        // Kept sources like weights are resident before the run starts
+        let mut live = 0;
+        for node in &order {
+            if is_source(*node) && self.no_delete.contains(node) {
+                let bytes = self.output_bytes(*node);
+                stats.parameters += bytes / 4;
+                live += bytes;
+                *device_live.entry(device(*node)).or_default() += bytes;
+            }
+        }
+        stats.peak_memory = live;
+        stats.device_peak_memory = device_live.clone();
+
+        for node in order {
+            let op = self.node_weight(node).unwrap();
+            match self.op_cost(node) {
+                Some(cost) => {
+                    stats.total.flops += cost.flops;
+                    stats.total.bytes_read += cost.bytes_read;
+                    stats.total.bytes_written += cost.bytes_written;
+                    stats.ops.push((node, op_name(op.as_ref()), cost));
+                }
+                None if !is_source(node) => {
+                    stats.unknown_ops.push((node, op_name(op.as_ref())));
+                }
+                None => {}
+            }
+
+            // A background copy's buffer is held from when the copy starts, until what it gets handed on to is freed
+            let kept_source = is_source(node) && self.no_delete.contains(&node);
+            if !kept_source && !self.is_op::<AwaitCopy>(node) {
+                let bytes = self.output_bytes(node);
+                live += bytes;
+                let on_device = device_live.entry(device(node)).or_default();
+                *on_device += bytes;
+                let peak = stats.device_peak_memory.entry(device(node)).or_default();
+                *peak = (*peak).max(*on_device);
+            }
+            stats.peak_memory = stats.peak_memory.max(live);
+            for (src, _, _) in self.get_sources(node) {
+                let remaining = consumers.get_mut(&src).unwrap();
+                *remaining -= 1;
+                if *remaining == 0
+                    && !self.no_delete.contains(&src)
+                    && !self.is_op::<StartCopy>(src)
+                {
+                    let bytes = self.output_bytes(src);
+                    live -= bytes;
+                    *device_live.get_mut(&device(src)).unwrap() -= bytes;
+                }
+            }
+        }
+        stats
+    }
+
+    /// Estimated size of a node's output buffer, or zero if it uses dimensions that aren't set
+    pub(crate) fn output_bytes(&self, node: NodeIndex) -> usize {
+        if let Some(cost) = self.op_cost(node) {
+            return cost.bytes_written;
+        }
+        if let Some(n) = self
+            .resolved_source_shapes(node)
+            .and_then(|shapes| self.node_weight(node).unwrap().infer_shape(&shapes))
+            .and_then(|st| st.n_elements().to_usize())
+        {
+            return n * 4;
+        }
+        // Fall back on how consumers read it, which works for loads
+        let mut stack = vec![];
+        self.graph
+            .edges_directed(node, Direction::Outgoing)
+            .filter_map(|e| e.weight().as_data())
+            .filter_map(|(_, _, mut st)| {
+                st.try_resolve_global_dyn_dims_stack(&self.dyn_map, &mut stack)
+                    .ok()?;
+                st.n_physical_elements().to_usize()
+            })
+            .max()
+            .unwrap_or_default()
+            * 4
+    }
+}
+
+/// Name of an op without its parameters, so ops of one kind get grouped together
+pub(crate) fn op_name(op: &dyn Operator) -> String {
+    if op.as_any().is::<Constant>() {
+        return "Constant".to_string();
+    }
+    let name = format!("{op:?}");
+    name.split(['(', ' ', '{'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_graph_stats() {
+        let mut cx = Graph::new();
+        let a = cx.tensor::<(Dyn<'b'>, LConst<3>)>();
+        let w = cx.tensor::<R2<3, 4>>().keep();
+        a.matmul(w).exp2().retrieve();
+        // Nothing using the unset dimension can be counted yet
+        assert_eq!(cx.stats().total, OpCost::default());
+        assert_eq!(cx.stats().unknown_ops.len(), 3);
+
+        cx.set_dyn_dim('b', 2);
+        let stats = cx.stats();
+        assert!(stats.unknown_ops.is_empty());
+        // The matmul is a broadcasted multiply and a sum, each doing one flop per multiplied pair
+        assert_eq!(stats.total.flops, 2 * 2 * 3 * 4 + 2 * 4);
+        assert_eq!(
+            stats
+                .by_op()
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect::<Vec<_>>(),
+            vec!["Mul", "SumReduce", "Exp2"]
+        );
+        assert_eq!(stats.parameters, 12);
+        // The weights, the multiplied pairs and their sums are all live before the pairs get freed
+        assert_eq!(stats.peak_memory, (12 + 24 + 8) * 4);
+        assert_eq!(
+            stats.total.bytes_read,
+            // Broadcast inputs to the multiply are only read once
+            (6 + 12) * 4 + 24 * 4 + 8 * 4
+        );
+    }
+}
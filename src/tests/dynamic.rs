@@ -38,6 +38,40 @@ fn test_movement() {
     assert_close(&b.data(), &d_b.as_vec());
 }
 
+#[test]
+fn test_dims_change_between_runs() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<(Dyn<'a'>, Const<3>)>();
+    let b = a
+        .permute::<_, Axes2<1, 0>>()
+        .sum_reduce::<_, Axis<1>>()
+        .retrieve();
+    let c = a.pad::<(Dyn<'a'>, Const<4>)>(&[(0, 0), (0, 1)]).retrieve();
+
+    // Shapes resolved for one size can't be reused for another, but can once it comes back
+    for rows in [2, 3, 2] {
+        let data = (0..rows * 3).map(|i| i as f32).collect::<Vec<_>>();
+        a.set_dyn(data.clone(), &[rows, 3]);
+        cx.execute();
+        let sums = (0..3)
+            .map(|j| (0..rows).map(|i| data[i * 3 + j]).sum())
+            .collect::<Vec<f32>>();
+        assert_close(&b.data(), &sums);
+        let padded = data
+            .chunks(3)
+            .flat_map(|r| r.iter().copied().chain([0.]))
+            .collect::<Vec<_>>();
+        assert_close(&c.data(), &padded);
+        b.drop();
+        c.drop();
+    }
+
+    // A dimension that's gone isn't read from the last run
+    cx.dyn_map.clear();
+    cx.set_tensor(a.id, 0, Tensor::new(vec![0.; 6]));
+    assert_eq!(cx.try_execute(), Err(LuminalError::UnknownDimension('a')));
+}
+
 #[test]
 fn test_matmul() {
     let mut cx = Graph::new();
@@ -0,0 +1,99 @@
+//! Golden tests of what optimizer passes do to a graph's structure. Snapshots live in `snapshots/`; after changing a
+//! pass on purpose, review the new ones with `cargo insta review` or rerun with `INSTA_UPDATE=always`.
+
+use crate::{
+    debug::canonical_text,
+    generic_compiler::{
+        ArithmeticElimination, ContiguousElimination, DepthFirst, RemoveSingleReductions,
+        RemoveUnusedNodes, CSE,
+    },
+    prelude::*,
+};
+
+/// Canonical text of the graph `build` makes, before and after `compiler` runs on it
+fn before_after<C: Compiler, T: ToIdsMut>(
+    compiler: C,
+    build: impl FnOnce(&mut Graph) -> T,
+) -> String {
+    let mut cx = Graph::new();
+    let mut outputs = build(&mut cx);
+    let before = canonical_text(&cx);
+    cx.compile(compiler, &mut outputs);
+    format!("{before}\n---\n{}", canonical_text(&cx))
+}
+
+#[test]
+fn test_canonical_text_ignores_build_order() {
+    let mut a = Graph::new();
+    let x = a.named_tensor::<R1<3>>("x");
+    let y = a.named_tensor::<R1<3>>("y");
+    (x.exp2() + y.sin()).retrieve();
+    let mut b = Graph::new();
+    let y = b.named_tensor::<R1<3>>("y");
+    let s = y.sin();
+    let x = b.named_tensor::<R1<3>>("x");
+    (x.exp2() + s).retrieve();
+    assert_eq!(canonical_text(&a), canonical_text(&b));
+    insta::assert_snapshot!(canonical_text(&a));
+}
+
+#[test]
+fn test_cse() {
+    insta::assert_snapshot!(before_after(CSE, |cx| {
+        let a = cx.tensor::<R1<3>>();
+        (a.exp2() + a.exp2()).retrieve()
+    }));
+}
+
+#[test]
+fn test_arithmetic_elimination() {
+    insta::assert_snapshot!(before_after(ArithmeticElimination, |cx| {
+        let a = cx.tensor::<R1<3>>();
+        ((a * 1.) + 0.).sin().retrieve()
+    }));
+}
+
+#[test]
+fn test_remove_unused_nodes() {
+    insta::assert_snapshot!(before_after(RemoveUnusedNodes, |cx| {
+        let a = cx.tensor::<R1<3>>();
+        let _unused = a.exp2().sin();
+        a.sqrt().retrieve()
+    }));
+}
+
+#[test]
+fn test_remove_single_reductions() {
+    insta::assert_snapshot!(before_after(RemoveSingleReductions, |cx| {
+        let a = cx.tensor::<R2<4, 1>>();
+        a.sum_reduce::<_, Axis<1>>().exp2().retrieve()
+    }));
+}
+
+#[test]
+fn test_contiguous_elimination() {
+    insta::assert_snapshot!(before_after(ContiguousElimination, |cx| {
+        let a = cx.tensor::<R2<2, 3>>();
+        let b = cx.tensor::<R2<3, 2>>();
+        (a.permute::<_, Axes2<1, 0>>().contiguous() + b).retrieve()
+    }));
+}
+
+#[test]
+fn test_depth_first() {
+    insta::assert_snapshot!(before_after(DepthFirst, |cx| {
+        let a = cx.tensor::<R1<3>>();
+        let b = cx.tensor::<R1<3>>();
+        (a.exp2().sin() + b.exp2().sin()).retrieve()
+    }));
+}
+
+#[test]
+fn test_generic_compiler() {
+    insta::assert_snapshot!(before_after(GenericCompiler::default(), |cx| {
+        let x = cx.tensor::<R2<2, 3>>();
+        let w = cx.tensor::<R2<3, 4>>().keep();
+        let h = x.matmul(w) * 1.;
+        (h.relu() + h.relu()).softmax::<Axis<1>>().retrieve()
+    }));
+}
@@ -3,6 +3,8 @@
 #[cfg(test)]
 mod dynamic;
 #[cfg(test)]
+mod golden;
+#[cfg(test)]
 pub mod harness;
 pub mod test_graphs;
 #[cfg(test)]
@@ -39,6 +41,828 @@ fn main() {
     assert_close(&unoptimized_d, &d.data());
 }
 
+#[test]
+fn test_shared_data() {
+    let weights: std::sync::Arc<[f32]> = vec![1.0, 2.0, 3.0].into();
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set(weights.clone()).keep();
+    let b = cx.tensor::<R1<3>>().set(vec![1.0, 2.0, 3.0]);
+    let mut c = (a * b).exp2().retrieve();
+    cx.execute();
+    // The graph should be reading straight from the shared buffer
+    assert_eq!(
+        cx.get_tensor_ref(a.id, 0)
+            .unwrap()
+            .as_f32_slice()
+            .unwrap()
+            .as_ptr(),
+        weights.as_ptr()
+    );
+    let unoptimized_c = c.data();
+
+    cx.compile(GenericCompiler::default(), &mut c);
+    cx.execute();
+    assert_close(&unoptimized_c, &[2.0, 16.0, 512.0]);
+    assert_close(&unoptimized_c, &c.data());
+}
+
+#[test]
+fn test_pinned_encoder_output() {
+    let mut cx = Graph::new();
+    let enc_in = cx.tensor::<R1<3>>().set(vec![1., 2., 3.]);
+    let enc_out = (enc_in.exp2() * 2.).pin();
+    let dec_in = cx.tensor::<R1<3>>();
+    let dec_out = (dec_in + enc_out).retrieve();
+
+    dec_in.set(vec![0., 0., 0.]);
+    cx.execute();
+    assert_exact(&dec_out.data(), &[4., 8., 16.]);
+
+    // The encoder shouldn't run again, so changing its input has no effect while the output is pinned
+    enc_in.set(vec![0., 0., 0.]);
+    for i in 1..3 {
+        dec_out.drop();
+        dec_in.set(vec![i as f32; 3]);
+        cx.execute();
+        let i = i as f32;
+        assert_exact(&dec_out.data(), &[4. + i, 8. + i, 16. + i]);
+        assert!(cx.get_tensor_ref(enc_out.id, 0).is_some());
+    }
+
+    // Unpinning recomputes the encoder
+    enc_out.unpin();
+    dec_out.drop();
+    cx.execute();
+    assert_exact(&dec_out.data(), &[4., 4., 4.]);
+    assert!(cx.get_tensor_ref(enc_out.id, 0).is_none());
+}
+
+#[test]
+fn test_get() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+    let b = a * 2.;
+    let c = b.exp2();
+    (b, c).retrieve();
+    let permuted = b.permute::<R2<3, 2>, Axes2<1, 0>>();
+    let sliced = b.slice((.., 2..));
+    cx.execute();
+
+    assert_exact(&cx.get(&b).unwrap(), &[2., 4., 6., 8., 10., 12.]);
+    assert_exact(&cx.get(&permuted).unwrap(), &[2., 8., 4., 10., 6., 12.]);
+    assert_exact(&cx.get(&sliced).unwrap(), &[6., 12.]);
+    assert_eq!(cx.get(&c).unwrap().len(), 6);
+    // Intermediates that weren't retrieved get cleaned up
+    assert!(cx.get(&a).is_none());
+}
+
+#[test]
+fn test_tensor_storage() {
+    let mut tensor = Tensor::new(vec![1., 2., 3.]);
+    assert_eq!(tensor.len(), 3);
+    assert_eq!(tensor.dtype(), DType::F32);
+    assert_eq!(tensor.device(), "cpu");
+    assert_eq!(
+        tensor.bytes().unwrap(),
+        &[1f32.to_ne_bytes(), 2f32.to_ne_bytes(), 3f32.to_ne_bytes()].concat()
+    );
+    tensor.get_mut::<Vec<f32>>().unwrap()[0] = 4.;
+    assert_exact(tensor.f32s().unwrap(), &[4., 2., 3.]);
+
+    // The wrong type is reported with what the data actually is
+    let shared = Tensor::new(SharedData::new(vec![1., 2.]));
+    assert_exact(shared.f32s().unwrap(), &[1., 2.]);
+    assert_eq!(
+        shared.get::<Vec<f32>>().unwrap_err(),
+        LuminalError::WrongData {
+            expected: "alloc::vec::Vec<f32>",
+            found: "2 F32 elements on cpu".to_string()
+        }
+    );
+
+    // Data that only gives its bytes is sized by them
+    #[derive(Debug, Clone)]
+    struct Bytes(Vec<u8>);
+    impl Data for Bytes {
+        fn as_any(&self) -> &dyn std::any::Any {
+            self
+        }
+        fn as_any_mut(&mut self) -> &mut dyn std::any::Any {
+            self
+        }
+        fn bytes(&self) -> Option<&[u8]> {
+            Some(&self.0)
+        }
+    }
+    assert_eq!(Tensor::new(Bytes(vec![0; 8])).len(), 2);
+}
+
+#[test]
+fn test_try_execute() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R1<3>>("A").set([1., 2., 3.]);
+    let b = cx.named_tensor::<R1<3>>("B");
+    let c = (a * b).retrieve();
+    match cx.try_execute() {
+        Err(e @ LuminalError::UnsetTensor { node, .. }) => {
+            assert_eq!(node, b.id);
+            assert!(e.to_string().starts_with("B Load"));
+        }
+        r => panic!("Expected an unset tensor, got {r:?}"),
+    }
+
+    b.set([1., 1., 1.]);
+    cx.try_execute().unwrap();
+    assert_exact(&c.data(), &[1., 2., 3.]);
+
+    assert!(matches!(
+        cx.checked_op::<Function>(c.id),
+        Err(LuminalError::WrongOpType { node, .. }) if node == c.id
+    ));
+
+    // Ops report data they can't read as an error, rather than panicking
+    c.drop();
+    cx.set_tensor(
+        b.id,
+        0,
+        Tensor::new(CsrMatrix::from_dense(1, 3, &[1., 0., 1.])),
+    );
+    match cx.try_execute() {
+        Err(LuminalError::OpFailed {
+            node,
+            input_shapes,
+            error,
+            ..
+        }) => {
+            assert_eq!(node, c.id);
+            assert_eq!(input_shapes, vec![vec![3], vec![3]]);
+            assert!(matches!(*error, LuminalError::WrongData { .. }));
+        }
+        r => panic!("Expected op failure, got {r:?}"),
+    }
+
+    // Unset dynamic dimensions are reported by name
+    let mut cx = Graph::new();
+    let d = cx.tensor::<(Dyn<'a'>,)>().set_dyn(vec![1., 2.], &[2]);
+    let _ = (d * 2.).retrieve();
+    cx.dyn_map.clear();
+    assert_eq!(cx.try_execute(), Err(LuminalError::UnknownDimension('a')));
+}
+
+#[test]
+fn test_bind_inputs() {
+    let mut cx = Graph::new();
+    let a = cx.input::<(Dyn<'s'>, Const<2>)>("a");
+    let b = (a * 2.).sum_reduce::<_, Axis<1>>().retrieve();
+    assert_eq!(
+        cx.try_execute(),
+        Err(LuminalError::UnboundInput("a".to_string()))
+    );
+
+    for n in [1, 3] {
+        let data = (0..n * 2).map(|i| i as f32).collect::<Vec<_>>();
+        cx.bind("a", data, &[n, 2]).unwrap();
+        cx.execute();
+        assert_exact(
+            &b.data(),
+            &(0..n).map(|i| 8. * i as f32 + 2.).collect::<Vec<_>>(),
+        );
+        b.drop();
+    }
+
+    // Inputs are used up by a run
+    assert!(cx.try_execute().is_err());
+    assert_eq!(
+        cx.bind("b", vec![1.], &[1]),
+        Err(LuminalError::UnknownInput("b".to_string()))
+    );
+    assert!(matches!(
+        cx.bind("a", vec![1., 2., 3.], &[1, 3]),
+        Err(LuminalError::InputShapeMismatch { .. })
+    ));
+}
+
+#[test]
+fn test_signatures() {
+    let mut cx = Graph::new();
+    let ids = cx.input::<(Dyn<'b'>, Dyn<'s'>)>("input_ids");
+    let mask = cx.input::<(Dyn<'s'>,)>("mask").with_dtype(DType::F16);
+    let logits = (ids * mask.expand::<(Dyn<'b'>, Dyn<'s'>), _>())
+        .permute::<(Dyn<'s'>, Dyn<'b'>), _>()
+        .output("logits");
+    let total = logits.sum_reduce::<_, Axis<0>>().retrieve();
+    cx.compile(GenericCompiler::default(), ());
+
+    let inputs = cx.inputs();
+    assert_eq!(
+        inputs.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(),
+        ["input_ids", "mask"]
+    );
+    assert_eq!(inputs[0].shape, vec![BigExpression::from('b'), 's'.into()]);
+    assert_eq!((inputs[0].dtype, inputs[1].dtype), (DType::F32, DType::F16));
+    // Outputs keep their names through compilation, and are seen through their views
+    let outputs = cx.outputs();
+    assert_eq!(outputs.len(), 2);
+    assert_eq!(outputs[0].name, "logits");
+    assert_eq!(outputs[0].shape, vec![BigExpression::from('s'), 'b'.into()]);
+    assert_eq!(outputs[1].node, total.id);
+    assert_eq!(outputs[1].shape, vec![BigExpression::from('b')]);
+}
+
+#[test]
+fn test_retention() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = a * 2.;
+    let c = (b * b).retrieve();
+
+    // Intermediates are freed by default, so only the retrieved output is left
+    cx.execute();
+    assert_exact(&c.data(), &[4., 16., 36.]);
+    assert!(cx.get_tensor_ref(b.id, 0).is_none());
+    assert_eq!(cx.tensors.len(), 1);
+    c.drop();
+
+    // Held until the next run, which still recomputes it
+    b.retain(Retention::UntilNextRun);
+    for (data, scale) in [([1., 2., 3.], 1.), ([2., 4., 6.], 2.)] {
+        a.set(data);
+        cx.execute();
+        assert_exact(&b.data(), &[2. * scale, 4. * scale, 6. * scale]);
+        assert_exact(
+            &c.data(),
+            &[4. * scale * scale, 16. * scale * scale, 36. * scale * scale],
+        );
+        c.drop();
+    }
+
+    // Or hold everything
+    b.retain(Retention::FreeAfterLastUse);
+    cx.set_retention(Retention::UntilNextRun);
+    cx.execute();
+    assert_exact(&c.data(), &[16., 64., 144.]);
+    assert_exact(&b.data(), &[4., 8., 12.]);
+}
+
+#[test]
+fn test_infer_shape() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+    let b = cx.tensor::<R1<3>>().set(random_vec(3));
+    let c = (a.permute::<R2<3, 2>, _>().sum_reduce::<R1<3>, _>() * b).exp();
+    let d = a.max_reduce::<R1<2>, _>().cumsum_last_dim() + 1.;
+    assert_eq!(cx.output_shapes(a.id), None);
+    assert_eq!(cx.output_shapes(c.id).unwrap()[0].shape_usize(), vec![3]);
+    assert_eq!(cx.output_shapes(d.id).unwrap()[0].shape_usize(), vec![2]);
+
+    // Inferred shapes match what every op actually produces
+    cx.execute_no_delete();
+    for node in cx.node_indices() {
+        if let Some(shapes) = cx.output_shapes(node) {
+            assert_eq!(
+                shapes[0].n_elements().to_usize().unwrap(),
+                cx.get_tensor_ref(node, 0)
+                    .unwrap()
+                    .as_f32_slice()
+                    .unwrap()
+                    .len()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_shape_validation() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R2<2, 3>>("A");
+    let b = cx.named_tensor::<R2<3, 2>>("B");
+    let line = line!() + 1;
+    let op = cx.add_op(Add);
+    let err = op
+        .input(a.id, 0, a.shape)
+        .input(b.id, 0, b.shape)
+        .try_finish()
+        .unwrap_err();
+    assert!(err.to_string().starts_with(&format!(
+        "Add has incompatible input shapes: A Load [2, 3], B Load [3, 2], built at {}:{line}:",
+        file!()
+    )));
+    // The bad op shouldn't be left in the graph
+    assert_eq!(cx.node_count(), 2);
+
+    assert!(cx
+        .add_op(SumReduce(2))
+        .input(a.id, 0, a.shape)
+        .try_finish()
+        .is_err());
+
+    // Inferred output shapes are kept, and views have to cover all of them
+    let e = a.exp2();
+    assert_eq!(cx.shapes[&e.id].shape_usize(), vec![2, 3]);
+    assert!(cx
+        .add_op(Sin)
+        .input(e.id, 0, ShapeTracker::new(&[4.into(), 2.into()]))
+        .try_finish()
+        .is_err());
+    let mut transposed = e.shape;
+    transposed.permute(&[1, 0]);
+    assert!(cx
+        .add_op(Sin)
+        .input(e.id, 0, transposed)
+        .try_finish()
+        .is_ok());
+
+    // Unknown dims can't be checked until runtime
+    let c = cx.named_tensor::<(Dyn<'a'>, Const<3>)>("C");
+    assert!(cx
+        .add_op(Mul)
+        .input(a.id, 0, a.shape)
+        .input(c.id, 0, c.shape)
+        .try_finish()
+        .is_ok());
+}
+
+#[test]
+fn test_contiguous_elimination() {
+    let build = |cx: &mut Graph| {
+        let a = cx.tensor::<R2<2, 3>>().set([[1., 2., 3.], [4., 5., 6.]]);
+        let b = cx
+            .tensor::<R3<4, 3, 2>>()
+            .set_dyn((0..24).map(|i| i as f32).collect::<Vec<_>>(), &[4, 3, 2]);
+        // Permute and expand, then view the contiguous result through another permute and slice
+        let c = a
+            .permute::<R2<3, 2>, Axes2<1, 0>>()
+            .expand::<R3<4, 3, 2>, Axis<0>>()
+            .contiguous()
+            .permute::<R3<3, 4, 2>, Axes3<1, 0, 2>>()
+            .slice((1.., .., ..1))
+            .realize::<R3<2, 4, 1>>();
+        (c + b
+            .permute::<R3<3, 4, 2>, Axes3<1, 0, 2>>()
+            .slice((1.., .., ..1))
+            .realize())
+        .retrieve();
+        // Reshaping the contiguous data can't be folded away
+        a.permute::<R2<3, 2>, Axes2<1, 0>>()
+            .contiguous()
+            .reshape::<R1<6>>()
+            .retrieve();
+    };
+    crate::verify::compare(build, &[&ContiguousElimination], 0., 0.).unwrap();
+
+    let mut cx = Graph::new();
+    build(&mut cx);
+    let n_contiguous = |cx: &Graph| {
+        cx.node_indices()
+            .filter(|n| cx.is_op::<Contiguous>(*n))
+            .count()
+    };
+    assert_eq!(n_contiguous(&cx), 2);
+    cx.compile(ContiguousElimination, ());
+    assert_eq!(n_contiguous(&cx), 1);
+}
+
+#[test]
+fn test_cast() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1.0001, 1.01, 65519.]);
+    let half = a.cast::<f16>().retrieve();
+    let brain = a.cast::<bf16>().retrieve();
+    let full = a.cast::<f32>();
+    cx.execute();
+
+    assert_eq!(full.id, a.id);
+    assert_exact(&half.data(), &[1., 1.0097656, 65504.]);
+    assert_exact(&brain.data(), &[1., 1.0078125, 65536.]);
+    // Stored in half precision buffers, and widened again by ops reading them
+    let stored = cx.get_tensor_ref(half.id, 0).unwrap();
+    assert_eq!(
+        (stored.dtype(), stored.bytes().unwrap().len()),
+        (DType::F16, 6)
+    );
+    assert!(cx.get_tensor_ref(brain.id, 0).unwrap().is::<Vec<bf16>>());
+    let doubled = (half * 2.).retrieve();
+    cx.execute();
+    assert_exact(&doubled.data(), &[2., 2.0195312, 131008.]);
+}
+
+#[test]
+fn test_fold_batch_norm() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R3<2, 3, 4>>().set(random_vec(2 * 3 * 4));
+    let b = cx.tensor::<R2<4, 5>>().set(random_vec(4 * 5)).keep();
+    let cols = cx.tensor::<R1<5>>().set(random_vec(5)).keep();
+    let rows = cx.tensor::<R1<3>>().set(random_vec(3)).keep();
+    let c = cx.tensor::<R2<3, 4>>().set(random_vec(3 * 4)).keep();
+    let bias = cx.tensor::<R1<5>>().set(random_vec(5)).keep();
+    let shift = cx.tensor::<R1<5>>().set(random_vec(5)).keep();
+    // Scaling columns folds into the right hand side, and rows into the left
+    let mut by_col = (a.matmul(b) * cols.expand::<_, Axes2<0, 1>>()).retrieve();
+    let mut by_row = (c.matmul(b) * rows.expand::<_, Axis<1>>()).retrieve();
+    // A bias before the scale and a shift after it fold into one bias
+    let mut affine = ((a.matmul(b) + bias.expand::<_, Axes2<0, 1>>())
+        * (cols * 2.).expand::<_, Axes2<0, 1>>()
+        + shift.expand::<_, Axes2<0, 1>>())
+    .retrieve();
+    // Scales varying along more than one dimension can't be folded
+    let mut by_elem = (c.matmul(b) * c.matmul(b)).retrieve();
+    cx.execute();
+    let unfolded = (by_col.data(), by_row.data(), affine.data(), by_elem.data());
+
+    cx.compile(
+        FoldBatchNorm,
+        (&mut by_col, &mut by_row, &mut affine, &mut by_elem),
+    );
+    // The matmuls read folded weights loaded once, rather than multiplying the weights every run
+    let reads_weights = |sum: NodeIndex| {
+        cx.get_sources(cx.get_sources(sum)[0].0)
+            .iter()
+            .all(|(src, _, _)| cx.is_op::<Function>(*src))
+    };
+    assert!(cx.is_op::<SumReduce>(by_col.id) && reads_weights(by_col.id));
+    assert!(cx.is_op::<SumReduce>(by_row.id) && reads_weights(by_row.id));
+    let affine_srcs = cx.get_sources(affine.id);
+    assert!(cx.is_op::<Add>(affine.id));
+    assert!(cx.is_op::<SumReduce>(affine_srcs[0].0) && reads_weights(affine_srcs[0].0));
+    assert!(cx.is_op::<Function>(affine_srcs[1].0));
+    assert!(cx.is_op::<Mul>(by_elem.id));
+    cx.execute();
+    assert_close(&by_col.data(), &unfolded.0);
+    assert_close(&by_row.data(), &unfolded.1);
+    assert_close(&affine.data(), &unfolded.2);
+    assert_exact(&by_elem.data(), &unfolded.3);
+}
+
+#[test]
+fn test_autocast() {
+    let data = (random_vec(2 * 3 * 4), random_vec(4 * 5), random_vec(3 * 5));
+    let build = |cx: &mut Graph, explicit: bool| {
+        let a = cx.tensor::<R3<2, 3, 4>>().set(data.0.clone());
+        let b = cx.tensor::<R2<4, 5>>().set(data.1.clone());
+        let c = cx.tensor::<R2<3, 5>>().set(data.2.clone());
+        let (a_in, b_in) = if explicit {
+            (a.cast::<f16>(), b.cast::<f16>())
+        } else {
+            (a, b)
+        };
+        // Elementwise multiplies and full precision matmuls aren't cast
+        let out = (a_in.matmul(b_in) * c.expand::<_, Axis<0>>()).retrieve();
+        let kept = a.matmul(b).retrieve();
+        (out, kept)
+    };
+
+    let mut reference = Graph::new();
+    let (ref_out, ref_kept) = build(&mut reference, true);
+    reference.execute();
+
+    let mut cx = Graph::new();
+    let (out, kept) = build(&mut cx, false);
+    cx.compile(AutoCast::new(DType::F16).skip(kept), ());
+    let n_casts = cx.node_indices().filter(|n| cx.is_op::<Cast>(*n)).count();
+    assert_eq!(n_casts, 2);
+    // Casting twice shouldn't add more
+    cx.compile(AutoCast::new(DType::F16).skip(kept), ());
+    assert_eq!(
+        cx.node_indices().filter(|n| cx.is_op::<Cast>(*n)).count(),
+        n_casts
+    );
+    cx.execute();
+
+    assert_exact(&out.data(), &ref_out.data());
+    assert_exact(&kept.data(), &ref_kept.data());
+    assert_ne!(out.data(), {
+        let mut full = Graph::new();
+        let (o, _) = build(&mut full, false);
+        full.execute();
+        o.data()
+    });
+}
+
+#[test]
+fn test_recompute_intermediates() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set(random_vec(4));
+    let b = cx.tensor::<R1<4>>().set(random_vec(4));
+    // A unary op only reads one input, so recomputing it for both consumers is cheaper than storing it
+    let exp = a.exp2();
+    let mut c = exp.sin().retrieve();
+    let mut d = exp.sqrt().retrieve();
+    // A binary op shared three ways reads too much to be worth running three times
+    let sum = a + b;
+    let mut e = sum.sin().retrieve();
+    let mut f = sum.exp2().retrieve();
+    let mut g = sum.recip().retrieve();
+    assert_eq!(
+        cx.op_cost(sum.id),
+        Some(OpCost {
+            flops: 4,
+            bytes_read: 32,
+            bytes_written: 16
+        })
+    );
+    cx.execute();
+    let before = [c.data(), d.data(), e.data(), f.data(), g.data()];
+
+    cx.compile(
+        RecomputeIntermediates::default(),
+        (&mut c, &mut d, &mut e, &mut f, &mut g),
+    );
+    assert_eq!(
+        cx.node_indices().filter(|n| cx.is_op::<Exp2>(*n)).count(),
+        3
+    );
+    assert_eq!(cx.node_indices().filter(|n| cx.is_op::<Add>(*n)).count(), 1);
+    cx.execute();
+    for (out, expected) in [c, d, e, f, g].iter().zip(before) {
+        assert_exact(&out.data(), &expected);
+    }
+}
+
+#[test]
+fn test_nan_guard() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., -2., 4.]);
+    let line = line!() + 1;
+    let log = a.log2();
+    let _ = (log * 2.).retrieve();
+    // Without the guard NaNs flow through silently
+    cx.try_execute().unwrap();
+
+    cx.set_nan_guard(true);
+    let err = cx.try_execute().unwrap_err();
+    let LuminalError::NonFinite {
+        node,
+        input_shapes,
+        location,
+        index,
+        value,
+        ..
+    } = &err
+    else {
+        panic!("Expected a NaN error, got {err}");
+    };
+    assert_eq!(*node, log.id);
+    assert_eq!(input_shapes, &vec![vec![3]]);
+    assert_eq!((*index, *value), (1, "NaN"));
+    let location = location.unwrap();
+    assert_eq!((location.file(), location.line()), (file!(), line));
+    assert!(err.to_string().contains(&format!("{}:{line}", file!())));
+
+    // Every other way of running the graph checks its ops too
+    let message = err.to_string();
+    let execute_async = |cx: &mut Graph| {
+        struct NoopWaker;
+        impl std::task::Wake for NoopWaker {
+            fn wake(self: std::sync::Arc<Self>) {}
+        }
+        let waker = std::sync::Arc::new(NoopWaker).into();
+        let mut context = std::task::Context::from_waker(&waker);
+        let mut future = std::pin::pin!(cx.execute_async());
+        while std::future::Future::poll(future.as_mut(), &mut context).is_pending() {}
+    };
+    let runs: [&dyn Fn(&mut Graph); 4] = [
+        &Graph::execute,
+        &Graph::execute_no_delete,
+        &Graph::execute_debug,
+        &execute_async,
+    ];
+    for run in runs {
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run(&mut cx)))
+            .expect_err("The NaN wasn't caught");
+        assert_eq!(panic.downcast_ref::<String>(), Some(&message));
+        cx.tensors.clear();
+    }
+}
+
+#[test]
+fn test_build_locations() {
+    let mut cx = Graph::new();
+    let a = cx.named_tensor::<R2<2, 3>>("A");
+    let a_line = line!() - 1;
+    let b = a.into_dyn().softmax(1);
+    let b_line = line!() - 1;
+    b.retrieve();
+    for (node, line) in [(a.id, a_line), (b.id, b_line)] {
+        let location = cx.location(node).unwrap();
+        assert_eq!((location.file(), location.line()), (file!(), line));
+    }
+    // Every node built by the softmax points at it, not at the hl_ops inside it
+    assert!(cx
+        .graph
+        .node_indices()
+        .filter(|n| *n != a.id)
+        .all(|n| cx.location(n).unwrap().line() == b_line));
+    let dot = cx.to_dot();
+    assert!(dot.contains(&format!("{}:{a_line}", file!())));
+    assert!(dot.contains(&format!("{}:{b_line}", file!())));
+
+    // Errors say where the failing node was built
+    let err = cx.try_execute().unwrap_err();
+    assert!(matches!(err, LuminalError::UnsetTensor { node, .. } if node == a.id));
+    assert!(err.to_string().contains(&format!("{}:{a_line}", file!())));
+    let line = line!() + 1;
+    let op = cx.add_op(Add);
+    let err = op
+        .input(a.id, 0, a.shape)
+        .input(b.id, 0, ShapeTracker::new(&[2.into()]))
+        .try_finish()
+        .unwrap_err();
+    assert!(err.to_string().contains(&format!("{}:{line}", file!())));
+}
+
+/// Subtracts, but prints itself the same as an add
+struct FakeAdd;
+
+impl Debug for FakeAdd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Add")
+    }
+}
+
+impl Operator for FakeAdd {
+    fn process(&mut self, inp: Vec<(InputTensor, ShapeTracker)>) -> Vec<Tensor> {
+        let a = inp[0].0.borrowed().as_f32_slice().unwrap();
+        let b = inp[1].0.borrowed().as_f32_slice().unwrap();
+        vec![Tensor::new(
+            a.iter().zip(b).map(|(a, b)| a - b).collect::<Vec<_>>(),
+        )]
+    }
+}
+
+#[test]
+fn test_is_op() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set([3., 2., 1.]);
+    let add = (a + b).retrieve();
+    let fake = GraphTensor::<R1<3>>::from_id(
+        cx.add_op(FakeAdd)
+            .input(a.id, 0, a.shape)
+            .input(b.id, 0, b.shape)
+            .finish(),
+        a.shape,
+        &mut cx,
+    )
+    .retrieve();
+    assert!(cx.is_op::<Add>(add.id));
+    assert!(!cx.is_op::<Add>(fake.id));
+    assert!(cx.is_op::<FakeAdd>(fake.id));
+
+    // Same sources and same debug name, but different op types shouldn't get merged
+    cx.compile(CSE, ());
+    cx.execute();
+    assert_exact(&add.data(), &[4., 4., 4.]);
+    assert_exact(&fake.data(), &[-2., 0., 2.]);
+}
+
+#[test]
+fn test_get_sources_ordered() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set([3., 2., 1.]);
+    let c = (b * 2.).less_than(a);
+    let srcs = cx.get_sources_ordered(c.id).unwrap();
+    assert_eq!(srcs.len(), 2);
+    assert!(cx.is_op::<Mul>(srcs[0].0));
+    assert_eq!(srcs[1].0, a.id);
+
+    // Ordered patterns only match inputs in the right positions
+    let unordered = binary::<LessThan>(node(), op::<Mul>());
+    assert!(unordered.search(&mut cx).next_match());
+    let ordered = ordered_binary::<LessThan>(node(), op::<Mul>());
+    assert!(!ordered.search(&mut cx).next_match());
+    let ordered = ordered_binary::<LessThan>(op::<Mul>(), node());
+    assert!(ordered.search(&mut cx).next_match());
+
+    // Two edges into the same input is an error
+    cx.add_edge(
+        b.id,
+        c.id,
+        Dependency::Data {
+            input_order: 1,
+            output_order: 0,
+            shape: b.shape,
+        },
+    );
+    assert!(matches!(
+        cx.get_sources_ordered(c.id),
+        Err(LuminalError::BadInputOrder { .. })
+    ));
+}
+
+#[test]
+fn test_match_shared_node() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>();
+    let b = cx.tensor::<R1<3>>();
+    let same = a * a.exp2();
+    let different = a * b.exp2();
+
+    // One pattern node reached along two paths has to land on the same node each time
+    let x = node();
+    let pattern = binary::<Mul>(x.clone(), unary::<Exp2>(x.clone()));
+    let mut search = pattern.clone().search(&mut cx);
+    assert!(search.next_match());
+    assert_eq!(search.get(&x), a.id);
+    assert_eq!(search.get(&pattern), same.id);
+    assert!(!search.next_match());
+
+    // Two pattern nodes can't share a node
+    let pattern = binary::<Mul>(node(), unary::<Exp2>(node()));
+    let mut search = pattern.clone().search(&mut cx);
+    assert!(search.next_match());
+    assert_eq!(search.get(&pattern), different.id);
+    assert!(!search.next_match());
+}
+
+#[test]
+fn test_execute_batch() {
+    let mut cx = Graph::new();
+    let input = cx.tensor::<(Dyn<'b'>, Const<3>)>();
+    let weight = cx
+        .tensor::<R2<3, 2>>()
+        .set([[1., 2.], [0., -1.], [3., 1.]])
+        .keep();
+    let out = input.matmul(weight).retrieve();
+    let summed = input.sum_reduce::<(Dyn<'b'>,), _>().retrieve();
+
+    let examples = [vec![1., 2., 3.], vec![-1., 0., 2.], vec![0.5, 0.5, 0.5]];
+    // Run a batch of 2, then 3, reusing the same graph
+    for n in [2, 3] {
+        let batch = cx.execute_batch('b', &[(input.id, examples[..n].to_vec())], (out, summed));
+        assert_eq!(batch.len(), n);
+        for (example, outputs) in examples.iter().zip(&batch) {
+            let expected = (0..2)
+                .map(|j| {
+                    (0..3)
+                        .map(|k| example[k] * [[1., 2.], [0., -1.], [3., 1.]][k][j])
+                        .sum::<f32>()
+                })
+                .collect::<Vec<_>>();
+            assert_close(&outputs[0], &expected);
+            assert_close(&outputs[1], &[example.iter().sum::<f32>()]);
+        }
+    }
+}
+
+#[test]
+fn test_execute_batch_declared_input() {
+    let mut cx = Graph::new();
+    let input = cx.input::<(Dyn<'b'>, Const<2>)>("input");
+    let out = (input * 2.).sum_reduce::<(Dyn<'b'>,), _>().retrieve();
+
+    let batch = cx.execute_batch('b', &[(input.id, vec![vec![1., 2.], vec![3., 4.]])], out);
+    assert_eq!(batch, vec![vec![vec![6.]], vec![vec![14.]]]);
+    // The batch doesn't stick around once it's been used
+    assert!(!cx.tensors.contains_key(&(input.id, 0)));
+    let batch = cx.execute_batch('b', &[(input.id, vec![vec![0., 1.]])], out);
+    assert_eq!(batch, vec![vec![vec![2.]]]);
+}
+
+#[test]
+fn test_execute_async() {
+    use std::{
+        future::Future,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+    };
+    struct NoopWaker;
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+    let b = cx.tensor::<R1<3>>().set([4., 5., 6.]);
+    let c = ((a + b) * a).retrieve();
+
+    let waker = Arc::new(NoopWaker).into();
+    let mut context = Context::from_waker(&waker);
+    let mut n_polls = 0;
+    {
+        let mut future = std::pin::pin!(cx.execute_async());
+        while future.as_mut().poll(&mut context) == Poll::Pending {
+            n_polls += 1;
+        }
+    }
+    // Yielded between nodes instead of running everything at once
+    assert!(n_polls > 1);
+    assert_exact(&c.data(), &[5., 14., 27.]);
+
+    // Dropping a run partway through leaves nothing but the kept tensors behind
+    c.drop();
+    {
+        let mut future = std::pin::pin!(cx.execute_async());
+        for _ in 0..3 {
+            assert_eq!(future.as_mut().poll(&mut context), Poll::Pending);
+        }
+    }
+    assert!(cx.tensors.keys().all(|(n, _)| cx.no_delete.contains(n)));
+    assert!(cx.get_tensor_ref(c.id, 0).is_none());
+    cx.execute();
+    assert_exact(&c.data(), &[5., 14., 27.]);
+}
+
 #[test]
 fn test_matmul() {
     let mut cx = Graph::new();
@@ -103,6 +927,26 @@ pub fn assert_exact<T: PartialEq + Debug>(a_vec: &[T], b_vec: &[T]) {
     }
 }
 
+/// Ensure a single element tensor, like a loss, is nearly equal to a value
+#[track_caller]
+pub fn assert_item_close<S: SingleElementShape>(tensor: GraphTensor<S>, expected: f32) {
+    assert_item_close_precision(tensor, expected, 1e-3);
+}
+
+/// Ensure a single element tensor is nearly equal to a value, to within a threshold
+#[track_caller]
+pub fn assert_item_close_precision<S: SingleElementShape>(
+    tensor: GraphTensor<S>,
+    expected: f32,
+    threshold: f32,
+) {
+    let item = tensor.item();
+    assert!(
+        (item - expected).abs() <= threshold,
+        "{item} is not close to {expected}"
+    );
+}
+
 pub fn random_array<const N: usize>() -> [f32; N] {
     let mut rng = thread_rng();
     random_array_rng(&mut rng)
@@ -140,8 +984,9 @@ macro_rules! test_imports {
                 Axis as LAxis, Const as LConst, *,
             },
             tests::{
-                assert_close, assert_close_precision, assert_exact, random_array, random_array_rng,
-                random_vec, random_vec_rng, test_graphs,
+                assert_close, assert_close_precision, assert_exact, assert_item_close,
+                assert_item_close_precision, random_array, random_array_rng, random_vec,
+                random_vec_rng, test_graphs,
             },
         };
     };
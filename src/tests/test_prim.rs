@@ -239,6 +239,67 @@ fn test_max() {
     assert_close(&c.data(), &d_c.as_vec());
 }
 
+#[test]
+fn test_maximum_minimum() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 0., 3.]);
+    let b = cx.tensor::<R1<3>>().set([1., 2., -2.]);
+    let c = a.maximum(b).retrieve();
+    let d = a.minimum(b).retrieve();
+    let e = a.maximum(0.5).minimum(2.).retrieve();
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor([1., 0., 3.]);
+    let d_b = d_dev.tensor([1., 2., -2.]);
+    assert_close(&c.data(), &d_a.clone().maximum(d_b.clone()).as_vec());
+    assert_close(&d.data(), &d_a.minimum(d_b).as_vec());
+    assert_close(&e.data(), &[1., 0.5, 2.]);
+}
+
+#[test]
+fn test_pow() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<4>>().set([1., -2., 3., 0.5]);
+    let b = cx.tensor::<R1<4>>().set([2., 1., 0.5, 3.]);
+    let cubed = a.pow(3.).retrieve();
+    let inv_squared = a.pow(-2.).retrieve();
+    let zeroth = a.pow(0.).retrieve();
+    let root = a.abs().pow(0.5).retrieve();
+    let tensor_pow = a.abs().pow(b).retrieve();
+    cx.execute();
+
+    let d_dev = Cpu::default();
+    let d_a = d_dev.tensor([1., -2., 3., 0.5]);
+    let d_b = d_dev.tensor([2., 1., 0.5, 3.]);
+    assert_close(&cubed.data(), &d_a.clone().powi(3).as_vec());
+    assert_close(&inv_squared.data(), &d_a.clone().powi(-2).as_vec());
+    assert_close(&zeroth.data(), &[1., 1., 1., 1.]);
+    assert_close(&root.data(), &d_a.clone().abs().sqrt().as_vec());
+    assert_close(&tensor_pow.data(), &(d_a.abs().ln() * d_b).exp().as_vec());
+}
+
+#[test]
+fn test_scalar_ops() {
+    let mut cx = Graph::new();
+    let a = cx.tensor::<R1<3>>().set([1., 2., 4.]);
+    let b = (1. - a).retrieve();
+    let c = (2. / a).retrieve();
+    let d = (5. % a).retrieve();
+    let mut e = a;
+    e += 1.;
+    e *= 3.;
+    e -= 2.;
+    e /= 2.;
+    e.retrieve();
+    cx.execute();
+
+    assert_close(&b.data(), &[0., -1., -3.]);
+    assert_close(&c.data(), &[2., 1., 0.5]);
+    assert_close(&d.data(), &[0., 1., 1.]);
+    assert_close(&e.data(), &[2., 3.5, 6.5]);
+}
+
 #[test]
 fn test_mod() {
     let mut cx = Graph::new();
@@ -259,6 +320,20 @@ fn test_mod() {
     );
 }
 
+#[test]
+fn test_select() {
+    let mut cx = Graph::new();
+    let a = cx
+        .tensor::<R1<4>>()
+        .set([1., f32::NEG_INFINITY, f32::NAN, f32::INFINITY]);
+    let mask = cx.tensor::<R1<4>>().set([1., 0., 0., 1.]);
+    let b = a.select(mask).retrieve();
+    cx.execute();
+
+    // Masked out infinities and NaNs are zeros rather than NaNs
+    assert_eq!(b.data(), vec![1., 0., 0., f32::INFINITY]);
+}
+
 // Reduction op tests
 
 #[test]
@@ -0,0 +1,175 @@
+use std::{fmt::Debug, path::Path};
+
+use itertools::Itertools;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, EnteredSpan, Id},
+    Subscriber,
+};
+use tracing_chrome::{ChromeLayerBuilder, EventOrSpan, FlushGuard};
+use tracing_subscriber::{
+    layer::Context, prelude::*, registry::LookupSpan, util::TryInitError, Layer,
+};
+
+use crate::{debug::pass_name, prelude::*, stats::op_name};
+
+/// Write a timeline of every execution and compiler pass to a Chrome trace file, which can be opened in Perfetto or
+/// `chrome://tracing`. Installs a global tracing subscriber, so this fails if one is already set.
+///
+/// The trace is written as spans finish, and flushed when the returned guard is dropped.
+/// ```rust,no_run
+/// use luminal::prelude::*;
+/// let _guard = luminal::trace::chrome_trace("trace.json").unwrap();
+/// let mut cx = Graph::new();
+/// let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+/// let b = a.exp2().retrieve();
+/// cx.compile(GenericCompiler::default(), b);
+/// cx.execute();
+/// ```
+pub fn chrome_trace(path: impl AsRef<Path>) -> Result<FlushGuard, TryInitError> {
+    let (layer, guard) = chrome_trace_layer(path);
+    tracing_subscriber::registry()
+        .with(SpanNames)
+        .with(layer)
+        .try_init()?;
+    Ok(guard)
+}
+
+/// A subscriber writing a Chrome trace file, for setting as the default within a scope rather than globally
+pub fn chrome_trace_subscriber(
+    path: impl AsRef<Path>,
+) -> (impl Subscriber + Send + Sync, FlushGuard) {
+    let (layer, guard) = chrome_trace_layer(path);
+    (
+        tracing_subscriber::registry().with(SpanNames).with(layer),
+        guard,
+    )
+}
+
+fn chrome_trace_layer<S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync>(
+    path: impl AsRef<Path>,
+) -> (tracing_chrome::ChromeLayer<S>, FlushGuard) {
+    ChromeLayerBuilder::new()
+        .file(path)
+        .include_args(true)
+        .name_fn(Box::new(|event_or_span| match event_or_span {
+            EventOrSpan::Event(e) => e.metadata().name().to_string(),
+            EventOrSpan::Span(s) => s
+                .extensions()
+                .get::<SpanName>()
+                .map(|n| n.0.clone())
+                .unwrap_or_else(|| s.name().to_string()),
+        }))
+        .build()
+}
+
+/// Span around one op running. Op and pass spans all share a few static names, so the op or pass itself goes in a
+/// `name` field, which the Chrome exporter shows as the span's name.
+pub(crate) fn op_span(
+    node: NodeIndex,
+    op: &dyn Operator,
+    inputs: &[(InputTensor, ShapeTracker)],
+) -> EnteredSpan {
+    let span = tracing::info_span!(
+        "op",
+        name = tracing::field::Empty,
+        node = node.index(),
+        shapes = tracing::field::Empty
+    );
+    if !span.is_disabled() {
+        span.record("name", op_name(op));
+        span.record(
+            "shapes",
+            inputs
+                .iter()
+                .map(|(_, st)| st.shape_usize().iter().join("x"))
+                .join(", "),
+        );
+    }
+    span.entered()
+}
+
+/// Span around one compiler pass
+pub(crate) fn pass_span<C>() -> EnteredSpan {
+    named_pass_span(&pass_name::<C>())
+}
+
+/// Span around one compiler pass, named by a `PassManager`
+pub(crate) fn named_pass_span(name: &str) -> EnteredSpan {
+    let span = tracing::info_span!("pass", name = tracing::field::Empty);
+    if !span.is_disabled() {
+        span.record("name", name);
+    }
+    span.entered()
+}
+
+/// Span around a whole execution
+pub(crate) fn execute_span() -> EnteredSpan {
+    tracing::info_span!("execute").entered()
+}
+
+struct SpanName(String);
+
+/// Stores the `name` field of new spans, for the Chrome exporter to name them with
+struct SpanNames;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanNames {
+    fn on_record(&self, id: &Id, values: &tracing::span::Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = NameVisitor(None);
+        values.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().replace(SpanName(name));
+        }
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = NameVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(name), Some(span)) = (visitor.0, ctx.span(id)) {
+            span.extensions_mut().replace(SpanName(name));
+        }
+    }
+}
+
+struct NameVisitor(Option<String>);
+
+impl Visit for NameVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "name" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "name" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+
+    #[test]
+    fn test_chrome_trace() {
+        let path =
+            std::env::temp_dir().join(format!("luminal_trace_{}.json", uuid::Uuid::new_v4()));
+        let (subscriber, guard) = super::chrome_trace_subscriber(&path);
+        tracing::subscriber::with_default(subscriber, || {
+            let mut cx = Graph::new();
+            let a = cx.tensor::<R2<2, 3>>().set(random_vec(6));
+            let mut b = (a.exp2() * 2.).sum_reduce::<_, LAxis<1>>().retrieve();
+            cx.compile(GenericCompiler::default(), &mut b);
+            cx.execute();
+        });
+        drop(guard);
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        // Spans are named after the op or pass, and ops record their input shapes
+        for name in ["\"execute\"", "\"Exp2\"", "\"SumReduce\"", "\"CSE\"", "2x3"] {
+            assert!(trace.contains(name), "{name} isn't in the trace");
+        }
+    }
+}
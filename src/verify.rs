@@ -0,0 +1,238 @@
+use std::fmt::Display;
+
+use crate::prelude::*;
+
+/// A compiler stack that can be checked against the unoptimized graph. Implemented for every `Compiler`.
+pub trait VerifyCompiler {
+    fn name(&self) -> String;
+    /// Compile the graph, remapping the output ids
+    fn compile_outputs(&self, graph: &mut Graph, outputs: &mut Vec<NodeIndex>);
+}
+
+impl<C: Compiler> VerifyCompiler for C {
+    fn name(&self) -> String {
+        std::any::type_name::<C>().to_string()
+    }
+
+    fn compile_outputs(&self, graph: &mut Graph, outputs: &mut Vec<NodeIndex>) {
+        self.compile(graph, outputs);
+        graph.toposort();
+        graph.reset();
+    }
+}
+
+/// The first retrieved output where a compiled graph disagrees with the unoptimized one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// The compiler stack that produced the wrong output
+    pub compiler: String,
+    /// The output node, in the unoptimized graph
+    pub node: NodeIndex,
+    /// The output's op, in the unoptimized graph
+    pub op: String,
+    /// The first element that diverged
+    pub index: usize,
+    /// None if the compiled graph produced more elements than the unoptimized one
+    pub expected: Option<f32>,
+    /// None if the compiled graph didn't produce this element
+    pub found: Option<f32>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = |v: Option<f32>| v.map_or("nothing".to_string(), |v| v.to_string());
+        write!(
+            f,
+            "{} diverged at {} ({}), index {}: expected {}, found {}",
+            self.compiler,
+            self.op,
+            self.node.index(),
+            self.index,
+            value(self.expected),
+            value(self.found)
+        )
+    }
+}
+
+/// Check that compiling a graph doesn't change its results.
+///
+/// `build` constructs the graph, sets its inputs and retrieves the tensors to check. It's called once for the unoptimized
+/// reference and once for each compiler stack. Every retrieved tensor is compared element by element, and the first
+/// divergent output (in execution order) is reported. Elements match if they're equal (so matching infinities do), both
+/// NaN, or within `atol + rtol * |expected|` of each other, and outputs must have the same number of elements. Retrieve
+/// intermediate tensors too to narrow down where things go wrong.
+/// ```rust
+/// use luminal::{prelude::*, verify::compare};
+/// compare(
+///     |cx| {
+///         let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+///         (a.exp() * 2.).sum_reduce::<_, Axis<0>>().retrieve();
+///     },
+///     &[&GenericCompiler::default()],
+///     1e-5,
+///     1e-4,
+/// )
+/// .unwrap();
+/// ```
+pub fn compare(
+    build: impl Fn(&mut Graph),
+    compilers: &[&dyn VerifyCompiler],
+    atol: f32,
+    rtol: f32,
+) -> Result<(), Divergence> {
+    let mut reference = Graph::new();
+    build(&mut reference);
+    reference.toposort();
+    let outputs = reference
+        .linearized_graph
+        .as_ref()
+        .unwrap()
+        .iter()
+        .map(|(n, _)| *n)
+        .filter(|n| reference.to_retrieve.contains_key(n))
+        .collect::<Vec<_>>();
+    reference.execute();
+
+    for compiler in compilers {
+        let mut cx = Graph::new();
+        build(&mut cx);
+        let mut compiled_outputs = outputs.clone();
+        compiler.compile_outputs(&mut cx, &mut compiled_outputs);
+        cx.execute();
+        for (node, compiled) in outputs.iter().zip(compiled_outputs) {
+            let expected = reference
+                .get_view(*node, reference.to_retrieve[node].1)
+                .unwrap();
+            let found = cx
+                .to_retrieve
+                .get(&compiled)
+                .and_then(|(_, shape)| cx.get_view(compiled, *shape))
+                .unwrap_or_default();
+            let mismatch = (0..expected.len().max(found.len())).find_map(|i| {
+                match (expected.get(i).copied(), found.get(i).copied()) {
+                    (Some(e), Some(f))
+                        if e == f
+                            || (e.is_nan() && f.is_nan())
+                            || (e - f).abs() <= atol + rtol * e.abs() =>
+                    {
+                        None
+                    }
+                    (e, f) => Some((i, e, f)),
+                }
+            });
+            if let Some((index, expected, found)) = mismatch {
+                return Err(Divergence {
+                    compiler: compiler.name(),
+                    node: *node,
+                    op: format!("{:?}", reference.node_weight(*node).unwrap()),
+                    index,
+                    expected,
+                    found,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    crate::test_imports!();
+    use crate::verify::{compare, Divergence};
+
+    /// Turns every add into a multiply
+    struct BrokenCompiler;
+
+    impl Compiler for BrokenCompiler {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, _: T) {
+            for node in graph.node_indices().collect::<Vec<_>>() {
+                if graph.is_op::<Add>(node) {
+                    *graph.node_weight_mut(node).unwrap() = Box::new(Mul);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let build = |cx: &mut Graph| {
+            let a = cx.tensor::<R1<3>>().set([1., 2., 3.]);
+            let b = cx.tensor::<R1<3>>().set([4., 5., 6.]);
+            (a * b).retrieve();
+            (a + b).exp2().retrieve();
+        };
+        compare(build, &[&GenericCompiler::default(), &()], 1e-5, 0.).unwrap();
+
+        let Divergence {
+            op,
+            index,
+            expected,
+            found,
+            ..
+        } = compare(build, &[&BrokenCompiler], 1e-5, 0.).unwrap_err();
+        assert_eq!(op, "Exp2");
+        assert_eq!(index, 0);
+        assert_eq!(expected, Some(32.));
+        assert_eq!(found, Some(16.));
+
+        // 1000 + 1.001 and 1000 * 1.001 are only close relative to their size
+        let close = |cx: &mut Graph| {
+            let a = cx.tensor::<R1<1>>().set([1000.]);
+            let b = cx.tensor::<R1<1>>().set([1.001]);
+            (a + b).retrieve();
+        };
+        assert!(compare(close, &[&BrokenCompiler], 1e-5, 0.).is_err());
+        compare(close, &[&BrokenCompiler], 1e-5, 1e-5).unwrap();
+    }
+
+    /// Doubles every retrieved output's length by concatenating it with itself
+    struct LongerCompiler;
+
+    impl Compiler for LongerCompiler {
+        type Output = ();
+        fn compile<T: ToIdsMut>(&self, graph: &mut Graph, mut ids: T) {
+            for id in ids.to_ids_mut() {
+                let (_, shape) = graph.to_retrieve.remove(id).unwrap();
+                let mut doubled = shape;
+                doubled.dims[doubled.indexes[0]] *= 2;
+                let new = graph
+                    .add_op(Function(
+                        "Double".to_string(),
+                        Box::new(|inp| {
+                            let data = inp[0].0.borrowed().f32s().unwrap();
+                            vec![crate::op::Tensor::new([data, data].concat())]
+                        }),
+                    ))
+                    .input(*id, 0, shape)
+                    .finish();
+                graph.keep_tensors(new);
+                graph.to_retrieve.insert(new, (0, doubled));
+                *id = new;
+            }
+        }
+    }
+
+    #[test]
+    fn test_compare_edge_cases() {
+        // Matching infinities are equal, even though their difference is NaN
+        let infinite = |cx: &mut Graph| {
+            cx.tensor::<R1<2>>().set([0., 1.]).log2().retrieve();
+        };
+        compare(infinite, &[&GenericCompiler::default()], 0., 0.).unwrap();
+
+        // Extra elements in the compiled output diverge
+        let build = |cx: &mut Graph| {
+            cx.tensor::<R1<2>>().set([1., 2.]).exp2().retrieve();
+        };
+        let Divergence {
+            index,
+            expected,
+            found,
+            ..
+        } = compare(build, &[&LongerCompiler], 1e-5, 0.).unwrap_err();
+        assert_eq!(index, 2);
+        assert_eq!(expected, None);
+        assert_eq!(found, Some(2.));
+    }
+}
@@ -0,0 +1,196 @@
+//! Random small graphs of primitive ops and their dfdx reference, shared by the fuzz tests of each compiler stack.
+//!
+//! Programs are lists of ops over square matrices, each reading earlier values, so proptest can shrink a failure down to
+//! the few ops that trigger it.
+
+use dfdx::prelude::{Axes2 as DAxes2, Axis as DAxis, Cpu, Tensor as DTensor, TensorFromVec, *};
+use luminal::prelude::*;
+use proptest::prelude::*;
+
+type Reference = DTensor<(usize, usize), f32, Cpu>;
+
+/// An op on values made earlier in the program. Indexes wrap around the values made so far. Ops with a restricted
+/// domain get their input squashed into it first, so both sides stay real valued.
+#[derive(Debug, Clone)]
+pub enum Op {
+    Exp2(usize),
+    /// log2(x^2 + 1)
+    Log2(usize),
+    Sin(usize),
+    /// sqrt(|x|)
+    Sqrt(usize),
+    /// 1 / (x^2 + 1)
+    Recip(usize),
+    Add(usize, usize),
+    Mul(usize, usize),
+    Max(usize, usize),
+    LessThan(usize, usize),
+    /// Multiply by a constant, including ones the optimizers fold away
+    Scale(usize, f32),
+    /// Reduce along an axis and expand back out, so every value stays square
+    SumReduce(usize, usize),
+    MaxReduce(usize, usize),
+    Transpose(usize),
+    Matmul(usize, usize),
+    Softmax(usize),
+}
+
+pub fn op() -> impl Strategy<Value = Op> {
+    let i = any::<usize>;
+    prop_oneof![
+        i().prop_map(Op::Exp2),
+        i().prop_map(Op::Log2),
+        i().prop_map(Op::Sin),
+        i().prop_map(Op::Sqrt),
+        i().prop_map(Op::Recip),
+        (i(), i()).prop_map(|(a, b)| Op::Add(a, b)),
+        (i(), i()).prop_map(|(a, b)| Op::Mul(a, b)),
+        (i(), i()).prop_map(|(a, b)| Op::Max(a, b)),
+        (i(), i()).prop_map(|(a, b)| Op::LessThan(a, b)),
+        (i(), prop::sample::select(vec![0., 1., -1., 0.5, 2.])).prop_map(|(a, c)| Op::Scale(a, c)),
+        (i(), 0..2usize).prop_map(|(a, axis)| Op::SumReduce(a, axis)),
+        (i(), 0..2usize).prop_map(|(a, axis)| Op::MaxReduce(a, axis)),
+        i().prop_map(Op::Transpose),
+        (i(), i()).prop_map(|(a, b)| Op::Matmul(a, b)),
+        i().prop_map(Op::Softmax),
+    ]
+}
+
+/// Values the program keeps as outputs: the last one, and one from the middle so shared intermediates get retrieved too
+pub fn output_values(program: &[Op]) -> Vec<usize> {
+    let last = program.len() + 1;
+    let middle = 2 + program.len() / 2;
+    if middle < last {
+        vec![middle, last]
+    } else {
+        vec![last]
+    }
+}
+
+pub fn build_luminal(
+    cx: &mut Graph,
+    program: &[Op],
+    n: usize,
+    a: &[f32],
+    b: &[f32],
+) -> Vec<DynGraphTensor> {
+    let mut values = vec![
+        cx.dyn_tensor("a", &[n, n]).set(a.to_vec()),
+        cx.dyn_tensor("b", &[n, n]).set(b.to_vec()),
+    ];
+    for op in program {
+        let v = |i: &usize| values[i % values.len()];
+        let value = match op {
+            Op::Exp2(x) => v(x).exp2(),
+            Op::Log2(x) => (v(x) * v(x) + 1.).log2(),
+            Op::Sin(x) => v(x).sin(),
+            Op::Sqrt(x) => v(x).abs().sqrt(),
+            Op::Recip(x) => (v(x) * v(x) + 1.).recip(),
+            Op::Add(x, y) => v(x) + v(y),
+            Op::Mul(x, y) => v(x) * v(y),
+            Op::Max(x, y) => v(x).maximum(v(y)),
+            Op::LessThan(x, y) => v(x).less_than(v(y)),
+            Op::Scale(x, c) => v(x) * *c,
+            Op::SumReduce(x, axis) => v(x).sum_reduce(&[*axis]).expand(*axis, n),
+            Op::MaxReduce(x, axis) => v(x).max_reduce(&[*axis]).expand(*axis, n),
+            Op::Transpose(x) => v(x).permute(&[1, 0]),
+            Op::Matmul(x, y) => v(x).matmul(v(y)),
+            Op::Softmax(x) => v(x).softmax(1),
+        };
+        values.push(value);
+    }
+    output_values(program)
+        .into_iter()
+        .map(|i| values[i].retrieve())
+        .collect()
+}
+
+pub fn run_reference(program: &[Op], n: usize, a: &[f32], b: &[f32]) -> Vec<Vec<f32>> {
+    let dev = Cpu::default();
+    let mut values: Vec<Reference> = vec![
+        dev.tensor_from_vec(a.to_vec(), (n, n)),
+        dev.tensor_from_vec(b.to_vec(), (n, n)),
+    ];
+    let ln2 = std::f32::consts::LN_2;
+    for op in program {
+        let v = |i: &usize| values[i % values.len()].clone();
+        let value = match op {
+            Op::Exp2(x) => (v(x) * ln2).exp(),
+            Op::Log2(x) => ((v(x) * v(x)) + 1.).ln() / ln2,
+            Op::Sin(x) => v(x).sin(),
+            Op::Sqrt(x) => v(x).abs().sqrt(),
+            Op::Recip(x) => ((v(x) * v(x)) + 1.).recip(),
+            Op::Add(x, y) => v(x) + v(y),
+            Op::Mul(x, y) => v(x) * v(y),
+            Op::Max(x, y) => v(x).maximum(v(y)),
+            Op::LessThan(x, y) => {
+                let lt = v(x)
+                    .as_vec()
+                    .into_iter()
+                    .zip(v(y).as_vec())
+                    .map(|(a, b)| if a < b { 1. } else { 0. })
+                    .collect();
+                dev.tensor_from_vec(lt, (n, n))
+            }
+            Op::Scale(x, c) => v(x) * *c,
+            Op::SumReduce(x, 0) => v(x)
+                .sum::<(usize,), DAxis<0>>()
+                .broadcast_like::<_, DAxis<0>>(&(n, n)),
+            Op::SumReduce(x, _) => v(x)
+                .sum::<(usize,), DAxis<1>>()
+                .broadcast_like::<_, DAxis<1>>(&(n, n)),
+            Op::MaxReduce(x, 0) => v(x)
+                .max::<(usize,), DAxis<0>>()
+                .broadcast_like::<_, DAxis<0>>(&(n, n)),
+            Op::MaxReduce(x, _) => v(x)
+                .max::<(usize,), DAxis<1>>()
+                .broadcast_like::<_, DAxis<1>>(&(n, n)),
+            Op::Transpose(x) => v(x).permute::<_, DAxes2<1, 0>>(),
+            Op::Matmul(x, y) => v(x).matmul(v(y)),
+            Op::Softmax(x) => v(x).softmax::<DAxis<1>>(),
+        };
+        values.push(value);
+    }
+    output_values(program)
+        .into_iter()
+        .map(|i| values[i].as_vec())
+        .collect()
+}
+
+/// Close enough, relative to the size of the values. Overflow can show up as inf on one side and NaN on the other
+/// depending on how ops got rearranged, so any two non-finite values match.
+pub fn matches(a: f32, b: f32) -> bool {
+    if !a.is_finite() || !b.is_finite() {
+        return !a.is_finite() && !b.is_finite();
+    }
+    (a - b).abs() <= 1e-3 * a.abs().max(b.abs()).max(1.)
+}
+
+/// Build a program, compile it with `compile` and check its outputs against the reference
+pub fn check_program(
+    program: &[Op],
+    n: usize,
+    a: &[f32],
+    b: &[f32],
+    stack: &str,
+    compile: impl FnOnce(&mut Graph, &mut Vec<DynGraphTensor>),
+) -> Result<(), TestCaseError> {
+    let expected = run_reference(program, n, a, b);
+    let mut cx = Graph::new();
+    let mut outputs = build_luminal(&mut cx, program, n, a, b);
+    compile(&mut cx, &mut outputs);
+    cx.execute();
+    for (i, (output, expected)) in outputs.iter().zip(&expected).enumerate() {
+        let data = output.data();
+        prop_assert_eq!(data.len(), expected.len(), "output {} through {}", i, stack);
+        prop_assert!(
+            data.iter().zip(expected).all(|(a, b)| matches(*a, *b)),
+            "output {} through {}: got {:?}, expected {:?}",
+            i,
+            stack,
+            data,
+            expected
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! Random small graphs of primitive ops, run through each optimizer stack and checked against dfdx.
+//!
+//! Failing cases are saved under `proptest-regressions/` and rerun first next time. The CPU backend's stacks are fuzzed
+//! the same way in `crates/luminal_cpu/tests`.
+
+mod fuzz;
+
+use fuzz::{check_program, op};
+use luminal::prelude::*;
+use proptest::prelude::*;
+
+/// Optimizer stacks every program goes through. The first one doesn't optimize at all.
+const STACKS: &[&str] = &[
+    "none",
+    "GenericCompiler",
+    "GenericCompiler + ContiguousElimination",
+    "GenericCompiler + EGraphSimplifier",
+    "CSE + RecomputeIntermediates",
+    "RemoveSingleReductions + DepthFirst",
+];
+
+fn compile(stack: &str, cx: &mut Graph, outputs: &mut Vec<DynGraphTensor>) {
+    match stack {
+        "none" => {}
+        "GenericCompiler" => {
+            cx.compile(GenericCompiler::default(), outputs);
+        }
+        "GenericCompiler + ContiguousElimination" => {
+            cx.compile((GenericCompiler::default(), ContiguousElimination), outputs);
+        }
+        "GenericCompiler + EGraphSimplifier" => {
+            cx.compile(
+                (GenericCompiler::default(), EGraphSimplifier::default()),
+                outputs,
+            );
+        }
+        "CSE + RecomputeIntermediates" => {
+            cx.compile((CSE, RecomputeIntermediates::default()), outputs);
+        }
+        "RemoveSingleReductions + DepthFirst" => {
+            cx.compile((RemoveSingleReductions, DepthFirst), outputs);
+        }
+        _ => unreachable!(),
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+    #[test]
+    fn fuzz_ops(
+        n in 1..4usize,
+        program in prop::collection::vec(op(), 1..10),
+        a in prop::collection::vec(-1f32..1., 9),
+        b in prop::collection::vec(-1f32..1., 9),
+    ) {
+        let (a, b) = (&a[..n * n], &b[..n * n]);
+        for stack in STACKS {
+            check_program(&program, n, a, b, stack, |cx, outputs| compile(stack, cx, outputs))?;
+        }
+    }
+}